@@ -8,6 +8,22 @@ function opAsync(opName: string, a?: unknown, b?: unknown): Promise<unknown> {
     return Deno.core.opAsync(opName, a, b);
 }
 
+/**
+ * The error a save fails with when the entity was modified since it was
+ * loaded. Its `code` is `"VERSION_CONFLICT"`.
+ */
+class VersionConflictError extends Error {
+    readonly code = "VERSION_CONFLICT";
+
+    constructor(message: string) {
+        super(message);
+        this.name = "VersionConflictError";
+    }
+}
+
+// The ops fail with the "VersionConflict" class on version conflicts.
+Deno.core.registerErrorClass("VersionConflict", VersionConflictError);
+
 /**
  * Base class for various Operators applicable on `ChiselCursor`. An
  * implementation of Operator<T> processes an AsyncIterable<T> and
@@ -74,6 +90,12 @@ abstract class Operator<Input, Output> {
     }
 }
 
+/**
+ * Versions of the loaded entities, which `ChiselEntity.save()` uses to detect
+ * conflicting updates. Kept out of the entities so they aren't serialized.
+ */
+const entityVersions = new WeakMap<object, number>();
+
 /**
  * Specifies Entity whose elements are to be fetched.
  */
@@ -92,8 +114,15 @@ class BaseEntity<T> extends Operator<never, T> {
     }
 
     recordToOutput(rawRecord: unknown): T {
+        const { __version, ...properties } = rawRecord as Record<
+            string,
+            unknown
+        >;
         const result = new this.baseConstructor();
-        Object.assign(result, rawRecord);
+        Object.assign(result, properties);
+        if (typeof __version == "number") {
+            entityVersions.set(result as unknown as object, __version);
+        }
         return result;
     }
 
@@ -388,13 +417,21 @@ export class ChiselCursor<T> {
         predicate: (arg: T) => boolean,
     ): ChiselCursor<T>;
     /**
-     * Restricts this cursor to contain just the objects that match the `Partial`
-     * object `restrictions`.
+     * Restricts this cursor to contain just the objects that match the
+     * `restrictions`. Each property of `restrictions` is either a value the
+     * field must be equal to or a `FieldPredicate` on the field.
+     *
+     * @example
+     * ```typescript
+     * Person.cursor().filter({ age: { $gt: 18 }, name: { $in: ["Alice", "Bob"] } });
+     * ```
      */
-    filter(restrictions: Partial<T>): ChiselCursor<T>;
+    filter(restrictions: FilterPredicates<T>): ChiselCursor<T>;
 
     // Common implementation for filter overloads.
-    filter(arg1: ((arg: T) => boolean) | Partial<T>): ChiselCursor<T> {
+    filter(
+        arg1: ((arg: T) => boolean) | FilterPredicates<T>,
+    ): ChiselCursor<T> {
         if (typeof arg1 == "function") {
             return new ChiselCursor(
                 new PredicateFilter(
@@ -411,10 +448,15 @@ export class ChiselCursor<T> {
             }
             const predicate = (arg: T) => {
                 for (const key in restrictions) {
-                    if (restrictions[key] === undefined) {
+                    const restriction = restrictions[key];
+                    if (restriction === undefined) {
                         continue;
                     }
-                    if (arg[key] != restrictions[key]) {
+                    if (isFieldPredicate(restriction)) {
+                        if (!fieldPredicateHolds(arg[key], restriction)) {
+                            return false;
+                        }
+                    } else if (arg[key] != restriction) {
                         return false;
                     }
                 }
@@ -464,6 +506,39 @@ export class ChiselCursor<T> {
         );
     }
 
+    /** Sorts cursor elements by `key`, in ascending order unless `direction` is "desc". */
+    sort(key: keyof T, direction: "asc" | "desc" = "asc"): ChiselCursor<T> {
+        return this.sortBy(key, direction == "asc");
+    }
+
+    /** Same as `take`. */
+    limit(count: number): ChiselCursor<T> {
+        return this.take(count);
+    }
+
+    /** Same as `skip`. */
+    offset(count: number): ChiselCursor<T> {
+        return this.skip(count);
+    }
+
+    /**
+     * Counts the elements of this cursor. Only the ids of the elements
+     * are fetched from the database.
+     */
+    async count(): Promise<number> {
+        let count = 0;
+        const ids = new ChiselCursor(
+            new ColumnsSelect<T, (keyof T)[]>(
+                ["id" as keyof T],
+                this.inner,
+            ),
+        );
+        for await (const _ of ids) {
+            count++;
+        }
+        return count;
+    }
+
     /**
      * Finds minimal value over all elements using their `key` attribute.
      *
@@ -623,24 +698,92 @@ export class ChiselEntity {
         return result;
     }
 
-    /** saves the current object into the backend */
-    async save() {
+    /**
+     * Saves the current object into the backend.
+     *
+     * If the object was loaded from the backend and has been modified by
+     * someone else since, the save fails with an error whose `code` is
+     * `"VERSION_CONFLICT"`. Unless `confirmOverwrite` is given:
+     * it is then called with the current state of the object, and the save is
+     * retried over it if `confirmOverwrite` returns true.
+     */
+    async save(
+        confirmOverwrite?: (current: this) => boolean | Promise<boolean>,
+    ) {
         ensureNotGet();
-        type IdsJson = { id: string; children: Record<string, IdsJson> };
-        const jsonIds = await opAsync("op_chisel_store", {
-            name: this.constructor.name,
-            value: this,
-        }, requestContext) as IdsJson;
-        function backfillIds(this_: ChiselEntity, jsonIds: IdsJson) {
-            this_.id = jsonIds.id;
-            for (const [fieldName, value] of Object.entries(jsonIds.children)) {
-                const child = (this_ as unknown as Record<string, unknown>)[
-                    fieldName
-                ];
-                backfillIds(child as ChiselEntity, value);
+        const wasSaved = this.id !== undefined;
+        while (true) {
+            const expectedVersion = entityVersions.get(this);
+            let jsonIds;
+            try {
+                jsonIds = await opAsync("op_chisel_store", {
+                    name: this.constructor.name,
+                    value: this,
+                    expectedVersion,
+                }, requestContext) as IdsJson;
+            } catch (e) {
+                if (
+                    confirmOverwrite === undefined ||
+                    !(e instanceof VersionConflictError)
+                ) {
+                    throw e;
+                }
+                const type = this.constructor as typeof ChiselEntity;
+                const current = await type.findOne({ id: this.id }) as
+                    | this
+                    | undefined;
+                if (current === undefined || !await confirmOverwrite(current)) {
+                    throw e;
+                }
+                entityVersions.set(this, entityVersions.get(current)!);
+                continue;
             }
+            backfillIds(this, jsonIds);
+            if (expectedVersion !== undefined) {
+                entityVersions.set(this, expectedVersion + 1);
+            } else if (!wasSaved) {
+                entityVersions.set(this, 0);
+            } else {
+                // Overwrote whatever version was there.
+                entityVersions.delete(this);
+            }
+            return;
+        }
+    }
+
+    /**
+     * Calls `callback` with the new state of this entity every time it is
+     * updated, and with `null` when it is deleted, until the returned
+     * function is called. The entity must have been saved.
+     *
+     * @example
+     * ```typescript
+     * const unsubscribe = order.subscribe((updated) => {
+     *     console.log(updated?.status ?? "deleted");
+     * });
+     * ```
+     */
+    subscribe<T extends ChiselEntity>(
+        this: T,
+        callback: (updated: T | null) => void,
+    ): Unsubscribe {
+        if (this.id === undefined) {
+            throw new Error("Cannot subscribe to an entity that wasn't saved");
         }
-        backfillIds(this, jsonIds);
+        const type = this.constructor as { new (): T; name: string };
+        return openEventStream(
+            `/__chiselstrike/sse/${type.name}/${this.id}`,
+            (payload) => {
+                const event = payload as RawDataEvent;
+                if (event.operation == "delete") {
+                    callback(null);
+                } else if (event.operation == "update") {
+                    const updated = new type();
+                    Object.assign(updated, event.data);
+                    callback(updated);
+                }
+            },
+        );
     }
 
     /** Returns a `ChiselCursor` containing all elements of type T known to ChiselStrike.
@@ -666,6 +809,25 @@ export class ChiselEntity {
         return await it.toArray();
     }
 
+    /**
+     * Returns a `ChiselCursor` containing the entities of type T that match
+     * `restrictions`, see `ChiselCursor.filter`.
+     *
+     * @example
+     * ```typescript
+     * const adults = await Person.filter({ age: { $gt: 17 } })
+     *     .sort("age", "desc")
+     *     .limit(10)
+     *     .toArray();
+     * ```
+     */
+    static filter<T extends ChiselEntity>(
+        this: { new (): T },
+        restrictions: FilterPredicates<T>,
+    ): ChiselCursor<T> {
+        return chiselIterator<T>(this).filter(restrictions);
+    }
+
     /**
      * Returns all entities of type T for which the given `predicate` returns true.
      * You can optionaly specify `take` parameter that will limit the number of
@@ -758,6 +920,27 @@ export class ChiselEntity {
         return undefined;
     }
 
+    /** Returns the object whose id is `id`, or `undefined` if there is none. */
+    static async findById<T extends ChiselEntity>(
+        this: { new (): T },
+        id: string,
+    ): Promise<T | undefined> {
+        const response = await chiselFetch(
+            `/__chiselstrike/entities/${this.name}/${id}`,
+        );
+        if (response.status == 404) {
+            return undefined;
+        }
+        if (!response.ok) {
+            throw new Error(
+                `Failed to find ${this.name} ${id}: ${await response.text()}`,
+            );
+        }
+        const result = new this();
+        Object.assign(result, await responseData(response));
+        return result;
+    }
+
     // findOne function used by Chisel Compiler. Not intended for direct usage.
     static async __findOne<T extends ChiselEntity>(
         this: { new (): T },
@@ -862,38 +1045,488 @@ export class ChiselEntity {
         await result.save();
         return result;
     }
+
+    /**
+     * Inserts an entity with the given properties or, if one with the same
+     * values for all of `conflictFields` already exists, updates it. The
+     * `conflictFields` must be unique.
+     *
+     * @example
+     * ```typescript
+     * const user = await User.upsert(
+     *     { email: "alice@example.com", username: "alice" },
+     *     ["email"],
+     * );
+     * ```
+     * @returns The upserted entity, with the `id` property set.
+     */
+    static async upsert<T extends ChiselEntity>(
+        this: { new (): T },
+        data: Partial<T>,
+        conflictFields: (keyof T)[],
+    ): Promise<T> {
+        ensureNotGet();
+        const response = await chiselFetch(
+            `/__chiselstrike/entities/${this.name}/upsert`,
+            {
+                method: "POST",
+                headers: { "content-type": "application/json" },
+                body: JSON.stringify({ data, conflictFields }),
+            },
+        );
+        if (!response.ok) {
+            throw new Error(
+                `Failed to upsert ${this.name}: ${await response.text()}`,
+            );
+        }
+        const result = new this();
+        Object.assign(result, await responseData(response));
+        return result;
+    }
+
+    /**
+     * Runs `fn` in a database transaction: the writes it makes through the
+     * given `ChiselTransaction` are committed together once it returns, or
+     * rolled back if it throws.
+     *
+     * @example
+     * ```typescript
+     * const stock = await Stock.findOne({ item: "book" });
+     * await ChiselEntity.transaction(async (tx) => {
+     *     await tx.create(Order, { item: "book" });
+     *     stock.count -= 1;
+     *     await tx.update(stock);
+     * });
+     * ```
+     */
+    static async transaction(
+        fn: (tx: ChiselTransaction) => Promise<void>,
+    ): Promise<void> {
+        ensureNotGet();
+        const response = await chiselFetch("/__chiselstrike/transactions/begin", {
+            method: "POST",
+        });
+        if (!response.ok) {
+            throw new Error(
+                `Failed to begin a transaction: ${await response.text()}`,
+            );
+        }
+        const { id } = await responseData(response) as { id: string };
+        const tx = new ChiselTransaction(id);
+        try {
+            await fn(tx);
+        } catch (e) {
+            await tx.end("rollback");
+            throw e;
+        }
+        await tx.end("commit");
+    }
+
+    /**
+     * Calls `callback` for every entity of this type created, updated or deleted
+     * from now on, until the returned function is called.
+     *
+     * @example
+     * ```typescript
+     * const unsubscribe = User.watch((event) => {
+     *     console.log(event.operation, event.data.id);
+     * });
+     * // ...
+     * unsubscribe();
+     * ```
+     */
+    static watch<T extends ChiselEntity>(
+        this: { new (): T },
+        callback: (event: DataEvent<T>) => void,
+    ): Unsubscribe {
+        const type = this;
+        return openEventStream(
+            `/__chiselstrike/sse/${this.name}`,
+            (payload) => {
+                const event = payload as RawDataEvent;
+                if (event.operation == "delete") {
+                    callback({
+                        operation: "delete",
+                        data: event.data as { id: string },
+                    });
+                } else {
+                    const data = new type();
+                    Object.assign(data, event.data);
+                    callback({ operation: event.operation, data });
+                }
+            },
+        );
+    }
+}
+
+/** The kind of change that happened to an entity. */
+export type DataOperation = "create" | "update" | "delete";
+
+/** A change to an entity of type `T`. Deleted entities only carry their id. */
+export type DataEvent<T> =
+    | { operation: "create" | "update"; data: T }
+    | { operation: "delete"; data: { id: string } };
+
+/** Stops watching for changes when called. */
+export type Unsubscribe = () => void;
+
+/** DataEvent as sent by the server, before the data is made into an entity. */
+type RawDataEvent = {
+    operation: DataOperation;
+    data: Record<string, unknown>;
+};
+
+type IdsJson = { id: string; children: Record<string, IdsJson> };
+
+/** Sets the ids the server assigned to `entity` and its nested entities. */
+function backfillIds(entity: ChiselEntity, jsonIds: IdsJson) {
+    entity.id = jsonIds.id;
+    for (const [fieldName, value] of Object.entries(jsonIds.children)) {
+        const child = (entity as unknown as Record<string, unknown>)[
+            fieldName
+        ];
+        backfillIds(child as ChiselEntity, value);
+    }
+}
+
+/**
+ * Writes entities as part of a transaction started by
+ * `ChiselEntity.transaction`. The writes are only visible to others once
+ * the transaction commits.
+ */
+export class ChiselTransaction {
+    constructor(private readonly id: string) {}
+
+    private async send(
+        method: string,
+        path: string,
+        body?: unknown,
+        headers: Record<string, string> = {},
+    ): Promise<unknown> {
+        const response = await chiselFetch(
+            `/__chiselstrike/${path}`,
+            {
+                method,
+                headers: {
+                    ...headers,
+                    "ChiselTransaction": this.id,
+                    "content-type": "application/json",
+                },
+                body: body === undefined ? undefined : JSON.stringify(body),
+            },
+        );
+        if (!response.ok) {
+            throw new Error(
+                `Transaction ${this.id} failed: ${await response.text()}`,
+            );
+        }
+        if (response.status == 204) {
+            return undefined;
+        }
+        return await responseData(response);
+    }
+
+    /** Creates an entity of type `type` with the given properties. */
+    async create<T extends ChiselEntity>(
+        type: { new (): T },
+        ...properties: Partial<T>[]
+    ): Promise<T> {
+        const result = new type();
+        Object.assign(result, ...properties);
+        const jsonIds = await this.send(
+            "POST",
+            `entities/${type.name}`,
+            result,
+        ) as IdsJson;
+        backfillIds(result, jsonIds);
+        return result;
+    }
+
+    /**
+     * Saves `entity`, which must already have an id, over its current state.
+     * Like `ChiselEntity.save()`, fails if `entity` was loaded and has been
+     * modified by someone else since.
+     */
+    async update<T extends ChiselEntity>(entity: T): Promise<void> {
+        if (entity.id === undefined) {
+            throw new Error("Cannot update an entity that wasn't saved");
+        }
+        const version = entityVersions.get(entity);
+        const jsonIds = await this.send(
+            "POST",
+            `entities/${entity.constructor.name}`,
+            entity,
+            version === undefined ? {} : { "If-Match": `${version}` },
+        ) as IdsJson;
+        backfillIds(entity, jsonIds);
+        if (version !== undefined) {
+            entityVersions.set(entity, version + 1);
+        }
+    }
+
+    /**
+     * Deletes `entity`, which must have an id. Fails if it doesn't exist, or
+     * if it was loaded and has been modified by someone else since.
+     */
+    async delete<T extends ChiselEntity>(entity: T): Promise<void> {
+        if (entity.id === undefined) {
+            throw new Error("Cannot delete an entity that wasn't saved");
+        }
+        const version = entityVersions.get(entity);
+        await this.send(
+            "DELETE",
+            `entities/${entity.constructor.name}/${entity.id}`,
+            undefined,
+            version === undefined ? {} : { "If-Match": `${version}` },
+        );
+    }
+
+    /** Commits or rolls back the transaction. Called by `ChiselEntity.transaction`. */
+    async end(how: "commit" | "rollback"): Promise<void> {
+        await this.send("POST", `transactions/${how}`);
+    }
+}
+
+/**
+ * Sends a request to one of the server's own `/__chiselstrike` routes, which
+ * are authorized by the secret of the server process. The request is made
+ * for the logged-in user and the tenant, if any, so that the routes apply
+ * their policies and only touch the entities of that tenant.
+ *
+ * The request goes to the address the server listens at, never to the Host
+ * of the request being served, which the client controls.
+ */
+function chiselFetch(path: string, init: RequestInit = {}): Promise<Response> {
+    const url = new URL(path, `http://${serverContext.address}`);
+    url.searchParams.set("version", requestContext.apiVersion);
+    const headers = new Headers(init.headers);
+    headers.set("ChiselAuth", serverContext.secret);
+    if (requestContext.userId !== undefined) {
+        headers.set("ChiselUID", requestContext.userId);
+    }
+    if (requestContext.tenantId !== undefined) {
+        headers.set("ChiselTenant", requestContext.tenantId);
+    }
+    return fetch(url, { ...init, headers });
+}
+
+/**
+ * The payload of a JSON response of the native routes, which come wrapped
+ * as `{ data, meta }`.
+ */
+async function responseData(response: Response): Promise<unknown> {
+    const { data } = await response.json() as { data: unknown };
+    return data;
+}
+
+/**
+ * Opens a server-sent events stream at `path` and calls `onData` with the
+ * parsed JSON data of every event. Returns a function closing the stream.
+ */
+function openEventStream(
+    path: string,
+    onData: (data: unknown) => void,
+): Unsubscribe {
+    const controller = new AbortController();
+    const read = async () => {
+        const response = await chiselFetch(path, {
+            signal: controller.signal,
+            headers: { "accept": "text/event-stream" },
+        });
+        if (!response.ok || response.body === null) {
+            throw new Error(
+                `Failed to open ${path}: ${response.status} ${await response
+                    .text()}`,
+            );
+        }
+        const decoder = new TextDecoder();
+        let buffer = "";
+        for await (const chunk of response.body) {
+            buffer += decoder.decode(chunk, { stream: true });
+            let end;
+            while ((end = buffer.indexOf("\n\n")) >= 0) {
+                const data = buffer.slice(0, end).split("\n")
+                    .filter((line) => line.startsWith("data:"))
+                    .map((line) => line.slice("data:".length).trimStart())
+                    .join("\n");
+                buffer = buffer.slice(end + 2);
+                if (data.length != 0) {
+                    onData(JSON.parse(data));
+                }
+            }
+        }
+    };
+    read().catch((e) => {
+        if (!controller.signal.aborted) {
+            console.error(e);
+        }
+    });
+    return () => controller.abort();
+}
+
+/**
+ * Conditions on a single field, all of which must hold. `$contains` matches
+ * strings containing the given substring, or arrays containing the given
+ * element. `$overlap` matches arrays sharing an element with the given one,
+ * and `$in` matches any of the values. On `Record` fields, `$hasKey`
+ * matches records having the given key, and `$keyEq` those having all the
+ * given keys with the given values.
+ */
+export type FieldPredicate<V> = {
+    $gt?: V;
+    $lt?: V;
+    $contains?: V extends (infer E)[] ? E : string;
+    $overlap?: V extends unknown[] ? V : never;
+    $in?: V[];
+    $hasKey?: V extends Record<string, unknown> ? string : never;
+    $keyEq?: V extends Record<string, unknown> ? Partial<V> : never;
+};
+
+/** For every field, either the value it must be equal to or a `FieldPredicate`. */
+export type FilterPredicates<T> = {
+    [K in keyof T]?: T[K] | FieldPredicate<T[K]>;
+};
+
+function isFieldPredicate(value: unknown): value is FieldPredicate<unknown> {
+    if (typeof value != "object" || value === null || Array.isArray(value)) {
+        return false;
+    }
+    const keys = Object.keys(value);
+    return keys.length != 0 && keys.every((k) => k.startsWith("$"));
+}
+
+function fieldPredicateHolds<V>(
+    value: V,
+    predicate: FieldPredicate<V>,
+): boolean {
+    if (predicate.$gt !== undefined && !(value > predicate.$gt)) {
+        return false;
+    }
+    if (predicate.$lt !== undefined && !(value < predicate.$lt)) {
+        return false;
+    }
+    if (predicate.$contains !== undefined) {
+        const contains = Array.isArray(value)
+            ? value.includes(predicate.$contains)
+            : typeof value == "string" &&
+                value.includes(predicate.$contains as string);
+        if (!contains) {
+            return false;
+        }
+    }
+    if (predicate.$overlap !== undefined) {
+        const overlap = predicate.$overlap as unknown[];
+        if (!Array.isArray(value)) {
+            return false;
+        }
+        const elements: unknown[] = value;
+        if (!overlap.some((v) => elements.includes(v))) {
+            return false;
+        }
+    }
+    if (predicate.$in !== undefined && !predicate.$in.includes(value)) {
+        return false;
+    }
+    if (predicate.$hasKey !== undefined || predicate.$keyEq !== undefined) {
+        if (typeof value != "object" || value === null) {
+            return false;
+        }
+        const record = value as Record<string, unknown>;
+        if (predicate.$hasKey !== undefined && !(predicate.$hasKey in record)) {
+            return false;
+        }
+        const keyEq = predicate.$keyEq as Record<string, unknown> | undefined;
+        for (const key in keyEq) {
+            if (record[key] !== keyEq[key]) {
+                return false;
+            }
+        }
+    }
+    return true;
+}
+
+function binaryExpr(
+    left: Record<string, unknown>,
+    op: string,
+    right: Record<string, unknown>,
+): Record<string, unknown> {
+    return { exprType: "Binary", left, op, right };
+}
+
+function literalExpr(value: unknown): Record<string, unknown> {
+    return { exprType: "Literal", value };
+}
+
+function fieldPredicateToExpr(
+    property: Record<string, unknown>,
+    predicate: FieldPredicate<unknown>,
+): Record<string, unknown>[] {
+    const exprs = [];
+    if (predicate.$gt !== undefined) {
+        exprs.push(binaryExpr(property, "Gt", literalExpr(predicate.$gt)));
+    }
+    if (predicate.$lt !== undefined) {
+        exprs.push(binaryExpr(property, "Lt", literalExpr(predicate.$lt)));
+    }
+    if (predicate.$contains !== undefined) {
+        // Whether this is about a substring or an element depends on the
+        // type of the field, which the server knows.
+        exprs.push(
+            binaryExpr(property, "Contains", literalExpr(predicate.$contains)),
+        );
+    }
+    if (predicate.$overlap !== undefined) {
+        exprs.push(
+            binaryExpr(property, "Overlaps", literalExpr(predicate.$overlap)),
+        );
+    }
+    if (predicate.$hasKey !== undefined) {
+        exprs.push(
+            binaryExpr(property, "HasKey", literalExpr(predicate.$hasKey)),
+        );
+    }
+    const keyEq = predicate.$keyEq as Record<string, unknown> | undefined;
+    for (const key in keyEq) {
+        const entry = { exprType: "Property", object: property, property: key };
+        exprs.push(binaryExpr(entry, "Eq", literalExpr(keyEq[key])));
+    }
+    if (predicate.$in !== undefined) {
+        const alternatives = predicate.$in.map((v) =>
+            binaryExpr(property, "Eq", literalExpr(v))
+        );
+        exprs.push(
+            alternatives.reduce(
+                (acc, e) => binaryExpr(acc, "Or", e),
+                literalExpr(false),
+            ),
+        );
+    }
+    return exprs;
 }
 
 function restrictionsToFilterExpr<T extends ChiselEntity>(
-    restrictions: Partial<T>,
+    restrictions: FilterPredicates<T>,
 ): Record<string, unknown> | undefined {
     let expr = undefined;
     for (const key in restrictions) {
-        if (restrictions[key] === undefined) {
+        const restriction = restrictions[key];
+        if (restriction === undefined) {
             continue;
         }
-        const cmpExpr = {
-            exprType: "Binary",
-            left: {
-                exprType: "Property",
-                object: { exprType: "Parameter", position: 0 },
-                property: key,
-            },
-            op: "Eq",
-            right: {
-                exprType: "Literal",
-                value: restrictions[key],
-            },
+        const property = {
+            exprType: "Property",
+            object: { exprType: "Parameter", position: 0 },
+            property: key,
         };
-        if (expr === undefined) {
-            expr = cmpExpr;
-        } else {
-            expr = {
-                exprType: "Binary",
-                left: cmpExpr,
-                op: "And",
-                right: expr,
-            };
+        const cmpExprs = isFieldPredicate(restriction)
+            ? fieldPredicateToExpr(property, restriction)
+            : [binaryExpr(property, "Eq", literalExpr(restriction))];
+        for (const cmpExpr of cmpExprs) {
+            if (expr === undefined) {
+                expr = cmpExpr;
+            } else {
+                expr = binaryExpr(cmpExpr, "And", expr);
+            }
         }
     }
     return expr;
@@ -906,6 +1539,15 @@ export class AuthUser extends ChiselEntity {
     image?: string;
 }
 
+/**
+ * A tenant of the application. When the server runs with `--tenant-domain`,
+ * requests to `<subdomain>.<tenant domain>` act on behalf of the tenant
+ * with that `subdomain`.
+ */
+export class Tenant extends ChiselEntity {
+    subdomain = "";
+}
+
 /**
  * Gets a secret from the environment
  *
@@ -950,6 +1592,217 @@ export function unique(_target: unknown, _name: string): void {
     // chisel-decorator, no content
 }
 
+/**
+ * Stores the decorated string property encrypted with AES-256-GCM, under a
+ * key derived from the `CHISEL_FIELD_ENCRYPTION_KEY` environment variable of
+ * the server. It is decrypted when read, but it can't be filtered or sorted
+ * on, nor be unique.
+ *
+ * @example
+ * ```typescript
+ * class Patient extends ChiselEntity {
+ *     name: string;
+ *     @encrypted ssn: string;
+ * }
+ * ```
+ */
+export function encrypted(_target: unknown, _name: string): void {
+    // chisel-decorator, no content
+}
+
+/**
+ * Stores the decorated property in the database column `_name`, while it
+ * keeps its own name in JSON and in endpoint code.
+ *
+ * @example
+ * ```typescript
+ * class Post extends ChiselEntity {
+ *     @column("created_at") createdAt: string;
+ * }
+ * ```
+ */
+export function column(_name: string) {
+    return <T>(_target: T, _propertyName: string) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Marks the decorated property as deprecated. Responses holding it carry a
+ * `Deprecation-Warning` header, and writing it is warned about with a
+ * `Warning` header, or refused if the server runs with `--strict-mode`.
+ *
+ * @example
+ * ```typescript
+ * class User extends ChiselEntity {
+ *     @deprecated("use 'fullName' instead.") name?: string;
+ *     fullName: string;
+ * }
+ * ```
+ */
+export function deprecated(_message?: string) {
+    return <T>(_target: T, _propertyName: string) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Makes sure no two entities of the decorated class have the same values for
+ * all of `_fields` at once. Saving one that does fails with an error naming
+ * the constraint by `_name`, or by its fields if it has no name.
+ *
+ * @example
+ * ```typescript
+ * @uniqueTogether(["provider", "username"], "account_identity")
+ * class Account extends ChiselEntity {
+ *     provider: string;
+ *     username: string;
+ * }
+ * ```
+ */
+export function uniqueTogether(_fields: string[], _name?: string) {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Makes the database refuse entities of the decorated class for which the
+ * SQL condition `_expression` doesn't hold. Saving one fails with an error
+ * naming the constraint by `_name`.
+ *
+ * The condition is passed to the database as is, so it has to be written
+ * in SQL that both SQLite and PostgreSQL understand to work on either.
+ *
+ * @example
+ * ```typescript
+ * @check("positive_price", "price > 0")
+ * class Product extends ChiselEntity {
+ *     price: number;
+ * }
+ * ```
+ */
+export function check(_name: string, _expression: string) {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Adds to the decorated class the relation `_name`, holding the entities of
+ * `_targetType` that the SQL condition `_subquery` picks. In the condition,
+ * `$id` stands for the id of the entity the relation is of.
+ *
+ * The relation isn't stored: CRUD endpoints work it out for the entities
+ * they return when asked with `?expand=name`.
+ *
+ * @example
+ * ```typescript
+ * @relation("latestComment", "Comment", "post = $id ORDER BY created DESC LIMIT 1")
+ * class Post extends ChiselEntity {
+ *     title: string;
+ * }
+ * ```
+ */
+export function relation(_name: string, _targetType: string, _subquery: string) {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Makes the entities of the decorated class belong to tenants. Requests made
+ * for a tenant only find its own entities, and the entities they save get the
+ * tenant's id in their `tenant_id` property, which the class has to declare.
+ *
+ * @example
+ * ```typescript
+ * @multiTenant()
+ * class Project extends ChiselEntity {
+ *     name: string;
+ *     tenant_id = "";
+ * }
+ * ```
+ */
+export function multiTenant() {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Picks how the ids of new entities of the decorated class are generated:
+ * random UUIDs (`"uuid"`, the default), time-sortable ULIDs (`"ulid"`),
+ * 64-bit snowflake ids made unique across servers by a machine id from 0 to
+ * 1023 (`"snowflake"`), or 1, 2, 3 and so on (`"autoincrement"`). Entities
+ * saved before the strategy changed keep their ids.
+ *
+ * `"autoincrement"` counts the ids in the memory of the server, so it only
+ * works with a single server process writing to the database: two of them
+ * would give out the same ids.
+ *
+ * @example
+ * ```typescript
+ * @idStrategy("snowflake", 7)
+ * class Order extends ChiselEntity {
+ *     total: number;
+ * }
+ * ```
+ */
+export function idStrategy(
+    _strategy: "uuid" | "ulid" | "snowflake" | "autoincrement",
+    _machineId?: number,
+) {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Keeps the entities of the decorated class in the table `_tableName`,
+ * rather than in one named after the class, e.g. to use the tables of an
+ * existing database. The name must be an SQL identifier of letters, digits
+ * and underscores. Once the model is applied, its table can't change.
+ *
+ * @example
+ * ```typescript
+ * @tableName("legacy_users")
+ * class User extends ChiselEntity {
+ *     email: string;
+ * }
+ * ```
+ */
+export function tableName(_tableName: string) {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * POSTs the changes to the entities of the decorated class to `_url`, for
+ * the `_events` among `insert`, `update` and `delete`. The deliveries are
+ * signed with the value of the secret named `_secretName`: their
+ * `X-ChiselStrike-Signature-256` header is `sha256=` followed by the hex
+ * HMAC-SHA256 of the body.
+ *
+ * @example
+ * ```typescript
+ * @webhook("https://example.com/hooks/orders", ["insert", "delete"], "ORDERS_HOOK_SECRET")
+ * class Order extends ChiselEntity {
+ *     total: number;
+ * }
+ * ```
+ */
+export function webhook(
+    _url: string,
+    _events: ("insert" | "update" | "delete")[],
+    _secretName: string,
+) {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
 /** Returns the currently logged-in user or null if no one is logged in. */
 export async function loggedInUser(): Promise<AuthUser | undefined> {
     const id = requestContext.userId;
@@ -965,12 +1818,21 @@ function ensureNotGet() {
     }
 }
 
+/** Set by the worker when it starts. */
+export const serverContext = {
+    /** The address the server listens at for API requests, as `host:port`. */
+    address: "localhost:8080",
+    /** The secret the server accepts from its own API on the admin routes. */
+    secret: "",
+};
+
 export const requestContext: {
     path: string;
     method: string;
     headers: Record<string, string>;
     apiVersion: string;
     userId?: string;
+    tenantId?: string;
 } = {
     path: "",
     method: "",
@@ -1143,13 +2005,54 @@ export type CRUDCreateResponses<
     [K in keyof CRUDMethods<T, E, P>]: CRUDCreateResponse;
 };
 
+/** A page of the entities matching a crud `url`. */
+type CRUDPage<T extends ChiselEntity> = {
+    results: T[];
+    next_page?: string;
+    prev_page?: string;
+    first_page: string;
+    /** Only there if `total_count` is. */
+    last_page?: string;
+    /** Number of entities on all pages, unless the `url` has `count=false`. */
+    total_count?: number;
+};
+
+/**
+ * Responds with the results of a crud `page`, as well as the links to the
+ * neighbouring pages. Those also go in a `Link` header, along with the ones
+ * to the first and last pages, and the total count goes in `X-Total-Count`.
+ */
+async function crudPageResponse<T extends ChiselEntity>(
+    page: CRUDPage<T>,
+    createResponse: CRUDCreateResponse,
+): Promise<Response> {
+    const { results, next_page, prev_page, first_page, last_page } = page;
+    const response = await createResponse(
+        { results, next_page, prev_page },
+        200,
+    );
+    const links = Object.entries({
+        next: next_page,
+        prev: prev_page,
+        first: first_page,
+        last: last_page,
+    })
+        .filter(([_, url]) => url !== undefined)
+        .map(([rel, url]) => `<${url}>; rel="${rel}"`);
+    response.headers.set("Link", links.join(", "));
+    if (page.total_count !== undefined) {
+        response.headers.set("X-Total-Count", `${page.total_count}`);
+    }
+    return response;
+}
+
 /**
  * Fetches crud data based on crud `url`.
  */
 async function fetchEntitiesCrud<T extends ChiselEntity>(
     type: { new (): T },
     url: string,
-): Promise<T[]> {
+): Promise<CRUDPage<T>> {
     const results = await opAsync(
         "op_chisel_crud_query",
         {
@@ -1158,7 +2061,49 @@ async function fetchEntitiesCrud<T extends ChiselEntity>(
         },
         requestContext,
     );
-    return results as T[];
+    return results as CRUDPage<T>;
+}
+
+/**
+ * Responds with all the entities matching crud `url` as a JSON array, which
+ * is written while they are read from the database, one at a time. There
+ * are no pages, so no page links or total count either.
+ */
+function crudStreamResponse<T extends ChiselEntity>(
+    type: { new (): T },
+    url: string,
+): Response {
+    const rid = opSync(
+        "op_chisel_crud_stream",
+        {
+            typeName: type.name,
+            url,
+        },
+        requestContext,
+    ) as number;
+    const encoder = new TextEncoder();
+    let separator = "[";
+    const body = new ReadableStream<Uint8Array>({
+        async pull(controller: ReadableStreamDefaultController) {
+            const row = await opAsync("op_chisel_query_next", rid);
+            if (row === null) {
+                const end = separator == "[" ? "[]" : "]";
+                controller.enqueue(encoder.encode(end));
+                controller.close();
+                Deno.core.tryClose(rid);
+                return;
+            }
+            controller.enqueue(encoder.encode(separator + JSON.stringify(row)));
+            separator = ",";
+        },
+        cancel() {
+            Deno.core.tryClose(rid);
+        },
+    });
+    return new Response(body, {
+        status: 200,
+        headers: [["content-type", "application/json"]],
+    });
 }
 
 async function deleteEntitiesCrud<T extends ChiselEntity>(
@@ -1178,6 +2123,7 @@ async function deleteEntitiesCrud<T extends ChiselEntity>(
 const defaultCrudMethods: CRUDMethods<ChiselEntity, GenericChiselEntityClass> =
     {
         // Returns a specific entity matching params.id (if present) or all entities matching the filter in the `filter` URL parameter.
+        // The latter come with page links in the Link header, and their total count in X-Total-Count unless the URL has `count=false`.
         GET: async (
             entity: GenericChiselEntityClass,
             _req: Request,
@@ -1189,10 +2135,12 @@ const defaultCrudMethods: CRUDMethods<ChiselEntity, GenericChiselEntityClass> =
             if (id) {
                 const u = await entity.findOne({ id });
                 return createResponse(u ?? "Not found", u ? 200 : 404);
+            } else if (url.searchParams.get("stream") === "true") {
+                return crudStreamResponse(entity, url.href);
             } else {
-                return createResponse(
+                return crudPageResponse(
                     await fetchEntitiesCrud(entity, url.href),
-                    200,
+                    createResponse,
                 );
             }
         },
@@ -88,8 +88,8 @@ function endMsgProcessing() {
     }
 }
 
-export async function initWorker(id: number) {
-    await toWorker({ cmd: "initWorker", id });
+export async function initWorker(id: number, serverAddress: string) {
+    await toWorker({ cmd: "initWorker", id, serverAddress });
 }
 
 export async function readWorkerChannel() {
@@ -53,8 +53,10 @@ async function handleMsg(func: () => unknown) {
     postMessage({ msg: "reply", value, err });
 }
 
-function initWorker(id: number) {
+function initWorker(id: number, serverAddress: string, secret: string) {
     handleMsg(() => {
+        Chisel.serverContext.address = serverAddress;
+        Chisel.serverContext.secret = secret;
         Deno.core.opSync("op_chisel_init_worker", id);
     });
 }
@@ -184,9 +186,10 @@ async function callHandlerImpl(
         sendBodyPart(undefined, id);
         return start.Special;
     }
-    const { userid, url, method, headers, body_rid } = start.Js;
+    const { userid, tenantid, url, method, headers, body_rid } = start.Js;
     requestContext.method = method;
     requestContext.userId = userid;
+    requestContext.tenantId = tenantid;
     requestContext.headers = headers;
 
     // FIXME: maybe defer creating the transaction until we need one, to avoid doing it for
@@ -262,7 +265,7 @@ onmessage = function (e) {
             readWorkerChannel();
             break;
         case "initWorker":
-            initWorker(d.id);
+            initWorker(d.id, d.serverAddress);
             break;
         case "importEndpoints":
             importEndpoints(d.endpoints);
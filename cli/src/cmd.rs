@@ -8,5 +8,18 @@ macro_rules! execute {
     }};
 }
 
+pub(crate) mod analyze;
 pub(crate) mod apply;
+pub(crate) mod auth;
+pub(crate) mod db;
+pub(crate) mod describe;
 pub(crate) mod dev;
+pub(crate) mod diff;
+pub(crate) mod doctor;
+pub(crate) mod lint;
+pub(crate) mod logs;
+pub(crate) mod migrate;
+pub(crate) mod perf;
+pub(crate) mod repl;
+pub(crate) mod snapshot;
+pub(crate) mod validate;
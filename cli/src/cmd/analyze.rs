@@ -0,0 +1,176 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel analyze`: the latency of the endpoints, from the access log.
+
+use crate::cmd::logs::read_events;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Endpoints whose 99th percentile latency is above this are shown in red.
+const SLOW_P99_MS: f64 = 500.;
+
+pub(crate) struct AnalyzeOptions<'a> {
+    /// The file chiseld writes with `--access-log`, or the recent access
+    /// events of the server when unset.
+    pub(crate) log_file: Option<&'a Path>,
+    /// How many endpoints to show.
+    pub(crate) top: usize,
+}
+
+/// One request, as the server logs it.
+struct Access {
+    route: String,
+    status: u64,
+    duration_ms: f64,
+}
+
+impl Access {
+    /// Reads the access event `event`. Requests that no route served are
+    /// grouped by their path.
+    fn parse(event: &serde_json::Value) -> Result<Self> {
+        let route = event["route"].as_str().or_else(|| event["path"].as_str());
+        Ok(Self {
+            route: route.context("access event without a path")?.to_owned(),
+            status: event["status"]
+                .as_u64()
+                .context("access event without a status")?,
+            duration_ms: event["duration_ms"]
+                .as_f64()
+                .context("access event without a duration")?,
+        })
+    }
+}
+
+struct EndpointStats {
+    route: String,
+    requests: usize,
+    p50: f64,
+    p95: f64,
+    p99: f64,
+    /// Share of the requests that got a server error, a 5xx status.
+    error_rate: f64,
+}
+
+/// The `p`th percentile of `sorted`, by the nearest-rank method.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100. * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+/// The statistics of each endpoint, slowest first.
+fn endpoint_stats(accesses: Vec<Access>) -> Vec<EndpointStats> {
+    let mut by_route: HashMap<String, Vec<Access>> = HashMap::new();
+    for access in accesses {
+        by_route
+            .entry(access.route.clone())
+            .or_default()
+            .push(access);
+    }
+    let mut stats: Vec<EndpointStats> = by_route
+        .into_iter()
+        .map(|(route, accesses)| {
+            let mut durations: Vec<f64> = accesses.iter().map(|a| a.duration_ms).collect();
+            durations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let errors = accesses.iter().filter(|a| a.status >= 500).count();
+            EndpointStats {
+                route,
+                requests: accesses.len(),
+                p50: percentile(&durations, 50.),
+                p95: percentile(&durations, 95.),
+                p99: percentile(&durations, 99.),
+                error_rate: errors as f64 / accesses.len() as f64,
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| {
+        b.p99
+            .partial_cmp(&a.p99)
+            .unwrap()
+            .then_with(|| a.route.cmp(&b.route))
+    });
+    stats
+}
+
+fn read_log_file(path: &Path) -> Result<Vec<Access>> {
+    let log = fs::read_to_string(path)
+        .with_context(|| format!("could not read the access log {}", path.display()))?;
+    let mut accesses = vec![];
+    for (number, line) in log.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: serde_json::Value = serde_json::from_str(line)
+            .with_context(|| format!("line {} of {} is not JSON", number + 1, path.display()))?;
+        accesses.push(Access::parse(&event)?);
+    }
+    Ok(accesses)
+}
+
+fn fetch_accesses(api_addr: &str, secret: &str) -> Result<Vec<Access>> {
+    let mut accesses = vec![];
+    read_events(api_addr, secret, "access=true&follow=false", |event| {
+        let message = event["message"].as_str().unwrap_or_default();
+        let access: serde_json::Value = serde_json::from_str(message)
+            .with_context(|| format!("the access event '{}' is not JSON", message))?;
+        accesses.push(Access::parse(&access)?);
+        Ok(())
+    })?;
+    Ok(accesses)
+}
+
+/// Analyzes the accesses in the log file of `opts`, or else those fetched from
+/// the server at `api_addr` with the admin `secret`.
+pub(crate) fn cmd_analyze(
+    api_addr: &str,
+    secret: Option<&str>,
+    opts: AnalyzeOptions,
+) -> Result<()> {
+    let accesses = match (opts.log_file, secret) {
+        (Some(path), _) => read_log_file(path)?,
+        (None, Some(secret)) => fetch_accesses(api_addr, secret)?,
+        (None, None) => bail!("the accesses can't be fetched without the admin secret"),
+    };
+    if accesses.is_empty() {
+        println!("No requests logged yet.");
+        return Ok(());
+    }
+    let mut stats = endpoint_stats(accesses);
+    stats.truncate(opts.top);
+
+    let colored = nix::unistd::isatty(nix::libc::STDOUT_FILENO).unwrap_or(false);
+    let width = stats.iter().map(|s| s.route.len()).max().unwrap_or(0);
+    let width = width.max("ENDPOINT".len());
+    println!(
+        "{:<width$}  {:>8}  {:>9}  {:>9}  {:>9}  {:>6}",
+        "ENDPOINT",
+        "REQUESTS",
+        "P50 (ms)",
+        "P95 (ms)",
+        "P99 (ms)",
+        "ERRORS",
+        width = width
+    );
+    for s in stats {
+        let row = format!(
+            "{:<width$}  {:>8}  {:>9.1}  {:>9.1}  {:>9.1}  {:>5.1}%",
+            s.route,
+            s.requests,
+            s.p50,
+            s.p95,
+            s.p99,
+            s.error_rate * 100.,
+            width = width
+        );
+        if colored && s.p99 > SLOW_P99_MS {
+            println!("{}{}{}", RED, row, RESET);
+        } else {
+            println!("{}", row);
+        }
+    }
+    Ok(())
+}
@@ -8,6 +8,7 @@ use crate::chisel::{ChiselApplyRequest, IndexCandidate, PolicyUpdateRequest};
 use crate::project::{read_manifest, read_to_string, AutoIndex, Module, Optimize};
 use anyhow::{anyhow, Context, Result};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::io::Write;
 use std::path::PathBuf;
 use std::process::Stdio;
@@ -61,14 +62,13 @@ impl From<bool> for TypeChecking {
     }
 }
 
-pub(crate) async fn apply<S: ToString>(
-    server_url: String,
-    version: S,
+/// The request applying the project as `version`, along with the sources of
+/// its endpoints, which [`apply`] sends separately.
+pub(crate) async fn apply_request(
+    version: String,
     allow_type_deletion: AllowTypeDeletion,
     type_check: TypeChecking,
-) -> Result<()> {
-    let version = version.to_string();
-
+) -> Result<(ChiselApplyRequest, HashMap<String, String>)> {
     let manifest = read_manifest().with_context(|| "Reading manifest file".to_string())?;
     let models = manifest.models()?;
     let endpoints = manifest.endpoints()?;
@@ -131,8 +131,7 @@ pub(crate) async fn apply<S: ToString>(
         None => version_tag,
     };
 
-    let mut client = ChiselRpcClient::connect(server_url.clone()).await?;
-    let mut req = ChiselApplyRequest {
+    let req = ChiselApplyRequest {
         types: types_req,
         sources: Default::default(),
         index_candidates: index_candidates_req,
@@ -141,7 +140,21 @@ pub(crate) async fn apply<S: ToString>(
         version,
         version_tag,
         app_name,
+        dry_run: false,
+        schemas: manifest.endpoint_schemas()?,
     };
+    Ok((req, endpoints_req))
+}
+
+pub(crate) async fn apply<S: ToString>(
+    server_url: String,
+    version: S,
+    allow_type_deletion: AllowTypeDeletion,
+    type_check: TypeChecking,
+) -> Result<()> {
+    let (mut req, endpoints_req) =
+        apply_request(version.to_string(), allow_type_deletion, type_check).await?;
+    let mut client = ChiselRpcClient::connect(server_url.clone()).await?;
 
     // According to the spec
     // (https://html.spec.whatwg.org/multipage/webappapis.html#module-map),
@@ -173,10 +186,14 @@ pub(crate) async fn apply<S: ToString>(
         println!("Policy defined for label {}", lbl);
     }
 
+    for warning in msg.warnings {
+        println!("Warning: {}", warning);
+    }
+
     Ok(())
 }
 
-fn parse_indexes(code: String, entities: &[String]) -> Result<Vec<IndexCandidate>> {
+pub(crate) fn parse_indexes(code: String, entities: &[String]) -> Result<Vec<IndexCandidate>> {
     let mut index_candidates_req = vec![];
     let indexes = chiselc_output(code, "filter-properties", entities)?;
     let indexes: Value = serde_json::from_str(&indexes)?;
@@ -215,7 +232,7 @@ fn chiselc_cmd() -> Result<PathBuf> {
     Ok(cmd)
 }
 
-fn is_chiselc_available() -> bool {
+pub(crate) fn is_chiselc_available() -> bool {
     let cmd = match chiselc_cmd() {
         Ok(cmd) => cmd,
         _ => return false,
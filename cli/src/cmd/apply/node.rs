@@ -138,7 +138,7 @@ pub(crate) async fn apply(
     Ok((endpoints_req, index_candidates_req))
 }
 
-fn npx(
+pub(crate) fn npx(
     command: &str,
     args: &[&str],
     stdin: Option<std::process::ChildStdout>,
@@ -0,0 +1,86 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel auth`: manages users for local development, without going
+//! through an OAuth provider, and the secret of the auth routes.
+
+use crate::chisel::chisel_rpc_client::ChiselRpcClient;
+use crate::chisel::{
+    AddUserRequest, AdminSecretRequest, DeleteUserRequest, ListUsersRequest, RotateSecretRequest,
+};
+use anyhow::{anyhow, Result};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum AuthCommand {
+    /// Create a user and print its id, to send in the `ChiselUID` header.
+    AddUser {
+        /// Name of the user, which policies see as its email.
+        username: String,
+    },
+    /// List the users, by name.
+    ListUsers,
+    /// Delete a user, with its sessions and accounts.
+    DeleteUser { username: String },
+    /// Replace the secret of the auth and admin routes, and print the new one.
+    /// The previous secret is still accepted for 24 hours.
+    RotateSecret {
+        /// The new secret, in hex. A random one is generated otherwise.
+        #[structopt(long)]
+        new_secret: Option<String>,
+    },
+}
+
+/// Gets the secret that the admin routes of the server at `server_url` accept
+/// in the `ChiselAuth` header, whether an auth secret is configured or not.
+pub(crate) async fn admin_secret(server_url: String) -> Result<String> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let response = execute!(
+        client
+            .admin_secret(tonic::Request::new(AdminSecretRequest {}))
+            .await
+    );
+    Ok(response.secret)
+}
+
+pub(crate) async fn cmd_auth(server_url: String, cmd: AuthCommand) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    match cmd {
+        AuthCommand::AddUser { username } => {
+            let response = execute!(
+                client
+                    .add_user(tonic::Request::new(AddUserRequest { username }))
+                    .await
+            );
+            println!("{}", response.user_id);
+        }
+        AuthCommand::ListUsers => {
+            let response = execute!(
+                client
+                    .list_users(tonic::Request::new(ListUsersRequest {}))
+                    .await
+            );
+            for user in &response.users {
+                println!("{}\t{}", user.username, user.id);
+            }
+        }
+        AuthCommand::DeleteUser { username } => {
+            execute!(
+                client
+                    .delete_user(tonic::Request::new(DeleteUserRequest {
+                        username: username.clone()
+                    }))
+                    .await
+            );
+            println!("Deleted user {}.", username);
+        }
+        AuthCommand::RotateSecret { new_secret } => {
+            let response = execute!(
+                client
+                    .rotate_secret(tonic::Request::new(RotateSecretRequest { new_secret }))
+                    .await
+            );
+            println!("{}", response.secret);
+        }
+    }
+    Ok(())
+}
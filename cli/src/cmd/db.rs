@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel psql` and `chisel sqlite3`: open the command-line client of the
+//! database the server uses.
+
+use crate::project::read_manifest;
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Environment variable naming the database, over the manifest's `database_url`.
+const DATABASE_URL_VAR: &str = "CHISEL_DATABASE_URL";
+
+/// The database of a server started without `--db-uri`, relative to where it runs.
+const DEFAULT_DATABASE_URL: &str = "sqlite://.chiseld.db?mode=rwc";
+
+pub(crate) enum Client {
+    Psql,
+    Sqlite3,
+}
+
+/// The database URL set by [`DATABASE_URL_VAR`] or by the manifest, if any.
+pub(crate) fn database_url() -> Option<String> {
+    env::var(DATABASE_URL_VAR)
+        .ok()
+        .or_else(|| read_manifest().ok()?.database_url)
+}
+
+/// Decodes the `%XX` escapes of a URL component.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' && tail.len() >= 2 {
+            let hex = std::str::from_utf8(&tail[..2])?;
+            bytes.push(u8::from_str_radix(hex, 16).context("malformed % escape in URL")?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// `psql` connecting to `url`. The password goes in `PGPASSWORD` so that it
+/// doesn't show in the process list, and the errors don't echo the URL for
+/// the same reason.
+fn psql(url: &str) -> Result<Command> {
+    let rest = url
+        .strip_prefix("postgres://")
+        .or_else(|| url.strip_prefix("postgresql://"))
+        .ok_or_else(|| anyhow!("the database is not PostgreSQL, try `chisel sqlite3`"))?;
+    let authority = rest.split(&['/', '?'][..]).next().unwrap_or_default();
+    let mut cmd = Command::new("psql");
+    let url = match authority.rsplit_once('@') {
+        Some((user_info, host)) => match user_info.split_once(':') {
+            Some((user, password)) => {
+                cmd.env("PGPASSWORD", percent_decode(password)?);
+                format!("postgres://{}@{}{}", user, host, &rest[authority.len()..])
+            }
+            None => url.to_owned(),
+        },
+        None => url.to_owned(),
+    };
+    cmd.arg(url);
+    Ok(cmd)
+}
+
+/// `sqlite3` opening the database file of `url`.
+fn sqlite3(url: &str) -> Result<Command> {
+    let path = match url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+    {
+        Some(rest) => rest.split('?').next().unwrap_or_default(),
+        None => bail!("the database is not SQLite, try `chisel psql`"),
+    };
+    if !Path::new(path).exists() {
+        bail!(
+            "{} doesn't exist; is the server running in this directory?",
+            path
+        );
+    }
+    let mut cmd = Command::new("sqlite3");
+    cmd.arg(path);
+    Ok(cmd)
+}
+
+/// Runs `client` on the database of the project, with the extra `args`, and
+/// exits with its status.
+pub(crate) fn cmd_db(client: Client, args: Vec<String>) -> Result<()> {
+    let url = database_url().unwrap_or_else(|| DEFAULT_DATABASE_URL.to_owned());
+    let (program, mut cmd) = match client {
+        Client::Psql => ("psql", psql(&url)?),
+        Client::Sqlite3 => ("sqlite3", sqlite3(&url)?),
+    };
+    let status = cmd
+        .args(args)
+        .status()
+        .with_context(|| format!("could not run {}; is it installed?", program))?;
+    std::process::exit(status.code().unwrap_or(1));
+}
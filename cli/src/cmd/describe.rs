@@ -0,0 +1,159 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel describe TYPE`: print the columns, indexes and references of a
+//! type, the way `psql` prints `\d table`.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// Fetches the description of `type_name` from the admin routes, which take `secret`.
+fn fetch(api_addr: &str, secret: &str, version: &str, type_name: &str) -> Result<Value> {
+    let mut stream = TcpStream::connect(api_addr)
+        .with_context(|| format!("could not connect to the server at {}", api_addr))?;
+    write!(
+        stream,
+        "GET /__chiselstrike/admin/types/{}/{} HTTP/1.0\r\nHost: {}\r\nChiselAuth: {}\r\n\r\n",
+        version, type_name, api_addr, secret
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default();
+    let body: Value = match serde_json::from_str(body) {
+        Ok(body) => body,
+        Err(_) => bail!(
+            "could not describe {}: {}\n{}",
+            type_name,
+            status,
+            body.trim()
+        ),
+    };
+    // The admin routes wrap their responses in an envelope.
+    let data = body.get("data").cloned().unwrap_or(body);
+    if let Some(error) = data["error"].as_str() {
+        bail!("could not describe {}: {}", type_name, error);
+    }
+    Ok(data)
+}
+
+/// Lays out `rows` under `header` in aligned columns.
+fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<_> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!(" {:width$} ", cell, width = width))
+            .collect();
+        cells.join("|").trim_end().to_owned()
+    };
+    let mut out = line(header.to_vec());
+    out.push('\n');
+    let rule: Vec<_> = widths.iter().map(|w| "-".repeat(w + 2)).collect();
+    out += &rule.join("+");
+    for row in rows {
+        out.push('\n');
+        out += &line(row.iter().map(String::as_str).collect());
+    }
+    out
+}
+
+fn strings(value: &Value) -> Vec<&str> {
+    value
+        .as_array()
+        .map(|a| a.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+fn print_description(ty: &Value) {
+    let text = |v: &Value| v.as_str().unwrap_or_default().to_owned();
+    println!(
+        "Type \"{}\" of version {}, in table \"{}\"",
+        text(&ty["name"]),
+        text(&ty["version"]),
+        text(&ty["table"])
+    );
+    if let Some(parent) = ty["parent"].as_str() {
+        println!("Extends {}", parent);
+    }
+    let rows: Vec<_> = ty["fields"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|f| {
+            vec![
+                text(&f["name"]),
+                text(&f["type"]),
+                if f["nullable"] == true {
+                    ""
+                } else {
+                    "not null"
+                }
+                .to_owned(),
+                text(&f["default"]),
+                if f["unique"] == true { "unique" } else { "" }.to_owned(),
+                strings(&f["indexes"]).join(", "),
+            ]
+        })
+        .collect();
+    println!(
+        "{}",
+        table(
+            &["Field", "Type", "Nullable", "Default", "Unique", "Indexes"],
+            &rows
+        )
+    );
+    let sections = [
+        ("Indexes", &ty["indexes"]),
+        ("Unique constraints", &ty["unique_constraints"]),
+    ];
+    for (title, entries) in sections {
+        let entries = entries.as_array().cloned().unwrap_or_default();
+        if entries.is_empty() {
+            continue;
+        }
+        println!("{}:", title);
+        for e in entries {
+            println!(
+                "    \"{}\" ({})",
+                text(&e["name"]),
+                strings(&e["fields"]).join(", ")
+            );
+        }
+    }
+    let foreign_keys = ty["foreign_keys"].as_array().cloned().unwrap_or_default();
+    if !foreign_keys.is_empty() {
+        println!("Foreign keys:");
+        for fk in foreign_keys {
+            println!(
+                "    \"{}\" REFERENCES {}(id) in table \"{}\"",
+                text(&fk["field"]),
+                text(&fk["references"]),
+                text(&fk["table"])
+            );
+        }
+    }
+}
+
+pub(crate) fn cmd_describe_type(
+    api_addr: &str,
+    secret: &str,
+    version: &str,
+    type_name: &str,
+    json: bool,
+) -> Result<()> {
+    let ty = fetch(api_addr, secret, version, type_name)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&ty)?);
+    } else {
+        print_description(&ty);
+    }
+    Ok(())
+}
@@ -1,13 +1,13 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::cmd::apply::{apply, AllowTypeDeletion, TypeChecking};
-use crate::project::read_manifest;
+use crate::project::{read_manifest, Manifest};
 use crate::server::{start_server, wait};
 use crate::DEFAULT_API_VERSION;
 use anyhow::Result;
 use deno_core::futures;
 use endpoint_tsc::tsc_compile;
-use futures::channel::mpsc::channel;
+use futures::channel::mpsc::{channel, Receiver};
 use futures::{FutureExt, SinkExt, StreamExt};
 use notify::{event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use std::collections::HashSet;
@@ -36,33 +36,8 @@ pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()>
     let mut server = start_server()?;
     wait(server_url.clone()).await?;
     apply_from_dev(server_url.clone(), type_check).await;
-    let (mut watcher_tx, mut watcher_rx) = channel(1);
-    let mut apply_watcher = RecommendedWatcher::new(move |res: Result<Event, notify::Error>| {
-        futures::executor::block_on(async {
-            watcher_tx.send(res).await.unwrap();
-        });
-    })?;
-    let watcher_config = notify::Config::OngoingEvents(Some(Duration::from_millis(100)));
-    apply_watcher.configure(watcher_config.clone())?;
-
-    let mut tracked = HashSet::new();
-    let cwd = std::env::current_dir()?;
-
-    for dir in &manifest.models {
-        let dir = cwd.join(dir);
-        tracked.insert(dir);
-    }
-
-    for dir in &manifest.policies {
-        let dir = cwd.join(dir);
-        tracked.insert(dir);
-    }
-
-    for dir in &manifest.endpoints {
-        let dir = cwd.join(dir);
-        tracked.insert(dir);
-    }
-    apply_watcher.watch(&cwd, RecursiveMode::Recursive)?;
+    let (_watcher, mut watcher_rx) = watch_project()?;
+    let tracked = tracked_dirs(&manifest)?;
 
     loop {
         futures::select! {
@@ -70,29 +45,12 @@ pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()>
                 break;
             }
             res = watcher_rx.next().fuse() => {
-                let res = res.unwrap();
-                match res {
-                    Ok(Event {
-                        kind: EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_)),
-                        paths,
-                        ..
-                    }) => {
-                        let is_tracked = |x: &PathBuf| {
-                            for p in tracked.iter() {
-                                if x.starts_with(p) {
-                                    return !crate::project::ignore_path(x.to_str().unwrap());
-                                }
-                            }
-                            false
-                        };
-
-                        let paths: HashSet<PathBuf> =
-                            HashSet::from_iter(paths.into_iter().filter(is_tracked));
-                                if !paths.is_empty() {
+                match res.unwrap() {
+                    Ok(event) => {
+                        if touches_project(&event, &tracked) {
                             apply_from_dev(server_url.clone(), type_check).await;
                         }
                     }
-                    Ok(_) => { /* ignore */ }
                     Err(e) => eprintln!("watch error: {:?}", e),
                 }
             }
@@ -105,6 +63,49 @@ pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()>
     Ok(())
 }
 
+/// What happens in a watched directory.
+pub(crate) type WatchEvents = Receiver<Result<Event, notify::Error>>;
+
+/// Watches the current directory, sending what happens in it to the returned
+/// receiver for as long as the returned watcher lives.
+pub(crate) fn watch_project() -> Result<(RecommendedWatcher, WatchEvents)> {
+    let (mut watcher_tx, watcher_rx) = channel(1);
+    let mut watcher = RecommendedWatcher::new(move |res: Result<Event, notify::Error>| {
+        futures::executor::block_on(async {
+            watcher_tx.send(res).await.unwrap();
+        });
+    })?;
+    let watcher_config = notify::Config::OngoingEvents(Some(Duration::from_millis(100)));
+    watcher.configure(watcher_config)?;
+    watcher.watch(&std::env::current_dir()?, RecursiveMode::Recursive)?;
+    Ok((watcher, watcher_rx))
+}
+
+/// The directories holding the models, policies and endpoints of `manifest`.
+pub(crate) fn tracked_dirs(manifest: &Manifest) -> Result<HashSet<PathBuf>> {
+    let cwd = std::env::current_dir()?;
+    Ok(manifest
+        .models
+        .iter()
+        .chain(&manifest.policies)
+        .chain(&manifest.endpoints)
+        .map(|dir| cwd.join(dir))
+        .collect())
+}
+
+/// Whether `event` changes a file in the `tracked` directories.
+pub(crate) fn touches_project(event: &Event, tracked: &HashSet<PathBuf>) -> bool {
+    match event.kind {
+        EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_)) => {
+            event.paths.iter().any(|x| {
+                tracked.iter().any(|p| x.starts_with(p))
+                    && !crate::project::ignore_path(x.to_str().unwrap())
+            })
+        }
+        _ => false,
+    }
+}
+
 async fn apply_from_dev(server_url: String, type_check: TypeChecking) {
     if let Err(e) = apply(
         server_url,
@@ -0,0 +1,221 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel diff`: shows what `chisel apply` would change in the schema of
+//! the running server.
+
+use crate::chisel::FieldDefinition;
+use crate::cmd::apply::{is_chiselc_available, parse_indexes};
+use crate::cmd::validate::describe_version;
+use crate::project::{read_manifest, read_to_string, AutoIndex};
+use crate::ts::{field_to_ts, parse_types};
+use anyhow::Result;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// A type's fields, as the lines declaring them, and its indexes.
+#[derive(Default)]
+struct Schema {
+    fields: BTreeMap<String, String>,
+    indexes: BTreeSet<Vec<String>>,
+}
+
+fn field_map(fields: &[FieldDefinition]) -> BTreeMap<String, String> {
+    fields
+        .iter()
+        .map(|f| (f.name.clone(), field_to_ts(f)))
+        .collect()
+}
+
+#[derive(Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+enum Change {
+    CreateType {
+        name: String,
+    },
+    DropType {
+        name: String,
+    },
+    AddField {
+        type_name: String,
+        field: String,
+    },
+    DropField {
+        type_name: String,
+        field: String,
+    },
+    ChangeField {
+        type_name: String,
+        from: String,
+        to: String,
+    },
+    AddIndex {
+        type_name: String,
+        fields: Vec<String>,
+    },
+    DropIndex {
+        type_name: String,
+        fields: Vec<String>,
+    },
+}
+
+impl Change {
+    fn prefix(&self) -> char {
+        match self {
+            Change::CreateType { .. } | Change::AddField { .. } | Change::AddIndex { .. } => '+',
+            Change::DropType { .. } | Change::DropField { .. } | Change::DropIndex { .. } => '-',
+            Change::ChangeField { .. } => '~',
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self.prefix() {
+            '+' => GREEN,
+            '-' => RED,
+            _ => YELLOW,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.prefix())?;
+        match self {
+            Change::CreateType { name } | Change::DropType { name } => write!(f, "type {}", name),
+            Change::AddField { type_name, field } | Change::DropField { type_name, field } => {
+                write!(f, "{}.{}", type_name, field)
+            }
+            Change::ChangeField {
+                type_name,
+                from,
+                to,
+            } => write!(f, "{}.{} -> {}", type_name, from, to),
+            Change::AddIndex { type_name, fields } | Change::DropIndex { type_name, fields } => {
+                write!(f, "index {}({})", type_name, fields.join(", "))
+            }
+        }
+    }
+}
+
+/// The changes that take `server` to `local`, types in alphabetical order.
+fn diff(server: &BTreeMap<String, Schema>, local: &BTreeMap<String, Schema>) -> Vec<Change> {
+    let empty = Schema::default();
+    let mut changes = vec![];
+    let names: BTreeSet<_> = server.keys().chain(local.keys()).collect();
+    for name in names {
+        let type_name = name.to_owned();
+        let (from, to) = match (server.get(name), local.get(name)) {
+            (Some(from), Some(to)) => (from, to),
+            (None, Some(to)) => {
+                changes.push(Change::CreateType { name: type_name });
+                (&empty, to)
+            }
+            (Some(_), None) => {
+                changes.push(Change::DropType { name: type_name });
+                continue;
+            }
+            (None, None) => unreachable!(),
+        };
+        for (field, line) in &from.fields {
+            match to.fields.get(field) {
+                None => changes.push(Change::DropField {
+                    type_name: name.clone(),
+                    field: line.clone(),
+                }),
+                Some(new_line) if new_line != line => changes.push(Change::ChangeField {
+                    type_name: name.clone(),
+                    from: line.clone(),
+                    to: new_line.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for (field, line) in &to.fields {
+            if !from.fields.contains_key(field) {
+                changes.push(Change::AddField {
+                    type_name: name.clone(),
+                    field: line.clone(),
+                });
+            }
+        }
+        for fields in from.indexes.difference(&to.indexes) {
+            changes.push(Change::DropIndex {
+                type_name: name.clone(),
+                fields: fields.clone(),
+            });
+        }
+        for fields in to.indexes.difference(&from.indexes) {
+            changes.push(Change::AddIndex {
+                type_name: name.clone(),
+                fields: fields.clone(),
+            });
+        }
+    }
+    changes
+}
+
+/// The schema the project would have once applied. Indexes are only known
+/// when `chiselc` is around to find them, as in `chisel apply`.
+fn local_schema() -> Result<BTreeMap<String, Schema>> {
+    let manifest = read_manifest()?;
+    let mut schema: BTreeMap<String, Schema> = parse_types(&manifest.models()?)?
+        .into_iter()
+        .map(|ty| {
+            let fields = field_map(&ty.field_defs);
+            let schema = Schema {
+                fields,
+                ..Default::default()
+            };
+            (ty.name, schema)
+        })
+        .collect();
+    if is_chiselc_available() && manifest.auto_index == AutoIndex::Yes {
+        let entities: Vec<String> = schema.keys().cloned().collect();
+        for endpoint in manifest.endpoints()? {
+            for candidate in parse_indexes(read_to_string(endpoint)?, &entities)? {
+                if let Some(ty) = schema.get_mut(&candidate.entity_name) {
+                    ty.indexes.insert(candidate.properties);
+                }
+            }
+        }
+    }
+    Ok(schema)
+}
+
+pub(crate) async fn cmd_diff(server_url: String, version: String, json: bool) -> Result<()> {
+    let local = local_schema()?;
+    let mut server = BTreeMap::new();
+    if let Some(version_def) = describe_version(server_url, &version).await? {
+        for ty in version_def.type_defs {
+            let schema = Schema {
+                fields: field_map(&ty.field_defs),
+                indexes: ty.index_defs.into_iter().map(|i| i.fields).collect(),
+            };
+            server.insert(ty.name, schema);
+        }
+    }
+    let changes = diff(&server, &local);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+        return Ok(());
+    }
+    if changes.is_empty() {
+        println!("No pending changes to version {}.", version);
+        return Ok(());
+    }
+    let colored = nix::unistd::isatty(nix::libc::STDOUT_FILENO).unwrap_or(false);
+    for change in &changes {
+        if colored {
+            println!("{}{}{}", change.color(), change, RESET);
+        } else {
+            println!("{}", change);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,275 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel doctor`: looks for the usual reasons a project doesn't work, from
+//! missing tools to a server that runs something other than the project.
+
+use crate::chisel::chisel_rpc_client::ChiselRpcClient;
+use crate::chisel::StatusRequest;
+use crate::cmd::validate::validate;
+use crate::project::{read_manifest, MANIFEST_FILE};
+use anyhow::Result;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// How long to wait for the internal routes of the server to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A check that didn't pass: what is wrong and how to fix it.
+struct Problem {
+    what: String,
+    fix: String,
+}
+
+/// What a check found: a description of what is fine, or the problem.
+type Outcome = std::result::Result<String, Problem>;
+
+fn problem<W: ToString, F: ToString>(what: W, fix: F) -> Problem {
+    Problem {
+        what: what.to_string(),
+        fix: fix.to_string(),
+    }
+}
+
+/// Checks that `tool --version` reports at least `min_major`.
+fn tool_version(tool: &str, min_major: u64, fix: &str) -> Outcome {
+    let output = match Command::new(tool).arg("--version").output() {
+        Ok(output) if output.status.success() => output,
+        _ => return Err(problem(format!("{} was not found", tool), fix)),
+    };
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    let major = version
+        .trim_start_matches('v')
+        .split('.')
+        .next()
+        .and_then(|major| major.parse::<u64>().ok());
+    match major {
+        Some(major) if major >= min_major => Ok(format!("{} {}", tool, version)),
+        _ => Err(problem(
+            format!(
+                "{} {} is too old, version {} or later is needed",
+                tool, version, min_major
+            ),
+            fix,
+        )),
+    }
+}
+
+fn manifest() -> Outcome {
+    if !Path::new(MANIFEST_FILE).exists() {
+        return Err(problem(
+            format!("there is no {} in the current directory", MANIFEST_FILE),
+            "run `chisel init`, or run `chisel doctor` from the directory of the project",
+        ));
+    }
+    match read_manifest() {
+        Ok(_) => Ok(format!("{} is valid", MANIFEST_FILE)),
+        Err(e) => Err(problem(
+            format!("{:#}", e),
+            format!("fix {} so that it is valid TOML", MANIFEST_FILE),
+        )),
+    }
+}
+
+/// The file a location the server reads secrets from points to, if it is
+/// a file and not some other kind of URL.
+fn location_path(location: &str) -> Option<&str> {
+    match location.split_once("://") {
+        Some(("file", path)) => Some(path),
+        Some(_) => None,
+        None => Some(location),
+    }
+}
+
+/// Checks the environment variables that tell the server where to find the
+/// secrets. They are optional, but the server refuses to start if they are
+/// wrong.
+fn environment() -> Outcome {
+    let key = env::var("CHISEL_SECRET_KEY_LOCATION").ok();
+    if let Some(key) = &key {
+        if let Some(path) = location_path(key) {
+            if !Path::new(path).exists() {
+                return Err(problem(
+                    format!("CHISEL_SECRET_KEY_LOCATION is {}, which doesn't exist", key),
+                    "point CHISEL_SECRET_KEY_LOCATION to the PEM file of the key the secrets are encrypted with, or unset it",
+                ));
+            }
+        }
+    }
+    let secrets = match env::var("CHISEL_SECRET_LOCATION") {
+        Ok(secrets) if !secrets.contains("://") => {
+            return Err(problem(
+                format!("CHISEL_SECRET_LOCATION is {}, which is not a URL", secrets),
+                "set CHISEL_SECRET_LOCATION to a URL like file:///path/to/secrets, or unset it to read the secrets from .env",
+            ));
+        }
+        Ok(secrets) => secrets,
+        Err(_) => ".env".to_string(),
+    };
+    // Encrypted secrets can't be looked into without the key.
+    if key.is_none() {
+        if let Some(path) = location_path(&secrets) {
+            if let Ok(data) = std::fs::read_to_string(path) {
+                if let Err(e) = serde_json::from_str::<serde_json::Value>(&data) {
+                    return Err(problem(
+                        format!("the secrets in {} are not valid JSON: {}", path, e),
+                        format!("write the secrets in {} as a JSON object", path),
+                    ));
+                }
+            }
+        }
+    }
+    Ok("the environment variables are valid".to_string())
+}
+
+async fn server(server_url: String) -> Outcome {
+    let fix = "start the server with `chisel dev`, or pass its address with --rpc-addr";
+    let mut client = match ChiselRpcClient::connect(server_url.clone()).await {
+        Ok(client) => client,
+        Err(_) => return Err(problem(format!("no server answers at {}", server_url), fix)),
+    };
+    match client
+        .get_status(tonic::Request::new(StatusRequest {}))
+        .await
+    {
+        Ok(response) if response.get_ref().message == "OK" => {
+            Ok(format!("the server at {} is running", server_url))
+        }
+        Ok(response) => Err(problem(
+            format!(
+                "the server at {} reports {}",
+                server_url,
+                response.get_ref().message
+            ),
+            "restart the server with `chisel restart`",
+        )),
+        Err(e) => Err(problem(
+            format!(
+                "the server at {} doesn't answer: {}",
+                server_url,
+                e.message()
+            ),
+            fix,
+        )),
+    }
+}
+
+/// Asks the readiness probe of the server at `internal_addr`, which checks
+/// the database, and returns the status line and body of its response.
+fn readiness(internal_addr: &str) -> std::io::Result<(String, String)> {
+    let addr = internal_addr.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no address to connect to")
+    })?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "GET /readiness HTTP/1.0\r\nHost: {}\r\n\r\n",
+        internal_addr
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default().to_owned();
+    Ok((status, body.to_owned()))
+}
+
+fn database(internal_addr: &str) -> Outcome {
+    let (status, body) = match readiness(internal_addr) {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(problem(
+                format!(
+                    "nothing serves the internal routes at {}: {}",
+                    internal_addr, e
+                ),
+                "pass the --internal-routes-listen-addr of the server with --internal-addr",
+            ))
+        }
+    };
+    if status.contains(" 200 ") {
+        return Ok("the server can reach its database".to_string());
+    }
+    let error = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["error"].as_str().map(str::to_owned))
+        .unwrap_or(status);
+    Err(problem(
+        format!("the server can't reach its database: {}", error),
+        "check the --db-uri the server runs with, and that the database accepts connections",
+    ))
+}
+
+async fn schema(server_url: String, version: &str) -> Outcome {
+    match validate(server_url, version).await {
+        Ok(diff) if diff.is_empty() => Ok(format!(
+            "the server's version {} matches the project",
+            version
+        )),
+        Ok(_) => Err(problem(
+            format!("the server's version {} and the project diverge", version),
+            "run `chisel apply`, or `chisel validate` to see the differences",
+        )),
+        Err(e) => Err(problem(
+            format!("{:#}", e),
+            "fix the models and endpoints of the project",
+        )),
+    }
+}
+
+/// Prints what checks find as they run, and counts the problems.
+#[derive(Default)]
+struct Report {
+    checks: usize,
+    problems: usize,
+}
+
+impl Report {
+    fn add(&mut self, outcome: Outcome) -> bool {
+        self.checks += 1;
+        match outcome {
+            Ok(what) => {
+                println!("✓ {}", what);
+                true
+            }
+            Err(Problem { what, fix }) => {
+                self.problems += 1;
+                println!("✗ {}\n  fix: {}", what, fix);
+                false
+            }
+        }
+    }
+}
+
+pub(crate) async fn cmd_doctor(
+    server_url: String,
+    internal_addr: String,
+    version: String,
+) -> Result<()> {
+    let mut report = Report::default();
+    report.add(tool_version(
+        "node",
+        16,
+        "install Node.js 16 or later from https://nodejs.org",
+    ));
+    report.add(tool_version("npm", 8, "run `npm install -g npm@8`"));
+    let has_project = report.add(manifest());
+    report.add(environment());
+    let has_server = report.add(server(server_url.clone()).await);
+    report.add(database(&internal_addr));
+    if has_project && has_server {
+        report.add(schema(server_url, &version).await);
+    } else {
+        println!("- the schema was not checked, as that needs both the project and the server");
+    }
+    anyhow::ensure!(
+        report.problems == 0,
+        "{} of {} checks failed",
+        report.problems,
+        report.checks
+    );
+    Ok(())
+}
@@ -0,0 +1,347 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel lint`: catches mistakes in endpoints that would otherwise only
+//! show up when they run.
+//!
+//! The TypeScript compiler does the type checking, and the checks here add
+//! what it can't know about ChiselStrike: that the default export is the
+//! handler, that entity operations return promises, and so on.
+
+use crate::cmd::apply::node::npx;
+use crate::project::{read_manifest, Module};
+use crate::ts::{error_handler, parse_module};
+use anyhow::{anyhow, Result};
+use endpoint_tsc::compile_endpoints;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use swc_common::comments::SingleThreadedComments;
+use swc_common::sync::Lrc;
+use swc_common::{SourceMap, Span};
+use swc_ecmascript::ast::{
+    AwaitExpr, CallExpr, ClassDecl, DefaultDecl, ExportSpecifier, Expr, ExprStmt, FnDecl,
+    ImportSpecifier, MemberProp, ModuleDecl, ModuleExportName, ModuleItem, NamedExport, Pat,
+    TryStmt, TsEntityName, TsTypeRef, VarDeclarator,
+};
+use swc_ecmascript::visit::{Visit, VisitWith};
+
+/// Methods of entities and cursors that return a promise, which is lost
+/// if the call is a statement of its own.
+const ASYNC_METHODS: &[&str] = &[
+    "save", "delete", "findOne", "findMany", "findAll", "toArray", "forEach",
+];
+
+#[derive(Debug, PartialEq)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+struct Diagnostic {
+    severity: Severity,
+    path: PathBuf,
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.path.display(),
+            self.line,
+            severity,
+            self.message
+        )
+    }
+}
+
+/// What a walk over an endpoint finds.
+struct EndpointVisitor<'a> {
+    models: &'a BTreeSet<String>,
+    /// Names imported or declared in the module.
+    declared: BTreeSet<String>,
+    /// Models referred to, with where they are first used.
+    used_models: BTreeMap<String, Span>,
+    /// Calls to [`ASYNC_METHODS`] whose promise is dropped.
+    floating: Vec<(String, Span)>,
+    awaits: usize,
+    error_handlers: usize,
+}
+
+impl<'a> EndpointVisitor<'a> {
+    fn new(models: &'a BTreeSet<String>) -> Self {
+        Self {
+            models,
+            declared: Default::default(),
+            used_models: Default::default(),
+            floating: vec![],
+            awaits: 0,
+            error_handlers: 0,
+        }
+    }
+
+    fn use_name(&mut self, name: &str, span: Span) {
+        if self.models.contains(name) {
+            self.used_models.entry(name.to_owned()).or_insert(span);
+        }
+    }
+}
+
+impl<'a> Visit for EndpointVisitor<'a> {
+    fn visit_import_specifier(&mut self, n: &ImportSpecifier) {
+        let local = match n {
+            ImportSpecifier::Named(s) => &s.local,
+            ImportSpecifier::Default(s) => &s.local,
+            ImportSpecifier::Namespace(s) => &s.local,
+        };
+        self.declared.insert(local.sym.to_string());
+    }
+
+    fn visit_class_decl(&mut self, n: &ClassDecl) {
+        self.declared.insert(n.ident.sym.to_string());
+        n.visit_children_with(self);
+    }
+
+    fn visit_fn_decl(&mut self, n: &FnDecl) {
+        self.declared.insert(n.ident.sym.to_string());
+        n.visit_children_with(self);
+    }
+
+    fn visit_var_declarator(&mut self, n: &VarDeclarator) {
+        if let Pat::Ident(id) = &n.name {
+            self.declared.insert(id.id.sym.to_string());
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_expr(&mut self, n: &Expr) {
+        if let Expr::Ident(id) = n {
+            self.use_name(&id.sym, id.span);
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_ts_type_ref(&mut self, n: &TsTypeRef) {
+        if let TsEntityName::Ident(id) = &n.type_name {
+            self.use_name(&id.sym, id.span);
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_expr_stmt(&mut self, n: &ExprStmt) {
+        if let Expr::Call(call) = &*n.expr {
+            if let Some(method) = called_method(call) {
+                if ASYNC_METHODS.contains(&method.as_str()) {
+                    self.floating.push((method, call.span));
+                }
+            }
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_call_expr(&mut self, n: &CallExpr) {
+        if called_method(n).as_deref() == Some("catch") {
+            self.error_handlers += 1;
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_await_expr(&mut self, n: &AwaitExpr) {
+        self.awaits += 1;
+        n.visit_children_with(self);
+    }
+
+    fn visit_try_stmt(&mut self, n: &TryStmt) {
+        self.error_handlers += 1;
+        n.visit_children_with(self);
+    }
+}
+
+/// The name of the method `call` calls, if it's a method call.
+fn called_method(call: &CallExpr) -> Option<String> {
+    match &**call.callee.as_expr()? {
+        Expr::Member(member) => match &member.prop {
+            MemberProp::Ident(id) => Some(id.sym.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `export` is an `export { handler as default }`.
+fn exports_default(export: &NamedExport) -> bool {
+    export.specifiers.iter().any(|s| match s {
+        ExportSpecifier::Named(s) => {
+            matches!(&s.exported, Some(ModuleExportName::Ident(id)) if &*id.sym == "default")
+        }
+        _ => false,
+    })
+}
+
+/// Why `expr` can't be the default export of an endpoint, if it can't.
+/// Calls and names are given the benefit of the doubt, as in
+/// `export default Post.crud()`.
+fn not_a_handler(expr: &Expr) -> Option<&'static str> {
+    match expr {
+        Expr::Arrow(_) | Expr::Fn(_) | Expr::Call(_) | Expr::Ident(_) | Expr::Member(_) => None,
+        Expr::Paren(paren) => not_a_handler(&paren.expr),
+        Expr::TsAs(as_expr) => not_a_handler(&as_expr.expr),
+        Expr::Class(_) => Some("a class"),
+        Expr::Object(_) => Some("an object"),
+        _ => Some("not a function"),
+    }
+}
+
+fn lint_endpoint(path: &Path, models: &BTreeSet<String>) -> Result<Vec<Diagnostic>> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let handler = error_handler(&cm);
+    let comments = SingleThreadedComments::default();
+    let module = parse_module(&cm, &handler, &comments, &path)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    let mut diagnostics = vec![];
+    let mut report = |severity, span: Span, message: String| {
+        diagnostics.push(Diagnostic {
+            severity,
+            path: path.to_owned(),
+            line: cm.lookup_char_pos(span.lo).line,
+            message,
+        });
+    };
+
+    let mut default_export = None;
+    for item in &module.body {
+        let (span, problem) = match item {
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultDecl(decl)) => match &decl.decl {
+                DefaultDecl::Fn(_) => (decl.span, None),
+                DefaultDecl::Class(_) => (decl.span, Some("a class")),
+                DefaultDecl::TsInterfaceDecl(_) => (decl.span, Some("an interface")),
+            },
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultExpr(expr)) => {
+                (expr.span, not_a_handler(&expr.expr))
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportNamed(export)) if exports_default(export) => {
+                (export.span, None)
+            }
+            _ => continue,
+        };
+        if let Some(problem) = problem {
+            report(
+                Severity::Error,
+                span,
+                format!(
+                    "the default export must be the handler function, but it's {}",
+                    problem
+                ),
+            );
+        }
+        default_export = Some(span);
+    }
+    let default_export = match default_export {
+        Some(span) => span,
+        None => {
+            report(
+                Severity::Error,
+                module.span,
+                "missing `export default` of the handler function".into(),
+            );
+            module.span
+        }
+    };
+
+    let mut visitor = EndpointVisitor::new(models);
+    module.visit_with(&mut visitor);
+    for (model, span) in &visitor.used_models {
+        if !visitor.declared.contains(model) {
+            report(
+                Severity::Error,
+                *span,
+                format!("model `{}` is used but not imported", model),
+            );
+        }
+    }
+    for (method, span) in &visitor.floating {
+        report(
+            Severity::Error,
+            *span,
+            format!("the promise returned by `{}()` is not awaited", method),
+        );
+    }
+    if visitor.awaits > 0 && visitor.error_handlers == 0 {
+        report(
+            Severity::Warning,
+            default_export,
+            "nothing handles errors from awaited calls, so they all become 500 responses".into(),
+        );
+    }
+    Ok(diagnostics)
+}
+
+/// Runs the TypeScript compiler over `endpoints`, returning its complaints, if any.
+async fn type_check(modules: &Module, endpoints: &[PathBuf]) -> Result<Option<String>> {
+    match modules {
+        Module::Node => {
+            let tsc = npx(
+                "tsc",
+                &["--noemit", "--pretty", "--allowJs", "--checkJs"],
+                None,
+            )
+            .await??;
+            if tsc.status.success() {
+                return Ok(None);
+            }
+            let out = String::from_utf8_lossy(&tsc.stdout);
+            let err = String::from_utf8_lossy(&tsc.stderr);
+            Ok(Some(format!("{}\n{}", out, err)))
+        }
+        Module::Deno => {
+            let paths = endpoints
+                .iter()
+                .map(|f| f.to_str().ok_or_else(|| anyhow!("Path is not UTF8")))
+                .collect::<Result<Vec<_>>>()?;
+            match compile_endpoints(&paths).await {
+                Ok(_) => Ok(None),
+                Err(e) => Ok(Some(format!("{:?}", e))),
+            }
+        }
+    }
+}
+
+pub(crate) async fn cmd_lint() -> Result<()> {
+    let manifest = read_manifest()?;
+    let models: BTreeSet<String> = crate::ts::parse_types(&manifest.models()?)?
+        .into_iter()
+        .map(|ty| ty.name)
+        .collect();
+    let endpoints = manifest.endpoints()?;
+
+    let mut errors = 0;
+    let mut warnings = 0;
+    if let Some(complaints) = type_check(&manifest.modules, &endpoints).await? {
+        println!("{}", complaints.trim_end());
+        errors += 1;
+    }
+    for endpoint in &endpoints {
+        for diagnostic in lint_endpoint(endpoint, &models)? {
+            match diagnostic.severity {
+                Severity::Error => errors += 1,
+                Severity::Warning => warnings += 1,
+            }
+            println!("{}", diagnostic);
+        }
+    }
+    anyhow::ensure!(
+        errors == 0,
+        "lint found {} error(s) and {} warning(s)",
+        errors,
+        warnings
+    );
+    println!("Lint found no errors and {} warning(s).", warnings);
+    Ok(())
+}
@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel logs`: print the recent log of the server, and follow it.
+
+use anyhow::{bail, Context, Result};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+pub(crate) struct LogsOptions {
+    pub(crate) follow: bool,
+    pub(crate) level: String,
+    pub(crate) request_id: Option<String>,
+    pub(crate) since: Option<u64>,
+}
+
+/// Parses durations like `90`, `30s`, `5m`, `1h` or `2d` into seconds.
+pub(crate) fn parse_duration(s: &str) -> Result<u64> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("'{}' is not a duration such as 30s, 5m or 1h", s))?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("unknown unit '{}' in duration, use s, m, h or d", unit),
+    };
+    Ok(number * unit)
+}
+
+/// Escapes `s` for a query string.
+fn query_escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `event`, printed the way the server prints its log.
+fn format_event(event: &serde_json::Value) -> String {
+    let timestamp = event["timestamp"].as_f64().unwrap_or_default();
+    let timestamp = Utc
+        .timestamp(timestamp.trunc() as i64, (timestamp.fract() * 1e9) as u32)
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    let level = event["level"].as_str().unwrap_or_default();
+    let message = event["message"].as_str().unwrap_or_default();
+    match event["request_id"].as_str() {
+        Some(id) => format!("[{}] {} - {} (request {})", timestamp, level, message, id),
+        None => format!("[{}] {} - {}", timestamp, level, message),
+    }
+}
+
+/// Calls `f` with the events of the log stream of the server at
+/// `api_addr`, asked for with the query string `query`. The admin routes
+/// serving it take `secret`.
+pub(crate) fn read_events(
+    api_addr: &str,
+    secret: &str,
+    query: &str,
+    mut f: impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
+    let mut stream = TcpStream::connect(api_addr)
+        .with_context(|| format!("could not connect to the server at {}", api_addr))?;
+    write!(
+        stream,
+        "GET /__chiselstrike/admin/logs/stream?{} HTTP/1.0\r\nHost: {}\r\nChiselAuth: {}\r\n\r\n",
+        query, api_addr, secret
+    )?;
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && !line.trim_end().is_empty() {
+        line.clear();
+    }
+    if !status.contains(" 200 ") {
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        bail!("could not read the log: {}\n{}", status.trim(), body.trim());
+    }
+    for line in reader.lines() {
+        let line = line?;
+        if let Some(data) = line.strip_prefix("data: ") {
+            f(serde_json::from_str(data)?)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn cmd_logs(api_addr: &str, secret: &str, opts: LogsOptions) -> Result<()> {
+    let mut query = format!("level={}&follow={}", query_escape(&opts.level), opts.follow);
+    if let Some(id) = &opts.request_id {
+        query += &format!("&request_id={}", query_escape(id));
+    }
+    if let Some(since) = opts.since {
+        query += &format!("&since={}", since);
+    }
+    let stdout = std::io::stdout();
+    read_events(api_addr, secret, &query, |event| {
+        let mut out = stdout.lock();
+        writeln!(out, "{}", format_event(&event))?;
+        out.flush()?;
+        Ok(())
+    })
+}
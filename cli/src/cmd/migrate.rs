@@ -0,0 +1,532 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel migrate`: keeps the schema changes of the project as SQL files
+//! to be reviewed and committed, rather than applying them right away.
+//!
+//! The files record what the server will run, but it is the server that
+//! runs it, through the same path as `chisel apply`, so that its metadata
+//! stays in sync with the tables. Before applying, the server is asked again
+//! for the statements, which have to be those of the pending files.
+//!
+//! Migrations can also be written by hand, for what models can't express,
+//! such as indexes with custom options or data backfills. Those are pairs of
+//! `.up.sql` and `.down.sql` files, scaffolded by `chisel generate
+//! migration`. The server runs their `.up.sql` as is, and keeps track of
+//! which it ran in its `__chiselstrike_migrations` table.
+
+use crate::chisel::chisel_rpc_client::ChiselRpcClient;
+use crate::chisel::{
+    AppliedMigration, ApplyMigrationRequest, FakeMigrationRequest, ListMigrationsRequest,
+    SquashMigrationsRequest,
+};
+use crate::cmd::apply::{apply, apply_request, AllowTypeDeletion, TypeChecking};
+use crate::project::read_to_string;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+const MIGRATIONS_DIR: &str = "migrations";
+/// Where the hashes of the generated migrations are kept, to tell which are
+/// pending and that they haven't been edited since.
+const STATE_FILE: &str = "migrations/state.json";
+/// Suffix of the files of the hand-written migrations that the server runs.
+const UP_SUFFIX: &str = ".up.sql";
+/// Suffix of the files undoing the hand-written migrations, which are run by hand.
+const DOWN_SUFFIX: &str = ".down.sql";
+/// Where `chisel migrate --squash` moves the migrations it squashed.
+const SUPERSEDED_DIR: &str = "migrations/superseded";
+/// Starts the ids of the squashed migrations, which are followed by the
+/// timestamps of the first and last migrations they squash.
+const SQUASHED_PREFIX: &str = "squashed_";
+/// Starts the lines of the squashed migrations naming one they squash.
+const SUPERSEDES: &str = "-- supersedes ";
+
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    migrations: Vec<Migration>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Migration {
+    file: String,
+    hash: String,
+    /// When the migration was applied, or None while it's pending.
+    applied_at: Option<String>,
+}
+
+impl State {
+    fn load() -> Result<Self> {
+        if !Path::new(STATE_FILE).exists() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(&read_to_string(STATE_FILE)?)
+            .with_context(|| format!("parsing {}", STATE_FILE))
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(STATE_FILE, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing {}", STATE_FILE))
+    }
+
+    fn pending(&self) -> impl Iterator<Item = &Migration> {
+        self.migrations.iter().filter(|m| m.applied_at.is_none())
+    }
+}
+
+fn hash(sql: &str) -> String {
+    format!("{:x}", Sha256::digest(sql.as_bytes()))
+}
+
+/// `description` made fit for a file name, as in `add_person_email`.
+fn slug(description: &str) -> String {
+    let words: Vec<_> = description
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        return "migration".into();
+    }
+    words.join("_")
+}
+
+/// The statements in `sql`, without comments and with their whitespace
+/// collapsed. New tables get a random suffix every time their statements
+/// are worked out, which is dropped too.
+fn normalize(sql: &str) -> String {
+    let random_suffix = Regex::new(r"(ty_\w+?)_[0-9A-F]{32}").unwrap();
+    let sql: Vec<_> = sql
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("--"))
+        .flat_map(str::split_whitespace)
+        .collect();
+    random_suffix.replace_all(&sql.join(" "), "$1").into_owned()
+}
+
+/// The statements applying the project as `version` would run.
+async fn planned_statements(server_url: String, version: &str) -> Result<Vec<String>> {
+    let (mut req, _) =
+        apply_request(version.to_owned(), AllowTypeDeletion::Yes, TypeChecking::No).await?;
+    req.dry_run = true;
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let response = execute!(client.apply(tonic::Request::new(req)).await);
+    Ok(response.statements)
+}
+
+/// The ids of the hand-written migrations, that is the names of their
+/// `.up.sql` files without the suffix. They start with a timestamp, so they
+/// sort in the order the migrations were created in, which they apply in.
+/// Squashed migrations sort where the first migration they squash was.
+fn raw_migrations() -> Result<Vec<String>> {
+    if !Path::new(MIGRATIONS_DIR).exists() {
+        return Ok(vec![]);
+    }
+    let mut ids = vec![];
+    for entry in fs::read_dir(MIGRATIONS_DIR)? {
+        let name = entry?.file_name();
+        if let Some(id) = name.to_string_lossy().strip_suffix(UP_SUFFIX) {
+            ids.push(id.to_owned());
+        }
+    }
+    ids.sort_by_key(|id| id.strip_prefix(SQUASHED_PREFIX).unwrap_or(id).to_owned());
+    Ok(ids)
+}
+
+fn up_file(id: &str) -> String {
+    format!("{}/{}{}", MIGRATIONS_DIR, id, UP_SUFFIX)
+}
+
+/// Writes the `.up.sql` and `.down.sql` files of a new hand-written
+/// migration doing what `name` says.
+pub(crate) fn scaffold_migration(name: &str) -> Result<()> {
+    let from = match raw_migrations()?.pop() {
+        Some(previous) => format!("as {} leaves it", up_file(&previous)),
+        None => "the models leave, before any hand-written migration".to_owned(),
+    };
+    let id = format!("{}_{}", Utc::now().format("%Y%m%d%H%M%S"), slug(name));
+    let up = up_file(&id);
+    let down = format!("{}/{}{}", MIGRATIONS_DIR, id, DOWN_SUFFIX);
+    let up_sql = format!(
+        "-- {}\n\
+         -- Transitions from the schema {}.\n\
+         -- Run by `chisel migrate --apply` in a transaction, in the order of the file names.\n\n",
+        name, from
+    );
+    let down_sql = format!(
+        "-- Undoes {}. chisel never runs this file: run it by hand to roll back.\n\n",
+        up
+    );
+    fs::create_dir_all(MIGRATIONS_DIR)?;
+    for (file, sql) in [(&up, up_sql), (&down, down_sql)] {
+        fs::write(file, sql).with_context(|| format!("writing {}", file))?;
+    }
+    println!("Created {} and {}.", up, down);
+    Ok(())
+}
+
+fn to_sql(statements: &[String]) -> String {
+    statements
+        .iter()
+        .map(|s| {
+            let s = s.trim();
+            if s.ends_with(';') {
+                s.to_owned()
+            } else {
+                format!("{};", s)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn generate(server_url: String, version: String, description: String) -> Result<()> {
+    let mut state = State::load()?;
+    if let Some(pending) = state.pending().next() {
+        anyhow::bail!(
+            "{} is still pending, apply it with `chisel migrate --apply` first",
+            pending.file
+        );
+    }
+    let statements = planned_statements(server_url, &version).await?;
+    if statements.is_empty() {
+        println!("No schema changes to migrate.");
+        return Ok(());
+    }
+
+    let file = format!(
+        "{}/{}_{}.sql",
+        MIGRATIONS_DIR,
+        Utc::now().format("%Y%m%d%H%M%S"),
+        slug(&description)
+    );
+    let sql = format!(
+        "-- {}\n-- Generated by `chisel migrate --generate` for version {}.\n\
+         -- Index names with `new` in place of an id get one when applied.\n{}\n",
+        description,
+        version,
+        to_sql(&statements)
+    );
+    fs::create_dir_all(MIGRATIONS_DIR)?;
+    fs::write(&file, &sql).with_context(|| format!("writing {}", file))?;
+    state.migrations.push(Migration {
+        file: file.clone(),
+        hash: hash(&sql),
+        applied_at: None,
+    });
+    state.save()?;
+    println!("Generated {} with {} statement(s).", file, statements.len());
+    Ok(())
+}
+
+/// Applies the pending generated migrations and returns how many there were.
+async fn apply_generated(
+    server_url: String,
+    version: String,
+    allow_type_deletion: AllowTypeDeletion,
+) -> Result<usize> {
+    let mut state = State::load()?;
+    let mut pending_sql = String::new();
+    for migration in state.pending() {
+        let sql = read_to_string(&migration.file)?;
+        anyhow::ensure!(
+            hash(&sql) == migration.hash,
+            "{} was edited after it was generated",
+            migration.file
+        );
+        pending_sql.push_str(&sql);
+        pending_sql.push('\n');
+    }
+    if pending_sql.is_empty() {
+        return Ok(0);
+    }
+    let planned = planned_statements(server_url.clone(), &version).await?;
+    if normalize(&to_sql(&planned)) != normalize(&pending_sql) {
+        return Err(anyhow!(
+            "the project has changed since the pending migrations were generated; \
+             regenerate them with `chisel migrate --generate`"
+        ));
+    }
+
+    apply(server_url, &version, allow_type_deletion, TypeChecking::No).await?;
+    let now = Utc::now().to_rfc3339();
+    let mut applied = 0;
+    for migration in state.migrations.iter_mut() {
+        if migration.applied_at.is_none() {
+            println!("Applied {}", migration.file);
+            migration.applied_at = Some(now.clone());
+            applied += 1;
+        }
+    }
+    state.save()?;
+    Ok(applied)
+}
+
+/// The hand-written migrations the server ran, failed ones included.
+async fn ran_raw(
+    client: &mut ChiselRpcClient<tonic::transport::Channel>,
+) -> Result<Vec<AppliedMigration>> {
+    let response = execute!(
+        client
+            .list_migrations(tonic::Request::new(ListMigrationsRequest {}))
+            .await
+    );
+    Ok(response.applied)
+}
+
+/// Applies the pending hand-written migrations, in order, and returns how
+/// many there were. Stops at the first that fails, which the server then
+/// refuses to run again until its record is deleted.
+async fn apply_raw(server_url: String) -> Result<usize> {
+    let ids = raw_migrations()?;
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let ran = ran_raw(&mut client).await?;
+    let mut count = 0;
+    for id in ids {
+        if ran.iter().any(|m| m.id == id && m.error.is_empty()) {
+            continue;
+        }
+        let file = up_file(&id);
+        let sql = read_to_string(&file)?;
+        let superseded: Vec<&str> = sql
+            .lines()
+            .filter_map(|line| line.strip_prefix(SUPERSEDES))
+            .collect();
+        let squashes_applied = !superseded.is_empty()
+            && superseded
+                .iter()
+                .all(|s| ran.iter().any(|m| m.id == *s && m.error.is_empty()));
+        if squashes_applied {
+            execute!(
+                client
+                    .fake_migration(tonic::Request::new(FakeMigrationRequest { id }))
+                    .await
+            );
+            println!("Marked {} as applied, as what it squashes was", file);
+            continue;
+        }
+        execute!(
+            client
+                .apply_migration(tonic::Request::new(ApplyMigrationRequest { id, sql }))
+                .await
+        );
+        println!("Applied {}", file);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Marks the hand-written migration `migration`, or all the pending ones if
+/// it is None, as applied without running them. `migration` is an id, or the
+/// path of the `.up.sql` file.
+async fn fake(server_url: String, migration: Option<String>) -> Result<()> {
+    let ids = raw_migrations()?;
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let ran = ran_raw(&mut client).await?;
+    let to_fake: Vec<String> = match migration {
+        Some(migration) => {
+            let file_name = Path::new(&migration).file_name().unwrap_or_default();
+            let file_name = file_name.to_string_lossy();
+            let id = file_name.strip_suffix(UP_SUFFIX).unwrap_or(&file_name);
+            anyhow::ensure!(
+                ids.iter().any(|i| i == id),
+                "there is no migration {} in {}",
+                id,
+                MIGRATIONS_DIR
+            );
+            vec![id.to_owned()]
+        }
+        None => ids
+            .into_iter()
+            .filter(|id| !ran.iter().any(|m| m.id == *id && m.error.is_empty()))
+            .collect(),
+    };
+    if to_fake.is_empty() {
+        println!("No pending migrations.");
+        return Ok(());
+    }
+    eprintln!(
+        "Warning: faked migrations are recorded as applied without running their SQL. \
+         If the database doesn't have their changes yet, the queries relying on them will fail."
+    );
+    for id in to_fake {
+        let file = up_file(&id);
+        execute!(
+            client
+                .fake_migration(tonic::Request::new(FakeMigrationRequest { id }))
+                .await
+        );
+        println!("Faked {}", file);
+    }
+    Ok(())
+}
+
+/// The timestamp that starts the id of a migration.
+fn timestamp(id: &str) -> &str {
+    id.split('_').next().unwrap_or(id)
+}
+
+/// Replaces the hand-written migrations from the one whose id starts with
+/// `from` to the one whose id starts with `to` by a single migration with
+/// their net changes, and moves them to [`SUPERSEDED_DIR`].
+async fn squash(server_url: String, from: String, to: String) -> Result<()> {
+    let ids: Vec<String> = raw_migrations()?
+        .into_iter()
+        .filter(|id| *id >= from && (*id <= to || id.starts_with(&to)))
+        .collect();
+    anyhow::ensure!(
+        ids.len() >= 2,
+        "there are {} migration(s) from {} to {} in {}, squashing takes at least 2",
+        ids.len(),
+        from,
+        to,
+        MIGRATIONS_DIR
+    );
+    let sql = ids
+        .iter()
+        .map(|id| read_to_string(up_file(id)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let response = execute!(
+        client
+            .squash_migrations(tonic::Request::new(SquashMigrationsRequest { sql }))
+            .await
+    );
+
+    let id = format!(
+        "{}{}_{}",
+        SQUASHED_PREFIX,
+        timestamp(&ids[0]),
+        timestamp(&ids[ids.len() - 1])
+    );
+    let up = up_file(&id);
+    let down = format!("{}/{}{}", MIGRATIONS_DIR, id, DOWN_SUFFIX);
+    let mut up_sql =
+        "-- Squashed by `chisel migrate --squash`, in place of the migrations below.\n\
+         -- Marked as applied without running where they all were.\n"
+            .to_owned();
+    if !response.rerunnable {
+        up_sql.push_str(
+            "-- Not re-runnable: SQLite can't guard the columns it adds, so this fails \
+             where they exist already.\n",
+        );
+    }
+    for id in &ids {
+        up_sql.push_str(&format!("{}{}\n", SUPERSEDES, id));
+    }
+    up_sql.push('\n');
+    up_sql.push_str(&response.sql);
+    let mut down_sql = format!("-- Undoes {}, from the last migration it squashes.\n", up);
+    for id in ids.iter().rev() {
+        let file = format!("{}/{}{}", MIGRATIONS_DIR, id, DOWN_SUFFIX);
+        if Path::new(&file).exists() {
+            down_sql.push_str(&format!("\n-- {}\n{}", file, read_to_string(&file)?));
+        }
+    }
+    for (file, sql) in [(&up, up_sql), (&down, down_sql)] {
+        fs::write(file, sql).with_context(|| format!("writing {}", file))?;
+    }
+
+    fs::create_dir_all(SUPERSEDED_DIR)?;
+    for id in &ids {
+        for suffix in [UP_SUFFIX, DOWN_SUFFIX] {
+            let file = format!("{}{}", id, suffix);
+            let path = Path::new(MIGRATIONS_DIR).join(&file);
+            if path.exists() {
+                fs::rename(&path, Path::new(SUPERSEDED_DIR).join(&file))
+                    .with_context(|| format!("moving {} to {}", path.display(), SUPERSEDED_DIR))?;
+            }
+        }
+    }
+    println!(
+        "Squashed {} migrations into {}, and moved them to {}.",
+        ids.len(),
+        up,
+        SUPERSEDED_DIR
+    );
+    Ok(())
+}
+
+/// Applies the pending generated migrations, then the hand-written ones.
+async fn apply_pending(
+    server_url: String,
+    version: String,
+    allow_type_deletion: AllowTypeDeletion,
+) -> Result<()> {
+    let generated = apply_generated(server_url.clone(), version, allow_type_deletion).await?;
+    let raw = apply_raw(server_url).await?;
+    if generated + raw == 0 {
+        println!("No pending migrations.");
+    }
+    Ok(())
+}
+
+async fn status(server_url: String) -> Result<()> {
+    let state = State::load()?;
+    let raw = raw_migrations()?;
+    if state.migrations.is_empty() && raw.is_empty() {
+        println!("No migrations.");
+    }
+    for migration in &state.migrations {
+        match &migration.applied_at {
+            Some(applied_at) => println!("applied  {} ({})", migration.file, applied_at),
+            None => println!("pending  {}", migration.file),
+        }
+    }
+    if raw.is_empty() {
+        return Ok(());
+    }
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let ran = ran_raw(&mut client).await?;
+    for id in raw {
+        match ran.iter().find(|m| m.id == id) {
+            Some(m) if m.error.is_empty() => {
+                println!("applied  {} ({})", up_file(&id), m.applied_at)
+            }
+            Some(m) => println!("failed   {} ({}): {}", up_file(&id), m.applied_at, m.error),
+            None => println!("pending  {}", up_file(&id)),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) enum MigrateAction {
+    Generate {
+        description: String,
+    },
+    Apply {
+        allow_type_deletion: bool,
+    },
+    Status,
+    /// Mark a hand-written migration as applied, or all the pending ones if None.
+    Fake {
+        migration: Option<String>,
+    },
+    /// Merge the hand-written migrations with ids from `from` to `to` into one.
+    Squash {
+        from: String,
+        to: String,
+    },
+}
+
+pub(crate) async fn cmd_migrate(
+    server_url: String,
+    version: String,
+    action: MigrateAction,
+) -> Result<()> {
+    match action {
+        MigrateAction::Generate { description } => generate(server_url, version, description).await,
+        MigrateAction::Apply {
+            allow_type_deletion,
+        } => apply_pending(server_url, version, allow_type_deletion.into()).await,
+        MigrateAction::Status => status(server_url).await,
+        MigrateAction::Fake { migration } => fake(server_url, migration).await,
+        MigrateAction::Squash { from, to } => squash(server_url, from, to).await,
+    }
+}
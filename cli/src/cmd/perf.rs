@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel perf`: measure where the server spends its time.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum PerfCommand {
+    /// Profile the CPU usage of the server and open the flamegraph in the
+    /// browser. The server has to be built with the `profiling` feature.
+    Profile {
+        /// How long to profile for, in seconds.
+        #[structopt(long, default_value = "30")]
+        duration: u64,
+        /// Where to save the flamegraph.
+        #[structopt(long, default_value = "chisel-profile.svg")]
+        output: PathBuf,
+        /// Address of the API server.
+        #[structopt(long, default_value = "localhost:8080")]
+        api_addr: String,
+    },
+}
+
+/// Sends `POST /__chiselstrike/admin/profiler/<action>` with the admin `secret`
+/// and returns the body of the response.
+fn profiler(api_addr: &str, secret: &str, action: &str) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(api_addr)
+        .with_context(|| format!("could not connect to the server at {}", api_addr))?;
+    write!(
+        stream,
+        "POST /__chiselstrike/admin/profiler/{} HTTP/1.0\r\nHost: {}\r\nChiselAuth: {}\r\nContent-Length: 0\r\n\r\n",
+        action, api_addr, secret
+    )?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    let head_len = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("the server sent a malformed response")?;
+    let head = String::from_utf8_lossy(&response[..head_len]);
+    let status = head.lines().next().unwrap_or_default();
+    let body = response[head_len + 4..].to_vec();
+    if status.contains(" 404 ") {
+        bail!(
+            "the server at {} can't profile, it has to be built with `--features profiling`",
+            api_addr
+        );
+    }
+    if !status.contains(" 200 ") {
+        bail!(
+            "could not {} the profiler: {}\n{}",
+            action,
+            status,
+            String::from_utf8_lossy(&body).trim()
+        );
+    }
+    Ok(body)
+}
+
+/// Opens `path` with the default application of the desktop.
+fn open(path: &Path) -> Result<()> {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    let status = Command::new(opener).arg(path).status()?;
+    anyhow::ensure!(status.success(), "{} failed with {}", opener, status);
+    Ok(())
+}
+
+pub(crate) fn cmd_perf(cmd: PerfCommand, secret: &str) -> Result<()> {
+    match cmd {
+        PerfCommand::Profile {
+            duration,
+            output,
+            api_addr,
+        } => {
+            profiler(&api_addr, secret, "start")?;
+            println!("Profiling the server for {} seconds...", duration);
+            thread::sleep(Duration::from_secs(duration));
+            let svg = profiler(&api_addr, secret, "stop")?;
+            fs::write(&output, svg)
+                .with_context(|| format!("could not write {}", output.display()))?;
+            println!("Flamegraph saved to {}.", output.display());
+            if open(&output).is_err() {
+                println!("Open it in a browser to see it.");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,438 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel repl`: runs queries like `Post.findMany({authorId: "abc"})`
+//! against the running server.
+//!
+//! Expressions are parsed here and sent to the server as the same operator
+//! chain the TypeScript API builds, so they filter the way endpoints do.
+
+use crate::chisel::chisel_rpc_client::ChiselRpcClient;
+use crate::chisel::{FieldDefinition, QueryRequest};
+use crate::cmd::validate::describe_version;
+use crate::ts::field_to_ts;
+use anyhow::{anyhow, bail, Context as _, Result};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use swc_common::sync::Lrc;
+use swc_common::{FileName, SourceMap};
+use swc_ecmascript::ast::{
+    CallExpr, Callee, Expr, Lit, MemberProp, Prop, PropName, PropOrSpread, UnaryOp,
+};
+use swc_ecmascript::parser::{Parser, StringInput, Syntax, TsConfig};
+use tonic::transport::Channel;
+
+const METHODS: &[&str] = &["findAll", "findMany", "findOne"];
+const META_COMMANDS: &[&str] = &["exit", "help", "schema"];
+
+const HELP: &str = "\
+Expressions:
+  Type.findAll()                  all entities of Type
+  Type.findMany({field: value})   entities matching all the restrictions
+  Type.findOne({field: value})    the first entity matching, or null
+Restrictions take a value to compare with, or an object of $gt, $lt, $contains,
+$overlap, $in, $hasKey and $keyEq.
+
+Commands:
+  .schema Type   show the fields of Type
+  .help          show this help
+  .exit          leave (as does Ctrl-D)";
+
+/// The types of the version being explored, by name.
+type Types = BTreeMap<String, Vec<FieldDefinition>>;
+
+struct ReplHelper {
+    types: Types,
+}
+
+impl ReplHelper {
+    fn candidates(&self, before: &str) -> Vec<String> {
+        let to_strings = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        let before = before.trim_start();
+        if before == "." {
+            return to_strings(META_COMMANDS);
+        }
+        if before.ends_with('.') {
+            return to_strings(METHODS);
+        }
+        // Inside the arguments of `Type.method(`, the fields of Type are wanted.
+        if let (Some(dot), true) = (before.find('.'), before.contains('(')) {
+            if let Some(fields) = self.types.get(&before[..dot]) {
+                return fields.iter().map(|f| f.name.clone()).collect();
+            }
+        }
+        self.types.keys().cloned().collect()
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let matches = self
+            .candidates(&line[..start])
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {
+    /// Keeps reading lines while brackets are left open.
+    fn validate(&self, ctx: &mut ValidationContext<'_>) -> rustyline::Result<ValidationResult> {
+        let mut depth = 0i32;
+        let mut quote = None;
+        let mut escaped = false;
+        for c in ctx.input().chars() {
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None => match c {
+                    '"' | '\'' => quote = Some(c),
+                    '(' | '[' | '{' => depth += 1,
+                    ')' | ']' | '}' => depth -= 1,
+                    _ => {}
+                },
+            }
+        }
+        if depth > 0 {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for ReplHelper {}
+
+/// Evaluates a literal written in TypeScript, such as `{age: {$gt: 18}}`.
+fn literal(expr: &Expr) -> Result<Value> {
+    Ok(match expr {
+        Expr::Lit(Lit::Str(s)) => Value::String(s.value.to_string()),
+        Expr::Lit(Lit::Bool(b)) => Value::Bool(b.value),
+        Expr::Lit(Lit::Null(_)) => Value::Null,
+        Expr::Lit(Lit::Num(n)) => number(n.value)?,
+        Expr::Unary(unary) if unary.op == UnaryOp::Minus => match literal(&unary.arg)? {
+            Value::Number(n) => number(-n.as_f64().unwrap())?,
+            _ => bail!("only numbers can be negated"),
+        },
+        Expr::Paren(paren) => literal(&paren.expr)?,
+        Expr::Array(array) => Value::Array(
+            array
+                .elems
+                .iter()
+                .flatten()
+                .map(|e| literal(&e.expr))
+                .collect::<Result<_>>()?,
+        ),
+        Expr::Object(object) => {
+            let mut map = Map::new();
+            for prop in &object.props {
+                let kv = match prop {
+                    PropOrSpread::Prop(prop) => match &**prop {
+                        Prop::KeyValue(kv) => kv,
+                        _ => bail!("only `key: value` properties are supported"),
+                    },
+                    PropOrSpread::Spread(_) => bail!("spreads are not supported"),
+                };
+                let key = match &kv.key {
+                    PropName::Ident(id) => id.sym.to_string(),
+                    PropName::Str(s) => s.value.to_string(),
+                    _ => bail!("property names must be identifiers or strings"),
+                };
+                map.insert(key, literal(&kv.value)?);
+            }
+            Value::Object(map)
+        }
+        _ => bail!("only literals are supported as arguments"),
+    })
+}
+
+/// Integers are kept as such, so they compare the way they do in endpoints.
+fn number(n: f64) -> Result<Value> {
+    if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        return Ok(json!(n as i64));
+    }
+    serde_json::Number::from_f64(n)
+        .map(Value::Number)
+        .ok_or_else(|| anyhow!("{} is not a valid number", n))
+}
+
+fn binary(left: Value, op: &str, right: Value) -> Value {
+    json!({"exprType": "Binary", "left": left, "op": op, "right": right})
+}
+
+fn literal_expr(value: Value) -> Value {
+    json!({"exprType": "Literal", "value": value})
+}
+
+/// The filter expression for `restrictions`, as `restrictionsToFilterExpr()`
+/// in the TypeScript API builds it.
+fn filter_expr(restrictions: &Map<String, Value>) -> Result<Option<Value>> {
+    let mut expr = None;
+    for (key, restriction) in restrictions {
+        let property = json!({
+            "exprType": "Property",
+            "object": {"exprType": "Parameter", "position": 0},
+            "property": key,
+        });
+        let mut comparisons = vec![];
+        match restriction.as_object() {
+            Some(predicate) => {
+                for (op, value) in predicate {
+                    let value = value.clone();
+                    comparisons.push(match op.as_str() {
+                        "$gt" => binary(property.clone(), "Gt", literal_expr(value)),
+                        "$lt" => binary(property.clone(), "Lt", literal_expr(value)),
+                        "$contains" => binary(property.clone(), "Contains", literal_expr(value)),
+                        "$overlap" => {
+                            anyhow::ensure!(value.is_array(), "$overlap takes an array");
+                            binary(property.clone(), "Overlaps", literal_expr(value))
+                        }
+                        "$hasKey" => binary(property.clone(), "HasKey", literal_expr(value)),
+                        "$keyEq" => value
+                            .as_object()
+                            .ok_or_else(|| anyhow!("$keyEq takes an object"))?
+                            .iter()
+                            .fold(literal_expr(json!(true)), |acc, (k, v)| {
+                                let entry = json!({
+                                    "exprType": "Property",
+                                    "object": property,
+                                    "property": k,
+                                });
+                                let eq = binary(entry, "Eq", literal_expr(v.clone()));
+                                binary(acc, "And", eq)
+                            }),
+                        "$in" => value
+                            .as_array()
+                            .ok_or_else(|| anyhow!("$in takes an array"))?
+                            .iter()
+                            .fold(literal_expr(json!(false)), |acc, v| {
+                                let eq = binary(property.clone(), "Eq", literal_expr(v.clone()));
+                                binary(acc, "Or", eq)
+                            }),
+                        _ => bail!("unknown predicate `{}` for field `{}`", op, key),
+                    });
+                }
+            }
+            None => comparisons.push(binary(
+                property.clone(),
+                "Eq",
+                literal_expr(restriction.clone()),
+            )),
+        }
+        for comparison in comparisons {
+            expr = Some(match expr {
+                None => comparison,
+                Some(expr) => binary(comparison, "And", expr),
+            });
+        }
+    }
+    Ok(expr)
+}
+
+/// A query typed at the prompt.
+struct Query {
+    type_name: String,
+    /// Whether only the first match is wanted, as with `findOne()`.
+    first: bool,
+    op_chain: Value,
+}
+
+fn call_parts(call: &CallExpr) -> Option<(String, String)> {
+    let member = match &call.callee {
+        Callee::Expr(callee) => match &**callee {
+            Expr::Member(member) => member,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let type_name = match &*member.obj {
+        Expr::Ident(id) => id.sym.to_string(),
+        _ => return None,
+    };
+    match &member.prop {
+        MemberProp::Ident(id) => Some((type_name, id.sym.to_string())),
+        _ => None,
+    }
+}
+
+fn parse_query(input: &str, types: &Types) -> Result<Query> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Custom("repl".into()), input.to_owned());
+    let mut parser = Parser::new(
+        Syntax::Typescript(TsConfig::default()),
+        StringInput::from(&*fm),
+        None,
+    );
+    let expr = parser
+        .parse_expr()
+        .map_err(|e| anyhow!("syntax error: {}", e.into_kind().msg()))?;
+    let call = match &*expr {
+        Expr::Call(call) => call,
+        _ => bail!("expected a call such as `Type.findMany({{...}})`"),
+    };
+    let (type_name, method) =
+        call_parts(call).ok_or_else(|| anyhow!("expected a call such as `Type.findMany()`"))?;
+    if !types.contains_key(&type_name) {
+        bail!("unknown type `{}`", type_name);
+    }
+    let restrictions = match (method.as_str(), call.args.as_slice()) {
+        ("findAll", []) | ("findMany", []) => Map::new(),
+        ("findMany", [arg]) | ("findOne", [arg]) => match literal(&arg.expr)? {
+            Value::Object(restrictions) => restrictions,
+            _ => bail!("`{}()` takes an object of restrictions", method),
+        },
+        (m, _) if METHODS.contains(&m) => bail!("wrong number of arguments to `{}()`", m),
+        (m, _) => bail!(
+            "unknown method `{}`, expected one of {}",
+            m,
+            METHODS.join(", ")
+        ),
+    };
+
+    let mut op_chain = json!({"type": "BaseEntity", "name": type_name});
+    if let Some(expression) = filter_expr(&restrictions)? {
+        op_chain = json!({"type": "ExpressionFilter", "expression": expression, "inner": op_chain});
+    }
+    let first = method == "findOne";
+    if first {
+        op_chain = json!({"type": "Take", "count": 1, "inner": op_chain});
+    }
+    Ok(Query {
+        type_name,
+        first,
+        op_chain,
+    })
+}
+
+fn schema(types: &Types, type_name: &str) -> Result<String> {
+    let fields = types
+        .get(type_name)
+        .ok_or_else(|| anyhow!("unknown type `{}`", type_name))?;
+    let mut schema = format!("class {} {{\n", type_name);
+    for field in fields {
+        schema.push_str(&format!("    {};\n", field_to_ts(field)));
+    }
+    schema.push('}');
+    Ok(schema)
+}
+
+async fn run_query(
+    client: &mut ChiselRpcClient<Channel>,
+    version: &str,
+    query: Query,
+) -> Result<Value> {
+    let request = QueryRequest {
+        version: version.to_owned(),
+        op_chain: query.op_chain.to_string(),
+    };
+    let response = execute!(client.query(tonic::Request::new(request)).await);
+    let rows: Vec<Value> = serde_json::from_str(&response.rows)
+        .with_context(|| format!("invalid rows of {}", query.type_name))?;
+    Ok(if query.first {
+        rows.into_iter().next().unwrap_or(Value::Null)
+    } else {
+        Value::Array(rows)
+    })
+}
+
+/// What to print for `input`, or None to leave.
+async fn eval(
+    client: &mut ChiselRpcClient<Channel>,
+    version: &str,
+    types: &Types,
+    input: &str,
+) -> Result<Option<String>> {
+    if let Some(command) = input.strip_prefix('.') {
+        let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+        return match command {
+            "exit" => Ok(None),
+            "help" => Ok(Some(HELP.to_owned())),
+            "schema" if arg.trim().is_empty() => {
+                let names: Vec<_> = types.keys().map(String::as_str).collect();
+                Ok(Some(names.join("\n")))
+            }
+            "schema" => Ok(Some(schema(types, arg.trim())?)),
+            _ => bail!("unknown command `.{}`, try `.help`", command),
+        };
+    }
+    let query = parse_query(input, types)?;
+    let result = run_query(client, version, query).await?;
+    Ok(Some(serde_json::to_string_pretty(&result)?))
+}
+
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".chisel_repl_history"))
+}
+
+pub(crate) async fn cmd_repl(server_url: String, version: String) -> Result<()> {
+    let types: Types = describe_version(server_url.clone(), &version)
+        .await?
+        .ok_or_else(|| anyhow!("the server has no version {}", version))?
+        .type_defs
+        .into_iter()
+        .map(|ty| (ty.name, ty.field_defs))
+        .collect();
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+
+    let mut editor = Editor::<ReplHelper>::new();
+    editor.set_helper(Some(ReplHelper {
+        types: types.clone(),
+    }));
+    let history = history_file();
+    if let Some(history) = &history {
+        // There's no history the first time around.
+        let _ = editor.load_history(history);
+    }
+    println!(
+        "Exploring version {}. Type `.help` for help and `.exit` to leave.",
+        version
+    );
+    loop {
+        let input = match editor.readline(&format!("{}> ", version)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(input);
+        match eval(&mut client, &version, &types, input).await {
+            Ok(Some(output)) => println!("{}", output),
+            Ok(None) => break,
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+    if let Some(history) = &history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
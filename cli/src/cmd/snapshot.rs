@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+use crate::chisel::chisel_rpc_client::ChiselRpcClient;
+use crate::chisel::{
+    CreateSnapshotRequest, ListSnapshotsRequest, RestoreSnapshotRequest, SnapshotDefinition,
+};
+use crate::server::wait_with_cond;
+use anyhow::{anyhow, Result};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum SnapshotCommand {
+    /// List the database snapshots, oldest first.
+    List,
+    /// Take a database snapshot now.
+    Create,
+    /// Restart the server with the database restored from a snapshot.
+    Restore {
+        /// Snapshot id, as shown by `chisel snapshot list`.
+        id: String,
+    },
+}
+
+fn print_snapshot(s: &SnapshotDefinition) {
+    println!("{}\t{}\t{} bytes", s.id, s.created_at, s.size);
+}
+
+pub(crate) async fn cmd_snapshot(server_url: String, cmd: SnapshotCommand) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url.clone()).await?;
+    match cmd {
+        SnapshotCommand::List => {
+            let response = execute!(
+                client
+                    .list_snapshots(tonic::Request::new(ListSnapshotsRequest {}))
+                    .await
+            );
+            for s in &response.snapshots {
+                print_snapshot(s);
+            }
+        }
+        SnapshotCommand::Create => {
+            let response = execute!(
+                client
+                    .create_snapshot(tonic::Request::new(CreateSnapshotRequest {}))
+                    .await
+            );
+            if let Some(s) = &response.snapshot {
+                print_snapshot(s);
+            }
+        }
+        SnapshotCommand::Restore { id } => {
+            let response = execute!(
+                client
+                    .restore_snapshot(tonic::Request::new(RestoreSnapshotRequest {
+                        id: id.clone()
+                    }))
+                    .await
+            );
+            anyhow::ensure!(response.ok, "Server failed to restart.");
+            wait_with_cond(server_url, |status| status.server_id != response.server_id).await?;
+            println!("Restored snapshot {}.", id);
+        }
+    }
+    Ok(())
+}
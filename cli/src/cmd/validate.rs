@@ -0,0 +1,183 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! `chisel validate`: checks that the running server has what the local
+//! project defines.
+
+use crate::chisel::chisel_rpc_client::ChiselRpcClient;
+use crate::chisel::{DescribeRequest, FieldDefinition, VersionDefinition};
+use crate::cmd::apply::{apply, AllowTypeDeletion, TypeChecking};
+use crate::cmd::dev::{touches_project, tracked_dirs, watch_project};
+use crate::project::read_manifest;
+use crate::ts::{field_to_ts, parse_types};
+use anyhow::{anyhow, Result};
+use deno_core::futures;
+use endpoint_tsc::tsc_compile;
+use futures::StreamExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::io::{self, Write as _};
+use tsc_compile::deno_core;
+use utils::without_extension;
+
+/// Clears the terminal and moves the cursor to its top left corner.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// The classes of a project, as the lines declaring their fields.
+type Classes = BTreeMap<String, Vec<String>>;
+
+fn field_lines(fields: &[FieldDefinition]) -> Vec<String> {
+    fields.iter().map(field_to_ts).collect()
+}
+
+/// Appends a hunk titled `title` to `diff`, going from `server` to `local`.
+fn diff_hunk(diff: &mut String, title: &str, server: &[String], local: &[String]) {
+    if server == local {
+        return;
+    }
+    writeln!(diff, "@@ {} @@", title).unwrap();
+    for line in server.iter().filter(|line| !local.contains(line)) {
+        writeln!(diff, "-{}", line).unwrap();
+    }
+    for line in local.iter().filter(|line| !server.contains(line)) {
+        writeln!(diff, "+{}", line).unwrap();
+    }
+}
+
+/// What the server runs as `version`, if it has that version.
+pub(crate) async fn describe_version(
+    server_url: String,
+    version: &str,
+) -> Result<Option<VersionDefinition>> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let response = execute!(
+        client
+            .describe(tonic::Request::new(DescribeRequest {}))
+            .await
+    );
+    Ok(response
+        .version_defs
+        .into_iter()
+        .find(|v| v.version == version))
+}
+
+/// The differences between what the server runs as `version` and the local
+/// project, in the style of `git diff`, or an empty string if there are none.
+pub(crate) async fn validate(server_url: String, version: &str) -> Result<String> {
+    let manifest = read_manifest()?;
+    let local_classes: Classes = parse_types(&manifest.models()?)?
+        .into_iter()
+        .map(|ty| (ty.name, field_lines(&ty.field_defs)))
+        .collect();
+    let mut local_endpoints = BTreeSet::new();
+    for endpoint in manifest.endpoints()? {
+        let path = endpoint.display().to_string();
+        if let Some(path) = without_extension(&path).strip_prefix("endpoints/") {
+            local_endpoints.insert(format!("/{}/{}", version, path));
+        }
+    }
+
+    let mut server_classes = Classes::new();
+    let mut server_endpoints = BTreeSet::new();
+    if let Some(version_def) = describe_version(server_url, version).await? {
+        for ty in version_def.type_defs {
+            server_classes.insert(ty.name, field_lines(&ty.field_defs));
+        }
+        for endpoint in version_def.endpoint_defs {
+            server_endpoints.insert(endpoint.path);
+        }
+    }
+
+    let mut diff = String::new();
+    let names: BTreeSet<_> = server_classes.keys().chain(local_classes.keys()).collect();
+    for name in names {
+        let title = match (server_classes.get(name), local_classes.get(name)) {
+            (Some(_), Some(_)) => format!("class {}", name),
+            (Some(_), None) => format!("class {} (only on the server)", name),
+            _ => format!("class {} (only in the project)", name),
+        };
+        diff_hunk(
+            &mut diff,
+            &title,
+            server_classes.get(name).map_or(&[], Vec::as_slice),
+            local_classes.get(name).map_or(&[], Vec::as_slice),
+        );
+    }
+    let server_endpoints: Vec<_> = server_endpoints.into_iter().collect();
+    let local_endpoints: Vec<_> = local_endpoints.into_iter().collect();
+    diff_hunk(&mut diff, "endpoints", &server_endpoints, &local_endpoints);
+
+    if diff.is_empty() {
+        return Ok(diff);
+    }
+    Ok(format!(
+        "--- server (version {})\n+++ project\n{}",
+        version, diff
+    ))
+}
+
+fn summary(version: &str, diff: &str) -> String {
+    if diff.is_empty() {
+        format!("The server's version {} matches the project.\n", version)
+    } else {
+        diff.to_owned()
+    }
+}
+
+/// Validates every time the project changes, replacing the previous report
+/// on the terminal. With `auto_apply`, the project is applied first.
+async fn watch(server_url: String, version: String, auto_apply: bool) -> Result<()> {
+    let (_watcher, mut events) = watch_project()?;
+    let tracked = tracked_dirs(&read_manifest()?)?;
+    loop {
+        print!("{}", CLEAR_SCREEN);
+        io::stdout().flush()?;
+        if auto_apply {
+            let applied = apply(
+                server_url.clone(),
+                &version,
+                AllowTypeDeletion::No,
+                TypeChecking::No,
+            )
+            .await;
+            if let Err(e) = applied {
+                println!("Error applying the project: {:?}", e);
+            }
+        }
+        match validate(server_url.clone(), &version).await {
+            Ok(diff) => print!("{}", summary(&version, &diff)),
+            Err(e) => println!("Error: {:?}", e),
+        }
+        io::stdout().flush()?;
+
+        loop {
+            match events.next().await {
+                Some(Ok(event)) if touches_project(&event, &tracked) => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => eprintln!("watch error: {:?}", e),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+pub(crate) async fn cmd_validate(
+    server_url: String,
+    version: String,
+    watch_changes: bool,
+    auto_apply: bool,
+) -> Result<()> {
+    if watch_changes {
+        return watch(server_url, version, auto_apply).await;
+    }
+    anyhow::ensure!(!auto_apply, "--auto-apply only makes sense with --watch");
+    let diff = validate(server_url, &version).await?;
+    if !diff.is_empty() {
+        print!("{}", diff);
+        return Err(anyhow!(
+            "the server's version {} and the project diverge",
+            version
+        ));
+    }
+    print!("{}", summary(&version, &diff));
+    Ok(())
+}
@@ -1,18 +1,36 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
+use crate::cmd::analyze::{cmd_analyze, AnalyzeOptions};
 use crate::cmd::apply::apply;
+use crate::cmd::auth::{admin_secret, cmd_auth, AuthCommand};
+use crate::cmd::db::{cmd_db, Client};
+use crate::cmd::describe::cmd_describe_type;
 use crate::cmd::dev::cmd_dev;
-use crate::project::{create_project, CreateProjectOptions};
+use crate::cmd::diff::cmd_diff;
+use crate::cmd::doctor::cmd_doctor;
+use crate::cmd::lint::cmd_lint;
+use crate::cmd::logs::{cmd_logs, parse_duration, LogsOptions};
+use crate::cmd::migrate::{cmd_migrate, scaffold_migration, MigrateAction};
+use crate::cmd::perf::{cmd_perf, PerfCommand};
+use crate::cmd::repl::cmd_repl;
+use crate::cmd::snapshot::{cmd_snapshot, SnapshotCommand};
+use crate::cmd::validate::cmd_validate;
+use crate::project::{create_endpoint, create_model, create_project, CreateProjectOptions};
 use crate::server::{start_server, wait, wait_with_cond};
+use crate::ts::{
+    check_constraint_to_ts, field_to_ts, id_strategy_to_ts, unique_constraint_to_ts,
+    virtual_relation_to_ts, webhook_to_ts,
+};
 use anyhow::{anyhow, Result};
 use chisel::chisel_rpc_client::ChiselRpcClient;
 use chisel::{
-    ChiselDeleteRequest, DescribeRequest, PopulateRequest, RestartRequest, StatusRequest,
+    ChiselDeleteRequest, DeprecateVersionRequest, DescribeRequest, EncryptExistingRequest,
+    PopulateRequest, RestartRequest, StatusRequest,
 };
 use std::env;
 use std::fs;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 mod chisel;
@@ -55,14 +73,86 @@ enum Command {
         #[structopt(long, parse(try_from_str), default_value = "false")]
         auto_index: bool,
     },
-    /// Describe the endpoints, types, and policies.
-    Describe,
+    /// Print the latency and error rate of the slowest endpoints, from the access log.
+    Analyze {
+        /// Access log written by chiseld with `--access-log`. The recent requests
+        /// of the server are analyzed when unset.
+        #[structopt(long)]
+        log_file: Option<PathBuf>,
+        /// How many endpoints to print, slowest first.
+        #[structopt(long, default_value = "10")]
+        top: usize,
+        /// Address of the API server.
+        #[structopt(long, default_value = "localhost:8080")]
+        api_addr: String,
+    },
+    /// Manage users for local development, and the secret of the auth routes.
+    Auth {
+        #[structopt(subcommand)]
+        cmd: AuthCommand,
+    },
+    /// Describe the endpoints, types, and policies, or the fields and indexes of a type.
+    Describe {
+        /// Name of the type to describe the fields, indexes and references of.
+        type_name: Option<String>,
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+        /// Print the description of the type as JSON.
+        #[structopt(long, requires = "type-name")]
+        json: bool,
+        /// Address of the API server, which describes a type.
+        #[structopt(long, default_value = "localhost:8080")]
+        api_addr: String,
+    },
     /// Start a ChiselStrike server for local development.
     Dev {
         /// calls tsc --noEmit to check types. Useful if your IDE isn't doing it.
         #[structopt(long)]
         type_check: bool,
     },
+    /// Diagnose common problems with the environment, the project and the server.
+    Doctor {
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+        /// Address of the internal routes of the server, to check its database.
+        #[structopt(long, default_value = "127.0.0.1:9090")]
+        internal_addr: String,
+    },
+    /// Encrypt the values of an @encrypted field that were stored before it was encrypted.
+    EncryptExisting {
+        /// Name of the type.
+        type_name: String,
+        /// Name of the field, marked @encrypted and applied already.
+        field: String,
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+    },
+    /// Generate code in the current project.
+    Generate {
+        #[structopt(subcommand)]
+        cmd: GenerateCommand,
+    },
+    /// Check the endpoints for common mistakes.
+    Lint,
+    /// Print the recent log of the server.
+    Logs {
+        /// Keep printing the log as the server writes it.
+        #[structopt(short, long)]
+        follow: bool,
+        /// Least severe level printed.
+        #[structopt(long, default_value = "info", possible_values = &["error", "warn", "info", "debug", "trace"])]
+        level: String,
+        /// Only print the log of this request, at all levels. Responses carry
+        /// the id of their request in the `x-request-id` header.
+        #[structopt(long)]
+        request_id: Option<String>,
+        /// Only print what was logged this long ago or later, e.g. 30s, 5m or 1h.
+        #[structopt(long, parse(try_from_str = parse_duration))]
+        since: Option<u64>,
+        /// Address of the API server.
+        #[structopt(long, default_value = "localhost:8080")]
+        api_addr: String,
+    },
     /// Create a new ChiselStrike project.
     New {
         /// Path where to create the project.
@@ -77,6 +167,11 @@ enum Command {
         #[structopt(long, parse(try_from_str), default_value = "false")]
         auto_index: bool,
     },
+    /// Measure the performance of the server.
+    Perf {
+        #[structopt(subcommand)]
+        cmd: PerfCommand,
+    },
     /// Start the ChiselStrike server.
     Start,
     /// Show ChiselStrike server status.
@@ -100,12 +195,123 @@ enum Command {
         #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
         version: String,
     },
+    /// Announce that a numbered API version, such as v1, goes away on a date.
+    Deprecate {
+        #[structopt(long, parse(try_from_str=parse_version))]
+        version: String,
+        /// The date the version goes away on, as YYYY-MM-DD.
+        #[structopt(long)]
+        sunset: String,
+    },
     Populate {
         #[structopt(long)]
         version: String,
         #[structopt(long)]
         from: String,
     },
+    /// Manage database snapshots.
+    Snapshot {
+        #[structopt(subcommand)]
+        cmd: SnapshotCommand,
+    },
+    /// Check that the running server has the models and endpoints of the project.
+    Validate {
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+        /// Validate again every time the project changes.
+        #[structopt(long)]
+        watch: bool,
+        /// With --watch, apply the project before each validation.
+        #[structopt(long)]
+        auto_apply: bool,
+    },
+    /// Show the schema changes that applying the project would make.
+    Diff {
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+        /// Print the changes as JSON.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Keep schema changes as SQL files to review before they are applied.
+    Migrate {
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+        /// Write the SQL of the project's schema changes to a new file in `migrations`.
+        #[structopt(long, conflicts_with_all = &["apply", "status"])]
+        generate: bool,
+        /// With --generate, what the migration does, for its file name.
+        #[structopt(long, default_value = "migration")]
+        description: String,
+        /// Apply the pending migrations.
+        #[structopt(long, conflicts_with = "status")]
+        apply: bool,
+        /// With --apply, allow migrations to drop types and their data.
+        #[structopt(long)]
+        allow_type_deletion: bool,
+        /// List the applied and pending migrations.
+        #[structopt(long)]
+        status: bool,
+        /// Mark the hand-written migration with this id as applied, without running it.
+        #[structopt(long, conflicts_with_all = &["generate", "apply", "status", "fake-all"])]
+        fake: Option<String>,
+        /// Mark all the pending hand-written migrations as applied, without running them.
+        #[structopt(long, conflicts_with_all = &["generate", "apply", "status"])]
+        fake_all: bool,
+        /// Merge the hand-written migrations whose ids start with FROM, TO and
+        /// those in between into one.
+        #[structopt(long, number_of_values = 2, value_names = &["from", "to"],
+            conflicts_with_all = &["generate", "apply", "status", "fake", "fake-all"])]
+        squash: Vec<String>,
+    },
+    /// Open psql on the PostgreSQL database of the server.
+    Psql {
+        /// Arguments passed on to psql, after `--`.
+        #[structopt(last = true)]
+        args: Vec<String>,
+    },
+    /// Open sqlite3 on the SQLite database of the server.
+    Sqlite3 {
+        /// Arguments passed on to sqlite3, after `--`.
+        #[structopt(last = true)]
+        args: Vec<String>,
+    },
+    /// Explore the data of the running server interactively.
+    Repl {
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum GenerateCommand {
+    /// Scaffold a new model in the models directory.
+    Model {
+        /// Name of the model class.
+        name: String,
+        /// Description of the model, written to its doc comment.
+        #[structopt(long)]
+        description: Option<String>,
+        /// Table to keep the entities in, instead of one named after the model.
+        #[structopt(long)]
+        table_name: Option<String>,
+    },
+    /// Scaffold a CRUD endpoint for a model, along with the JSON Schema its
+    /// request bodies are validated against.
+    Endpoint {
+        /// Name of the model class.
+        model: String,
+        /// Path of the endpoint, which defaults to the model name in lowercase.
+        #[structopt(long)]
+        path: Option<String>,
+    },
+    /// Scaffold a pair of SQL files in the migrations directory, to migrate
+    /// the database by hand with `chisel migrate --apply`.
+    Migration {
+        /// What the migration does, for its file names.
+        #[structopt(long)]
+        name: String,
+    },
 }
 
 async fn delete<S: ToString>(server_url: String, version: S) -> Result<()> {
@@ -121,6 +327,45 @@ async fn delete<S: ToString>(server_url: String, version: S) -> Result<()> {
     Ok(())
 }
 
+async fn deprecate(server_url: String, version: String, sunset_date: String) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+
+    execute!(
+        client
+            .deprecate_version(tonic::Request::new(DeprecateVersionRequest {
+                version: version.clone(),
+                sunset_date: sunset_date.clone(),
+            }))
+            .await
+    );
+    println!("Deprecated {}, sunset on {}.", version, sunset_date);
+    Ok(())
+}
+
+async fn encrypt_existing(
+    server_url: String,
+    version: String,
+    type_name: String,
+    field_name: String,
+) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+
+    let response = execute!(
+        client
+            .encrypt_existing(tonic::Request::new(EncryptExistingRequest {
+                version,
+                type_name: type_name.clone(),
+                field_name: field_name.clone(),
+            }))
+            .await
+    );
+    println!(
+        "Encrypted {}.{} in {} rows.",
+        type_name, field_name, response.encrypted
+    );
+    Ok(())
+}
+
 async fn populate(server_url: String, to_version: String, from_version: String) -> Result<()> {
     let mut client = ChiselRpcClient::connect(server_url).await?;
 
@@ -167,7 +412,21 @@ async fn main() -> Result<()> {
             };
             create_project(&cwd, opts)?;
         }
-        Command::Describe => {
+        Command::Auth { cmd } => {
+            cmd_auth(server_url, cmd).await?;
+        }
+        Command::Describe {
+            type_name: Some(type_name),
+            version,
+            json,
+            api_addr,
+        } => {
+            let secret = admin_secret(server_url).await?;
+            cmd_describe_type(&api_addr, &secret, &version, &type_name, json)?;
+        }
+        Command::Describe {
+            type_name: None, ..
+        } => {
             let mut client = ChiselRpcClient::connect(server_url).await?;
             let request = tonic::Request::new(DescribeRequest {});
             let response = execute!(client.describe(request).await);
@@ -175,39 +434,45 @@ async fn main() -> Result<()> {
             for version_def in response.version_defs {
                 println!("Version: {} {{", version_def.version);
                 for def in &version_def.type_defs {
-                    println!("  class {} {{", def.name);
+                    if let Some(description) = &def.description {
+                        println!("  /** {} */", description);
+                    }
+                    for constraint in &def.unique_constraints {
+                        println!("  {}", unique_constraint_to_ts(constraint));
+                    }
+                    for constraint in &def.check_constraints {
+                        println!("  {}", check_constraint_to_ts(constraint));
+                    }
+                    for relation in &def.virtual_relations {
+                        println!("  {}", virtual_relation_to_ts(relation));
+                    }
+                    if def.multi_tenant {
+                        println!("  @multiTenant()");
+                    }
+                    if let Some(decorator) = id_strategy_to_ts(&def.id_strategy) {
+                        println!("  {}", decorator);
+                    }
+                    if let Some(webhook) = &def.webhook {
+                        println!("  {}", webhook_to_ts(webhook));
+                    }
+                    // Inherited fields are shown in the class they come from.
+                    let parent = def.parent.as_ref().and_then(|parent| {
+                        version_def.type_defs.iter().find(|t| &t.name == parent)
+                    });
+                    match parent {
+                        Some(parent) => println!("  class {} extends {} {{", def.name, parent.name),
+                        None => println!("  class {} {{", def.name),
+                    }
                     for field in &def.field_defs {
-                        let labels = if field.labels.is_empty() {
-                            "".into()
-                        } else {
-                            let mut labels = field
-                                .labels
-                                .iter()
-                                .map(|x| format!("\"{}\", ", x))
-                                .collect::<String>();
-                            // We add a , and a space in the map() function above to each element,
-                            // so for the last element we pop them both.
-                            labels.pop();
-                            labels.pop();
-                            format!("@labels({}) ", labels)
-                        };
-                        println!(
-                            "    {}{}{}{}: {}{};",
-                            if field.is_unique { "@unique " } else { "" },
-                            labels,
-                            field.name,
-                            if field.is_optional { "?" } else { "" },
-                            field.field_type,
-                            field
-                                .default_value
-                                .as_ref()
-                                .map(|d| if field.field_type == "string" {
-                                    format!(" = \"{}\"", d)
-                                } else {
-                                    format!(" = {}", d)
-                                })
-                                .unwrap_or_else(|| "".into()),
-                        );
+                        if parent
+                            .map_or(false, |p| p.field_defs.iter().any(|f| f.name == field.name))
+                        {
+                            continue;
+                        }
+                        if let Some(description) = &field.description {
+                            println!("    /** {} */", description);
+                        }
+                        println!("    {};", field_to_ts(field));
                     }
                     println!("  }}");
                 }
@@ -289,9 +554,125 @@ async fn main() -> Result<()> {
         Command::Delete { version } => {
             delete(server_url, version).await?;
         }
+        Command::Deprecate { version, sunset } => {
+            deprecate(server_url, version, sunset).await?;
+        }
+        Command::EncryptExisting {
+            type_name,
+            field,
+            version,
+        } => {
+            encrypt_existing(server_url, version, type_name, field).await?;
+        }
         Command::Populate { version, from } => {
             populate(server_url, version, from).await?;
         }
+        Command::Perf { cmd } => {
+            cmd_perf(cmd, &admin_secret(server_url).await?)?;
+        }
+        Command::Snapshot { cmd } => {
+            cmd_snapshot(server_url, cmd).await?;
+        }
+        Command::Validate {
+            version,
+            watch,
+            auto_apply,
+        } => {
+            cmd_validate(server_url, version, watch, auto_apply).await?;
+        }
+        Command::Diff { version, json } => {
+            cmd_diff(server_url, version, json).await?;
+        }
+        Command::Doctor {
+            version,
+            internal_addr,
+        } => {
+            cmd_doctor(server_url, internal_addr, version).await?;
+        }
+        Command::Migrate {
+            version,
+            generate,
+            description,
+            apply,
+            allow_type_deletion,
+            status,
+            fake,
+            fake_all,
+            squash,
+        } => {
+            let action = match (generate, apply, status) {
+                (true, _, _) => MigrateAction::Generate { description },
+                (_, true, _) => MigrateAction::Apply {
+                    allow_type_deletion,
+                },
+                (_, _, true) => MigrateAction::Status,
+                _ if fake.is_some() || fake_all => MigrateAction::Fake { migration: fake },
+                _ if !squash.is_empty() => MigrateAction::Squash {
+                    from: squash[0].clone(),
+                    to: squash[1].clone(),
+                },
+                _ => anyhow::bail!(
+                    "one of --generate, --apply, --status, --fake, --fake-all or --squash is needed"
+                ),
+            };
+            cmd_migrate(server_url, version, action).await?;
+        }
+        Command::Psql { args } => cmd_db(Client::Psql, args)?,
+        Command::Sqlite3 { args } => cmd_db(Client::Sqlite3, args)?,
+        Command::Repl { version } => {
+            cmd_repl(server_url, version).await?;
+        }
+        Command::Lint => {
+            cmd_lint().await?;
+        }
+        Command::Analyze {
+            log_file,
+            top,
+            api_addr,
+        } => {
+            // A log file can be analyzed without a server.
+            let secret = match log_file {
+                Some(_) => None,
+                None => Some(admin_secret(server_url).await?),
+            };
+            let opts = AnalyzeOptions {
+                log_file: log_file.as_deref(),
+                top,
+            };
+            cmd_analyze(&api_addr, secret.as_deref(), opts)?;
+        }
+        Command::Logs {
+            follow,
+            level,
+            request_id,
+            since,
+            api_addr,
+        } => {
+            let opts = LogsOptions {
+                follow,
+                level,
+                request_id,
+                since,
+            };
+            let secret = admin_secret(server_url).await?;
+            cmd_logs(&api_addr, &secret, opts)?;
+        }
+        Command::Generate { cmd } => match cmd {
+            GenerateCommand::Model {
+                name,
+                description,
+                table_name,
+            } => {
+                let cwd = env::current_dir()?;
+                create_model(&cwd, &name, description.as_deref(), table_name.as_deref())?;
+            }
+            GenerateCommand::Endpoint { model, path } => {
+                create_endpoint(&model, path.as_deref())?;
+            }
+            GenerateCommand::Migration { name } => {
+                scaffold_migration(&name)?;
+            }
+        },
     }
     Ok(())
 }
@@ -3,19 +3,21 @@
 use anyhow::{Context, Result};
 use handlebars::Handlebars;
 use serde_derive::Deserialize;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs;
 use std::io::{stdin, Read};
 use std::path::{Path, PathBuf};
 use utils::without_extension;
 
-const MANIFEST_FILE: &str = "Chisel.toml";
+pub(crate) const MANIFEST_FILE: &str = "Chisel.toml";
 const TYPES_DIR: &str = "./models";
 const ENDPOINTS_DIR: &str = "./endpoints";
 const LIB_DIR: &str = "./lib";
 const POLICIES_DIR: &str = "./policies";
 const VSCODE_DIR: &str = "./.vscode/";
+/// Suffix of the JSON Schema of the endpoint in the file of the same stem.
+const SCHEMA_EXTENSION: &str = ".schema.json";
 
 #[derive(Deserialize, PartialEq)]
 pub(crate) enum Module {
@@ -82,6 +84,8 @@ pub(crate) struct Manifest {
     /// Enable or disable auto-indexing.
     #[serde(default)]
     pub(crate) auto_index: AutoIndex,
+    /// URL of the database the server uses, passed as its `--db-uri`.
+    pub(crate) database_url: Option<String>,
 }
 
 impl Manifest {
@@ -90,7 +94,8 @@ impl Manifest {
     }
 
     pub fn endpoints(&self) -> anyhow::Result<Vec<PathBuf>> {
-        let ret = Self::dirs_to_paths(&self.endpoints)?;
+        let mut ret = Self::dirs_to_paths(&self.endpoints)?;
+        ret.retain(|p| !p.display().to_string().ends_with(SCHEMA_EXTENSION));
         // Check for duplicated endpoints now since otherwise TSC
         // reports the issue and we can produce a better diagnostic
         // than TSC.
@@ -105,6 +110,19 @@ impl Manifest {
         Ok(ret)
     }
 
+    /// The JSON Schemas of the endpoints, by the path of the endpoint
+    /// without its extension, as in `endpoints/orders`.
+    pub fn endpoint_schemas(&self) -> anyhow::Result<HashMap<String, String>> {
+        let mut schemas = HashMap::new();
+        for path in Self::dirs_to_paths(&self.endpoints)? {
+            let path = path.display().to_string();
+            if let Some(endpoint) = path.strip_suffix(SCHEMA_EXTENSION) {
+                schemas.insert(endpoint.to_owned(), read_to_string(&path)?);
+            }
+        }
+        Ok(schemas)
+    }
+
     pub fn policies(&self) -> anyhow::Result<Vec<PathBuf>> {
         Self::dirs_to_paths(&self.policies)
     }
@@ -270,6 +288,112 @@ pub(crate) fn create_project(path: &Path, opts: CreateProjectOptions) -> Result<
     Ok(())
 }
 
+/// Scaffolds a model called `name` in the models directory of the project
+/// at `path`. `description` goes in its doc comment, so it ends up in the
+/// type's generated schema.
+pub(crate) fn create_model(
+    path: &Path,
+    name: &str,
+    description: Option<&str>,
+    table_name: Option<&str>,
+) -> Result<()> {
+    anyhow::ensure!(
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && name.starts_with(|c: char| c.is_ascii_alphabetic()),
+        "`{}` is not a valid model name",
+        name
+    );
+    if let Some(table_name) = table_name {
+        // The server checks the same, but better to fail before writing the model.
+        anyhow::ensure!(
+            table_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && table_name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && table_name.len() <= 63,
+            "`{}` is not a valid SQL identifier for a table name",
+            table_name
+        );
+    }
+    let dir = path.join(TYPES_DIR);
+    let file = format!("{}.ts", name);
+    anyhow::ensure!(
+        !dir.join(&file).exists(),
+        "{} already exists",
+        Path::new(TYPES_DIR).join(&file).display()
+    );
+    fs::create_dir_all(&dir)?;
+
+    let description = description
+        .map(|d| d.replace("*/", "* /"))
+        .unwrap_or_else(|| "TODO: describe this model.".into());
+    let mut data = BTreeMap::new();
+    data.insert("modelName".to_string(), name);
+    data.insert("description".to_string(), description.as_str());
+    if let Some(table_name) = table_name {
+        data.insert("tableName".to_string(), table_name);
+    }
+    write_template!("model.ts", &file, data, &dir)?;
+    println!("Created {}", Path::new(TYPES_DIR).join(&file).display());
+    Ok(())
+}
+
+/// Scaffolds a CRUD endpoint for `model` in the endpoints directory of the
+/// project, at `name`, which defaults to the model name in lowercase. The
+/// endpoint comes with the JSON Schema of the model, which the bodies of
+/// the requests creating or replacing entities are validated against.
+pub(crate) fn create_endpoint(model: &str, name: Option<&str>) -> Result<()> {
+    let manifest = read_manifest()?;
+    let models = manifest.models()?;
+    let types = crate::ts::parse_types(&models)?;
+    let ty = types
+        .iter()
+        .find(|t| t.name == model)
+        .with_context(|| format!("there is no model named `{}`", model))?;
+    let model_file = models
+        .iter()
+        .find(|m| {
+            read_to_string(m).map_or(false, |code| code.contains(&format!("class {} ", model)))
+        })
+        .with_context(|| format!("could not find the file declaring `{}`", model))?;
+
+    let name = name.map_or_else(|| model.to_lowercase(), |n| n.trim_matches('/').to_owned());
+    let dir = Path::new(
+        manifest
+            .endpoints
+            .first()
+            .map_or(ENDPOINTS_DIR, |d| d.as_str()),
+    );
+    let file = dir.join(format!("{}.ts", name));
+    let schema_file = dir.join(format!("{}{}", name, SCHEMA_EXTENSION));
+    for f in [&file, &schema_file] {
+        anyhow::ensure!(!f.exists(), "{} already exists", f.display());
+    }
+    let dir = file.parent().unwrap();
+    fs::create_dir_all(dir)?;
+
+    let depth = dir.components().filter(|c| c.as_os_str() != ".").count();
+    let model_path = format!(
+        "{}{}",
+        "../".repeat(depth),
+        model_file.display().to_string().trim_start_matches("./")
+    );
+    let mut data = BTreeMap::new();
+    data.insert("modelName".to_string(), model);
+    data.insert("modelPath".to_string(), model_path.as_str());
+    write_template!(
+        "endpoint.ts",
+        file.file_name().unwrap().to_str().unwrap(),
+        data,
+        dir
+    )?;
+    let schema = crate::ts::json_schema(ty, &types);
+    fs::write(&schema_file, serde_json::to_string_pretty(&schema)? + "\n")?;
+    println!("Created {}", file.display());
+    println!("Created {}", schema_file.display());
+    Ok(())
+}
+
 pub(crate) fn project_exists(path: &Path) -> bool {
     path.join(Path::new(MANIFEST_FILE)).exists()
         || path.join(Path::new(TYPES_DIR)).exists()
@@ -2,6 +2,7 @@
 
 use crate::chisel::chisel_rpc_client::ChiselRpcClient;
 use crate::chisel::{StatusRequest, StatusResponse};
+use crate::cmd::db::database_url;
 use anyhow::Result;
 use std::future::Future;
 use std::io::ErrorKind;
@@ -26,7 +27,11 @@ pub(crate) fn start_server() -> anyhow::Result<std::process::Child> {
     let mut cmd = std::env::current_exe()?;
     cmd.pop();
     cmd.push("chiseld");
-    let server = match std::process::Command::new(cmd.clone()).spawn() {
+    let mut command = std::process::Command::new(cmd.clone());
+    if let Some(url) = database_url() {
+        command.arg("--db-uri").arg(url);
+    }
+    let server = match command.spawn() {
         Ok(server) => server,
         Err(e) => {
             match e.kind() {
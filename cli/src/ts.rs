@@ -1,16 +1,21 @@
-use crate::chisel::{AddTypeRequest, FieldDefinition};
+use crate::chisel::{
+    AddTypeRequest, CheckConstraintDefinition, FieldDefinition, UniqueConstraintDefinition,
+    VirtualRelationDefinition, WebhookDefinition,
+};
 use anyhow::{anyhow, bail, ensure, Context, Result};
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::path::Path;
+use swc_common::comments::{CommentKind, Comments, SingleThreadedComments};
 use swc_common::sync::Lrc;
 use swc_common::{
     errors::{emitter, Handler},
-    SourceMap, Spanned,
+    BytePos, SourceMap, Spanned,
 };
 use swc_ecma_ast::PropName;
 use swc_ecma_ast::{
-    ClassMember, ClassProp, Decl, Decorator, Expr, Ident, Lit, ModuleDecl, ModuleItem,
-    TsEntityName, TsKeywordTypeKind, TsType, TsTypeAnn,
+    ClassMember, ClassProp, Decl, Decorator, Expr, ExprOrSpread, Ident, Lit, Module, ModuleDecl,
+    ModuleItem, TsEntityName, TsKeywordTypeKind, TsLit, TsLitType, TsType, TsTypeAnn, TsTypeRef,
+    TsUnionOrIntersectionType, TsUnionType,
 };
 use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, TsConfig};
 use swc_ecmascript::ast as swc_ecma_ast;
@@ -28,6 +33,23 @@ fn get_ident_string(handler: &Handler, x: &Expr) -> Result<String> {
     }
 }
 
+/// The text of the JSDoc comment right before `pos`, without its tags.
+fn jsdoc(comments: &SingleThreadedComments, pos: BytePos) -> Option<String> {
+    let comment = comments
+        .get_leading(pos)?
+        .into_iter()
+        .rev()
+        .find(|c| c.kind == CommentKind::Block && c.text.starts_with('*'))?;
+    let text = comment.text[1..]
+        .lines()
+        .map(|line| line.trim().trim_start_matches('*').trim())
+        .filter(|line| !line.is_empty())
+        .take_while(|line| !line.starts_with('@'))
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!text.is_empty()).then(|| text)
+}
+
 fn ident_to_string(id: &Ident) -> String {
     id.sym.to_string()
 }
@@ -48,17 +70,75 @@ fn type_to_string(handler: &Handler, x: &TsType) -> Result<String> {
             _ => Err(swc_err(handler, x, "type keyword not supported")),
         },
         TsType::TsTypeRef(tr) => match &tr.type_name {
+            TsEntityName::Ident(id) if &*id.sym == "Record" => record_to_string(handler, tr),
             TsEntityName::Ident(id) => Ok(ident_to_string(id)),
             TsEntityName::TsQualifiedName(_) => Err(anyhow!("qualified names not supported")),
         },
-        TsType::TsArrayType(arr) => {
-            Ok("Array[".to_string() + &type_to_string(handler, &arr.elem_type)? + "]")
+        TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(union)) => {
+            enum_to_string(handler, union)
         }
+        TsType::TsArrayType(arr) => Ok(type_to_string(handler, &arr.elem_type)? + "[]"),
         TsType::TsOptionalType(opt) => Ok(type_to_string(handler, &opt.type_ann)? + "?"),
         t => Err(swc_err(handler, t, "type not supported")),
     }
 }
 
+/// A `Record<string, V>`, whose values `V` are scalars or `any`.
+fn record_to_string(handler: &Handler, tr: &TsTypeRef) -> Result<String> {
+    fn keyword(t: &TsType) -> Option<TsKeywordTypeKind> {
+        match t {
+            TsType::TsKeywordType(kw) => Some(kw.kind),
+            _ => None,
+        }
+    }
+    let params = tr.type_params.as_ref().map_or(&[][..], |p| &p.params[..]);
+    let value = match params {
+        [key, value] if keyword(key) == Some(TsKeywordTypeKind::TsStringKeyword) => {
+            match keyword(value) {
+                Some(TsKeywordTypeKind::TsAnyKeyword | TsKeywordTypeKind::TsUnknownKeyword) => {
+                    "any".into()
+                }
+                Some(_) => type_to_string(handler, value)?,
+                None => {
+                    return Err(swc_err(
+                        handler,
+                        tr,
+                        "Record values must be strings, numbers, booleans or any",
+                    ))
+                }
+            }
+        }
+        _ => return Err(swc_err(handler, tr, "only Record<string, V> is supported")),
+    };
+    Ok(format!("Record<string, {}>", value))
+}
+
+/// A union of string literals, as `"pending" | "active"`, written the way
+/// the server names enumerations.
+fn enum_to_string(handler: &Handler, union: &TsUnionType) -> Result<String> {
+    let mut variants = vec![];
+    for t in &union.types {
+        match &**t {
+            TsType::TsLitType(TsLitType {
+                lit: TsLit::Str(s), ..
+            }) if !s.value.contains('"') => variants.push(format!("\"{}\"", s.value)),
+            t => {
+                return Err(swc_err(
+                    handler,
+                    t,
+                    "only unions of string literals without double quotes are supported",
+                ))
+            }
+        }
+    }
+    Ok(variants.join(" | "))
+}
+
+/// Whether `field_type` names an enumeration, see [`enum_to_string`].
+fn is_enum_type(field_type: &str) -> bool {
+    field_type.starts_with('"')
+}
+
 fn get_field_type(handler: &Handler, x: &Option<TsTypeAnn>) -> Result<String> {
     let t = x.clone().context("type ann temporarily mandatory")?;
 
@@ -106,9 +186,21 @@ fn get_field_value(handler: &Handler, x: &Option<Box<Expr>>) -> Result<Option<(S
     }
 }
 
-fn get_type_decorators(handler: &Handler, x: &[Decorator]) -> Result<(Vec<String>, bool)> {
-    let mut output = vec![];
-    let mut is_unique = false;
+/// What the decorators of a class property say about its field.
+#[derive(Default)]
+struct FieldDecorators {
+    labels: Vec<String>,
+    is_unique: bool,
+    /// The column name given with `@column`, if different from the property name.
+    column: Option<String>,
+    is_deprecated: bool,
+    /// What `@deprecated` says to use instead.
+    deprecated_message: Option<String>,
+    is_encrypted: bool,
+}
+
+fn get_type_decorators(handler: &Handler, x: &[Decorator]) -> Result<FieldDecorators> {
+    let mut output = FieldDecorators::default();
     for dec in x.iter() {
         match &*dec.expr {
             Expr::Call(call) => {
@@ -117,32 +209,232 @@ fn get_type_decorators(handler: &Handler, x: &[Decorator]) -> Result<(Vec<String
                 })?;
                 let name = get_ident_string(handler, &callee)?;
                 ensure!(
-                    name == "labels",
+                    name == "labels" || name == "column" || name == "deprecated",
                     format!("decorator '{}' is not supported by ChiselStrike", name)
                 );
+                let mut args = vec![];
                 for arg in &call.args {
-                    if let Some((label, ty)) = get_field_value(handler, &Some(arg.expr.clone()))? {
-                        ensure!(ty == "string", "Only strings accepted as labels");
-                        output.push(label);
+                    if let Some((arg, ty)) = get_field_value(handler, &Some(arg.expr.clone()))? {
+                        ensure!(ty == "string", "Only strings accepted as {}", name);
+                        args.push(arg);
                     }
                 }
+                if name == "labels" {
+                    output.labels.extend(args);
+                } else if name == "deprecated" {
+                    ensure!(args.len() <= 1, "@deprecated takes at most one message");
+                    output.is_deprecated = true;
+                    output.deprecated_message = args.pop();
+                } else {
+                    ensure!(args.len() == 1, "@column takes exactly one column name");
+                    output.column = args.pop();
+                }
             }
             Expr::Ident(x) => {
                 let name = ident_to_string(x);
-                ensure!(name != "labels", "expected a call-like decorator");
-
                 ensure!(
-                    name == "unique",
-                    format!("decorator '{}' is not supported by ChiselStrike", name)
+                    name != "labels" && name != "column" && name != "deprecated",
+                    "expected a call-like decorator"
                 );
-                is_unique = true;
+
+                match name.as_str() {
+                    "unique" => output.is_unique = true,
+                    "encrypted" => output.is_encrypted = true,
+                    _ => bail!("decorator '{}' is not supported by ChiselStrike", name),
+                }
             }
             z => {
                 return Err(swc_err(handler, z, "expected a call-like decorator"));
             }
         };
     }
-    Ok((output, is_unique))
+    Ok(output)
+}
+
+/// What the decorators of a class say about its type.
+#[derive(Default)]
+struct ClassDecorators {
+    unique_constraints: Vec<UniqueConstraintDefinition>,
+    check_constraints: Vec<CheckConstraintDefinition>,
+    virtual_relations: Vec<VirtualRelationDefinition>,
+    multi_tenant: bool,
+    /// The strategy given with `@idStrategy`, as the server parses it.
+    id_strategy: Option<String>,
+    /// The backing table given with `@tableName`.
+    table_name: Option<String>,
+    webhook: Option<WebhookDefinition>,
+}
+
+fn string_arg(handler: &Handler, arg: &ExprOrSpread, what: &str) -> Result<String> {
+    match get_field_value(handler, &Some(arg.expr.clone()))? {
+        Some((value, ty)) if ty == "string" => Ok(value),
+        _ => bail!("{} must be a string", what),
+    }
+}
+
+/// A `@uniqueTogether(fields, name?)` with the given `args`.
+fn parse_unique_together(
+    handler: &Handler,
+    args: &[ExprOrSpread],
+) -> Result<UniqueConstraintDefinition> {
+    let (fields, name) = match args {
+        [fields] => (fields, None),
+        [fields, name] => (fields, Some(name)),
+        _ => bail!("@uniqueTogether takes a list of fields and an optional name"),
+    };
+    let fields = match &*fields.expr {
+        Expr::Array(array) => array
+            .elems
+            .iter()
+            .flatten()
+            .map(|elem| string_arg(handler, elem, "@uniqueTogether fields"))
+            .collect::<Result<Vec<_>>>()?,
+        z => return Err(swc_err(handler, z, "expected a list of fields")),
+    };
+    let name = name
+        .map(|name| string_arg(handler, name, "@uniqueTogether name"))
+        .transpose()?;
+    Ok(UniqueConstraintDefinition { fields, name })
+}
+
+fn get_class_decorators(handler: &Handler, x: &[Decorator]) -> Result<ClassDecorators> {
+    let mut output = ClassDecorators::default();
+    for dec in x.iter() {
+        let call = match &*dec.expr {
+            Expr::Call(call) => call,
+            z => return Err(swc_err(handler, z, "expected a call-like decorator")),
+        };
+        let callee =
+            call.callee.clone().expr().ok_or_else(|| {
+                anyhow!("expected expression, got {:?} instead", call.callee.clone())
+            })?;
+        let name = get_ident_string(handler, &callee)?;
+        match name.as_str() {
+            "uniqueTogether" => output
+                .unique_constraints
+                .push(parse_unique_together(handler, &call.args)?),
+            "check" => match &call.args[..] {
+                [name, expression] => output.check_constraints.push(CheckConstraintDefinition {
+                    name: string_arg(handler, name, "@check name")?,
+                    expression: string_arg(handler, expression, "@check expression")?,
+                }),
+                _ => bail!("@check takes a name and an SQL expression"),
+            },
+            "relation" => match &call.args[..] {
+                [name, target_type, subquery] => {
+                    output.virtual_relations.push(VirtualRelationDefinition {
+                        name: string_arg(handler, name, "@relation name")?,
+                        target_type: string_arg(handler, target_type, "@relation target type")?,
+                        subquery: string_arg(handler, subquery, "@relation subquery")?,
+                    })
+                }
+                _ => bail!("@relation takes a name, a target type and an SQL subquery"),
+            },
+            "multiTenant" => {
+                ensure!(call.args.is_empty(), "@multiTenant takes no arguments");
+                output.multi_tenant = true;
+            }
+            "idStrategy" => {
+                let strategy = match &call.args[..] {
+                    [strategy] => string_arg(handler, strategy, "@idStrategy strategy")?,
+                    [strategy, machine_id] => {
+                        let strategy = string_arg(handler, strategy, "@idStrategy strategy")?;
+                        let machine_id =
+                            match get_field_value(handler, &Some(machine_id.expr.clone()))? {
+                                Some((value, ty)) if ty == "number" => value,
+                                _ => bail!("the machine id of @idStrategy must be a number"),
+                            };
+                        ensure!(
+                            strategy == "snowflake",
+                            "only snowflake ids take a machine id"
+                        );
+                        format!("{}:{}", strategy, machine_id)
+                    }
+                    _ => bail!("@idStrategy takes a strategy, and a machine id for snowflake ids"),
+                };
+                output.id_strategy = Some(strategy);
+            }
+            "tableName" => match &call.args[..] {
+                [table_name] => {
+                    output.table_name = Some(string_arg(handler, table_name, "@tableName table")?)
+                }
+                _ => bail!("@tableName takes the name of a table"),
+            },
+            "webhook" => match &call.args[..] {
+                [url, events, secret] => {
+                    let events = match &*events.expr {
+                        Expr::Array(array) => array
+                            .elems
+                            .iter()
+                            .flatten()
+                            .map(|elem| string_arg(handler, elem, "@webhook events"))
+                            .collect::<Result<Vec<_>>>()?,
+                        z => return Err(swc_err(handler, z, "expected a list of events")),
+                    };
+                    output.webhook = Some(WebhookDefinition {
+                        url: string_arg(handler, url, "@webhook URL")?,
+                        secret: string_arg(handler, secret, "@webhook secret name")?,
+                        events,
+                    });
+                }
+                _ => bail!("@webhook takes a URL, a list of events and the name of a secret"),
+            },
+            _ => bail!(
+                "class decorator '{}' is not supported by ChiselStrike",
+                name
+            ),
+        }
+    }
+    Ok(output)
+}
+
+/// Adds the fields of the models that the types in `type_vec` extend to
+/// them, ahead of their own, as the server keeps a type and its fields
+/// together whatever they were inherited from.
+fn add_inherited_fields(type_vec: &mut [AddTypeRequest]) -> Result<()> {
+    let own_fields: HashMap<String, Vec<FieldDefinition>> = type_vec
+        .iter()
+        .map(|t| (t.name.clone(), t.field_defs.clone()))
+        .collect();
+    let parents: HashMap<String, Option<String>> = type_vec
+        .iter()
+        .map(|t| (t.name.clone(), t.parent.clone()))
+        .collect();
+    for t in type_vec.iter_mut() {
+        let mut inherited = vec![];
+        let mut ancestors = vec![t.name.clone()];
+        let mut parent = t.parent.clone();
+        while let Some(name) = parent {
+            ensure!(
+                !ancestors.contains(&name),
+                "class {} extends itself through {}",
+                t.name,
+                name
+            );
+            let fields = own_fields.get(&name).with_context(|| {
+                format!(
+                    "class {} extends {}, which is neither ChiselEntity nor a model",
+                    ancestors.last().unwrap(),
+                    name
+                )
+            })?;
+            inherited.splice(0..0, fields.iter().cloned());
+            parent = parents[&name].clone();
+            ancestors.push(name);
+        }
+        for field in &t.field_defs {
+            if let Some(field) = inherited.iter().find(|f| f.name == field.name) {
+                bail!(
+                    "field {} of class {} is already defined by the class it extends",
+                    field.name,
+                    t.name
+                );
+            }
+        }
+        inherited.append(&mut t.field_defs);
+        t.field_defs = inherited;
+    }
+    Ok(())
 }
 
 fn validate_type_vec(type_vec: &[AddTypeRequest], valid_types: &BTreeSet<String>) -> Result<()> {
@@ -150,11 +442,20 @@ fn validate_type_vec(type_vec: &[AddTypeRequest], valid_types: &BTreeSet<String>
     builtin_types.insert("string");
     builtin_types.insert("number");
     builtin_types.insert("boolean");
+    builtin_types.insert("Blob");
+    builtin_types.insert("string[]");
+    builtin_types.insert("number[]");
+    builtin_types.insert("boolean[]");
+    builtin_types.insert("Record<string, string>");
+    builtin_types.insert("Record<string, number>");
+    builtin_types.insert("Record<string, boolean>");
+    builtin_types.insert("Record<string, any>");
     builtin_types.insert("AuthUser");
 
     for t in type_vec {
         for field in t.field_defs.iter() {
             if builtin_types.get(&field.field_type as &str).is_none()
+                && !is_enum_type(&field.field_type)
                 && valid_types.get(&field.field_type).is_none()
             {
                 bail!("field {} in class {} neither a basic type, nor refers to a type defined in this context",
@@ -166,29 +467,230 @@ fn validate_type_vec(type_vec: &[AddTypeRequest], valid_types: &BTreeSet<String>
     Ok(())
 }
 
-fn parse_class_prop(x: &ClassProp, class_name: &str, handler: &Handler) -> Result<FieldDefinition> {
+fn parse_class_prop(
+    x: &ClassProp,
+    class_name: &str,
+    handler: &Handler,
+    comments: &SingleThreadedComments,
+) -> Result<FieldDefinition> {
+    let is_union = matches!(
+        x.type_ann.as_ref().map(|ann| &*ann.type_ann),
+        Some(TsType::TsUnionOrIntersectionType(_))
+    );
     let (default_value, field_type) = match get_field_value(handler, &x.value)? {
         None => (None, get_field_type(handler, &x.type_ann)?),
+        // The literal only says it's a string, the union which ones it can be.
+        Some((val, _)) if is_union => (Some(val), get_field_type(handler, &x.type_ann)?),
         Some((val, t)) => (Some(val), t),
     };
     let (field_name, is_optional) = get_field_info(handler, &x.key)?;
 
     anyhow::ensure!(field_name != "id", "Creating a field with the name `id` is not supported. 😟\nBut don't worry! ChiselStrike creates an id field automatically, and you can access it in your endpoints as {}.id 🤩", class_name);
 
-    let (labels, is_unique) = get_type_decorators(handler, &x.decorators)?;
+    let decorators = get_type_decorators(handler, &x.decorators)?;
+    // With `@column`, the property name is only what the field goes by in JSON.
+    let (name, json_name) = match decorators.column {
+        Some(column) if column != field_name => (column, Some(field_name)),
+        _ => (field_name, None),
+    };
 
     Ok(FieldDefinition {
-        name: field_name,
+        name,
         is_optional,
-        is_unique,
+        is_unique: decorators.is_unique,
         default_value,
         field_type,
-        labels,
+        labels: decorators.labels,
+        json_name,
+        description: jsdoc(comments, x.span.lo),
+        is_deprecated: decorators.is_deprecated,
+        deprecated_message: decorators.deprecated_message,
+        is_encrypted: decorators.is_encrypted,
     })
 }
 
+/// `field` as the TypeScript property it would be declared with, decorators included.
+pub(crate) fn field_to_ts(field: &FieldDefinition) -> String {
+    let mut ts = String::new();
+    if field.is_unique {
+        ts.push_str("@unique ");
+    }
+    if field.is_encrypted {
+        ts.push_str("@encrypted ");
+    }
+    if !field.labels.is_empty() {
+        let labels: Vec<_> = field.labels.iter().map(|x| format!("\"{}\"", x)).collect();
+        ts.push_str(&format!("@labels({}) ", labels.join(", ")));
+    }
+    let name = match &field.json_name {
+        Some(json_name) => {
+            ts.push_str(&format!("@column(\"{}\") ", field.name));
+            json_name
+        }
+        None => &field.name,
+    };
+    if field.is_deprecated {
+        match &field.deprecated_message {
+            Some(message) => ts.push_str(&format!("@deprecated(\"{}\") ", message)),
+            None => ts.push_str("@deprecated() "),
+        }
+    }
+    ts.push_str(name);
+    if field.is_optional {
+        ts.push('?');
+    }
+    ts.push_str(": ");
+    ts.push_str(&field.field_type);
+    match &field.default_value {
+        Some(d) if field.field_type == "string" || is_enum_type(&field.field_type) => {
+            ts.push_str(&format!(" = \"{}\"", d))
+        }
+        Some(d) => ts.push_str(&format!(" = {}", d)),
+        None => {}
+    }
+    ts
+}
+
+/// `constraint` as the decorator it would be declared with.
+pub(crate) fn check_constraint_to_ts(constraint: &CheckConstraintDefinition) -> String {
+    format!(
+        "@check(\"{}\", \"{}\")",
+        constraint.name, constraint.expression
+    )
+}
+
+/// `relation` as the decorator it would be declared with.
+pub(crate) fn virtual_relation_to_ts(relation: &VirtualRelationDefinition) -> String {
+    format!(
+        "@relation(\"{}\", \"{}\", \"{}\")",
+        relation.name, relation.target_type, relation.subquery
+    )
+}
+
+/// The `@idStrategy` decorator declaring `id_strategy`, as the server gives
+/// it, unless it is the default.
+pub(crate) fn id_strategy_to_ts(id_strategy: &str) -> Option<String> {
+    match id_strategy.split_once(':') {
+        _ if id_strategy.is_empty() || id_strategy == "uuid" => None,
+        Some((strategy, machine_id)) => {
+            Some(format!("@idStrategy(\"{}\", {})", strategy, machine_id))
+        }
+        None => Some(format!("@idStrategy(\"{}\")", id_strategy)),
+    }
+}
+
+/// `webhook` as the decorator it would be declared with.
+pub(crate) fn webhook_to_ts(webhook: &WebhookDefinition) -> String {
+    let events: Vec<_> = webhook
+        .events
+        .iter()
+        .map(|e| format!("\"{}\"", e))
+        .collect();
+    format!(
+        "@webhook(\"{}\", [{}], \"{}\")",
+        webhook.url,
+        events.join(", "),
+        webhook.secret
+    )
+}
+
+/// `constraint` as the decorator it would be declared with.
+pub(crate) fn unique_constraint_to_ts(constraint: &UniqueConstraintDefinition) -> String {
+    let fields: Vec<_> = constraint
+        .fields
+        .iter()
+        .map(|f| format!("\"{}\"", f))
+        .collect();
+    match &constraint.name {
+        Some(name) => format!("@uniqueTogether([{}], \"{}\")", fields.join(", "), name),
+        None => format!("@uniqueTogether([{}])", fields.join(", ")),
+    }
+}
+
+/// The JSON Schema of the bodies that create an instance of `ty`, whose
+/// related entities are among `types`.
+pub(crate) fn json_schema(ty: &AddTypeRequest, types: &[AddTypeRequest]) -> serde_json::Value {
+    let mut schema = object_schema(ty, types, &mut vec![]);
+    schema["$schema"] = "http://json-schema.org/draft-07/schema#".into();
+    schema["title"] = ty.name.clone().into();
+    schema
+}
+
+/// `ty` as an object schema. `outer` holds the types it is nested in, so
+/// that cyclic relations end in a plain object.
+fn object_schema<'a>(
+    ty: &'a AddTypeRequest,
+    types: &'a [AddTypeRequest],
+    outer: &mut Vec<&'a str>,
+) -> serde_json::Value {
+    outer.push(&ty.name);
+    let mut properties = serde_json::Map::new();
+    properties.insert("id".into(), serde_json::json!({ "type": "string" }));
+    let mut required = vec![];
+    for field in &ty.field_defs {
+        let key = field.json_name.as_ref().unwrap_or(&field.name);
+        let mut property = field_type_schema(&field.field_type, types, outer);
+        if let Some(description) = &field.description {
+            property["description"] = description.clone().into();
+        }
+        properties.insert(key.clone(), property);
+        if !field.is_optional && field.default_value.is_none() && !field.is_deprecated {
+            required.push(key.clone());
+        }
+    }
+    outer.pop();
+    serde_json::json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+fn field_type_schema<'a>(
+    field_type: &str,
+    types: &'a [AddTypeRequest],
+    outer: &mut Vec<&'a str>,
+) -> serde_json::Value {
+    if let Some(element) = field_type.strip_suffix("[]") {
+        return serde_json::json!({
+            "type": "array",
+            "items": field_type_schema(element, types, outer),
+        });
+    }
+    if let Some(value) = field_type
+        .strip_prefix("Record<string, ")
+        .and_then(|t| t.strip_suffix('>'))
+    {
+        return match value {
+            "any" => serde_json::json!({ "type": "object" }),
+            value => serde_json::json!({
+                "type": "object",
+                "additionalProperties": field_type_schema(value, types, outer),
+            }),
+        };
+    }
+    if is_enum_type(field_type) {
+        let variants: Vec<_> = field_type
+            .split(" | ")
+            .map(|v| v.trim_matches('"'))
+            .collect();
+        return serde_json::json!({ "enum": variants });
+    }
+    match field_type {
+        "string" | "number" | "boolean" => serde_json::json!({ "type": field_type }),
+        // Blobs are sent in whatever form the endpoint reads them.
+        "Blob" => serde_json::json!({}),
+        entity => match types.iter().find(|t| t.name == entity) {
+            Some(ty) if !outer.iter().any(|t| *t == entity) => object_schema(ty, types, outer),
+            _ => serde_json::json!({ "type": "object" }),
+        },
+    }
+}
+
 fn parse_class_decl<P: AsRef<Path>>(
     handler: &Handler,
+    comments: &SingleThreadedComments,
+    description: Option<String>,
     filename: &P,
     type_vec: &mut Vec<AddTypeRequest>,
     valid_types: &mut BTreeSet<String>,
@@ -204,15 +706,18 @@ fn parse_class_decl<P: AsRef<Path>>(
 
             for member in &x.class.body {
                 match member {
-                    ClassMember::ClassProp(x) => match parse_class_prop(x, &name, handler) {
-                        Err(err) => {
-                            handler.span_err(x.span(), &format!("While parsing class {}", name));
-                            bail!("{}", err);
-                        }
-                        Ok(fd) => {
-                            field_defs.push(fd);
+                    ClassMember::ClassProp(x) => {
+                        match parse_class_prop(x, &name, handler, comments) {
+                            Err(err) => {
+                                handler
+                                    .span_err(x.span(), &format!("While parsing class {}", name));
+                                bail!("{}", err);
+                            }
+                            Ok(fd) => {
+                                field_defs.push(fd);
+                            }
                         }
-                    },
+                    }
                     ClassMember::Constructor(_x) => {
                         handler.span_err(member.span(), "Constructors not allowed in ChiselStrike model definitions. Consider adding default values so one is not needed, or call ChiselEntity's create method");
                         bail!("invalid type file {}", filename.as_ref().display());
@@ -220,7 +725,26 @@ fn parse_class_decl<P: AsRef<Path>>(
                     _ => {}
                 }
             }
-            type_vec.push(AddTypeRequest { name, field_defs });
+            let parent = match x.class.super_class.as_deref() {
+                Some(Expr::Ident(ident)) if &*ident.sym != "ChiselEntity" => {
+                    Some(ident_to_string(ident))
+                }
+                _ => None,
+            };
+            let decorators = get_class_decorators(handler, &x.class.decorators)?;
+            type_vec.push(AddTypeRequest {
+                name,
+                field_defs,
+                description,
+                unique_constraints: decorators.unique_constraints,
+                check_constraints: decorators.check_constraints,
+                virtual_relations: decorators.virtual_relations,
+                parent,
+                multi_tenant: decorators.multi_tenant,
+                id_strategy: decorators.id_strategy,
+                table_name: decorators.table_name,
+                webhook: decorators.webhook,
+            });
         }
         z => {
             handler.span_err(z.span(), "Only class definitions allowed in the types file");
@@ -230,22 +754,24 @@ fn parse_class_decl<P: AsRef<Path>>(
     Ok(())
 }
 
-fn parse_one_file<P: AsRef<Path>>(
-    filename: &P,
-    type_vec: &mut Vec<AddTypeRequest>,
-    valid_types: &mut BTreeSet<String>,
-) -> Result<()> {
-    let cm: Lrc<SourceMap> = Default::default();
-
+/// A handler printing the parse errors of files in `cm` to stderr.
+pub(crate) fn error_handler(cm: &Lrc<SourceMap>) -> Handler {
     let emitter = Box::new(emitter::EmitterWriter::new(
         Box::new(std::io::stderr()),
         Some(cm.clone()),
         false,
         true,
     ));
+    Handler::with_emitter(true, false, emitter)
+}
 
-    let handler = Handler::with_emitter(true, false, emitter);
-
+/// Parses the TypeScript module in `filename`, keeping its comments in `comments`.
+pub(crate) fn parse_module<P: AsRef<Path>>(
+    cm: &Lrc<SourceMap>,
+    handler: &Handler,
+    comments: &SingleThreadedComments,
+    filename: &P,
+) -> Result<Module> {
     let fm = cm.load_file(filename.as_ref())?;
 
     let mut config = TsConfig {
@@ -259,28 +785,48 @@ fn parse_one_file<P: AsRef<Path>>(
         Syntax::Typescript(config),
         Default::default(),
         StringInput::from(&*fm),
-        None,
+        Some(comments),
     );
 
     let mut parser = Parser::new_from(lexer);
     let mut errors = false;
     for e in parser.take_errors() {
         errors = true;
-        e.into_diagnostic(&handler).emit();
+        e.into_diagnostic(handler).emit();
     }
     if errors {
         bail!("Exiting on parsing errors");
     }
 
-    let x = parser.parse_typescript_module().map_err(|e| {
-        e.into_diagnostic(&handler).emit();
+    parser.parse_typescript_module().map_err(|e| {
+        e.into_diagnostic(handler).emit();
         anyhow!("Exiting on script parsing errors")
-    })?;
+    })
+}
+
+fn parse_one_file<P: AsRef<Path>>(
+    filename: &P,
+    type_vec: &mut Vec<AddTypeRequest>,
+    valid_types: &mut BTreeSet<String>,
+) -> Result<()> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let handler = error_handler(&cm);
+    let comments = SingleThreadedComments::default();
+    let x = parse_module(&cm, &handler, &comments, filename)?;
 
     for decl in &x.body {
         match decl {
             ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(exp)) => {
-                parse_class_decl(&handler, filename, type_vec, valid_types, &exp.decl)?;
+                let description = jsdoc(&comments, exp.span.lo);
+                parse_class_decl(
+                    &handler,
+                    &comments,
+                    description,
+                    filename,
+                    type_vec,
+                    valid_types,
+                    &exp.decl,
+                )?;
             }
             ModuleItem::ModuleDecl(ModuleDecl::Import(_)) => {
                 // Right now just accept imports, but don't try to parse them.
@@ -307,6 +853,7 @@ pub(crate) fn parse_types<P: AsRef<Path>>(files: &[P]) -> Result<Vec<AddTypeRequ
         parse_one_file(filename, &mut type_vec, &mut valid_types)?;
     }
 
+    add_inherited_fields(&mut type_vec)?;
     validate_type_vec(&type_vec, &valid_types)?;
     Ok(type_vec)
 }
@@ -1,7 +1,11 @@
 use std::env;
+use std::net::TcpListener;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 use std::process;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
 
 pub struct Command {
     inner: process::Command,
@@ -61,3 +65,117 @@ pub fn repo_dir() -> PathBuf {
     path.pop();
     path
 }
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// The `CHISELD_AUTH_SECRET` of the servers started by [`TestServer::start`].
+#[allow(dead_code)]
+pub const AUTH_SECRET: &str = "end-to-end";
+
+/// A `chiseld` of its own, for end-to-end tests.
+///
+/// Every server listens on ports of its own and keeps its database in a
+/// directory of its own, so tests using one can run in parallel. The
+/// database is a SQLite file rather than `sqlite::memory:`, as each
+/// connection to an in-memory database would see a different one.
+#[allow(dead_code)]
+pub struct TestServer {
+    process: process::Child,
+    _dir: TempDir,
+    pub base_url: String,
+    pub client: reqwest::Client,
+}
+
+#[allow(dead_code)]
+impl TestServer {
+    /// Starts a server whose auth and admin routes take [`AUTH_SECRET`], and
+    /// waits for it to accept requests.
+    pub async fn start() -> Self {
+        Self::start_with_args(&[], Some(AUTH_SECRET)).await
+    }
+
+    /// Starts a server configured without an auth secret.
+    pub async fn without_auth_secret() -> Self {
+        Self::start_with_args(&[], None).await
+    }
+
+    /// Starts a server that puts the tables of the types it is given in
+    /// `namespace`, see `chiseld --namespace`.
+    pub async fn with_namespace(namespace: &str) -> Self {
+        Self::start_with_args(&["--namespace", namespace], Some(AUTH_SECRET)).await
+    }
+
+    async fn start_with_args(extra_args: &[&str], auth_secret: Option<&str>) -> Self {
+        static BUILD: Once = Once::new();
+        BUILD.call_once(|| {
+            let mut args = vec!["build"];
+            if bin_dir().ends_with("release") {
+                args.push("--release");
+            }
+            drop(run("cargo", args));
+        });
+
+        let dir = TempDir::new().unwrap();
+        if let Some(secret) = auth_secret {
+            let secrets = format!(r#"{{"CHISELD_AUTH_SECRET": "{}"}}"#, secret);
+            std::fs::write(dir.path().join(".env"), secrets).unwrap();
+        }
+        let api_addr = format!("127.0.0.1:{}", free_port());
+        let process = process::Command::new(bin_dir().join("chiseld"))
+            .args([
+                "--api-listen-addr",
+                &api_addr,
+                "--rpc-listen-addr",
+                &format!("127.0.0.1:{}", free_port()),
+                "--internal-routes-listen-addr",
+                &format!("127.0.0.1:{}", free_port()),
+                "--db-uri",
+                &format!(
+                    "sqlite://{}?mode=rwc",
+                    dir.path().join("chiseld.db").display()
+                ),
+            ])
+            .args(extra_args)
+            .env(
+                "CHISEL_SECRET_LOCATION",
+                format!("file://{}", dir.path().join(".env").display()),
+            )
+            .current_dir(dir.path())
+            .spawn()
+            .unwrap();
+        let server = TestServer {
+            process,
+            _dir: dir,
+            base_url: format!("http://{}", api_addr),
+            client: reqwest::Client::new(),
+        };
+
+        let started = Instant::now();
+        while server.client.get(&server.base_url).send().await.is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(30),
+                "chiseld didn't start listening on {}",
+                api_addr
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        server
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
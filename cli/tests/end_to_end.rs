@@ -0,0 +1,111 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{TestServer, AUTH_SECRET};
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn auth_sessions() {
+        let server = TestServer::start().await;
+        let client = &server.client;
+
+        let user: Value = client
+            .post(server.url("/__chiselstrike/auth/users"))
+            .header("ChiselAuth", AUTH_SECRET)
+            .json(&json!({"name": "Foo", "email": "foo@t.co"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(user["email"], "foo@t.co");
+        let user_id = user["id"].as_str().unwrap();
+
+        let session =
+            json!({"sessionToken": "tok1-secret", "userId": user_id, "expires": "2999-12-31"});
+        let res = client
+            .post(server.url("/__chiselstrike/auth/sessions"))
+            .header("ChiselAuth", AUTH_SECRET)
+            .json(&session)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+
+        let sessions_url = server.url("/__chiselstrike/admin/users/foo%40t.co/sessions");
+        let sessions: Value = client
+            .get(&sessions_url)
+            .header("ChiselAuth", AUTH_SECRET)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(sessions["meta"]["schema_version"], 1);
+        let sessions = &sessions["data"];
+        assert_eq!(sessions.as_array().unwrap().len(), 1);
+        assert_eq!(sessions[0]["token_prefix"], "tok1-sec");
+
+        let revoked: Value = client
+            .delete(&sessions_url)
+            .header("ChiselAuth", AUTH_SECRET)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(revoked["data"]["revoked"], 1);
+        let sessions: Value = client
+            .get(&sessions_url)
+            .header("ChiselAuth", AUTH_SECRET)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(sessions["data"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn admin_routes_without_auth_secret() {
+        let server = TestServer::without_auth_secret().await;
+        let versions = server.url("/__chiselstrike/admin/versions");
+        let res = server.client.get(&versions).send().await.unwrap();
+        assert_eq!(res.status(), 403);
+        let res = server
+            .client
+            .get(&versions)
+            .header("ChiselAuth", "")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 403);
+        // The auth routes keep accepting requests when no secret is configured.
+        let res = server
+            .client
+            .get(server.url("/__chiselstrike/auth/users"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn unknown_route() {
+        let server = TestServer::start().await;
+        let res = server
+            .client
+            .get(server.url("/dev/nothing"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+    }
+}
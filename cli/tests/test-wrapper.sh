@@ -10,6 +10,7 @@ export TEMPDIR=$(mktemp -d)
 cwd=$(pwd)
 
 export CHISEL_SECRET_LOCATION="file://$TEMPDIR/.env"
+export CHISEL_FIELD_ENCRYPTION_KEY="lit test secret"
 
 EXTENSION=`basename "$2" | cut -d'.' -f2`
 
@@ -1,11 +1,50 @@
 use api::chisel_d_ts;
 use api::chisel_js;
+use sha2::{Digest, Sha256};
 use std::env;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread;
 
-fn run_in<T: IntoIterator<Item = &'static str>>(cmd: &str, args: T, dir: PathBuf) {
+#[path = "src/npm.rs"]
+mod npm;
+
+/// npm older than this doesn't handle the lockfiles of the packages.
+const MIN_NPM_VERSION: (u64, u64, u64) = (8, 0, 0);
+
+/// Where `npm install` records the hash of the `package-lock.json` it installed.
+const STAMP: &str = "node_modules/.npminstall-stamp";
+
+/// How `npm install` is run, as picked with `CHISEL_NPM_INSTALL`.
+#[derive(Clone, Copy, PartialEq)]
+enum InstallMode {
+    /// Install when `package-lock.json` changed since the last install.
+    Auto,
+    /// Like `Auto`, but never reach the registry: fail if nothing was
+    /// installed yet, and install from the npm cache otherwise.
+    Offline,
+    /// Fail if the installed packages are not current, without installing (for CI).
+    Check,
+}
+
+impl InstallMode {
+    fn from_env() -> Self {
+        println!("cargo:rerun-if-env-changed=CHISEL_NPM_INSTALL");
+        match env::var("CHISEL_NPM_INSTALL").as_deref() {
+            Err(_) | Ok("") | Ok("auto") => InstallMode::Auto,
+            Ok("offline") => InstallMode::Offline,
+            Ok("check") => InstallMode::Check,
+            Ok(other) => panic!(
+                "CHISEL_NPM_INSTALL must be auto, offline or check, not {}",
+                other
+            ),
+        }
+    }
+}
+
+fn run_in<'a, T: IntoIterator<Item = &'a str>>(cmd: &str, args: T, dir: PathBuf) {
     assert!(
         dir.exists(),
         "{:?} does not exist. Current directory is {:?}",
@@ -27,7 +66,103 @@ fn run_in<T: IntoIterator<Item = &'static str>>(cmd: &str, args: T, dir: PathBuf
     assert!(status.unwrap().success());
 }
 
+/// Warns if npm is older than [`MIN_NPM_VERSION`], and fails if there is no npm at all.
+fn check_npm_version() {
+    let output = match Command::new("npm").arg("--version").output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => panic!(
+            "npm was not found in PATH. Building ChiselStrike needs Node.js and npm: \
+            install Node.js from https://nodejs.org or with your package manager"
+        ),
+        Err(e) => panic!("failed to run `npm --version`: {}", e),
+    };
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    let parts: Vec<u64> = version
+        .split('.')
+        .map(|n| n.parse())
+        .collect::<Result<_, _>>()
+        .unwrap_or_default();
+    let (major, minor, patch) = MIN_NPM_VERSION;
+    match parts[..] {
+        [a, b, c] if (a, b, c) >= MIN_NPM_VERSION => {}
+        [_, _, _] => println!(
+            "cargo:warning=npm version {} is below the minimum required {}.{}.{}. Build may fail.",
+            version, major, minor, patch
+        ),
+        _ => println!("cargo:warning=could not parse npm version {:?}", version),
+    }
+}
+
+fn lockfile_hash(dir: &Path) -> String {
+    let lockfile = dir.join("package-lock.json");
+    let lockfile =
+        fs::read(&lockfile).unwrap_or_else(|e| panic!("failed to read {:?}: {}", lockfile, e));
+    format!("{:x}", Sha256::digest(&lockfile))
+}
+
+/// The registry to install from: `CHISEL_NPM_REGISTRY` if set, or the one
+/// npm is configured with otherwise.
+fn npm_registry() -> Option<String> {
+    println!("cargo:rerun-if-env-changed=CHISEL_NPM_REGISTRY");
+    let var = env::var("CHISEL_NPM_REGISTRY").ok();
+    npm::registry(var.as_deref()).map(str::to_owned)
+}
+
+/// Runs `npm install` in `dir`, unless the stamp says it was already run
+/// for the current `package-lock.json`.
+fn npm_install(dir: PathBuf, mode: InstallMode, registry: Option<&str>) {
+    let stamp = dir.join(STAMP);
+    let installed = fs::read_to_string(&stamp).ok();
+    if installed.as_deref() == Some(lockfile_hash(&dir).as_str()) {
+        return;
+    }
+    match (mode, installed) {
+        (InstallMode::Auto, _) => run_in("npm", npm::install_args(registry, false), dir.clone()),
+        (InstallMode::Offline, Some(_)) => {
+            run_in("npm", npm::install_args(registry, true), dir.clone())
+        }
+        (InstallMode::Offline, None) => panic!(
+            "{:?} has never been installed, which can't be done offline. Run `npm install` in it first",
+            dir
+        ),
+        (InstallMode::Check, _) => panic!(
+            "the packages installed in {:?} don't match its package-lock.json. Run `npm install` in it",
+            dir
+        ),
+    }
+    fs::write(&stamp, lockfile_hash(&dir)).unwrap();
+}
+
+/// The packages to build: all of them, or only the one named by `CHISEL_BUILD_PACKAGE`.
+fn selected_packages(packages: Vec<PathBuf>) -> Vec<PathBuf> {
+    println!("cargo:rerun-if-env-changed=CHISEL_BUILD_PACKAGE");
+    let name = match env::var("CHISEL_BUILD_PACKAGE") {
+        Ok(name) if !name.is_empty() => name,
+        _ => return packages,
+    };
+    let is_selected = |dir: &PathBuf| dir.file_name().unwrap() == name.as_str();
+    if !packages.iter().any(is_selected) {
+        println!(
+            "cargo:warning=CHISEL_BUILD_PACKAGE names no package: {}. Building all of them",
+            name
+        );
+        return packages;
+    }
+    packages.into_iter().filter(is_selected).collect()
+}
+
+/// Installs and builds the npm package in `dir`.
+fn build_package(dir: PathBuf, mode: InstallMode, registry: Option<String>) {
+    npm_install(dir.clone(), mode, registry.as_deref());
+    run_in("npm", ["run", "build"], dir);
+}
+
 fn main() {
+    check_npm_version();
+    let mode = InstallMode::from_env();
+    let registry = npm_registry();
+    println!("cargo:rerun-if-env-changed=CHISEL_NPM_SEQUENTIAL");
+    let sequential = env::var_os("CHISEL_NPM_SEQUENTIAL").is_some();
     let create_app = Path::new("./create-chiselstrike-app").to_path_buf();
     let api = Path::new("./chiselstrike-api").to_path_buf();
 
@@ -37,22 +172,51 @@ fn main() {
     // build create-chiselstrike-app so we can use it in tests
     for v in [
         "create-chiselstrike-app/index.ts",
-        "create-chiselstrike-app/template/Chisel.toml",
-        "create-chiselstrike-app/template/hello.ts",
-        "create-chiselstrike-app/template/package.json",
-        "create-chiselstrike-app/template/settings.json",
-        "create-chiselstrike-app/template/tsconfig.json",
+        "create-chiselstrike-app/template",
         "create-chiselstrike-app/tsconfig.json",
         "create-chiselstrike-app/package.json",
+        "create-chiselstrike-app/package-lock.json",
     ] {
         println!("cargo:rerun-if-changed=./{}", v);
     }
-    run_in("npm", ["install"], create_app.clone());
-    run_in("npm", ["run", "build"], create_app);
-
-    for v in ["chiselstrike-api/package.json"] {
+    // Not lib/ as a whole, since chisel.js and chisel.d.ts are written there above.
+    for v in [
+        "chiselstrike-api/src",
+        "chiselstrike-api/lib/lib.deno_core.d.ts",
+        "chiselstrike-api/package.json",
+        "chiselstrike-api/package-lock.json",
+    ] {
         println!("cargo:rerun-if-changed=./{}", v);
     }
-    run_in("npm", ["install"], api.clone());
-    run_in("npm", ["run", "build"], api);
+
+    let packages = selected_packages(vec![create_app, api]);
+    if sequential {
+        for dir in packages {
+            build_package(dir, mode, registry.clone());
+        }
+        return;
+    }
+    // The packages don't depend on each other, so they are built in parallel.
+    let builds: Vec<_> = packages
+        .into_iter()
+        .map(|dir| {
+            let registry = registry.clone();
+            let build = {
+                let dir = dir.clone();
+                thread::spawn(move || build_package(dir, mode, registry))
+            };
+            (dir, build)
+        })
+        .collect();
+    let mut failed = false;
+    for (dir, build) in builds {
+        // The panic message was already printed by the thread that failed.
+        if build.join().is_err() {
+            eprintln!("building {:?} failed", dir);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,3 @@
+import { {{modelName}} } from "{{{modelPath}}}";
+
+export default {{modelName}}.crud();
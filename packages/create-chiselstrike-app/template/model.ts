@@ -0,0 +1,12 @@
+import { ChiselEntity{{#if tableName}}, tableName{{/if}} } from "@chiselstrike/api";
+
+/**
+ * {{{description}}}
+ */
+{{#if tableName}}
+@tableName("{{tableName}}")
+{{/if}}
+export class {{modelName}} extends ChiselEntity {
+    /** TODO: describe this field. */
+    name: string = "";
+}
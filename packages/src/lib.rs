@@ -1,3 +1,5 @@
 // This file exists since Cargo can't have an empty project.
 // We just want build.rs to be executed to build the packages
 // at build time.
+
+pub mod npm;
@@ -0,0 +1,40 @@
+//! How build.rs runs npm, kept here so that it can be tested.
+
+/// The registry named by `var`, the value of `CHISEL_NPM_REGISTRY`, or None
+/// to install from the one npm is configured with.
+pub fn registry(var: Option<&str>) -> Option<&str> {
+    var.filter(|r| !r.is_empty())
+}
+
+/// The arguments of `npm install`, installing from `registry` if any, and
+/// only from the npm cache if `offline`.
+pub fn install_args(registry: Option<&str>, offline: bool) -> Vec<&str> {
+    let mut args = vec!["install"];
+    if let Some(registry) = registry {
+        args.extend(["--registry", registry]);
+    }
+    if offline {
+        args.push("--offline");
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registries() {
+        let url = "https://npm.example.com/";
+        assert_eq!(
+            install_args(registry(Some(url)), false),
+            ["install", "--registry", url]
+        );
+        assert_eq!(
+            install_args(registry(Some(url)), true),
+            ["install", "--registry", url, "--offline"]
+        );
+        assert_eq!(install_args(registry(Some("")), false), ["install"]);
+        assert_eq!(install_args(registry(None), true), ["install", "--offline"]);
+    }
+}
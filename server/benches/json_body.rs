@@ -0,0 +1,66 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! The allocations made for the body of a collection of 100 entities, when
+//! it is serialized and when it is served from a cache. Run it with
+//! `cargo bench -p server --bench json_body`.
+
+use chisel_server::bench::{bytes_body, json_body};
+use hyper::body::Bytes;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts the allocations of the benchmark.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations(f: &dyn Fn()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let entities: Vec<_> = (0..100)
+        .map(|i| serde_json::json!({"id": i.to_string(), "name": "Alice", "age": 30}))
+        .collect();
+    let results = serde_json::json!({ "results": entities });
+
+    // What Body::json did when bodies held a Box<[u8]>.
+    let boxed = allocations(&|| {
+        let json = serde_json::to_string(&results).unwrap();
+        drop(json.into_boxed_str().into_boxed_bytes());
+    });
+    let serialized = allocations(&|| json_body(&results).unwrap());
+
+    let cached = Bytes::from(serde_json::to_vec(&results).unwrap());
+    // The first clone makes the buffer shared, once and for all.
+    drop(cached.clone());
+    let copied = allocations(&|| drop(cached.to_vec().into_boxed_slice()));
+    let shared = allocations(&|| bytes_body(cached.clone()));
+
+    println!("serialized: {} allocations, was {}", serialized, boxed);
+    println!("cached: {} allocations, was {}", shared, copied);
+    assert!(serialized <= boxed);
+    assert_eq!(shared, 0);
+    assert!(copied > 0);
+}
@@ -0,0 +1,30 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! How long finding a route takes among 20 to 20 000 of them, which should
+//! barely change. Run it with `cargo bench -p server --bench routing`.
+
+use chisel_server::bench::Routes;
+use std::time::Instant;
+
+const LOOKUPS: u32 = 100_000;
+
+fn main() {
+    let mut times = vec![];
+    for versions in [10, 100, 1_000, 10_000] {
+        let mut routes = Routes::default();
+        for i in 0..versions {
+            routes.add(&format!("/v{}/items/:id", i)).unwrap();
+            routes.add(&format!("/v{}/static", i)).unwrap();
+        }
+        let request = format!("/v{}/items/42", versions - 1);
+        let last = routes.find(&request).unwrap();
+        let start = Instant::now();
+        for _ in 0..LOOKUPS {
+            assert_eq!(routes.find(&request), Some(last));
+        }
+        let per_lookup = start.elapsed() / LOOKUPS;
+        println!("{:>6} routes: {:?} per lookup", versions * 2, per_lookup);
+        times.push(per_lookup);
+    }
+    assert!(times[3] < times[0] * 5, "{:?}", times);
+}
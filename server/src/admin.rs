@@ -0,0 +1,560 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Administrative routes under `/__chiselstrike/admin`.
+
+use crate::api::{
+    json_response, query_param, response_template, version_param, ApiService, Body, Middleware,
+    RouteFn, StreamingBody,
+};
+use crate::auth::{admin_header_refusal, decode_username, list_active_sessions, revoke_sessions};
+use crate::backup::{self, Format};
+use crate::context::{query_engine_route, RequestContext};
+use crate::datastore::QueryEngine;
+use crate::deno;
+use crate::multipart::{multipart_route, store_upload, MultipartBody};
+use crate::route_pattern::{route_param, RouteParams};
+use crate::types::{ObjectType, Type, TypeSystem};
+use crate::JsonObject;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use deno_core::futures::future::LocalBoxFuture;
+use deno_core::futures::FutureExt;
+use deno_core::url::form_urlencoded;
+use enclose::enclose;
+use hyper::http::request::Parts;
+use hyper::{Method, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Guards admin routes with the same secret as the auth endpoints, see
+/// [`admin_header_refusal`]. Unlike those, they refuse every request when
+/// there is no such secret, except for the server's own.
+pub(crate) struct AdminAuth;
+
+impl Middleware for AdminAuth {
+    fn call(
+        &self,
+        req: Request<hyper::Body>,
+        next: RouteFn,
+    ) -> LocalBoxFuture<'static, Result<Response<Body>>> {
+        async move {
+            let secrets = deno::current_worker_secrets();
+            if let Some(refusal) = admin_header_refusal(&secrets, req.headers().get("ChiselAuth")) {
+                return ApiService::forbidden(refusal);
+            }
+            next(req).await
+        }
+        .boxed_local()
+    }
+}
+
+/// Each database maintenance operation runs at most once in this long, as
+/// it can hold locks on the tables for a while.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// When each database maintenance operation last started, for all the
+/// executor threads.
+static LAST_MAINTENANCE: Lazy<Mutex<HashMap<&'static str, Instant>>> = Lazy::new(Default::default);
+
+/// Rows are inserted in transactions of this many rows at a time.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+async fn insert_batch(
+    qeng: &QueryEngine,
+    ty: &ObjectType,
+    batch: &mut Vec<JsonObject>,
+) -> Result<()> {
+    let mut transaction = qeng.start_transaction().await?;
+    for row in batch.drain(..) {
+        qeng.add_row(ty, &row, Some(&mut transaction)).await?;
+    }
+    QueryEngine::commit_transaction(transaction).await
+}
+
+/// Responds with the custom types of API version `:version`.
+async fn types(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let version = route_param(&req, "version")?;
+    let context = RequestContext::of(&req)?;
+    let type_system = context.type_system();
+    let mut types: Vec<_> = type_system
+        .get_version(&version)?
+        .custom_types
+        .values()
+        .map(|ty| &**ty)
+        .collect();
+    types.sort_by(|a, b| a.name().cmp(b.name()));
+    json_response(StatusCode::OK, &types)
+}
+
+/// The columns of `ty` with their constraints, the indexes that cover
+/// them, and the types they refer to, as `chisel describe` shows them.
+fn describe(ty: &ObjectType) -> serde_json::Value {
+    let indexes: Vec<_> = ty
+        .indexes()
+        .iter()
+        .map(|index| {
+            let name = index
+                .name()
+                .unwrap_or_else(|| index.planned_name(ty.backing_table()));
+            (name, &index.fields)
+        })
+        .collect();
+    let fields: Vec<_> = ty
+        .all_fields()
+        .map(|field| {
+            let covering: Vec<_> = indexes
+                .iter()
+                .filter(|(_, fields)| fields.contains(&field.name))
+                .map(|(name, _)| name)
+                .collect();
+            serde_json::json!({
+                "name": field.name,
+                "type": field.type_.name(),
+                "nullable": field.is_optional,
+                "default": field.user_provided_default(),
+                "unique": field.is_unique,
+                "indexes": covering,
+            })
+        })
+        .collect();
+    let indexes: Vec<_> = indexes
+        .iter()
+        .map(|(name, fields)| serde_json::json!({ "name": name, "fields": fields }))
+        .collect();
+    let unique_constraints: Vec<_> = ty
+        .unique_constraints()
+        .iter()
+        .map(|c| {
+            serde_json::json!({
+                "name": c.index_name(ty.backing_table()),
+                "fields": c.fields,
+            })
+        })
+        .collect();
+    let foreign_keys: Vec<_> = ty
+        .user_fields()
+        .filter_map(|field| match &field.type_ {
+            Type::Object(target) => Some(serde_json::json!({
+                "field": field.name,
+                "references": target.name(),
+                "table": target.backing_table(),
+            })),
+            _ => None,
+        })
+        .collect();
+    serde_json::json!({
+        "name": ty.name(),
+        "version": ty.api_version,
+        "table": ty.backing_table(),
+        "parent": ty.parent(),
+        "fields": fields,
+        "indexes": indexes,
+        "unique_constraints": unique_constraints,
+        "foreign_keys": foreign_keys,
+    })
+}
+
+/// Responds with the description of type `:type` of API version `:version`.
+async fn describe_type(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let version = route_param(&req, "version")?;
+    let type_name = route_param(&req, "type")?;
+    let context = RequestContext::of(&req)?;
+    match context
+        .type_system()
+        .lookup_object_type(&type_name, &version)
+    {
+        Ok(ty) => json_response(StatusCode::OK, describe(&ty)),
+        Err(e) => json_response(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({ "error": e.to_string() }),
+        ),
+    }
+}
+
+/// Responds with the statistics of the backing table of a type, of the
+/// version in `?version=`. Its row count is an estimate, see
+/// [`QueryEngine::estimated_row_count`].
+async fn table_stats(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let type_name = route_param(&req, "type")?;
+    let version = version_param(&req);
+    let context = RequestContext::of(&req)?;
+    let ty = match context
+        .type_system()
+        .lookup_object_type(&type_name, &version)
+    {
+        Ok(ty) => ty,
+        Err(e) => {
+            return json_response(
+                StatusCode::NOT_FOUND,
+                serde_json::json!({ "error": e.to_string() }),
+            )
+        }
+    };
+    json_response(StatusCode::OK, qeng.table_stats(&ty).await?)
+}
+
+/// Imports newline-delimited JSON objects into a type.
+///
+/// The body is consumed as it arrives, so the size of the import is not
+/// bounded by memory. Each batch is committed on its own, which means a
+/// failure halfway through leaves the earlier batches in place.
+async fn import(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let version = route_param(&req, "version")?;
+    let type_name = route_param(&req, "type")?;
+    let context = RequestContext::of(&req)?;
+    let ty = context
+        .type_system()
+        .lookup_object_type(&type_name, &version)?;
+    anyhow::ensure!(!ty.is_auth(), "Cannot import into type {}", type_name);
+
+    let mut body = StreamingBody::from(req.into_body());
+    let mut pending: Vec<u8> = vec![];
+    let mut batch = vec![];
+    let mut imported = 0;
+    loop {
+        let chunk = body.read_chunk().await?;
+        let done = chunk.is_none();
+        match chunk {
+            Some(chunk) => pending.extend_from_slice(&chunk),
+            // Treat leftovers as the last line, even without a trailing newline.
+            None => pending.push(b'\n'),
+        }
+        while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=pos).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            batch.push(serde_json::from_slice::<JsonObject>(&line)?);
+            if batch.len() == IMPORT_BATCH_SIZE {
+                imported += batch.len();
+                insert_batch(&qeng, &ty, &mut batch).await?;
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    imported += batch.len();
+    insert_batch(&qeng, &ty, &mut batch).await?;
+
+    json_response(StatusCode::OK, serde_json::json!({ "imported": imported }))
+}
+
+/// Creates an object from a multipart body, its parts being named after the
+/// fields they set. Files are written to the blob store, and their fields hold
+/// the URLs they are served at. Other parts are taken as string values.
+async fn upload(parts: Parts, body: MultipartBody) -> Result<Response<Body>> {
+    let param = |name: &str| {
+        parts
+            .extensions
+            .get::<RouteParams>()
+            .and_then(|p| p.0.get(name))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Internal error: missing {} in route", name))
+    };
+    let (version, type_name) = (param("version")?, param("type")?);
+    let context = RequestContext::from_extensions(&parts.extensions)?;
+    let ty = context
+        .type_system()
+        .lookup_object_type(&type_name, &version)?;
+    anyhow::ensure!(!ty.is_auth(), "Cannot upload into type {}", type_name);
+    let store = context.blob_store;
+
+    let mut object = JsonObject::new();
+    for field in body.into_fields() {
+        let name = field.name.clone();
+        if field.file_name.is_some() {
+            store_upload(store.as_ref(), field, &ty, &name, &mut object).await?;
+        } else {
+            let value = String::from_utf8(field.data.to_vec())
+                .with_context(|| format!("field {} is not UTF-8", name))?;
+            object.insert(name, value.into());
+        }
+    }
+    let id = context.query_engine.add_row(&ty, &object, None).await?.id;
+    object.insert("id".into(), id.into());
+    json_response(StatusCode::OK, object)
+}
+
+async fn delete_blob(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let key = route_param(&req, "*")?;
+    let store = RequestContext::of(&req)?.blob_store;
+    store.delete(&key).await?;
+    json_response(StatusCode::OK, serde_json::json!({ "deleted": key }))
+}
+
+fn backup_format(req: &Request<hyper::Body>) -> Result<Format> {
+    let query = req.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "format")
+        .map(|(_, v)| v.parse())
+        .unwrap_or(Ok(Format::Sqlite))
+}
+
+/// Downloads a backup of the database, as `?format=sqlite` (the default), `json` or `csv`.
+async fn backup(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let format = backup_format(&req)?;
+    let ts = RequestContext::of(&req)?.type_system().clone();
+    let dir = TempDir::new()?;
+    let path = backup::backup(&qeng, &ts, format, dir.path()).await?;
+    let filename = format!(
+        "chisel_backup_{}.{}",
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        format.extension()
+    );
+    Ok(response_template()
+        .header("Content-Type", "application/octet-stream")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename={}", filename),
+        )
+        .body(backup::file_body(&path).await?)?)
+}
+
+/// Restores a backup downloaded from the backup route, given the same `format`.
+async fn restore(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let format = backup_format(&req)?;
+    let ts = RequestContext::of(&req)?.type_system().clone();
+    let restored = backup::restore(&qeng, &ts, format, req.into_body().into()).await?;
+    json_response(StatusCode::OK, serde_json::json!({ "restored": restored }))
+}
+
+/// Claims the maintenance `operation` for now, unless it started less than
+/// [`MAINTENANCE_INTERVAL`] ago. Returns how long to wait in that case.
+fn claim_maintenance(operation: &'static str) -> Result<(), Duration> {
+    let mut last = LAST_MAINTENANCE.lock().unwrap();
+    let now = Instant::now();
+    if let Some(started) = last.get(operation) {
+        let elapsed = now.duration_since(*started);
+        if elapsed < MAINTENANCE_INTERVAL {
+            return Err(MAINTENANCE_INTERVAL - elapsed);
+        }
+    }
+    last.insert(operation, now);
+    Ok(())
+}
+
+/// Runs the maintenance `operation`, unless it ran recently, in which case
+/// this responds with `429 Too Many Requests`. A failed operation can be
+/// retried right away.
+async fn run_maintenance(
+    operation: &'static str,
+    run: impl Future<Output = Result<()>>,
+) -> Result<Response<Body>> {
+    if let Err(wait) = claim_maintenance(operation) {
+        let message = format!(
+            "database {} already ran in the last hour, retry in {} seconds",
+            operation,
+            wait.as_secs()
+        );
+        let mut response = json_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            serde_json::json!({ "error": message }),
+        )?;
+        response
+            .headers_mut()
+            .insert(hyper::header::RETRY_AFTER, wait.as_secs().into());
+        return Ok(response);
+    }
+    info!("Running database {}", operation);
+    let start = Instant::now();
+    if let Err(e) = run.await {
+        LAST_MAINTENANCE.lock().unwrap().remove(operation);
+        return Err(e);
+    }
+    let elapsed = start.elapsed();
+    info!("Database {} took {:?}", operation, elapsed);
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({ "operation": operation, "duration_ms": elapsed.as_millis() as u64 }),
+    )
+}
+
+/// Runs `VACUUM` on the database, see [`QueryEngine::vacuum`].
+async fn vacuum(_req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    run_maintenance("vacuum", qeng.vacuum()).await
+}
+
+/// Runs `ANALYZE` on the backing table of the type in `?type=`, of the
+/// version in `?version=`, or on all the tables if there is none.
+async fn analyze(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let ty = match query_param(&req, "type") {
+        Some(type_name) => {
+            let context = RequestContext::of(&req)?;
+            let type_system = context.type_system();
+            Some(type_system.lookup_object_type(&type_name, &version_param(&req))?)
+        }
+        None => None,
+    };
+    run_maintenance("analyze", qeng.analyze(ty.as_ref())).await
+}
+
+async fn get_sessions(
+    req: Request<hyper::Body>,
+    qeng: Arc<QueryEngine>,
+    ts: Arc<TypeSystem>,
+) -> Result<Response<Body>> {
+    let username = decode_username(&route_param(&req, "username")?)?;
+    let sessions = list_active_sessions(&qeng, &ts, &username).await?;
+    json_response(StatusCode::OK, &sessions)
+}
+
+async fn delete_sessions(
+    req: Request<hyper::Body>,
+    qeng: Arc<QueryEngine>,
+    ts: Arc<TypeSystem>,
+) -> Result<Response<Body>> {
+    let username = decode_username(&route_param(&req, "username")?)?;
+    let revoked = revoke_sessions(&qeng, &ts, &username).await?;
+    json_response(StatusCode::OK, serde_json::json!({ "revoked": revoked }))
+}
+
+/// Migrates the tables of all the types that weren't yet, as their first
+/// queries would with `--lazy-migrations`. See [`QueryEngine::migrate_lazily`].
+async fn warmup_migrations(
+    req: Request<hyper::Body>,
+    qeng: Arc<QueryEngine>,
+) -> Result<Response<Body>> {
+    let ts = RequestContext::of(&req)?.type_system().clone();
+    let mut migrated = 0;
+    for version_types in ts.versions.values() {
+        for ty in version_types.custom_types.values() {
+            migrated += qeng.migrate_lazily(ty).await?;
+        }
+    }
+    json_response(StatusCode::OK, serde_json::json!({ "migrated": migrated }))
+}
+
+/// Responds with the numbered API versions that are active and those that
+/// are deprecated, with the dates they go away on.
+async fn versions(api: ApiService) -> Result<Response<Body>> {
+    let deprecated = api.deprecated_versions();
+    let active: Vec<_> = api
+        .route_versions()
+        .into_iter()
+        .filter(|version| !deprecated.contains_key(version))
+        .map(|version| version.to_string())
+        .collect();
+    let deprecated: Vec<_> = deprecated
+        .into_iter()
+        .map(|(version, sunset)| {
+            serde_json::json!({
+                "version": version.to_string(),
+                "sunset": sunset.format("%Y-%m-%d").to_string(),
+            })
+        })
+        .collect();
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({ "active": active, "deprecated": deprecated }),
+    )
+}
+
+/// Registers the admin routes, which are implemented natively rather than in JavaScript.
+pub(crate) fn init(api: &ApiService, ts: &TypeSystem) -> Result<()> {
+    let ts = Arc::new(ts.clone());
+    let admin = api
+        .add_route_group("/__chiselstrike/admin", vec![Arc::new(AdminAuth)])
+        .json_body();
+    // For routes taking files rather than JSON.
+    let files = admin.accepts_any_content_type();
+    let user = admin.group("/users/:username", vec![]);
+
+    user.add_route(
+        Method::GET,
+        "/sessions",
+        query_engine_route(enclose! { (ts) move |req, qeng| {
+            get_sessions(req, qeng, ts.clone())
+        }}),
+    )?;
+    user.add_route(
+        Method::DELETE,
+        "/sessions",
+        query_engine_route(move |req, qeng| delete_sessions(req, qeng, ts.clone())),
+    )?;
+
+    files.add_route(
+        Method::POST,
+        "/upload/:version/:type",
+        multipart_route(upload),
+    )?;
+    admin.add_route(
+        Method::DELETE,
+        "/blobs/*",
+        Arc::new(|req| delete_blob(req).boxed_local()),
+    )?;
+    admin.add_route(Method::POST, "/backup", query_engine_route(backup))?;
+    admin.add_route(Method::POST, "/database/vacuum", query_engine_route(vacuum))?;
+    admin.add_route(
+        Method::POST,
+        "/database/analyze",
+        query_engine_route(analyze),
+    )?;
+    files.add_route(Method::POST, "/restore", query_engine_route(restore))?;
+    admin.add_route(
+        Method::POST,
+        "/migrate/warmup",
+        query_engine_route(warmup_migrations),
+    )?;
+    admin.add_route(Method::GET, "/versions", {
+        let api = api.clone();
+        Arc::new(move |_req| versions(api.clone()).boxed_local())
+    })?;
+    admin.add_route(
+        Method::GET,
+        "/types/:version",
+        Arc::new(|req| types(req).boxed_local()),
+    )?;
+    admin.add_route(
+        Method::GET,
+        "/types/:version/:type",
+        Arc::new(|req| describe_type(req).boxed_local()),
+    )?;
+    admin.add_route(
+        Method::GET,
+        "/tables/:type/stats",
+        query_engine_route(table_stats),
+    )?;
+    files.add_route(
+        Method::POST,
+        "/import/:version/:type",
+        query_engine_route(import),
+    )?;
+    #[cfg(feature = "jemalloc")]
+    {
+        use crate::heap;
+        admin.add_route(
+            Method::GET,
+            "/heap/stats",
+            Arc::new(|_req| heap::stats().boxed_local()),
+        )?;
+        admin.add_route(
+            Method::POST,
+            "/heap/dump",
+            Arc::new(|_req| heap::dump().boxed_local()),
+        )?;
+    }
+    #[cfg(feature = "profiling")]
+    {
+        use crate::profiler;
+        admin.add_route(
+            Method::POST,
+            "/profiler/start",
+            Arc::new(|_req| profiler::start().boxed_local()),
+        )?;
+        admin.add_route(
+            Method::POST,
+            "/profiler/stop",
+            Arc::new(|_req| profiler::stop().boxed_local()),
+        )?;
+    }
+    admin.add_route(
+        Method::GET,
+        "/logs/stream",
+        Arc::new(|req| crate::logs::stream(req).boxed_local()),
+    )
+}
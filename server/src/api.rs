@@ -1,20 +1,30 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
-use crate::prefix_map::PrefixMap;
+use crate::context::RequestContext;
+use crate::datastore::engine::QueryError;
+use crate::entities::DeprecatedFieldWrite;
+use crate::logs;
+use crate::route_pattern::RouteParams;
+use crate::route_trie::RouteTrie;
+use crate::route_version::{self, ApiVersionError, RouteVersion};
+use crate::transactions::TransactionError;
 use anyhow::{Error, Result};
+use chrono::NaiveDate;
 use deno_core::futures;
+use deno_core::url::form_urlencoded;
 use futures::future::LocalBoxFuture;
 use futures::ready;
 use futures::stream::Stream;
-use hyper::body::HttpBody;
+use futures::FutureExt;
+use hyper::body::{Bytes, HttpBody};
 use hyper::header::HeaderValue;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{HeaderMap, Request, Response, Server, StatusCode};
+use hyper::{HeaderMap, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::convert::TryFrom;
-use std::io::Cursor;
 use std::net::ToSocketAddrs;
 use std::path::Path;
 use std::path::PathBuf;
@@ -22,17 +32,34 @@ use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 type JsStream = Pin<Box<dyn Stream<Item = Result<Box<[u8]>>>>>;
 
+/// A request body that is consumed as it arrives, rather than buffered whole.
+pub(crate) struct StreamingBody(hyper::Body);
+
+impl From<hyper::Body> for StreamingBody {
+    fn from(body: hyper::Body) -> Self {
+        Self(body)
+    }
+}
+
+impl StreamingBody {
+    /// Waits for the next chunk of the body, or returns None at the end of it.
+    pub(crate) async fn read_chunk(&mut self) -> Result<Option<Bytes>> {
+        Ok(self.0.data().await.transpose()?)
+    }
+}
+
 pub(crate) enum Body {
-    Const(Option<Box<[u8]>>),
+    Const(Option<Bytes>),
     Stream(JsStream),
 }
 
 impl From<String> for Body {
     fn from(a: String) -> Self {
-        Body::Const(Some(a.into_boxed_str().into_boxed_bytes()))
+        Body::from_bytes(a.into())
     }
 }
 
@@ -42,8 +69,22 @@ impl Default for Body {
     }
 }
 
+impl Body {
+    /// A body sending `bytes` as they are, without copying them. Handy for
+    /// data that is already in memory, like a cached response or a blob.
+    pub(crate) fn from_bytes(bytes: Bytes) -> Self {
+        Body::Const(Some(bytes))
+    }
+
+    /// A body holding `value` as JSON. The `Content-Type` is left to the
+    /// response, which [`json_response`] takes care of.
+    pub(crate) fn json(value: impl Serialize) -> serde_json::Result<Self> {
+        Ok(Body::from_bytes(serde_json::to_vec(&value)?.into()))
+    }
+}
+
 impl HttpBody for Body {
-    type Data = Cursor<Box<[u8]>>;
+    type Data = Bytes;
     type Error = Error;
 
     fn poll_data(
@@ -51,9 +92,9 @@ impl HttpBody for Body {
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
         let r = match self.get_mut() {
-            Body::Const(ref mut inner) => inner.take().map(|x| Ok(Cursor::new(x))),
+            Body::Const(ref mut inner) => inner.take().map(Ok),
             Body::Stream(ref mut stream) => {
-                ready!(stream.as_mut().poll_next(cx)).map(|x| x.map(Cursor::new))
+                ready!(stream.as_mut().poll_next(cx)).map(|x| x.map(Bytes::from))
             }
         };
         Poll::Ready(r)
@@ -72,10 +113,83 @@ impl HttpBody for Body {
 //
 // At the same time, we need to make this clonable to be able to sanely use interior mutability
 // inside the ApiService struct, so we need some reference counted type instead of a Box
-type RouteFn = Arc<
+//
+// The futures they return are not Send, though: JavaScript endpoints run on the V8 isolate of the
+// executor thread serving the request, and an isolate can't move between threads. Multiple cores
+// are used by running one executor, each with its own single-threaded runtime, per core instead.
+pub(crate) type RouteFn = Arc<
     dyn Fn(Request<hyper::Body>) -> LocalBoxFuture<'static, Result<Response<Body>>> + Send + Sync,
 >;
 
+/// An error that knows which HTTP response it should become when a route fails with it.
+pub(crate) trait HttpError: std::error::Error + Send + Sync + 'static {
+    fn status_code(&self) -> StatusCode;
+
+    fn body(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl HttpError for QueryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            QueryError::VersionConflict(..)
+            | QueryError::UniqueViolation(..)
+            | QueryError::OtherTenant(_) => StatusCode::CONFLICT,
+            QueryError::NotNullable(..) => StatusCode::UNPROCESSABLE_ENTITY,
+            QueryError::CheckConstraintViolation { .. } | QueryError::EncryptedField(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            QueryError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+impl HttpError for DeprecatedFieldWrite {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+impl HttpError for ApiVersionError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+impl HttpError for TransactionError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TransactionError::Unknown(_) => StatusCode::NOT_FOUND,
+            TransactionError::InUse(_) => StatusCode::CONFLICT,
+        }
+    }
+}
+
+/// The first error in the chain of `err` that is an [`HttpError`], if any.
+fn http_error(err: &Error) -> Option<&dyn HttpError> {
+    err.chain().find_map(|cause| {
+        cause
+            .downcast_ref::<QueryError>()
+            .map(|e| e as &dyn HttpError)
+            .or_else(|| {
+                cause
+                    .downcast_ref::<DeprecatedFieldWrite>()
+                    .map(|e| e as &dyn HttpError)
+            })
+            .or_else(|| {
+                cause
+                    .downcast_ref::<ApiVersionError>()
+                    .map(|e| e as &dyn HttpError)
+            })
+            .or_else(|| {
+                cause
+                    .downcast_ref::<TransactionError>()
+                    .map(|e| e as &dyn HttpError)
+            })
+    })
+}
+
 #[derive(Default, Clone, Debug)]
 pub(crate) struct RequestPath {
     api_version: String,
@@ -148,14 +262,260 @@ impl ApiInfo {
 }
 pub(crate) type ApiInfoMap = HashMap<PathBuf, ApiInfo>;
 
+/// Code that runs around a route handler, e.g. to check credentials.
+///
+/// Calling `next` continues to the wrapped handler; not calling it allows
+/// the middleware to answer the request on its own.
+pub(crate) trait Middleware: Send + Sync {
+    fn call(
+        &self,
+        req: Request<hyper::Body>,
+        next: RouteFn,
+    ) -> LocalBoxFuture<'static, Result<Response<Body>>>;
+}
+
+/// Rejects requests that have a body which isn't JSON with `415 Unsupported Media Type`.
+///
+/// `PATCH` requests may send `application/merge-patch+json` too.
+pub(crate) struct JsonBody;
+
+impl JsonBody {
+    fn accepts(req: &Request<hyper::Body>) -> bool {
+        let no_body = req.body().is_end_stream();
+        if no_body || ![Method::POST, Method::PUT, Method::PATCH].contains(req.method()) {
+            return true;
+        }
+        let content_type = req
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase());
+        match content_type.as_deref() {
+            Some("application/json") => true,
+            Some("application/merge-patch+json") => req.method() == Method::PATCH,
+            _ => false,
+        }
+    }
+}
+
+impl Middleware for JsonBody {
+    fn call(
+        &self,
+        req: Request<hyper::Body>,
+        next: RouteFn,
+    ) -> LocalBoxFuture<'static, Result<Response<Body>>> {
+        if JsonBody::accepts(&req) {
+            return next(req);
+        }
+        let response = Response::builder()
+            .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .body("request body must be JSON".to_string().into());
+        async move { Ok(response?) }.boxed_local()
+    }
+}
+
+/// Header carrying the id that tags the log of a request.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longer incoming request ids are replaced, to keep the log readable.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Version of the `meta` object added by [`Envelope`], bumped whenever its fields change.
+const ENVELOPE_SCHEMA_VERSION: u32 = 1;
+
+/// Wraps JSON responses as `{"data": <payload>, "meta": {...}}`, with `meta`
+/// holding a `request_id`, a `timestamp` and the `schema_version`.
+///
+/// Responses of other content types, streamed ones, and the errors turned
+/// into responses by the [`ApiService`] are left alone.
+pub(crate) struct Envelope;
+
+impl Envelope {
+    fn wrap(response: Response<Body>) -> Result<Response<Body>> {
+        let is_json = response
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map_or(false, |v| v.trim() == "application/json");
+        if !is_json || matches!(response.body(), Body::Stream(_)) {
+            return Ok(response);
+        }
+        let (mut parts, body) = response.into_parts();
+        let data: serde_json::Value = match body {
+            Body::Const(Some(bytes)) if !bytes.is_empty() => serde_json::from_slice(&bytes)?,
+            _ => serde_json::Value::Null,
+        };
+        let meta = serde_json::json!({
+            "request_id": logs::current_request_id(),
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "schema_version": ENVELOPE_SCHEMA_VERSION,
+        });
+        parts.headers.remove(hyper::header::CONTENT_LENGTH);
+        let body = Body::json(serde_json::json!({ "data": data, "meta": meta }))?;
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+impl Middleware for Envelope {
+    fn call(
+        &self,
+        req: Request<hyper::Body>,
+        next: RouteFn,
+    ) -> LocalBoxFuture<'static, Result<Response<Body>>> {
+        async move { Envelope::wrap(next(req).await?) }.boxed_local()
+    }
+}
+
+/// A set of routes sharing a path prefix and a middleware stack.
+///
+/// Created with [`ApiService::add_route_group`]. Nested groups run the
+/// middleware of the outer group first.
+pub(crate) struct RouteGroup<'a> {
+    api: &'a ApiService,
+    prefix: PathBuf,
+    middleware: Vec<Arc<dyn Middleware>>,
+    /// Whether [`JsonBody`] runs, after the rest of the middleware.
+    json_body: bool,
+    /// Whether responses go through [`Envelope`], unless [`Self::no_envelope`].
+    envelope: bool,
+}
+
+impl<'a> RouteGroup<'a> {
+    fn full_path(&self, path: &str) -> PathBuf {
+        self.prefix.join(path.trim_start_matches('/'))
+    }
+
+    fn wrap(&self, route_fn: RouteFn) -> RouteFn {
+        let route_fn: RouteFn = match self.json_body {
+            true => Arc::new(move |req| JsonBody.call(req, route_fn.clone())),
+            false => route_fn,
+        };
+        let route_fn: RouteFn = match self.envelope {
+            true => Arc::new(move |req| Envelope.call(req, route_fn.clone())),
+            false => route_fn,
+        };
+        self.middleware.iter().rev().fold(route_fn, |next, m| {
+            let m = m.clone();
+            Arc::new(move |req| m.call(req, next.clone()))
+        })
+    }
+
+    pub(crate) fn add_route(&self, method: Method, path: &str, route_fn: RouteFn) -> Result<()> {
+        self.api
+            .add_method_route(method, self.full_path(path), self.wrap(route_fn))
+    }
+
+    /// Creates a nested group under `prefix`, relative to this group.
+    pub(crate) fn group(&self, prefix: &str, middleware: Vec<Arc<dyn Middleware>>) -> Self {
+        let mut all = self.middleware.clone();
+        all.extend(middleware);
+        Self {
+            api: self.api,
+            prefix: self.full_path(prefix),
+            middleware: all,
+            json_body: self.json_body,
+            envelope: self.envelope,
+        }
+    }
+
+    /// Makes the routes of this group only take JSON bodies, see [`JsonBody`].
+    pub(crate) fn json_body(self) -> Self {
+        Self {
+            json_body: true,
+            ..self
+        }
+    }
+
+    /// The same group, but exempt from [`Self::json_body`], e.g. for routes
+    /// taking multipart bodies.
+    pub(crate) fn accepts_any_content_type(&self) -> Self {
+        Self {
+            api: self.api,
+            prefix: self.prefix.clone(),
+            middleware: self.middleware.clone(),
+            json_body: false,
+            envelope: self.envelope,
+        }
+    }
+
+    /// The same group, but exempt from [`Envelope`], for routes whose
+    /// clients expect a flat response.
+    pub(crate) fn no_envelope(&self) -> Self {
+        Self {
+            api: self.api,
+            prefix: self.prefix.clone(),
+            middleware: self.middleware.clone(),
+            json_body: self.json_body,
+            envelope: false,
+        }
+    }
+}
+
+/// The handlers registered for a single path.
+///
+/// A handler registered without a method serves every method that doesn't
+/// have a more specific handler.
+#[derive(Clone, Default)]
+struct MethodRoutes {
+    any: Option<RouteFn>,
+    by_method: HashMap<Method, RouteFn>,
+}
+
+impl MethodRoutes {
+    /// Adds a handler, returning false if one is already registered for `method`.
+    fn insert(&mut self, method: Option<Method>, route_fn: RouteFn) -> bool {
+        match method {
+            None if self.any.is_some() => false,
+            None => {
+                self.any = Some(route_fn);
+                true
+            }
+            Some(method) if self.by_method.contains_key(&method) => false,
+            Some(method) => {
+                self.by_method.insert(method, route_fn);
+                true
+            }
+        }
+    }
+
+    /// Returns the handler for `method` or, failing that, the methods that are allowed.
+    fn resolve(&self, method: &Method) -> RouteMatch {
+        match self.by_method.get(method).or(self.any.as_ref()) {
+            Some(route_fn) => RouteMatch::Found(route_fn.clone()),
+            None => {
+                let mut allowed: Vec<Method> = self.by_method.keys().cloned().collect();
+                allowed.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                RouteMatch::MethodNotAllowed(allowed)
+            }
+        }
+    }
+}
+
+enum RouteMatch {
+    Found(RouteFn),
+    MethodNotAllowed(Vec<Method>),
+}
+
 /// API service for Chisel server.
+///
+/// Clones share their routes: a route added through one is served by all.
+#[derive(Clone)]
 pub(crate) struct ApiService {
-    // Although we are on a TPC environment, this sync mutex should be fine. It will
-    // never contend because the ApiService is thread-local. The alternative is a RefCell
-    // with runtime checking, which is likely cheaper, but still this is safer and we don't
-    // have to manually implement Send (which is unsafe).
-    paths: Mutex<PrefixMap<RouteFn>>,
-    info: Mutex<ApiInfoMap>,
+    // Although we are on a TPC environment, this sync mutex should be fine. Clones share it,
+    // but each executor thread creates its own ApiService and its clones stay on that thread,
+    // so it never contends. The alternative is a RefCell with runtime checking, which is likely
+    // cheaper, but still this is safer and we don't have to manually implement Send (which is
+    // unsafe).
+    routes: Arc<Mutex<RouteTrie<MethodRoutes>>>,
+    /// Invoked when no route matches. Defaults to [`ApiService::default_not_found`].
+    not_found_handler: Arc<Mutex<Option<RouteFn>>>,
+    info: Arc<Mutex<ApiInfoMap>>,
+    /// Sunset dates of the deprecated versions.
+    deprecations: Arc<Mutex<BTreeMap<RouteVersion, NaiveDate>>>,
+    /// Added to the extensions of every request routed.
+    context: Option<RequestContext>,
 }
 
 impl ApiService {
@@ -163,67 +523,232 @@ impl ApiService {
         info.insert("__chiselstrike".into(), ApiInfo::chiselstrike());
         info.insert("".into(), ApiInfo::all_routes());
         Self {
-            paths: Default::default(),
-            info: Mutex::new(info),
+            routes: Default::default(),
+            not_found_handler: Default::default(),
+            info: Arc::new(Mutex::new(info)),
+            deprecations: Default::default(),
+            context: None,
         }
     }
 
-    /// Finds the right RouteFn for this request.
-    fn find_route_fn<S: AsRef<Path>>(&self, request: S) -> Option<RouteFn> {
-        match self.paths.lock().unwrap().longest_prefix(request.as_ref()) {
-            None => None,
-            Some((_, f)) => Some(f.clone()),
+    pub(crate) fn with_context(self, context: RequestContext) -> Self {
+        Self {
+            context: Some(context),
+            ..self
         }
     }
 
-    /// Adds a route.
+    /// Finds the right RouteFn for this request, along with any captured path parameters.
+    fn find_route_fn(
+        &self,
+        method: &Method,
+        request: &str,
+    ) -> Option<(String, RouteMatch, Option<RouteParams>)> {
+        let table = self.routes.lock().unwrap();
+        let (path, routes, params) = table.find(request)?;
+        let path = path.display().to_string();
+        Some((path, routes.resolve(method), params))
+    }
+
+    /// Adds a route that serves every HTTP method.
     ///
     /// Params:
     ///
-    ///  * path: The path for the route, including any leading /
-    ///  * code: A String containing the raw code of the endpoint, before any compilation.
+    ///  * path: The path for the route, including any leading /. Segments of the
+    ///    form `:name` capture a path parameter, and a trailing `*` captures the
+    ///    rest of the path. Captures are passed along as a [`RouteParams`] extension.
     ///  * route_fn: the actual function to be executed, likely some call to deno.
-    pub(crate) fn add_route(&self, path: PathBuf, route_fn: RouteFn) {
-        self.paths.lock().unwrap().insert(path, route_fn);
+    ///
+    /// Registering a path twice is an error: replacing a route requires removing it
+    /// first. Nested paths are fine, since a request is always dispatched to the
+    /// longest matching prefix, so `/a/b` never shadows anything else under `/a`.
+    pub(crate) fn add_route(&self, path: PathBuf, route_fn: RouteFn) -> Result<()> {
+        self.insert_route(None, path, route_fn)
+    }
+
+    /// Replaces the handler for requests that don't match any route.
+    #[allow(dead_code)] // chiseld itself is happy with the default.
+    pub(crate) fn set_not_found_handler(&self, handler: RouteFn) {
+        *self.not_found_handler.lock().unwrap() = Some(handler);
+    }
+
+    /// Starts a group of routes under `prefix` that all go through `middleware`,
+    /// in order, and wrap their JSON responses in an [`Envelope`].
+    pub(crate) fn add_route_group(
+        &self,
+        prefix: &str,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> RouteGroup<'_> {
+        RouteGroup {
+            api: self,
+            prefix: PathBuf::from(prefix),
+            middleware,
+            json_body: false,
+            envelope: true,
+        }
+    }
+
+    /// Adds a route that only serves `method`. Requests to the same path with
+    /// a method that has no handler get a `405 Method Not Allowed`.
+    pub(crate) fn add_method_route(
+        &self,
+        method: Method,
+        path: PathBuf,
+        route_fn: RouteFn,
+    ) -> Result<()> {
+        self.insert_route(Some(method), path, route_fn)
+    }
+
+    fn insert_route(&self, method: Option<Method>, path: PathBuf, route_fn: RouteFn) -> Result<()> {
+        let conflict = || {
+            let method = method.as_ref().map(Method::as_str).unwrap_or("*");
+            anyhow::anyhow!("route {} {} is already registered", method, path.display())
+        };
+        let mut table = self.routes.lock().unwrap();
+        let routes = table.get_or_insert_with(&path, Default::default)?;
+        if !routes.insert(method.clone(), route_fn) {
+            return Err(conflict());
+        }
+        Ok(())
     }
 
     /// Remove all routes that have this prefix.
     pub(crate) fn remove_routes(&self, prefix: &Path) {
-        self.paths.lock().unwrap().remove_prefix(prefix)
+        self.routes.lock().unwrap().remove_prefix(prefix)
     }
 
-    pub(crate) fn update_api_info<P: AsRef<Path>>(&self, api_version: P, info: ApiInfo) {
-        crate::introspect::add_introspection(self, &api_version);
+    pub(crate) fn update_api_info<P: AsRef<Path>>(
+        &self,
+        api_version: P,
+        info: ApiInfo,
+    ) -> Result<()> {
+        crate::introspect::add_introspection(self, &api_version)?;
         self.info
             .lock()
             .unwrap()
             .insert(api_version.as_ref().into(), info);
+        Ok(())
     }
 
     pub(crate) fn get_api_info<P: AsRef<Path>>(&self, api_version: P) -> Option<ApiInfo> {
         self.info.lock().unwrap().get(api_version.as_ref()).cloned()
     }
 
+    /// Makes every response of API version `v{version}` announce that it is
+    /// deprecated and goes away on `sunset_date`.
+    pub(crate) fn deprecate_version(&self, version: u32, sunset_date: NaiveDate) {
+        self.deprecations
+            .lock()
+            .unwrap()
+            .insert(RouteVersion(version), sunset_date);
+    }
+
+    /// The numbered versions with endpoints or types, oldest first.
+    pub(crate) fn route_versions(&self) -> Vec<RouteVersion> {
+        let mut versions: Vec<_> = self
+            .info
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|name| name.to_str().and_then(RouteVersion::parse))
+            .collect();
+        versions.sort();
+        versions
+    }
+
+    pub(crate) fn deprecated_versions(&self) -> BTreeMap<RouteVersion, NaiveDate> {
+        self.deprecations.lock().unwrap().clone()
+    }
+
     pub(crate) fn routes(&self) -> Vec<String> {
-        let mut result = vec![];
-        for (path, _) in self.paths.lock().unwrap().iter() {
-            result.push(path.display().to_string());
-        }
-        result
+        let table = self.routes.lock().unwrap();
+        let paths = table.paths().into_iter();
+        paths.map(|path| path.display().to_string()).collect()
     }
 
-    async fn route_impl(&self, req: Request<hyper::Body>) -> Result<Response<Body>> {
-        if let Some(route_fn) = self.find_route_fn(req.uri().path()) {
-            return route_fn(req).await;
+    /// Serves `req`, setting `route` to the path of the route serving it, if any.
+    async fn route_impl(
+        &self,
+        mut req: Request<hyper::Body>,
+        route: &mut Option<String>,
+    ) -> Result<Response<Body>> {
+        if let Some(context) = &self.context {
+            req.extensions_mut().insert(context.clone());
+        }
+        let found = self.find_route_fn(req.method(), req.uri().path());
+        let found = found.map(|(path, route_match, params)| {
+            *route = Some(path);
+            (route_match, params)
+        });
+        match found {
+            Some((RouteMatch::Found(route_fn), params)) => {
+                if let Some(params) = params {
+                    req.extensions_mut().insert(params);
+                }
+                route_fn(req).await
+            }
+            Some((RouteMatch::MethodNotAllowed(allowed), _)) => {
+                ApiService::method_not_allowed(&allowed)
+            }
+            None => {
+                let handler = self.not_found_handler.lock().unwrap().clone();
+                match handler {
+                    Some(handler) => handler(req).await,
+                    None => ApiService::default_not_found(&req),
+                }
+            }
         }
-        ApiService::not_found()
     }
 
+    /// Routes `req` with its handling tagged by the id in its `X-Request-Id`
+    /// header, or by a new one, which the response carries back. Each request
+    /// makes an access event when its response is ready, see [`logs::log_access`].
     async fn route(&self, req: Request<hyper::Body>) -> hyper::http::Result<Response<Body>> {
-        match self.route_impl(req).await {
-            Ok(val) => Ok(val),
-            Err(err) => Self::internal_error(err),
+        let start = Instant::now();
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+            .map(str::to_owned)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let mut route = None;
+        let tagged = async {
+            let response = self.route_tagged(req, &mut route).await;
+            if let Ok(response) = &response {
+                let duration = start.elapsed();
+                let route = route.as_deref();
+                logs::log_access(&method, &path, route, response.status(), duration);
+            }
+            response
+        };
+        let mut response = logs::with_request_id(request_id.clone(), tagged).await?;
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
         }
+        Ok(response)
+    }
+
+    async fn route_tagged(
+        &self,
+        mut req: Request<hyper::Body>,
+        route: &mut Option<String>,
+    ) -> hyper::http::Result<Response<Body>> {
+        let version = match route_version::resolve(&mut req) {
+            Ok(version) => version,
+            Err(err) => return Self::error_response(err),
+        };
+        let mut response = match self.route_impl(req, route).await {
+            Ok(val) => val,
+            Err(err) => Self::error_response(err)?,
+        };
+        let sunset = version.and_then(|v| self.deprecations.lock().unwrap().get(&v).copied());
+        if let Some(sunset) = sunset {
+            route_version::mark_deprecated(response.headers_mut(), sunset);
+        }
+        Ok(response)
     }
 
     pub(crate) fn not_found() -> Result<Response<Body>> {
@@ -232,7 +757,32 @@ impl ApiService {
             .body(Body::default())?)
     }
 
-    fn internal_error(err: anyhow::Error) -> hyper::http::Result<Response<Body>> {
+    fn default_not_found(req: &Request<hyper::Body>) -> Result<Response<Body>> {
+        debug!("No route for {}", req.uri().path());
+        let body = serde_json::json!({
+            "error": "route not found",
+            "path": req.uri().path(),
+            "request_id": logs::current_request_id(),
+        });
+        json_response(StatusCode::NOT_FOUND, body)
+    }
+
+    fn method_not_allowed(allowed: &[Method]) -> Result<Response<Body>> {
+        let allowed: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+        Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", allowed.join(", "))
+            .body(Body::default())?)
+    }
+
+    /// Responds as the [`HttpError`] in `err` asks for, or with `500 Internal Server Error`.
+    fn error_response(err: anyhow::Error) -> hyper::http::Result<Response<Body>> {
+        if let Some(http_error) = http_error(&err) {
+            return Response::builder()
+                .status(http_error.status_code())
+                .body(http_error.body().into());
+        }
+        warn!("Request failed: {:?}", err);
         Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
             .body(format!("{:?}\n", err).into())
@@ -311,13 +861,642 @@ pub(crate) fn spawn(
     Ok(tasks)
 }
 
+/// A response holding `value` as JSON, with the headers of [`response_template`].
+pub(crate) fn json_response(status: StatusCode, value: impl Serialize) -> Result<Response<Body>> {
+    Ok(response_template()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::json(value)?)?)
+}
+
 pub(crate) fn response_template() -> http::response::Builder {
     Response::builder()
         // TODO: Let the user control this.
         .header("Access-Control-Allow-Origin", "*")
         .header(
             "Access-Control-Allow-Methods",
-            "POST, PUT, GET, OPTIONS, DELETE",
+            "POST, PUT, PATCH, GET, OPTIONS, DELETE",
         )
         .header("Access-Control-Allow-Headers", "Content-Type,ChiselUID")
 }
+
+/// API version used by native routes when the request doesn't pick one with `?version=`.
+const DEFAULT_VERSION: &str = "dev";
+
+/// Gets the API version a request to a native route is about.
+pub(crate) fn version_param(req: &Request<hyper::Body>) -> String {
+    query_param(req, "version").unwrap_or_else(|| DEFAULT_VERSION.to_owned())
+}
+
+/// The value of the `name` query parameter of `req`, if any.
+pub(crate) fn query_param(req: &Request<hyper::Body>, name: &str) -> Option<String> {
+    let query = req.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::LocalBlobStore;
+    use crate::datastore::query::tests::{make_object, make_type_system, setup_clear_db, VERSION};
+    use crate::transactions::TRANSACTION_HEADER;
+    use crate::types::{Field, NewField, ObjectType, Type, TypeSystem};
+    use tempfile::NamedTempFile;
+
+    fn respond(status: u16) -> RouteFn {
+        Arc::new(move |_req| {
+            async move { Ok(Response::builder().status(status).body(Body::default())?) }
+                .boxed_local()
+        })
+    }
+
+    async fn status(api: &ApiService, method: Method, path: &str) -> StatusCode {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(hyper::Body::empty())
+            .unwrap();
+        api.route_impl(req, &mut None).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn duplicated_routes() {
+        let api = ApiService::new(Default::default());
+        api.add_route("/dev/a".into(), respond(200)).unwrap();
+        api.add_route("/dev/a".into(), respond(200)).unwrap_err();
+        api.add_method_route(Method::GET, "/dev/a".into(), respond(200))
+            .unwrap();
+        api.add_method_route(Method::GET, "/dev/a".into(), respond(200))
+            .unwrap_err();
+        api.add_route("/dev/a/b".into(), respond(200)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clones_share_routes() {
+        let api = ApiService::new(Default::default());
+        let clone = api.clone();
+        clone
+            .add_method_route(Method::GET, "/dev/a".into(), respond(200))
+            .unwrap();
+        assert_eq!(status(&api, Method::GET, "/dev/a").await, 200);
+    }
+
+    #[tokio::test]
+    async fn knows_the_route_of_requests() {
+        let api = ApiService::new(Default::default());
+        api.add_route("/dev/books/:id".into(), respond(200))
+            .unwrap();
+        let request = |path| Request::get(path).body(hyper::Body::empty()).unwrap();
+        let mut route = None;
+        api.route_impl(request("/dev/books/1"), &mut route)
+            .await
+            .unwrap();
+        assert_eq!(route.as_deref(), Some("/dev/books/:id"));
+        let mut route = None;
+        api.route_impl(request("/dev/authors"), &mut route)
+            .await
+            .unwrap();
+        assert_eq!(route, None);
+    }
+
+    /// A context with `type_system`, over a fresh database holding the tables of `entities`.
+    async fn context(
+        type_system: TypeSystem,
+        entities: &[Arc<ObjectType>],
+    ) -> (RequestContext, NamedTempFile) {
+        let (qeng, db_file) = setup_clear_db(entities).await;
+        let store = LocalBlobStore::new(db_file.path().with_extension("blobs"));
+        let context = RequestContext::new(Arc::new(qeng), Arc::new(store), type_system);
+        (context, db_file)
+    }
+
+    #[tokio::test]
+    async fn routes_get_their_context() {
+        let mut type_system = TypeSystem::default();
+        type_system.get_version_mut("dev");
+        let (context_with_dev, _with_dev_db) = context(type_system, &[]).await;
+        let with_dev = ApiService::new(Default::default()).with_context(context_with_dev);
+        let (context_without_dev, _without_dev_db) = context(TypeSystem::default(), &[]).await;
+        let without_dev = ApiService::new(Default::default()).with_context(context_without_dev);
+
+        let route_fn: RouteFn = Arc::new(|req| {
+            async move {
+                let status = match RequestContext::of(&req)?.type_system().get_version("dev") {
+                    Ok(_) => 200,
+                    Err(_) => 404,
+                };
+                Ok(Response::builder().status(status).body(Body::default())?)
+            }
+            .boxed_local()
+        });
+        for api in [&with_dev, &without_dev] {
+            api.add_route("/dev/types".into(), route_fn.clone())
+                .unwrap();
+        }
+        assert_eq!(status(&with_dev, Method::GET, "/dev/types").await, 200);
+        assert_eq!(
+            status(&without_dev, Method::GET, "/dev/types").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn error_statuses() {
+        let api = ApiService::new(Default::default());
+        let fail = |err: fn() -> Error| -> RouteFn {
+            Arc::new(move |_req| async move { Err::<Response<Body>, _>(err()) }.boxed_local())
+        };
+        let conflict = || Error::from(QueryError::VersionConflict("Person".into(), "1".into()));
+        api.add_route("/dev/conflict".into(), fail(conflict))
+            .unwrap();
+        let wrapped = || {
+            Error::from(QueryError::NotNullable("Person".into(), "name".into())).context("saving")
+        };
+        api.add_route("/dev/wrapped".into(), fail(wrapped)).unwrap();
+        let check = || {
+            Error::from(QueryError::CheckConstraintViolation {
+                constraint_name: "positive_price".into(),
+            })
+        };
+        api.add_route("/dev/check".into(), fail(check)).unwrap();
+        api.add_route("/dev/other".into(), fail(|| anyhow::anyhow!("boom")))
+            .unwrap();
+
+        let get = |path: &str| {
+            let req = Request::builder()
+                .uri(path)
+                .body(hyper::Body::empty())
+                .unwrap();
+            api.route(req)
+        };
+        assert_eq!(get("/dev/conflict").await.unwrap().status(), 409);
+        assert_eq!(get("/dev/wrapped").await.unwrap().status(), 422);
+        assert_eq!(get("/dev/check").await.unwrap().status(), 400);
+        assert_eq!(get("/dev/other").await.unwrap().status(), 500);
+    }
+
+    #[tokio::test]
+    async fn methods() {
+        let api = ApiService::new(Default::default());
+        api.add_method_route(Method::GET, "/dev/a".into(), respond(200))
+            .unwrap();
+        api.add_method_route(Method::POST, "/dev/a".into(), respond(201))
+            .unwrap();
+        assert_eq!(status(&api, Method::GET, "/dev/a").await, 200);
+        assert_eq!(status(&api, Method::POST, "/dev/a").await, 201);
+        assert_eq!(
+            status(&api, Method::PUT, "/dev/a").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+
+    #[tokio::test]
+    async fn params() {
+        let api = ApiService::new(Default::default());
+        let route_fn: RouteFn = Arc::new(|req| {
+            async move {
+                let params = req.extensions().get::<RouteParams>().unwrap();
+                let status = params.0["id"].parse::<u16>()?;
+                Ok(Response::builder().status(status).body(Body::default())?)
+            }
+            .boxed_local()
+        });
+        api.add_route("/dev/users/:id".into(), route_fn).unwrap();
+        assert_eq!(status(&api, Method::GET, "/dev/users/202").await, 202);
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let api = ApiService::new(Default::default());
+        assert_eq!(
+            status(&api, Method::GET, "/dev/nothing").await,
+            StatusCode::NOT_FOUND
+        );
+        api.set_not_found_handler(respond(418));
+        assert_eq!(status(&api, Method::GET, "/dev/nothing").await, 418);
+    }
+
+    #[tokio::test]
+    async fn groups() {
+        struct Deny;
+        impl Middleware for Deny {
+            fn call(
+                &self,
+                _req: Request<hyper::Body>,
+                _next: RouteFn,
+            ) -> LocalBoxFuture<'static, Result<Response<Body>>> {
+                async { ApiService::forbidden("denied") }.boxed_local()
+            }
+        }
+
+        let api = ApiService::new(Default::default());
+        let open = api.add_route_group("/dev", vec![]);
+        open.add_route(Method::GET, "/a", respond(200)).unwrap();
+        let closed = open.group("/closed", vec![Arc::new(Deny)]);
+        closed.add_route(Method::GET, "/b", respond(200)).unwrap();
+        assert_eq!(status(&api, Method::GET, "/dev/a").await, 200);
+        assert_eq!(
+            status(&api, Method::GET, "/dev/closed/b").await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn json_bodies() {
+        let api = ApiService::new(Default::default());
+        let json = api.add_route_group("/dev", vec![]).json_body();
+        json.add_route(Method::POST, "/a", respond(200)).unwrap();
+        json.add_route(Method::PATCH, "/a", respond(200)).unwrap();
+        let any = json.accepts_any_content_type();
+        any.add_route(Method::POST, "/b", respond(200)).unwrap();
+
+        let send = |method: Method, path: &str, content_type: Option<&str>, body: &str| {
+            let mut req = Request::builder().method(method).uri(path);
+            if let Some(content_type) = content_type {
+                req = req.header("Content-Type", content_type);
+            }
+            let req = req.body(hyper::Body::from(body.to_owned())).unwrap();
+            let api = api.clone();
+            async move { api.route_impl(req, &mut None).await.unwrap().status() }
+        };
+        let json_type = Some("application/json; charset=utf-8");
+        let form_type = Some("application/x-www-form-urlencoded");
+        let merge_patch = Some("application/merge-patch+json");
+        assert_eq!(send(Method::POST, "/dev/a", json_type, "{}").await, 200);
+        assert_eq!(send(Method::POST, "/dev/a", form_type, "a=1").await, 415);
+        assert_eq!(send(Method::POST, "/dev/a", None, "{}").await, 415);
+        assert_eq!(send(Method::POST, "/dev/a", None, "").await, 200);
+        assert_eq!(send(Method::PATCH, "/dev/a", merge_patch, "{}").await, 200);
+        assert_eq!(send(Method::POST, "/dev/a", merge_patch, "{}").await, 415);
+        assert_eq!(send(Method::POST, "/dev/b", form_type, "a=1").await, 200);
+    }
+
+    #[tokio::test]
+    async fn envelopes() {
+        let api = ApiService::new(Default::default());
+        let json = |value: serde_json::Value| -> RouteFn {
+            Arc::new(move |_req| {
+                let value = value.clone();
+                async move { json_response(StatusCode::OK, value) }.boxed_local()
+            })
+        };
+        let wrapped = api.add_route_group("/dev", vec![]);
+        wrapped
+            .add_route(Method::GET, "/a", json(serde_json::json!({"x": 1})))
+            .unwrap();
+        wrapped
+            .add_route(Method::GET, "/text", respond(200))
+            .unwrap();
+        let flat = wrapped.no_envelope();
+        flat.add_route(Method::GET, "/flat", json(serde_json::json!({"x": 1})))
+            .unwrap();
+
+        let get = |path: &str| {
+            let req = Request::builder()
+                .uri(path)
+                .body(hyper::Body::empty())
+                .unwrap();
+            let api = api.clone();
+            async move {
+                let body = api.route(req).await.unwrap().into_body();
+                hyper::body::to_bytes(body).await.unwrap()
+            }
+        };
+        let a: serde_json::Value = serde_json::from_slice(&get("/dev/a").await).unwrap();
+        assert_eq!(a["data"], serde_json::json!({"x": 1}));
+        assert_eq!(a["meta"]["schema_version"], ENVELOPE_SCHEMA_VERSION);
+        assert!(a["meta"]["request_id"].is_string());
+        assert!(a["meta"]["timestamp"].is_string());
+        assert!(get("/dev/text").await.is_empty());
+        assert_eq!(&get("/dev/flat").await[..], br#"{"x":1}"#);
+    }
+
+    #[tokio::test]
+    async fn request_ids() {
+        let api = ApiService::new(Default::default());
+        let get = |request_id: Option<&str>| {
+            let mut req = Request::builder().uri("/dev/missing");
+            if let Some(id) = request_id {
+                req = req.header(REQUEST_ID_HEADER, id);
+            }
+            let req = req.body(hyper::Body::empty()).unwrap();
+            let api = api.clone();
+            async move {
+                let response = api.route(req).await.unwrap();
+                let header = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+                let header = header.to_owned();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(body["request_id"], header);
+                header
+            }
+        };
+        assert_eq!(get(Some("abc")).await, "abc");
+        assert_ne!(get(None).await, get(None).await);
+        assert_ne!(get(Some(&"x".repeat(200))).await.len(), 200);
+    }
+
+    #[tokio::test]
+    async fn versions() {
+        let api = ApiService::new(Default::default());
+        api.add_route("/v1/users".into(), respond(200)).unwrap();
+        api.add_route("/v2/users".into(), respond(201)).unwrap();
+        api.deprecate_version(1, NaiveDate::from_ymd(2023, 6, 30));
+
+        let get = |path: &str, version: Option<&str>| {
+            let mut req = Request::builder().uri(path);
+            if let Some(version) = version {
+                req = req.header(route_version::API_VERSION_HEADER, version);
+            }
+            api.route(req.body(hyper::Body::empty()).unwrap())
+        };
+        let v1 = get("/v1/users", None).await.unwrap();
+        assert_eq!(v1.status(), 200);
+        assert_eq!(v1.headers()["Sunset"], "Fri, 30 Jun 2023 00:00:00 GMT");
+        let v1 = get("/users", Some("1")).await.unwrap();
+        assert_eq!(v1.status(), 200);
+        assert_eq!(v1.headers()["Deprecation"], "true");
+        let v2 = get("/users", Some("2")).await.unwrap();
+        assert_eq!(v2.status(), 201);
+        assert!(v2.headers().get("Deprecation").is_none());
+        assert_eq!(get("/v1/users", Some("2")).await.unwrap().status(), 400);
+        assert_eq!(get("/users", Some("v2")).await.unwrap().status(), 400);
+    }
+
+    /// An API serving the entity and transaction routes, over a fresh
+    /// database holding `Person`, with a unique `name` and an optional `age`.
+    async fn entities_api() -> (ApiService, NamedTempFile) {
+        let desc = NewField::new("name", Type::String, VERSION).unwrap();
+        let name = Field::new(desc, vec![], None, false, true);
+        let desc = NewField::new("age", Type::Float, VERSION).unwrap();
+        let age = Field::new(desc, vec![], None, true, false);
+        let person = make_object("Person", vec![name, age]);
+        let (context, db_file) = context(make_type_system(&[person.clone()]), &[person]).await;
+        let api = ApiService::new(Default::default()).with_context(context);
+        crate::entities::init(&api).unwrap();
+        crate::transactions::init(&api).unwrap();
+        (api, db_file)
+    }
+
+    /// Sends a request with a JSON `body` to the route at `path` under
+    /// `/__chiselstrike`, and returns the status of the response along with
+    /// its data, or its text if it isn't JSON.
+    async fn call(
+        api: &ApiService,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let uri = format!("/__chiselstrike/{}{}version={}", path, separator, VERSION);
+        let mut req = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        if !headers.iter().any(|(name, _)| *name == "Content-Type") {
+            req = req.header("Content-Type", "application/json");
+        }
+        let body = body.map_or_else(hyper::Body::empty, |b| b.to_string().into());
+        let response = api.route(req.body(body).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(json) => json["data"].clone(),
+            Err(_) => String::from_utf8_lossy(&body).into_owned().into(),
+        };
+        (status, body)
+    }
+
+    /// Creates a `Person` through the entity routes and returns its id.
+    async fn create_person(api: &ApiService, person: serde_json::Value) -> String {
+        let (status, ids) = call(api, Method::POST, "entities/Person", &[], Some(person)).await;
+        assert_eq!(status, StatusCode::OK);
+        ids["id"].as_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn entity_upsert() {
+        let (api, _db_file) = entities_api().await;
+        let api = &api;
+        let upsert = move |age: f64| {
+            let body = serde_json::json!({
+                "data": {"name": "Ada", "age": age},
+                "conflictFields": ["name"],
+            });
+            call(api, Method::POST, "entities/Person/upsert", &[], Some(body))
+        };
+        let (status, created) = upsert(36.0).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(created["age"], 36.0);
+        let (status, updated) = upsert(37.0).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(updated["id"], created["id"]);
+        assert_eq!(updated["age"], 37.0);
+    }
+
+    #[tokio::test]
+    async fn entity_transactions() {
+        let (api, _db_file) = entities_api().await;
+        let api = &api;
+        let begin = move || async move {
+            let (status, begun) = call(api, Method::POST, "transactions/begin", &[], None).await;
+            assert_eq!(status, StatusCode::OK);
+            begun["id"].as_str().unwrap().to_owned()
+        };
+        let save = move |transaction: String, name: &'static str| async move {
+            let person = serde_json::json!({ "name": name });
+            let header = [(TRANSACTION_HEADER, transaction.as_str())];
+            call(api, Method::POST, "entities/Person", &header, Some(person)).await
+        };
+        let end = move |transaction: String, how: &'static str| async move {
+            let header = [(TRANSACTION_HEADER, transaction.as_str())];
+            let path = format!("transactions/{}", how);
+            call(api, Method::POST, &path, &header, None).await.0
+        };
+        let find = move |id: serde_json::Value| async move {
+            let path = format!("entities/Person/{}", id.as_str().unwrap());
+            call(api, Method::GET, &path, &[], None).await.0
+        };
+
+        let transaction = begin().await;
+        let (status, kept) = save(transaction.clone(), "Ada").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(end(transaction.clone(), "commit").await, StatusCode::OK);
+        assert_eq!(find(kept["id"].clone()).await, StatusCode::OK);
+        assert_eq!(
+            end(transaction, "commit").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let transaction = begin().await;
+        let (status, dropped) = save(transaction.clone(), "Bob").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(end(transaction, "rollback").await, StatusCode::OK);
+        assert_eq!(find(dropped["id"].clone()).await, StatusCode::NOT_FOUND);
+
+        // A failed write fails the whole transaction, including the writes before it.
+        let transaction = begin().await;
+        let (status, dropped) = save(transaction.clone(), "Cy").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = save(transaction.clone(), "Ada").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(end(transaction, "commit").await, StatusCode::CONFLICT);
+        assert_eq!(find(dropped["id"].clone()).await, StatusCode::NOT_FOUND);
+
+        let (status, _) = save("unknown".to_owned(), "Cy").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn entity_find_by_id() {
+        let (api, _db_file) = entities_api().await;
+        let api = &api;
+        let id = create_person(api, serde_json::json!({"name": "Ada", "age": 36})).await;
+        let (status, found) = call(
+            api,
+            Method::GET,
+            &format!("entities/Person/{}", id),
+            &[],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(found["name"], "Ada");
+        assert_eq!(found["age"], 36.0);
+        let (status, text) = call(api, Method::GET, "entities/Person/nobody", &[], None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(text, "Person nobody not found");
+    }
+
+    #[tokio::test]
+    async fn entity_delete() {
+        let (api, _db_file) = entities_api().await;
+        let api = &api;
+        let id = create_person(api, serde_json::json!({"name": "Ada"})).await;
+        let path = &format!("entities/Person/{}", id);
+        let stale = [("If-Match", "\"99\"")];
+        let (status, _) = call(api, Method::DELETE, path, &stale, None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, body) = call(api, Method::DELETE, path, &[], None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(body, "");
+        let (status, _) = call(api, Method::DELETE, path, &[], None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(api, Method::GET, path, &[], None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn entity_patch() {
+        let (api, _db_file) = entities_api().await;
+        let api = &api;
+        let id = create_person(api, serde_json::json!({"name": "Ada", "age": 36})).await;
+        let path = &format!("entities/Person/{}", id);
+        let patch = move |content_type, body| {
+            let headers = [("Content-Type", content_type)];
+            async move { call(api, Method::PATCH, path, &headers, Some(body)).await }
+        };
+        let merge_patch = "application/merge-patch+json";
+
+        let (status, _) = patch("application/json", serde_json::json!({"age": 40})).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, patched) = patch(merge_patch, serde_json::json!({"age": 40})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(patched["name"], "Ada");
+        assert_eq!(patched["age"], 40.0);
+        let (status, text) = patch(merge_patch, serde_json::json!({ "name": null })).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            text,
+            QueryError::NotNullable("Person".into(), "name".into()).to_string()
+        );
+        let (status, _) = patch(merge_patch, serde_json::json!({ "age": null })).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn entity_replace() {
+        let (api, _db_file) = entities_api().await;
+        let api = &api;
+        let id = create_person(api, serde_json::json!({"name": "Ada", "age": 36})).await;
+        let path = &format!("entities/Person/{}", id);
+        let put = move |body| call(api, Method::PUT, path, &[], Some(body));
+
+        let (status, replaced) = put(serde_json::json!({"name": "Bob"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(replaced["id"], id.as_str());
+        assert_eq!(replaced["name"], "Bob");
+        assert_eq!(replaced["age"], serde_json::Value::Null);
+        let (status, text) = put(serde_json::json!({"age": 1})).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(text, "Person.name is missing");
+        let (status, text) = put(serde_json::json!({"name": "Cy", "height": 1})).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(text, "field height not present in Person");
+    }
+
+    #[tokio::test]
+    async fn entity_batch() {
+        let (api, _db_file) = entities_api().await;
+        let api = &api;
+        let ada = create_person(api, serde_json::json!({"name": "Ada"})).await;
+        let bob = create_person(api, serde_json::json!({"name": "Bob"})).await;
+        let batch = move |body| call(api, Method::POST, "entities/Person/batch", &[], Some(body));
+        let find = move |id: &str| {
+            let path = format!("entities/Person/{}", id);
+            async move { call(api, Method::GET, &path, &[], None).await }
+        };
+
+        let (status, done) = batch(serde_json::json!({
+            "create": [{"name": "Cy"}],
+            "update": [{"id": ada, "age": 36}],
+            "delete": [bob],
+        }))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(done["updated"], serde_json::json!([ada]));
+        assert_eq!(done["deleted"], serde_json::json!([bob]));
+        let cy = done["created"][0]["id"].as_str().unwrap();
+        assert_eq!(find(cy).await.1["name"], "Cy");
+        assert_eq!(find(&ada).await.1["age"], 36.0);
+        assert_eq!(find(&bob).await.0, StatusCode::NOT_FOUND);
+
+        let (status, failed) = batch(serde_json::json!({
+            "create": [{"name": "Dan"}, {"name": null}],
+            "update": [{"age": 1}],
+        }))
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let failures = failed["failures"].as_array().unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0]["operation"], "create");
+        assert_eq!(failures[0]["index"], 1);
+        assert_eq!(failures[1]["operation"], "update");
+        assert_eq!(failures[1]["index"], 0);
+
+        // A failure found while writing undoes the writes before it.
+        let (status, failed) = batch(serde_json::json!({
+            "create": [{"name": "Dan"}],
+            "delete": ["nobody"],
+        }))
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(failed["failures"][0]["operation"], "delete");
+        let dan = serde_json::json!({"searchKey": {"name": "Dan"}});
+        let path = "entities/Person/find_or_create";
+        let (status, _) = call(api, Method::POST, path, &[], Some(dan)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn bytes_are_not_copied() {
+        let bytes = Bytes::from_static(b"{\"cached\": true}");
+        let mut body = Body::from_bytes(bytes.clone());
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(data.as_ptr(), bytes.as_ptr());
+        assert!(body.data().await.is_none());
+    }
+}
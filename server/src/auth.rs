@@ -3,22 +3,180 @@
 use crate::api::ApiService;
 use crate::datastore::engine::SqlWithArguments;
 use crate::datastore::query::SqlValue;
+use crate::datastore::{MetaService, QueryEngine};
 use crate::deno::lookup_builtin_type;
 use crate::deno::query_engine_arc;
-use crate::types::{ObjectType, Type};
+use crate::session_cache;
+use crate::types::{ObjectType, Type, TypeSystem};
+use crate::JsonObject;
 use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
 use deno_core::OpState;
+use hyper::header::HeaderValue;
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use serde_derive::{Deserialize, Serialize};
 use sqlx::Row;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub(crate) const AUTH_USER_NAME: &str = "AuthUser";
 pub(crate) const AUTH_SESSION_NAME: &str = "AuthSession";
 pub(crate) const AUTH_TOKEN_NAME: &str = "AuthToken";
 pub(crate) const AUTH_ACCOUNT_NAME: &str = "AuthAccount";
 
+/// Name of the secret that requests to the auth and admin routes must send
+/// in the `ChiselAuth` header.
+pub(crate) const AUTH_SECRET_NAME: &str = "CHISELD_AUTH_SECRET";
+
+/// Secret of this server process, which its own TypeScript API sends to the
+/// admin routes, and which the CLI gets over RPC. The admin routes accept it
+/// whether an auth secret is configured or not.
+pub(crate) static PROCESS_SECRET: Lazy<String> = Lazy::new(random_secret);
+
+/// Key in `__chiselstrike_meta` holding the secrets set by `chisel auth rotate-secret`.
+const ROTATED_SECRETS_KEY: &str = "auth_secrets";
+
+/// How long the previous secret is still accepted after a rotation.
+const ROTATION_OVERLAP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An auth secret set by a rotation, accepted from `valid_from` (a UNIX
+/// timestamp) on.
+#[derive(Debug, Serialize, Deserialize)]
+struct RotatedSecret {
+    secret: String,
+    valid_from: u64,
+}
+
+/// 32 random bytes, in hex.
+fn random_secret() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn rotated_secrets(meta: &MetaService) -> Result<Vec<RotatedSecret>> {
+    match meta.get_meta_value(ROTATED_SECRETS_KEY).await? {
+        Some(value) => Ok(serde_json::from_str(&value)?),
+        None => Ok(vec![]),
+    }
+}
+
+/// Replaces the auth secret with `new_secret`, or a random one, and returns
+/// it. The previous secret, which is `file_secret` on the first rotation, is
+/// still accepted during [`ROTATION_OVERLAP`]; older ones are dropped.
+pub(crate) async fn rotate_auth_secret(
+    meta: &MetaService,
+    file_secret: Option<String>,
+    new_secret: Option<String>,
+) -> Result<String> {
+    let secret = match new_secret {
+        Some(secret) => {
+            anyhow::ensure!(
+                !secret.is_empty() && secret.len() % 2 == 0,
+                "the new secret must be a non-empty string of hex digit pairs"
+            );
+            anyhow::ensure!(
+                secret.chars().all(|c| c.is_ascii_hexdigit()),
+                "the new secret can only have hex digits, got {}",
+                secret
+            );
+            secret.to_ascii_lowercase()
+        }
+        None => random_secret(),
+    };
+    let mut secrets = rotated_secrets(meta).await?;
+    let previous = match secrets.pop() {
+        Some(previous) => Some(previous),
+        None => file_secret.map(|secret| RotatedSecret {
+            secret,
+            valid_from: 0,
+        }),
+    };
+    let secrets: Vec<_> = previous
+        .into_iter()
+        .chain(std::iter::once(RotatedSecret {
+            secret: secret.clone(),
+            valid_from: now_secs(),
+        }))
+        .collect();
+
+    let mut transaction = meta.start_transaction().await?;
+    meta.set_meta_value(
+        &mut transaction,
+        ROTATED_SECRETS_KEY,
+        &serde_json::to_string(&secrets)?,
+    )
+    .await?;
+    MetaService::commit_transaction(transaction).await?;
+    Ok(secret)
+}
+
+/// Makes the secrets set by rotations, if any, take the place of the auth
+/// secret read from the secrets file: it becomes an array of the newest
+/// secret and, during the overlap after a rotation, the previous one.
+pub(crate) async fn add_rotated_auth_secrets(
+    meta: &MetaService,
+    secrets: &mut JsonObject,
+) -> Result<()> {
+    let mut rotated = rotated_secrets(meta).await?;
+    let newest = match rotated.pop() {
+        Some(newest) => newest,
+        None => return Ok(()),
+    };
+    let mut accepted = vec![];
+    if now_secs() < newest.valid_from + ROTATION_OVERLAP.as_secs() {
+        accepted.extend(rotated.into_iter().map(|s| s.secret));
+    }
+    accepted.push(newest.secret);
+    secrets.insert(AUTH_SECRET_NAME.into(), accepted.into());
+    Ok(())
+}
+
+/// Checks the `ChiselAuth` header of a request against the auth secret of
+/// `secrets`, returning why the request is refused, if it is.
+pub(crate) fn auth_header_refusal(
+    secrets: &JsonObject,
+    header: Option<&HeaderValue>,
+) -> Option<&'static str> {
+    match (secrets.get(AUTH_SECRET_NAME), header) {
+        (Some(_), None) => Some("ChiselAuth"),
+        (Some(serde_json::Value::String(s)), Some(h)) if *s != *h => Some("Fundamental auth"),
+        (Some(serde_json::Value::Array(accepted)), Some(h))
+            if !accepted
+                .iter()
+                .any(|s| s.as_str().map_or(false, |s| h == s)) =>
+        {
+            Some("Fundamental auth")
+        }
+        _ => None,
+    }
+}
+
+/// Like [`auth_header_refusal`], for the admin routes, but they also accept the
+/// [`PROCESS_SECRET`], and refuse requests without it if no auth secret is configured.
+pub(crate) fn admin_header_refusal(
+    secrets: &JsonObject,
+    header: Option<&HeaderValue>,
+) -> Option<&'static str> {
+    match header {
+        Some(h) if h == PROCESS_SECRET.as_str() => None,
+        _ if !secrets.contains_key(AUTH_SECRET_NAME) => Some("ChiselAuth"),
+        _ => auth_header_refusal(secrets, header),
+    }
+}
+
 fn get_auth_user_type(state: &OpState) -> Result<Arc<ObjectType>> {
     match lookup_builtin_type(state, AUTH_USER_NAME) {
         Ok(Type::Object(t)) => Ok(t),
@@ -42,7 +200,7 @@ export default {type_name}.crud()"#
         ),
     );
 
-    crate::server::add_endpoints(sources, api).await
+    crate::server::add_endpoints(sources, &Default::default(), api).await
 }
 
 pub(crate) async fn init(api: &mut ApiService) -> Result<()> {
@@ -52,6 +210,190 @@ pub(crate) async fn init(api: &mut ApiService) -> Result<()> {
     add_crud_endpoint_for_type(AUTH_ACCOUNT_NAME, "accounts", api).await
 }
 
+/// An active session, as exposed to admins.
+///
+/// Only a prefix of the session token is exposed, which is enough to tell
+/// sessions apart without leaking the token itself. Session creation and
+/// usage times are not tracked yet, so they are always `None` for now.
+#[derive(Debug, Serialize)]
+pub(crate) struct SessionInfo {
+    pub(crate) token_prefix: String,
+    pub(crate) created_at: Option<String>,
+    pub(crate) last_used_at: Option<String>,
+    pub(crate) expires_at: String,
+}
+
+/// Decodes a username that was percent-encoded to fit in a URL path.
+///
+/// `+` is left alone, since it only stands for a space in form data.
+pub(crate) fn decode_username(encoded: &str) -> Result<String> {
+    let username = percent_decode_str(encoded)
+        .decode_utf8()
+        .map_err(|e| anyhow::anyhow!("username {} isn't valid UTF-8: {}", encoded, e))?;
+    Ok(username.into_owned())
+}
+
+fn builtin_object_type(ts: &TypeSystem, type_name: &str) -> Result<Arc<ObjectType>> {
+    match ts.lookup_builtin_type(type_name)? {
+        Type::Object(ty) => Ok(ty),
+        _ => anyhow::bail!("Internal error: type {} not found", type_name),
+    }
+}
+
+pub(crate) fn builtin_backing_table(ts: &TypeSystem, type_name: &str) -> Result<String> {
+    Ok(builtin_object_type(ts, type_name)?
+        .backing_table()
+        .to_owned())
+}
+
+/// A user, as listed by `chisel auth list-users`.
+#[derive(Debug)]
+pub(crate) struct UserInfo {
+    pub(crate) id: String,
+    pub(crate) username: String,
+}
+
+/// Ids of the users named `username`.
+async fn user_ids(qeng: &QueryEngine, ts: &TypeSystem, username: &str) -> Result<Vec<String>> {
+    let rows = qeng
+        .fetch_all(SqlWithArguments {
+            sql: format!(
+                "SELECT id FROM \"{}\" WHERE email = $1",
+                builtin_backing_table(ts, AUTH_USER_NAME)?
+            ),
+            args: vec![SqlValue::String(username.to_owned())],
+        })
+        .await?;
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// Creates a user named `username` without going through an OAuth provider,
+/// and returns its id.
+pub(crate) async fn add_user(
+    qeng: &QueryEngine,
+    ts: &TypeSystem,
+    username: &str,
+) -> Result<String> {
+    anyhow::ensure!(!username.is_empty(), "username can't be empty");
+    anyhow::ensure!(
+        user_ids(qeng, ts, username).await?.is_empty(),
+        "user {} already exists",
+        username
+    );
+    let mut user = JsonObject::new();
+    user.insert("email".into(), username.into());
+    user.insert("name".into(), username.into());
+    let ty = builtin_object_type(ts, AUTH_USER_NAME)?;
+    Ok(qeng.add_row(&ty, &user, None).await?.id)
+}
+
+/// Lists all users, by username.
+pub(crate) async fn list_users(qeng: &QueryEngine, ts: &TypeSystem) -> Result<Vec<UserInfo>> {
+    let rows = qeng
+        .fetch_all(SqlWithArguments {
+            sql: format!(
+                "SELECT id, email FROM \"{}\" ORDER BY email",
+                builtin_backing_table(ts, AUTH_USER_NAME)?
+            ),
+            args: vec![],
+        })
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| UserInfo {
+            id: row.get("id"),
+            username: row.get::<Option<String>, _>("email").unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Deletes the user named `username`, along with its sessions and accounts.
+pub(crate) async fn delete_user(qeng: &QueryEngine, ts: &TypeSystem, username: &str) -> Result<()> {
+    let ids = user_ids(qeng, ts, username).await?;
+    anyhow::ensure!(!ids.is_empty(), "there is no user {}", username);
+    let mut queries = vec![];
+    for id in ids {
+        for (type_name, column) in [
+            (AUTH_SESSION_NAME, "userId"),
+            (AUTH_ACCOUNT_NAME, "userId"),
+            (AUTH_USER_NAME, "id"),
+        ] {
+            queries.push(SqlWithArguments {
+                sql: format!(
+                    "DELETE FROM \"{}\" WHERE \"{}\" = $1",
+                    builtin_backing_table(ts, type_name)?,
+                    column
+                ),
+                args: vec![SqlValue::String(id.clone())],
+            });
+        }
+    }
+    qeng.execute_transaction(&queries).await?;
+    forget_cached_username(username);
+    Ok(())
+}
+
+/// Lists sessions of `username` that haven't expired yet.
+pub(crate) async fn list_active_sessions(
+    qeng: &QueryEngine,
+    ts: &TypeSystem,
+    username: &str,
+) -> Result<Vec<SessionInfo>> {
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let rows = qeng
+        .fetch_all(SqlWithArguments {
+            sql: format!(
+                r#"SELECT s."sessionToken" AS token, s.expires AS expires
+                FROM "{}" s JOIN "{}" u ON s."userId" = u.id
+                WHERE u.email = $1 AND s.expires > $2"#,
+                builtin_backing_table(ts, AUTH_SESSION_NAME)?,
+                builtin_backing_table(ts, AUTH_USER_NAME)?,
+            ),
+            args: vec![SqlValue::String(username.to_owned()), SqlValue::String(now)],
+        })
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let token: &str = row.get("token");
+            SessionInfo {
+                token_prefix: token.chars().take(8).collect(),
+                created_at: None,
+                last_used_at: None,
+                expires_at: row.get("expires"),
+            }
+        })
+        .collect())
+}
+
+/// Drops the cached username of the users named `username`, so that
+/// requests stop being made as them at once.
+fn forget_cached_username(username: &str) {
+    if let Some(cache) = session_cache::get() {
+        cache.forget(username);
+    }
+}
+
+/// Deletes all sessions of `username`, returning how many were revoked.
+pub(crate) async fn revoke_sessions(
+    qeng: &QueryEngine,
+    ts: &TypeSystem,
+    username: &str,
+) -> Result<u64> {
+    let revoked = qeng
+        .execute_transaction(&[SqlWithArguments {
+            sql: format!(
+                r#"DELETE FROM "{}" WHERE "userId" IN (SELECT id FROM "{}" WHERE email = $1)"#,
+                builtin_backing_table(ts, AUTH_SESSION_NAME)?,
+                builtin_backing_table(ts, AUTH_USER_NAME)?,
+            ),
+            args: vec![SqlValue::String(username.to_owned())],
+        }])
+        .await?;
+    forget_cached_username(username);
+    Ok(revoked)
+}
+
 /// Extracts the username of the logged-in user, or None if there was no login.
 pub(crate) async fn get_username_from_id(
     state: Rc<RefCell<OpState>>,
@@ -70,13 +412,17 @@ pub(crate) async fn get_username_from_id(
             None
         }
         (Some(id), Ok(user_type)) => {
+            let cache = session_cache::get();
+            if let Some(username) = cache.and_then(|cache| cache.get(&id)) {
+                return Some(username);
+            }
             match qeng
                 .fetch_one(SqlWithArguments {
                     sql: format!(
                         "SELECT email FROM \"{}\" WHERE id=$1", // For now, let's pretend email is username.
                         user_type.backing_table()
                     ),
-                    args: vec![SqlValue::String(id)],
+                    args: vec![SqlValue::String(id.clone())],
                 })
                 .await
             {
@@ -84,8 +430,227 @@ pub(crate) async fn get_username_from_id(
                     warn!("Username query error: {:?}", e);
                     None
                 }
-                Ok(row) => row.get("email"),
+                Ok(row) => {
+                    let username: Option<String> = row.get("email");
+                    if let (Some(cache), Some(username)) = (cache, &username) {
+                        cache.insert(id, username.clone());
+                    }
+                    username
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::meta::tests::setup_meta;
+    use crate::datastore::query::tests::setup_clear_db;
+    use chrono::Duration;
+    use serde_json::json;
+    use tempfile::NamedTempFile;
+
+    async fn setup() -> (QueryEngine, TypeSystem, NamedTempFile) {
+        let (qeng, db_file) = setup_clear_db(&[]).await;
+        let ts = TypeSystem::default();
+        ts.create_builtin_backing_tables(&qeng).await.unwrap();
+        (qeng, ts, db_file)
+    }
+
+    async fn add(qeng: &QueryEngine, ts: &TypeSystem, type_name: &str, value: serde_json::Value) {
+        let ty = match ts.lookup_builtin_type(type_name).unwrap() {
+            Type::Object(ty) => ty,
+            _ => panic!("{} isn't an object type", type_name),
+        };
+        qeng.add_row(&ty, value.as_object().unwrap(), None)
+            .await
+            .unwrap();
+    }
+
+    async fn add_session(
+        qeng: &QueryEngine,
+        ts: &TypeSystem,
+        token: &str,
+        user_id: &str,
+        hours: i64,
+    ) {
+        let expires = Utc::now() + Duration::hours(hours);
+        let expires = expires.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let session = json!({"sessionToken": token, "userId": user_id, "expires": expires});
+        add(qeng, ts, AUTH_SESSION_NAME, session).await;
+    }
+
+    async fn setup_sessions() -> (QueryEngine, TypeSystem, NamedTempFile) {
+        let (qeng, ts, db_file) = setup().await;
+        for (id, email) in [("u1", "alice@example.com"), ("u2", "bob@example.com")] {
+            add(
+                &qeng,
+                &ts,
+                AUTH_USER_NAME,
+                json!({"id": id, "email": email}),
+            )
+            .await;
+        }
+        add_session(&qeng, &ts, "alice-active-token", "u1", 1).await;
+        add_session(&qeng, &ts, "alice-expired-token", "u1", -1).await;
+        add_session(&qeng, &ts, "bob-active-token", "u2", 1).await;
+        (qeng, ts, db_file)
+    }
+
+    #[tokio::test]
+    async fn active_sessions() {
+        let (qeng, ts, _db_file) = setup_sessions().await;
+
+        let sessions = list_active_sessions(&qeng, &ts, "alice@example.com")
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].token_prefix, "alice-ac");
+
+        let sessions = list_active_sessions(&qeng, &ts, "carol@example.com")
+            .await
+            .unwrap();
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn revoke() {
+        let (qeng, ts, _db_file) = setup_sessions().await;
+
+        assert_eq!(
+            revoke_sessions(&qeng, &ts, "alice@example.com")
+                .await
+                .unwrap(),
+            2
+        );
+        let alice = list_active_sessions(&qeng, &ts, "alice@example.com");
+        assert!(alice.await.unwrap().is_empty());
+        let bob = list_active_sessions(&qeng, &ts, "bob@example.com");
+        assert_eq!(bob.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn users() {
+        let (qeng, ts, _db_file) = setup_sessions().await;
+
+        let carol = add_user(&qeng, &ts, "carol@example.com").await.unwrap();
+        let err = add_user(&qeng, &ts, "carol@example.com").await.unwrap_err();
+        assert_eq!(err.to_string(), "user carol@example.com already exists");
+        let users = list_users(&qeng, &ts).await.unwrap();
+        let names: Vec<_> = users.iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(
+            names,
+            ["alice@example.com", "bob@example.com", "carol@example.com"]
+        );
+        assert_eq!(users[2].id, carol);
+
+        delete_user(&qeng, &ts, "alice@example.com").await.unwrap();
+        let users = list_users(&qeng, &ts).await.unwrap();
+        assert_eq!(users.len(), 2);
+        let sessions = qeng
+            .fetch_all(SqlWithArguments {
+                sql: r#"SELECT * FROM "auth_session" WHERE "userId" = 'u1'"#.to_string(),
+                args: vec![],
+            })
+            .await
+            .unwrap();
+        assert!(sessions.is_empty());
+        let err = delete_user(&qeng, &ts, "alice@example.com")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "there is no user alice@example.com");
+    }
+
+    async fn accepted_secrets(meta: &MetaService) -> serde_json::Value {
+        let mut secrets = JsonObject::new();
+        secrets.insert(AUTH_SECRET_NAME.into(), "from the file".into());
+        add_rotated_auth_secrets(meta, &mut secrets).await.unwrap();
+        secrets[AUTH_SECRET_NAME].clone()
+    }
+
+    #[tokio::test]
+    async fn rotate_secret() {
+        let (meta, _db_file) = setup_meta().await;
+        assert_eq!(accepted_secrets(&meta).await, "from the file");
+
+        let old = Some("old".to_string());
+        let first = rotate_auth_secret(&meta, old.clone(), None).await.unwrap();
+        assert_eq!(first.len(), 64);
+        let secrets = accepted_secrets(&meta).await;
+        assert_eq!(secrets, json!(["old", first]));
+
+        let secrets = JsonObject::from_iter([(AUTH_SECRET_NAME.to_string(), secrets)]);
+        let header = |h: &str| HeaderValue::from_str(h).unwrap();
+        assert_eq!(auth_header_refusal(&secrets, Some(&header("old"))), None);
+        assert_eq!(auth_header_refusal(&secrets, Some(&header(&first))), None);
+        assert_eq!(
+            auth_header_refusal(&secrets, Some(&header("other"))),
+            Some("Fundamental auth")
+        );
+        assert_eq!(auth_header_refusal(&secrets, None), Some("ChiselAuth"));
+        assert_eq!(admin_header_refusal(&secrets, Some(&header(&first))), None);
+        assert_eq!(
+            admin_header_refusal(&secrets, Some(&header(&PROCESS_SECRET))),
+            None
+        );
+        assert_eq!(
+            admin_header_refusal(&secrets, Some(&header("other"))),
+            Some("Fundamental auth")
+        );
+
+        let second = rotate_auth_secret(&meta, old.clone(), Some("AB12".into()))
+            .await
+            .unwrap();
+        assert_eq!(second, "ab12");
+        assert_eq!(accepted_secrets(&meta).await, json!([first, "ab12"]));
+        let err = rotate_auth_secret(&meta, old, Some("xyz1".into()))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the new secret can only have hex digits, got xyz1"
+        );
+
+        // Once the overlap is over, only the newest secret is accepted.
+        let expired = json!([{"secret": "a", "valid_from": 0}, {"secret": "b", "valid_from": 1}]);
+        let mut transaction = meta.start_transaction().await.unwrap();
+        meta.set_meta_value(&mut transaction, ROTATED_SECRETS_KEY, &expired.to_string())
+            .await
+            .unwrap();
+        MetaService::commit_transaction(transaction).await.unwrap();
+        assert_eq!(accepted_secrets(&meta).await, json!(["b"]));
+    }
+
+    #[test]
+    fn admin_routes_without_secret() {
+        let header = |h: &str| HeaderValue::from_str(h).unwrap();
+        let secrets = JsonObject::new();
+        assert_eq!(auth_header_refusal(&secrets, None), None);
+        assert_eq!(admin_header_refusal(&secrets, None), Some("ChiselAuth"));
+        assert_eq!(
+            admin_header_refusal(&secrets, Some(&header("guess"))),
+            Some("ChiselAuth")
+        );
+        assert_eq!(
+            admin_header_refusal(&secrets, Some(&header(&PROCESS_SECRET))),
+            None
+        );
+    }
+
+    #[test]
+    fn decode_usernames() {
+        assert_eq!(decode_username("alice").unwrap(), "alice");
+        assert_eq!(decode_username("alice%20smith").unwrap(), "alice smith");
+        assert_eq!(decode_username("alice+bob").unwrap(), "alice+bob");
+        assert_eq!(decode_username("alice%2Bbob").unwrap(), "alice+bob");
+        assert_eq!(
+            decode_username("alice%40example.com").unwrap(),
+            "alice@example.com"
+        );
+        assert_eq!(decode_username("jos%C3%A9").unwrap(), "josé");
+        assert_eq!(decode_username("josé").unwrap(), "josé");
+        assert_eq!(decode_username("100%25").unwrap(), "100%");
+        assert!(decode_username("%FF").is_err());
+    }
+}
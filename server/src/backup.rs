@@ -0,0 +1,241 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Database backups and restores, driven by the admin routes.
+
+use crate::api::{Body, StreamingBody};
+use crate::auth::{
+    builtin_backing_table, AUTH_ACCOUNT_NAME, AUTH_SESSION_NAME, AUTH_TOKEN_NAME, AUTH_USER_NAME,
+};
+use crate::datastore::query::QueryPlan;
+use crate::datastore::QueryEngine;
+use crate::types::{ObjectType, TypeSystem};
+use crate::JsonObject;
+use anyhow::{Context, Result};
+use deno_core::futures::{stream, StreamExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::Value;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+
+/// The kind of archive a backup is written to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    /// A copy of the SQLite database file.
+    Sqlite,
+    /// A `.tar.gz` with one newline-delimited JSON file per type.
+    Json,
+    /// A `.tar.gz` with one CSV file per type. Can't be restored.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sqlite" => Ok(Format::Sqlite),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => anyhow::bail!("unknown backup format {}, expected sqlite, json or csv", s),
+        }
+    }
+}
+
+impl Format {
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Format::Sqlite => "db",
+            Format::Json | Format::Csv => "tar.gz",
+        }
+    }
+
+    fn type_file_extension(&self) -> &'static str {
+        match self {
+            Format::Sqlite => unreachable!("SQLite backups are not split by type"),
+            Format::Json => "ndjson",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// SQLite restores also bring back the auth data, which JSON backups don't include.
+const AUTH_TYPE_NAMES: [&str; 4] = [
+    AUTH_USER_NAME,
+    AUTH_SESSION_NAME,
+    AUTH_TOKEN_NAME,
+    AUTH_ACCOUNT_NAME,
+];
+
+/// User-defined types in every API version, with the version they belong to.
+fn custom_types(ts: &TypeSystem) -> impl Iterator<Item = (&String, &Arc<ObjectType>)> {
+    ts.versions
+        .iter()
+        .flat_map(|(version, types)| types.custom_types.values().map(move |ty| (version, ty)))
+}
+
+fn csv_field(value: Option<&Value>) -> String {
+    let s = match value {
+        None | Some(Value::Null) => return String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    };
+    if s.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+fn csv_line(names: &[&str], row: &JsonObject) -> String {
+    let fields: Vec<String> = names.iter().map(|n| csv_field(row.get(*n))).collect();
+    fields.join(",")
+}
+
+/// Writes the rows of `ty` to a file at `path` as they are read, one per line.
+async fn write_rows(
+    qeng: &Arc<QueryEngine>,
+    ty: &Arc<ObjectType>,
+    format: Format,
+    path: &Path,
+) -> Result<()> {
+    let names: Vec<&str> = ty.all_fields().map(|f| f.json_name()).collect();
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    if format == Format::Csv {
+        writeln!(out, "{}", names.join(","))?;
+    }
+    let tr = qeng.clone().start_transaction_static().await?;
+    let mut rows = qeng.query(tr, QueryPlan::from_type(ty))?;
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        match format {
+            Format::Csv => out.write_all(csv_line(&names, &row).as_bytes())?,
+            _ => serde_json::to_writer(&mut out, &row)?,
+        }
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes a backup of the database to a new file under `dir` and returns its path.
+pub(crate) async fn backup(
+    qeng: &Arc<QueryEngine>,
+    ts: &TypeSystem,
+    format: Format,
+    dir: &Path,
+) -> Result<PathBuf> {
+    let path = dir.join(format!("backup.{}", format.extension()));
+    if format == Format::Sqlite {
+        qeng.backup_sqlite(&path).await?;
+        return Ok(path);
+    }
+
+    let file = std::fs::File::create(&path)?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    // Tar entries are preceded by their size, so each type goes through a file first.
+    let rows_path = dir.join("rows");
+    for (version, ty) in custom_types(ts) {
+        write_rows(qeng, ty, format, &rows_path).await?;
+        let name = format!("{}/{}.{}", version, ty.name(), format.type_file_extension());
+        archive.append_path_with_name(&rows_path, name)?;
+    }
+    std::fs::remove_file(&rows_path).ok();
+    archive.into_inner()?.finish()?.flush()?;
+    Ok(path)
+}
+
+/// Streams the file at `path` as a response body, deleting it once opened.
+pub(crate) async fn file_body(path: &Path) -> Result<Body> {
+    let file = tokio::fs::File::open(path).await?;
+    tokio::fs::remove_file(path).await?;
+    let chunks = stream::unfold(file, |mut file| async move {
+        let mut buf = vec![0; 64 * 1024];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(buf.into_boxed_slice()), file))
+            }
+            Err(e) => Some((Err(e.into()), file)),
+        }
+    });
+    Ok(Body::Stream(Box::pin(chunks)))
+}
+
+/// Restores a backup produced by [`backup`], replacing the rows of every type it contains.
+///
+/// Returns the number of rows restored, when known.
+pub(crate) async fn restore(
+    qeng: &Arc<QueryEngine>,
+    ts: &TypeSystem,
+    format: Format,
+    mut body: StreamingBody,
+) -> Result<Option<u64>> {
+    anyhow::ensure!(
+        format != Format::Csv,
+        "CSV backups don't keep field types and can't be restored, use json or sqlite instead"
+    );
+    let dir = TempDir::new()?;
+    let path = dir.path().join(format!("restore.{}", format.extension()));
+    let mut file = std::fs::File::create(&path)?;
+    while let Some(chunk) = body.read_chunk().await? {
+        file.write_all(&chunk)?;
+    }
+    drop(file);
+
+    if format == Format::Sqlite {
+        let mut tables: Vec<String> = custom_types(ts)
+            .map(|(_, ty)| ty.backing_table().to_owned())
+            .collect();
+        for name in AUTH_TYPE_NAMES {
+            tables.push(builtin_backing_table(ts, name)?);
+        }
+        qeng.restore_sqlite(&path, &tables).await?;
+        return Ok(None);
+    }
+
+    let mut archive = tar::Archive::new(GzDecoder::new(std::fs::File::open(&path)?));
+    let mut restored = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let (version, type_name) = name
+            .strip_suffix(".ndjson")
+            .and_then(|n| n.split_once('/'))
+            .with_context(|| format!("unexpected file {} in backup", name))?;
+        let ty = ts.lookup_object_type(type_name, version)?;
+        let mut data = String::new();
+        entry.read_to_string(&mut data)?;
+        let rows = data
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(serde_json::from_str::<JsonObject>)
+            .collect::<serde_json::Result<Vec<_>>>()
+            .with_context(|| format!("parsing {}", name))?;
+        restored += rows.len() as u64;
+        qeng.replace_rows(&ty, &rows).await?;
+    }
+    Ok(Some(restored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::csv_field;
+    use serde_json::json;
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field(None), "");
+        assert_eq!(csv_field(Some(&json!(null))), "");
+        assert_eq!(csv_field(Some(&json!(1.5))), "1.5");
+        assert_eq!(csv_field(Some(&json!(true))), "true");
+        assert_eq!(csv_field(Some(&json!("plain"))), "plain");
+        assert_eq!(csv_field(Some(&json!("a,\"b\""))), "\"a,\"\"b\"\"\"");
+    }
+}
@@ -0,0 +1,45 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! The internals that the benchmarks in `benches/` measure. Benchmarks are
+//! built against the public API of the crate, which this is the only part
+//! of that they need.
+
+use crate::api::Body;
+use crate::route_trie::RouteTrie;
+use anyhow::Result;
+use hyper::body::Bytes;
+use serde::Serialize;
+use std::path::Path;
+
+/// Routes numbered by the order they were added, in a [`RouteTrie`].
+#[derive(Default)]
+pub struct Routes {
+    trie: RouteTrie<usize>,
+    added: usize,
+}
+
+impl Routes {
+    /// Adds the route `path`, numbered after the routes added before it.
+    pub fn add(&mut self, path: &str) -> Result<()> {
+        let number = self.added;
+        self.trie.get_or_insert_with(Path::new(path), || number)?;
+        self.added += 1;
+        Ok(())
+    }
+
+    /// The number of the route serving the request `path`.
+    pub fn find(&self, path: &str) -> Option<usize> {
+        self.trie.find(path).map(|(_, number, _)| *number)
+    }
+}
+
+/// Makes the body of a response holding `value` as JSON, and drops it.
+pub fn json_body(value: impl Serialize) -> serde_json::Result<()> {
+    drop(Body::json(value)?);
+    Ok(())
+}
+
+/// Makes the body of a response sending `bytes`, and drops it.
+pub fn bytes_body(bytes: Bytes) {
+    drop(Body::from_bytes(bytes));
+}
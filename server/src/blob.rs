@@ -0,0 +1,156 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Storage for binary data that doesn't belong in the database.
+
+use crate::admin::AdminAuth;
+use crate::api::{response_template, ApiService, Body, Middleware};
+use crate::context::RequestContext;
+use crate::route_pattern::RouteParams;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use deno_core::futures::FutureExt;
+use hyper::body::Bytes;
+use hyper::{Method, Request, Response};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Prefix of the route that serves stored blobs.
+pub(crate) const BLOBS_PATH: &str = "/__chiselstrike/blobs";
+
+/// A key-value store for binary blobs.
+///
+/// Keys are `/`-separated relative paths, like `Photo/image/<uuid>`.
+#[async_trait(?Send)]
+pub(crate) trait BlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> Result<()>;
+    async fn get(&self, key: &str) -> Result<Bytes>;
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// URL under which the blob stored at `key` is served.
+    fn url(&self, key: &str) -> String {
+        blob_url(key)
+    }
+}
+
+/// URL under which the blob stored at `key` is served.
+///
+/// `Blob` fields hold a key in the database and are returned as this URL.
+pub(crate) fn blob_url(key: &str) -> String {
+    format!("{}/{}", BLOBS_PATH, key)
+}
+
+/// Recovers the key from a `Blob` field value, which is either the key itself or
+/// the URL previously returned for it.
+pub(crate) fn blob_key(value: &str) -> &str {
+    value
+        .strip_prefix(BLOBS_PATH)
+        .and_then(|k| k.strip_prefix('/'))
+        .unwrap_or(value)
+}
+
+/// Stores blobs as files under a base directory.
+pub(crate) struct LocalBlobStore {
+    base_path: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub(crate) fn new(base_path: PathBuf) -> Self {
+        Self { base_path }
+    }
+
+    /// Maps a key to a file, refusing keys that would escape the base directory.
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let key = Path::new(key);
+        anyhow::ensure!(
+            key.components().all(|c| matches!(c, Component::Normal(_))),
+            "invalid blob key {}",
+            key.display()
+        );
+        Ok(self.base_path.join(key))
+    }
+}
+
+#[async_trait(?Send)]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("creating {}", dir.display()))?;
+        }
+        tokio::fs::write(&path, &data)
+            .await
+            .with_context(|| format!("writing {}", path.display()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        let path = self.path(key)?;
+        let data = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("reading {}", path.display()))?;
+        Ok(data.into())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        tokio::fs::remove_file(&path)
+            .await
+            .with_context(|| format!("removing {}", path.display()))
+    }
+}
+
+async fn serve(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let key = match req.extensions().get::<RouteParams>() {
+        Some(params) => params.0["*"].clone(),
+        None => return ApiService::not_found(),
+    };
+    let store = RequestContext::of(&req)?.blob_store;
+    let data = match store.get(&key).await {
+        Ok(data) => data,
+        Err(e) => {
+            debug!("Could not serve blob {}: {:?}", key, e);
+            return ApiService::not_found();
+        }
+    };
+    Ok(response_template().body(Body::from_bytes(data))?)
+}
+
+/// Registers the route serving stored blobs. Unless `public`, it takes the
+/// same secret as the admin routes.
+pub(crate) fn init(api: &ApiService, public: bool) -> Result<()> {
+    let middleware: Vec<Arc<dyn Middleware>> = match public {
+        true => vec![],
+        false => vec![Arc::new(AdminAuth)],
+    };
+    // Blobs are served as they were stored.
+    api.add_route_group(BLOBS_PATH, middleware)
+        .no_envelope()
+        .add_route(Method::GET, "/*", Arc::new(|req| serve(req).boxed_local()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn put_get() -> Result<()> {
+        let tmp_dir = TempDir::new("blobs")?;
+        let store = LocalBlobStore::new(tmp_dir.path().to_owned());
+        store.put("a/b", Bytes::from_static(b"data")).await?;
+        assert_eq!(store.get("a/b").await?, Bytes::from_static(b"data"));
+        assert_eq!(store.url("a/b"), "/__chiselstrike/blobs/a/b");
+        store.put("../a", Bytes::new()).await.unwrap_err();
+        store.get("/etc/passwd").await.unwrap_err();
+        store.delete("a/b").await?;
+        store.get("a/b").await.unwrap_err();
+        Ok(())
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(blob_key("Photo/image/1"), "Photo/image/1");
+        assert_eq!(blob_key(&blob_url("Photo/image/1")), "Photo/image/1");
+    }
+}
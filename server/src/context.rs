@@ -0,0 +1,88 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! What native route handlers work with.
+//!
+//! The [`ApiService`](crate::api::ApiService) adds its [`RequestContext`] to
+//! the extensions of every request it routes, so handlers take their state
+//! from the request rather than from thread-local globals, and tests can hand
+//! each request a context of their own.
+
+use crate::api::{Body, RouteFn};
+use crate::blob::BlobStore;
+use crate::datastore::QueryEngine;
+use crate::types::TypeSystem;
+use anyhow::{Context, Result};
+use deno_core::futures::{future, Future, FutureExt};
+use hyper::http::Extensions;
+use hyper::{Request, Response};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+#[derive(Clone)]
+pub(crate) struct RequestContext {
+    pub(crate) query_engine: Arc<QueryEngine>,
+    pub(crate) blob_store: Arc<dyn BlobStore + Send + Sync>,
+    /// Updated as API versions are applied and deleted.
+    type_system: Arc<RwLock<TypeSystem>>,
+    /// Whether writing a deprecated field fails, rather than being warned about.
+    pub(crate) strict_mode: bool,
+}
+
+impl RequestContext {
+    pub(crate) fn new(
+        query_engine: Arc<QueryEngine>,
+        blob_store: Arc<dyn BlobStore + Send + Sync>,
+        type_system: TypeSystem,
+    ) -> Self {
+        Self {
+            query_engine,
+            blob_store,
+            type_system: Arc::new(RwLock::new(type_system)),
+            strict_mode: false,
+        }
+    }
+
+    pub(crate) fn with_strict_mode(self, strict_mode: bool) -> Self {
+        Self {
+            strict_mode,
+            ..self
+        }
+    }
+
+    /// The context `req` was routed with.
+    pub(crate) fn of(req: &Request<hyper::Body>) -> Result<Self> {
+        Self::from_extensions(req.extensions())
+    }
+
+    pub(crate) fn from_extensions(extensions: &Extensions) -> Result<Self> {
+        extensions
+            .get::<RequestContext>()
+            .cloned()
+            .context("Internal error: request has no context")
+    }
+
+    /// The current type system. Don't hold on to it across an `await`, or
+    /// updates to it will block.
+    pub(crate) fn type_system(&self) -> RwLockReadGuard<'_, TypeSystem> {
+        self.type_system.read().unwrap()
+    }
+
+    pub(crate) fn set_type_system(&self, type_system: TypeSystem) {
+        *self.type_system.write().unwrap() = type_system;
+    }
+
+    pub(crate) fn remove_type_version(&self, version: &str) {
+        self.type_system.write().unwrap().versions.remove(version);
+    }
+}
+
+/// A route calling `handler` with the query engine of the request's context.
+pub(crate) fn query_engine_route<F, Fut>(handler: F) -> RouteFn
+where
+    F: Fn(Request<hyper::Body>, Arc<QueryEngine>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<Body>>> + 'static,
+{
+    Arc::new(move |req| match RequestContext::of(&req) {
+        Ok(context) => handler(req, context.query_engine).boxed_local(),
+        Err(e) => future::ready(Err(e)).boxed_local(),
+    })
+}
@@ -1,4 +1,4 @@
-use crate::datastore::engine::{QueryEngine, TransactionStatic};
+use crate::datastore::engine::{QueryEngine, QueryResults, TransactionStatic};
 use crate::datastore::expr::{BinaryExpr, BinaryOp, Expr, Literal, PropertyAccess};
 use crate::datastore::query::{Mutation, QueryOp, QueryPlan, RequestContext, SortBy, SortKey};
 use crate::types::{ObjectType, Type};
@@ -45,6 +45,28 @@ fn run_query_impl(
     let ops = query.make_query_ops()?;
     let query_plan = QueryPlan::from_ops(context, base_type, ops)?;
     let stream = query_engine.query(tr.clone(), query_plan)?;
+    let count_plan = if query.count {
+        let ops = query.make_count_ops();
+        Some(QueryPlan::from_ops(context, base_type, ops)?)
+    } else {
+        None
+    };
+    let mut relations = vec![];
+    for name in &query.expand {
+        let relation = base_type.virtual_relation(name).with_context(|| {
+            format!(
+                "type {} has no relation {} to expand",
+                base_type.name(),
+                name
+            )
+        })?;
+        let target = context
+            .ts
+            .lookup_object_type(&relation.target_type, &context.api_version)
+            .with_context(|| format!("unknown target type of relation {}", name))?;
+        let plan = QueryPlan::from_ops(context, &target, vec![])?;
+        relations.push((relation.clone(), plan));
+    }
 
     Ok(async move {
         let mut results = stream
@@ -62,6 +84,24 @@ fn run_query_impl(
             }
         }
 
+        let ids: Vec<String> = results
+            .iter()
+            .filter_map(|r| r.get("id").and_then(|id| id.as_str()).map(str::to_owned))
+            .collect();
+        for (relation, plan) in &relations {
+            let mut related = query_engine
+                .query_related(tr.clone(), plan, &relation.subquery, &ids)
+                .await?;
+            for result in results.iter_mut() {
+                let entities = result
+                    .get("id")
+                    .and_then(|id| id.as_str())
+                    .and_then(|id| related.remove(id))
+                    .unwrap_or_default();
+                result.insert(relation.name.clone(), json!(entities));
+            }
+        }
+
         let mut ret = JsonObject::new();
 
         let next_page = get_next_page(&params, &query, &host, &results)?;
@@ -74,10 +114,61 @@ fn run_query_impl(
         }
 
         ret.insert("results".into(), json!(results));
+        let first_page = make_offset_page_url(&params.url, &host, &query, None)?;
+        ret.insert("first_page".into(), json!(first_page));
+        if let Some(count_plan) = count_plan {
+            let (total_count, estimated) = query_engine.count_or_estimate(tr, count_plan).await?;
+            ret.insert("total_count".into(), json!(total_count));
+            if estimated {
+                ret.insert("total_count_estimated".into(), json!(true));
+            }
+            if query.page_size > 0 {
+                let last_offset = total_count.saturating_sub(1) / query.page_size * query.page_size;
+                let last_page =
+                    make_offset_page_url(&params.url, &host, &query, Some(last_offset))?;
+                ret.insert("last_page".into(), json!(last_page));
+            }
+        }
         Ok(ret)
     })
 }
 
+/// Parses CRUD `params` of a `stream=true` request and returns the stream of
+/// all the matching rows, which are read from the database as they are
+/// consumed rather than collected into a page. Unless the URL has a limit,
+/// the rows aren't limited to the default page size.
+pub(crate) fn stream_query(
+    context: &RequestContext<'_>,
+    params: QueryParams,
+    query_engine: Arc<QueryEngine>,
+    tr: TransactionStatic,
+) -> Result<QueryResults> {
+    let base_type = &context
+        .ts
+        .lookup_object_type(&params.type_name, &context.api_version)
+        .context("unexpected type name as crud query base type")?;
+    let query = Query::from_url(base_type, &params.url)?;
+    anyhow::ensure!(
+        query.expand.is_empty(),
+        "expand is not supported when streaming results"
+    );
+    anyhow::ensure!(
+        query.cursor.as_ref().map_or(true, |c| c.forward),
+        "backward cursors are not supported when streaming results"
+    );
+    let mut ops = query.make_query_ops()?;
+    let limited = params
+        .url
+        .query_pairs()
+        .any(|(key, _)| key == "limit" || key == "page_size");
+    if !limited {
+        // The take of the default page size always comes last.
+        ops.pop();
+    }
+    let query_plan = QueryPlan::from_ops(context, base_type, ops)?;
+    query_engine.query(tr, query_plan)
+}
+
 /// Evaluates current query circumstances and potentially generates
 /// next page url if there is a potential for retrieving elements that succeed
 /// current resulting elements in query's sort.
@@ -171,6 +262,43 @@ fn make_page_url(url: &Url, host: &Option<String>, cursor: &Cursor) -> Result<Ur
     Ok(page_url)
 }
 
+/// Generates URL retrieving the page at `offset`, or the first page if there is none.
+/// Unlike the cursor-based URLs of [`make_page_url`], these don't depend on the
+/// current results, so the last page can be found from the total count alone.
+fn make_offset_page_url(
+    url: &Url,
+    host: &Option<String>,
+    query: &Query,
+    offset: Option<u64>,
+) -> Result<Url> {
+    // Cursors carry the sort themselves, so their URLs have no `sort` parameter.
+    let sort_key = match &query.cursor {
+        Some(cursor) => &cursor.axes[0].key,
+        None => &query.sort.keys[0],
+    };
+    let sort = format!(
+        "{}{}",
+        if sort_key.ascending { "" } else { "-" },
+        sort_key.field_name
+    );
+
+    let mut page_url = replace_host_address(url.clone(), host)?;
+    page_url.set_query(Some(""));
+    for (key, value) in url.query_pairs() {
+        if key == "cursor" || key == "sort" || key == "offset" {
+            continue;
+        }
+        page_url.query_pairs_mut().append_pair(&key, &value);
+    }
+    page_url.query_pairs_mut().append_pair("sort", &sort);
+    if let Some(offset) = offset {
+        page_url
+            .query_pairs_mut()
+            .append_pair("offset", &offset.to_string());
+    }
+    Ok(page_url)
+}
+
 /// Constructs Delete Mutation from CRUD url.
 pub(crate) fn delete_from_url(c: &RequestContext, type_name: &str, url: &str) -> Result<Mutation> {
     let base_entity = match c.ts.lookup_type(type_name, &c.api_version) {
@@ -200,6 +328,12 @@ struct Query {
     sort: SortBy,
     /// Filters restricting the result set. They will be joined in AND-fashion.
     filters: Vec<Expr>,
+    /// Filter selecting the page of the cursor, if there is one.
+    cursor_filter: Option<Expr>,
+    /// Whether to count all the results, on top of fetching the current page.
+    count: bool,
+    /// Virtual relations to be fetched along with the results, by name.
+    expand: Vec<String>,
 }
 
 impl Query {
@@ -215,6 +349,9 @@ impl Query {
                 }],
             },
             filters: vec![],
+            cursor_filter: None,
+            count: true,
+            expand: vec![],
         }
     }
 
@@ -235,6 +372,17 @@ impl Query {
                     })?;
                     q.offset = Some(o);
                 }
+                "count" => {
+                    q.count = value.parse().with_context(|| {
+                        format!("failed to parse count. Expected bool, got '{}'", value)
+                    })?;
+                }
+                "expand" => q.expand.extend(
+                    value
+                        .split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_owned),
+                ),
                 "cursor" => {
                     anyhow::ensure!(
                         q.cursor.is_none(),
@@ -258,7 +406,7 @@ impl Query {
         ensure_sort_by_id(&mut q.sort);
         if let Some(cursor) = &q.cursor {
             q.sort = cursor.get_sort();
-            q.filters.push(cursor.get_filter(base_type)?);
+            q.cursor_filter = Some(cursor.get_filter(base_type)?);
         }
         Ok(q)
     }
@@ -267,7 +415,7 @@ impl Query {
     /// The query ops can be used to retrieve desired results from the database.
     fn make_query_ops(&self) -> Result<Vec<QueryOp>> {
         let mut ops = vec![QueryOp::SortBy(self.sort.clone())];
-        for f_expr in self.filters.iter().cloned() {
+        for f_expr in self.filters.iter().chain(&self.cursor_filter).cloned() {
             ops.push(QueryOp::Filter { expression: f_expr });
         }
         if let Some(offset) = self.offset {
@@ -278,6 +426,15 @@ impl Query {
         });
         Ok(ops)
     }
+
+    /// Makes query ops retrieving all of the results that the pages are taken from.
+    fn make_count_ops(&self) -> Vec<QueryOp> {
+        self.filters
+            .iter()
+            .cloned()
+            .map(|expression| QueryOp::Filter { expression })
+            .collect()
+    }
 }
 
 fn ensure_sort_by_id(sort: &mut SortBy) {
@@ -391,7 +548,7 @@ impl Cursor {
     }
 }
 
-fn json_to_literal(field_type: &Type, value: &serde_json::Value) -> Result<Expr> {
+pub(crate) fn json_to_literal(field_type: &Type, value: &serde_json::Value) -> Result<Expr> {
     macro_rules! convert {
         ($as_type:ident, $ty_name:literal) => {{
             value
@@ -407,9 +564,13 @@ fn json_to_literal(field_type: &Type, value: &serde_json::Value) -> Result<Expr>
             "trying to filter by property of type '{}' which is not supported",
             ty.name()
         ),
-        Type::String | Type::Id => Literal::String(convert!(as_str, "string")),
+        Type::String | Type::Id | Type::Enum(_) => Literal::String(convert!(as_str, "string")),
         Type::Float => Literal::F64(convert!(as_f64, "float")),
         Type::Boolean => Literal::Bool(convert!(as_bool, "bool")),
+        Type::Blob | Type::Array(_) | Type::Map(_) => anyhow::bail!(
+            "trying to filter by property of type '{}' which is not supported",
+            field_type.name()
+        ),
     };
     Ok(Expr::Literal { value: literal })
 }
@@ -442,15 +603,17 @@ fn parse_sort(base_type: &Arc<ObjectType>, value: &str) -> Result<SortBy> {
     } else {
         value
     };
-    anyhow::ensure!(
-        base_type.has_field(field_name),
-        "trying to sort by non-existent field '{}' on entity {}",
-        field_name,
-        base_type.name(),
-    );
+    let field = base_type.lookup_field(field_name)?.with_context(|| {
+        format!(
+            "trying to sort by non-existent field '{}' on entity {}",
+            field_name,
+            base_type.name(),
+        )
+    })?;
     Ok(SortBy {
         keys: vec![SortKey {
-            field_name: field_name.to_owned(),
+            // The name the cursor finds the field by in the results.
+            field_name: field.json_name().to_owned(),
             ascending,
         }],
     })
@@ -471,15 +634,20 @@ fn filter_from_param(base_type: &Arc<ObjectType>, param_key: &str, value: &str)
     let (property_chain, field_type) = make_property_chain(base_type, &fields)?;
 
     let err_msg = |ty_name| format!("failed to convert filter value '{}' to {}", value, ty_name);
-    let literal = match field_type {
+    let literal = match &field_type {
         Type::Object(ty) => anyhow::bail!(
             "trying to filter by property '{}' of type '{}' which is not supported",
             fields.last().unwrap(),
             ty.name()
         ),
-        Type::String | Type::Id => Literal::String(value.to_owned()),
+        Type::String | Type::Id | Type::Enum(_) => Literal::String(value.to_owned()),
         Type::Float => Literal::F64(value.parse::<f64>().with_context(|| err_msg("f64"))?),
         Type::Boolean => Literal::Bool(value.parse::<bool>().with_context(|| err_msg("bool"))?),
+        Type::Blob | Type::Array(_) | Type::Map(_) => anyhow::bail!(
+            "trying to filter by property '{}' of type '{}' which is not supported",
+            fields.last().unwrap(),
+            field_type.name()
+        ),
     };
 
     Ok(BinaryExpr::new(operator, property_chain, literal.into()).into())
@@ -496,7 +664,7 @@ fn make_property_chain(base_type: &Arc<ObjectType>, fields: &[&str]) -> Result<(
     let mut last_type = Type::Object(base_type.clone());
     for &field_str in fields {
         if let Type::Object(entity) = last_type {
-            if let Some(field) = entity.get_field(field_str) {
+            if let Some(field) = entity.lookup_field(field_str)? {
                 last_type = field.type_.clone();
             } else {
                 anyhow::bail!(
@@ -566,7 +734,7 @@ mod tests {
         VERSION,
     };
     use crate::policies::Policies;
-    use crate::types::{FieldDescriptor, ObjectDescriptor};
+    use crate::types::{AuthOrNot, FieldDescriptor, NewObject, ObjectDescriptor, VirtualRelation};
     use crate::JsonObject;
 
     use itertools::Itertools;
@@ -769,6 +937,8 @@ mod tests {
                 ts: &make_type_system(&*ENTITIES),
                 api_version: VERSION.to_owned(),
                 user_id: None,
+                tenant_id: None,
+                region: None,
                 path: "".to_string(),
                 headers,
             },
@@ -874,6 +1044,221 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_expand_virtual_relation() {
+        let comment_ty = make_object(
+            "Comment",
+            vec![
+                make_field("post", Type::String),
+                make_field("body", Type::String),
+            ],
+        );
+        let latest = VirtualRelation {
+            name: "latestComment".into(),
+            target_type: "Comment".into(),
+            subquery: "\"post\" = $id ORDER BY \"body\" DESC LIMIT 1".into(),
+        };
+        let post_ty = Arc::new(
+            ObjectType::new(
+                NewObject::new("Post", VERSION),
+                vec![make_field("name", Type::String)],
+                vec![],
+                AuthOrNot::IsNotAuth,
+            )
+            .unwrap()
+            .with_virtual_relations(vec![latest])
+            .unwrap(),
+        );
+        let entities = [post_ty.clone(), comment_ty.clone()];
+        let (query_engine, _db_file) = setup_clear_db(&entities).await;
+        let qe = Arc::new(query_engine);
+        let first = json!({"name": "first"});
+        let second = json!({"name": "second"});
+        add_row(&qe, &post_ty, &first).await;
+        add_row(&qe, &post_ty, &second).await;
+        let posts = fetch_rows(&qe, &post_ty).await;
+        let id_of = |name: &str| {
+            let post = posts.iter().find(|p| p["name"] == name).unwrap();
+            post["id"].as_str().unwrap().to_owned()
+        };
+        for body in ["a", "c", "b"] {
+            let comment = json!({"post": id_of("first"), "body": body});
+            add_row(&qe, &comment_ty, &comment).await;
+        }
+
+        let ts = make_type_system(&entities);
+        let run = |query_string: &str| {
+            let qe = qe.clone();
+            let url = url(query_string);
+            let ts = &ts;
+            async move {
+                let tr = qe.clone().start_transaction_static().await.unwrap();
+                super::run_query(
+                    &RequestContext {
+                        policies: &Policies::default(),
+                        ts,
+                        api_version: VERSION.to_owned(),
+                        user_id: None,
+                        tenant_id: None,
+                        region: None,
+                        path: "".to_string(),
+                        headers: HashMap::default(),
+                    },
+                    QueryParams {
+                        type_name: "Post".to_owned(),
+                        url,
+                    },
+                    qe,
+                    tr,
+                )
+                .await
+            }
+        };
+
+        let r = run("sort=name&expand=latestComment").await.unwrap();
+        let expanded: Vec<_> = r["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|post| {
+                let bodies: Vec<_> = post["latestComment"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|c| c["body"].as_str().unwrap().to_owned())
+                    .collect();
+                (post["name"].as_str().unwrap().to_owned(), bodies)
+            })
+            .collect();
+        assert_eq!(
+            expanded,
+            vec![
+                ("first".to_owned(), vec!["c".to_owned()]),
+                ("second".to_owned(), vec![]),
+            ]
+        );
+
+        let r = run("").await.unwrap();
+        assert!(r["results"][0].get("latestComment").is_none());
+        assert!(run("expand=comments").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_total_count() {
+        let (query_engine, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for (name, age) in [("Alan", 30f32), ("John", 20f32), ("Steve", 29f32)] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": json!(age)})).await;
+        }
+
+        let r = run_query("Person", url("page_size=1&.age~gt=25"), qe)
+            .await
+            .unwrap();
+        assert_eq!(collect_names(&r).len(), 1);
+        assert_eq!(r["total_count"], json!(2));
+
+        // Paging with a cursor doesn't change the count.
+        let next_page = Url::parse(r["next_page"].as_str().unwrap()).unwrap();
+        let r = run_query("Person", next_page, qe).await.unwrap();
+        assert_eq!(r["total_count"], json!(2));
+
+        let r = run_query("Person", url("count=false"), qe).await.unwrap();
+        assert!(!r.contains_key("total_count"));
+    }
+
+    async fn stream_names(url: Url, qe: &QueryEngine) -> Result<Vec<String>> {
+        let qe = Arc::new(qe.clone());
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        let stream = super::stream_query(
+            &RequestContext {
+                policies: &Policies::default(),
+                ts: &make_type_system(&*ENTITIES),
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                tenant_id: None,
+                region: None,
+                path: "".to_string(),
+                headers: HashMap::default(),
+            },
+            QueryParams {
+                type_name: "Person".to_owned(),
+                url,
+            },
+            qe,
+            tr,
+        )?;
+        let rows = stream.collect::<Vec<_>>().await;
+        rows.into_iter()
+            .map(|row| Ok(row?["name"].as_str().unwrap().to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_stream_query() {
+        let (query_engine, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for i in 0..1001 {
+            let name = format!("{:04}", i);
+            add_row(
+                qe,
+                &PERSON_TY,
+                &json!({"name": name, "age": json!(i as f32)}),
+            )
+            .await;
+        }
+
+        // Not limited to the default page size of 1000.
+        let names = stream_names(url("stream=true&sort=name"), qe)
+            .await
+            .unwrap();
+        assert_eq!(names.len(), 1001);
+        assert_eq!(names[1000], "1000");
+
+        let names = stream_names(url("stream=true&sort=-name&limit=2"), qe)
+            .await
+            .unwrap();
+        assert_eq!(names, vec!["1000", "0999"]);
+        let names = stream_names(url("stream=true&.age~lt=2"), qe)
+            .await
+            .unwrap();
+        assert_eq!(names.len(), 2);
+
+        stream_names(url("stream=true&expand=friends"), qe)
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_page_links() {
+        let (query_engine, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for (name, age) in [("Alan", 30f32), ("Alex", 40f32), ("John", 20f32)] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": json!(age)})).await;
+        }
+        fn page_url(r: &JsonObject, rel: &str) -> Option<Url> {
+            r.get(rel).map(|u| Url::parse(u.as_str().unwrap()).unwrap())
+        }
+
+        let mut names = vec![];
+        let mut next_page = Some(url("sort=-name&page_size=2"));
+        while let Some(page) = next_page {
+            let r = run_query("Person", page, qe).await.unwrap();
+            names.extend(collect_names(&r));
+
+            let first = run_query("Person", page_url(&r, "first_page").unwrap(), qe)
+                .await
+                .unwrap();
+            assert_eq!(collect_names(&first), vec!["John", "Alex"]);
+            let last = run_query("Person", page_url(&r, "last_page").unwrap(), qe)
+                .await
+                .unwrap();
+            assert_eq!(collect_names(&last), vec!["Alan"]);
+
+            next_page = page_url(&r, "next_page");
+        }
+        assert_eq!(names, vec!["John", "Alex", "Alan"]);
+    }
+
     #[tokio::test]
     async fn test_paging() {
         let alan = json!({"name": "Alan", "age": json!(30f32)});
@@ -1076,6 +1461,8 @@ mod tests {
                     ts: &make_type_system(&*ENTITIES),
                     api_version: VERSION.to_owned(),
                     user_id: None,
+                    tenant_id: None,
+                    region: None,
                     path: "".to_string(),
                     headers: HashMap::default(),
                 },
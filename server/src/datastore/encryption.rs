@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Encryption at rest of the fields marked `@encrypted`.
+//!
+//! Values are encrypted with AES-256-GCM under a key derived with
+//! HKDF-SHA256 from the `CHISEL_FIELD_ENCRYPTION_KEY` environment variable,
+//! so that whoever reads the database can't read them without it. They are
+//! stored as `enc:v1:` followed by the base64 of a random nonce and the
+//! ciphertext. Values without that prefix were stored before their field
+//! was encrypted: they are read as they are until `chisel encrypt-existing`
+//! encrypts them.
+
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Context, Result};
+use hkdf::Hkdf;
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use sha2::Sha256;
+
+/// The environment variable holding the secret the key is derived from.
+pub(crate) const KEY_VAR: &str = "CHISEL_FIELD_ENCRYPTION_KEY";
+
+const PREFIX: &str = "enc:v1:";
+
+/// Tells the field key apart from other keys derived from the same secret.
+const HKDF_INFO: &[u8] = b"chiselstrike field encryption v1";
+
+const NONCE_LEN: usize = 12;
+
+struct FieldCipher(Aes256Gcm);
+
+impl FieldCipher {
+    fn new(secret: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(HKDF_INFO, &mut key)
+            .expect("HKDF-SHA256 can derive 32 bytes");
+        Self(Aes256Gcm::new(aes_gcm::Key::from_slice(&key)))
+    }
+
+    fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .map_err(|x| anyhow!("Failed to encrypt field: {:?}", x))?;
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+        Ok(format!("{}{}", PREFIX, base64::encode(payload)))
+    }
+
+    fn decrypt(&self, value: &str) -> Result<String> {
+        let payload = match value.strip_prefix(PREFIX) {
+            Some(payload) => payload,
+            None => return Ok(value.to_owned()),
+        };
+        let payload = base64::decode(payload).context("encrypted field is not base64")?;
+        anyhow::ensure!(payload.len() > NONCE_LEN, "encrypted field is truncated");
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt field: was {} changed?", KEY_VAR))?;
+        String::from_utf8(plaintext).context("decrypted field is not UTF-8")
+    }
+}
+
+static CIPHER: Lazy<Option<FieldCipher>> = Lazy::new(|| {
+    let secret = std::env::var(KEY_VAR).ok().filter(|s| !s.is_empty())?;
+    Some(FieldCipher::new(secret.as_bytes()))
+});
+
+fn cipher() -> Result<&'static FieldCipher> {
+    CIPHER
+        .as_ref()
+        .ok_or_else(|| anyhow!("encrypted fields need a secret in {}", KEY_VAR))
+}
+
+/// Fails unless there is a key to encrypt fields with.
+pub(crate) fn ensure_key() -> Result<()> {
+    cipher().map(|_| ())
+}
+
+/// Whether `value` is stored encrypted, rather than as plaintext.
+pub(crate) fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// `plaintext`, encrypted for storing.
+pub(crate) fn encrypt(plaintext: &str) -> Result<String> {
+    cipher()?.encrypt(plaintext)
+}
+
+/// The plaintext of the stored `value`.
+pub(crate) fn decrypt(value: &str) -> Result<String> {
+    if !is_encrypted(value) {
+        return Ok(value.to_owned());
+    }
+    cipher()?.decrypt(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cipher = FieldCipher::new(b"secret");
+        let first = cipher.encrypt("555-0100").unwrap();
+        let second = cipher.encrypt("555-0100").unwrap();
+        assert!(is_encrypted(&first));
+        assert!(!first.contains("555-0100"));
+        assert_ne!(first, second);
+        assert_eq!(cipher.decrypt(&first).unwrap(), "555-0100");
+        assert_eq!(cipher.decrypt(&second).unwrap(), "555-0100");
+        assert_eq!(cipher.decrypt("plain").unwrap(), "plain");
+
+        FieldCipher::new(b"other secret")
+            .decrypt(&first)
+            .unwrap_err();
+        let mut tampered = first.clone();
+        tampered.replace_range(PREFIX.len()..PREFIX.len() + 4, "AAAA");
+        cipher.decrypt(&tampered).unwrap_err();
+        cipher.decrypt("enc:v1:AAAA").unwrap_err();
+    }
+}
@@ -1,10 +1,19 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
+use crate::blob::{blob_key, blob_url};
+use crate::datastore::dbconn::statement_timeout_sql;
 use crate::datastore::query::{
-    Mutation, QueriedEntity, QueryField, QueryPlan, SqlValue, TargetDatabase,
+    escape_string, Mutation, QueriedEntity, QueryField, QueryPlan, SqlValue, TargetDatabase,
 };
+use crate::datastore::watch::{self, ChangeEvent, ChangeStream, PgListenManager};
+use crate::datastore::{crud, encryption, ids};
 use crate::datastore::{DbConnection, Kind};
-use crate::types::{DbIndex, Field, ObjectDelta, ObjectType, Type};
+use crate::metrics::{self, Operation};
+use crate::migrations;
+use crate::types::{
+    CheckConstraint, DbIndex, Field, IdStrategy, ObjectDelta, ObjectType, Type, UniqueConstraint,
+    VERSION_FIELD_NAME,
+};
 use crate::JsonObject;
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use async_lock::Mutex;
@@ -19,13 +28,146 @@ use pin_project::pin_project;
 use sea_query::{Alias, ColumnDef, Index, Table};
 use serde::Serialize;
 use serde_json::json;
-use sqlx::any::{Any, AnyArguments, AnyPool, AnyRow};
+use sqlx::any::{Any, AnyArguments, AnyConnection, AnyPool, AnyQueryResult, AnyRow};
 use sqlx::{Executor, Row, Transaction, ValueRef};
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use uuid::Uuid;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum HealthCheckError {
+    #[error["database health check timed out after {0:?}"]]
+    Timeout(Duration),
+    #[error["no database connection available in the pool"]]
+    PoolExhausted,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum QueryError {
+    #[error["version conflict: {0} {1} was modified since it was loaded"]]
+    VersionConflict(String, String),
+    #[error["{0}.{1} can't be null"]]
+    NotNullable(String, String),
+    #[error["{0} violates unique constraint {1}"]]
+    UniqueViolation(String, String),
+    #[error["violates check constraint {constraint_name}"]]
+    CheckConstraintViolation { constraint_name: String },
+    #[error["query cancelled after running for longer than {0:?}"]]
+    Timeout(Duration),
+    #[error["field {0} is encrypted, so it can't be filtered or sorted on"]]
+    EncryptedField(String),
+    #[error["{0} conflicts with an entity of another tenant"]]
+    OtherTenant(String),
+}
+
+fn check_constraint_sql(constraint: &CheckConstraint) -> String {
+    format!(
+        "CONSTRAINT \"{}\" CHECK ({})",
+        constraint.name, constraint.expression
+    )
+}
+
+/// Turns `e` into a [`QueryError::UniqueViolation`] or a
+/// [`QueryError::CheckConstraintViolation`] if it comes from one of the
+/// constraints of `ty`.
+fn constraint_violation(ty: &ObjectType, e: sqlx::Error) -> anyhow::Error {
+    if let sqlx::Error::Database(db_error) = &e {
+        let message = db_error.message();
+        for constraint in ty.check_constraints() {
+            let name = &constraint.name;
+            if message.ends_with(&format!("CHECK constraint failed: {name}"))
+                || message.contains(&format!("check constraint \"{name}\""))
+            {
+                return QueryError::CheckConstraintViolation {
+                    constraint_name: name.clone(),
+                }
+                .into();
+            }
+        }
+        let table = ty.backing_table();
+        for constraint in ty.unique_constraints() {
+            // PostgreSQL names the index, SQLite lists its columns.
+            let index = format!("\"{}\"", constraint.index_name(table));
+            let columns = constraint
+                .fields
+                .iter()
+                .map(|f| format!("{table}.{f}"))
+                .join(", ");
+            if message.contains(&index) || message.ends_with(&format!("failed: {columns}")) {
+                return QueryError::UniqueViolation(
+                    ty.name().to_owned(),
+                    constraint.display_name(),
+                )
+                .into();
+            }
+        }
+    }
+    e.into()
+}
+
+fn ensure_index_names(indexes: &[DbIndex]) -> Result<()> {
+    anyhow::ensure!(
+        indexes.iter().all(|index| index.name().is_some()),
+        "index must have a name at a time of table creation"
+    );
+    Ok(())
+}
+
+/// Checks that `value` fits the array or map `field`.
+fn check_json(field: &Field, value: &serde_json::Value) -> Result<()> {
+    let (values, elem): (Vec<_>, _) = match &field.type_ {
+        Type::Array(elem) => {
+            let values = value
+                .as_array()
+                .with_context(|| format!("field {} takes an array, got {}", field.name, value))?;
+            (values.iter().collect(), Some(&**elem))
+        }
+        Type::Map(elem) => {
+            let values = value
+                .as_object()
+                .with_context(|| format!("field {} takes an object, got {}", field.name, value))?;
+            (values.values().collect(), elem.as_deref())
+        }
+        _ => anyhow::bail!("field {} doesn't hold JSON", field.name),
+    };
+    for v in values {
+        let ok = match elem {
+            None => true,
+            Some(Type::Float) => v.is_number(),
+            Some(Type::Boolean) => v.is_boolean(),
+            Some(_) => v.is_string(),
+        };
+        anyhow::ensure!(
+            ok,
+            "field {} takes values of type {}, got {}",
+            field.name,
+            field.type_.name(),
+            v
+        );
+    }
+    Ok(())
+}
+
+/// Definition of the column that [`ObjectType::version_field`] is stored in.
+fn version_column_def() -> ColumnDef {
+    ColumnDef::new(Alias::new(VERSION_FIELD_NAME))
+        .double()
+        .not_null()
+        .default(0)
+        .to_owned()
+}
+
+/// The argument binding `version` to the column of [`version_column_def`].
+/// Versions are bound rather than formatted into the SQL, so that the
+/// statement stays the same across versions and its prepared form is reused.
+fn version_arg(version: u64) -> SqlValue {
+    SqlValue::F64(version as f64)
+}
 
 /// A query row is a JSON object that represent the queried entities.
 pub(crate) type ResultRow = JsonObject;
@@ -43,13 +185,24 @@ pub(crate) fn extract_transaction(transaction: TransactionStatic) -> Transaction
 /// `RawQueryResults` represents the raw query results from the backing stor
 ///  before policies are applied.
 #[pin_project]
-struct RawQueryResults<T> {
+struct RawQueryResults<C, T> {
     raw_query: String,
-    tr: MutexGuardArc<Transaction<'static, Any>>,
+    /// The transaction or connection the stream reads from, held until it ends.
+    conn: C,
     #[pin]
     stream: T,
 }
 
+/// Whether `e` is a PostgreSQL server cancelling a statement, which it does once
+/// the statement runs past the `statement_timeout` of its connection.
+fn is_statement_timeout(e: &sqlx::Error) -> bool {
+    const QUERY_CANCELED: &str = "57014";
+    match e {
+        sqlx::Error::Database(e) => e.code().as_deref() == Some(QUERY_CANCELED),
+        _ => false,
+    }
+}
+
 async fn make_transactioned_stream(
     tr: TransactionStatic,
     raw_query: String,
@@ -58,18 +211,52 @@ async fn make_transactioned_stream(
 
     // The string data and Transaction will not move anymore.
     let raw_query_ptr = raw_query.as_ref() as *const str;
-    let query = sqlx::query::<Any>(unsafe { &*raw_query_ptr });
+    // Query plans inline their values, so their SQL is not worth keeping prepared.
+    let query = sqlx::query::<Any>(unsafe { &*raw_query_ptr }).persistent(false);
     let tr_ptr = &mut *tr as *mut _;
     let tr_ref = unsafe { &mut *tr_ptr };
     let stream = query.fetch(tr_ref).map(|i| i.map_err(anyhow::Error::new));
 
     RawQueryResults {
-        tr,
+        conn: tr,
         raw_query,
         stream,
     }
 }
 
+/// Reads `raw_query` from a connection of `replica`, outside of any
+/// transaction, or from `tr` if the replica can't be connected to.
+async fn make_replica_stream(
+    replica: AnyPool,
+    tr: TransactionStatic,
+    raw_query: String,
+) -> impl Stream<Item = anyhow::Result<AnyRow>> {
+    let mut conn = match replica.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            warn!(
+                "Region replica unavailable, reading from the primary: {}",
+                e
+            );
+            return make_transactioned_stream(tr, raw_query).await.left_stream();
+        }
+    };
+
+    // Like in make_transactioned_stream, neither the string nor the connection will move.
+    let raw_query_ptr = raw_query.as_ref() as *const str;
+    let query = sqlx::query::<Any>(unsafe { &*raw_query_ptr }).persistent(false);
+    let conn_ptr = &mut *conn as *mut AnyConnection;
+    let conn_ref = unsafe { &mut *conn_ptr };
+    let stream = query.fetch(conn_ref).map(|i| i.map_err(anyhow::Error::new));
+
+    RawQueryResults {
+        conn,
+        raw_query,
+        stream,
+    }
+    .right_stream()
+}
+
 pub(crate) fn new_query_results(
     raw_query: String,
     tr: TransactionStatic,
@@ -77,7 +264,49 @@ pub(crate) fn new_query_results(
     make_transactioned_stream(tr, raw_query).flatten_stream()
 }
 
-impl<T: Stream<Item = Result<AnyRow>>> Stream for RawQueryResults<T> {
+/// Rows of a query that fail with [`QueryError::Timeout`] and end once the
+/// database takes longer than `timeout` to give the next one. The time the
+/// consumer takes between rows doesn't count.
+#[pin_project]
+struct TimedResults<T> {
+    #[pin]
+    stream: T,
+    #[pin]
+    deadline: tokio::time::Sleep,
+    timeout: Duration,
+    /// Called when the deadline passes, to make the error to end with.
+    on_timeout: Option<Box<dyn FnOnce() -> anyhow::Error + Send>>,
+}
+
+impl<T: Stream<Item = Result<AnyRow>>> Stream for TimedResults<T> {
+    type Item = Result<AnyRow>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let on_timeout = match this.on_timeout.take() {
+            Some(on_timeout) => on_timeout,
+            None => return Poll::Ready(None),
+        };
+        match this.stream.poll_next(cx) {
+            Poll::Ready(Some(row)) => {
+                *this.on_timeout = Some(on_timeout);
+                let timeout = *this.timeout;
+                this.deadline.reset(tokio::time::Instant::now() + timeout);
+                Poll::Ready(Some(row))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => match this.deadline.poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Some(Err(on_timeout()))),
+                Poll::Pending => {
+                    *this.on_timeout = Some(on_timeout);
+                    Poll::Pending
+                }
+            },
+        }
+    }
+}
+
+impl<C, T: Stream<Item = Result<AnyRow>>> Stream for RawQueryResults<C, T> {
     type Item = Result<AnyRow>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
@@ -92,11 +321,19 @@ impl TryFrom<&Field> for ColumnDef {
         if field.is_unique {
             column_def.unique_key();
         }
-        match field.type_ {
+        match &field.type_ {
             Type::String => column_def.text(),
             Type::Id => column_def.text().primary_key(),
             Type::Float => column_def.double(),
             Type::Boolean => column_def.boolean(),
+            Type::Blob => column_def.text(), // Key into the blob store.
+            Type::Array(_) | Type::Map(_) => column_def.text(), // JSON array or object.
+            Type::Enum(variants) => {
+                let variants = variants.iter().map(|v| escape_string(v)).join(", ");
+                column_def
+                    .text()
+                    .extra(format!("CHECK (\"{}\" IN ({}))", field.name, variants))
+            }
             Type::Object(_) => column_def.text(), // Foreign key, must the be same type as Type::Id
         };
 
@@ -115,8 +352,17 @@ pub(crate) struct SqlWithArguments {
 }
 
 impl SqlWithArguments {
-    fn get_sqlx(&self) -> sqlx::query::Query<'_, sqlx::Any, AnyArguments> {
-        let mut sqlx_query = sqlx::query(&self.sql);
+    /// A query running this SQL with these arguments. sqlx keeps its statement
+    /// prepared on each connection it runs on, see
+    /// [`crate::datastore::dbconn::STATEMENT_CACHE_CAPACITY`].
+    fn get_sqlx(&self) -> sqlx::query::Query<'_, sqlx::Any, AnyArguments<'_>> {
+        self.bind(sqlx::query(&self.sql))
+    }
+
+    fn bind<'q>(
+        &'q self,
+        mut sqlx_query: sqlx::query::Query<'q, sqlx::Any, AnyArguments<'q>>,
+    ) -> sqlx::query::Query<'q, sqlx::Any, AnyArguments<'q>> {
         for arg in &self.args {
             match arg {
                 SqlValue::Bool(arg) => sqlx_query = sqlx_query.bind(arg),
@@ -130,7 +376,7 @@ impl SqlWithArguments {
 
 /// Represents recurent structure of nested object ids. Each level holds
 /// the `id` of the current object and `children` object in a map where
-/// key is the field name of the object and value is another IdTree level.
+/// key is the JSON name of the field and value is another IdTree level.
 ///
 /// Example: Object {
 ///     id: "xxx",
@@ -153,6 +399,45 @@ pub(crate) struct IdTree {
     children: HashMap<String, IdTree>,
 }
 
+/// Tables with more rows than this, going by the statistics of the database,
+/// aren't counted whole, see [`QueryEngine::count_or_estimate`].
+pub(crate) const EXACT_COUNT_LIMIT: u64 = 100_000;
+
+/// What the database knows about the backing table of a type, see
+/// [`QueryEngine::table_stats`].
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TableStats {
+    pub(crate) estimated_row_count: u64,
+    /// Bytes taken by the table, with its indexes on PostgreSQL.
+    pub(crate) size_bytes: Option<u64>,
+    /// When the table was last vacuumed, by hand or automatically. SQLite
+    /// doesn't keep track of it.
+    pub(crate) last_vacuum: Option<String>,
+}
+
+/// The rows matching a [`QueryEngine::search`].
+pub(crate) struct SearchResults {
+    /// The ids of the best matching rows, best first, with their relevance:
+    /// the higher, the better the row matches.
+    pub(crate) hits: Vec<(String, f64)>,
+    /// How many rows match, counting those past the limit.
+    pub(crate) total_hits: u64,
+}
+
+/// The temporary FTS5 table SQLite searches in.
+const SEARCH_TABLE: &str = "chisel_search";
+
+/// The terms of `query`, each quoted so that FTS5 takes its syntax
+/// characters literally. As with `plainto_tsquery`, a row matches if it
+/// holds all of them.
+fn fts5_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .join(" ")
+}
+
 fn column_is_null(row: &AnyRow, column_idx: usize) -> bool {
     row.try_get_raw(column_idx).unwrap().is_null()
 }
@@ -180,20 +465,129 @@ fn id_idx(entity: &QueriedEntity) -> usize {
 /// backing store database to take advantage of the query optimizer. However,
 /// some parts of a mutation or query need to run through the policy engine,
 /// which is not always offloadable to a database.
+///
+/// Clones share the connection pool and the change listener of the original,
+/// so they can be handed to other tasks freely.
 #[derive(Clone)]
 pub(crate) struct QueryEngine {
     kind: Kind,
     pool: AnyPool,
+    conn_uri: String,
+    /// Created by the first `watch` on PostgreSQL.
+    pg_listener: Arc<Mutex<Option<Arc<PgListenManager>>>>,
+    /// See [`crate::datastore::PoolConfig::query_timeout`].
+    query_timeout: Duration,
+    /// The database replicas of the regions, which query plans made for a
+    /// request from one of them read from. See [`crate::region`].
+    region_pools: Arc<HashMap<String, AnyPool>>,
+    /// See [`crate::datastore::PoolConfig::lazy_migrations`].
+    lazy_migrations: bool,
+    /// See [`crate::datastore::PoolConfig::namespace`].
+    namespace: Option<String>,
 }
 
 impl QueryEngine {
-    fn new(kind: Kind, pool: AnyPool) -> Self {
-        Self { kind, pool }
+    fn new(kind: Kind, pool: AnyPool, conn_uri: String, query_timeout: Duration) -> Self {
+        Self {
+            kind,
+            pool,
+            conn_uri,
+            pg_listener: Default::default(),
+            query_timeout,
+            region_pools: Default::default(),
+            lazy_migrations: false,
+            namespace: None,
+        }
     }
 
     pub(crate) async fn local_connection(conn: &DbConnection, nr_conn: usize) -> Result<Self> {
         let local = conn.local_connection(nr_conn).await?;
-        Ok(Self::new(local.kind, local.pool))
+        let mut engine = Self::new(local.kind, local.pool, local.conn_uri, local.query_timeout);
+        engine.region_pools = Arc::new(local.region_pools);
+        engine.lazy_migrations = local.lazy_migrations;
+        engine.namespace = local.namespace;
+        Ok(engine)
+    }
+
+    /// The kind of the database the engine runs queries on.
+    pub(crate) fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// The namespace the tables of new types are put in, if any.
+    pub(crate) fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Runs `execution`, that of `sql`, failing with [`QueryError::Timeout`]
+    /// if it takes longer than the query timeout. On PostgreSQL, the server
+    /// cancels the statement then too, as the [`DbConnection`] sets its
+    /// `statement_timeout`. The statements changing the schema aren't timed,
+    /// as migrations can rightly take long, see [`Self::execute_all`].
+    async fn timed<T>(
+        &self,
+        sql: &str,
+        execution: impl Future<Output = std::result::Result<T, sqlx::Error>>,
+    ) -> Result<std::result::Result<T, sqlx::Error>> {
+        if self.query_timeout.is_zero() {
+            return Ok(execution.await);
+        }
+        match tokio::time::timeout(self.query_timeout, execution).await {
+            Ok(Err(e)) if is_statement_timeout(&e) => Err(self.timed_out(sql)),
+            Ok(result) => Ok(result),
+            Err(_) => Err(self.timed_out(sql)),
+        }
+    }
+
+    /// The rows of `sql`, timed like [`Self::timed`] does. They are read
+    /// from the replica of `region` if there is one, and from `tr` otherwise.
+    fn timed_results(
+        &self,
+        sql: String,
+        tr: TransactionStatic,
+        region: Option<&str>,
+    ) -> impl Stream<Item = Result<AnyRow>> + Send {
+        let stream = match region.and_then(|r| self.region_pools.get(r)) {
+            Some(replica) => make_replica_stream(replica.clone(), tr, sql.clone())
+                .flatten_stream()
+                .left_stream(),
+            None => make_transactioned_stream(tr, sql.clone())
+                .flatten_stream()
+                .right_stream(),
+        };
+        if self.query_timeout.is_zero() {
+            return stream.left_stream();
+        }
+        let engine = self.clone();
+        let timed_sql = sql.clone();
+        let stream = stream.map(move |row| match row {
+            Err(e)
+                if e.downcast_ref::<sqlx::Error>()
+                    .map_or(false, is_statement_timeout) =>
+            {
+                Err(engine.timed_out(&timed_sql))
+            }
+            row => row,
+        });
+        let engine = self.clone();
+        TimedResults {
+            stream,
+            deadline: tokio::time::sleep(self.query_timeout),
+            timeout: self.query_timeout,
+            on_timeout: Some(Box::new(move || engine.timed_out(&sql))),
+        }
+        .right_stream()
+    }
+
+    /// Reports that `sql` went past the query timeout. PostgreSQL servers
+    /// cancel it on their own, while SQLite has no way of cancelling a
+    /// statement another connection runs.
+    fn timed_out(&self, sql: &str) -> anyhow::Error {
+        const MAX_LOGGED_SQL: usize = 200;
+        let logged: String = sql.chars().take(MAX_LOGGED_SQL).collect();
+        warn!("Query timed out after {:?}: {}", self.query_timeout, logged);
+        metrics::count_query_timeout();
+        QueryError::Timeout(self.query_timeout).into()
     }
 
     fn target_db(&self) -> TargetDatabase {
@@ -203,21 +597,65 @@ impl QueryEngine {
         }
     }
 
-    pub(crate) async fn drop_table(
+    /// Verifies that the database is reachable by running a trivial query
+    /// that doesn't touch any user data. Returns the round-trip time.
+    pub(crate) async fn health_check(&self, timeout: Duration) -> Result<Duration> {
+        let start = Instant::now();
+        let query = sqlx::query("SELECT 1").execute(&self.pool);
+        match tokio::time::timeout(timeout, query).await {
+            Ok(Ok(_)) => Ok(start.elapsed()),
+            Ok(Err(sqlx::Error::PoolTimedOut)) => Err(HealthCheckError::PoolExhausted.into()),
+            Ok(Err(e)) => Err(anyhow::Error::from(e).context("running health check query")),
+            Err(_) => Err(HealthCheckError::Timeout(timeout).into()),
+        }
+    }
+
+    /// Lifts the `statement_timeout` that PostgreSQL connections of the pool
+    /// have, for the rest of `transaction` or until it is put back with
+    /// `timed`, so that the statements changing the schema can take long.
+    async fn set_timed(&self, transaction: &mut Transaction<'_, Any>, timed: bool) -> Result<()> {
+        if !matches!(self.kind, Kind::Postgres) || self.query_timeout.is_zero() {
+            return Ok(());
+        }
+        let timeout = match timed {
+            true => self.query_timeout,
+            false => Duration::ZERO,
+        };
+        let sql = format!("SET LOCAL {}", statement_timeout_sql(timeout));
+        transaction.execute(sql.as_str()).await?;
+        Ok(())
+    }
+
+    /// Runs `statements`, which change the schema, in `transaction`, untimed.
+    async fn execute_all(
         &self,
         transaction: &mut Transaction<'_, Any>,
-        ty: &ObjectType,
+        statements: Vec<String>,
     ) -> Result<()> {
-        self.drop_indexes(transaction, ty, ty.indexes()).await?;
+        self.set_timed(transaction, false).await?;
+        for sql in statements {
+            transaction.execute(sqlx::query(&sql)).await?;
+        }
+        self.set_timed(transaction, true).await
+    }
 
+    /// The statements [`Self::drop_table`] runs.
+    pub(crate) fn drop_table_sql(&self, ty: &ObjectType) -> Result<Vec<String>> {
+        let mut statements = Self::drop_indexes_sql(ty, ty.indexes())?;
         let drop_table = Table::drop()
             .table(Alias::new(ty.backing_table()))
             .to_owned();
-        let drop_table = drop_table.build_any(DbConnection::get_query_builder(&self.kind));
-        let drop_table = sqlx::query(&drop_table);
-        transaction.execute(drop_table).await?;
+        statements.push(drop_table.build_any(DbConnection::get_query_builder(&self.kind)));
+        Ok(statements)
+    }
 
-        Ok(())
+    pub(crate) async fn drop_table(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        ty: &ObjectType,
+    ) -> Result<()> {
+        self.execute_all(transaction, self.drop_table_sql(ty)?)
+            .await
     }
 
     pub(crate) async fn start_transaction_static(self: Arc<Self>) -> Result<TransactionStatic> {
@@ -239,11 +677,9 @@ impl QueryEngine {
         Ok(())
     }
 
-    pub(crate) async fn create_table(
-        &self,
-        transaction: &mut Transaction<'_, Any>,
-        ty: &ObjectType,
-    ) -> Result<()> {
+    /// The statements [`Self::create_table`] runs, besides the ones installing
+    /// the change triggers.
+    pub(crate) fn create_table_sql(&self, ty: &ObjectType) -> Result<Vec<String>> {
         let mut create_table = Table::create()
             .table(Alias::new(ty.backing_table()))
             .if_not_exists()
@@ -253,33 +689,334 @@ impl QueryEngine {
             let mut column_def = ColumnDef::try_from(field)?;
             create_table.col(&mut column_def);
         }
-        let create_table = create_table.build_any(DbConnection::get_query_builder(&self.kind));
-
-        let create_table = sqlx::query(&create_table);
-        transaction.execute(create_table).await?;
+        create_table.col(&mut version_column_def());
+        let mut create_table = create_table.build_any(DbConnection::get_query_builder(&self.kind));
+        if !ty.check_constraints().is_empty() {
+            // Added to the statement sea-query builds, as it has no CHECK clauses.
+            let checks = ty.check_constraints().iter().map(check_constraint_sql);
+            let columns = create_table
+                .strip_suffix(')')
+                .context("CREATE TABLE statement without columns")?;
+            create_table = format!("{}, {})", columns, checks.join(", "));
+        }
+        let mut statements = vec![create_table];
+        statements.extend(Self::create_indexes_sql(ty, ty.indexes()));
+        statements.extend(Self::create_unique_constraints_sql(
+            ty,
+            ty.unique_constraints(),
+        ));
+        Ok(statements)
+    }
 
-        Self::create_indexes(transaction, ty, ty.indexes()).await?;
+    pub(crate) async fn create_table(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        ty: &ObjectType,
+    ) -> Result<()> {
+        ensure_index_names(ty.indexes())?;
+        self.execute_all(transaction, self.create_table_sql(ty)?)
+            .await?;
+        self.install_change_triggers(transaction, ty).await?;
         Ok(())
     }
 
-    pub(crate) async fn alter_table(
+    /// Adds the version column to the backing table of `ty` if it was
+    /// created before rows were versioned.
+    pub(crate) async fn ensure_version_column(&self, ty: &ObjectType) -> Result<()> {
+        // Probing outside of a transaction, as a failed statement aborts PostgreSQL ones.
+        let probe = format!(
+            "SELECT \"{}\" FROM \"{}\" LIMIT 1",
+            VERSION_FIELD_NAME,
+            ty.backing_table()
+        );
+        if sqlx::query(&probe).fetch_all(&self.pool).await.is_ok() {
+            return Ok(());
+        }
+        // Faking Postgres like alter_table() does, a constant default is fine on SQLite.
+        let alter = Table::alter()
+            .table(Alias::new(ty.backing_table()))
+            .add_column(&mut version_column_def())
+            .to_owned()
+            .build_any(DbConnection::get_query_builder(&Kind::Postgres));
+        let mut transaction = self.start_transaction().await?;
+        self.execute_all(&mut transaction, vec![alter]).await?;
+        QueryEngine::commit_transaction(transaction).await
+    }
+
+    /// Makes changes to the rows of `ty` visible to [`Self::watch`] on
+    /// PostgreSQL. On SQLite, where every change costs a write to the change
+    /// log, only the tables being watched get triggers, on their first watch.
+    ///
+    /// This is idempotent, so it is also used to upgrade tables created before
+    /// changes were tracked.
+    pub(crate) async fn install_change_triggers(
         &self,
         transaction: &mut Transaction<'_, Any>,
         ty: &ObjectType,
-        delta: ObjectDelta,
     ) -> Result<()> {
-        self.drop_indexes(transaction, ty, &delta.removed_indexes)
+        if matches!(self.kind, Kind::Sqlite) {
+            return Ok(());
+        }
+        self.install_table_change_triggers(transaction, ty.backing_table())
+            .await
+    }
+
+    async fn install_table_change_triggers(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+    ) -> Result<()> {
+        let statements = watch::change_feed_sql(self.kind)
+            .into_iter()
+            .chain(watch::change_trigger_sql(self.kind, table));
+        self.set_timed(transaction, false).await?;
+        for sql in statements {
+            transaction.execute(sql.as_str()).await?;
+        }
+        self.set_timed(transaction, true).await
+    }
+
+    /// Migrates the table of `ty`, and those of the types it nests, the way
+    /// startup does without `--lazy-migrations`: adds their version column and
+    /// their change triggers. Does nothing unless migrations are lazy. Each
+    /// table is migrated on the first query that needs it, and recorded in
+    /// [`migrations::MIGRATIONS_TABLE`] so that it isn't again after a
+    /// restart. Returns how many tables were migrated.
+    ///
+    /// This migrates in a transaction of its own, see [`Self::migrate_lazily_in`]
+    /// for queries that run in one.
+    pub(crate) async fn migrate_lazily(&self, ty: &ObjectType) -> Result<usize> {
+        if !self.lazy_migrations {
+            return Ok(0);
+        }
+        let mut transaction = self.start_transaction().await?;
+        let migrated = self.migrate_tables(ty, &mut transaction).await?;
+        QueryEngine::commit_transaction(transaction).await?;
+        for table in &migrated {
+            migrations::set_type_migrated(&self.conn_uri, table);
+        }
+        Ok(migrated.len())
+    }
+
+    /// Like [`Self::migrate_lazily`], but in `transaction`, that of the query
+    /// needing the tables. A request that wrote already holds locks that a
+    /// migration on another connection would wait on. The tables are only
+    /// remembered as migrated by the first query after `transaction` commits,
+    /// as it may be rolled back.
+    async fn migrate_lazily_in(
+        &self,
+        ty: &ObjectType,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<usize> {
+        if !self.lazy_migrations {
+            return Ok(0);
+        }
+        Ok(self.migrate_tables(ty, transaction).await?.len())
+    }
+
+    /// The tables [`Self::migrate_lazily`] migrates, migrated in `transaction`.
+    async fn migrate_tables<'a>(
+        &self,
+        ty: &'a ObjectType,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<Vec<&'a str>> {
+        let mut migrated = vec![];
+        let mut pending = vec![ty];
+        let mut seen = HashSet::new();
+        while let Some(ty) = pending.pop() {
+            let table = ty.backing_table();
+            if !seen.insert(table) || migrations::is_type_migrated(&self.conn_uri, table) {
+                continue;
+            }
+            for field in ty.user_fields() {
+                if let Type::Object(nested) = &field.type_ {
+                    if !nested.is_auth() {
+                        pending.push(nested);
+                    }
+                }
+            }
+            if self.migrate_type(ty, transaction).await? {
+                migrated.push(table);
+            }
+        }
+        Ok(migrated)
+    }
+
+    /// Migrates the table of `ty` in `transaction`, unless it was already or
+    /// doesn't exist yet. Returns whether it did.
+    async fn migrate_type(
+        &self,
+        ty: &ObjectType,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<bool> {
+        let table = ty.backing_table();
+        let id = migrations::type_migration_id(table);
+        let recorded = format!(
+            "SELECT id FROM {} WHERE id = $1",
+            migrations::MIGRATIONS_TABLE
+        );
+        // Only a committed record is remembered, which the pool reads.
+        let committed = self
+            .fetch_all(SqlWithArguments {
+                sql: recorded.clone(),
+                args: vec![SqlValue::String(id.clone())],
+            })
             .await?;
+        if !committed.is_empty() {
+            migrations::set_type_migrated(&self.conn_uri, table);
+            return Ok(false);
+        }
+        // An earlier query of `transaction` may have migrated it.
+        let recorded = sqlx::query(&recorded)
+            .bind(id.clone())
+            .fetch_optional(&mut *transaction)
+            .await?;
+        if recorded.is_some() || !self.table_exists(transaction, table).await? {
+            return Ok(false);
+        }
+        if !self
+            .column_exists(transaction, table, VERSION_FIELD_NAME)
+            .await?
+        {
+            let alter = Table::alter()
+                .table(Alias::new(table))
+                .add_column(&mut version_column_def())
+                .to_owned()
+                .build_any(DbConnection::get_query_builder(&Kind::Postgres));
+            self.execute_all(transaction, vec![alter]).await?;
+        }
+        self.install_change_triggers(transaction, ty).await?;
+        // Another executor thread may have migrated it meanwhile.
+        let record = SqlWithArguments {
+            sql: format!(
+                "INSERT INTO {0} (id, applied_at) SELECT $1, $2 \
+                 WHERE NOT EXISTS (SELECT 1 FROM {0} WHERE id = $3)",
+                migrations::MIGRATIONS_TABLE
+            ),
+            args: vec![
+                SqlValue::String(id.clone()),
+                SqlValue::String(chrono::Utc::now().to_rfc3339()),
+                SqlValue::String(id),
+            ],
+        };
+        self.execute_prepared(transaction, &record).await??;
+        info!(
+            "Migrated the table of type {} on its first query",
+            ty.name()
+        );
+        Ok(true)
+    }
 
-        // using a macro as async closures are unstable
-        macro_rules! do_query {
-            ( $table:expr ) => {{
-                let table = $table.build_any(DbConnection::get_query_builder(&Kind::Postgres));
-                let table = sqlx::query(&table);
+    async fn table_exists(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+    ) -> Result<bool> {
+        let (sql, name) = match self.kind {
+            Kind::Postgres => (
+                "SELECT 1 WHERE to_regclass($1) IS NOT NULL",
+                format!("\"{}\"", table),
+            ),
+            Kind::Sqlite => (
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = $1",
+                table.to_owned(),
+            ),
+        };
+        let row = sqlx::query(sql)
+            .bind(name)
+            .fetch_optional(&mut *transaction)
+            .await?;
+        Ok(row.is_some())
+    }
 
-                transaction.execute(table).await
-            }};
+    /// Whether `table` has `column`. Unlike [`Self::ensure_version_column`],
+    /// this doesn't probe the column, which would abort a PostgreSQL
+    /// transaction when it is missing.
+    async fn column_exists(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+        column: &str,
+    ) -> Result<bool> {
+        let (sql, table) = match self.kind {
+            Kind::Postgres => (
+                "SELECT 1 FROM pg_attribute WHERE attrelid = to_regclass($1) \
+                 AND attname = $2 AND NOT attisdropped",
+                format!("\"{}\"", table),
+            ),
+            Kind::Sqlite => (
+                "SELECT 1 FROM pragma_table_info($1) WHERE name = $2",
+                table.to_owned(),
+            ),
+        };
+        let row = sqlx::query(sql)
+            .bind(table)
+            .bind(column.to_owned())
+            .fetch_optional(&mut *transaction)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Streams the changes to the rows of `table` made after this call.
+    ///
+    /// PostgreSQL pushes the changes as they are committed, while SQLite is polled.
+    pub(crate) async fn watch(&self, table: &str) -> Result<ChangeStream> {
+        let table = table.to_owned();
+        match self.kind {
+            Kind::Postgres => {
+                let mut listener = self.pg_listener.lock().await;
+                if listener.is_none() {
+                    *listener = Some(Arc::new(PgListenManager::connect(&self.conn_uri).await?));
+                }
+                let changes = listener.as_ref().unwrap().subscribe();
+                let changes = changes.filter(move |e| futures::future::ready(e.table == table));
+                Ok(Box::pin(changes.map(Ok)))
+            }
+            Kind::Sqlite => {
+                let mut transaction = self.start_transaction().await?;
+                self.install_table_change_triggers(&mut transaction, &table)
+                    .await?;
+                QueryEngine::commit_transaction(transaction).await?;
+                watch::poll_change_log(self.pool.clone(), table).await
+            }
         }
+    }
+
+    /// Reports the changes to the rows of `ty`, which must have soft deletes,
+    /// along with the rows before and after them. The table is polled every
+    /// `interval`, see [`watch::poll_table`].
+    pub(crate) async fn watch_table(
+        self: Arc<Self>,
+        ty: Arc<ObjectType>,
+        interval: Duration,
+    ) -> Result<broadcast::Receiver<ChangeEvent>> {
+        watch::poll_table(self, ty, interval).await
+    }
+
+    /// The statements [`Self::alter_table`] runs, ending with the creation of
+    /// `indexes` where they are missing.
+    ///
+    /// SQLite can't change the check constraints of a table once it's created,
+    /// so that errors out there.
+    pub(crate) fn alter_table_sql(
+        &self,
+        ty: &ObjectType,
+        delta: &ObjectDelta,
+        indexes: &[DbIndex],
+    ) -> Result<Vec<String>> {
+        let checks_changed = !delta.added_check_constraints.is_empty()
+            || !delta.removed_check_constraints.is_empty();
+        anyhow::ensure!(
+            !checks_changed || matches!(self.kind, Kind::Postgres),
+            "the check constraints of {} can't be changed on SQLite once it is created",
+            ty.name()
+        );
+        let mut statements = Self::drop_indexes_sql(ty, &delta.removed_indexes)?;
+        statements.extend(Self::drop_unique_constraints_sql(
+            ty,
+            &delta.removed_unique_constraints,
+        ));
+        let query_builder = DbConnection::get_query_builder(&Kind::Postgres);
 
         // SQLite doesn't support multiple add column statements
         // (details at https://github.com/SeaQL/sea-query/issues/213), generate a separate alter
@@ -306,7 +1043,7 @@ impl QueryEngine {
                 .add_column(&mut column_def)
                 .to_owned();
 
-            do_query!(table)?;
+            statements.push(table.build_any(query_builder));
         }
 
         for field in delta.removed_fields.iter() {
@@ -315,7 +1052,7 @@ impl QueryEngine {
                 .drop_column(Alias::new(&field.name))
                 .to_owned();
 
-            do_query!(table)?;
+            statements.push(table.build_any(query_builder));
         }
         // We don't loop over the modified part of the delta: SQLite doesn't support modify columns
         // at all, but that is fine since the currently supported field modifications are handled
@@ -325,54 +1062,112 @@ impl QueryEngine {
         // since we always write with defaults. For all others, we should error out way before we
         // get here.
 
-        Self::create_indexes(transaction, ty, ty.indexes()).await?;
-
-        Ok(())
+        statements.extend(Self::create_indexes_sql(ty, indexes));
+        statements.extend(Self::create_unique_constraints_sql(
+            ty,
+            &delta.added_unique_constraints,
+        ));
+        for constraint in &delta.removed_check_constraints {
+            statements.push(format!(
+                "ALTER TABLE \"{}\" DROP CONSTRAINT IF EXISTS \"{}\"",
+                ty.backing_table(),
+                constraint.name
+            ));
+        }
+        for constraint in &delta.added_check_constraints {
+            statements.push(format!(
+                "ALTER TABLE \"{}\" ADD {}",
+                ty.backing_table(),
+                check_constraint_sql(constraint)
+            ));
+        }
+        Ok(statements)
     }
 
-    pub(crate) async fn create_indexes(
+    pub(crate) async fn alter_table(
+        &self,
         transaction: &mut Transaction<'_, Any>,
         ty: &ObjectType,
-        indexes: &[DbIndex],
+        delta: ObjectDelta,
     ) -> Result<()> {
-        for index in indexes {
-            let idx_name = index
-                .name()
-                .context("index must have a name at a time of table creation")?;
-            let columns = index.fields.iter().join(", ");
-            let create_index = format!(
-                r#"
+        ensure_index_names(ty.indexes())?;
+        let statements = self.alter_table_sql(ty, &delta, ty.indexes())?;
+        self.execute_all(transaction, statements).await
+    }
+
+    /// The statements creating `indexes` on the backing table of `ty`. Indexes
+    /// yet to be given an id get a placeholder name, see [`DbIndex::planned_name`].
+    fn create_indexes_sql(ty: &ObjectType, indexes: &[DbIndex]) -> Vec<String> {
+        indexes
+            .iter()
+            .map(|index| {
+                let idx_name = index
+                    .name()
+                    .unwrap_or_else(|| index.planned_name(ty.backing_table()));
+                let columns = index.fields.iter().join(", ");
+                format!(
+                    r#"
                 CREATE INDEX IF NOT EXISTS "{idx_name}" ON "{}" ({columns});
             "#,
-                ty.backing_table()
-            );
-            let create_index = sqlx::query(&create_index);
-            transaction.execute(create_index).await?;
-        }
-        Ok(())
+                    ty.backing_table()
+                )
+            })
+            .collect()
     }
 
-    pub(crate) async fn drop_indexes(
-        &self,
-        transaction: &mut Transaction<'_, Any>,
+    /// Unique constraints are kept as unique indexes rather than as `UNIQUE`
+    /// table constraints, as SQLite can't add those to an existing table.
+    fn create_unique_constraints_sql(
         ty: &ObjectType,
-        indexes: &[DbIndex],
-    ) -> Result<()> {
-        for removed_idx in indexes {
-            let drop_idx = Index::drop()
-                .name(
-                    &removed_idx
-                        .name()
-                        .context("index must have a name when dropped")?,
+        constraints: &[UniqueConstraint],
+    ) -> Vec<String> {
+        constraints
+            .iter()
+            .map(|constraint| {
+                let columns = constraint
+                    .fields
+                    .iter()
+                    .map(|f| format!("\"{f}\""))
+                    .join(", ");
+                format!(
+                    "CREATE UNIQUE INDEX IF NOT EXISTS \"{}\" ON \"{}\" ({columns})",
+                    constraint.index_name(ty.backing_table()),
+                    ty.backing_table()
                 )
-                .table(Alias::new(ty.backing_table()))
-                .to_owned();
+            })
+            .collect()
+    }
 
-            let drop_idx = drop_idx.build_any(DbConnection::get_query_builder(&Kind::Postgres));
-            let drop_idx = sqlx::query(&drop_idx);
-            transaction.execute(drop_idx).await?;
-        }
-        Ok(())
+    fn drop_unique_constraints_sql(
+        ty: &ObjectType,
+        constraints: &[UniqueConstraint],
+    ) -> Vec<String> {
+        constraints
+            .iter()
+            .map(|constraint| {
+                format!(
+                    "DROP INDEX IF EXISTS \"{}\"",
+                    constraint.index_name(ty.backing_table())
+                )
+            })
+            .collect()
+    }
+
+    fn drop_indexes_sql(ty: &ObjectType, indexes: &[DbIndex]) -> Result<Vec<String>> {
+        indexes
+            .iter()
+            .map(|removed_idx| {
+                let drop_idx = Index::drop()
+                    .name(
+                        &removed_idx
+                            .name()
+                            .context("index must have a name when dropped")?,
+                    )
+                    .table(Alias::new(ty.backing_table()))
+                    .to_owned();
+                Ok(drop_idx.build_any(DbConnection::get_query_builder(&Kind::Postgres)))
+            })
+            .collect()
     }
 
     fn row_to_json(db_kind: Kind, entity: &QueriedEntity, row: &AnyRow) -> Result<ResultRow> {
@@ -384,8 +1179,8 @@ impl QueryEngine {
                     type_,
                     column_idx,
                     is_optional,
+                    is_encrypted,
                     transform,
-                    ..
                 } => {
                     if *is_optional && column_is_null(row, *column_idx) {
                         continue;
@@ -403,8 +1198,16 @@ impl QueryEngine {
                             let val: f64 = row.get_unchecked(column_idx);
                             json!(val)
                         }
-                        Type::String => to_json!(&str),
+                        Type::String if *is_encrypted => {
+                            json!(encryption::decrypt(row.get::<&str, _>(column_idx))?)
+                        }
+                        Type::String | Type::Enum(_) => to_json!(&str),
                         Type::Id => to_json!(&str),
+                        Type::Blob => json!(blob_url(row.get::<&str, _>(column_idx))),
+                        Type::Array(_) | Type::Map(_) => {
+                            serde_json::from_str(row.get::<&str, _>(column_idx))
+                                .context("column doesn't hold JSON")?
+                        }
                         Type::Boolean => {
                             // Similarly to the float issue, type information is not filled in
                             // *if* this value was put in as a result of coalesce() (default).
@@ -469,14 +1272,326 @@ impl QueryEngine {
         tr: TransactionStatic,
         query_plan: QueryPlan,
     ) -> anyhow::Result<QueryResults> {
+        let region = query_plan.region().map(str::to_owned);
+        let base_type = query_plan.base_type().clone();
         let query = query_plan.build_query(&self.target_db())?;
         let allowed_fields = query.allowed_fields;
         let db_kind = self.kind;
 
-        let stream = new_query_results(query.raw_sql, tr);
-        let stream = stream.map(move |row| Self::row_to_json(db_kind, &query.entity, &row?));
-        let stream = Box::pin(stream.map(move |o| Self::project(o, &allowed_fields)));
-        Ok(stream)
+        let timer = metrics::time_query(query.entity.ty().backing_table(), Operation::Select);
+
+        let migration_tr = tr.clone();
+        let stream = self.timed_results(query.raw_sql, tr, region.as_deref());
+        let stream = stream.map(move |row| {
+            // The query is counted when its rows are dropped.
+            let _timer = &timer;
+            Self::row_to_json(db_kind, &query.entity, &row?)
+        });
+        let stream = stream.map(move |o| Self::project(o, &allowed_fields));
+        // The tables are migrated before the query runs, when it is first polled.
+        let engine = self.clone();
+        let migration = async move {
+            let mut transaction = migration_tr.lock_arc().await;
+            engine.migrate_lazily_in(&base_type, &mut transaction).await
+        };
+        let migration = futures::stream::once(migration)
+            .filter_map(|migrated| futures::future::ready(migrated.err().map(Err)));
+        Ok(Box::pin(migration.chain(stream)))
+    }
+
+    /// Runs `query_plan` for each of `parent_ids` through the subquery of a
+    /// virtual relation, and returns the results by parent id. The queries of
+    /// many parents are run as one statement rather than one by one.
+    pub(crate) async fn query_related(
+        &self,
+        tr: TransactionStatic,
+        query_plan: &QueryPlan,
+        subquery: &str,
+        parent_ids: &[String],
+    ) -> Result<HashMap<String, Vec<JsonObject>>> {
+        let _timer = metrics::time_query(query_plan.base_type().backing_table(), Operation::Select);
+        self.migrate_lazily_in(query_plan.base_type(), &mut *tr.lock().await)
+            .await?;
+        // SQLite allows at most 500 SELECTs in a compound one.
+        const PARENTS_PER_STATEMENT: usize = 100;
+        const PARENT_COLUMN: &str = "__parent_id";
+
+        let mut related: HashMap<String, Vec<JsonObject>> = HashMap::new();
+        for parent_ids in parent_ids.chunks(PARENTS_PER_STATEMENT) {
+            let queries = parent_ids
+                .iter()
+                .map(|id| query_plan.build_related_query(&self.target_db(), subquery, id))
+                .collect::<Result<Vec<_>>>()?;
+            // The parent id goes last, so that the columns of the plan keep
+            // their indexes.
+            let selects = parent_ids.iter().zip(&queries).map(|(id, query)| {
+                format!(
+                    "SELECT *, {} AS \"{}\" FROM ({}) AS related",
+                    escape_string(id),
+                    PARENT_COLUMN,
+                    query.raw_sql
+                )
+            });
+            // The queries only differ in their subquery, so their rows map to
+            // JSON alike.
+            let query = &queries[0];
+            let rows = self
+                .timed_results(selects.join(" UNION ALL "), tr.clone(), query_plan.region())
+                .collect::<Vec<_>>()
+                .await;
+            for row in rows {
+                let row = row.context("failed to run the subquery of a relation")?;
+                let parent_id: String = row.try_get(PARENT_COLUMN)?;
+                let value = Self::row_to_json(self.kind, &query.entity, &row);
+                let value = Self::project(value, &query.allowed_fields)?;
+                related.entry(parent_id).or_default().push(value);
+            }
+        }
+        Ok(related)
+    }
+
+    /// Counts the rows the given `query_plan` would return.
+    pub(crate) async fn count(&self, tr: TransactionStatic, query_plan: QueryPlan) -> Result<u64> {
+        let _timer = metrics::time_query(query_plan.base_type().backing_table(), Operation::Select);
+        let mut transaction = tr.lock().await;
+        self.migrate_lazily_in(query_plan.base_type(), &mut transaction)
+            .await?;
+        let query = query_plan.build_query(&self.target_db())?;
+        let sql = format!("SELECT COUNT(*) FROM ({}) AS counted", query.raw_sql);
+        let row = self
+            .timed(
+                &sql,
+                sqlx::query(&sql)
+                    .persistent(false)
+                    .fetch_one(&mut *transaction),
+            )
+            .await??;
+        Ok(row.try_get::<i64, _>(0)? as u64)
+    }
+
+    /// Estimates the number of rows of `ty` from the statistics the database
+    /// keeps, which is fast and doesn't lock the table like counting does.
+    ///
+    /// The estimate may be stale, or plain wrong: PostgreSQL refreshes
+    /// `pg_class.reltuples` on `VACUUM`, `ANALYZE` and some DDL, and SQLite
+    /// only fills `sqlite_stat1` on `ANALYZE`. Tables without statistics
+    /// are estimated at 0 rows.
+    pub(crate) async fn estimated_row_count(&self, ty: &ObjectType) -> Result<u64> {
+        let mut transaction = self.start_transaction().await?;
+        let count = self.estimate_rows(ty, &mut transaction).await?;
+        QueryEngine::commit_transaction(transaction).await?;
+        Ok(count)
+    }
+
+    /// Counts the rows `query_plan` would return like [`Self::count`] does,
+    /// unless the plan takes a whole table that has more than
+    /// [`EXACT_COUNT_LIMIT`] rows by [`Self::estimated_row_count`]. Then that
+    /// estimate is used instead. Returns the count and whether it's estimated.
+    pub(crate) async fn count_or_estimate(
+        &self,
+        tr: TransactionStatic,
+        query_plan: QueryPlan,
+    ) -> Result<(u64, bool)> {
+        if query_plan.is_unfiltered() {
+            let mut transaction = tr.lock().await;
+            let estimate = self
+                .estimate_rows(query_plan.base_type(), &mut transaction)
+                .await?;
+            if estimate > EXACT_COUNT_LIMIT {
+                return Ok((estimate, true));
+            }
+        }
+        Ok((self.count(tr, query_plan).await?, false))
+    }
+
+    async fn estimate_rows(
+        &self,
+        ty: &ObjectType,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<u64> {
+        let table = ty.backing_table();
+        let (sql, table) = match self.kind {
+            Kind::Postgres => (
+                "SELECT GREATEST(reltuples, 0)::bigint FROM pg_class WHERE oid = to_regclass($1)",
+                format!("\"{}\"", table),
+            ),
+            Kind::Sqlite => {
+                let probe = "SELECT 1 FROM sqlite_master WHERE name = 'sqlite_stat1'";
+                let stats = sqlx::query(probe).fetch_optional(&mut *transaction);
+                if stats.await?.is_none() {
+                    return Ok(0);
+                }
+                // The first number of the stat of a table is its number of rows.
+                (
+                    "SELECT CAST(stat AS INTEGER) FROM sqlite_stat1 WHERE tbl = $1 LIMIT 1",
+                    table.to_owned(),
+                )
+            }
+        };
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Select);
+        let query = sqlx::query(sql)
+            .bind(table)
+            .fetch_optional(&mut *transaction);
+        let count = match self.timed(sql, query).await?? {
+            Some(row) => row.try_get::<i64, _>(0)?,
+            None => 0,
+        };
+        Ok(count.max(0) as u64)
+    }
+
+    /// The [`TableStats`] of the backing table of `ty`.
+    pub(crate) async fn table_stats(&self, ty: &ObjectType) -> Result<TableStats> {
+        let table = ty.backing_table();
+        let estimated_row_count = self.estimated_row_count(ty).await?;
+        let (size_bytes, last_vacuum) = match self.kind {
+            Kind::Postgres => {
+                let sql = "SELECT pg_total_relation_size(c.oid), \
+                    GREATEST(s.last_vacuum, s.last_autovacuum)::text \
+                    FROM pg_class c LEFT JOIN pg_stat_user_tables s ON s.relid = c.oid \
+                    WHERE c.oid = to_regclass($1)";
+                let row = sqlx::query(sql)
+                    .bind(format!("\"{}\"", table))
+                    .fetch_optional(&self.pool)
+                    .await?;
+                match row {
+                    Some(row) => (
+                        Some(row.try_get::<i64, _>(0)? as u64),
+                        row.try_get::<Option<String>, _>(1)?,
+                    ),
+                    None => (None, None),
+                }
+            }
+            Kind::Sqlite => {
+                // dbstat is only there if SQLite was built with it.
+                let size = sqlx::query("SELECT SUM(pgsize) FROM dbstat WHERE name = $1")
+                    .bind(table)
+                    .fetch_one(&self.pool)
+                    .await
+                    .ok()
+                    .and_then(|row| row.try_get::<Option<i64>, _>(0).ok().flatten());
+                (size.map(|size| size as u64), None)
+            }
+        };
+        Ok(TableStats {
+            estimated_row_count,
+            size_bytes,
+            last_vacuum,
+        })
+    }
+
+    /// Searches the words of `query` in the string `fields` of `ty` and
+    /// returns the `limit` best matches, ranked by relevance with
+    /// `ts_rank_cd` on PostgreSQL and FTS5's `bm25()` on SQLite. Both split
+    /// the text into words the same way, without stemming. If `ty` is
+    /// multi-tenant and `tenant_id` is set, only the rows of that tenant match.
+    ///
+    /// There is no full-text index, so a search reads the whole table. On
+    /// SQLite, it copies it into a temporary FTS5 table for the duration of
+    /// the search.
+    pub(crate) async fn search(
+        &self,
+        ty: &ObjectType,
+        fields: &[&Field],
+        query: &str,
+        limit: u64,
+        tenant_id: Option<&str>,
+    ) -> Result<SearchResults> {
+        anyhow::ensure!(!fields.is_empty(), "a search needs fields to search in");
+        anyhow::ensure!(!query.trim().is_empty(), "a search needs words to search");
+        let table = ty.backing_table();
+        let columns = fields.iter().map(|f| format!("\"{}\"", f.name)).join(", ");
+        let tenant_id = match ty.is_multi_tenant() {
+            true => tenant_id,
+            false => None,
+        };
+        // The tenant, if any, is bound to $2.
+        let tenant_column = match tenant_id {
+            Some(_) => Some(format!("\"{}\"", ty.tenant_id_field()?.name)),
+            None => None,
+        };
+        let same_tenant = match &tenant_column {
+            Some(column) => format!(" AND {} = $2", column),
+            None => String::new(),
+        };
+        let (setup, hits_sql, count_sql, cleanup, query) = match self.kind {
+            Kind::Postgres => {
+                let document = format!("to_tsvector('simple', concat_ws(' ', {}))", columns);
+                let matches = format!(
+                    "{} @@ plainto_tsquery('simple', $1){}",
+                    document, same_tenant
+                );
+                let hits = format!(
+                    "SELECT \"id\", ts_rank_cd({}, plainto_tsquery('simple', $1))::float8 AS score \
+                    FROM \"{}\" WHERE {} ORDER BY score DESC, \"id\" LIMIT {}",
+                    document, table, matches, limit
+                );
+                let count = format!("SELECT COUNT(*) FROM \"{}\" WHERE {}", table, matches);
+                (vec![], hits, count, vec![], query.to_owned())
+            }
+            Kind::Sqlite => {
+                // Numbered, since FTS5 reserves some column names, like `rank`.
+                let search_columns = (0..fields.len()).map(|i| format!("c{}", i)).join(", ");
+                // The tenant is copied along, under the name of its column.
+                let (tenant_definition, tenant_select) = match &tenant_column {
+                    Some(column) => (format!(", {} UNINDEXED", column), format!(", {}", column)),
+                    None => (String::new(), String::new()),
+                };
+                let setup = vec![
+                    format!(
+                        "CREATE VIRTUAL TABLE temp.{} USING fts5(\"id\" UNINDEXED{}, {})",
+                        SEARCH_TABLE, tenant_definition, search_columns
+                    ),
+                    format!(
+                        "INSERT INTO temp.{} SELECT \"id\"{}, {} FROM \"{}\"",
+                        SEARCH_TABLE, tenant_select, columns, table
+                    ),
+                ];
+                let hits = format!(
+                    "SELECT \"id\", -bm25({0}) AS score FROM temp.{0} WHERE {0} MATCH $1{2} \
+                    ORDER BY score DESC, \"id\" LIMIT {1}",
+                    SEARCH_TABLE, limit, same_tenant
+                );
+                let count = format!(
+                    "SELECT COUNT(*) FROM temp.{0} WHERE {0} MATCH $1{1}",
+                    SEARCH_TABLE, same_tenant
+                );
+                let cleanup = vec![format!("DROP TABLE temp.{}", SEARCH_TABLE)];
+                (setup, hits, count, cleanup, fts5_query(query))
+            }
+        };
+
+        let _timer = metrics::time_query(table, Operation::Select);
+        // Dropping the transaction on an early return drops the FTS5 table too.
+        let mut transaction = self.start_transaction().await?;
+        for sql in &setup {
+            let execution = sqlx::query(sql)
+                .persistent(false)
+                .execute(&mut *transaction);
+            self.timed(sql, execution).await??;
+        }
+        let mut hits = sqlx::query(&hits_sql).persistent(false).bind(query.clone());
+        let mut count = sqlx::query(&count_sql).persistent(false).bind(query);
+        if let Some(tenant_id) = tenant_id {
+            hits = hits.bind(tenant_id.to_owned());
+            count = count.bind(tenant_id.to_owned());
+        }
+        let hits = hits.fetch_all(&mut *transaction);
+        let hits = self
+            .timed(&hits_sql, hits)
+            .await??
+            .iter()
+            .map(|row| Ok((row.try_get::<String, _>(0)?, row.try_get::<f64, _>(1)?)))
+            .collect::<Result<_>>()?;
+        let count = count.fetch_one(&mut *transaction);
+        let total_hits = self.timed(&count_sql, count).await??.try_get::<i64, _>(0)? as u64;
+        for sql in &cleanup {
+            let execution = sqlx::query(sql)
+                .persistent(false)
+                .execute(&mut *transaction);
+            self.timed(sql, execution).await??;
+        }
+        QueryEngine::commit_transaction(transaction).await?;
+        Ok(SearchResults { hits, total_hits })
     }
 
     /// Execute the given `mutation`.
@@ -496,9 +1611,12 @@ impl QueryEngine {
         mutation: Mutation,
         transaction: &mut Transaction<'_, Any>,
     ) -> Result<()> {
+        let _timer = metrics::time_query(mutation.base_type().backing_table(), Operation::Delete);
+        self.migrate_lazily_in(mutation.base_type(), transaction)
+            .await?;
         let raw_sql = mutation.build_sql(self.target_db())?;
-        let query = sqlx::query(&raw_sql);
-        transaction.execute(query).await?;
+        let query = sqlx::query(&raw_sql).persistent(false);
+        self.timed(&raw_sql, transaction.execute(query)).await??;
 
         Ok(())
     }
@@ -518,8 +1636,52 @@ impl QueryEngine {
         ty_value: &JsonObject,
         transaction: Option<&mut Transaction<'_, Any>>,
     ) -> Result<IdTree> {
-        let (inserts, id_tree) = self.prepare_insertion(ty, ty_value)?;
-        self.run_sql_queries(&inserts, transaction).await?;
+        self.update_row(ty, ty_value, None, transaction).await
+    }
+
+    /// Same as [`Self::add_row`], but if `expected_version` is given, a row that
+    /// already exists is only overwritten while its version is still that one.
+    /// Fails with [`QueryError::VersionConflict`] otherwise, and with
+    /// [`QueryError::UniqueViolation`] or [`QueryError::CheckConstraintViolation`]
+    /// if the row breaks a constraint.
+    pub(crate) async fn update_row(
+        &self,
+        ty: &ObjectType,
+        ty_value: &JsonObject,
+        expected_version: Option<u64>,
+        transaction: Option<&mut Transaction<'_, Any>>,
+    ) -> Result<IdTree> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Insert);
+        self.seed_id_sequences(ty).await?;
+        let (inserts, id_tree) = self.prepare_insertion(ty, ty_value, expected_version)?;
+        let mut own_transaction = None;
+        let transaction = match transaction {
+            Some(transaction) => {
+                self.migrate_lazily_in(ty, transaction).await?;
+                transaction
+            }
+            None => {
+                self.migrate_lazily(ty).await?;
+                own_transaction.insert(self.start_transaction().await?)
+            }
+        };
+        let mut rows_affected = 0;
+        for q in &inserts {
+            rows_affected = self
+                .execute_prepared(transaction, q)
+                .await?
+                .map_err(|e| constraint_violation(ty, e))?
+                .rows_affected();
+        }
+        // The row itself is inserted last, after the nested ones.
+        if expected_version.is_some() && rows_affected == 0 {
+            return Err(
+                QueryError::VersionConflict(ty.name().to_owned(), id_tree.id.clone()).into(),
+            );
+        }
+        if let Some(transaction) = own_transaction {
+            QueryEngine::commit_transaction(transaction).await?;
+        }
         Ok(id_tree)
     }
 
@@ -528,13 +1690,586 @@ impl QueryEngine {
         ty: &ObjectType,
         ty_value: &JsonObject,
     ) -> Result<()> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Insert);
+        self.seed_id_sequences(ty).await?;
         let query = self.prepare_insertion_shallow(ty, ty_value)?;
         self.run_sql_queries(&[query], None).await?;
         Ok(())
     }
 
     pub(crate) async fn fetch_one(&self, q: SqlWithArguments) -> Result<AnyRow> {
-        Ok(q.get_sqlx().fetch_one(&self.pool).await?)
+        let mut conn = self.pool.acquire().await?;
+        let execution = q.get_sqlx().fetch_one(&mut *conn);
+        Ok(self.timed(&q.sql, execution).await??)
+    }
+
+    pub(crate) async fn fetch_all(&self, q: SqlWithArguments) -> Result<Vec<AnyRow>> {
+        let mut conn = self.pool.acquire().await?;
+        let execution = q.get_sqlx().fetch_all(&mut *conn);
+        Ok(self.timed(&q.sql, execution).await??)
+    }
+
+    /// Executes `q` in `transaction`, preparing its statement only the first
+    /// time it runs on the connection. The result is that of [`Self::timed`].
+    async fn execute_prepared(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        q: &SqlWithArguments,
+    ) -> Result<Result<AnyQueryResult, sqlx::Error>> {
+        self.timed(&q.sql, q.get_sqlx().execute(&mut *transaction))
+            .await
+    }
+
+    /// Runs `queries` in a single transaction and returns the total number of affected rows.
+    pub(crate) async fn execute_transaction(&self, queries: &[SqlWithArguments]) -> Result<u64> {
+        let mut transaction = self.start_transaction().await?;
+        let mut rows_affected = 0;
+        for q in queries {
+            let result = self.execute_prepared(&mut transaction, q);
+            rows_affected += result.await??.rows_affected();
+        }
+        QueryEngine::commit_transaction(transaction).await?;
+        Ok(rows_affected)
+    }
+
+    /// Deletes the row of `ty` with the given `id`, if it is at `expected_version`
+    /// when one is given. Returns whether a row was deleted.
+    pub(crate) async fn delete_row(
+        &self,
+        ty: &ObjectType,
+        id: &str,
+        expected_version: Option<u64>,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<bool> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Delete);
+        self.migrate_lazily_in(ty, transaction).await?;
+        let mut sql = format!("DELETE FROM \"{}\" WHERE \"id\" = $1", ty.backing_table());
+        let mut args = vec![SqlValue::String(id.to_owned())];
+        if let Some(version) = expected_version {
+            sql.push_str(&format!(" AND \"{}\" = $2", VERSION_FIELD_NAME));
+            args.push(version_arg(version));
+        }
+        let query = SqlWithArguments { sql, args };
+        let result = self.execute_prepared(transaction, &query).await??;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The tenant the row `id` of the multi-tenant `ty` belongs to, if there is such a row.
+    pub(crate) async fn row_tenant(
+        &self,
+        ty: &ObjectType,
+        id: &str,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<Option<String>> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Select);
+        let query = SqlWithArguments {
+            sql: format!(
+                "SELECT \"{}\" FROM \"{}\" WHERE \"id\" = $1",
+                ty.tenant_id_field()?.name,
+                ty.backing_table()
+            ),
+            args: vec![SqlValue::String(id.to_owned())],
+        };
+        let row = self
+            .timed(
+                &query.sql,
+                query.get_sqlx().fetch_optional(&mut *transaction),
+            )
+            .await??;
+        Ok(row.map(|row| row.try_get::<String, _>(0)).transpose()?)
+    }
+
+    /// Returns the ids of the rows of `ty` whose `field` refers to the entity `id`.
+    pub(crate) async fn referencing_ids(
+        &self,
+        ty: &ObjectType,
+        field: &str,
+        id: &str,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<Vec<String>> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Select);
+        let query = SqlWithArguments {
+            sql: format!(
+                "SELECT \"id\" FROM \"{}\" WHERE \"{}\" = $1",
+                ty.backing_table(),
+                field
+            ),
+            args: vec![SqlValue::String(id.to_owned())],
+        };
+        let rows = self
+            .timed(&query.sql, query.get_sqlx().fetch_all(&mut *transaction))
+            .await??;
+        rows.iter()
+            .map(|row| Ok(row.try_get::<String, _>(0)?))
+            .collect()
+    }
+
+    /// Sets the fields of the row `id` of `ty` to the values in `patch`, leaving
+    /// the other fields alone. Fields referring to other entities must hold their
+    /// ids. With `expected_version`, the row is only updated if it is at that version.
+    /// Returns whether a row was updated.
+    ///
+    /// Fails with [`QueryError::NotNullable`] if `patch` holds a null for a required field.
+    pub(crate) async fn patch_row(
+        &self,
+        ty: &ObjectType,
+        id: &str,
+        patch: &JsonObject,
+        expected_version: Option<u64>,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<bool> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Update);
+        self.migrate_lazily_in(ty, transaction).await?;
+        let mut updates = vec![];
+        let mut args = vec![SqlValue::String(id.to_owned())];
+        for (name, value) in patch {
+            let field = ty
+                .get_field_by_json_name(name)
+                .with_context(|| format!("field {} not present in {}", name, ty.name()))?;
+            anyhow::ensure!(
+                field.type_ != Type::Id,
+                "cannot change the id of {}",
+                ty.name()
+            );
+            if value.is_null() {
+                if !field.is_optional {
+                    return Err(QueryError::NotNullable(ty.name().to_owned(), name.clone()).into());
+                }
+                // Same as in make_insert_query().
+                updates.push(format!("\"{}\" = NULL", field.name));
+                continue;
+            }
+            let arg = self
+                .convert_to_argument(ty, field, patch)
+                .with_context(|| QueryEngine::incompatible(field, ty))?;
+            args.push(arg);
+            updates.push(format!("\"{}\" = ${}", field.name, args.len()));
+        }
+        updates.push(format!("\"{0}\" = \"{0}\" + 1", VERSION_FIELD_NAME));
+        let mut sql = format!(
+            "UPDATE \"{}\" SET {} WHERE \"id\" = $1",
+            ty.backing_table(),
+            updates.join(",")
+        );
+        if let Some(version) = expected_version {
+            args.push(version_arg(version));
+            sql.push_str(&format!(
+                " AND \"{}\" = ${}",
+                VERSION_FIELD_NAME,
+                args.len()
+            ));
+        }
+        let query = SqlWithArguments { sql, args };
+        let result = self
+            .execute_prepared(transaction, &query)
+            .await?
+            .map_err(|e| constraint_violation(ty, e))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Inserts `ty_value`, or updates the row that has the same values for
+    /// all of `conflict_fields`, which must be covered by a unique constraint.
+    /// Returns the id of the inserted or updated row.
+    ///
+    /// If `ty` is multi-tenant and `tenant_id` is set, the row is inserted for that tenant, and
+    /// a conflicting row of another tenant is left alone, failing with [`QueryError::OtherTenant`].
+    ///
+    /// The row is inserted shallowly, so fields referring to other entities must hold their ids.
+    pub(crate) async fn upsert_row(
+        &self,
+        ty: &ObjectType,
+        ty_value: &JsonObject,
+        conflict_fields: &[String],
+        tenant_id: Option<&str>,
+    ) -> Result<String> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Insert);
+        anyhow::ensure!(
+            !conflict_fields.is_empty(),
+            "upsert into {} needs at least one conflict field",
+            ty.name()
+        );
+        for name in ty_value.keys() {
+            anyhow::ensure!(
+                ty.get_field_by_json_name(name).is_some(),
+                "field {} not present in {}",
+                name,
+                ty.name()
+            );
+        }
+        self.migrate_lazily(ty).await?;
+        self.seed_id_sequences(ty).await?;
+        let mut conflict_columns = vec![];
+        for name in conflict_fields {
+            match ty.lookup_field(name)? {
+                Some(field) => conflict_columns.push(format!("\"{}\"", field.name)),
+                None => anyhow::bail!("field {} not present in {}", name, ty.name()),
+            }
+        }
+
+        let mut ty_value = ty_value.clone();
+        let mut same_tenant = String::new();
+        if let (true, Some(tenant_id)) = (ty.is_multi_tenant(), tenant_id) {
+            let field = ty.tenant_id_field()?;
+            ty_value.insert(field.json_name().to_owned(), tenant_id.into());
+            same_tenant = format!(
+                " WHERE \"{0}\".\"{1}\" = excluded.\"{1}\"",
+                ty.backing_table(),
+                field.name
+            );
+        }
+
+        let (columns, binds, args) = self.shallow_insertion_values(ty, &ty_value)?;
+        let table = ty.backing_table();
+        let updates = columns
+            .iter()
+            .filter(|c| c.as_str() != "\"id\"")
+            .map(|c| format!("{0} = excluded.{0}", c))
+            .chain(std::iter::once(format!(
+                "\"{0}\" = \"{1}\".\"{0}\" + 1",
+                VERSION_FIELD_NAME, table
+            )))
+            .join(",");
+        let sql = format!(
+            "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {}{} RETURNING \"id\"",
+            table,
+            columns.join(","),
+            binds.join(","),
+            conflict_columns.join(","),
+            updates,
+            same_tenant,
+        );
+        // A conflict with a row of another tenant updates nothing, so returns nothing.
+        let rows = self.fetch_all(SqlWithArguments { sql, args }).await?;
+        match rows.first() {
+            Some(row) => Ok(row.get("id")),
+            None => Err(QueryError::OtherTenant(ty.name().to_owned()).into()),
+        }
+    }
+
+    /// Returns the row of `ty` whose fields equal those of `search_key`, or
+    /// inserts one made of `search_key` over `defaults` when there is none,
+    /// and tells whether it was inserted. Rows are shallow, as in
+    /// [`Self::upsert_row`].
+    ///
+    /// Concurrent calls only settle on the same row when a unique constraint
+    /// or index covers the fields of `search_key`: without one, the database
+    /// has no conflict on which to skip the second insertion.
+    pub(crate) async fn find_or_create(
+        &self,
+        ty: &Arc<ObjectType>,
+        search_key: &JsonObject,
+        defaults: &JsonObject,
+    ) -> Result<(JsonObject, bool)> {
+        anyhow::ensure!(
+            !search_key.is_empty(),
+            "find_or_create on {} needs a search key",
+            ty.name()
+        );
+        let mut filters = vec![];
+        for (name, value) in search_key {
+            let field = ty
+                .get_field_by_json_name(name)
+                .ok_or_else(|| anyhow!("field {} not present in {}", name, ty.name()))?;
+            anyhow::ensure!(
+                !value.is_null(),
+                "search key field {} of {} is null",
+                name,
+                ty.name()
+            );
+            filters.push((name, crud::json_to_literal(&field.type_, value)?));
+        }
+        let plan = || {
+            filters
+                .iter()
+                .fold(QueryPlan::from_type(ty), |plan, (name, value)| {
+                    plan.filter_eq(name, value.clone())
+                })
+        };
+        for name in defaults.keys() {
+            anyhow::ensure!(
+                ty.get_field_by_json_name(name).is_some(),
+                "field {} not present in {}",
+                name,
+                ty.name()
+            );
+        }
+        self.migrate_lazily(ty).await?;
+        self.seed_id_sequences(ty).await?;
+        let mut row = defaults.clone();
+        row.extend(search_key.clone());
+        let (columns, binds, args) = self.shallow_insertion_values(ty, &row)?;
+        let insert = SqlWithArguments {
+            sql: format!(
+                "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT DO NOTHING",
+                ty.backing_table(),
+                columns.join(","),
+                binds.join(","),
+            ),
+            args,
+        };
+
+        let tr: TransactionStatic = Arc::new(Mutex::new(self.start_transaction().await?));
+        if let Some(found) = self.find_one(tr.clone(), plan()).await? {
+            QueryEngine::commit_transaction_static(tr).await?;
+            return Ok((found, false));
+        }
+        let created = {
+            let _timer = metrics::time_query(ty.backing_table(), Operation::Insert);
+            let mut transaction = tr.lock().await;
+            let result = self.execute_prepared(&mut transaction, &insert).await??;
+            result.rows_affected() == 1
+        };
+        // Either this insertion or a concurrent one made the row.
+        let found = self.find_one(tr.clone(), plan()).await?;
+        QueryEngine::commit_transaction_static(tr).await?;
+        let found = found.with_context(|| {
+            format!(
+                "could not create a {} matching the search key, it conflicts with another one",
+                ty.name()
+            )
+        })?;
+        Ok((found, created))
+    }
+
+    /// The first row `query_plan` returns, if any.
+    async fn find_one(
+        &self,
+        tr: TransactionStatic,
+        query_plan: QueryPlan,
+    ) -> Result<Option<JsonObject>> {
+        let mut rows = self.query(tr, query_plan)?;
+        rows.next().await.transpose()
+    }
+
+    /// Replaces all rows of `ty` with `rows`, in a single transaction.
+    ///
+    /// Rows are inserted shallowly, so fields referring to other entities must hold their ids.
+    pub(crate) async fn replace_rows(&self, ty: &ObjectType, rows: &[JsonObject]) -> Result<()> {
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Insert);
+        let mut queries = vec![SqlWithArguments {
+            sql: format!("DELETE FROM \"{}\"", ty.backing_table()),
+            args: vec![],
+        }];
+        for row in rows {
+            queries.push(self.prepare_insertion_shallow(ty, row)?);
+        }
+        self.execute_transaction(&queries).await?;
+        Ok(())
+    }
+
+    /// Encrypts the values of the encrypted `field` of `ty` that were stored
+    /// before it was encrypted. Returns how many rows it encrypted.
+    pub(crate) async fn encrypt_existing(&self, ty: &ObjectType, field: &Field) -> Result<u64> {
+        anyhow::ensure!(
+            field.is_encrypted,
+            "field {} of {} is not encrypted: mark it @encrypted and apply first",
+            field.name,
+            ty.name()
+        );
+        let _timer = metrics::time_query(ty.backing_table(), Operation::Update);
+        let mut transaction = self.start_transaction().await?;
+        let query = SqlWithArguments {
+            sql: format!(
+                "SELECT \"id\", \"{}\" FROM \"{}\" WHERE \"{}\" IS NOT NULL",
+                field.name,
+                ty.backing_table(),
+                field.name
+            ),
+            args: vec![],
+        };
+        let rows = self
+            .timed(&query.sql, query.get_sqlx().fetch_all(&mut *transaction))
+            .await??;
+        let update = format!(
+            "UPDATE \"{}\" SET \"{}\" = $1 WHERE \"id\" = $2",
+            ty.backing_table(),
+            field.name
+        );
+        let mut encrypted = 0;
+        for row in rows {
+            let id: String = row.try_get(0)?;
+            let value: String = row.try_get(1)?;
+            if encryption::is_encrypted(&value) {
+                continue;
+            }
+            let query = SqlWithArguments {
+                sql: update.clone(),
+                args: vec![
+                    SqlValue::String(encryption::encrypt(&value)?),
+                    SqlValue::String(id),
+                ],
+            };
+            self.execute_prepared(&mut transaction, &query).await??;
+            encrypted += 1;
+        }
+        QueryEngine::commit_transaction(transaction).await?;
+        Ok(encrypted)
+    }
+
+    /// Gives the space of deleted rows back to the file system: runs `VACUUM`
+    /// on SQLite, and `VACUUM ANALYZE` on PostgreSQL, which refreshes the
+    /// statistics of the tables too.
+    pub(crate) async fn vacuum(&self) -> Result<()> {
+        let sql = match self.kind {
+            Kind::Postgres => "VACUUM ANALYZE",
+            Kind::Sqlite => "VACUUM",
+        };
+        // Neither database can vacuum inside a transaction.
+        let mut conn = self.pool.acquire().await?;
+        let execution = sqlx::query(sql).persistent(false).execute(&mut *conn);
+        self.timed(sql, execution).await??;
+        Ok(())
+    }
+
+    /// Refreshes the statistics the database plans queries with, of the
+    /// backing table of `object_type`, or of all the tables if there is none.
+    pub(crate) async fn analyze(&self, object_type: Option<&Arc<ObjectType>>) -> Result<()> {
+        let sql = match object_type {
+            Some(ty) => format!("ANALYZE \"{}\"", ty.backing_table()),
+            None => "ANALYZE".to_owned(),
+        };
+        let mut conn = self.pool.acquire().await?;
+        let execution = sqlx::query(&sql).persistent(false).execute(&mut *conn);
+        self.timed(&sql, execution).await??;
+        Ok(())
+    }
+
+    /// Runs the statements of the hand-written migration `sql` in a
+    /// transaction, and records `migration_id` in the migrations table once
+    /// they all succeeded.
+    ///
+    /// When a statement fails, the transaction is rolled back and the
+    /// migration is recorded as failed, with the statement and its line in
+    /// the error. A failed migration is not run again until its record is
+    /// deleted by hand, once what made it fail is fixed.
+    pub(crate) async fn execute_raw_migration(&self, sql: &str, migration_id: &str) -> Result<()> {
+        let records = migrations::records(self).await?;
+        if let Some(record) = records.iter().find(|r| r.id == migration_id) {
+            match &record.error {
+                Some(error) => anyhow::bail!(
+                    "migration {} failed at {}, and is not run again until its row is deleted \
+                     from {}: {}",
+                    migration_id,
+                    record.applied_at,
+                    migrations::MIGRATIONS_TABLE,
+                    error
+                ),
+                None => anyhow::bail!("migration {} was applied already", migration_id),
+            }
+        }
+        let mut transaction = self.start_transaction().await?;
+        for (line, statement) in migrations::split_statements(sql) {
+            let execution = sqlx::query(&statement)
+                .persistent(false)
+                .execute(&mut transaction);
+            let error = match self.timed(&statement, execution).await {
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) => anyhow::Error::from(e),
+                Err(e) => e,
+            };
+            let error = format!(
+                "statement on line {} failed: {}\n{}",
+                line, statement, error
+            );
+            // The record has to outlive the rollback.
+            drop(transaction);
+            let failed = SqlWithArguments {
+                sql: format!(
+                    "INSERT INTO {} (id, applied_at, error) VALUES ($1, $2, $3)",
+                    migrations::MIGRATIONS_TABLE
+                ),
+                args: vec![
+                    SqlValue::String(migration_id.to_owned()),
+                    SqlValue::String(chrono::Utc::now().to_rfc3339()),
+                    SqlValue::String(error.clone()),
+                ],
+            };
+            self.execute_transaction(&[failed]).await?;
+            anyhow::bail!("migration {} failed: {}", migration_id, error);
+        }
+        let applied = SqlWithArguments {
+            sql: format!(
+                "INSERT INTO {} (id, applied_at) VALUES ($1, $2)",
+                migrations::MIGRATIONS_TABLE
+            ),
+            args: vec![
+                SqlValue::String(migration_id.to_owned()),
+                SqlValue::String(chrono::Utc::now().to_rfc3339()),
+            ],
+        };
+        self.execute_prepared(&mut transaction, &applied).await??;
+        QueryEngine::commit_transaction(transaction).await
+    }
+
+    /// Moves the contents of the SQLite write-ahead log into the database file.
+    pub(crate) async fn checkpoint_wal(&self) -> Result<()> {
+        anyhow::ensure!(
+            matches!(self.kind, Kind::Sqlite),
+            "WAL checkpoints are only supported for SQLite"
+        );
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Writes a consistent copy of a SQLite database to `path`, which must not exist yet.
+    pub(crate) async fn backup_sqlite(&self, path: &Path) -> Result<()> {
+        anyhow::ensure!(
+            matches!(self.kind, Kind::Sqlite),
+            "database file backups are only supported for SQLite"
+        );
+        sqlx::query("VACUUM INTO $1")
+            .bind(path.to_string_lossy().into_owned())
+            .execute(&self.pool)
+            .await
+            .with_context(|| format!("backing up the database to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Replaces the contents of `tables` with their contents in the SQLite database at `path`.
+    ///
+    /// Both databases must have the same schema for these tables.
+    pub(crate) async fn restore_sqlite(&self, path: &Path, tables: &[String]) -> Result<()> {
+        anyhow::ensure!(
+            matches!(self.kind, Kind::Sqlite),
+            "database file restores are only supported for SQLite"
+        );
+        // ATTACH is per connection, so everything has to run on the same one.
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query("ATTACH DATABASE $1 AS backup")
+            .bind(path.to_string_lossy().into_owned())
+            .execute(&mut conn)
+            .await
+            .context("opening the backup")?;
+        let restored: Result<()> = async {
+            let mut transaction = sqlx::Connection::begin(&mut *conn).await?;
+            for table in tables {
+                transaction
+                    .execute(format!("DELETE FROM main.\"{}\"", table).as_str())
+                    .await?;
+                transaction
+                    .execute(
+                        format!(
+                            "INSERT INTO main.\"{0}\" SELECT * FROM backup.\"{0}\"",
+                            table
+                        )
+                        .as_str(),
+                    )
+                    .await
+                    .with_context(|| format!("restoring table {}", table))?;
+            }
+            transaction.commit().await?;
+            Ok(())
+        }
+        .await;
+        let detached = sqlx::query("DETACH DATABASE backup")
+            .execute(&mut conn)
+            .await;
+        // A failed restore is what the caller needs to hear about, not what came after it.
+        restored?;
+        detached.context("closing the backup")?;
+        Ok(())
     }
 
     async fn run_sql_queries(
@@ -544,12 +2279,12 @@ impl QueryEngine {
     ) -> Result<()> {
         if let Some(transaction) = transaction {
             for q in queries {
-                transaction.execute(q.get_sqlx()).await?;
+                self.execute_prepared(transaction, q).await??;
             }
         } else {
             let mut transaction = self.start_transaction().await?;
             for q in queries {
-                transaction.execute(q.get_sqlx()).await?;
+                self.execute_prepared(&mut transaction, q).await??;
             }
             QueryEngine::commit_transaction(transaction).await?;
         }
@@ -568,10 +2303,13 @@ impl QueryEngine {
     /// and value `ty_value` into database.
     /// Returns vector of SQL insert queries with corresponding arguments and IdTree of
     /// inserted objects.
+    ///
+    /// Only the top-level object is checked against `expected_version`.
     fn prepare_insertion(
         &self,
         ty: &ObjectType,
         ty_value: &JsonObject,
+        expected_version: Option<u64>,
     ) -> Result<(Vec<SqlWithArguments>, IdTree)> {
         let mut child_ids = HashMap::<String, IdTree>::new();
         let mut obj_id = Option::<String>::None;
@@ -579,7 +2317,7 @@ impl QueryEngine {
         let mut inserts = Vec::<SqlWithArguments>::new();
 
         for field in ty.all_fields() {
-            let field_value = ty_value.get(&field.name);
+            let field_value = ty_value.get(field.json_name());
             if (field_value.is_none() || field_value.unwrap().is_null()) && field.is_optional {
                 continue;
             }
@@ -605,16 +2343,16 @@ impl QueryEngine {
                         }
                     } else {
                         let (nested_inserts, nested_ids) =
-                            self.prepare_insertion(nested_type, nested_value)?;
+                            self.prepare_insertion(nested_type, nested_value, None)?;
                         inserts.extend(nested_inserts);
                         let nested_id = nested_ids.id.to_owned();
-                        child_ids.insert(field.name.to_owned(), nested_ids);
+                        child_ids.insert(field.json_name().to_owned(), nested_ids);
                         nested_id
                     };
                     SqlValue::String(nested_id)
                 }
                 _ => self
-                    .convert_to_argument(field, ty_value)
+                    .convert_to_argument(ty, field, ty_value)
                     .with_context(incompatible_data)?,
             };
 
@@ -627,9 +2365,11 @@ impl QueryEngine {
             }
             query_args.push(arg);
         }
+        // Bound after the fields, as make_insert_query() expects.
+        query_args.extend(expected_version.map(version_arg));
 
         inserts.push(SqlWithArguments {
-            sql: self.make_insert_query(ty, ty_value)?,
+            sql: self.make_insert_query(ty, ty_value, expected_version)?,
             args: query_args,
         });
         let obj_id = obj_id
@@ -643,9 +2383,91 @@ impl QueryEngine {
         ))
     }
 
+    /// Counts the ids of the rows of the auto-incremented types among `ty`
+    /// and the types nested in it, for their new rows to take the next ones.
+    async fn seed_id_sequences(&self, ty: &ObjectType) -> Result<()> {
+        let mut pending = vec![ty];
+        let mut seen = HashSet::new();
+        while let Some(ty) = pending.pop() {
+            if !seen.insert(ty.backing_table()) {
+                continue;
+            }
+            for field in ty.user_fields() {
+                if let Type::Object(nested) = &field.type_ {
+                    if !nested.is_auth() {
+                        pending.push(nested);
+                    }
+                }
+            }
+            let table = ty.backing_table();
+            if ty.id_strategy() != IdStrategy::AutoIncrement
+                || ids::is_sequence_seeded(&self.conn_uri, table)
+            {
+                continue;
+            }
+            let rows = self
+                .fetch_all(SqlWithArguments {
+                    sql: format!("SELECT \"id\" FROM \"{}\"", table),
+                    args: vec![],
+                })
+                .await?;
+            let row_ids: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+            ids::seed_sequence(&self.conn_uri, table, row_ids.iter().map(String::as_str));
+        }
+        Ok(())
+    }
+
+    /// The quoted columns, the placeholders and the arguments inserting
+    /// `ty_value` as a shallow row of `ty` takes. Optional fields missing from
+    /// `ty_value` are left out.
+    fn shallow_insertion_values(
+        &self,
+        ty: &ObjectType,
+        ty_value: &JsonObject,
+    ) -> Result<(Vec<String>, Vec<String>, Vec<SqlValue>)> {
+        let mut columns = vec![];
+        let mut binds = vec![];
+        let mut args = vec![];
+        for field in ty.all_fields() {
+            let value = ty_value.get(field.json_name());
+            if value.is_none() && field.is_optional {
+                continue;
+            }
+            columns.push(format!("\"{}\"", field.name));
+            if field.is_optional && value.unwrap().is_null() {
+                // Same as in make_insert_query().
+                binds.push("NULL".to_owned());
+                continue;
+            }
+            let arg = self
+                .convert_to_argument(ty, field, ty_value)
+                .with_context(|| QueryEngine::incompatible(field, ty))?;
+            args.push(arg);
+            binds.push(format!("${}", args.len()));
+        }
+        Ok((columns, binds, args))
+    }
+
+    /// The value of `field` for a new row of `ty` that doesn't give one.
+    fn generate_value(&self, ty: &ObjectType, field: &Field) -> Result<Option<String>> {
+        match field.type_ {
+            Type::Id => Ok(Some(ids::generate_id(
+                ty.id_strategy(),
+                &self.conn_uri,
+                ty.backing_table(),
+            )?)),
+            _ => Ok(field.generate_value()),
+        }
+    }
+
     /// Converts `field` with value `ty_value` into SqlValue while ensuring the
     /// generation of default and generable values.
-    fn convert_to_argument(&self, field: &Field, ty_value: &JsonObject) -> Result<SqlValue> {
+    fn convert_to_argument(
+        &self,
+        ty: &ObjectType,
+        field: &Field,
+        ty_value: &JsonObject,
+    ) -> Result<SqlValue> {
         macro_rules! parse_default_value {
             (str, $value:expr) => {{
                 $value
@@ -660,13 +2482,15 @@ impl QueryEngine {
         }
         macro_rules! convert_json_value {
             ($as_type:ident, $fallback:ident) => {{
-                match ty_value.get(&field.name) {
+                match ty_value.get(field.json_name()) {
                     Some(value_json) => value_json
                         .$as_type()
                         .context("failed to convert json to specific type")?
                         .to_owned(),
                     None => {
-                        let value = field.generate_value().context("failed to generate value")?;
+                        let value = self
+                            .generate_value(ty, field)?
+                            .context("failed to generate value")?;
                         parse_default_value!($fallback, value)
                     }
                 }
@@ -674,27 +2498,67 @@ impl QueryEngine {
         }
 
         let arg = match &field.type_ {
+            Type::String if field.is_encrypted => {
+                let value: String = convert_json_value!(as_str, str);
+                SqlValue::String(encryption::encrypt(&value)?)
+            }
             Type::String | Type::Id | Type::Object(_) => {
                 SqlValue::String(convert_json_value!(as_str, str))
             }
+            Type::Enum(variants) => {
+                let value: String = convert_json_value!(as_str, str);
+                anyhow::ensure!(
+                    variants.contains(&value),
+                    "field {} takes one of {}, got \"{}\"",
+                    field.name,
+                    field.type_.name(),
+                    value
+                );
+                SqlValue::String(value)
+            }
             Type::Float => SqlValue::F64(convert_json_value!(as_f64, f64)),
             Type::Boolean => SqlValue::Bool(convert_json_value!(as_bool, bool)),
+            Type::Blob => {
+                let value: String = convert_json_value!(as_str, str);
+                SqlValue::String(blob_key(&value).to_owned())
+            }
+            Type::Array(_) | Type::Map(_) => {
+                let value = match ty_value.get(field.json_name()) {
+                    Some(value) => value.clone(),
+                    None => {
+                        let value = self
+                            .generate_value(ty, field)?
+                            .context("failed to generate value")?;
+                        serde_json::from_str(&value).context("failed to parse default value")?
+                    }
+                };
+                check_json(field, &value)?;
+                SqlValue::String(value.to_string())
+            }
         };
         Ok(arg)
     }
 
     /// For given object of type `ty` and its value `ty_value` computes a string
-    /// representing SQL query which inserts the object into database.
-    fn make_insert_query(&self, ty: &ObjectType, ty_value: &JsonObject) -> Result<String> {
+    /// representing SQL query which inserts the object into database. An
+    /// existing row is updated instead, if its version is `expected_version`
+    /// when that is given, in which case the version is bound after the fields.
+    fn make_insert_query(
+        &self,
+        ty: &ObjectType,
+        ty_value: &JsonObject,
+        expected_version: Option<u64>,
+    ) -> Result<String> {
         let mut field_binds = String::new();
         let mut field_names = vec![];
+        let mut json_names = vec![];
         let mut id_name = String::new();
         let mut update_binds = String::new();
         let mut id_bind = String::new();
 
         let mut i = 0;
         for f in ty.all_fields() {
-            let val = ty_value.get(&f.name);
+            let val = ty_value.get(f.json_name());
             if val.is_none() && f.is_optional {
                 continue;
             }
@@ -709,10 +2573,14 @@ impl QueryEngine {
             field_binds.push(',');
 
             field_names.push(f.name.clone());
+            json_names.push(f.json_name());
             if f.type_ == Type::Id {
                 if let Some(idstr) = val {
                     let idstr = idstr.as_str().context("invalid ID: It is not a string")?;
-                    Uuid::parse_str(idstr).map_err(|_| anyhow!("invalid ID '{}'", idstr))?;
+                    anyhow::ensure!(ids::is_valid_id(idstr), "invalid ID '{}'", idstr);
+                    if let (IdStrategy::AutoIncrement, Ok(id)) = (ty.id_strategy(), idstr.parse()) {
+                        ids::observe_id(&self.conn_uri, ty.backing_table(), id);
+                    }
                 }
                 anyhow::ensure!(id_bind.is_empty(), "More than one ID??");
                 id_name = f.name.to_string();
@@ -722,10 +2590,23 @@ impl QueryEngine {
         }
         field_binds.pop();
         update_binds.pop();
+        update_binds.push_str(&format!(
+            ",\"{0}\" = \"{1}\".\"{0}\" + 1",
+            VERSION_FIELD_NAME,
+            ty.backing_table()
+        ));
+        let version_condition = expected_version.map_or(String::new(), |_| {
+            format!(
+                " AND \"{}\".\"{}\" = ${}",
+                ty.backing_table(),
+                VERSION_FIELD_NAME,
+                i + 1
+            )
+        });
 
         for v in ty_value.keys() {
             anyhow::ensure!(
-                field_names.contains(v),
+                json_names.contains(&v.as_str()),
                 "field {} not present in {}",
                 v,
                 ty.name()
@@ -733,7 +2614,7 @@ impl QueryEngine {
         }
 
         Ok(std::format!(
-            "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} WHERE \"{}\".\"{}\" = {}",
+            "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} WHERE \"{}\".\"{}\" = {}{}",
             &ty.backing_table(),
             field_names.into_iter().map(|f| format!("\"{}\"", f)).join(","),
             field_binds,
@@ -742,6 +2623,7 @@ impl QueryEngine {
             &ty.backing_table(),
             id_name,
             id_bind,
+            version_condition,
         ))
     }
 
@@ -752,17 +2634,17 @@ impl QueryEngine {
     ) -> Result<SqlWithArguments> {
         let mut query_args = Vec::<SqlValue>::new();
         for field in ty.all_fields() {
-            if ty_value.get(&field.name).is_none() && field.is_optional {
+            if ty_value.get(field.json_name()).is_none() && field.is_optional {
                 continue;
             }
             let arg = self
-                .convert_to_argument(field, ty_value)
+                .convert_to_argument(ty, field, ty_value)
                 .with_context(|| QueryEngine::incompatible(field, ty))?;
             query_args.push(arg);
         }
 
         Ok(SqlWithArguments {
-            sql: self.make_insert_query(ty, ty_value)?,
+            sql: self.make_insert_query(ty, ty_value, None)?,
             args: query_args,
         })
     }
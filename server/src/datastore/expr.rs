@@ -43,6 +43,8 @@ pub(crate) enum Literal {
     I64(i64),
     F64(f64),
     String(String),
+    /// Only taken by [`BinaryOp::Overlaps`].
+    Array(Vec<Literal>),
     Null,
 }
 
@@ -117,6 +119,13 @@ pub(crate) enum BinaryOp {
     Or,
     Like,
     NotLike,
+    /// A string property containing a substring, or an array property
+    /// containing an element.
+    Contains,
+    /// An array property sharing at least one element with an array literal.
+    Overlaps,
+    /// A map property having a string literal as a key.
+    HasKey,
 }
 
 impl BinaryOp {
@@ -132,6 +141,10 @@ impl BinaryOp {
             Self::Or => "OR",
             Self::Like => "LIKE",
             Self::NotLike => "NOT LIKE",
+            // What these become depends on the type of the property.
+            Self::Contains | Self::Overlaps | Self::HasKey => {
+                unreachable!("{:?} has no SQL operator", self)
+            }
         }
     }
 }
@@ -173,6 +186,9 @@ impl BinaryExpr {
     make_op_method! {or, Or}
     make_op_method! {like, Like}
     make_op_method! {not_like, NotLike}
+    make_op_method! {contains, Contains}
+    make_op_method! {overlaps, Overlaps}
+    make_op_method! {has_key, HasKey}
 }
 
 #[cfg(test)]
@@ -303,6 +319,24 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_literal_parsing_array() {
+        let expr = serde_json::from_str(
+            r#"{
+            "exprType": "Literal",
+            "value": ["a", 1]
+        }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            expr,
+            Expr::Literal {
+                value: Literal::Array(vec!["a".into(), 1u64.into()])
+            }
+        );
+    }
+
     #[test]
     #[should_panic(expected = "missing field `value`")]
     fn test_literal_parsing_value_missing_panic() {
@@ -0,0 +1,165 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Generation of the ids of new rows, after the [`IdStrategy`] of their type.
+
+use crate::types::{IdStrategy, SNOWFLAKE_MACHINE_ID_BITS};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ulid::Ulid;
+use uuid::Uuid;
+
+/// Start of the timestamps of snowflake ids: 2022-01-01T00:00:00Z.
+const SNOWFLAKE_EPOCH: Duration = Duration::from_millis(1_640_995_200_000);
+
+/// Number of bits of a snowflake id holding its sequence number, which tells
+/// apart the ids generated in the same millisecond.
+const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+
+/// The millisecond of the last snowflake id, and the sequence number it had.
+static SNOWFLAKE_STATE: Lazy<Mutex<(u64, u64)>> = Lazy::new(|| Mutex::new((0, 0)));
+
+/// The last id given to a row of each auto-incremented table, by the URI of
+/// its database and its name. The executor threads each have their own
+/// query engine, so this is kept for the whole process. Other processes
+/// writing to the same database count on their own, so auto-incremented
+/// types only get unique ids from a single process.
+static SEQUENCES: Lazy<Mutex<HashMap<(String, String), u64>>> = Lazy::new(Default::default);
+
+fn snowflake(machine_id: u16) -> Result<String> {
+    let mut state = SNOWFLAKE_STATE.lock().unwrap();
+    let mut millis;
+    let mut sequence;
+    loop {
+        millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH + SNOWFLAKE_EPOCH)
+            .context("the clock is set before 2022")?
+            .as_millis() as u64;
+        // The clock going back would repeat ids: wait for it to catch up.
+        if millis < state.0 {
+            std::thread::yield_now();
+            continue;
+        }
+        sequence = if millis == state.0 { state.1 + 1 } else { 0 };
+        if sequence < 1 << SNOWFLAKE_SEQUENCE_BITS {
+            break;
+        }
+        // All the ids of this millisecond are taken.
+        std::thread::yield_now();
+    }
+    *state = (millis, sequence);
+    let id = (millis << (SNOWFLAKE_MACHINE_ID_BITS + SNOWFLAKE_SEQUENCE_BITS))
+        | (u64::from(machine_id) << SNOWFLAKE_SEQUENCE_BITS)
+        | sequence;
+    Ok(id.to_string())
+}
+
+/// Whether the ids of `table` in the database at `conn_uri` are counted
+/// already, see [`seed_sequence`].
+pub(crate) fn is_sequence_seeded(conn_uri: &str, table: &str) -> bool {
+    let key = (conn_uri.to_owned(), table.to_owned());
+    SEQUENCES.lock().unwrap().contains_key(&key)
+}
+
+/// Starts counting the ids of `table` after `ids`, the ones it holds. The
+/// ids that aren't numbers, from before the type was auto-incremented,
+/// don't count.
+pub(crate) fn seed_sequence<'a>(conn_uri: &str, table: &str, ids: impl Iterator<Item = &'a str>) {
+    let last = ids.filter_map(|id| id.parse().ok()).max().unwrap_or(0);
+    observe_id(conn_uri, table, last);
+}
+
+/// Makes sure the ids generated for `table` come after `id`.
+pub(crate) fn observe_id(conn_uri: &str, table: &str, id: u64) {
+    let key = (conn_uri.to_owned(), table.to_owned());
+    let mut sequences = SEQUENCES.lock().unwrap();
+    let last = sequences.entry(key).or_default();
+    *last = (*last).max(id);
+}
+
+/// A new id for a row of `table`, in the database at `conn_uri`, whose type
+/// generates ids with `strategy`.
+pub(crate) fn generate_id(strategy: IdStrategy, conn_uri: &str, table: &str) -> Result<String> {
+    match strategy {
+        IdStrategy::RandomUuid => Ok(Uuid::new_v4().to_string()),
+        IdStrategy::Ulid => Ok(Ulid::new().to_string()),
+        IdStrategy::Snowflake { machine_id } => snowflake(machine_id),
+        IdStrategy::AutoIncrement => {
+            let key = (conn_uri.to_owned(), table.to_owned());
+            let mut sequences = SEQUENCES.lock().unwrap();
+            let last = sequences
+                .get_mut(&key)
+                .with_context(|| format!("the ids of {} are not counted yet", table))?;
+            *last += 1;
+            Ok(last.to_string())
+        }
+    }
+}
+
+/// Whether `id` can be the id of a row: a UUID, a ULID or a number, as any
+/// of the strategies generates.
+pub(crate) fn is_valid_id(id: &str) -> bool {
+    Uuid::parse_str(id).is_ok() || Ulid::from_string(id).is_ok() || id.parse::<u64>().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategies() {
+        let uuid = generate_id(IdStrategy::RandomUuid, "db", "t").unwrap();
+        assert!(Uuid::parse_str(&uuid).is_ok());
+        let first = generate_id(IdStrategy::Ulid, "db", "t").unwrap();
+        assert!(Ulid::from_string(&first).is_ok());
+
+        let snowflakes: Vec<u64> = (0..10000)
+            .map(|_| generate_id(IdStrategy::Snowflake { machine_id: 7 }, "db", "t").unwrap())
+            .map(|id| id.parse().unwrap())
+            .collect();
+        assert!(snowflakes.windows(2).all(|w| w[0] < w[1]));
+        assert!(snowflakes
+            .iter()
+            .all(|id| (id >> SNOWFLAKE_SEQUENCE_BITS) & 1023 == 7));
+
+        let snowflake = snowflakes[0].to_string();
+        for id in [uuid.as_str(), first.as_str(), "12", snowflake.as_str()] {
+            assert!(is_valid_id(id), "{}", id);
+        }
+        assert!(!is_valid_id("'; DROP TABLE t"));
+    }
+
+    #[test]
+    fn sequences() {
+        let next = || generate_id(IdStrategy::AutoIncrement, "db", "seq").unwrap();
+        assert!(!is_sequence_seeded("db", "seq"));
+        generate_id(IdStrategy::AutoIncrement, "db", "seq").unwrap_err();
+        let uuid = Uuid::new_v4().to_string();
+        seed_sequence("db", "seq", [uuid.as_str(), "3", "41", "7"].into_iter());
+        assert!(is_sequence_seeded("db", "seq"));
+        assert_eq!(next(), "42");
+        observe_id("db", "seq", 100);
+        observe_id("db", "seq", 5);
+        assert_eq!(next(), "101");
+        assert!(!is_sequence_seeded("other", "seq"));
+    }
+
+    #[test]
+    fn parse_strategies() {
+        for strategy in [
+            IdStrategy::RandomUuid,
+            IdStrategy::Ulid,
+            IdStrategy::Snowflake { machine_id: 1023 },
+            IdStrategy::AutoIncrement,
+        ] {
+            assert_eq!(
+                strategy.to_string().parse::<IdStrategy>().unwrap(),
+                strategy
+            );
+        }
+        "snowflake:1024".parse::<IdStrategy>().unwrap_err();
+        "serial".parse::<IdStrategy>().unwrap_err();
+    }
+}
@@ -0,0 +1,115 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Background purging of expired session tokens.
+
+use crate::auth::AUTH_SESSION_NAME;
+use crate::datastore::engine::SqlWithArguments;
+use crate::datastore::query::SqlValue;
+use crate::datastore::{MetaService, QueryEngine};
+use crate::types::{Type, TypeSystem};
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use deno_core::futures;
+use futures::FutureExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+/// Key in `__chiselstrike_meta` holding the UNIX timestamp of the last cleanup.
+const LAST_CLEANUP_KEY: &str = "last_session_cleanup";
+
+/// If the last cleanup is older than this on startup, run one right away.
+const STARTUP_CLEANUP_THRESHOLD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Periodically deletes sessions that expired more than `ttl` ago.
+pub(crate) struct Cleaner {
+    meta: MetaService,
+    query_engine: QueryEngine,
+    session_table: String,
+    interval: Duration,
+    ttl: Duration,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Cleaner {
+    pub(crate) fn new(
+        meta: MetaService,
+        query_engine: QueryEngine,
+        type_system: &TypeSystem,
+        interval: Duration,
+        ttl: Duration,
+    ) -> Result<Self> {
+        let session_table = match type_system.lookup_builtin_type(AUTH_SESSION_NAME)? {
+            Type::Object(ty) => ty.backing_table().to_owned(),
+            _ => anyhow::bail!("Internal error: type {} not found", AUTH_SESSION_NAME),
+        };
+        Ok(Self {
+            meta,
+            query_engine,
+            session_table,
+            interval,
+            ttl,
+        })
+    }
+
+    /// Deletes expired sessions and records the time of this cleanup.
+    /// Returns the number of purged sessions.
+    pub(crate) async fn purge_expired_sessions(&self) -> Result<u64> {
+        // Session expiration is stored as an ISO 8601 string, which sorts lexicographically.
+        let cutoff = Utc::now() - chrono::Duration::from_std(self.ttl)?;
+        let cutoff = cutoff.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let purged = self
+            .query_engine
+            .execute_transaction(&[SqlWithArguments {
+                sql: format!("DELETE FROM \"{}\" WHERE expires < $1", self.session_table),
+                args: vec![SqlValue::String(cutoff)],
+            }])
+            .await?;
+
+        let mut transaction = self.meta.start_transaction().await?;
+        self.meta
+            .set_meta_value(&mut transaction, LAST_CLEANUP_KEY, &now_secs().to_string())
+            .await?;
+        MetaService::commit_transaction(transaction).await?;
+
+        info!("Purged {} expired session(s)", purged);
+        Ok(purged)
+    }
+
+    async fn needs_startup_cleanup(&self) -> Result<bool> {
+        let last = self.meta.get_meta_value(LAST_CLEANUP_KEY).await?;
+        let last = last.and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+        Ok(now_secs().saturating_sub(last) > STARTUP_CLEANUP_THRESHOLD.as_secs())
+    }
+
+    /// Runs the cleanup loop until `shutdown` fires.
+    pub(crate) async fn run(self, shutdown: async_channel::Receiver<()>) {
+        match self.needs_startup_cleanup().await {
+            Ok(true) => {
+                if let Err(e) = self.purge_expired_sessions().await {
+                    warn!("Session cleanup failed: {:?}", e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Could not read last session cleanup time: {:?}", e),
+        }
+
+        loop {
+            futures::select! {
+                _ = sleep(self.interval).fuse() => {},
+                _ = shutdown.recv().fuse() => {
+                    break;
+                }
+            };
+
+            if let Err(e) = self.purge_expired_sessions().await {
+                warn!("Session cleanup failed: {:?}", e);
+            }
+        }
+    }
+}
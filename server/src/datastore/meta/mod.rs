@@ -1,3 +1,4 @@
+pub(crate) mod cleaner;
 pub(crate) mod schema;
 
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
@@ -8,13 +9,16 @@ use crate::policies::Policies;
 use crate::prefix_map::PrefixMap;
 use crate::types::AuthOrNot::IsNotAuth;
 use crate::types::{
-    DbIndex, ExistingField, ExistingObject, Field, FieldDelta, ObjectDelta, ObjectType, TypeSystem,
+    CheckConstraint, DbIndex, ExistingField, ExistingObject, Field, FieldDelta, IdStrategy,
+    ObjectDelta, ObjectType, TypeSystem, UniqueConstraint, VirtualRelation, WebhookConfig,
 };
+use crate::webhooks::Delivery;
 use anyhow::Context;
 use sqlx::any::{Any, AnyPool};
 use sqlx::{Execute, Executor, Row, Transaction};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
 
 /// Meta service.
@@ -82,11 +86,27 @@ async fn update_field_query(
     let field_id = delta.id;
 
     if let Some(field) = &delta.attrs {
-        let default_stmt = if field.default.is_none() {
-            ""
-        } else {
-            ", default_value = $5"
+        // Binding nulls is unreliable, so absent values are spelled out.
+        let mut values = vec![];
+        let mut bind = |value: &String| {
+            values.push(value.clone());
+            format!("${}", 6 + values.len())
         };
+        let json_name = field
+            .json_name
+            .as_ref()
+            .map_or("NULL".to_owned(), &mut bind);
+        let description = field
+            .description
+            .as_ref()
+            .map_or("NULL".to_owned(), &mut bind);
+        let deprecated_message = field
+            .deprecated_message
+            .as_ref()
+            .map_or("NULL".to_owned(), &mut bind);
+        let default_stmt = field.default.as_ref().map_or(String::new(), |value| {
+            format!(", default_value = {}", bind(value))
+        });
 
         let querystr = format!(
             r#"
@@ -94,7 +114,12 @@ async fn update_field_query(
             SET
                 field_type = $1,
                 is_optional = $2::bool,
-                is_unique = $4::bool {default_stmt}
+                is_unique = $3::bool,
+                is_deprecated = $5::bool,
+                is_encrypted = $6::bool,
+                json_name = {json_name},
+                description = {description},
+                deprecated_message = {deprecated_message} {default_stmt}
             WHERE field_id = $4"#
         );
         let mut query = sqlx::query(&querystr);
@@ -103,10 +128,12 @@ async fn update_field_query(
             .bind(field.type_.name())
             .bind(field.is_optional)
             .bind(field.is_unique)
-            .bind(field_id);
+            .bind(field_id)
+            .bind(field.is_deprecated)
+            .bind(field.is_encrypted);
 
-        if let Some(value) = &field.default {
-            query = query.bind(value.to_owned());
+        for value in values {
+            query = query.bind(value);
         }
 
         execute(transaction, query).await?;
@@ -207,6 +234,28 @@ async fn insert_field_query(
     let add_field_name = add_field_name.bind(full_name).bind(field_id);
     execute(transaction, add_field_name).await?;
 
+    for (column, value) in [
+        ("json_name", &field.json_name),
+        ("description", &field.description),
+        ("deprecated_message", &field.deprecated_message),
+    ] {
+        if let Some(value) = value {
+            let q = format!("UPDATE fields SET {column} = $1 WHERE field_id = $2");
+            let q = sqlx::query(&q).bind(value).bind(field_id);
+            execute(transaction, q).await?;
+        }
+    }
+    for (column, value) in [
+        ("is_deprecated", field.is_deprecated),
+        ("is_encrypted", field.is_encrypted),
+    ] {
+        if value {
+            let q = format!("UPDATE fields SET {column} = $1::bool WHERE field_id = $2");
+            let q = sqlx::query(&q).bind(true).bind(field_id);
+            execute(transaction, q).await?;
+        }
+    }
+
     for label in &field.labels {
         let q = sqlx::query("INSERT INTO field_labels (label_name, field_id) VALUES ($1, $2)")
             .bind(label)
@@ -225,6 +274,9 @@ impl MetaService {
         conn: &DbConnection,
         nr_conn: usize,
     ) -> anyhow::Result<Self> {
+        // Its schema changes aren't timed, like those of the query engine.
+        let mut conn = conn.clone();
+        conn.query_timeout = Duration::ZERO;
         let local = conn.local_connection(nr_conn).await?;
         Ok(Self::new(local.kind, local.pool))
     }
@@ -502,6 +554,11 @@ impl MetaService {
             SELECT
                 types.type_id AS type_id,
                 types.backing_table AS backing_table,
+                types.description AS description,
+                types.parent AS parent,
+                types.multi_tenant AS multi_tenant,
+                types.id_strategy AS id_strategy,
+                types.webhook AS webhook,
                 type_names.name AS type_name
             FROM types
             INNER JOIN type_names ON types.type_id = type_names.type_id"#,
@@ -513,11 +570,35 @@ impl MetaService {
             let type_id: i32 = row.get("type_id");
             let backing_table: &str = row.get("backing_table");
             let type_name: &str = row.get("type_name");
+            let description: Option<String> = row.get("description");
+            let parent: Option<String> = row.get("parent");
+            let multi_tenant: bool = row.get("multi_tenant");
+            let id_strategy: Option<String> = row.get("id_strategy");
+            let id_strategy = match id_strategy {
+                Some(id_strategy) => id_strategy.parse()?,
+                None => IdStrategy::default(),
+            };
+            let webhook: Option<String> = row.get("webhook");
+            let webhook: Option<WebhookConfig> = match webhook {
+                Some(webhook) => Some(serde_json::from_str(&webhook)?),
+                None => None,
+            };
             let desc = ExistingObject::new(type_name, backing_table, type_id)?;
             let fields = self.load_type_fields(&ts, type_id).await?;
             let indexes = self.load_type_indexes(type_id, backing_table).await?;
-
-            let ty = ObjectType::new(desc, fields, indexes, IsNotAuth)?;
+            let unique_constraints = self.load_unique_constraints(type_id).await?;
+            let check_constraints = self.load_check_constraints(type_id).await?;
+            let virtual_relations = self.load_virtual_relations(type_id).await?;
+
+            let ty = ObjectType::new(desc, fields, indexes, IsNotAuth)?
+                .with_description(description)
+                .with_parent(parent)
+                .with_multi_tenant(multi_tenant)?
+                .with_id_strategy(id_strategy)
+                .with_webhook(webhook)
+                .with_unique_constraints(unique_constraints)?
+                .with_check_constraints(check_constraints)?
+                .with_virtual_relations(virtual_relations)?;
             ts.add_type(Arc::new(ty))?;
         }
         Ok(ts)
@@ -532,7 +613,12 @@ impl MetaService {
                 fields.field_type AS field_type,
                 fields.default_value AS default_value,
                 fields.is_optional AS is_optional,
-                fields.is_unique AS is_unique
+                fields.is_unique AS is_unique,
+                fields.json_name AS json_name,
+                fields.description AS description,
+                fields.is_deprecated AS is_deprecated,
+                fields.deprecated_message AS deprecated_message,
+                fields.is_encrypted AS is_encrypted
             FROM field_names
             INNER JOIN fields
                 ON fields.type_id = $1 AND field_names.field_id = fields.field_id;"#,
@@ -560,6 +646,11 @@ impl MetaService {
             let field_def: Option<String> = row.get("default_value");
             let is_optional: bool = row.get("is_optional");
             let is_unique: bool = row.get("is_unique");
+            let json_name: Option<String> = row.get("json_name");
+            let description: Option<String> = row.get("description");
+            let is_deprecated: bool = row.get("is_deprecated");
+            let deprecated_message: Option<String> = row.get("deprecated_message");
+            let is_encrypted: bool = row.get("is_encrypted");
 
             let labels_query =
                 sqlx::query("SELECT label_name FROM field_labels WHERE field_id = $1");
@@ -573,7 +664,16 @@ impl MetaService {
                 .map(|r| r.get("label_name"))
                 .collect::<Vec<String>>();
 
-            fields.push(Field::new(desc, labels, field_def, is_optional, is_unique));
+            let mut field = Field::new(desc, labels, field_def, is_optional, is_unique)
+                .with_json_name(json_name)
+                .with_description(description);
+            if is_deprecated {
+                field = field.deprecated(deprecated_message);
+            }
+            if is_encrypted {
+                field = field.encrypted();
+            }
+            fields.push(field);
         }
         Ok(fields)
     }
@@ -605,6 +705,53 @@ impl MetaService {
         Ok(indexes)
     }
 
+    async fn load_unique_constraints(&self, type_id: i32) -> anyhow::Result<Vec<UniqueConstraint>> {
+        let query = sqlx::query("SELECT name, fields FROM unique_constraints WHERE type_id = $1")
+            .bind(type_id);
+        let rows = fetch_all(&self.pool, query).await?;
+
+        let mut constraints = vec![];
+        for row in rows {
+            let name: &str = row.get("name");
+            let fields: &str = row.get("fields");
+            constraints.push(UniqueConstraint {
+                fields: fields.split(';').map(|s| s.to_string()).collect(),
+                name: (!name.is_empty()).then(|| name.to_owned()),
+            });
+        }
+        Ok(constraints)
+    }
+
+    async fn load_check_constraints(&self, type_id: i32) -> anyhow::Result<Vec<CheckConstraint>> {
+        let query =
+            sqlx::query("SELECT name, expression FROM check_constraints WHERE type_id = $1")
+                .bind(type_id);
+        let rows = fetch_all(&self.pool, query).await?;
+        Ok(rows
+            .iter()
+            .map(|row| CheckConstraint {
+                name: row.get("name"),
+                expression: row.get("expression"),
+            })
+            .collect())
+    }
+
+    async fn load_virtual_relations(&self, type_id: i32) -> anyhow::Result<Vec<VirtualRelation>> {
+        let query = sqlx::query(
+            "SELECT name, target_type, subquery FROM virtual_relations WHERE type_id = $1",
+        )
+        .bind(type_id);
+        let rows = fetch_all(&self.pool, query).await?;
+        Ok(rows
+            .iter()
+            .map(|row| VirtualRelation {
+                name: row.get("name"),
+                target_type: row.get("target_type"),
+                subquery: row.get("subquery"),
+            })
+            .collect())
+    }
+
     pub(crate) async fn remove_type(
         &self,
         transaction: &mut Transaction<'_, Any>,
@@ -647,13 +794,100 @@ impl MetaService {
 
         Self::delete_indexes(transaction, &delta.removed_indexes).await?;
 
-        Self::insert_indexes(
-            transaction,
-            ty.meta_id
-                .context("object must have an id when it's being updated")?,
-            &delta.added_indexes,
-        )
-        .await?;
+        let type_id = ty
+            .meta_id
+            .context("object must have an id when it's being updated")?;
+        Self::insert_indexes(transaction, type_id, &delta.added_indexes).await?;
+        Self::delete_unique_constraints(transaction, type_id, &delta.removed_unique_constraints)
+            .await?;
+        Self::insert_unique_constraints(transaction, type_id, &delta.added_unique_constraints)
+            .await?;
+        Self::delete_check_constraints(transaction, type_id, &delta.removed_check_constraints)
+            .await?;
+        Self::insert_check_constraints(transaction, type_id, &delta.added_check_constraints)
+            .await?;
+        Self::delete_virtual_relations(transaction, type_id, &delta.removed_virtual_relations)
+            .await?;
+        Self::insert_virtual_relations(transaction, type_id, &delta.added_virtual_relations)
+            .await?;
+        Self::update_type_description(transaction, type_id, delta.description.as_deref()).await?;
+        Self::update_type_parent(transaction, type_id, delta.parent.as_deref()).await?;
+        Self::update_type_multi_tenant(transaction, type_id, delta.multi_tenant).await?;
+        Self::update_type_id_strategy(transaction, type_id, delta.id_strategy).await?;
+        Self::update_type_webhook(transaction, type_id, delta.webhook.as_ref()).await
+    }
+
+    async fn update_type_description(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        description: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let query = match description {
+            // Binding nulls is unreliable, as in update_field_query().
+            None => {
+                sqlx::query("UPDATE types SET description = NULL WHERE type_id = $1").bind(type_id)
+            }
+            Some(description) => {
+                sqlx::query("UPDATE types SET description = $1 WHERE type_id = $2")
+                    .bind(description.to_owned())
+                    .bind(type_id)
+            }
+        };
+        execute(transaction, query).await?;
+        Ok(())
+    }
+
+    async fn update_type_parent(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        parent: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let query = match parent {
+            None => sqlx::query("UPDATE types SET parent = NULL WHERE type_id = $1").bind(type_id),
+            Some(parent) => sqlx::query("UPDATE types SET parent = $1 WHERE type_id = $2")
+                .bind(parent.to_owned())
+                .bind(type_id),
+        };
+        execute(transaction, query).await?;
+        Ok(())
+    }
+
+    async fn update_type_multi_tenant(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        multi_tenant: bool,
+    ) -> anyhow::Result<()> {
+        let query = sqlx::query("UPDATE types SET multi_tenant = $1::bool WHERE type_id = $2")
+            .bind(multi_tenant)
+            .bind(type_id);
+        execute(transaction, query).await?;
+        Ok(())
+    }
+
+    async fn update_type_id_strategy(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        id_strategy: IdStrategy,
+    ) -> anyhow::Result<()> {
+        let query = sqlx::query("UPDATE types SET id_strategy = $1 WHERE type_id = $2")
+            .bind(id_strategy.to_string())
+            .bind(type_id);
+        execute(transaction, query).await?;
+        Ok(())
+    }
+
+    async fn update_type_webhook(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        webhook: Option<&WebhookConfig>,
+    ) -> anyhow::Result<()> {
+        let query = match webhook {
+            None => sqlx::query("UPDATE types SET webhook = NULL WHERE type_id = $1").bind(type_id),
+            Some(webhook) => sqlx::query("UPDATE types SET webhook = $1 WHERE type_id = $2")
+                .bind(serde_json::to_string(webhook)?)
+                .bind(type_id),
+        };
+        execute(transaction, query).await?;
         Ok(())
     }
 
@@ -668,6 +902,114 @@ impl MetaService {
         Ok(())
     }
 
+    /// Reads a value from the server's own key-value bookkeeping table.
+    pub(crate) async fn get_meta_value(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let query = sqlx::query("SELECT value FROM __chiselstrike_meta WHERE key = $1")
+            .bind(key.to_owned());
+        let rows = fetch_all(&self.pool, query).await?;
+        Ok(rows.into_iter().next().map(|row| row.get("value")))
+    }
+
+    pub(crate) async fn set_meta_value(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        key: &str,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        let query = sqlx::query(
+            r#"
+            INSERT INTO __chiselstrike_meta (key, value)
+            VALUES ($1, $2)
+            ON CONFLICT(key) DO UPDATE SET value = $2
+            WHERE __chiselstrike_meta.key = $1"#,
+        )
+        .bind(key.to_owned())
+        .bind(value.to_owned());
+        execute(transaction, query).await?;
+        Ok(())
+    }
+
+    /// Records an attempt at delivering a change to a webhook, and drops the
+    /// records of the type of the delivery but the latest `keep`.
+    pub(crate) async fn insert_webhook_delivery(
+        &self,
+        delivery: &Delivery,
+        keep: usize,
+    ) -> anyhow::Result<()> {
+        // Binding nulls is unreliable, as in update_field_query(), so a
+        // missing status is 0 and a missing error is empty.
+        let query = sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries
+                (type_name, event, url, attempt, status, latency_ms, error, delivered_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+        )
+        .bind(delivery.type_name.clone())
+        .bind(delivery.event.clone())
+        .bind(delivery.url.clone())
+        .bind(delivery.attempt as i32)
+        .bind(delivery.status.unwrap_or(0) as i32)
+        .bind(delivery.latency_ms as i64)
+        .bind(delivery.error.clone().unwrap_or_default())
+        .bind(delivery.delivered_at.clone());
+        let prune = sqlx::query(
+            r#"
+            DELETE FROM webhook_deliveries
+            WHERE type_name = $1 AND delivery_id <= (
+                SELECT delivery_id FROM webhook_deliveries
+                WHERE type_name = $2
+                ORDER BY delivery_id DESC
+                LIMIT 1 OFFSET $3
+            )"#,
+        )
+        .bind(delivery.type_name.clone())
+        .bind(delivery.type_name.clone())
+        .bind(keep as i64);
+        let mut transaction = self.start_transaction().await?;
+        execute(&mut transaction, query).await?;
+        execute(&mut transaction, prune).await?;
+        Self::commit_transaction(transaction).await?;
+        Ok(())
+    }
+
+    /// The latest `limit` attempts at delivering changes to the webhook of
+    /// `type_name`, latest first.
+    pub(crate) async fn webhook_deliveries(
+        &self,
+        type_name: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Delivery>> {
+        let query = sqlx::query(
+            r#"
+            SELECT type_name, event, url, attempt, status, latency_ms, error, delivered_at
+            FROM webhook_deliveries
+            WHERE type_name = $1
+            ORDER BY delivery_id DESC
+            LIMIT $2"#,
+        )
+        .bind(type_name.to_owned())
+        .bind(limit as i64);
+        let rows = fetch_all(&self.pool, query).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let status: i32 = row.get("status");
+                let latency_ms: i64 = row.get("latency_ms");
+                let error: String = row.get("error");
+                Delivery {
+                    type_name: row.get("type_name"),
+                    event: row.get("event"),
+                    url: row.get("url"),
+                    attempt: row.get::<i32, _>("attempt") as u32,
+                    status: (status != 0).then(|| status as u16),
+                    latency_ms: latency_ms as u64,
+                    error: (!error.is_empty()).then(|| error),
+                    delivered_at: row.get("delivered_at"),
+                }
+            })
+            .collect())
+    }
+
     /// Persist a specific policy version.
     ///
     /// We don't have a method that persist all policies, for all versions, because
@@ -734,11 +1076,19 @@ impl MetaService {
         let id: i32 = row.get("type_id");
         let add_type_name = add_type_name.bind(id).bind(ty.persisted_name());
         execute(transaction, add_type_name).await?;
+        Self::update_type_description(transaction, id, ty.description()).await?;
+        Self::update_type_parent(transaction, id, ty.parent()).await?;
+        Self::update_type_multi_tenant(transaction, id, ty.is_multi_tenant()).await?;
+        Self::update_type_id_strategy(transaction, id, ty.id_strategy()).await?;
+        Self::update_type_webhook(transaction, id, ty.webhook()).await?;
 
         for field in ty.user_fields() {
             insert_field_query(transaction, ty, Some(id), field).await?;
         }
         Self::insert_indexes(transaction, id, ty.indexes()).await?;
+        Self::insert_unique_constraints(transaction, id, ty.unique_constraints()).await?;
+        Self::insert_check_constraints(transaction, id, ty.check_constraints()).await?;
+        Self::insert_virtual_relations(transaction, id, ty.virtual_relations()).await?;
         Ok(())
     }
 
@@ -770,14 +1120,126 @@ impl MetaService {
         }
         Ok(())
     }
+
+    /// Constraints without a name are stored with an empty one, as binding
+    /// nulls is unreliable (see update_field_query()).
+    async fn insert_unique_constraints(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        constraints: &[UniqueConstraint],
+    ) -> anyhow::Result<()> {
+        for constraint in constraints {
+            let add_constraint = sqlx::query(
+                "INSERT INTO unique_constraints (type_id, name, fields) VALUES ($1, $2, $3)",
+            )
+            .bind(type_id)
+            .bind(constraint.name.clone().unwrap_or_default())
+            .bind(constraint.fields.join(";"));
+            execute(transaction, add_constraint).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_unique_constraints(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        constraints: &[UniqueConstraint],
+    ) -> anyhow::Result<()> {
+        for constraint in constraints {
+            let del_constraint = sqlx::query(
+                "DELETE FROM unique_constraints WHERE type_id = $1 AND name = $2 AND fields = $3",
+            )
+            .bind(type_id)
+            .bind(constraint.name.clone().unwrap_or_default())
+            .bind(constraint.fields.join(";"));
+            execute(transaction, del_constraint).await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_check_constraints(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        constraints: &[CheckConstraint],
+    ) -> anyhow::Result<()> {
+        for constraint in constraints {
+            let add_constraint = sqlx::query(
+                "INSERT INTO check_constraints (type_id, name, expression) VALUES ($1, $2, $3)",
+            )
+            .bind(type_id)
+            .bind(constraint.name.clone())
+            .bind(constraint.expression.clone());
+            execute(transaction, add_constraint).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_check_constraints(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        constraints: &[CheckConstraint],
+    ) -> anyhow::Result<()> {
+        for constraint in constraints {
+            let del_constraint =
+                sqlx::query("DELETE FROM check_constraints WHERE type_id = $1 AND name = $2")
+                    .bind(type_id)
+                    .bind(constraint.name.clone());
+            execute(transaction, del_constraint).await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_virtual_relations(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        relations: &[VirtualRelation],
+    ) -> anyhow::Result<()> {
+        for relation in relations {
+            let add_relation = sqlx::query(
+                "INSERT INTO virtual_relations (type_id, name, target_type, subquery) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(type_id)
+            .bind(relation.name.clone())
+            .bind(relation.target_type.clone())
+            .bind(relation.subquery.clone());
+            execute(transaction, add_relation).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_virtual_relations(
+        transaction: &mut Transaction<'_, Any>,
+        type_id: i32,
+        relations: &[VirtualRelation],
+    ) -> anyhow::Result<()> {
+        for relation in relations {
+            let del_relation =
+                sqlx::query("DELETE FROM virtual_relations WHERE type_id = $1 AND name = $2")
+                    .bind(type_id)
+                    .bind(relation.name.clone());
+            execute(transaction, del_relation).await?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::datastore::{query::tests::*, QueryEngine};
     use anyhow::Result;
     use tempdir::TempDir;
+    use tempfile::NamedTempFile;
+
+    /// A meta service over a fresh database with the current schema.
+    pub(crate) async fn setup_meta() -> (MetaService, NamedTempFile) {
+        let db_file = NamedTempFile::new().unwrap();
+        let uri = format!("sqlite://{}?mode=rwc", db_file.path().display());
+        let conn = DbConnection::connect(&uri, 1).await.unwrap();
+        let meta = MetaService::local_connection(&conn, 1).await.unwrap();
+        meta.create_schema().await.unwrap();
+        (meta, db_file)
+    }
 
     // test that we can open and successfully evolve 0.6 to the current version
     #[tokio::test]
@@ -932,4 +1394,47 @@ mod tests {
         fs::metadata(meta_path).await.unwrap();
         Ok(())
     }
+
+    #[tokio::test]
+    async fn meta_values() -> Result<()> {
+        let (meta, _db_file) = setup_meta().await;
+        assert_eq!(meta.get_meta_value("key").await?, None);
+
+        let mut transaction = meta.start_transaction().await?;
+        meta.set_meta_value(&mut transaction, "key", "1").await?;
+        meta.set_meta_value(&mut transaction, "key", "2").await?;
+        MetaService::commit_transaction(transaction).await?;
+        assert_eq!(meta.get_meta_value("key").await?, Some("2".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_deliveries_are_pruned() -> Result<()> {
+        let (meta, _db_file) = setup_meta().await;
+        let delivery = |type_name: &str, attempt| Delivery {
+            type_name: type_name.to_owned(),
+            event: "insert".to_owned(),
+            url: "http://localhost/hook".to_owned(),
+            attempt,
+            status: Some(200),
+            latency_ms: 1,
+            error: None,
+            delivered_at: "2022-08-01T00:00:00.000Z".to_owned(),
+        };
+        meta.insert_webhook_delivery(&delivery("Other", 0), 2)
+            .await?;
+        for attempt in 0..3 {
+            meta.insert_webhook_delivery(&delivery("Person", attempt), 2)
+                .await?;
+        }
+        let attempts: Vec<_> = meta
+            .webhook_deliveries("Person", 10)
+            .await?
+            .iter()
+            .map(|d| d.attempt)
+            .collect();
+        assert_eq!(attempts, [2, 1]);
+        assert_eq!(meta.webhook_deliveries("Other", 10).await?.len(), 1);
+        Ok(())
+    }
 }
@@ -27,6 +27,11 @@ enum Types {
     TypeId,
     BackingTable,
     ApiVersion,
+    Description,
+    Parent,
+    MultiTenant,
+    IdStrategy,
+    Webhook,
 }
 
 #[derive(Iden)]
@@ -45,6 +50,11 @@ enum Fields {
     DefaultValue,
     IsOptional,
     IsUnique,
+    JsonName,
+    Description,
+    IsDeprecated,
+    DeprecatedMessage,
+    IsEncrypted,
 }
 
 #[derive(Iden)]
@@ -69,6 +79,34 @@ enum Indexes {
     Fields,
 }
 
+#[derive(Iden)]
+enum UniqueConstraints {
+    Table,
+    ConstraintId,
+    TypeId,
+    Name,
+    Fields,
+}
+
+#[derive(Iden)]
+enum CheckConstraints {
+    Table,
+    ConstraintId,
+    TypeId,
+    Name,
+    Expression,
+}
+
+#[derive(Iden)]
+enum VirtualRelations {
+    Table,
+    RelationId,
+    TypeId,
+    Name,
+    TargetType,
+    Subquery,
+}
+
 #[derive(Iden)]
 enum Sources {
     Table,
@@ -83,7 +121,31 @@ enum Policies {
     PolicyStr,
 }
 
-pub(crate) static CURRENT_VERSION: &str = "0.7";
+/// Every attempt at delivering a change to a webhook, see [`crate::webhooks`].
+#[derive(Iden)]
+enum WebhookDeliveries {
+    Table,
+    DeliveryId,
+    TypeName,
+    Event,
+    Url,
+    Attempt,
+    Status,
+    LatencyMs,
+    Error,
+    DeliveredAt,
+}
+
+/// Free-form key-value store for bookkeeping done by the server itself.
+#[derive(Iden)]
+enum ChiselMeta {
+    #[iden = "__chiselstrike_meta"]
+    Table,
+    Key,
+    Value,
+}
+
+pub(crate) static CURRENT_VERSION: &str = "0.15";
 
 // Evolves from a version and returns the new version it evolved to
 //
@@ -101,6 +163,78 @@ pub(crate) async fn evolve_from(
                 .to_owned()];
             Ok((v, "0.7".to_string()))
         }
+        "0.7" => {
+            let v = vec![Table::alter()
+                .table(Fields::Table)
+                .add_column(ColumnDef::new(Fields::JsonName).text())
+                .to_owned()];
+            Ok((v, "0.8".to_string()))
+        }
+        "0.8" => {
+            let v = vec![
+                Table::alter()
+                    .table(Types::Table)
+                    .add_column(ColumnDef::new(Types::Description).text())
+                    .to_owned(),
+                Table::alter()
+                    .table(Fields::Table)
+                    .add_column(ColumnDef::new(Fields::Description).text())
+                    .to_owned(),
+            ];
+            Ok((v, "0.9".to_string()))
+        }
+        "0.9" => {
+            let v = vec![
+                Table::alter()
+                    .table(Fields::Table)
+                    .add_column(
+                        ColumnDef::new(Fields::IsDeprecated)
+                            .boolean()
+                            .default(false),
+                    )
+                    .to_owned(),
+                Table::alter()
+                    .table(Fields::Table)
+                    .add_column(ColumnDef::new(Fields::DeprecatedMessage).text())
+                    .to_owned(),
+            ];
+            Ok((v, "0.10".to_string()))
+        }
+        "0.10" => {
+            let v = vec![Table::alter()
+                .table(Types::Table)
+                .add_column(ColumnDef::new(Types::Parent).text())
+                .to_owned()];
+            Ok((v, "0.11".to_string()))
+        }
+        "0.11" => {
+            let v = vec![Table::alter()
+                .table(Types::Table)
+                .add_column(ColumnDef::new(Types::MultiTenant).boolean().default(false))
+                .to_owned()];
+            Ok((v, "0.12".to_string()))
+        }
+        "0.12" => {
+            let v = vec![Table::alter()
+                .table(Types::Table)
+                .add_column(ColumnDef::new(Types::IdStrategy).text())
+                .to_owned()];
+            Ok((v, "0.13".to_string()))
+        }
+        "0.13" => {
+            let v = vec![Table::alter()
+                .table(Fields::Table)
+                .add_column(ColumnDef::new(Fields::IsEncrypted).boolean().default(false))
+                .to_owned()];
+            Ok((v, "0.14".to_string()))
+        }
+        "0.14" => {
+            let v = vec![Table::alter()
+                .table(Types::Table)
+                .add_column(ColumnDef::new(Types::Webhook).text())
+                .to_owned()];
+            Ok((v, "0.15".to_string()))
+        }
         v => anyhow::bail!("Don't know how to evolve from version {}", v),
     }
 }
@@ -132,6 +266,11 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         )
         .col(ColumnDef::new(Types::BackingTable).text().unique_key())
         .col(ColumnDef::new(Types::ApiVersion).text().unique_key())
+        .col(ColumnDef::new(Types::Description).text())
+        .col(ColumnDef::new(Types::Parent).text())
+        .col(ColumnDef::new(Types::MultiTenant).boolean().default(false))
+        .col(ColumnDef::new(Types::IdStrategy).text())
+        .col(ColumnDef::new(Types::Webhook).text())
         .to_owned();
     let type_names = Table::create()
         .table(TypeNames::Table)
@@ -158,6 +297,15 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         .col(ColumnDef::new(Fields::DefaultValue).text())
         .col(ColumnDef::new(Fields::IsOptional).boolean())
         .col(ColumnDef::new(Fields::IsUnique).boolean())
+        .col(ColumnDef::new(Fields::JsonName).text())
+        .col(ColumnDef::new(Fields::Description).text())
+        .col(
+            ColumnDef::new(Fields::IsDeprecated)
+                .boolean()
+                .default(false),
+        )
+        .col(ColumnDef::new(Fields::DeprecatedMessage).text())
+        .col(ColumnDef::new(Fields::IsEncrypted).boolean().default(false))
         .col(ColumnDef::new(TypeNames::TypeId).integer())
         .foreign_key(
             ForeignKey::create()
@@ -208,6 +356,64 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
                 .on_delete(ForeignKeyAction::Cascade),
         )
         .to_owned();
+    let unique_constraints = Table::create()
+        .table(UniqueConstraints::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(UniqueConstraints::ConstraintId)
+                .integer()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(UniqueConstraints::TypeId).integer())
+        .col(ColumnDef::new(UniqueConstraints::Name).text())
+        .col(ColumnDef::new(UniqueConstraints::Fields).text())
+        .foreign_key(
+            ForeignKey::create()
+                .from(UniqueConstraints::Table, UniqueConstraints::TypeId)
+                .to(Types::Table, Types::TypeId)
+                .on_delete(ForeignKeyAction::Cascade),
+        )
+        .to_owned();
+    let check_constraints = Table::create()
+        .table(CheckConstraints::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(CheckConstraints::ConstraintId)
+                .integer()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(CheckConstraints::TypeId).integer())
+        .col(ColumnDef::new(CheckConstraints::Name).text())
+        .col(ColumnDef::new(CheckConstraints::Expression).text())
+        .foreign_key(
+            ForeignKey::create()
+                .from(CheckConstraints::Table, CheckConstraints::TypeId)
+                .to(Types::Table, Types::TypeId)
+                .on_delete(ForeignKeyAction::Cascade),
+        )
+        .to_owned();
+    let virtual_relations = Table::create()
+        .table(VirtualRelations::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(VirtualRelations::RelationId)
+                .integer()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(VirtualRelations::TypeId).integer())
+        .col(ColumnDef::new(VirtualRelations::Name).text())
+        .col(ColumnDef::new(VirtualRelations::TargetType).text())
+        .col(ColumnDef::new(VirtualRelations::Subquery).text())
+        .foreign_key(
+            ForeignKey::create()
+                .from(VirtualRelations::Table, VirtualRelations::TypeId)
+                .to(Types::Table, Types::TypeId)
+                .on_delete(ForeignKeyAction::Cascade),
+        )
+        .to_owned();
     let sources = Table::create()
         .table(Sources::Table)
         .if_not_exists()
@@ -222,6 +428,32 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         .col(ColumnDef::new(Policies::PolicyStr).text())
         .to_owned();
 
+    let chisel_meta = Table::create()
+        .table(ChiselMeta::Table)
+        .if_not_exists()
+        .col(ColumnDef::new(ChiselMeta::Key).text().unique_key())
+        .col(ColumnDef::new(ChiselMeta::Value).text())
+        .to_owned();
+
+    let webhook_deliveries = Table::create()
+        .table(WebhookDeliveries::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(WebhookDeliveries::DeliveryId)
+                .integer()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(WebhookDeliveries::TypeName).text())
+        .col(ColumnDef::new(WebhookDeliveries::Event).text())
+        .col(ColumnDef::new(WebhookDeliveries::Url).text())
+        .col(ColumnDef::new(WebhookDeliveries::Attempt).integer())
+        .col(ColumnDef::new(WebhookDeliveries::Status).integer())
+        .col(ColumnDef::new(WebhookDeliveries::LatencyMs).big_integer())
+        .col(ColumnDef::new(WebhookDeliveries::Error).text())
+        .col(ColumnDef::new(WebhookDeliveries::DeliveredAt).text())
+        .to_owned();
+
     vec![
         version,
         api_info,
@@ -231,7 +463,12 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         type_fields,
         field_labels,
         indexes,
+        unique_constraints,
+        check_constraints,
+        virtual_relations,
         sources,
         policies,
+        chisel_meta,
+        webhook_deliveries,
     ]
 }
@@ -54,12 +54,17 @@
 
 pub(crate) mod crud;
 mod dbconn;
+pub(crate) mod encryption;
 pub(crate) mod engine;
 pub(crate) mod expr;
+pub(crate) mod ids;
 pub(crate) mod meta;
 pub(crate) mod query;
+pub(crate) mod snapshot;
+pub(crate) mod watch;
 
 pub(crate) use dbconn::DbConnection;
 pub(crate) use dbconn::Kind;
+pub(crate) use dbconn::PoolConfig;
 pub(crate) use engine::QueryEngine;
 pub(crate) use meta::MetaService;
@@ -1,8 +1,10 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::auth::AUTH_USER_NAME;
-use crate::datastore::expr::{BinaryExpr, Expr, Literal, PropertyAccess};
+use crate::datastore::engine::QueryError;
+use crate::datastore::expr::{BinaryExpr, BinaryOp, Expr, Literal, PropertyAccess};
 use crate::policies::{FieldPolicies, Policies};
+use crate::tenancy;
 use crate::types::{Field, ObjectType, Type, TypeSystem};
 
 use anyhow::{anyhow, Context, Result};
@@ -27,6 +29,38 @@ impl From<&str> for SqlValue {
     }
 }
 
+/// The filter keeping only the rows of `ty` that belong to `tenant_id`, if
+/// `ty` is multi-tenant. Unless the server serves tenants, requests aren't made
+/// for one and see every row; if it does, a query made for no tenant fails
+/// rather than seeing the rows of all of them.
+fn tenant_filter(
+    ty: &ObjectType,
+    tenant_id: Option<&str>,
+    serves_tenants: bool,
+) -> Result<Option<Expr>> {
+    if !ty.is_multi_tenant() {
+        return Ok(None);
+    }
+    let field = ty.tenant_id_field()?;
+    let tenant_id = match (tenant_id, serves_tenants) {
+        (Some(tenant_id), _) => tenant_id,
+        (None, false) => return Ok(None),
+        (None, true) => anyhow::bail!(
+            "query of multi-tenant type {} is made for no tenant",
+            ty.name()
+        ),
+    };
+    let property_access = PropertyAccess {
+        property: field.name.to_owned(),
+        object: Expr::Parameter { position: 0 }.into(),
+    };
+    let tenant_id: Literal = tenant_id.into();
+    Ok(Some(BinaryExpr::eq(
+        property_access.into(),
+        tenant_id.into(),
+    )))
+}
+
 /// RequestContext bears a mix of contextual variables used by QueryPlan
 /// and Mutations.
 pub(crate) struct RequestContext<'a> {
@@ -38,6 +72,11 @@ pub(crate) struct RequestContext<'a> {
     pub api_version: String,
     /// Id of user making the request.
     pub user_id: Option<String>,
+    /// Id of the tenant the request is made for, see [`crate::tenancy`].
+    pub tenant_id: Option<String>,
+    /// Region the request comes from, whose database replica it reads from,
+    /// see [`crate::region`]. None once the request wrote.
+    pub region: Option<String>,
     /// Current URL path from which this request originated.
     pub path: String,
     /// Current HTTP headers.
@@ -55,11 +94,13 @@ impl RequestContext<'_> {
 #[derive(Debug, Clone)]
 pub(crate) enum QueryField {
     Scalar {
-        /// Name of the original Type field
+        /// Name of the original Type field in JSON, see [`Field::json_name`].
         name: String,
         /// Type of the field
         type_: Type,
         is_optional: bool,
+        /// Whether the column holds the field encrypted, see [`Field::is_encrypted`].
+        is_encrypted: bool,
         /// Index of a column containing this field in the resulting row we get from
         /// the database.
         column_idx: usize,
@@ -67,7 +108,7 @@ pub(crate) enum QueryField {
         transform: Option<fn(Value) -> Value>,
     },
     Entity {
-        /// Name of the original Type field
+        /// Name of the original Type field in JSON, see [`Field::json_name`].
         name: String,
         is_optional: bool,
         /// Policy transformation to be applied on the resulting JSON value.
@@ -104,18 +145,29 @@ pub(crate) struct QueriedEntity {
     ty: Arc<ObjectType>,
     /// Alias name of this entity to be used in SQL query.
     table_alias: String,
-    /// Map from Entity field name to joined Entities which correspond to the entities
-    /// stored under the field name.
+    /// Map from Entity field JSON name to joined Entities which correspond to the entities
+    /// stored under the field.
     joins: HashMap<String, Join>,
 }
 
 impl QueriedEntity {
+    pub(crate) fn ty(&self) -> &ObjectType {
+        &self.ty
+    }
+
     pub(crate) fn get_child_entity<'a>(&'a self, child_name: &str) -> Option<&'a QueriedEntity> {
         self.joins.get(child_name).map(|c| &c.entity)
     }
 
-    fn has_field(&self, field_name: &str) -> bool {
-        self.ty.all_fields().any(|field| field.name == field_name)
+    /// The field `name` refers to, by either its column or its JSON name.
+    fn lookup_field(&self, name: &str) -> Result<&Field> {
+        self.ty.lookup_field(name)?.ok_or_else(|| {
+            anyhow!(
+                "expression error: entity '{}' doesn't have field '{}'",
+                self.ty.name(),
+                name
+            )
+        })
     }
 }
 
@@ -223,9 +275,24 @@ pub(crate) struct QueryPlan {
     join_counter: usize,
     /// Operators used to mutate the result set.
     operators: Vec<QueryOp>,
+    /// Region of the request the plan is made for, see [`RequestContext::region`].
+    region: Option<String>,
 }
 
 impl QueryPlan {
+    /// The region whose database replica the plan reads from, if any.
+    pub(crate) fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// Whether the plan returns every row of its base type, policies included.
+    pub(crate) fn is_unfiltered(&self) -> bool {
+        !self
+            .operators
+            .iter()
+            .any(|op| matches!(op, QueryOp::Filter { .. }))
+    }
+
     fn new(base_type: Arc<ObjectType>) -> Self {
         Self {
             columns: vec![],
@@ -238,10 +305,11 @@ impl QueryPlan {
             allowed_fields: None,
             join_counter: 0,
             operators: vec![],
+            region: None,
         }
     }
 
-    fn base_type(&self) -> &Arc<ObjectType> {
+    pub(crate) fn base_type(&self) -> &Arc<ObjectType> {
         &self.entity.ty
     }
 
@@ -262,6 +330,47 @@ impl QueryPlan {
         builder
     }
 
+    /// Narrows the plan down to the entity whose id is `id`.
+    pub(crate) fn filter_by_id(self, id: &str) -> Self {
+        self.filter_eq("id", Literal::String(id.to_owned()).into())
+    }
+
+    /// Narrows the plan down to the entities whose `field` equals `value`.
+    pub(crate) fn filter_eq(mut self, field: &str, value: Expr) -> Self {
+        let property = PropertyAccess {
+            property: field.to_owned(),
+            object: Box::new(Expr::Parameter { position: 0 }),
+        };
+        let expression = BinaryExpr::new(BinaryOp::Eq, property.into(), value);
+        self.extend_operators(vec![QueryOp::Filter {
+            expression: expression.into(),
+        }]);
+        self
+    }
+
+    /// Narrows the plan down to the entities whose `field` is at least
+    /// `value`, sorted by that field.
+    pub(crate) fn filter_since(mut self, field: &str, value: f64) -> Self {
+        let property = PropertyAccess {
+            property: field.to_owned(),
+            object: Box::new(Expr::Parameter { position: 0 }),
+        };
+        let expression =
+            BinaryExpr::new(BinaryOp::GtEq, property.into(), Literal::F64(value).into());
+        self.extend_operators(vec![
+            QueryOp::Filter {
+                expression: expression.into(),
+            },
+            QueryOp::SortBy(SortBy {
+                keys: vec![SortKey {
+                    field_name: field.to_owned(),
+                    ascending: true,
+                }],
+            }),
+        ]);
+        self
+    }
+
     fn from_entity_name(c: &RequestContext, entity_name: &str) -> Result<Self> {
         let ty = c
             .ts
@@ -271,7 +380,7 @@ impl QueryPlan {
             })?;
 
         let mut builder = Self::new(ty.clone());
-        builder.entity = builder.load_entity(c, &ty);
+        builder.entity = builder.load_entity(c, &ty)?;
         Ok(builder)
     }
 
@@ -283,7 +392,7 @@ impl QueryPlan {
         operators: Vec<QueryOp>,
     ) -> Result<Self> {
         let mut query_plan = Self::new(ty.clone());
-        query_plan.entity = query_plan.load_entity(c, ty);
+        query_plan.entity = query_plan.load_entity(c, ty)?;
         query_plan.extend_operators(operators);
         Ok(query_plan)
     }
@@ -294,6 +403,10 @@ impl QueryPlan {
     pub(crate) fn from_op_chain(context: &RequestContext, op_chain: QueryOpChain) -> Result<Self> {
         let (entity_name, operators) = convert_ops(op_chain)?;
         let mut builder = Self::from_entity_name(context, &entity_name)?;
+        // For ChiselEntity.save() to detect conflicting updates.
+        let ty = builder.base_type().clone();
+        let version = builder.make_scalar_field(ty.version_field(), ty.backing_table(), None);
+        builder.entity.fields.push(version);
 
         builder.extend_operators(operators);
         Ok(builder)
@@ -324,9 +437,10 @@ impl QueryPlan {
     ) -> QueryField {
         let column_idx = self.columns.len();
         let select_field = QueryField::Scalar {
-            name: field.name.clone(),
+            name: field.json_name().to_owned(),
             type_: field.type_.clone(),
             is_optional: field.is_optional,
+            is_encrypted: field.is_encrypted,
             column_idx,
             transform,
         };
@@ -339,10 +453,19 @@ impl QueryPlan {
     }
 
     /// Prepares the retrieval of Entity of type `ty` from the database and
-    /// ensures login restrictions are respected.
-    fn load_entity(&mut self, context: &RequestContext, ty: &Arc<ObjectType>) -> QueriedEntity {
+    /// ensures login and tenant restrictions are respected, see [`tenant_filter`].
+    fn load_entity(
+        &mut self,
+        context: &RequestContext,
+        ty: &Arc<ObjectType>,
+    ) -> Result<QueriedEntity> {
+        self.region = context.region.clone();
         self.add_login_filters_recursive(context, ty, Expr::Parameter { position: 0 });
-        self.load_entity_recursive(context, ty, ty.backing_table())
+        let tenant_id = context.tenant_id.as_deref();
+        if let Some(expression) = tenant_filter(ty, tenant_id, tenancy::is_enabled())? {
+            self.operators.push(QueryOp::Filter { expression });
+        }
+        Ok(self.load_entity_recursive(context, ty, ty.backing_table()))
     }
 
     /// Loads QueriedEntity for a given type `ty` to be retrieved from the
@@ -359,7 +482,7 @@ impl QueryPlan {
         let mut fields = vec![];
         let mut joins = HashMap::default();
         for field in ty.all_fields() {
-            let field_policy = field_policies.transforms.get(&field.name).cloned();
+            let field_policy = field_policies.transforms.get(field.json_name()).cloned();
 
             let query_field = if let Type::Object(nested_ty) = &field.type_ {
                 let nested_table = format!(
@@ -374,7 +497,7 @@ impl QueryPlan {
 
                 self.make_scalar_field(field, current_table, field_policy);
                 joins.insert(
-                    field.name.to_owned(),
+                    field.json_name().to_owned(),
                     Join {
                         entity: self.load_entity_recursive(context, nested_ty, &nested_table),
                         lkey: field.name.to_owned(),
@@ -382,7 +505,7 @@ impl QueryPlan {
                     },
                 );
                 QueryField::Entity {
-                    name: field.name.clone(),
+                    name: field.json_name().to_owned(),
                     is_optional: field.is_optional,
                     transform: field_policy,
                 }
@@ -421,7 +544,7 @@ impl QueryPlan {
                     object: property_chain.clone().into(),
                 };
                 if nested_ty.name() == AUTH_USER_NAME {
-                    if field_policies.match_login.contains(&field.name) {
+                    if field_policies.match_login.contains(field.json_name()) {
                         let expr = BinaryExpr::eq(property_access.into(), user_id.clone().into());
                         self.operators.push(QueryOp::Filter { expression: expr });
                     }
@@ -471,9 +594,9 @@ impl QueryPlan {
         gather_joins(&self.entity)
     }
 
-    fn make_filter_string(&self, expr: &Option<Expr>) -> Result<String> {
+    fn make_filter_string(&self, target: &TargetDatabase, expr: &Option<Expr>) -> Result<String> {
         let where_cond = if let Some(expr) = expr {
-            let condition = self.filter_expr_to_string(expr)?;
+            let condition = self.filter_expr_to_string(target, expr)?;
             format!("WHERE {}", condition)
         } else {
             "".to_owned()
@@ -481,31 +604,107 @@ impl QueryPlan {
         Ok(where_cond)
     }
 
-    fn filter_expr_to_string(&self, expr: &Expr) -> Result<String> {
+    fn filter_expr_to_string(&self, target: &TargetDatabase, expr: &Expr) -> Result<String> {
         let expr_str = match &expr {
-            Expr::Literal { value } => match &value {
-                Literal::Bool(lit) => (if *lit { "true" } else { "false" }).to_string(),
-                Literal::U64(lit) => lit.to_string(),
-                Literal::I64(lit) => lit.to_string(),
-                Literal::F64(lit) => lit.to_string(),
-                Literal::String(lit) => escape_string(lit),
-                Literal::Null => "NULL".to_string(),
-            },
-            Expr::Binary(binary_exp) => {
-                format!(
+            Expr::Literal { value } => literal_to_string(value)?,
+            Expr::Binary(binary_exp)
+                if matches!(
+                    binary_exp.op,
+                    BinaryOp::Contains | BinaryOp::Overlaps | BinaryOp::HasKey
+                ) =>
+            {
+                self.membership_expr_to_string(target, binary_exp)?
+            }
+            Expr::Binary(binary_exp) => match self.map_key_expr_to_string(target, binary_exp)? {
+                Some(condition) => condition,
+                None => format!(
                     "({} {} {})",
-                    self.filter_expr_to_string(&binary_exp.left)?,
+                    self.filter_expr_to_string(target, &binary_exp.left)?,
                     binary_exp.op.to_sql_string(),
-                    self.filter_expr_to_string(&binary_exp.right)?,
-                )
-            }
-            Expr::Property(property) => self.property_expr_to_string(property)?,
+                    self.filter_expr_to_string(target, &binary_exp.right)?,
+                ),
+            },
+            Expr::Property(property) => self.property_expr_to_string(property)?.1,
             Expr::Parameter { .. } => anyhow::bail!("unexpected standalone parameter usage"),
         };
         Ok(expr_str)
     }
 
-    fn property_expr_to_string(&self, prop_access: &PropertyAccess) -> Result<String> {
+    /// What a `Contains`, `Overlaps` or `HasKey` expression means depends on
+    /// the type of the property on its left.
+    fn membership_expr_to_string(
+        &self,
+        target: &TargetDatabase,
+        expr: &BinaryExpr,
+    ) -> Result<String> {
+        let (field, column) = match &*expr.left {
+            Expr::Property(property) => self.property_expr_to_string(property)?,
+            _ => anyhow::bail!("{:?} takes a property on its left", expr.op),
+        };
+        let value = match &*expr.right {
+            Expr::Literal { value } => value,
+            _ => anyhow::bail!("{:?} takes a literal on its right", expr.op),
+        };
+        match (&field.type_, &expr.op, value) {
+            (Type::String, BinaryOp::Contains, Literal::String(substring)) => {
+                // LIKE wildcards in the substring are not escaped.
+                let pattern = escape_string(&format!("%{}%", substring));
+                Ok(format!("({} LIKE {})", column, pattern))
+            }
+            (Type::Array(_), BinaryOp::Contains, value) if !matches!(value, Literal::Array(_)) => {
+                array_overlaps(target, &column, std::slice::from_ref(value))
+            }
+            (Type::Array(_), BinaryOp::Overlaps, Literal::Array(values)) => {
+                array_overlaps(target, &column, values)
+            }
+            (Type::Map(_), BinaryOp::HasKey, Literal::String(key)) => {
+                map_has_key(target, &column, key)
+            }
+            _ => anyhow::bail!(
+                "expression error: field '{}' of type {} can't be filtered with {:?} {:?}",
+                field.name,
+                field.type_.name(),
+                expr.op,
+                value
+            ),
+        }
+    }
+
+    /// The condition of `expr` if its left is a key of a map field, as in
+    /// `meta.color == "red"`.
+    fn map_key_expr_to_string(
+        &self,
+        target: &TargetDatabase,
+        expr: &BinaryExpr,
+    ) -> Result<Option<String>> {
+        let (map, key) = match &*expr.left {
+            Expr::Property(PropertyAccess { property, object }) => match &**object {
+                Expr::Property(map) => (map, property),
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        let (field, column) = self.property_expr_to_string(map)?;
+        if !matches!(field.type_, Type::Map(_)) {
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            matches!(expr.op, BinaryOp::Eq),
+            "expression error: keys of map field '{}' can only be compared for equality",
+            field.name
+        );
+        let value = match &*expr.right {
+            Expr::Literal { value } => value,
+            _ => anyhow::bail!(
+                "keys of map field '{}' can only be compared with literals",
+                field.name
+            ),
+        };
+        map_key_eq(target, &column, key, value).map(Some)
+    }
+
+    /// The field `prop_access` refers to, and its column in the query.
+    fn property_expr_to_string(&self, prop_access: &PropertyAccess) -> Result<(&Field, String)> {
         fn get_property_chain(prop_access: &PropertyAccess) -> Result<Vec<String>> {
             match &*prop_access.object {
                 Expr::Property(obj) => {
@@ -520,56 +719,55 @@ impl QueryPlan {
         let properties = get_property_chain(prop_access)?;
         assert!(!properties.is_empty());
 
-        let check_field = |entity: &QueriedEntity, field| {
-            anyhow::ensure!(
-                entity.has_field(field),
-                "expression error: entity '{}' doesn't have field '{}'",
-                entity.ty.name(),
-                field
-            );
-            Ok(())
-        };
-
-        let mut field = &properties[0];
         let mut entity = &self.entity;
-        check_field(entity, field)?;
-
+        let mut field = entity.lookup_field(&properties[0])?;
         for next_field in &properties[1..] {
+            anyhow::ensure!(
+                !matches!(field.type_, Type::Map(_)),
+                "expression error: keys of map field '{}' can only be compared for equality",
+                field.name
+            );
             entity = &entity
                 .joins
-                .get(field)
+                .get(field.json_name())
                 .ok_or_else(|| {
                     anyhow!(
                         "expression error: unable to locate joined entity on field {}",
-                        field
+                        field.name
                     )
                 })?
                 .entity;
-            field = next_field;
-            check_field(entity, field)?;
+            field = entity.lookup_field(next_field)?;
+        }
+        if field.is_encrypted {
+            return Err(QueryError::EncryptedField(field.name.clone()).into());
         }
         let c_alias = ColumnAlias {
-            field_name: field.to_owned(),
+            field_name: field.name.to_owned(),
             table_name: entity.table_alias.to_owned(),
         };
 
-        Ok(format!("\"{}\"", c_alias))
+        Ok((field, format!("\"{}\"", c_alias)))
     }
 
     fn make_sort_string(&self, sort: Option<&SortBy>) -> Result<String> {
         let sort_str = if let Some(sort) = sort {
             let mut order_tokens = vec![];
             for sort_key in &sort.keys {
-                if !self.base_type().has_field(&sort_key.field_name) {
-                    anyhow::bail!(
+                let field = match self.base_type().lookup_field(&sort_key.field_name)? {
+                    Some(field) => field,
+                    None => anyhow::bail!(
                         "entity '{}' has no field named '{}'",
                         self.base_type().name(),
                         sort_key.field_name
-                    );
+                    ),
+                };
+                if field.is_encrypted {
+                    return Err(QueryError::EncryptedField(field.name.clone()).into());
                 }
                 let order = if sort_key.ascending { "ASC" } else { "DESC" };
                 let c_alias = ColumnAlias {
-                    field_name: sort_key.field_name.to_owned(),
+                    field_name: field.name.to_owned(),
                     table_name: self.base_type().backing_table().to_owned(),
                 };
                 order_tokens.push(format!("\"{c_alias}\" {order}"));
@@ -597,15 +795,12 @@ impl QueryPlan {
         }
     }
 
-    fn make_core_select(&self) -> String {
+    /// The SELECT of the columns of the plan. `source` is the backing table
+    /// of the base type, or a subquery aliased as it.
+    fn make_core_select(&self, source: &str) -> String {
         let column_string = self.make_column_string();
         let join_string = self.make_join_string();
-        format!(
-            "SELECT {} FROM \"{}\" {}",
-            column_string,
-            self.base_type().backing_table(),
-            join_string,
-        )
+        format!("SELECT {} FROM {} {}", column_string, source, join_string)
     }
 
     /// Splits the operators' slice at a first occurrence of Take or Skip (break) operator into two slices
@@ -658,15 +853,15 @@ impl QueryPlan {
             .map(|op| *op.as_skip().unwrap())
     }
 
-    fn make_raw_query(&self, target: &TargetDatabase) -> Result<String> {
-        let mut sql_query = self.make_core_select();
+    fn make_raw_query(&self, target: &TargetDatabase, source: &str) -> Result<String> {
+        let mut sql_query = self.make_core_select(source);
         let mut remaining_ops: &[QueryOp] = &self.operators[..];
         while !remaining_ops.is_empty() {
             let (ops, remainder) = self.split_on_first_take(remaining_ops);
             remaining_ops = remainder;
 
             let filter_expr = self.gather_filters(ops);
-            let filter_string = self.make_filter_string(&filter_expr)?;
+            let filter_string = self.make_filter_string(target, &filter_expr)?;
 
             let sort = self.find_last_sort_by(ops);
             let sort_string = self.make_sort_string(sort)?;
@@ -685,8 +880,31 @@ impl QueryPlan {
     }
 
     pub(crate) fn build_query(&self, target: &TargetDatabase) -> Result<Query> {
+        let source = format!("\"{}\"", self.base_type().backing_table());
         Ok(Query {
-            raw_sql: self.make_raw_query(target)?,
+            raw_sql: self.make_raw_query(target, &source)?,
+            entity: self.entity.clone(),
+            allowed_fields: self.allowed_fields.clone(),
+        })
+    }
+
+    /// Builds the query of a [`VirtualRelation`](crate::types::VirtualRelation)
+    /// for the entity `parent_id`: the plan only sees the rows of its base type
+    /// that the relation's `subquery` picks.
+    pub(crate) fn build_related_query(
+        &self,
+        target: &TargetDatabase,
+        subquery: &str,
+        parent_id: &str,
+    ) -> Result<Query> {
+        let table = self.base_type().backing_table();
+        let condition = subquery.replace("$id", &escape_string(parent_id));
+        let source = format!(
+            "(SELECT * FROM \"{}\" WHERE {}) AS \"{}\"",
+            table, condition, table
+        );
+        Ok(Query {
+            raw_sql: self.make_raw_query(target, &source)?,
             entity: self.entity.clone(),
             allowed_fields: self.allowed_fields.clone(),
         })
@@ -694,10 +912,94 @@ impl QueryPlan {
 }
 
 // FIXME: We should use prepared statements instead
-fn escape_string(s: &str) -> String {
+pub(crate) fn escape_string(s: &str) -> String {
     format!("{}", format_sql_query::QuotedData(s))
 }
 
+fn literal_to_string(literal: &Literal) -> Result<String> {
+    Ok(match literal {
+        Literal::Bool(lit) => (if *lit { "true" } else { "false" }).to_string(),
+        Literal::U64(lit) => lit.to_string(),
+        Literal::I64(lit) => lit.to_string(),
+        Literal::F64(lit) => lit.to_string(),
+        Literal::String(lit) => escape_string(lit),
+        Literal::Array(_) => anyhow::bail!("array literals only go with Overlaps"),
+        Literal::Null => "NULL".to_string(),
+    })
+}
+
+/// The JSON path of `key` in an object, as SQLite takes it.
+fn json_path(key: &str) -> Result<String> {
+    anyhow::ensure!(
+        !key.contains('"'),
+        "map keys with double quotes can't be filtered by"
+    );
+    Ok(escape_string(&format!("$.\"{}\"", key)))
+}
+
+/// A condition on the JSON object in `column` having `key`.
+fn map_has_key(target: &TargetDatabase, column: &str, key: &str) -> Result<String> {
+    Ok(match target {
+        TargetDatabase::Sqlite => {
+            format!("(json_type({}, {}) IS NOT NULL)", column, json_path(key)?)
+        }
+        TargetDatabase::Postgres => format!("(({})::jsonb ? {})", column, escape_string(key)),
+    })
+}
+
+/// A condition on the JSON object in `column` having `value` at `key`.
+fn map_key_eq(target: &TargetDatabase, column: &str, key: &str, value: &Literal) -> Result<String> {
+    Ok(match target {
+        TargetDatabase::Sqlite => format!(
+            "(json_extract({}, {}) = {})",
+            column,
+            json_path(key)?,
+            literal_to_string(value)?
+        ),
+        TargetDatabase::Postgres => format!(
+            "((({})::jsonb -> {}) = {}::jsonb)",
+            column,
+            escape_string(key),
+            escape_string(&serde_json::to_string(value)?)
+        ),
+    })
+}
+
+/// A condition on the JSON array in `column` having any of `values`.
+fn array_overlaps(target: &TargetDatabase, column: &str, values: &[Literal]) -> Result<String> {
+    if values.is_empty() {
+        return Ok("false".into());
+    }
+    let condition = match target {
+        TargetDatabase::Sqlite => {
+            let values = values
+                .iter()
+                .map(literal_to_string)
+                .collect::<Result<Vec<_>>>()?;
+            format!(
+                "EXISTS (SELECT 1 FROM json_each({}) WHERE json_each.value IN ({}))",
+                column,
+                values.join(", ")
+            )
+        }
+        TargetDatabase::Postgres => {
+            // Containment of one-element arrays compares numbers as numbers,
+            // which text comparison of the elements wouldn't.
+            let values = values
+                .iter()
+                .map(|v| {
+                    Ok(format!(
+                        "{}::jsonb",
+                        escape_string(&serde_json::to_string(&[v])?)
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            format!("(({})::jsonb @> ANY(ARRAY[{}]))", column, values.join(", "))
+        }
+    };
+    Ok(condition)
+}
+
 /// Returns the longest possible prefix of `s` that is at most `max_len`
 /// bytes long and ends at a character boundary so that we don't break
 /// multi-byte characters.
@@ -779,6 +1081,10 @@ pub(crate) struct Mutation {
 }
 
 impl Mutation {
+    pub(crate) fn base_type(&self) -> &ObjectType {
+        &self.base_entity
+    }
+
     /// Constructs delete from filter expression.
     pub(crate) fn delete_from_expr(
         c: &RequestContext,
@@ -827,10 +1133,14 @@ pub(crate) mod tests {
     use futures::StreamExt;
     use once_cell::sync::Lazy;
     use serde_json::json;
+    use sqlx::Row;
     use tempfile::NamedTempFile;
 
+    use crate::datastore::encryption;
+    use crate::datastore::engine::SqlWithArguments;
     use crate::datastore::expr::BinaryOp;
-    use crate::datastore::{DbConnection, QueryEngine};
+    use crate::datastore::{DbConnection, PoolConfig, QueryEngine};
+    use crate::migrations;
     use crate::types;
     use crate::JsonObject;
 
@@ -901,7 +1211,7 @@ pub(crate) mod tests {
         let rows = fetch_rows(query_engine, entity).await;
         assert!(rows.iter().any(|row| {
             ins_row.iter().all(|(key, value)| {
-                if let Type::Object(_) = entity.get_field(key).unwrap().type_ {
+                if let Type::Object(_) = entity.get_field_by_json_name(key).unwrap().type_ {
                     true
                 } else {
                     row[key] == *value
@@ -957,6 +1267,8 @@ pub(crate) mod tests {
                     ts: &make_type_system(&*ENTITIES),
                     api_version: VERSION.to_owned(),
                     user_id: None,
+                    tenant_id: None,
+                    region: None,
                     path: "".to_string(),
                     headers: HashMap::default(),
                 },
@@ -1016,6 +1328,214 @@ pub(crate) mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_tenant_filter() {
+        let project_ty = Arc::new(
+            ObjectType::new(
+                types::NewObject::new("Project", VERSION),
+                vec![
+                    make_field("name", Type::String),
+                    make_field("tenant_id", Type::String),
+                ],
+                vec![],
+                types::AuthOrNot::IsNotAuth,
+            )
+            .unwrap()
+            .with_multi_tenant(true)
+            .unwrap(),
+        );
+        let (qe, _db_file) = setup_clear_db(&[project_ty.clone()]).await;
+        for (name, tenant) in [("alpha", "t1"), ("beta", "t2"), ("gamma", "t1")] {
+            add_row(
+                &qe,
+                &project_ty,
+                &json!({"name": name, "tenant_id": tenant}),
+            )
+            .await;
+        }
+
+        let err = tenant_filter(&project_ty, None, true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "query of multi-tenant type Project is made for no tenant"
+        );
+
+        let ts = make_type_system(&[project_ty]);
+        let fetch_names = |tenant_id: Option<&str>| {
+            let query_plan = QueryPlan::from_op_chain(
+                &RequestContext {
+                    policies: &Policies::default(),
+                    ts: &ts,
+                    api_version: VERSION.to_owned(),
+                    user_id: None,
+                    tenant_id: tenant_id.map(str::to_owned),
+                    region: None,
+                    path: "".to_string(),
+                    headers: HashMap::default(),
+                },
+                QueryOpChain::BaseEntity {
+                    name: "Project".to_owned(),
+                },
+            )
+            .unwrap();
+            let qe = qe.clone();
+            async move {
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                let mut names: Vec<_> = rows
+                    .iter()
+                    .map(|r| r["name"].as_str().unwrap().to_owned())
+                    .collect();
+                names.sort();
+                names
+            }
+        };
+        assert_eq!(fetch_names(Some("t1")).await, vec!["alpha", "gamma"]);
+        assert_eq!(fetch_names(Some("t2")).await, vec!["beta"]);
+        assert!(fetch_names(Some("t3")).await.is_empty());
+        assert_eq!(fetch_names(None).await, vec!["alpha", "beta", "gamma"]);
+    }
+
+    #[tokio::test]
+    async fn test_auto_increment_ids() {
+        let ticket_ty = Arc::new(
+            ObjectType::new(
+                types::NewObject::new("Ticket", VERSION),
+                vec![make_field("title", Type::String)],
+                vec![],
+                types::AuthOrNot::IsNotAuth,
+            )
+            .unwrap()
+            .with_id_strategy(types::IdStrategy::AutoIncrement),
+        );
+        let (qe, _db_file) = setup_clear_db(&[ticket_ty.clone()]).await;
+        // Rows from before the type was auto-incremented keep their ids.
+        let uuid = uuid::Uuid::new_v4().to_string();
+        add_row(&qe, &ticket_ty, &json!({"id": uuid, "title": "old"})).await;
+        let mut ids = vec![];
+        for title in ["a", "b"] {
+            let row = json!({ "title": title });
+            let id_tree = qe
+                .add_row(&ticket_ty, row.as_object().unwrap(), None)
+                .await
+                .unwrap();
+            ids.push(id_tree.id);
+        }
+        assert_eq!(ids, ["1", "2"]);
+        add_row(&qe, &ticket_ty, &json!({"id": "10", "title": "c"})).await;
+        let row = json!({"title": "d"});
+        let id_tree = qe
+            .add_row(&ticket_ty, row.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(id_tree.id, "11");
+        let row = json!({"id": "not an id", "title": "e"});
+        qe.add_row(&ticket_ty, row.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let db_file = NamedTempFile::new().unwrap();
+        let db_uri = format!("sqlite://{}?mode=rwc", db_file.path().to_string_lossy());
+        let config = PoolConfig {
+            nr_connections: 1,
+            query_timeout: std::time::Duration::from_millis(10),
+            lazy_migrations: false,
+            namespace: None,
+        };
+        let conn = DbConnection::connect_with(&db_uri, &config).await.unwrap();
+        let qe = QueryEngine::local_connection(&conn, 1).await.unwrap();
+        let timeouts = crate::metrics::query_timeouts();
+
+        let slow = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c \
+                    WHERE x < 100000000) SELECT count(*) FROM c";
+        let err = qe
+            .fetch_one(SqlWithArguments {
+                sql: slow.to_owned(),
+                args: vec![],
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::Timeout(_))
+        ));
+        assert!(crate::metrics::query_timeouts() > timeouts);
+    }
+
+    #[tokio::test]
+    async fn test_find_or_create() {
+        let unique = |name| {
+            let mut field = make_field(name, Type::String);
+            field.is_unique = true;
+            field
+        };
+        let user_ty = Arc::new(
+            ObjectType::new(
+                types::NewObject::new("User", VERSION),
+                vec![
+                    unique("email"),
+                    unique("handle"),
+                    make_field("name", Type::String),
+                ],
+                vec![],
+                types::AuthOrNot::IsNotAuth,
+            )
+            .unwrap(),
+        );
+        let (qe, _db_file) = setup_clear_db(&[user_ty.clone()]).await;
+        let find_or_create = |search_key: serde_json::Value, defaults: serde_json::Value| {
+            let qe = qe.clone();
+            let user_ty = user_ty.clone();
+            async move {
+                qe.find_or_create(
+                    &user_ty,
+                    search_key.as_object().unwrap(),
+                    defaults.as_object().unwrap(),
+                )
+                .await
+            }
+        };
+
+        let (created, is_new) = find_or_create(
+            json!({"email": "alice@example.com"}),
+            json!({"handle": "alice", "name": "Alice"}),
+        )
+        .await
+        .unwrap();
+        assert!(is_new);
+        assert_eq!(created["email"], "alice@example.com");
+        assert_eq!(created["name"], "Alice");
+
+        let (found, is_new) = find_or_create(
+            json!({"email": "alice@example.com"}),
+            json!({"handle": "other", "name": "Other"}),
+        )
+        .await
+        .unwrap();
+        assert!(!is_new);
+        assert_eq!(found, created);
+
+        // The new row would take the handle of the one there is.
+        find_or_create(
+            json!({"email": "bob@example.com"}),
+            json!({"handle": "alice", "name": "Bob"}),
+        )
+        .await
+        .unwrap_err();
+        find_or_create(json!({}), json!({"handle": "bob", "name": "Bob"}))
+            .await
+            .unwrap_err();
+        find_or_create(
+            json!({ "email": null }),
+            json!({"handle": "bob", "name": "Bob"}),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(fetch_rows(&qe, &user_ty).await.len(), 1);
+    }
+
     #[tokio::test]
     async fn test_delete_with_expr() {
         let delete_with_expr = |entity_name: &str, expr: Expr| {
@@ -1025,6 +1545,8 @@ pub(crate) mod tests {
                     ts: &make_type_system(&*ENTITIES),
                     api_version: VERSION.to_owned(),
                     user_id: None,
+                    tenant_id: None,
+                    region: None,
                     path: "".to_string(),
                     headers: HashMap::default(),
                 },
@@ -1072,4 +1594,833 @@ pub(crate) mod tests {
             assert_eq!(fetch_rows(&qe, &COMPANY_TY).await.len(), 0);
         }
     }
+
+    #[tokio::test]
+    async fn version_conflict() {
+        let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let person = json!({
+            "id": "8b3d1f4e-4f5a-4c1e-9d2b-1a2b3c4d5e6f",
+            "name": "John",
+            "age": json!(20f32),
+        });
+        let person = person.as_object().unwrap();
+        let qe = &qe;
+        let save =
+            move |expected_version| qe.update_row(&PERSON_TY, person, expected_version, None);
+
+        // Rows start at version zero, and every write moves them one up.
+        save(None).await.unwrap();
+        save(Some(0)).await.unwrap();
+        let err = save(Some(0)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::VersionConflict(..))
+        ));
+        save(Some(1)).await.unwrap();
+        save(None).await.unwrap();
+        save(Some(3)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn clones_share_pool() {
+        let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let clone = qe.clone();
+        let mut transaction = clone.start_transaction().await.unwrap();
+        let person = json!({"name": "John", "age": json!(20f32)});
+        clone
+            .add_row(
+                &PERSON_TY,
+                person.as_object().unwrap(),
+                Some(&mut transaction),
+            )
+            .await
+            .unwrap();
+        QueryEngine::commit_transaction(transaction).await.unwrap();
+        assert_eq!(fetch_rows(&qe, &PERSON_TY).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delete_expected_version() {
+        let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let id = "8b3d1f4e-4f5a-4c1e-9d2b-1a2b3c4d5e6f";
+        let person = json!({"id": id, "name": "John", "age": json!(20f32)});
+        qe.add_row(&PERSON_TY, person.as_object().unwrap(), None)
+            .await
+            .unwrap();
+
+        let mut transaction = qe.start_transaction().await.unwrap();
+        assert!(!qe
+            .delete_row(&PERSON_TY, id, Some(1), &mut transaction)
+            .await
+            .unwrap());
+        assert!(qe
+            .delete_row(&PERSON_TY, id, Some(0), &mut transaction)
+            .await
+            .unwrap());
+        assert!(!qe
+            .delete_row(&PERSON_TY, id, None, &mut transaction)
+            .await
+            .unwrap());
+        QueryEngine::commit_transaction(transaction).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn patch() {
+        let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let id = "8b3d1f4e-4f5a-4c1e-9d2b-1a2b3c4d5e6f";
+        let person = json!({"id": id, "name": "John", "age": json!(20f32)});
+        add_row(&qe, &PERSON_TY, &person).await;
+
+        let mut transaction = qe.start_transaction().await.unwrap();
+        let patch = json!({ "age": json!(21f32) });
+        let patch = patch.as_object().unwrap();
+        assert!(qe
+            .patch_row(&PERSON_TY, id, patch, Some(0), &mut transaction)
+            .await
+            .unwrap());
+        assert!(!qe
+            .patch_row(&PERSON_TY, id, patch, Some(0), &mut transaction)
+            .await
+            .unwrap());
+        let patch = json!({ "name": null });
+        let err = qe
+            .patch_row(
+                &PERSON_TY,
+                id,
+                patch.as_object().unwrap(),
+                None,
+                &mut transaction,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::NotNullable(..))
+        ));
+        QueryEngine::commit_transaction(transaction).await.unwrap();
+
+        let rows = fetch_rows(&qe, &PERSON_TY).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["name"], json!("John"));
+        assert_eq!(rows[0]["age"], json!(21f32));
+    }
+
+    #[tokio::test]
+    async fn json_names() {
+        let post = make_object(
+            "Post",
+            vec![
+                make_field("title", Type::String),
+                make_field("created_at", Type::String).with_json_name(Some("createdAt".into())),
+            ],
+        );
+        let (qe, _db_file) = setup_clear_db(&[post.clone()]).await;
+        add_row(
+            &qe,
+            &post,
+            &json!({"title": "a", "createdAt": "2022-01-01"}),
+        )
+        .await;
+        add_row(
+            &qe,
+            &post,
+            &json!({"title": "b", "createdAt": "2022-02-01"}),
+        )
+        .await;
+        let rows = fetch_rows(&qe, &post).await;
+        assert_eq!(rows[0]["createdAt"], json!("2022-01-01"));
+        assert!(rows[0].get("created_at").is_none());
+
+        let ts = make_type_system(&[post.clone()]);
+        let fetch_titles = |name: &'static str| {
+            let op_chain = QueryOpChain::Filter {
+                expression: binary(&[name], BinaryOp::Eq, "2022-02-01".into()),
+                inner: QueryOpChain::BaseEntity {
+                    name: "Post".to_owned(),
+                }
+                .into(),
+            };
+            let context = RequestContext {
+                policies: &Policies::default(),
+                ts: &ts,
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                tenant_id: None,
+                region: None,
+                path: "".to_string(),
+                headers: HashMap::default(),
+            };
+            let query_plan = QueryPlan::from_op_chain(&context, op_chain).unwrap();
+            let qe = qe.clone();
+            async move {
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                rows.iter().map(|r| r["title"].clone()).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(fetch_titles("createdAt").await, vec![json!("b")]);
+        assert_eq!(fetch_titles("created_at").await, vec![json!("b")]);
+
+        let ambiguous = make_object(
+            "Ambiguous",
+            vec![
+                make_field("a", Type::String),
+                make_field("b", Type::String).with_json_name(Some("a".into())),
+            ],
+        );
+        assert!(ambiguous.lookup_field("a").is_err());
+        assert_eq!(
+            ambiguous.lookup_field("b").unwrap().unwrap().json_name(),
+            "a"
+        );
+    }
+
+    #[tokio::test]
+    async fn array_fields() {
+        let post = make_object(
+            "Post",
+            vec![
+                make_field("title", Type::String),
+                make_field("tags", Type::Array(Box::new(Type::String))),
+                make_field("scores", Type::Array(Box::new(Type::Float))),
+            ],
+        );
+        let (qe, _db_file) = setup_clear_db(&[post.clone()]).await;
+        for row in [
+            json!({"title": "first", "tags": ["rust", "db"], "scores": [1.5, 2.0]}),
+            json!({"title": "second", "tags": ["ts"], "scores": [3.0]}),
+            json!({"title": "third", "tags": [], "scores": []}),
+        ] {
+            add_row(&qe, &post, &row).await;
+        }
+        let err = qe
+            .add_row(
+                &post,
+                json!({"title": "bad", "tags": [1], "scores": []})
+                    .as_object()
+                    .unwrap(),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("type string[], got 1"),
+            "{:?}",
+            err
+        );
+
+        let ts = make_type_system(&[post.clone()]);
+        let fetch_titles = |field: &'static str, op: BinaryOp, literal: Literal| {
+            let op_chain = QueryOpChain::Filter {
+                expression: binary(&[field], op, literal),
+                inner: QueryOpChain::BaseEntity {
+                    name: "Post".to_owned(),
+                }
+                .into(),
+            };
+            let context = RequestContext {
+                policies: &Policies::default(),
+                ts: &ts,
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                tenant_id: None,
+                region: None,
+                path: "".to_string(),
+                headers: HashMap::default(),
+            };
+            let query_plan = QueryPlan::from_op_chain(&context, op_chain).unwrap();
+            let qe = qe.clone();
+            async move {
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                rows.iter().map(|r| r["title"].clone()).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            fetch_titles("tags", BinaryOp::Contains, "db".into()).await,
+            vec![json!("first")]
+        );
+        assert_eq!(
+            fetch_titles("scores", BinaryOp::Contains, (3.).into()).await,
+            vec![json!("second")]
+        );
+        let either = Literal::Array(vec!["ts".into(), "db".into()]);
+        assert_eq!(
+            fetch_titles("tags", BinaryOp::Overlaps, either).await,
+            vec![json!("first"), json!("second")]
+        );
+        assert!(
+            fetch_titles("tags", BinaryOp::Overlaps, Literal::Array(vec![]))
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            fetch_titles("title", BinaryOp::Contains, "ir".into()).await,
+            vec![json!("first"), json!("third")]
+        );
+    }
+
+    #[tokio::test]
+    async fn map_fields() {
+        let item = make_object(
+            "Item",
+            vec![
+                make_field("name", Type::String),
+                make_field("labels", Type::Map(Some(Box::new(Type::String)))),
+                make_field("meta", Type::Map(None)),
+            ],
+        );
+        let (qe, _db_file) = setup_clear_db(&[item.clone()]).await;
+        for row in [
+            json!({"name": "a", "labels": {"color": "red"}, "meta": {"size": 3, "tags": ["x"]}}),
+            json!({"name": "b", "labels": {"color": "blue", "shape": "round"}, "meta": {}}),
+            json!({"name": "c", "labels": {}, "meta": {"size": 4}}),
+        ] {
+            add_row(&qe, &item, &row).await;
+        }
+        let bad = json!({"name": "d", "labels": {"color": 1}, "meta": {}});
+        assert!(qe
+            .add_row(&item, bad.as_object().unwrap(), None)
+            .await
+            .is_err());
+
+        let ts = make_type_system(&[item.clone()]);
+        let fetch_names = |fields: &[&'static str], op: BinaryOp, literal: Literal| {
+            let op_chain = QueryOpChain::Filter {
+                expression: binary(fields, op, literal),
+                inner: QueryOpChain::BaseEntity {
+                    name: "Item".to_owned(),
+                }
+                .into(),
+            };
+            let context = RequestContext {
+                policies: &Policies::default(),
+                ts: &ts,
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                tenant_id: None,
+                region: None,
+                path: "".to_string(),
+                headers: HashMap::default(),
+            };
+            let query_plan = QueryPlan::from_op_chain(&context, op_chain).unwrap();
+            let qe = qe.clone();
+            async move {
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                rows.iter().map(|r| r["name"].clone()).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            fetch_names(&["labels"], BinaryOp::HasKey, "shape".into()).await,
+            vec![json!("b")]
+        );
+        assert_eq!(
+            fetch_names(&["meta"], BinaryOp::HasKey, "size".into()).await,
+            vec![json!("a"), json!("c")]
+        );
+        assert_eq!(
+            fetch_names(&["labels", "color"], BinaryOp::Eq, "red".into()).await,
+            vec![json!("a")]
+        );
+        assert_eq!(
+            fetch_names(&["meta", "size"], BinaryOp::Eq, (4.).into()).await,
+            vec![json!("c")]
+        );
+        let rows = fetch_rows(&qe, &item).await;
+        assert_eq!(rows[0]["meta"], json!({"size": 3, "tags": ["x"]}));
+    }
+
+    #[tokio::test]
+    async fn unique_constraints() {
+        let fields = vec![
+            make_field("provider", Type::String),
+            make_field("username", Type::String),
+        ];
+        let desc = types::NewObject::new("Account", VERSION);
+        let account = ObjectType::new(desc, fields, vec![], types::AuthOrNot::IsNotAuth)
+            .unwrap()
+            .with_unique_constraints(vec![types::UniqueConstraint {
+                fields: vec!["provider".into(), "username".into()],
+                name: Some("identity".into()),
+            }])
+            .unwrap();
+        let account = Arc::new(account);
+        let (qe, _db_file) = setup_clear_db(&[account.clone()]).await;
+        add_row(
+            &qe,
+            &account,
+            &json!({"provider": "github", "username": "ann"}),
+        )
+        .await;
+        add_row(
+            &qe,
+            &account,
+            &json!({"provider": "gitlab", "username": "ann"}),
+        )
+        .await;
+
+        let row = json!({"provider": "github", "username": "ann"});
+        let err = qe
+            .add_row(&account, row.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        match err.downcast_ref::<QueryError>() {
+            Some(QueryError::UniqueViolation(ty, constraint)) => {
+                assert_eq!((ty.as_str(), constraint.as_str()), ("Account", "identity"))
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(fetch_rows(&qe, &account).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn check_constraints() {
+        let desc = types::NewObject::new("Product", VERSION);
+        let fields = vec![make_field("price", Type::Float)];
+        let product = ObjectType::new(desc, fields, vec![], types::AuthOrNot::IsNotAuth)
+            .unwrap()
+            .with_check_constraints(vec![types::CheckConstraint {
+                name: "positive_price".into(),
+                expression: "price > 0".into(),
+            }])
+            .unwrap();
+        let product = Arc::new(product);
+        let (qe, _db_file) = setup_clear_db(&[product.clone()]).await;
+        add_row(&qe, &product, &json!({"price": 2.5})).await;
+
+        let row = json!({"price": -1.0});
+        let err = qe
+            .add_row(&product, row.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        match err.downcast_ref::<QueryError>() {
+            Some(QueryError::CheckConstraintViolation { constraint_name }) => {
+                assert_eq!(constraint_name, "positive_price")
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(fetch_rows(&qe, &product).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn enum_fields() {
+        let status = Type::Enum(vec!["pending".into(), "it's done".into()]);
+        let task = make_object(
+            "Task",
+            vec![
+                make_field("title", Type::String),
+                make_field("status", status),
+            ],
+        );
+        let (qe, _db_file) = setup_clear_db(&[task.clone()]).await;
+        add_row(&qe, &task, &json!({"title": "a", "status": "pending"})).await;
+        add_row(&qe, &task, &json!({"title": "b", "status": "it's done"})).await;
+
+        let row = json!({"title": "c", "status": "closed"});
+        let err = qe
+            .add_row(&task, row.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("takes one of \"pending\" | \"it's done\""));
+
+        let raw = format!(
+            "INSERT INTO \"{}\" (\"id\", \"title\", \"status\") VALUES ('x', 'd', 'closed')",
+            task.backing_table()
+        );
+        let raw = SqlWithArguments {
+            sql: raw,
+            args: vec![],
+        };
+        assert!(qe.execute_transaction(&[raw]).await.is_err());
+        assert_eq!(fetch_rows(&qe, &task).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_estimated_row_count() {
+        let (qe, _db_file) = setup_clear_db(&[PERSON_TY.clone()]).await;
+        for name in ["Alice", "Bob", "Carol"] {
+            add_row(&qe, &PERSON_TY, &json!({"name": name, "age": 30})).await;
+        }
+        // There are no statistics before ANALYZE.
+        assert_eq!(qe.estimated_row_count(&PERSON_TY).await.unwrap(), 0);
+        let analyze = SqlWithArguments {
+            sql: "ANALYZE".to_owned(),
+            args: vec![],
+        };
+        qe.execute_transaction(&[analyze]).await.unwrap();
+        assert_eq!(qe.estimated_row_count(&PERSON_TY).await.unwrap(), 3);
+
+        let stats = qe.table_stats(&PERSON_TY).await.unwrap();
+        assert_eq!(stats.estimated_row_count, 3);
+        assert_eq!(stats.last_vacuum, None);
+
+        // Small tables are counted exactly.
+        let qe = Arc::new(qe);
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        let plan = QueryPlan::from_type(&PERSON_TY);
+        assert!(plan.is_unfiltered());
+        assert_eq!(qe.count_or_estimate(tr, plan).await.unwrap(), (3, false));
+        let plan = QueryPlan::from_type(&PERSON_TY)
+            .filter_eq("name", Literal::String("Bob".to_owned()).into());
+        assert!(!plan.is_unfiltered());
+    }
+
+    #[tokio::test]
+    async fn encrypted_fields() {
+        std::env::set_var(encryption::KEY_VAR, "test secret");
+        let plain = make_object(
+            "Patient",
+            vec![
+                make_field("name", Type::String),
+                make_field("ssn", Type::String),
+            ],
+        );
+        let (qe, _db_file) = setup_clear_db(&[plain.clone()]).await;
+        add_row(&qe, &plain, &json!({"name": "a", "ssn": "078-05-1120"})).await;
+
+        let patient = make_object(
+            "Patient",
+            vec![
+                make_field("name", Type::String),
+                make_field("ssn", Type::String).encrypted(),
+            ],
+        );
+        add_row(&qe, &patient, &json!({"name": "b", "ssn": "219-09-9999"})).await;
+        async fn stored(qe: &QueryEngine, ty: &ObjectType) -> Vec<String> {
+            let sql = format!(
+                "SELECT \"ssn\" FROM \"{}\" ORDER BY \"name\"",
+                ty.backing_table()
+            );
+            let rows = qe.fetch_all(SqlWithArguments { sql, args: vec![] });
+            let rows = rows.await.unwrap();
+            rows.iter().map(|r| r.get::<String, _>(0)).collect()
+        }
+        let before = stored(&qe, &patient).await;
+        assert_eq!(before[0], "078-05-1120");
+        assert!(encryption::is_encrypted(&before[1]));
+
+        let ssn = patient.lookup_field("ssn").unwrap().unwrap();
+        assert_eq!(qe.encrypt_existing(&patient, ssn).await.unwrap(), 1);
+        assert_eq!(qe.encrypt_existing(&patient, ssn).await.unwrap(), 0);
+        let after = stored(&qe, &patient).await;
+        assert!(encryption::is_encrypted(&after[0]));
+        assert_eq!(after[1], before[1]);
+        let mut ssns: Vec<_> = fetch_rows(&qe, &patient)
+            .await
+            .iter()
+            .map(|r| r["ssn"].clone())
+            .collect();
+        ssns.sort_by_key(|v| v.to_string());
+        assert_eq!(ssns, vec![json!("078-05-1120"), json!("219-09-9999")]);
+
+        let plan = QueryPlan::from_type(&patient)
+            .filter_eq("ssn", Literal::String("219-09-9999".to_owned()).into());
+        let qe = Arc::new(qe.clone());
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        let err = qe.query(tr, plan).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::EncryptedField(_))
+        ));
+
+        let desc = types::NewField::new("ssn", Type::Float, VERSION).unwrap();
+        let number = Field::new(desc, vec![], None, false, false).encrypted();
+        let desc = types::NewObject::new("Number", VERSION);
+        ObjectType::new(desc, vec![number], vec![], types::AuthOrNot::IsNotAuth).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn search() {
+        let post = make_object(
+            "Post",
+            vec![
+                make_field("title", Type::String),
+                make_field("body", Type::String),
+            ],
+        );
+        let (qe, _db_file) = setup_clear_db(&[post.clone()]).await;
+        for (title, body) in [
+            ("rust", "rust rust and more rust"),
+            ("cooking", "a recipe without any rust"),
+            ("gardening", "roses and tulips"),
+        ] {
+            add_row(&qe, &post, &json!({"title": title, "body": body})).await;
+        }
+        let fields = [
+            post.lookup_field("title").unwrap().unwrap(),
+            post.lookup_field("body").unwrap().unwrap(),
+        ];
+        async fn titles(qe: &QueryEngine, ty: &ObjectType, hits: &[(String, f64)]) -> Vec<String> {
+            let mut titles = vec![];
+            for (id, _) in hits {
+                let sql = format!(
+                    "SELECT \"title\" FROM \"{}\" WHERE \"id\" = '{}'",
+                    ty.backing_table(),
+                    id
+                );
+                let row = qe.fetch_one(SqlWithArguments { sql, args: vec![] });
+                titles.push(row.await.unwrap().get::<String, _>(0));
+            }
+            titles
+        }
+
+        let results = qe.search(&post, &fields, "rust", 10, None).await.unwrap();
+        assert_eq!(results.total_hits, 2);
+        assert_eq!(titles(&qe, &post, &results.hits).await, ["rust", "cooking"]);
+        assert!(results.hits[0].1 > results.hits[1].1);
+
+        let results = qe.search(&post, &fields, "rust", 1, None).await.unwrap();
+        assert_eq!(results.total_hits, 2);
+        assert_eq!(titles(&qe, &post, &results.hits).await, ["rust"]);
+
+        let results = qe
+            .search(&post, &fields, "Rust recipe", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(titles(&qe, &post, &results.hits).await, ["cooking"]);
+
+        let results = qe
+            .search(&post, &fields[..1], "tulips", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(results.total_hits, 0);
+        let results = qe
+            .search(&post, &fields, "\"tulips OR", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(results.total_hits, 0);
+        qe.search(&post, &fields, " ", 10, None).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn upserts_and_searches_by_tenant() {
+        let mut slug = make_field("slug", Type::String);
+        slug.is_unique = true;
+        let page = Arc::new(
+            ObjectType::new(
+                types::NewObject::new("Page", VERSION),
+                vec![
+                    slug,
+                    make_field("text", Type::String),
+                    make_field("tenant_id", Type::String),
+                ],
+                vec![],
+                types::AuthOrNot::IsNotAuth,
+            )
+            .unwrap()
+            .with_multi_tenant(true)
+            .unwrap(),
+        );
+        let (qe, _db_file) = setup_clear_db(&[page.clone()]).await;
+        let slug = vec!["slug".to_owned()];
+        let upsert = |text: &str, tenant_id| {
+            let value = json!({"slug": "home", "text": text});
+            let qe = qe.clone();
+            let page = page.clone();
+            let slug = slug.clone();
+            async move {
+                qe.upsert_row(&page, value.as_object().unwrap(), &slug, Some(tenant_id))
+                    .await
+            }
+        };
+
+        let id = upsert("welcome to t1", "t1").await.unwrap();
+        assert_eq!(upsert("still t1", "t1").await.unwrap(), id);
+        let err = upsert("taken by t2", "t2").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::OtherTenant(_))
+        ));
+        let sql = format!(
+            "SELECT \"text\", \"tenant_id\" FROM \"{}\"",
+            page.backing_table()
+        );
+        let row = qe.fetch_one(SqlWithArguments { sql, args: vec![] });
+        let row = row.await.unwrap();
+        assert_eq!(row.get::<String, _>(0), "still t1");
+        assert_eq!(row.get::<String, _>(1), "t1");
+
+        let fields = [page.lookup_field("text").unwrap().unwrap()];
+        let search = |tenant_id| qe.search(&page, &fields, "t1", 10, tenant_id);
+        let results = search(Some("t1")).await.unwrap();
+        assert_eq!(results.total_hits, 1);
+        assert_eq!(results.hits[0].0, id);
+        let results = search(Some("t2")).await.unwrap();
+        assert_eq!(results.total_hits, 0);
+        assert!(results.hits.is_empty());
+    }
+
+    #[tokio::test]
+    async fn raw_migrations() {
+        let (qe, _db_file) = setup_clear_db(&[]).await;
+        migrations::create_table(&qe).await.unwrap();
+        async fn count(qe: &QueryEngine, table: &str) -> i64 {
+            let sql = format!("SELECT COUNT(*) FROM {}", table);
+            let row = qe.fetch_one(SqlWithArguments { sql, args: vec![] });
+            row.await.unwrap().get::<i64, _>(0)
+        }
+
+        let sql = "CREATE TABLE log (msg TEXT);\nINSERT INTO log VALUES ('a;b');\n";
+        qe.execute_raw_migration(sql, "1_log").await.unwrap();
+        assert_eq!(count(&qe, "log").await, 1);
+        let err = qe.execute_raw_migration(sql, "1_log").await.unwrap_err();
+        assert!(err.to_string().contains("applied already"), "{}", err);
+
+        let sql = "INSERT INTO log VALUES ('b');\n\nINSERT INTO nope VALUES (1);";
+        let err = qe.execute_raw_migration(sql, "2_bad").await.unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("line 3 failed: INSERT INTO nope"), "{}", err);
+        assert_eq!(count(&qe, "log").await, 1);
+        let records = migrations::records(&qe).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].error.is_none());
+        assert!(records[1].error.as_ref().unwrap().contains("line 3"));
+
+        let err = qe.execute_raw_migration(sql, "2_bad").await.unwrap_err();
+        assert!(err.to_string().contains("is not run again"), "{}", err);
+        assert_eq!(count(&qe, "log").await, 1);
+
+        migrations::fake(&qe, "2_bad").await.unwrap();
+        migrations::fake(&qe, "3_by_hand").await.unwrap();
+        migrations::fake(&qe, "1_log").await.unwrap_err();
+        let records = migrations::records(&qe).await.unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|r| r.error.is_none()));
+        assert_eq!(count(&qe, "log").await, 1);
+    }
+
+    #[tokio::test]
+    async fn region_replicas() {
+        let (primary, primary_file) = setup_clear_db(&*ENTITIES).await;
+        add_row(&primary, &PERSON_TY, &json!({"name": "Primary", "age": 1.})).await;
+        let (replica, replica_file) = setup_clear_db(&*ENTITIES).await;
+        add_row(&replica, &PERSON_TY, &json!({"name": "Replica", "age": 1.})).await;
+
+        let uri = |file: &NamedTempFile| format!("sqlite://{}", file.path().to_string_lossy());
+        // A replica that is gone by the time it is read from.
+        let down_file = NamedTempFile::new().unwrap();
+        let regions = [
+            ("eu".to_owned(), uri(&replica_file)),
+            ("down".to_owned(), uri(&down_file)),
+        ];
+        let conn = DbConnection::connect(&uri(&primary_file), 1)
+            .await
+            .unwrap()
+            .with_regions(&regions, 1)
+            .unwrap();
+        down_file.close().unwrap();
+        let qe = QueryEngine::local_connection(&conn, 1).await.unwrap();
+
+        async fn names(qe: &QueryEngine, region: Option<&str>) -> Vec<String> {
+            let context = RequestContext {
+                policies: &Policies::default(),
+                ts: &make_type_system(&*ENTITIES),
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                tenant_id: None,
+                region: region.map(str::to_owned),
+                path: "".to_string(),
+                headers: HashMap::default(),
+            };
+            let op_chain = QueryOpChain::BaseEntity {
+                name: "Person".to_owned(),
+            };
+            let query_plan = QueryPlan::from_op_chain(&context, op_chain).unwrap();
+            let rows = fetch_rows_with_plan(qe, query_plan).await;
+            rows.iter()
+                .map(|r| r["name"].as_str().unwrap().to_owned())
+                .collect()
+        }
+        assert_eq!(names(&qe, Some("eu")).await, vec!["Replica"]);
+        assert_eq!(names(&qe, Some("us")).await, vec!["Primary"]);
+        assert_eq!(names(&qe, Some("down")).await, vec!["Primary"]);
+        assert_eq!(names(&qe, None).await, vec!["Primary"]);
+
+        let other = "postgres://localhost/db".to_owned();
+        let regions = [("eu".to_owned(), other)];
+        let conn = DbConnection::connect(&uri(&primary_file), 1).await.unwrap();
+        conn.with_regions(&regions, 1).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn lazy_migrations() {
+        let (qe, db_file) = setup_clear_db(&[PERSON_TY.clone()]).await;
+        migrations::create_table(&qe).await.unwrap();
+        assert_eq!(qe.migrate_lazily(&COMPANY_TY).await.unwrap(), 0);
+
+        let db_uri = format!("sqlite://{}", db_file.path().to_string_lossy());
+        let config = PoolConfig {
+            nr_connections: 2,
+            query_timeout: std::time::Duration::ZERO,
+            lazy_migrations: true,
+            namespace: None,
+        };
+        let conn = DbConnection::connect_with(&db_uri, &config).await.unwrap();
+        let lazy = QueryEngine::local_connection(&conn, 2).await.unwrap();
+        // Company nests Person, and has no table yet.
+        assert_eq!(lazy.migrate_lazily(&COMPANY_TY).await.unwrap(), 1);
+        let person_table = PERSON_TY.backing_table();
+        assert!(migrations::is_type_migrated(&db_uri, person_table));
+        assert!(!migrations::is_type_migrated(
+            &db_uri,
+            COMPANY_TY.backing_table()
+        ));
+        assert_eq!(lazy.migrate_lazily(&PERSON_TY).await.unwrap(), 0);
+        add_row(&lazy, &PERSON_TY, &json!({"name": "John", "age": 20.})).await;
+
+        let id = migrations::type_migration_id(person_table);
+        let sql = format!(
+            "SELECT COUNT(*) FROM {} WHERE id = $1",
+            migrations::MIGRATIONS_TABLE
+        );
+        let args = vec![SqlValue::String(id)];
+        let row = lazy
+            .fetch_one(SqlWithArguments { sql, args })
+            .await
+            .unwrap();
+        assert_eq!(row.get::<i64, _>(0), 1);
+        assert!(migrations::records(&lazy).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lazy_migrations_in_the_request_transaction() {
+        let (qe, db_file) = setup_clear_db(&*ENTITIES).await;
+        migrations::create_table(&qe).await.unwrap();
+        let db_uri = format!("sqlite://{}", db_file.path().to_string_lossy());
+        let config = PoolConfig {
+            nr_connections: 2,
+            query_timeout: std::time::Duration::ZERO,
+            lazy_migrations: true,
+            namespace: None,
+        };
+        let conn = DbConnection::connect_with(&db_uri, &config).await.unwrap();
+        let lazy = Arc::new(QueryEngine::local_connection(&conn, 2).await.unwrap());
+
+        // Writing to Person takes the write lock, which the migration of
+        // Company would wait on if it didn't run in the same transaction.
+        let tr = lazy.clone().start_transaction_static().await.unwrap();
+        let john = json!({"name": "John", "age": 20.});
+        {
+            let mut transaction = tr.lock().await;
+            lazy.add_row(
+                &PERSON_TY,
+                john.as_object().unwrap(),
+                Some(&mut transaction),
+            )
+            .await
+            .unwrap();
+        }
+        let companies = lazy
+            .query(tr.clone(), QueryPlan::from_type(&COMPANY_TY))
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert!(companies.is_empty());
+        // Not until the transaction commits.
+        let company_table = COMPANY_TY.backing_table();
+        assert!(!migrations::is_type_migrated(&db_uri, company_table));
+        QueryEngine::commit_transaction_static(tr).await.unwrap();
+
+        assert_eq!(lazy.migrate_lazily(&COMPANY_TY).await.unwrap(), 0);
+        assert!(migrations::is_type_migrated(&db_uri, company_table));
+        assert_eq!(fetch_rows(&lazy, &PERSON_TY).await.len(), 1);
+    }
 }
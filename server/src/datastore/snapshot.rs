@@ -0,0 +1,263 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Point-in-time snapshots of a SQLite database.
+//!
+//! Snapshots are full copies of the database, kept in a directory next to a
+//! `manifest.json` that lists them from oldest to newest. Restoring can't
+//! happen under a running server, so it is only scheduled here and carried
+//! out by [`SnapshotManager::apply_pending_restore`] on the next start.
+
+use crate::datastore::QueryEngine;
+use anyhow::{Context, Result};
+use async_lock::Mutex;
+use chrono::{SecondsFormat, Utc};
+use deno_core::futures;
+use futures::FutureExt;
+use serde_derive::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Holds the id of the snapshot to restore on the next start.
+const PENDING_RESTORE_FILE: &str = "pending-restore";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Snapshot {
+    pub(crate) id: String,
+    /// RFC 3339 timestamp.
+    pub(crate) created_at: String,
+    /// Size of the snapshot file in bytes.
+    pub(crate) size: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Manifest {
+    snapshots: Vec<Snapshot>,
+}
+
+fn snapshot_file(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.db", id))
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("removing {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) struct SnapshotManager {
+    query_engine: QueryEngine,
+    dir: PathBuf,
+    /// How many snapshots to keep. Zero keeps all of them.
+    retention: usize,
+    /// Serializes manifest updates.
+    manifest_lock: Mutex<()>,
+}
+
+impl SnapshotManager {
+    pub(crate) fn new(query_engine: QueryEngine, dir: PathBuf, retention: usize) -> Self {
+        Self {
+            query_engine,
+            dir,
+            retention,
+            manifest_lock: Mutex::new(()),
+        }
+    }
+
+    async fn read_manifest(&self) -> Result<Manifest> {
+        let path = self.dir.join(MANIFEST_FILE);
+        match tokio::fs::read(&path).await {
+            Ok(data) => {
+                serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    async fn write_manifest(&self, manifest: &Manifest) -> Result<()> {
+        // Write to the side and rename, so a crash never leaves a truncated manifest.
+        let path = self.dir.join(MANIFEST_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST_FILE));
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(manifest)?).await?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Lists the snapshots, oldest first.
+    pub(crate) async fn list(&self) -> Result<Vec<Snapshot>> {
+        Ok(self.read_manifest().await?.snapshots)
+    }
+
+    /// Checkpoints the WAL and copies the database into a new snapshot,
+    /// dropping the oldest ones beyond the retention limit.
+    pub(crate) async fn create(&self) -> Result<Snapshot> {
+        let _guard = self.manifest_lock.lock().await;
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        let mut manifest = self.read_manifest().await?;
+
+        let now = Utc::now();
+        let base_id = now.format("%Y%m%dT%H%M%S%.3fZ").to_string();
+        let mut id = base_id.clone();
+        let mut n = 1;
+        while manifest.snapshots.iter().any(|s| s.id == id) {
+            id = format!("{}-{}", base_id, n);
+            n += 1;
+        }
+
+        let path = snapshot_file(&self.dir, &id);
+        self.query_engine.checkpoint_wal().await?;
+        self.query_engine.backup_sqlite(&path).await?;
+        let snapshot = Snapshot {
+            id,
+            created_at: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            size: tokio::fs::metadata(&path).await?.len(),
+        };
+        manifest.snapshots.push(snapshot.clone());
+
+        if self.retention > 0 && manifest.snapshots.len() > self.retention {
+            let expired = manifest.snapshots.len() - self.retention;
+            for old in manifest.snapshots.drain(..expired) {
+                remove_if_exists(&snapshot_file(&self.dir, &old.id)).await?;
+            }
+        }
+        self.write_manifest(&manifest).await?;
+        info!("Created database snapshot {}", snapshot.id);
+        Ok(snapshot)
+    }
+
+    /// Marks snapshot `id` to be restored the next time the server starts.
+    pub(crate) async fn schedule_restore(&self, id: &str) -> Result<()> {
+        let _guard = self.manifest_lock.lock().await;
+        let manifest = self.read_manifest().await?;
+        anyhow::ensure!(
+            manifest.snapshots.iter().any(|s| s.id == id),
+            "no such snapshot: {}",
+            id
+        );
+        tokio::fs::write(self.dir.join(PENDING_RESTORE_FILE), id).await?;
+        Ok(())
+    }
+
+    /// Replaces the database at `db_path` with the snapshot scheduled by
+    /// [`Self::schedule_restore`], if any. Must run before the database is opened.
+    ///
+    /// Returns the id of the restored snapshot.
+    pub(crate) async fn apply_pending_restore(
+        dir: &Path,
+        db_path: &Path,
+    ) -> Result<Option<String>> {
+        let pending = dir.join(PENDING_RESTORE_FILE);
+        let id = match tokio::fs::read_to_string(&pending).await {
+            Ok(id) => id.trim().to_owned(),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", pending.display())),
+        };
+
+        let snapshot = snapshot_file(dir, &id);
+        let tmp = db_path.with_extension("restore.tmp");
+        tokio::fs::copy(&snapshot, &tmp)
+            .await
+            .with_context(|| format!("copying snapshot {}", snapshot.display()))?;
+        tokio::fs::rename(&tmp, db_path).await?;
+        // The WAL of the old database must not be replayed over the snapshot.
+        for suffix in ["-wal", "-shm"] {
+            let mut aux = db_path.as_os_str().to_owned();
+            aux.push(suffix);
+            remove_if_exists(Path::new(&aux)).await?;
+        }
+        tokio::fs::remove_file(&pending).await?;
+        Ok(Some(id))
+    }
+
+    /// Creates a snapshot every `interval` until `shutdown` fires.
+    pub(crate) async fn run(
+        self: Arc<Self>,
+        interval: Duration,
+        shutdown: async_channel::Receiver<()>,
+    ) {
+        loop {
+            futures::select! {
+                _ = sleep(interval).fuse() => {},
+                _ = shutdown.recv().fuse() => {
+                    break;
+                }
+            };
+
+            if let Err(e) = self.create().await {
+                warn!("Database snapshot failed: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::engine::SqlWithArguments;
+    use crate::datastore::DbConnection;
+    use sqlx::Row;
+    use tempdir::TempDir;
+
+    fn sql(sql: &str) -> SqlWithArguments {
+        SqlWithArguments {
+            sql: sql.to_owned(),
+            args: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_corrupted_database() -> Result<()> {
+        let tmp_dir = TempDir::new("snapshot")?;
+        let db_path = tmp_dir.path().join("chisel.db");
+        let snapshot_dir = tmp_dir.path().join("snapshots");
+        let conn_str = format!("sqlite://{}?mode=rwc", db_path.display());
+
+        let conn = DbConnection::connect(&conn_str, 1).await?;
+        let qeng = QueryEngine::local_connection(&conn, 1).await?;
+        qeng.execute_transaction(&[
+            sql("CREATE TABLE t (x TEXT)"),
+            sql("INSERT INTO t VALUES ('before')"),
+        ])
+        .await?;
+
+        let manager = SnapshotManager::new(qeng, snapshot_dir.clone(), 2);
+        let first = manager.create().await?;
+        manager.create().await?;
+        manager.create().await?;
+        let snapshots = manager.list().await?;
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots.iter().all(|s| s.id != first.id));
+        assert!(!snapshot_file(&snapshot_dir, &first.id).exists());
+
+        manager.schedule_restore(&snapshots[1].id).await?;
+        manager.schedule_restore("nonexistent").await.unwrap_err();
+        conn.pool.close().await;
+
+        tokio::fs::write(&db_path, b"this is not a database").await?;
+        let restored = SnapshotManager::apply_pending_restore(&snapshot_dir, &db_path).await?;
+        assert_eq!(restored.as_ref(), Some(&snapshots[1].id));
+
+        let conn = DbConnection::connect(&conn_str, 1).await?;
+        let qeng = QueryEngine::local_connection(&conn, 1).await?;
+        let row = qeng.fetch_one(sql("SELECT x FROM t")).await?;
+        assert_eq!(row.get::<&str, _>(0), "before");
+
+        // Nothing is pending anymore.
+        assert_eq!(
+            SnapshotManager::apply_pending_restore(&snapshot_dir, &db_path).await?,
+            None
+        );
+        Ok(())
+    }
+}
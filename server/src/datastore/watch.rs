@@ -0,0 +1,480 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Change notifications for live queries.
+//!
+//! Backing tables get triggers that report their row changes. On PostgreSQL
+//! every table has them, and they `NOTIFY` the [`CHANGES_CHANNEL`] channel,
+//! which a [`PgListenManager`] forwards to watchers as soon as they arrive.
+//! SQLite has nothing similar, so there the triggers append to a change log
+//! table that watchers poll every [`POLL_INTERVAL`]. Since that makes every
+//! write a second one, SQLite tables only get triggers once watched.
+//!
+//! Soft deletes are only updates to those triggers. [`poll_table`] instead
+//! polls a type for the rows whose [`UPDATED_AT_FIELD`] moved, and reports
+//! setting the [`DELETED_AT_FIELD`] as a deletion, along with the row before
+//! and after every change.
+
+use crate::datastore::query::{truncate_identifier, QueryPlan};
+use crate::datastore::{Kind, QueryEngine};
+use crate::types::{ObjectType, Type};
+use crate::JsonObject;
+use anyhow::Result;
+use deno_core::futures::stream::{self, BoxStream};
+use deno_core::futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::any::AnyPool;
+use sqlx::postgres::PgListener;
+use sqlx::Row;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::sleep;
+
+/// PostgreSQL channel the change triggers notify.
+pub(crate) const CHANGES_CHANNEL: &str = "chiselstrike_changes";
+
+/// SQLite table the change triggers append to.
+const CHANGE_LOG_TABLE: &str = "__chiselstrike_changes";
+
+/// Only this many of the latest changes are kept in the SQLite change log.
+const CHANGE_LOG_SIZE: usize = 10000;
+
+/// How often SQLite watchers look for new changes.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Operation {
+    Create,
+    Update,
+    Delete,
+}
+
+impl Operation {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "create" => Ok(Operation::Create),
+            "update" => Ok(Operation::Update),
+            "delete" => Ok(Operation::Delete),
+            _ => anyhow::bail!("unknown change operation {}", s),
+        }
+    }
+}
+
+/// A row of `table` was created, updated or deleted.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ChangeEvent {
+    pub(crate) table: String,
+    pub(crate) id: String,
+    pub(crate) operation: Operation,
+    /// The row before the change. Only [`poll_table`] knows it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) before: Option<JsonObject>,
+    /// The row after the change, unless it was deleted. Only [`poll_table`]
+    /// knows it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) after: Option<JsonObject>,
+}
+
+pub(crate) type ChangeStream = BoxStream<'static, Result<ChangeEvent>>;
+
+/// Statements creating what the change triggers of every table rely on.
+pub(crate) fn change_feed_sql(kind: Kind) -> Vec<String> {
+    match kind {
+        Kind::Postgres => vec![format!(
+            "CREATE OR REPLACE FUNCTION chiselstrike_notify_change() RETURNS trigger AS $$
+            BEGIN
+                PERFORM pg_notify('{}', json_build_object(
+                    'table', TG_TABLE_NAME,
+                    'id', CASE WHEN TG_OP = 'DELETE' THEN OLD.id ELSE NEW.id END,
+                    'operation', CASE TG_OP
+                        WHEN 'INSERT' THEN 'create'
+                        WHEN 'UPDATE' THEN 'update'
+                        ELSE 'delete' END
+                )::text);
+                RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql",
+            CHANGES_CHANNEL
+        )],
+        Kind::Sqlite => vec![format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                table_name TEXT NOT NULL,
+                row_id TEXT NOT NULL,
+                operation TEXT NOT NULL
+            )",
+            CHANGE_LOG_TABLE
+        )],
+    }
+}
+
+/// Statements (re)creating the triggers that report the changes to `table`.
+pub(crate) fn change_trigger_sql(kind: Kind, table: &str) -> Vec<String> {
+    match kind {
+        Kind::Postgres => {
+            let name = format!("{}_changes", table);
+            let name = truncate_identifier(&name);
+            vec![
+                format!("DROP TRIGGER IF EXISTS \"{}\" ON \"{}\"", name, table),
+                format!(
+                    "CREATE TRIGGER \"{}\" AFTER INSERT OR UPDATE OR DELETE ON \"{}\"
+                    FOR EACH ROW EXECUTE PROCEDURE chiselstrike_notify_change()",
+                    name, table
+                ),
+            ]
+        }
+        Kind::Sqlite => [
+            ("INSERT", Operation::Create, "NEW"),
+            ("UPDATE", Operation::Update, "NEW"),
+            ("DELETE", Operation::Delete, "OLD"),
+        ]
+        .iter()
+        .map(|(event, operation, row)| {
+            let operation = operation.as_str();
+            format!(
+                "CREATE TRIGGER IF NOT EXISTS \"{table}_changes_{operation}\" AFTER {event} ON \"{table}\"
+                BEGIN
+                    INSERT INTO {log} (table_name, row_id, operation)
+                        VALUES ('{table}', {row}.id, '{operation}');
+                    DELETE FROM {log} WHERE seq <= (SELECT MAX(seq) FROM {log}) - {size};
+                END",
+                log = CHANGE_LOG_TABLE,
+                size = CHANGE_LOG_SIZE,
+            )
+        })
+        .collect(),
+    }
+}
+
+type Subscribers = Arc<Mutex<Vec<async_channel::Sender<ChangeEvent>>>>;
+
+/// Listens for change notifications on a dedicated PostgreSQL connection
+/// and forwards them to every subscriber.
+pub(crate) struct PgListenManager {
+    subscribers: Subscribers,
+}
+
+impl PgListenManager {
+    pub(crate) async fn connect(uri: &str) -> Result<Self> {
+        let mut listener = PgListener::connect(uri).await?;
+        listener.listen(CHANGES_CHANNEL).await?;
+        let subscribers = Subscribers::default();
+        let forward_to = subscribers.clone();
+        tokio::task::spawn(async move {
+            // Stop once the manager is gone.
+            while Arc::strong_count(&forward_to) > 1 {
+                let notification = match listener.recv().await {
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        // recv() reconnects on the next call.
+                        warn!("Lost the connection listening for changes: {:?}", e);
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                match serde_json::from_str::<ChangeEvent>(notification.payload()) {
+                    Ok(event) => forward_to
+                        .lock()
+                        .unwrap()
+                        .retain(|s| s.try_send(event.clone()).is_ok()),
+                    Err(e) => warn!("Malformed change notification: {:?}", e),
+                }
+            }
+        });
+        Ok(Self { subscribers })
+    }
+
+    /// Receives every change notified from now on.
+    pub(crate) fn subscribe(&self) -> async_channel::Receiver<ChangeEvent> {
+        let (tx, rx) = async_channel::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/// Polls the SQLite change log for changes to `table` made after this call.
+pub(crate) async fn poll_change_log(pool: AnyPool, table: String) -> Result<ChangeStream> {
+    let last_seq: i64 = sqlx::query(&format!(
+        "SELECT COALESCE(MAX(seq), 0) FROM {}",
+        CHANGE_LOG_TABLE
+    ))
+    .fetch_one(&pool)
+    .await?
+    .get(0);
+
+    let state = (pool, table, last_seq, VecDeque::new());
+    let changes = stream::unfold(
+        state,
+        |(pool, table, mut last_seq, mut pending)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (pool, table, last_seq, pending)));
+                }
+                sleep(POLL_INTERVAL).await;
+                let sql = format!(
+                "SELECT seq, row_id, operation FROM {} WHERE seq > $1 AND table_name = $2 ORDER BY seq",
+                CHANGE_LOG_TABLE
+            );
+                let rows = sqlx::query(&sql)
+                    .bind(last_seq)
+                    .bind(table.clone())
+                    .fetch_all(&pool)
+                    .await;
+                let rows = match rows {
+                    Ok(rows) => rows,
+                    Err(e) => return Some((Err(e.into()), (pool, table, last_seq, pending))),
+                };
+                for row in rows {
+                    last_seq = row.get(0);
+                    match row.get::<&str, _>(2).parse() {
+                        Ok(operation) => pending.push_back(ChangeEvent {
+                            table: table.clone(),
+                            id: row.get::<String, _>(1),
+                            operation,
+                            before: None,
+                            after: None,
+                        }),
+                        Err(e) => warn!("Malformed change log entry: {:?}", e),
+                    }
+                }
+            }
+        },
+    );
+    Ok(Box::pin(changes))
+}
+
+/// Field holding when a row was last written, as a timestamp in milliseconds.
+pub(crate) const UPDATED_AT_FIELD: &str = "updated_at";
+
+/// Field holding when a row was soft deleted, unset while it isn't.
+pub(crate) const DELETED_AT_FIELD: &str = "deleted_at";
+
+/// The longest an idle [`poll_table`] waits between two polls.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(8);
+
+/// Whether `ty` has the fields [`poll_table`] needs.
+pub(crate) fn is_watchable(ty: &ObjectType) -> bool {
+    [UPDATED_AT_FIELD, DELETED_AT_FIELD]
+        .iter()
+        .all(|name| matches!(ty.get_field(name), Some(f) if matches!(f.type_, Type::Float)))
+}
+
+fn is_deleted(row: &JsonObject) -> bool {
+    !matches!(row.get(DELETED_AT_FIELD), None | Some(Value::Null))
+}
+
+/// The change from `before` to `after` of a row, if there is one to report.
+/// Soft deleted rows count as gone, so restoring one creates it again.
+fn row_change(before: Option<&JsonObject>, after: &JsonObject) -> Option<Operation> {
+    let live_before = before.filter(|b| !is_deleted(b));
+    match (live_before, is_deleted(after)) {
+        (Some(before), false) if before != after => Some(Operation::Update),
+        (Some(_), true) => Some(Operation::Delete),
+        (None, false) => Some(Operation::Create),
+        _ => None,
+    }
+}
+
+async fn fetch_rows(qeng: &Arc<QueryEngine>, plan: QueryPlan) -> Result<Vec<JsonObject>> {
+    let tr = qeng.clone().start_transaction_static().await?;
+    qeng.query(tr, plan)?
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Reports the changes to the rows of `ty` made after this call to every
+/// receiver, by polling for the rows whose [`UPDATED_AT_FIELD`] is at least
+/// the latest one seen. The polls are `interval` apart, twice as far apart
+/// after every poll that finds nothing, up to [`MAX_POLL_INTERVAL`].
+///
+/// The watcher keeps the last seen version of every row, to tell the
+/// operations apart and report the rows before the changes. Rows removed
+/// outright rather than soft deleted aren't noticed. Polling stops once all
+/// receivers are dropped.
+pub(crate) async fn poll_table(
+    qeng: Arc<QueryEngine>,
+    ty: Arc<ObjectType>,
+    interval: Duration,
+) -> Result<broadcast::Receiver<ChangeEvent>> {
+    anyhow::ensure!(
+        is_watchable(&ty),
+        "type {} needs the number fields {} and {} to be watched",
+        ty.name(),
+        UPDATED_AT_FIELD,
+        DELETED_AT_FIELD
+    );
+    let updated_at = |row: &JsonObject| row.get(UPDATED_AT_FIELD).and_then(Value::as_f64);
+    let mut rows: HashMap<String, JsonObject> = HashMap::new();
+    let mut since = f64::MIN;
+    for row in fetch_rows(&qeng, QueryPlan::from_type(&ty)).await? {
+        since = updated_at(&row).map_or(since, |u| u.max(since));
+        if let Some(id) = row.get("id").and_then(Value::as_str) {
+            rows.insert(id.to_owned(), row);
+        }
+    }
+
+    let (tx, rx) = broadcast::channel(1024);
+    tokio::task::spawn(async move {
+        let mut wait = interval;
+        while tx.receiver_count() > 0 {
+            sleep(wait).await;
+            let plan = QueryPlan::from_type(&ty).filter_since(UPDATED_AT_FIELD, since);
+            let changed = match fetch_rows(&qeng, plan).await {
+                Ok(changed) => changed,
+                Err(e) => {
+                    warn!("Failed to poll {} for changes: {:?}", ty.name(), e);
+                    continue;
+                }
+            };
+            let mut reported = false;
+            for after in changed {
+                since = updated_at(&after).map_or(since, |u| u.max(since));
+                let id = match after.get("id").and_then(Value::as_str) {
+                    Some(id) => id.to_owned(),
+                    None => continue,
+                };
+                let before = rows.get(&id);
+                if let Some(operation) = row_change(before, &after) {
+                    let event = ChangeEvent {
+                        table: ty.backing_table().to_owned(),
+                        id: id.clone(),
+                        operation,
+                        before: before.filter(|b| !is_deleted(b)).cloned(),
+                        after: (operation != Operation::Delete).then(|| after.clone()),
+                    };
+                    // Fails only once there are no receivers left.
+                    let _ = tx.send(event);
+                    reported = true;
+                }
+                rows.insert(id, after);
+            }
+            wait = match reported {
+                true => interval,
+                false => (wait * 2).min(MAX_POLL_INTERVAL.max(interval)),
+            };
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::engine::SqlWithArguments;
+    use crate::datastore::query::tests::{add_row, make_field, make_object, setup_clear_db};
+    use crate::datastore::query::SqlValue;
+    use crate::types::{Field, NewField};
+    use serde_json::json;
+
+    fn sql(sql: String) -> SqlWithArguments {
+        SqlWithArguments { sql, args: vec![] }
+    }
+
+    #[tokio::test]
+    async fn sqlite_changes() -> Result<()> {
+        let (qeng, _db_file) = setup_clear_db(&[]).await;
+
+        qeng.execute_transaction(&[
+            sql("CREATE TABLE t (id TEXT, x TEXT)".to_owned()),
+            sql("CREATE TABLE unwatched (id TEXT)".to_owned()),
+        ])
+        .await?;
+        let mut changes = qeng.watch("t").await?;
+        let triggers = |table: &str| {
+            qeng.fetch_all(SqlWithArguments {
+                sql: "SELECT name FROM sqlite_master WHERE type = 'trigger' AND tbl_name = $1"
+                    .to_owned(),
+                args: vec![SqlValue::String(table.to_owned())],
+            })
+        };
+        assert_eq!(triggers("t").await?.len(), 3);
+        assert!(triggers("unwatched").await?.is_empty());
+
+        qeng.execute_transaction(&[
+            sql("INSERT INTO t VALUES ('1', 'a')".to_owned()),
+            sql("UPDATE t SET x = 'b' WHERE id = '1'".to_owned()),
+            sql("DELETE FROM t".to_owned()),
+        ])
+        .await?;
+        for operation in [Operation::Create, Operation::Update, Operation::Delete] {
+            let event = changes.next().await.unwrap()?;
+            let expected = ChangeEvent {
+                table: "t".to_owned(),
+                id: "1".to_owned(),
+                operation,
+                before: None,
+                after: None,
+            };
+            assert_eq!(event, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn polled_changes() -> Result<()> {
+        let deleted_at = NewField::new(DELETED_AT_FIELD, Type::Float, "version_1")?;
+        let ty = make_object(
+            "Note",
+            vec![
+                make_field("text", Type::String),
+                make_field(UPDATED_AT_FIELD, Type::Float),
+                Field::new(deleted_at, vec![], None, true, false),
+            ],
+        );
+        assert!(is_watchable(&ty));
+        assert!(!is_watchable(&make_object("Plain", vec![])));
+        let (qeng, _db_file) = setup_clear_db(&[ty.clone()]).await;
+        let qeng = Arc::new(qeng);
+        add_row(&qeng, &ty, &json!({"text": "old", "updated_at": 1.0})).await;
+        let mut changes = qeng
+            .clone()
+            .watch_table(ty.clone(), Duration::from_millis(10))
+            .await?;
+        let update = |set: &str| {
+            sql(format!(
+                "UPDATE \"{}\" SET {} WHERE text = 'new' OR text = 'old'",
+                ty.backing_table(),
+                set
+            ))
+        };
+
+        qeng.execute_transaction(&[update("text = 'new', updated_at = 2")])
+            .await?;
+        let event = changes.recv().await?;
+        assert_eq!(event.operation, Operation::Update);
+        assert_eq!(event.before.unwrap()["text"], "old");
+        assert_eq!(event.after.unwrap()["text"], "new");
+
+        add_row(&qeng, &ty, &json!({"text": "other", "updated_at": 3.0})).await;
+        let event = changes.recv().await?;
+        assert_eq!(event.operation, Operation::Create);
+        assert_eq!(event.before, None);
+        assert_eq!(event.after.unwrap()["text"], "other");
+
+        qeng.execute_transaction(&[update("deleted_at = 4, updated_at = 4")])
+            .await?;
+        let event = changes.recv().await?;
+        assert_eq!(event.operation, Operation::Delete);
+        assert_eq!(event.before.unwrap()["text"], "new");
+        assert_eq!(event.after, None);
+        Ok(())
+    }
+}
@@ -2,18 +2,22 @@
 
 use crate::api::ApiService;
 use crate::api::{response_template, Body, RequestPath};
-use crate::auth::get_username_from_id;
+use crate::auth::{auth_header_refusal, get_username_from_id, PROCESS_SECRET};
 use crate::datastore::crud;
 use crate::datastore::engine::extract_transaction;
 use crate::datastore::engine::IdTree;
+use crate::datastore::engine::QueryError;
 use crate::datastore::engine::TransactionStatic;
 use crate::datastore::engine::{QueryResults, ResultRow};
 use crate::datastore::expr::Expr;
 use crate::datastore::query::{Mutation, QueryOpChain, QueryPlan, RequestContext};
 use crate::datastore::MetaService;
 use crate::datastore::QueryEngine;
-use crate::policies::Policies;
+use crate::policies::{FieldPolicies, Policies};
 use crate::rcmut::RcMut;
+use crate::runtime;
+use crate::tenancy::{set_tenant, TenantId};
+use crate::types::ObjectType;
 use crate::types::Type;
 use crate::types::TypeSystem;
 use crate::types::TypeSystemError;
@@ -72,7 +76,6 @@ use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fmt::Debug;
 use std::future::Future;
-use std::io::Read;
 use std::net::SocketAddr;
 use std::ops::DerefMut;
 use std::pin::Pin;
@@ -185,6 +188,23 @@ impl deno_core::ModuleLoader for ModuleLoader {
     }
 }
 
+/// The class of the JavaScript errors that ops fail with. Version conflicts get
+/// one of their own, which the TypeScript API registers, so that `save` can
+/// tell them apart.
+fn error_class(err: &AnyError) -> &'static str {
+    let is_version_conflict = err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<QueryError>(),
+            Some(QueryError::VersionConflict(..))
+        )
+    });
+    if is_version_conflict {
+        "VersionConflict"
+    } else {
+        "Error"
+    }
+}
+
 fn build_extensions() -> Vec<Extension> {
     vec![Extension::builder()
         .ops(vec![
@@ -195,6 +215,7 @@ fn build_extensions() -> Vec<Extension> {
             op_chisel_crud_delete::decl(),
             op_chisel_get_secret::decl(),
             op_chisel_crud_query::decl(),
+            op_chisel_crud_stream::decl(),
             op_chisel_relational_query_create::decl(),
             op_chisel_query_next::decl(),
             op_chisel_commit_transaction::decl(),
@@ -242,7 +263,7 @@ fn create_web_worker(
             preload_module_cb: preload_module_cb.clone(),
             worker_type: args.worker_type,
             maybe_inspector_server: maybe_inspector_server.clone(),
-            get_error_class_fn: None,
+            get_error_class_fn: Some(&error_class),
             blob_store: Default::default(),
             broadcast_channel: Default::default(),
             shared_array_buffer_store: None,
@@ -319,7 +340,7 @@ impl DenoService {
             maybe_inspector_server: inspector.clone(),
             should_break_on_first_statement: false,
             module_loader,
-            get_error_class_fn: None,
+            get_error_class_fn: Some(&error_class),
             origin_storage_dir: None,
             blob_store: Default::default(),
             broadcast_channel: Default::default(),
@@ -465,29 +486,42 @@ struct ChiselRequestContext {
     /// Current user ID.
     #[serde(rename = "userId")]
     user_id: Option<String>,
+    /// ID of the tenant the request is made for.
+    #[serde(rename = "tenantId")]
+    tenant_id: Option<String>,
 }
 
 impl RequestContext<'_> {
-    fn new<'a>(
-        policies: &'a Policies,
-        ts: &'a TypeSystem,
-        context: ChiselRequestContext,
-    ) -> RequestContext<'a> {
+    fn new(state: &OpState, context: ChiselRequestContext) -> RequestContext<'_> {
+        // The replica lags behind the writes of the request, so once it wrote,
+        // its reads go to the primary, in its transaction.
+        let region = match state.has::<RequestWrote>() {
+            true => None,
+            false => crate::region::region_of(crate::region::headers(), &context.headers),
+        };
         RequestContext {
-            policies,
-            ts,
+            policies: current_policies(state),
+            ts: current_type_system(state),
             api_version: context.api_version,
             user_id: context.user_id,
+            tenant_id: context.tenant_id,
+            region,
             path: context.path,
             headers: context.headers,
         }
     }
 }
 
+/// Put in the OpState once the current request writes to the database.
+struct RequestWrote;
+
 #[derive(Deserialize)]
 struct StoreContent {
     name: String,
     value: JsonObject,
+    /// Version the entity had when it was loaded, if it was.
+    #[serde(rename = "expectedVersion")]
+    expected_version: Option<u64>,
 }
 
 fn is_auth_path(api_version: &str, path: &str) -> bool {
@@ -501,7 +535,7 @@ async fn op_chisel_store(
     c: ChiselRequestContext,
 ) -> Result<IdTree> {
     let type_name = &content.name;
-    let value = &content.value;
+    let mut value = content.value;
 
     let (query_engine, ty) = {
         let state = state.borrow();
@@ -517,12 +551,33 @@ async fn op_chisel_store(
         (query_engine, ty)
     };
     let transaction = {
-        let state = state.borrow();
+        let mut state = state.borrow_mut();
+        state.put(RequestWrote);
         current_transaction(&state)
     };
     let mut transaction = transaction.lock().await;
+    if let Some(tenant_id) = &c.tenant_id {
+        if let Some(id) = value.get("id").and_then(|id| id.as_str()) {
+            if ty.is_multi_tenant() {
+                let owner = query_engine
+                    .row_tenant(&ty, id, transaction.deref_mut())
+                    .await?;
+                anyhow::ensure!(
+                    owner.map_or(true, |owner| &owner == tenant_id),
+                    "Cannot save into type {}.",
+                    type_name
+                );
+            }
+        }
+        set_tenant(&ty, &mut value, tenant_id);
+    }
     query_engine
-        .add_row(&ty, value, Some(transaction.deref_mut()))
+        .update_row(
+            &ty,
+            &value,
+            content.expected_version,
+            Some(transaction.deref_mut()),
+        )
         .await
 }
 
@@ -541,13 +596,10 @@ async fn op_chisel_entity_delete(
     context: ChiselRequestContext,
 ) -> Result<()> {
     let mutation = {
-        let state = state.borrow_mut();
+        let mut state = state.borrow_mut();
+        state.put(RequestWrote);
         Mutation::delete_from_expr(
-            &RequestContext::new(
-                current_policies(&state),
-                current_type_system(&state),
-                context,
-            ),
+            &RequestContext::new(&state, context),
             &params.type_name,
             &params.filter_expr,
         )
@@ -582,13 +634,10 @@ async fn op_chisel_crud_delete(
     context: ChiselRequestContext,
 ) -> Result<()> {
     let mutation = {
-        let state = state.borrow_mut();
+        let mut state = state.borrow_mut();
+        state.put(RequestWrote);
         crud::delete_from_url(
-            &RequestContext::new(
-                current_policies(&state),
-                current_type_system(&state),
-                context,
-            ),
+            &RequestContext::new(&state, context),
             &params.type_name,
             &params.url,
         )
@@ -660,11 +709,7 @@ async fn op_chisel_crud_query(
         let query_engine = query_engine_arc(op_state);
 
         crud::run_query(
-            &RequestContext::new(
-                current_policies(op_state),
-                current_type_system(op_state),
-                context,
-            ),
+            &RequestContext::new(op_state, context),
             params,
             query_engine,
             transaction,
@@ -679,27 +724,40 @@ fn op_chisel_relational_query_create(
     op_chain: QueryOpChain,
     context: ChiselRequestContext,
 ) -> Result<ResourceId> {
-    let query_plan = QueryPlan::from_op_chain(
-        &RequestContext::new(
-            current_policies(op_state),
-            current_type_system(op_state),
-            context,
-        ),
-        op_chain,
-    )?;
+    let query_plan = QueryPlan::from_op_chain(&RequestContext::new(op_state, context), op_chain)?;
     create_query(op_state, query_plan)
 }
 
+/// Starts streaming the results of a crud query, which are then read with
+/// `op_chisel_query_next`.
+#[op]
+fn op_chisel_crud_stream(
+    op_state: &mut OpState,
+    params: crud::QueryParams,
+    context: ChiselRequestContext,
+) -> Result<ResourceId> {
+    let stream = crud::stream_query(
+        &RequestContext::new(op_state, context),
+        params,
+        query_engine_arc(op_state),
+        current_transaction(op_state),
+    )?;
+    Ok(add_query_stream(op_state, stream))
+}
+
 fn create_query(op_state: &mut OpState, query_plan: QueryPlan) -> Result<ResourceId> {
     let transaction = current_transaction(op_state);
     let query_engine = query_engine_arc(op_state);
     let stream = query_engine.query(transaction, query_plan)?;
+    Ok(add_query_stream(op_state, stream))
+}
+
+fn add_query_stream(op_state: &mut OpState, stream: QueryResults) -> ResourceId {
     let resource = QueryStreamResource {
         stream: RefCell::new(stream),
         cancel: Default::default(),
     };
-    let rid = op_state.resource_table.add(resource);
-    Ok(rid)
+    op_state.resource_table.add(resource)
 }
 
 // A future that resolves when this stream next element is available.
@@ -810,7 +868,10 @@ extern "C" fn promise_reject_callback(message: v8::PromiseRejectMessage) {
     }
 }
 
-pub(crate) async fn init_deno(inspect_brk: bool) -> Result<()> {
+/// Starts the runtime of this executor thread. `api_listen_addr` is where the
+/// worker sends the requests to the server's own routes, along with the
+/// [`PROCESS_SECRET`].
+pub(crate) async fn init_deno(inspect_brk: bool, api_listen_addr: &str) -> Result<()> {
     let (service, init_worker) = DenoService::new(inspect_brk).await;
     DENO.with(|d| {
         d.set(Rc::new(RefCell::new(service)))
@@ -825,9 +886,11 @@ pub(crate) async fn init_deno(inspect_brk: bool) -> Result<()> {
     scope.set_promise_reject_callback(promise_reject_callback);
     let undefined = v8::undefined(scope).into();
     let id = v8::Number::new(scope, service.worker_channel_id as f64).into();
+    let api_listen_addr = v8::String::new(scope, api_listen_addr).unwrap().into();
+    let secret = v8::String::new(scope, &PROCESS_SECRET).unwrap().into();
     init_worker
         .open(scope)
-        .call(scope, undefined, &[id])
+        .call(scope, undefined, &[id, api_listen_addr, secret])
         .unwrap();
     Ok(())
 }
@@ -1035,6 +1098,40 @@ pub(crate) async fn set_policies(policies: Policies) {
     to_worker(WorkerMsg::SetPolicies(policies)).await;
 }
 
+/// Field policies of this thread's worker for `ty`, as they apply to a
+/// request made to `path` by the user `user_id`.
+pub(crate) fn field_policies(
+    user_id: &Option<String>,
+    path: &str,
+    ty: &ObjectType,
+) -> FieldPolicies {
+    let mut service = match try_get() {
+        Some(service) => service,
+        None => {
+            return FieldPolicies {
+                current_userid: user_id.clone(),
+                ..Default::default()
+            }
+        }
+    };
+    let service: &mut DenoService = &mut service;
+    let state = service.worker.js_runtime.op_state();
+    let state = state.borrow();
+    current_policies(&state).make_field_policies(user_id, path, ty)
+}
+
+/// The secrets this thread's worker has, as last refreshed.
+pub(crate) fn current_worker_secrets() -> JsonObject {
+    let mut service = match try_get() {
+        Some(service) => service,
+        None => return JsonObject::new(),
+    };
+    let service: &mut DenoService = &mut service;
+    let state = service.worker.js_runtime.op_state();
+    let state = state.borrow();
+    current_secrets(&state).clone()
+}
+
 fn take_current_transaction(state: &mut OpState) -> TransactionStatic {
     state.take()
 }
@@ -1077,6 +1174,7 @@ pub(crate) fn lookup_builtin_type(
 }
 
 pub(crate) async fn remove_type_version(version: &str) {
+    runtime::get().context.remove_type_version(version);
     to_worker(WorkerMsg::RemoveTypeVersion(version.to_string())).await;
 }
 
@@ -1100,6 +1198,7 @@ async fn to_worker(msg: WorkerMsg) {
 }
 
 pub(crate) async fn set_type_system(type_system: TypeSystem) {
+    runtime::get().context.set_type_system(type_system.clone());
     to_worker(WorkerMsg::SetTypeSystem(type_system)).await;
 }
 
@@ -1131,7 +1230,9 @@ fn op_chisel_rollback_transaction(state: &mut OpState) -> Result<()> {
 async fn op_chisel_create_transaction(state: Rc<RefCell<OpState>>) -> Result<()> {
     let qe = query_engine_arc(&state.borrow());
     let transaction = qe.start_transaction_static().await?;
-    set_current_transaction(&mut state.borrow_mut(), transaction);
+    let mut state = state.borrow_mut();
+    state.try_take::<RequestWrote>();
+    set_current_transaction(&mut state, transaction);
     Ok(())
 }
 
@@ -1191,16 +1292,12 @@ async fn special_response(
         return Ok(Some(Response::builder().body("ok".to_string().into())?));
     }
     if req_path.starts_with("/__chiselstrike/auth/") {
-        let auth_header = req.headers().get("ChiselAuth");
-        let expected_secret = current_secrets(&state.borrow())
-            .get("CHISELD_AUTH_SECRET")
-            .cloned();
-        match (expected_secret, auth_header) {
-            (Some(_), None) => return Ok(Some(ApiService::forbidden("ChiselAuth")?)),
-            (Some(serde_json::Value::String(s)), Some(h)) if s != *h => {
-                return Ok(Some(ApiService::forbidden("Fundamental auth")?))
-            }
-            _ => (),
+        let refusal = auth_header_refusal(
+            current_secrets(&state.borrow()),
+            req.headers().get("ChiselAuth"),
+        );
+        if let Some(refusal) = refusal {
+            return Ok(Some(ApiService::forbidden(refusal)?));
         }
     } else {
         let username = get_username_from_id(state.clone(), userid.clone()).await;
@@ -1227,8 +1324,7 @@ async fn convert_response(mut res: Response<Body>) -> Result<ResponseParts> {
 
     let mut body = vec![];
     while let Some(data) = res_body.data().await {
-        let mut data = data?;
-        data.read_to_end(&mut body)?;
+        body.extend_from_slice(&data?);
     }
 
     let res_headers = res.headers();
@@ -1358,6 +1454,7 @@ struct StartRequest {
     method: String,
     url: String,
     userid: Option<String>,
+    tenantid: Option<String>,
 }
 
 async fn handle_request(
@@ -1365,6 +1462,8 @@ async fn handle_request(
     userid: Option<String>,
     req: Request<hyper::Body>,
 ) -> Result<StartRequest> {
+    let tenantid = req.extensions().get::<TenantId>().map(|t| t.0.clone());
+
     // FIXME: this request conversion is probably simplistic. Check deno/ext/http/lib.rs
 
     // Hyper gives us a URL with just the path, make it a full URL
@@ -1406,6 +1505,7 @@ async fn handle_request(
         method,
         url,
         userid,
+        tenantid,
     })
 }
 
@@ -1443,6 +1543,12 @@ async fn op_chisel_start_request(state: Rc<RefCell<OpState>>) -> Result<StartReq
     ))
 }
 
+/// The runtime of this thread, if it has one. Only the threads of the tests
+/// have none, and they run with no policies and no secrets.
+fn try_get() -> Option<RcMut<DenoService>> {
+    DENO.with(|x| x.get().cloned().map(RcMut::new))
+}
+
 fn get() -> RcMut<DenoService> {
     DENO.with(|x| {
         let rc = x.get().expect("Runtime is not yet initialized.").clone();
@@ -0,0 +1,911 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Native routes under `/__chiselstrike/entities`, backing the parts of the
+//! TypeScript API that need more than the Deno ops provide.
+//!
+//! Writes run inside the transaction named by the
+//! [`TRANSACTION_HEADER`](crate::transactions::TRANSACTION_HEADER), if any, and
+//! a route that fails partway leaves none of them behind, see [`Writes`].
+
+use crate::admin::AdminAuth;
+use crate::api::{json_response, query_param, response_template, version_param, ApiService, Body};
+use crate::context::{query_engine_route, RequestContext};
+use crate::datastore::engine::{QueryError, TransactionStatic};
+use crate::datastore::query::QueryPlan;
+use crate::datastore::QueryEngine;
+use crate::deno;
+use crate::policies::FieldPolicies;
+use crate::route_pattern::route_param;
+use crate::tenancy::{self, set_tenant, TENANT_HEADER};
+use crate::transactions::Writes;
+use crate::types::{Field, ObjectType, Type};
+use crate::JsonObject;
+use anyhow::{Context, Result};
+use deno_core::futures::StreamExt;
+use hyper::header::{HeaderValue, WARNING};
+use hyper::{Method, Request, Response, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::any::Any;
+use sqlx::Transaction;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub(crate) const ENTITIES_PATH: &str = "/__chiselstrike/entities";
+
+/// Label of the fields through which an entity is owned by the one it refers
+/// to, so that `?cascade=true` deletes it along with its owner.
+const OWNER_LABEL: &str = "owner";
+
+/// Header of the responses holding deprecated fields, with one value per field.
+const DEPRECATION_WARNING_HEADER: &str = "Deprecation-Warning";
+
+/// How many entities a search responds with, unless it sets a `limit`.
+const SEARCH_LIMIT: u64 = 20;
+
+/// Writing a deprecated field in strict mode.
+#[derive(Debug, thiserror::Error)]
+#[error["{0}"]]
+pub(crate) struct DeprecatedFieldWrite(String);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpsertBody {
+    data: JsonObject,
+    conflict_fields: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FindOrCreateBody {
+    search_key: JsonObject,
+    #[serde(default)]
+    defaults: JsonObject,
+}
+
+#[derive(Deserialize)]
+struct SearchBody {
+    query: String,
+    fields: Vec<String>,
+    limit: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct BatchBody {
+    create: Vec<JsonObject>,
+    update: Vec<JsonObject>,
+    delete: Vec<String>,
+}
+
+/// An operation of a batch that can't be done.
+#[derive(Serialize)]
+struct BatchFailure {
+    operation: &'static str,
+    index: usize,
+    error: String,
+}
+
+fn not_found(ty: &ObjectType, id: &str) -> Result<Response<Body>> {
+    Ok(response_template()
+        .status(StatusCode::NOT_FOUND)
+        .body(format!("{} {} not found", ty.name(), id).into())?)
+}
+
+fn conflict(conflict: &QueryError) -> Result<Response<Body>> {
+    Ok(response_template()
+        .status(StatusCode::CONFLICT)
+        .body(conflict.to_string().into())?)
+}
+
+fn unprocessable(message: String) -> Result<Response<Body>> {
+    Ok(response_template()
+        .status(StatusCode::UNPROCESSABLE_ENTITY)
+        .body(message.into())?)
+}
+
+fn forbidden(ty: &ObjectType, id: &str) -> Result<Response<Body>> {
+    Ok(response_template()
+        .status(StatusCode::FORBIDDEN)
+        .body(format!("{} {} belongs to another user", ty.name(), id).into())?)
+}
+
+/// Looks up the `:type` of the route, which has to be writable.
+fn entity_type(req: &Request<hyper::Body>) -> Result<Arc<ObjectType>> {
+    let type_name = route_param(req, "type")?;
+    let context = RequestContext::of(req)?;
+    let ty = context
+        .type_system()
+        .lookup_object_type(&type_name, &version_param(req))?;
+    anyhow::ensure!(!ty.is_auth(), "Cannot save into type {}", type_name);
+    Ok(ty)
+}
+
+fn user_id(req: &Request<hyper::Body>) -> Result<Option<String>> {
+    match req.headers().get("ChiselUID") {
+        Some(user_id) => Ok(Some(user_id.to_str()?.to_owned())),
+        None => Ok(None),
+    }
+}
+
+/// The tenant in the [`TENANT_HEADER`], to which the entities of `ty` are
+/// scoped. There is none for types that aren't multi-tenant, and requests for
+/// the ones that are have to name one when the server serves tenants.
+fn tenant_of(req: &Request<hyper::Body>, ty: &ObjectType) -> Result<Option<String>> {
+    if !ty.is_multi_tenant() {
+        return Ok(None);
+    }
+    match req.headers().get(TENANT_HEADER) {
+        Some(tenant_id) => Ok(Some(tenant_id.to_str()?.to_owned())),
+        None if tenancy::is_enabled() => anyhow::bail!(
+            "request for multi-tenant type {} is made for no tenant",
+            ty.name()
+        ),
+        None => Ok(None),
+    }
+}
+
+/// Whether `row`, of `ty`, belongs to `tenant_id`, if that's set.
+fn is_tenants(ty: &ObjectType, tenant_id: &Option<String>, row: &JsonObject) -> Result<bool> {
+    Ok(match tenant_id {
+        Some(tenant_id) => {
+            let field = ty.tenant_id_field()?;
+            row.get(field.json_name()).and_then(Value::as_str) == Some(tenant_id.as_str())
+        }
+        None => true,
+    })
+}
+
+/// Makes `value`, about to be saved, belong to `tenant_id`, and tells whether
+/// that would overwrite an entity of another tenant, as the Deno ops do.
+async fn claim_for_tenant(
+    qeng: &QueryEngine,
+    ty: &ObjectType,
+    tenant_id: &Option<String>,
+    value: &mut JsonObject,
+    transaction: &mut Transaction<'static, Any>,
+) -> Result<bool> {
+    let tenant_id = match tenant_id {
+        Some(tenant_id) => tenant_id,
+        None => return Ok(false),
+    };
+    if let Some(id) = value.get("id").and_then(Value::as_str) {
+        if let Some(owner) = qeng.row_tenant(ty, id, transaction).await? {
+            if &owner != tenant_id {
+                return Ok(true);
+            }
+        }
+    }
+    set_tenant(ty, value, tenant_id);
+    Ok(false)
+}
+
+/// The entity version held by the `If-Match` header, if any.
+fn expected_version(req: &Request<hyper::Body>) -> Result<Option<u64>> {
+    match req.headers().get("If-Match") {
+        Some(version) => Ok(Some(
+            version
+                .to_str()?
+                .trim_matches('"')
+                .parse::<u64>()
+                .context("If-Match must hold an entity version")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Whether `row` belongs to the user of `policies`, as far as their
+/// `matchLogin` fields are concerned.
+fn is_owned(policies: &FieldPolicies, row: &JsonObject) -> bool {
+    policies
+        .match_login
+        .iter()
+        .all(|field| match (row.get(field), &policies.current_userid) {
+            (Some(Value::String(owner)), Some(user_id)) => owner == user_id,
+            _ => false,
+        })
+}
+
+fn apply_transforms(policies: &FieldPolicies, row: &mut JsonObject) {
+    for (field, transform) in &policies.transforms {
+        if let Some(value) = row.remove(field) {
+            row.insert(field.clone(), transform(value));
+        }
+    }
+}
+
+/// Warnings about the deprecated fields of `ty` that `fields` holds.
+fn deprecation_warnings(ty: &ObjectType, fields: &JsonObject) -> Vec<String> {
+    ty.user_fields()
+        .filter(|field| fields.contains_key(field.json_name()))
+        .filter_map(Field::deprecation_warning)
+        .collect()
+}
+
+/// Warnings about writing `fields` into `ty`, for [`with_warnings`]. In strict
+/// mode, writing a deprecated field fails with [`DeprecatedFieldWrite`] instead.
+fn deprecated_writes(
+    ty: &ObjectType,
+    fields: &JsonObject,
+    strict_mode: bool,
+) -> Result<Vec<String>> {
+    let warnings = deprecation_warnings(ty, fields);
+    match warnings.first() {
+        Some(warning) if strict_mode => Err(DeprecatedFieldWrite(warning.clone()).into()),
+        _ => Ok(warnings),
+    }
+}
+
+/// Adds a `Warning: 299` header to `response` for each of `warnings`.
+fn with_warnings(mut response: Response<Body>, warnings: &[String]) -> Result<Response<Body>> {
+    for warning in warnings {
+        let value = format!("299 - \"{}\"", warning.replace('"', "\\\""));
+        response
+            .headers_mut()
+            .append(WARNING, HeaderValue::from_str(&value)?);
+    }
+    Ok(response)
+}
+
+/// Responds with `row`, of type `ty`, with a [`DEPRECATION_WARNING_HEADER`] for each
+/// deprecated field in it.
+fn row_response(ty: &ObjectType, row: &JsonObject) -> Result<Response<Body>> {
+    let mut response = json_response(StatusCode::OK, row)?;
+    for warning in deprecation_warnings(ty, row) {
+        response
+            .headers_mut()
+            .append(DEPRECATION_WARNING_HEADER, HeaderValue::from_str(&warning)?);
+    }
+    Ok(response)
+}
+
+/// Fetches the row of entity `id`, without applying any policies.
+async fn fetch_row(
+    qeng: &Arc<QueryEngine>,
+    tr: TransactionStatic,
+    ty: &Arc<ObjectType>,
+    id: &str,
+) -> Result<Option<JsonObject>> {
+    let mut rows = qeng.query(tr, QueryPlan::from_type(ty).filter_by_id(id))?;
+    rows.next().await.transpose()
+}
+
+/// Responds with the entity of the route's `:id`, with the field policies
+/// applied as they would be for the user in the `ChiselUID` header. Entities
+/// of multi-tenant types are only found for their tenant, if the
+/// [`TENANT_HEADER`] names one. The same goes for every route here.
+async fn find_by_id(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let context = RequestContext::of(&req)?;
+    let ty = context
+        .type_system()
+        .lookup_object_type(&route_param(&req, "type")?, &version_param(&req))?;
+    let id = route_param(&req, "id")?;
+    let tenant_id = tenant_of(&req, &ty)?;
+
+    let tr = qeng.clone().start_transaction_static().await?;
+    let mut row = match fetch_row(&qeng, tr, &ty, &id).await? {
+        Some(row) => row,
+        None => return not_found(&ty, &id),
+    };
+    let policies = deno::field_policies(&user_id(&req)?, req.uri().path(), &ty);
+    if !is_owned(&policies, &row) || !is_tenants(&ty, &tenant_id, &row)? {
+        return not_found(&ty, &id);
+    }
+    apply_transforms(&policies, &mut row);
+    row_response(&ty, &row)
+}
+
+/// Inserts or updates the entity in the body, depending on whether its
+/// conflict fields match a row. Responds with `409 Conflict` if the row they
+/// match belongs to another tenant.
+async fn upsert(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let ty = entity_type(&req)?;
+    let tenant_id = tenant_of(&req, &ty)?;
+    let strict_mode = RequestContext::of(&req)?.strict_mode;
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let body: UpsertBody = serde_json::from_slice(&body).context("invalid upsert request")?;
+    let warnings = deprecated_writes(&ty, &body.data, strict_mode)?;
+    let upserted = qeng
+        .upsert_row(&ty, &body.data, &body.conflict_fields, tenant_id.as_deref())
+        .await;
+    let id = match upserted {
+        Ok(id) => id,
+        Err(e) => {
+            return match e.downcast_ref::<QueryError>() {
+                Some(e @ QueryError::OtherTenant(..)) => conflict(e),
+                _ => Err(e),
+            }
+        }
+    };
+
+    let tr = qeng.clone().start_transaction_static().await?;
+    let row = fetch_row(&qeng, tr, &ty, &id)
+        .await?
+        .with_context(|| format!("upserted {} {} is gone", ty.name(), id))?;
+    with_warnings(row_response(&ty, &row)?, &warnings)
+}
+
+/// Responds with the entity matching the search key in the body, after
+/// creating it from the search key and the defaults if there is none. The
+/// status is `201 Created` in that case.
+async fn find_or_create(
+    req: Request<hyper::Body>,
+    qeng: Arc<QueryEngine>,
+) -> Result<Response<Body>> {
+    let ty = entity_type(&req)?;
+    let tenant_id = tenant_of(&req, &ty)?;
+    let strict_mode = RequestContext::of(&req)?.strict_mode;
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let body: FindOrCreateBody =
+        serde_json::from_slice(&body).context("invalid find_or_create request")?;
+    let mut warnings = deprecated_writes(&ty, &body.search_key, strict_mode)?;
+    warnings.extend(deprecated_writes(&ty, &body.defaults, strict_mode)?);
+    // The tenant is part of the search key, so that only its entities are found.
+    let mut search_key = body.search_key;
+    if let Some(tenant_id) = &tenant_id {
+        set_tenant(&ty, &mut search_key, tenant_id);
+    }
+    let (row, created) = qeng
+        .find_or_create(&ty, &search_key, &body.defaults)
+        .await?;
+    let mut response = row_response(&ty, &row)?;
+    if created {
+        *response.status_mut() = StatusCode::CREATED;
+    }
+    with_warnings(response, &warnings)
+}
+
+/// Searches the words of the `query` of the body in its `fields`, which have to
+/// be strings, and responds with the `limit` best matching entities, each with
+/// its relevance in `_score`, best first. `total_hits` counts all the matches.
+async fn search(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let ty = {
+        let context = RequestContext::of(&req)?;
+        let type_name = route_param(&req, "type")?;
+        let type_system = context.type_system();
+        type_system.lookup_object_type(&type_name, &version_param(&req))?
+    };
+    let tenant_id = tenant_of(&req, &ty)?;
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let body: SearchBody = serde_json::from_slice(&body).context("invalid search")?;
+    let mut fields = vec![];
+    for name in &body.fields {
+        let field = match ty.get_field_by_json_name(name) {
+            Some(field) => field,
+            None => return unprocessable(format!("field {} not present in {}", name, ty.name())),
+        };
+        if field.is_encrypted {
+            return Err(QueryError::EncryptedField(name.clone()).into());
+        }
+        if !matches!(field.type_, Type::String) {
+            return unprocessable(format!("{}.{} is not a string", ty.name(), name));
+        }
+        fields.push(field);
+    }
+
+    let limit = body.limit.unwrap_or(SEARCH_LIMIT);
+    let results = qeng
+        .search(&ty, &fields, &body.query, limit, tenant_id.as_deref())
+        .await?;
+    let tr = qeng.clone().start_transaction_static().await?;
+    let mut rows = vec![];
+    for (id, score) in results.hits {
+        // Entities deleted since the search are left out.
+        if let Some(mut row) = fetch_row(&qeng, tr.clone(), &ty, &id).await? {
+            row.insert("_score".to_owned(), json!(score));
+            rows.push(row);
+        }
+    }
+    json_response(
+        StatusCode::OK,
+        json!({ "results": rows, "total_hits": results.total_hits }),
+    )
+}
+
+/// Saves the entity in the body, along with the nested ones, creating them or
+/// overwriting the ones with the same ids. Responds with the ids of the saved entities.
+///
+/// An `If-Match` header holding a version makes the save fail with `409 Conflict`
+/// if the entity isn't at that version anymore.
+///
+/// Writing a deprecated field is warned about with a `Warning` header, or fails with
+/// `400 Bad Request` in strict mode. The same goes for the other routes writing entities.
+///
+/// Overwriting an entity of another tenant fails with `409 Conflict` too.
+async fn save(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let ty = entity_type(&req)?;
+    let tenant_id = tenant_of(&req, &ty)?;
+    let expected_version = expected_version(&req)?;
+    let writes = Writes::begin(&req, &qeng).await?;
+    let strict_mode = RequestContext::of(&req)?.strict_mode;
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let mut value: JsonObject = serde_json::from_slice(&body).context("invalid entity")?;
+    let warnings = deprecated_writes(&ty, &value, strict_mode)?;
+    let saved = {
+        let mut transaction = writes.transaction().lock().await;
+        if claim_for_tenant(&qeng, &ty, &tenant_id, &mut value, &mut transaction).await? {
+            return conflict(&QueryError::OtherTenant(ty.name().to_owned()));
+        }
+        qeng.update_row(&ty, &value, expected_version, Some(&mut *transaction))
+            .await
+    };
+    match saved {
+        Ok(ids) => {
+            writes.finish().await?;
+            with_warnings(json_response(StatusCode::OK, &ids)?, &warnings)
+        }
+        Err(e) => match e.downcast_ref::<QueryError>() {
+            Some(e @ (QueryError::VersionConflict(..) | QueryError::UniqueViolation(..))) => {
+                conflict(e)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// What handling a `PATCH` or `PUT` to `/:type/:id` needs from its request, besides the body.
+struct UpdateRequest {
+    ty: Arc<ObjectType>,
+    id: String,
+    tenant_id: Option<String>,
+    expected_version: Option<u64>,
+    policies: FieldPolicies,
+    writes: Writes,
+    strict_mode: bool,
+}
+
+impl UpdateRequest {
+    async fn new(req: &Request<hyper::Body>, qeng: &Arc<QueryEngine>) -> Result<Self> {
+        let ty = entity_type(req)?;
+        let policies = deno::field_policies(&user_id(req)?, req.uri().path(), &ty);
+        Ok(Self {
+            id: route_param(req, "id")?,
+            tenant_id: tenant_of(req, &ty)?,
+            ty,
+            expected_version: expected_version(req)?,
+            policies,
+            writes: Writes::begin(req, qeng).await?,
+            strict_mode: RequestContext::of(req)?.strict_mode,
+        })
+    }
+
+    /// Sets `fields` of the entity and responds with the result, along with
+    /// `warnings` about the fields written. Responds like [`delete`] if that
+    /// can't be done, with `422 Unprocessable Entity` if `fields` holds a
+    /// null for a required field, and with `409 Conflict` if it breaks a
+    /// unique constraint.
+    async fn apply(
+        self,
+        qeng: Arc<QueryEngine>,
+        mut fields: JsonObject,
+        warnings: &[String],
+    ) -> Result<Response<Body>> {
+        let Self {
+            ty,
+            id,
+            tenant_id,
+            expected_version,
+            policies,
+            writes,
+            ..
+        } = self;
+        let transaction = writes.transaction();
+
+        match fetch_row(&qeng, transaction.clone(), &ty, &id).await? {
+            Some(row) if !is_tenants(&ty, &tenant_id, &row)? => return not_found(&ty, &id),
+            Some(row) if !is_owned(&policies, &row) => return forbidden(&ty, &id),
+            Some(_) => {}
+            None => return not_found(&ty, &id),
+        }
+        // Keeps the entity from being moved to another tenant.
+        if let Some(tenant_id) = &tenant_id {
+            set_tenant(&ty, &mut fields, tenant_id);
+        }
+        let updated = {
+            let mut transaction = transaction.lock().await;
+            qeng.patch_row(&ty, &id, &fields, expected_version, &mut transaction)
+                .await
+        };
+        match updated {
+            Ok(true) => {}
+            Ok(false) => return conflict(&QueryError::VersionConflict(ty.name().to_owned(), id)),
+            Err(e) => {
+                return match e.downcast_ref::<QueryError>() {
+                    Some(e @ QueryError::NotNullable(..)) => unprocessable(e.to_string()),
+                    Some(e @ QueryError::UniqueViolation(..)) => conflict(e),
+                    _ => Err(e),
+                }
+            }
+        }
+        let mut row = fetch_row(&qeng, transaction.clone(), &ty, &id)
+            .await?
+            .with_context(|| format!("updated {} {} is gone", ty.name(), id))?;
+        writes.finish().await?;
+        apply_transforms(&policies, &mut row);
+        with_warnings(row_response(&ty, &row)?, warnings)
+    }
+}
+
+/// Applies the JSON Merge Patch (RFC 7396) in the body to the entity of the route's
+/// `:id`, as [`UpdateRequest::apply`] does. Only the fields in the patch change;
+/// those referring to other entities take ids.
+async fn patch(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let content_type = match req.headers().get("Content-Type") {
+        Some(content_type) => content_type.to_str()?,
+        None => "",
+    };
+    if !content_type.starts_with("application/merge-patch+json") {
+        return Ok(response_template()
+            .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .body("PATCH takes an application/merge-patch+json body".into())?);
+    }
+    let update = UpdateRequest::new(&req, &qeng).await?;
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let patch: JsonObject = serde_json::from_slice(&body).context("invalid merge patch")?;
+    let warnings = deprecated_writes(&update.ty, &patch, update.strict_mode)?;
+    update.apply(qeng, patch, &warnings).await
+}
+
+/// Parses the default `value` of `field` into JSON.
+fn default_json(field: &Field, value: &str) -> Result<Value> {
+    Ok(match field.type_ {
+        Type::Float => json!(value.parse::<f64>()?),
+        Type::Boolean => json!(value.parse::<bool>()?),
+        _ => json!(value),
+    })
+}
+
+/// Replaces every field of the entity of the route's `:id` with the ones in the body,
+/// as [`UpdateRequest::apply`] does. Fields that aren't in the body take their default
+/// value, or null if they are optional; if they are neither, this fails with `422`.
+/// Fields referring to other entities take ids.
+async fn replace(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let update = UpdateRequest::new(&req, &qeng).await?;
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let mut value: JsonObject = serde_json::from_slice(&body).context("invalid entity")?;
+    value.remove("id");
+    let warnings = deprecated_writes(&update.ty, &value, update.strict_mode)?;
+
+    let ty = update.ty.clone();
+    let mut fields = JsonObject::new();
+    for field in ty.user_fields() {
+        let field_value = match (
+            value.remove(field.json_name()),
+            field.user_provided_default(),
+        ) {
+            (Some(field_value), _) => field_value,
+            (None, Some(default)) => default_json(field, default)?,
+            (None, None) if field.is_optional => Value::Null,
+            (None, None) => {
+                return unprocessable(format!("{}.{} is missing", ty.name(), field.json_name()));
+            }
+        };
+        fields.insert(field.json_name().to_owned(), field_value);
+    }
+    if let Some(name) = value.keys().next() {
+        return unprocessable(format!("field {} not present in {}", name, ty.name()));
+    }
+    update.apply(qeng, fields, &warnings).await
+}
+
+/// Deletes the entities that `id` of `ty` owns, meaning the ones referring
+/// to it through a field labeled [`OWNER_LABEL`], and so on down.
+async fn delete_owned(
+    context: &RequestContext,
+    ty: Arc<ObjectType>,
+    id: String,
+    transaction: &mut Transaction<'static, Any>,
+) -> Result<()> {
+    let mut owners = vec![(ty, id)];
+    while let Some((owner_ty, owner_id)) = owners.pop() {
+        let mut owned_by = vec![];
+        {
+            let type_system = context.type_system();
+            let version = type_system.get_version(&owner_ty.api_version)?;
+            for ty in version.custom_types.values() {
+                for field in ty.user_fields() {
+                    let refers_to_owner = match &field.type_ {
+                        Type::Object(target) => target.name() == owner_ty.name(),
+                        _ => false,
+                    };
+                    if refers_to_owner && field.labels.iter().any(|l| l == OWNER_LABEL) {
+                        owned_by.push((ty.clone(), field.name.clone()));
+                    }
+                }
+            }
+        }
+        for (ty, field) in owned_by {
+            let qeng = &context.query_engine;
+            for id in qeng
+                .referencing_ids(&ty, &field, &owner_id, transaction)
+                .await?
+            {
+                qeng.delete_row(&ty, &id, None, transaction).await?;
+                owners.push((ty.clone(), id));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the entity of the route's `:id` and responds with `204 No Content`.
+///
+/// Responds with `404 Not Found` if there is no such entity, and with `403 Forbidden`
+/// if it belongs to someone else than the user in the `ChiselUID` header. `If-Match`
+/// is honored as in [`save`]. With `?cascade=true`, the entities it owns are deleted too.
+async fn delete(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let ty = entity_type(&req)?;
+    let id = route_param(&req, "id")?;
+    let tenant_id = tenant_of(&req, &ty)?;
+    let expected_version = expected_version(&req)?;
+    let cascade = query_param(&req, "cascade").as_deref() == Some("true");
+    let writes = Writes::begin(&req, &qeng).await?;
+    let transaction = writes.transaction();
+
+    let row = match fetch_row(&qeng, transaction.clone(), &ty, &id).await? {
+        Some(row) if is_tenants(&ty, &tenant_id, &row)? => row,
+        _ => return not_found(&ty, &id),
+    };
+    let policies = deno::field_policies(&user_id(&req)?, req.uri().path(), &ty);
+    if !is_owned(&policies, &row) {
+        return forbidden(&ty, &id);
+    }
+    {
+        let mut transaction = transaction.lock().await;
+        if !qeng
+            .delete_row(&ty, &id, expected_version, &mut transaction)
+            .await?
+        {
+            return conflict(&QueryError::VersionConflict(ty.name().to_owned(), id));
+        }
+        if cascade {
+            delete_owned(&RequestContext::of(&req)?, ty, id, &mut transaction).await?;
+        }
+    }
+    writes.finish().await?;
+    Ok(response_template()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::default())?)
+}
+
+/// Checks that `fields` only holds fields of `ty`, with no nulls for required ones.
+fn validate_fields(ty: &ObjectType, fields: &JsonObject) -> Result<()> {
+    for (name, value) in fields {
+        let field = ty
+            .get_field_by_json_name(name)
+            .with_context(|| format!("field {} not present in {}", name, ty.name()))?;
+        if value.is_null() && !field.is_optional {
+            return Err(QueryError::NotNullable(ty.name().to_owned(), name.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Updates the entity `id` with `fields`, or deletes it if there are none.
+async fn batch_write(
+    qeng: &Arc<QueryEngine>,
+    ty: &Arc<ObjectType>,
+    policies: &FieldPolicies,
+    tenant_id: &Option<String>,
+    transaction: &TransactionStatic,
+    id: &str,
+    fields: Option<&JsonObject>,
+) -> Result<()> {
+    let row = match fetch_row(qeng, transaction.clone(), ty, id).await? {
+        Some(row) if is_tenants(ty, tenant_id, &row)? => row,
+        _ => anyhow::bail!("{} {} not found", ty.name(), id),
+    };
+    anyhow::ensure!(
+        is_owned(policies, &row),
+        "{} {} belongs to another user",
+        ty.name(),
+        id
+    );
+    let mut transaction = transaction.lock().await;
+    match fields {
+        Some(fields) => {
+            qeng.patch_row(ty, id, fields, None, &mut transaction)
+                .await?
+        }
+        None => qeng.delete_row(ty, id, None, &mut transaction).await?,
+    };
+    Ok(())
+}
+
+fn batch_failed(failures: Vec<BatchFailure>) -> Result<Response<Body>> {
+    json_response(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        json!({ "failures": failures }),
+    )
+}
+
+/// Creates, updates and deletes the entities listed in the `create`, `update` and
+/// `delete` arrays of the body, all in one transaction. Entities to create are saved
+/// like in [`save`], and updates are applied like in [`patch`], so they need an `id`.
+/// Responds with the ids of the entities that were written, or with `422` listing
+/// the operations that failed, in which case none of them is done.
+async fn batch(req: Request<hyper::Body>, qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let ty = entity_type(&req)?;
+    let tenant_id = tenant_of(&req, &ty)?;
+    let policies = deno::field_policies(&user_id(&req)?, req.uri().path(), &ty);
+    let writes = Writes::begin(&req, &qeng).await?;
+    let strict_mode = RequestContext::of(&req)?.strict_mode;
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let mut batch: BatchBody = serde_json::from_slice(&body).context("invalid batch")?;
+    let mut warnings = vec![];
+    for value in batch.create.iter().chain(&batch.update) {
+        for warning in deprecated_writes(&ty, value, strict_mode)? {
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+    }
+
+    let mut failures = vec![];
+    for (index, value) in batch.create.iter().enumerate() {
+        if let Err(e) = validate_fields(&ty, value) {
+            failures.push(BatchFailure {
+                operation: "create",
+                index,
+                error: e.to_string(),
+            });
+        }
+    }
+    let mut updates = vec![];
+    for (index, value) in batch.update.into_iter().enumerate() {
+        let mut fields = value;
+        if let Some(tenant_id) = &tenant_id {
+            set_tenant(&ty, &mut fields, tenant_id);
+        }
+        let update = match fields.remove("id") {
+            Some(Value::String(id)) => validate_fields(&ty, &fields).map(|_| (id, fields)),
+            _ => Err(anyhow::anyhow!("an update needs the id of the entity")),
+        };
+        match update {
+            Ok(update) => updates.push(update),
+            Err(e) => failures.push(BatchFailure {
+                operation: "update",
+                index,
+                error: e.to_string(),
+            }),
+        }
+    }
+    if !failures.is_empty() {
+        return batch_failed(failures);
+    }
+
+    let transaction = writes.transaction();
+    let mut created = vec![];
+    for (index, value) in batch.create.iter_mut().enumerate() {
+        let mut transaction = transaction.lock().await;
+        let saved = match claim_for_tenant(&qeng, &ty, &tenant_id, value, &mut transaction).await {
+            Ok(true) => Err(anyhow::Error::from(QueryError::OtherTenant(
+                ty.name().to_owned(),
+            ))),
+            Ok(false) => {
+                qeng.update_row(&ty, value, None, Some(&mut *transaction))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match saved {
+            Ok(ids) => created.push(ids),
+            Err(e) => {
+                return batch_failed(vec![BatchFailure {
+                    operation: "create",
+                    index,
+                    error: e.to_string(),
+                }])
+            }
+        }
+    }
+    // Any failure from here on leaves the transaction unusable, so it's the only one reported.
+    let pending = updates
+        .iter()
+        .map(|(id, fields)| ("update", id, Some(fields)))
+        .chain(batch.delete.iter().map(|id| ("delete", id, None)));
+    let mut indexes = HashMap::<&str, usize>::new();
+    for (operation, id, fields) in pending {
+        let index = indexes.entry(operation).or_default();
+        if let Err(e) =
+            batch_write(&qeng, &ty, &policies, &tenant_id, transaction, id, fields).await
+        {
+            return batch_failed(vec![BatchFailure {
+                operation,
+                index: *index,
+                error: e.to_string(),
+            }]);
+        }
+        *index += 1;
+    }
+    writes.finish().await?;
+    let updated: Vec<_> = updates.into_iter().map(|(id, _)| id).collect();
+    let response = json_response(
+        StatusCode::OK,
+        json!({
+            "created": created,
+            "updated": updated,
+            "deleted": batch.delete,
+        }),
+    )?;
+    with_warnings(response, &warnings)
+}
+
+/// Registers the routes under `/__chiselstrike/entities`:
+///
+/// * `GET /:type/:id` fetches an entity.
+/// * `POST /:type` saves an entity, creating it or overwriting it.
+/// * `PUT /:type/:id` replaces all fields of an entity and `PATCH /:type/:id` some of them.
+/// * `DELETE /:type/:id` deletes an entity, and with `?cascade=true` the ones it owns.
+/// * `POST /:type/batch` creates, updates and deletes entities in one go.
+/// * `POST /:type/upsert` inserts or updates an entity depending on whether
+///   its conflict fields match a row.
+/// * `POST /:type/find_or_create` finds the entity matching a search key, or
+///   creates it.
+/// * `POST /:type/search` searches entities by the words in some of their fields.
+///
+/// Policies don't apply to these routes, so they are guarded like the admin ones.
+pub(crate) fn init(api: &ApiService) -> Result<()> {
+    let entities = api
+        .add_route_group(ENTITIES_PATH, vec![Arc::new(AdminAuth)])
+        .json_body();
+    // Ahead of `/:type/:id`, which would match them too.
+    entities.add_route(Method::POST, "/:type/batch", query_engine_route(batch))?;
+    entities.add_route(Method::POST, "/:type/upsert", query_engine_route(upsert))?;
+    entities.add_route(
+        Method::POST,
+        "/:type/find_or_create",
+        query_engine_route(find_or_create),
+    )?;
+    entities.add_route(Method::POST, "/:type/search", query_engine_route(search))?;
+    entities.add_route(Method::GET, "/:type/:id", query_engine_route(find_by_id))?;
+    entities.add_route(Method::POST, "/:type", query_engine_route(save))?;
+    entities.add_route(Method::PUT, "/:type/:id", query_engine_route(replace))?;
+    entities.add_route(Method::PATCH, "/:type/:id", query_engine_route(patch))?;
+    entities.add_route(Method::DELETE, "/:type/:id", query_engine_route(delete))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::{make_field, make_object};
+
+    #[test]
+    fn deprecated_fields() {
+        let ty = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("nick", Type::String).deprecated(Some("use 'name' instead.".into())),
+                make_field("age", Type::Float).deprecated(None),
+            ],
+        );
+        let fields = json!({"name": "Ada", "nick": "ada"});
+        let fields = fields.as_object().unwrap();
+
+        let warnings = deprecated_writes(&ty, fields, false).unwrap();
+        assert_eq!(
+            warnings,
+            vec!["Field 'nick' is deprecated: use 'name' instead.".to_owned()]
+        );
+        let err = deprecated_writes(&ty, fields, true).unwrap_err();
+        assert!(err.downcast_ref::<DeprecatedFieldWrite>().is_some());
+        assert!(deprecated_writes(&ty, &JsonObject::new(), true)
+            .unwrap()
+            .is_empty());
+
+        let response = response_template().body(Body::default()).unwrap();
+        let response = with_warnings(response, &warnings).unwrap();
+        assert_eq!(
+            response.headers()[WARNING],
+            "299 - \"Field 'nick' is deprecated: use 'name' instead.\""
+        );
+
+        let row = json!({"name": "Ada", "age": 36});
+        let response = row_response(&ty, row.as_object().unwrap()).unwrap();
+        assert_eq!(
+            response.headers()[DEPRECATION_WARNING_HEADER],
+            "Field 'age' is deprecated."
+        );
+    }
+}
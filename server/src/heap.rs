@@ -0,0 +1,62 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Heap statistics and dumps of jemalloc, the allocator of `chiseld` when
+//! built with the `jemalloc` feature, served on the admin routes.
+//!
+//! Dumps need jemalloc's heap profiling, which samples allocations and is
+//! turned on at startup with `_RJEM_MALLOC_CONF=prof:true`.
+
+use crate::api::{json_response, Body};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use hyper::{Response, StatusCode};
+use jemalloc_ctl::{epoch, raw, stats};
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+/// Responds with the bytes jemalloc holds, by what it holds them for.
+pub(crate) async fn stats() -> Result<Response<Body>> {
+    // The statistics are a snapshot, refreshed by advancing the epoch.
+    epoch::advance().map_err(|e| anyhow!("could not refresh the heap statistics: {}", e))?;
+    let read = |stat: jemalloc_ctl::Result<usize>| {
+        stat.map_err(|e| anyhow!("could not read the heap statistics: {}", e))
+    };
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({
+            "allocated": read(stats::allocated::read())?,
+            "active": read(stats::active::read())?,
+            "metadata": read(stats::metadata::read())?,
+            "resident": read(stats::resident::read())?,
+            "mapped": read(stats::mapped::read())?,
+            "retained": read(stats::retained::read())?,
+        }),
+    )
+}
+
+/// Dumps the sampled allocations into a file of the temporary directory, for
+/// `jeprof`, and responds with its path. Responds with `409 Conflict` if
+/// heap profiling is off.
+pub(crate) async fn dump() -> Result<Response<Body>> {
+    // Safe as `opt.prof` is a bool.
+    let profiling = unsafe { raw::read::<bool>(b"opt.prof\0") }.unwrap_or(false);
+    if !profiling {
+        return json_response(
+            StatusCode::CONFLICT,
+            serde_json::json!({
+                "error": "heap profiling is off, start chiseld with _RJEM_MALLOC_CONF=prof:true"
+            }),
+        );
+    }
+    let path = std::env::temp_dir().join(format!(
+        "chiseld.{}.{}.heap",
+        std::process::id(),
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+    let c_path = CString::new(path.to_string_lossy().into_owned())?;
+    // Safe as `prof.dump` takes a C string, which outlives the call.
+    unsafe { raw::write(b"prof.dump\0", c_path.as_ptr() as *const c_char) }
+        .map_err(|e| anyhow!("could not dump the heap: {}", e))?;
+    info!("Dumped the heap profile to {}", path.display());
+    json_response(StatusCode::OK, serde_json::json!({ "path": path }))
+}
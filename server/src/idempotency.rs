@@ -0,0 +1,468 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Replaying the responses of retried requests.
+//!
+//! A client retrying a `POST`, `PUT`, `PATCH` or `DELETE` after a network
+//! failure can't tell whether its first attempt went through. Sending the
+//! same [`IDEMPOTENCY_KEY_HEADER`] with every attempt makes the endpoint run
+//! once: the retries get the response that was given to the first attempt.
+//! Keys are scoped to the user in the `ChiselUID` header and to the path.
+//!
+//! The first attempt claims its key by inserting a pending row before the
+//! endpoint runs, so that an attempt made while it is still running is
+//! refused rather than run again. The row then gets the response. Rows are
+//! kept for [`TTL`] in the data database, next to the session tokens, so that
+//! all executor threads see them.
+
+use crate::api::{Body, Middleware, RouteFn};
+use crate::context::RequestContext;
+use crate::datastore::engine::SqlWithArguments;
+use crate::datastore::query::SqlValue;
+use crate::datastore::QueryEngine;
+use anyhow::Result;
+use deno_core::futures::future::LocalBoxFuture;
+use deno_core::futures::FutureExt;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Added to the responses that are replayed rather than made by the endpoint.
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const IDEMPOTENCY_TABLE: &str = "__chiselstrike_idempotency";
+
+/// How long a response is replayed for.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long the key of a request that is still running stays claimed, which
+/// bounds how long a request lost to a crash blocks its retries.
+const PENDING_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Status of the rows of the requests still running.
+const PENDING: f64 = 0.0;
+
+fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Creates the table the responses are kept in, unless it exists.
+pub(crate) async fn create_table(query_engine: &QueryEngine) -> Result<()> {
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            user_id TEXT NOT NULL,
+            key TEXT NOT NULL,
+            path TEXT NOT NULL,
+            fingerprint TEXT NOT NULL,
+            status DOUBLE PRECISION NOT NULL,
+            headers TEXT NOT NULL,
+            body TEXT NOT NULL,
+            expires_at DOUBLE PRECISION NOT NULL,
+            PRIMARY KEY (user_id, key, path)
+        )",
+        IDEMPOTENCY_TABLE
+    );
+    query_engine
+        .execute_transaction(&[SqlWithArguments { sql, args: vec![] }])
+        .await?;
+    Ok(())
+}
+
+/// Tells apart the requests that reuse a key: the key must only ever be
+/// sent with the same method and body.
+fn fingerprint(method: &Method, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_str());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// What identifies the row of a request: the user it is made by, or the
+/// empty string if none, its key, and its path.
+struct RowKey {
+    user_id: String,
+    key: String,
+    path: String,
+}
+
+impl RowKey {
+    fn args(&self) -> Vec<SqlValue> {
+        vec![
+            self.user_id.as_str().into(),
+            self.key.as_str().into(),
+            self.path.as_str().into(),
+        ]
+    }
+}
+
+const ROW_KEY_CONDITION: &str = "user_id = $1 AND key = $2 AND path = $3";
+
+/// A response given to the first request with some key.
+struct Cached {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+/// What became of the claim of a request on its key.
+enum Claim {
+    /// The request has the key, and runs the endpoint.
+    Won,
+    /// An earlier request has the key, and is still running.
+    Pending { fingerprint: String },
+    /// An earlier request has the key, and was given this response.
+    Done { fingerprint: String, cached: Cached },
+    /// An earlier request had the key, but gave it up in the meantime.
+    Released,
+}
+
+/// Claims the key of a request for it, unless an earlier request did.
+async fn claim(query_engine: &QueryEngine, row_key: &RowKey, fingerprint: &str) -> Result<Claim> {
+    let now = now_secs();
+    query_engine
+        .execute_transaction(&[SqlWithArguments {
+            sql: format!("DELETE FROM {} WHERE expires_at <= $1", IDEMPOTENCY_TABLE),
+            args: vec![SqlValue::F64(now)],
+        }])
+        .await?;
+    let mut args = row_key.args();
+    args.extend([
+        fingerprint.into(),
+        SqlValue::F64(PENDING),
+        SqlValue::F64(now + PENDING_TTL.as_secs_f64()),
+    ]);
+    let inserted = query_engine
+        .execute_transaction(&[SqlWithArguments {
+            sql: format!(
+                "INSERT INTO {} (user_id, key, path, fingerprint, status, headers, body, expires_at) \
+                VALUES ($1, $2, $3, $4, $5, '[]', '', $6) ON CONFLICT DO NOTHING",
+                IDEMPOTENCY_TABLE
+            ),
+            args,
+        }])
+        .await?;
+    if inserted > 0 {
+        return Ok(Claim::Won);
+    }
+
+    let rows = query_engine
+        .fetch_all(SqlWithArguments {
+            sql: format!(
+                "SELECT fingerprint, status, headers, body FROM {} WHERE {}",
+                IDEMPOTENCY_TABLE, ROW_KEY_CONDITION
+            ),
+            args: row_key.args(),
+        })
+        .await?;
+    let row = match rows.first() {
+        Some(row) => row,
+        None => return Ok(Claim::Released),
+    };
+    let fingerprint = row.get("fingerprint");
+    let status = row.get::<f64, _>("status");
+    if status == PENDING {
+        return Ok(Claim::Pending { fingerprint });
+    }
+    let mut headers = HeaderMap::new();
+    let pairs: Vec<(String, String)> = serde_json::from_str(row.get("headers"))?;
+    for (name, value) in pairs {
+        headers.append(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(&value)?,
+        );
+    }
+    let cached = Cached {
+        status: StatusCode::from_u16(status as u16)?,
+        headers,
+        body: base64::decode(row.get::<String, _>("body"))?,
+    };
+    Ok(Claim::Done {
+        fingerprint,
+        cached,
+    })
+}
+
+/// Keeps the response to the request that claimed `row_key`.
+async fn complete(
+    query_engine: &QueryEngine,
+    row_key: &RowKey,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<()> {
+    // Headers that aren't text are left out of the replays.
+    let headers: Vec<(&str, &str)> = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect();
+    let mut args = row_key.args();
+    args.extend([
+        SqlValue::F64(status.as_u16().into()),
+        SqlValue::String(serde_json::to_string(&headers)?),
+        SqlValue::String(base64::encode(body)),
+        SqlValue::F64(now_secs() + TTL.as_secs_f64()),
+    ]);
+    query_engine
+        .execute_transaction(&[SqlWithArguments {
+            sql: format!(
+                "UPDATE {} SET status = $4, headers = $5, body = $6, expires_at = $7 WHERE {}",
+                IDEMPOTENCY_TABLE, ROW_KEY_CONDITION
+            ),
+            args,
+        }])
+        .await?;
+    Ok(())
+}
+
+/// Gives up the claim on `row_key`, so that a retry runs the endpoint again.
+/// If that fails, the claim lasts until it expires.
+async fn release(query_engine: &QueryEngine, row_key: &RowKey) {
+    let released = query_engine
+        .execute_transaction(&[SqlWithArguments {
+            sql: format!(
+                "DELETE FROM {} WHERE {}",
+                IDEMPOTENCY_TABLE, ROW_KEY_CONDITION
+            ),
+            args: row_key.args(),
+        }])
+        .await;
+    if let Err(e) = released {
+        warn!("Could not release Idempotency-Key {}: {:?}", row_key.key, e);
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(status)
+        .body(message.to_string().into())?)
+}
+
+/// Answers the requests that reuse an [`IDEMPOTENCY_KEY_HEADER`] with the
+/// status, headers and body given to the first one, or `409 Conflict` when
+/// they differ from it or it is still running. Server errors aren't kept, so
+/// that a retry can succeed.
+///
+/// Requests and responses with a key are buffered whole. Failing to keep a
+/// response is only logged, as the endpoint has run by then.
+pub(crate) struct IdempotencyMiddleware;
+
+impl IdempotencyMiddleware {
+    /// `route_fn`, behind this middleware.
+    pub(crate) fn wrap(route_fn: RouteFn) -> RouteFn {
+        Arc::new(move |req| IdempotencyMiddleware.call(req, route_fn.clone()))
+    }
+}
+
+impl Middleware for IdempotencyMiddleware {
+    fn call(
+        &self,
+        req: Request<hyper::Body>,
+        next: RouteFn,
+    ) -> LocalBoxFuture<'static, Result<Response<Body>>> {
+        let unsafe_method = [Method::POST, Method::PUT, Method::PATCH, Method::DELETE];
+        let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+            Some(key) if unsafe_method.contains(req.method()) => key.clone(),
+            _ => return next(req),
+        };
+        async move {
+            let key = match key
+                .to_str()
+                .ok()
+                .and_then(|k| Uuid::parse_str(k.trim()).ok())
+            {
+                Some(key) => key.to_string(),
+                None => return error(StatusCode::BAD_REQUEST, "Idempotency-Key must be a UUID"),
+            };
+            let user_id = match req.headers().get("ChiselUID") {
+                Some(user_id) => user_id.to_str()?.to_owned(),
+                None => String::new(),
+            };
+            let row_key = RowKey {
+                user_id,
+                key,
+                path: req.uri().path().to_owned(),
+            };
+            let query_engine = RequestContext::of(&req)?.query_engine;
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            let fingerprint = fingerprint(&parts.method, &body);
+
+            let first = match claim(&query_engine, &row_key, &fingerprint).await? {
+                Claim::Won => None,
+                Claim::Pending { fingerprint } => Some((fingerprint, None)),
+                Claim::Done {
+                    fingerprint,
+                    cached,
+                } => Some((fingerprint, Some(cached))),
+                Claim::Released => {
+                    return error(
+                        StatusCode::CONFLICT,
+                        "the request with this Idempotency-Key just failed, retry",
+                    )
+                }
+            };
+            if let Some((first_fingerprint, cached)) = first {
+                if first_fingerprint != fingerprint {
+                    return error(
+                        StatusCode::CONFLICT,
+                        "Idempotency-Key was already used with a different request",
+                    );
+                }
+                let cached = match cached {
+                    Some(cached) => cached,
+                    None => {
+                        return error(
+                            StatusCode::CONFLICT,
+                            "a request with this Idempotency-Key is still running",
+                        )
+                    }
+                };
+                let mut response = Response::builder().status(cached.status);
+                *response.headers_mut().unwrap() = cached.headers;
+                return Ok(response
+                    .header(REPLAYED_HEADER, "true")
+                    .body(Body::from_bytes(cached.body.into()))?);
+            }
+
+            let response = next(Request::from_parts(parts, body.into())).await;
+            let response = match response {
+                Ok(response) if !response.status().is_server_error() => response,
+                response => {
+                    release(&query_engine, &row_key).await;
+                    return response;
+                }
+            };
+            let (parts, body) = response.into_parts();
+            let body = match hyper::body::to_bytes(body).await {
+                Ok(body) => body,
+                Err(e) => {
+                    release(&query_engine, &row_key).await;
+                    return Err(e);
+                }
+            };
+            if let Err(e) =
+                complete(&query_engine, &row_key, parts.status, &parts.headers, &body).await
+            {
+                warn!(
+                    "Could not keep the response to Idempotency-Key {}: {:?}",
+                    row_key.key, e
+                );
+            }
+            Ok(Response::from_parts(parts, Body::from_bytes(body)))
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::LocalBlobStore;
+    use crate::datastore::query::tests::setup_clear_db;
+    use crate::types::TypeSystem;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn replays_responses() {
+        let (qeng, db_file) = setup_clear_db(&[]).await;
+        create_table(&qeng).await.unwrap();
+        let store = Arc::new(LocalBlobStore::new(db_file.path().with_extension("blobs")));
+        let context = RequestContext::new(Arc::new(qeng), store, TypeSystem::default());
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let route = IdempotencyMiddleware::wrap({
+            let calls = calls.clone();
+            Arc::new(move |_req| {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    Ok(Response::builder()
+                        .status(201)
+                        .header("Location", format!("/dev/orders/{}", n))
+                        .body(format!("{}", n).into())?)
+                }
+                .boxed_local()
+            })
+        });
+        let send = |user: &str, key: &str, body: &str| {
+            let mut req = Request::builder()
+                .method(Method::POST)
+                .uri("/dev/orders")
+                .header("ChiselUID", user)
+                .header(IDEMPOTENCY_KEY_HEADER, key)
+                .body(hyper::Body::from(body.to_owned()))
+                .unwrap();
+            req.extensions_mut().insert(context.clone());
+            let route = route.clone();
+            async move {
+                let response = route(req).await.unwrap();
+                let status = response.status();
+                let location = response.headers().get("Location").cloned();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, location, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+        let created = |n: usize| {
+            let location = HeaderValue::from_str(&format!("/dev/orders/{}", n)).unwrap();
+            (StatusCode::CREATED, Some(location), n.to_string())
+        };
+
+        let key = Uuid::new_v4().to_string();
+        assert_eq!(send("ada", &key, "{}").await, created(0));
+        assert_eq!(send("ada", &key, "{}").await, created(0));
+        assert_eq!(send("ada", &key, "{\"a\":1}").await.0, StatusCode::CONFLICT);
+        assert_eq!(send("bob", &key, "{}").await, created(1));
+        let other = Uuid::new_v4().to_string();
+        assert_eq!(send("ada", &other, "{}").await, created(2));
+        assert_eq!(
+            send("ada", "not-a-uuid", "{}").await.0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_while_the_first_runs() {
+        let (qeng, _db_file) = setup_clear_db(&[]).await;
+        create_table(&qeng).await.unwrap();
+        let row_key = RowKey {
+            user_id: "ada".into(),
+            key: Uuid::new_v4().to_string(),
+            path: "/dev/orders".into(),
+        };
+
+        assert!(matches!(
+            claim(&qeng, &row_key, "f").await.unwrap(),
+            Claim::Won
+        ));
+        assert!(matches!(
+            claim(&qeng, &row_key, "f").await.unwrap(),
+            Claim::Pending { fingerprint } if fingerprint == "f"
+        ));
+        release(&qeng, &row_key).await;
+        assert!(matches!(
+            claim(&qeng, &row_key, "f").await.unwrap(),
+            Claim::Won
+        ));
+        let headers = HeaderMap::new();
+        complete(&qeng, &row_key, StatusCode::OK, &headers, b"done")
+            .await
+            .unwrap();
+        match claim(&qeng, &row_key, "f").await.unwrap() {
+            Claim::Done { cached, .. } => assert_eq!(cached.body, b"done"),
+            _ => panic!("the response should be kept"),
+        }
+    }
+}
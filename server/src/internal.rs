@@ -1,17 +1,24 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::chisel::{chisel_rpc_client::ChiselRpcClient, ChiselApplyRequest};
+use crate::datastore::QueryEngine;
+use crate::metrics;
 use anyhow::Result;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use once_cell::sync::OnceCell;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 /// If set, serve the web UI using this address for gRPC calls.
 static SERVE_WEBUI: OnceCell<SocketAddr> = OnceCell::new();
 
+/// Query engine and timeout used by the readiness probe to check the database.
+static HEALTH_CHECK: OnceCell<(QueryEngine, Duration)> = OnceCell::new();
+
 fn response(body: &str, status: u16) -> Result<Response<Body>> {
     Ok(Response::builder()
         .status(status)
@@ -19,6 +26,28 @@ fn response(body: &str, status: u16) -> Result<Response<Body>> {
         .unwrap())
 }
 
+async fn readiness() -> Result<Response<Body>> {
+    let (query_engine, timeout) = HEALTH_CHECK
+        .get()
+        .expect("HEALTH_CHECK not initialized before serving internal routes");
+    match query_engine.health_check(*timeout).await {
+        Ok(latency) => {
+            let body = json!({
+                "status": "ready",
+                "db_latency_ms": latency.as_secs_f64() * 1000.0,
+            });
+            response(&body.to_string(), 200)
+        }
+        Err(e) => {
+            let body = json!({
+                "status": "not ready",
+                "error": format!("{:#}", e),
+            });
+            response(&body.to_string(), 503)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct WebUIPostBody {
     endpoint: String,
@@ -39,6 +68,8 @@ async fn webapply(body: Body, rpc_addr: &SocketAddr) -> Result<Response<Body>> {
             version: "dev".into(),
             version_tag: "dev".into(),
             app_name: "ChiselStrike WebUI".into(),
+            dry_run: false,
+            schemas: Default::default(),
         }))
         .await?;
     response("applied", 200)
@@ -49,10 +80,12 @@ async fn route(req: Request<Body>) -> Result<Response<Body>> {
         // Conceptually those checks are different and could eventually become
         // more complex functions. But for now we just return simple strings.
         // FWIW, K8s does not require us to return those specific strings.
-        // Anything that returns a code 200 is enough.
+        // Anything that returns a code 200 is enough. These routes don't go
+        // through the ApiService, so they are never wrapped in an Envelope.
         ("/status", _) => response("ok", 200),
-        ("/readiness", _) => response("ready", 200),
+        ("/readiness", _) => readiness().await,
         ("/liveness", _) => response("alive", 200),
+        ("/metrics", _) => response(&metrics::render(), 200),
         ("/apply", Some(rpc_addr)) => webapply(req.into_body(), rpc_addr).await,
         ("/webui", Some(_)) => {
             let html = std::str::from_utf8(include_bytes!("webui.html"))?;
@@ -68,7 +101,17 @@ async fn route(req: Request<Body>) -> Result<Response<Body>> {
 /// Unlike the API server, it is strictly bound to 127.0.0.1. This is enough
 /// for the Kubernetes checks to work, and it is one less thing for us to secure
 /// and prevent DDoS attacks again - which is why this is a different server
-pub(crate) fn init(addr: SocketAddr, serve_webui: bool, rpc_addr: SocketAddr) {
+pub(crate) fn init(
+    addr: SocketAddr,
+    serve_webui: bool,
+    rpc_addr: SocketAddr,
+    query_engine: QueryEngine,
+    health_check_timeout: Duration,
+) {
+    HEALTH_CHECK
+        .set((query_engine, health_check_timeout))
+        .map_err(|_| ())
+        .expect("HEALTH_CHECK already initialized before internal::init()");
     if serve_webui {
         SERVE_WEBUI
             .set(rpc_addr)
@@ -7,18 +7,71 @@
 //! metadata of the ChiselStrike server endpoints as OpenAPI 2.0 format:
 //!
 //! https://swagger.io/specification/v2/
+//!
+//! The entity types of the API version are listed under `definitions`, with
+//! the descriptions taken from their JSDoc comments.
 
 use crate::api::{response_template, ApiService, Body};
 use crate::runtime;
+use crate::types::{ObjectType, Type, VersionTypes};
 use anyhow::Result;
 use deno_core::futures;
 use futures::FutureExt;
 use hyper::{Request, Response};
 use openapi::{Info, Operations, Spec};
+use serde_json::{json, Map, Value};
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+/// The JSON Schema of the values of `ty`, as used by OpenAPI 2.0.
+fn type_schema(ty: &Type) -> Value {
+    match ty {
+        Type::String | Type::Id | Type::Blob => json!({"type": "string"}),
+        Type::Enum(variants) => json!({"type": "string", "enum": variants}),
+        Type::Float => json!({"type": "number"}),
+        Type::Boolean => json!({"type": "boolean"}),
+        Type::Array(elem) => json!({"type": "array", "items": type_schema(elem)}),
+        Type::Map(value) => json!({
+            "type": "object",
+            "additionalProperties": value.as_deref().map_or(json!(true), type_schema),
+        }),
+        Type::Object(ty) => json!({ "$ref": format!("#/definitions/{}", ty.name()) }),
+    }
+}
+
+fn object_schema(ty: &ObjectType) -> Value {
+    let mut properties = Map::new();
+    let mut required = vec![];
+    for field in ty.all_fields() {
+        let mut schema = type_schema(&field.type_);
+        if let Some(description) = &field.description {
+            schema["description"] = json!(description);
+        }
+        if field.is_deprecated {
+            schema["deprecated"] = json!(true);
+        }
+        properties.insert(field.json_name().to_owned(), schema);
+        if !field.is_optional {
+            required.push(field.json_name());
+        }
+    }
+    let mut schema = json!({"type": "object", "properties": properties, "required": required});
+    if let Some(description) = ty.description() {
+        schema["description"] = json!(description);
+    }
+    schema
+}
+
+fn definitions(types: &VersionTypes) -> Value {
+    let definitions: Map<String, Value> = types
+        .custom_types
+        .values()
+        .map(|ty| (ty.name().to_owned(), object_schema(ty)))
+        .collect();
+    Value::Object(definitions)
+}
+
 async fn introspect(req: Request<hyper::Body>) -> Result<Response<Body>> {
     let api = runtime::get().api.clone();
 
@@ -70,21 +123,53 @@ async fn introspect(req: Request<hyper::Body>) -> Result<Response<Body>> {
         security_definitions: None,
         tags: None,
     };
+    let mut spec = serde_json::to_value(&spec)?;
+    if let Ok(types) = runtime::get()
+        .context
+        .type_system()
+        .get_version(api_version)
+    {
+        spec["definitions"] = definitions(types);
+    }
     Ok(response_template()
-        .body(openapi::to_json(&spec).unwrap().into())
+        .body(serde_json::to_string_pretty(&spec)?.into())
         .unwrap())
 }
 
-pub(crate) fn add_introspection<P: AsRef<Path>>(api: &ApiService, path: P) {
+pub(crate) fn add_introspection<P: AsRef<Path>>(api: &ApiService, path: P) -> Result<()> {
     let mut introspect_route = PathBuf::from("/");
     introspect_route.push(&path);
     api.add_route(
         introspect_route,
         Arc::new(move |req| { introspect(req) }.boxed_local()),
-    );
+    )
 }
 
-pub(crate) fn init(api: &ApiService) {
-    add_introspection(api, "/");
-    add_introspection(api, "__chiselstrike");
+pub(crate) fn init(api: &ApiService) -> Result<()> {
+    add_introspection(api, "/")?;
+    add_introspection(api, "__chiselstrike")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::{make_field, VERSION};
+    use crate::types::{AuthOrNot, NewObject};
+
+    #[test]
+    fn described_schema() {
+        let fields = vec![
+            make_field("name", Type::String).with_description(Some("Full name".into())),
+            make_field("age", Type::Float),
+        ];
+        let desc = NewObject::new("Person", VERSION);
+        let person = ObjectType::new(desc, fields, vec![], AuthOrNot::IsNotAuth)
+            .unwrap()
+            .with_description(Some("Someone".into()));
+        let schema = object_schema(&person);
+        assert_eq!(schema["description"], "Someone");
+        assert_eq!(schema["properties"]["name"]["description"], "Full name");
+        assert_eq!(schema["properties"]["age"], json!({"type": "number"}));
+        assert_eq!(schema["required"], json!(["id", "name", "age"]));
+    }
 }
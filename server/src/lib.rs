@@ -14,21 +14,47 @@ macro_rules! send_command {
 #[macro_use]
 extern crate log;
 
+pub(crate) mod admin;
 pub(crate) mod api;
 pub(crate) mod auth;
+pub(crate) mod backup;
+#[doc(hidden)]
+pub mod bench;
+pub(crate) mod blob;
+pub(crate) mod context;
 pub(crate) mod datastore;
 pub(crate) mod deno;
+pub(crate) mod entities;
+#[cfg(feature = "jemalloc")]
+pub(crate) mod heap;
+pub(crate) mod idempotency;
 pub(crate) mod internal;
 pub(crate) mod introspect;
+pub mod logs;
+pub(crate) mod metrics;
+pub(crate) mod migrations;
+pub(crate) mod multipart;
 pub(crate) mod policies;
 pub(crate) mod prefix_map;
+#[cfg(feature = "profiling")]
+pub(crate) mod profiler;
 pub(crate) mod rcmut;
+pub(crate) mod region;
+pub(crate) mod route_pattern;
+pub(crate) mod route_trie;
+pub(crate) mod route_version;
 pub(crate) mod rpc;
 pub(crate) mod runtime;
+pub(crate) mod schema_validation;
 pub(crate) mod secrets;
 pub mod server;
+pub(crate) mod session_cache;
+pub(crate) mod sse;
+pub(crate) mod tenancy;
+pub(crate) mod transactions;
 pub(crate) mod types;
 pub(crate) mod vecmap;
+pub(crate) mod webhooks;
 
 pub(crate) mod chisel {
     tonic::include_proto!("chisel");
@@ -0,0 +1,354 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! The recent log of the server, streamed to `chisel logs`.
+//!
+//! Every line the logger prints is also kept in memory, as a [`LogEvent`]
+//! tagged with the request being handled when it was logged, and sent to
+//! the followers of `GET /__chiselstrike/admin/logs/stream`. So is one access
+//! event per request served, which `chisel analyze` reads, but those are
+//! only printed to the `--access-log` file.
+
+use crate::api::{response_template, Body};
+use anyhow::{Context, Result};
+use deno_core::futures::stream::{self, StreamExt};
+use deno_core::url::form_urlencoded;
+use hyper::{Method, Request, Response, StatusCode};
+use log::{warn, Level, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Only this many of the latest events are kept for replaying.
+const BUFFERED_EVENTS: usize = 10000;
+
+/// Target of the access events.
+const ACCESS_TARGET: &str = "chisel::access";
+
+/// Where the access events are appended, one JSON object per line.
+static ACCESS_LOG: Lazy<Mutex<Option<File>>> = Lazy::new(Default::default);
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the request whose handling is running, if any.
+pub(crate) fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs `f`, tagging what it logs with `request_id`.
+pub(crate) async fn with_request_id<F: Future>(request_id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(request_id, f).await
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct LogEvent {
+    /// Seconds since the Unix epoch.
+    timestamp: f64,
+    level: Level,
+    target: String,
+    message: String,
+    request_id: Option<String>,
+}
+
+impl LogEvent {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "timestamp": self.timestamp,
+            "level": self.level.as_str(),
+            "target": self.target,
+            "message": self.message,
+            "request_id": self.request_id,
+        })
+    }
+}
+
+struct LogHub {
+    recent: Mutex<VecDeque<LogEvent>>,
+    sender: broadcast::Sender<LogEvent>,
+}
+
+static HUB: Lazy<LogHub> = Lazy::new(|| LogHub {
+    recent: Mutex::new(VecDeque::new()),
+    sender: broadcast::channel(1024).0,
+});
+
+impl LogHub {
+    fn push(&self, event: LogEvent) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == BUFFERED_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // Fails when nobody follows, which is fine.
+        let _ = self.sender.send(event);
+    }
+
+    /// The events kept so far, and the ones to come.
+    fn subscribe(&self) -> (Vec<LogEvent>, broadcast::Receiver<LogEvent>) {
+        let recent = self.recent.lock().unwrap();
+        (recent.iter().cloned().collect(), self.sender.subscribe())
+    }
+}
+
+/// Prints with the wrapped logger, and keeps what it prints for streaming.
+struct CapturingLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        HUB.push(LogEvent {
+            timestamp: now(),
+            level: record.level(),
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+            request_id: current_request_id(),
+        });
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Installs `logger` as the global logger, capturing what it prints.
+pub fn init(logger: env_logger::Logger) {
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(CapturingLogger { inner: logger }))
+        .expect("the logger is set only once");
+}
+
+/// Appends the access events to the file at `path` from now on.
+pub(crate) fn set_access_log(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("could not open the access log {}", path.display()))?;
+    *ACCESS_LOG.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Records that the request `method path`, served by the route `route`,
+/// got a `status` response after `duration`.
+pub(crate) fn log_access(
+    method: &Method,
+    path: &str,
+    route: Option<&str>,
+    status: StatusCode,
+    duration: Duration,
+) {
+    let timestamp = now();
+    let request_id = current_request_id();
+    let message = serde_json::json!({
+        "timestamp": timestamp,
+        "method": method.as_str(),
+        "path": path,
+        "route": route,
+        "status": status.as_u16(),
+        "duration_ms": duration.as_secs_f64() * 1000.,
+        "request_id": request_id,
+    })
+    .to_string();
+    if let Some(file) = ACCESS_LOG.lock().unwrap().as_mut() {
+        if let Err(err) = writeln!(file, "{}", message) {
+            warn!("Could not write the access log: {}", err);
+        }
+    }
+    HUB.push(LogEvent {
+        timestamp,
+        level: Level::Info,
+        target: ACCESS_TARGET.to_owned(),
+        message,
+        request_id,
+    });
+}
+
+/// Which events a follower of the log wants.
+struct Filter {
+    /// The least severe level shown.
+    level: Level,
+    /// Only the events of this request, whatever their level.
+    request_id: Option<String>,
+    /// Only the events logged after this time, in seconds since the Unix epoch.
+    since: f64,
+    /// The access events instead of the others.
+    access: bool,
+}
+
+impl Filter {
+    fn matches(&self, event: &LogEvent) -> bool {
+        let level = match &self.request_id {
+            Some(id) => event.request_id.as_ref() == Some(id),
+            None => event.level <= self.level,
+        };
+        let access = (event.target == ACCESS_TARGET) == self.access;
+        level && access && event.timestamp >= self.since
+    }
+}
+
+fn sse_event(event: &LogEvent) -> Result<Box<[u8]>> {
+    Ok(format!("data: {}\n\n", event.to_json())
+        .into_bytes()
+        .into_boxed_slice())
+}
+
+/// Streams the log as server-sent events holding JSON objects.
+///
+/// Takes the query parameters `level` (`info` unless set), `request_id`,
+/// which shows the events of a request at all levels, `since`, the number
+/// of seconds of past events to replay before the new ones (all the kept
+/// ones unless set), `follow`, which ends the stream after the replay
+/// when `false`, and `access`, which streams the access events instead of
+/// the log when `true`. The message of an access event is a JSON object.
+pub(crate) async fn stream(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let mut filter = Filter {
+        level: Level::Info,
+        request_id: None,
+        since: f64::MIN,
+        access: false,
+    };
+    let mut follow = true;
+    let query = req.uri().query().unwrap_or_default();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match &*key {
+            "level" => {
+                filter.level = Level::from_str(&value)
+                    .with_context(|| format!("unknown log level '{}'", value))?
+            }
+            "request_id" => filter.request_id = Some(value.into_owned()),
+            "since" => {
+                let since: f64 = value
+                    .parse()
+                    .with_context(|| format!("since must be seconds, got '{}'", value))?;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                filter.since = now.as_secs_f64() - since;
+            }
+            "follow" => {
+                follow = value
+                    .parse()
+                    .with_context(|| format!("follow must be a boolean, got '{}'", value))?
+            }
+            "access" => {
+                filter.access = value
+                    .parse()
+                    .with_context(|| format!("access must be a boolean, got '{}'", value))?
+            }
+            _ => {}
+        }
+    }
+
+    let (recent, receiver) = HUB.subscribe();
+    let replayed: Vec<_> = recent
+        .iter()
+        .filter(|e| filter.matches(e))
+        .cloned()
+        .collect();
+    let replayed = stream::iter(replayed).map(|e| sse_event(&e));
+    let body = if follow {
+        let new = stream::unfold(receiver, move |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    // Whoever follows that slowly misses some lines.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        let new = new
+            .filter(move |e| std::future::ready(filter.matches(e)))
+            .map(|e| sse_event(&e));
+        Body::Stream(Box::pin(replayed.chain(new)))
+    } else {
+        Body::Stream(Box::pin(replayed))
+    };
+    Ok(response_template()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(level: Level, request_id: Option<&str>, timestamp: f64) -> LogEvent {
+        LogEvent {
+            timestamp,
+            level,
+            target: "chisel_server".into(),
+            message: "hello".into(),
+            request_id: request_id.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let warnings = Filter {
+            level: Level::Warn,
+            request_id: None,
+            since: 10.,
+            access: false,
+        };
+        assert!(warnings.matches(&event(Level::Error, None, 10.)));
+        assert!(!warnings.matches(&event(Level::Info, None, 10.)));
+        assert!(!warnings.matches(&event(Level::Error, None, 9.)));
+
+        let request = Filter {
+            level: Level::Warn,
+            request_id: Some("r1".into()),
+            since: f64::MIN,
+            access: false,
+        };
+        assert!(request.matches(&event(Level::Debug, Some("r1"), 0.)));
+        assert!(!request.matches(&event(Level::Error, Some("r2"), 0.)));
+        assert!(!request.matches(&event(Level::Error, None, 0.)));
+
+        let mut access = event(Level::Info, Some("r1"), 0.);
+        access.target = ACCESS_TARGET.into();
+        assert!(!request.matches(&access));
+        let accesses = Filter {
+            level: Level::Info,
+            request_id: None,
+            since: f64::MIN,
+            access: true,
+        };
+        assert!(accesses.matches(&access));
+        assert!(!accesses.matches(&event(Level::Info, None, 0.)));
+    }
+
+    #[tokio::test]
+    async fn tags_requests() {
+        assert_eq!(current_request_id(), None);
+        let id = with_request_id("r1".into(), async {
+            tokio::task::yield_now().await;
+            current_request_id()
+        })
+        .await;
+        assert_eq!(id.as_deref(), Some("r1"));
+    }
+}
@@ -4,7 +4,7 @@
 extern crate log;
 
 use anyhow::Result;
-use chisel_server::server;
+use chisel_server::{logs, server};
 use env_logger::Env;
 use log::LevelFilter;
 use nix::unistd::execv;
@@ -14,9 +14,13 @@ use std::ffi::CString;
 use std::io::Write;
 use structopt::StructOpt;
 
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+    let logger = env_logger::Builder::from_env(Env::default().default_filter_or("info"))
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -27,7 +31,8 @@ async fn main() -> Result<()> {
             )
         })
         .filter_module("sqlx::query", LevelFilter::Warn)
-        .init();
+        .build();
+    logs::init(logger);
 
     let args: Vec<CString> = env::args().map(|x| CString::new(x).unwrap()).collect();
     let exe = env::current_exe()?.into_os_string().into_string().unwrap();
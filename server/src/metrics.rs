@@ -0,0 +1,206 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Counters of the server process, served on the internal `/metrics` route
+//! in the Prometheus text format.
+
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Queries cut short by the `--query-timeout-ms` deadline.
+static QUERY_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// What a query does to its table, as labeled in the per-table metrics.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) enum Operation {
+    Select,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Select => "select",
+            Operation::Insert => "insert",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+/// Upper bounds, in seconds, of the buckets of `chisel_query_duration_seconds`.
+/// The same as the default ones of the Prometheus clients.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct QueryStats {
+    total: u64,
+    /// Queries that took at most the matching bound of [`DURATION_BUCKETS`].
+    buckets: [u64; DURATION_BUCKETS.len()],
+    seconds: f64,
+}
+
+/// Statistics of the queries, by the backing table they run on. The
+/// executor threads each have their own query engine, so this is kept for
+/// the whole process.
+static QUERY_STATS: Lazy<Mutex<BTreeMap<(String, Operation), QueryStats>>> =
+    Lazy::new(Default::default);
+
+/// When set, [`render`] starts the per-table statistics over once it has
+/// rendered them, so that each call only shows the queries made since the
+/// previous one. Lets tests look at their own queries alone.
+pub(crate) static RESET_COUNTERS: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn count_query_timeout() {
+    QUERY_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn query_timeouts() -> u64 {
+    QUERY_TIMEOUTS.load(Ordering::Relaxed)
+}
+
+/// Counts a query that took `duration` to run `operation` on `table`.
+pub(crate) fn observe_query(table: &str, operation: Operation, duration: Duration) {
+    let mut stats = QUERY_STATS.lock().unwrap();
+    let stats = stats.entry((table.to_owned(), operation)).or_default();
+    let seconds = duration.as_secs_f64();
+    stats.total += 1;
+    stats.seconds += seconds;
+    for (bound, count) in DURATION_BUCKETS.iter().zip(&mut stats.buckets) {
+        if seconds <= *bound {
+            *count += 1;
+        }
+    }
+}
+
+/// Times a query from its creation until it is dropped, see [`time_query`].
+pub(crate) struct QueryTimer {
+    table: String,
+    operation: Operation,
+    start: Instant,
+}
+
+/// Starts timing a query running `operation` on `table`. The query is
+/// counted when the timer is dropped, e.g. when the last row is read.
+pub(crate) fn time_query(table: &str, operation: Operation) -> QueryTimer {
+    QueryTimer {
+        table: table.to_owned(),
+        operation,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        observe_query(&self.table, self.operation, self.start.elapsed());
+    }
+}
+
+/// All the metrics, in the Prometheus text exposition format.
+pub(crate) fn render() -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# HELP query_timeout_ms Queries cancelled for running past the query timeout."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE query_timeout_ms counter").unwrap();
+    writeln!(out, "query_timeout_ms {}", query_timeouts()).unwrap();
+
+    let mut stats = QUERY_STATS.lock().unwrap();
+    let labels = |table: &str, operation: &Operation| {
+        format!("table=\"{}\",operation=\"{}\"", table, operation.as_str())
+    };
+    writeln!(
+        out,
+        "# HELP chisel_query_total Queries run, by table and operation."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE chisel_query_total counter").unwrap();
+    for ((table, operation), stats) in stats.iter() {
+        let labels = labels(table, operation);
+        writeln!(out, "chisel_query_total{{{}}} {}", labels, stats.total).unwrap();
+    }
+    writeln!(
+        out,
+        "# HELP chisel_query_duration_seconds Time spent running queries, by table and operation."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE chisel_query_duration_seconds histogram").unwrap();
+    for ((table, operation), stats) in stats.iter() {
+        let labels = labels(table, operation);
+        let name = "chisel_query_duration_seconds";
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&stats.buckets) {
+            writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, count
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, stats.total
+        )
+        .unwrap();
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, stats.seconds).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, stats.total).unwrap();
+    }
+    if RESET_COUNTERS.load(Ordering::Relaxed) {
+        stats.clear();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_table_queries() {
+        let table = "metrics_per_table_queries";
+        observe_query(table, Operation::Select, Duration::from_millis(500));
+        observe_query(table, Operation::Select, Duration::from_secs(2));
+        drop(time_query(table, Operation::Delete));
+
+        let out = render();
+        let select = "table=\"metrics_per_table_queries\",operation=\"select\"";
+        for line in [
+            format!("chisel_query_total{{{}}} 2", select),
+            format!(
+                "chisel_query_duration_seconds_bucket{{{},le=\"0.25\"}} 0",
+                select
+            ),
+            format!(
+                "chisel_query_duration_seconds_bucket{{{},le=\"0.5\"}} 1",
+                select
+            ),
+            format!(
+                "chisel_query_duration_seconds_bucket{{{},le=\"2.5\"}} 2",
+                select
+            ),
+            format!(
+                "chisel_query_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                select
+            ),
+            format!("chisel_query_duration_seconds_sum{{{}}} 2.5", select),
+            "chisel_query_total{table=\"metrics_per_table_queries\",operation=\"delete\"} 1"
+                .to_owned(),
+        ] {
+            assert!(out.lines().any(|l| l == line), "{} not in {}", line, out);
+        }
+
+        RESET_COUNTERS.store(true, Ordering::Relaxed);
+        render();
+        RESET_COUNTERS.store(false, Ordering::Relaxed);
+        assert!(!render().contains(table));
+    }
+}
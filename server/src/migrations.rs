@@ -0,0 +1,537 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Hand-written SQL migrations, from the `.up.sql` files that `chisel
+//! generate migration` scaffolds, and the table recording which of them
+//! were run on the database. They are run by
+//! [`QueryEngine::execute_raw_migration`].
+//!
+//! The table also records the types whose tables were migrated on their
+//! first query, with `--lazy-migrations`, see [`QueryEngine::migrate_lazily`].
+
+use crate::datastore::engine::SqlWithArguments;
+use crate::datastore::query::SqlValue;
+use crate::datastore::{Kind, QueryEngine};
+use anyhow::Result;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use regex::{Match, Regex};
+use sqlx::Row;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+pub(crate) const MIGRATIONS_TABLE: &str = "__chiselstrike_migrations";
+
+/// The records of lazily migrated types have ids made of this and their
+/// backing table, which hand-written migrations can't start with.
+const TYPE_PREFIX: &str = "type:";
+
+/// The tables known to be migrated lazily, by the URI of their database and
+/// their name. The executor threads each have their own query engine, so
+/// this is kept for the whole process.
+static MIGRATED_TYPES: Lazy<Mutex<HashSet<(String, String)>>> = Lazy::new(Default::default);
+
+/// A migration that was run, successfully or not.
+pub(crate) struct MigrationRecord {
+    pub(crate) id: String,
+    /// When the migration was run.
+    pub(crate) applied_at: String,
+    /// Why the migration failed, if it did. Failed migrations are not run
+    /// again until their record is deleted.
+    pub(crate) error: Option<String>,
+}
+
+/// Creates the table recording the migrations that were run, unless it exists.
+pub(crate) async fn create_table(query_engine: &QueryEngine) -> Result<()> {
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            id TEXT PRIMARY KEY,
+            applied_at TEXT NOT NULL,
+            error TEXT
+        )",
+        MIGRATIONS_TABLE
+    );
+    query_engine
+        .execute_transaction(&[SqlWithArguments { sql, args: vec![] }])
+        .await?;
+    Ok(())
+}
+
+/// The migrations that were run, in the order of their ids.
+pub(crate) async fn records(query_engine: &QueryEngine) -> Result<Vec<MigrationRecord>> {
+    let rows = query_engine
+        .fetch_all(SqlWithArguments {
+            sql: format!(
+                "SELECT id, applied_at, error FROM {} WHERE id NOT LIKE '{}%' ORDER BY id",
+                MIGRATIONS_TABLE, TYPE_PREFIX
+            ),
+            args: vec![],
+        })
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| MigrationRecord {
+            id: row.get("id"),
+            applied_at: row.get("applied_at"),
+            error: row.get("error"),
+        })
+        .collect())
+}
+
+/// Records the migration `id` as applied without running it, for databases
+/// that were migrated by other means. A failed migration can be faked too,
+/// once what it was meant to do was done by hand.
+pub(crate) async fn fake(query_engine: &QueryEngine, id: &str) -> Result<()> {
+    let records = records(query_engine).await?;
+    let sql = match records.iter().find(|r| r.id == id) {
+        Some(record) if record.error.is_none() => {
+            anyhow::bail!("migration {} was applied already", id)
+        }
+        Some(_) => format!(
+            "UPDATE {} SET applied_at = $2, error = NULL WHERE id = $1",
+            MIGRATIONS_TABLE
+        ),
+        None => format!(
+            "INSERT INTO {} (id, applied_at) VALUES ($1, $2)",
+            MIGRATIONS_TABLE
+        ),
+    };
+    let args = vec![
+        SqlValue::String(id.to_owned()),
+        SqlValue::String(Utc::now().to_rfc3339()),
+    ];
+    query_engine
+        .execute_transaction(&[SqlWithArguments { sql, args }])
+        .await?;
+    Ok(())
+}
+
+/// The id of the record of the lazy migration of `table`.
+pub(crate) fn type_migration_id(table: &str) -> String {
+    format!("{}{}", TYPE_PREFIX, table)
+}
+
+pub(crate) fn is_type_migrated(conn_uri: &str, table: &str) -> bool {
+    let key = (conn_uri.to_owned(), table.to_owned());
+    MIGRATED_TYPES.lock().unwrap().contains(&key)
+}
+
+/// Remembers that `table` was migrated, once its record is committed.
+pub(crate) fn set_type_migrated(conn_uri: &str, table: &str) {
+    let key = (conn_uri.to_owned(), table.to_owned());
+    MIGRATED_TYPES.lock().unwrap().insert(key);
+}
+
+/// The statements of `sql`, with the line each starts on, counting from 1.
+///
+/// Statements end at the semicolons that are not in a string, a quoted
+/// identifier or a comment. Statements holding nothing but comments are left
+/// out. Dollar-quoted PostgreSQL strings are not recognized, so the bodies
+/// of functions can't hold semicolons.
+pub(crate) fn split_statements(sql: &str) -> Vec<(usize, String)> {
+    let mut statements = vec![];
+    let mut current = String::new();
+    // The line of the first character of `current` that isn't blank or a comment.
+    let mut start_line = None;
+    let mut line = 1;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                start_line.get_or_insert(line);
+                current.push(c);
+                for q in chars.by_ref() {
+                    current.push(q);
+                    if q == '\n' {
+                        line += 1;
+                    }
+                    // A doubled quote is an escaped one, read as the next string.
+                    if q == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for q in chars.by_ref() {
+                    if q == '\n' {
+                        current.push(q);
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for q in chars.by_ref() {
+                    if q == '\n' {
+                        current.push(q);
+                        line += 1;
+                    }
+                    if previous == '*' && q == '/' {
+                        break;
+                    }
+                    previous = q;
+                }
+            }
+            ';' => {
+                if let Some(start) = start_line.take() {
+                    statements.push((start, current.trim().to_owned()));
+                }
+                current.clear();
+            }
+            _ => {
+                if c == '\n' {
+                    line += 1;
+                } else if !c.is_whitespace() {
+                    start_line.get_or_insert(line);
+                }
+                current.push(c);
+            }
+        }
+    }
+    if let Some(start) = start_line {
+        statements.push((start, current.trim().to_owned()));
+    }
+    statements
+}
+
+/// Statements creating or dropping a table, an index or a view.
+static OBJECT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r#"(?is)^(CREATE|DROP)\s+(?:UNIQUE\s+)?(TABLE|INDEX|VIEW)(\s+IF(?:\s+NOT)?\s+EXISTS)?\s+("[^"]+"|[\w.]+)(?:\s+ON\s+("[^"]+"|[\w.]+))?"#,
+    )
+    .unwrap()
+});
+
+/// Statements changing the rows or the columns of a table.
+static WRITE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)^(?:ALTER\s+TABLE|INSERT\s+INTO|UPDATE|DELETE\s+FROM)\s+("[^"]+"|[\w.]+)"#)
+        .unwrap()
+});
+
+/// Statements adding a column, or a constraint if `ADD` isn't followed by `COLUMN`.
+static ADD_COLUMN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r#"(?is)^ALTER\s+TABLE\s+(?:"[^"]+"|[\w.]+)\s+(ADD)(\s+COLUMN)?(\s+IF\s+NOT\s+EXISTS)?\s+("[^"]+"|\w+)"#,
+    )
+    .unwrap()
+});
+
+/// The words following `ADD` in statements adding a constraint.
+const CONSTRAINTS: [&str; 6] = [
+    "CONSTRAINT",
+    "PRIMARY",
+    "UNIQUE",
+    "FOREIGN",
+    "CHECK",
+    "EXCLUDE",
+];
+
+/// Where `IF NOT EXISTS` goes in `sql` if it adds a column without it.
+fn column_guard_at(sql: &str) -> Option<usize> {
+    let c = ADD_COLUMN.captures(sql)?;
+    if c.get(3).is_some() {
+        return None;
+    }
+    match c.get(2) {
+        Some(column) => Some(column.end()),
+        None if CONSTRAINTS.iter().any(|k| c[4].eq_ignore_ascii_case(k)) => None,
+        None => Some(c.get(1).unwrap().end()),
+    }
+}
+
+fn object_name(m: Match) -> String {
+    m.as_str().trim_matches('"').to_lowercase()
+}
+
+/// A table, index or view that a statement creates or drops.
+struct Object {
+    create: bool,
+    kind: String,
+    name: String,
+    /// Where `IF [NOT] EXISTS` goes, or None if the statement has it already.
+    guard_at: Option<usize>,
+}
+
+/// A statement of the migrations being squashed.
+struct Statement {
+    sql: String,
+    object: Option<Object>,
+    /// The table the statement changes, or the index or view it creates or drops.
+    target: Option<String>,
+    /// Where `IF NOT EXISTS` goes if the statement adds a column without it.
+    column_guard_at: Option<usize>,
+}
+
+/// The net changes of hand-written migrations, see [`squash`].
+pub(crate) struct Squashed {
+    pub(crate) sql: String,
+    /// Whether `sql` can run on a database that has some of the changes
+    /// already. SQLite can't guard the columns it adds.
+    pub(crate) rerunnable: bool,
+}
+
+impl Statement {
+    fn new(sql: String) -> Self {
+        if let Some(c) = OBJECT.captures(&sql) {
+            let name = object_name(c.get(4).unwrap());
+            let target = c.get(5).map(object_name).unwrap_or_else(|| name.clone());
+            let object = Object {
+                create: c[1].eq_ignore_ascii_case("CREATE"),
+                kind: c[2].to_uppercase(),
+                name,
+                guard_at: match c.get(3) {
+                    Some(_) => None,
+                    None => Some(c.get(2).unwrap().end()),
+                },
+            };
+            return Self {
+                sql,
+                object: Some(object),
+                target: Some(target),
+                column_guard_at: None,
+            };
+        }
+        let target = WRITE.captures(&sql).map(|c| object_name(c.get(1).unwrap()));
+        let column_guard_at = column_guard_at(&sql);
+        Self {
+            sql,
+            object: None,
+            target,
+            column_guard_at,
+        }
+    }
+
+    fn creates(&self, kind: &str, name: &str) -> bool {
+        matches!(&self.object, Some(o) if o.create && o.kind == kind && o.name == name)
+    }
+
+    /// Whether the statement does nothing but create, drop or change `name`.
+    fn only_targets(&self, name: &str) -> bool {
+        self.target.as_deref() == Some(name) || matches!(&self.object, Some(o) if o.name == name)
+    }
+
+    fn mentions(&self, name: &str) -> bool {
+        let word = Regex::new(&format!(r"(?i)\b{}\b", regex::escape(name))).unwrap();
+        word.is_match(&self.sql)
+    }
+
+    /// The statement, with `IF NOT EXISTS` or `IF EXISTS` if it creates or
+    /// drops something, or adds a column on a `kind` database that can guard
+    /// that.
+    fn guarded(&self, kind: Kind) -> String {
+        if let (Some(at), Kind::Postgres) = (self.column_guard_at, kind) {
+            return format!("{} IF NOT EXISTS{}", &self.sql[..at], &self.sql[at..]);
+        }
+        match &self.object {
+            Some(Object {
+                create,
+                guard_at: Some(at),
+                ..
+            }) => {
+                let guard = if *create {
+                    " IF NOT EXISTS"
+                } else {
+                    " IF EXISTS"
+                };
+                format!("{}{}{}", &self.sql[..*at], guard, &self.sql[*at..])
+            }
+            _ => self.sql.clone(),
+        }
+    }
+}
+
+/// The statements of the migrations `migrations`, in order, without the
+/// tables, indexes and views created and then dropped again along the way,
+/// and with `IF NOT EXISTS` and `IF EXISTS` guards on what is created and
+/// dropped.
+///
+/// What was done to a table between its creation and its drop goes too,
+/// unless other statements use the table, in which case it all stays.
+/// Columns added with `ALTER TABLE` are guarded on PostgreSQL only, as
+/// SQLite can't, see [`Squashed::rerunnable`].
+pub(crate) fn squash(migrations: &[&str], kind: Kind) -> Squashed {
+    let mut statements: Vec<Option<Statement>> = migrations
+        .iter()
+        .flat_map(|sql| split_statements(sql))
+        .map(|(_, sql)| Some(Statement::new(sql)))
+        .collect();
+    // Leaving out a table can leave another one unused, so this goes on
+    // until nothing changes.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..statements.len() {
+            let (kind, name) = match statements[i].as_ref().and_then(|s| s.object.as_ref()) {
+                Some(o) if !o.create => (o.kind.clone(), o.name.clone()),
+                _ => continue,
+            };
+            let created = statements[..i]
+                .iter()
+                .rposition(|s| matches!(s, Some(s) if s.creates(&kind, &name)));
+            let span = match created {
+                Some(j) => &mut statements[j..=i],
+                None => continue,
+            };
+            let unused_elsewhere = span
+                .iter()
+                .flatten()
+                .filter(|s| s.mentions(&name))
+                .all(|s| s.only_targets(&name));
+            if unused_elsewhere {
+                for statement in span {
+                    if matches!(statement, Some(s) if s.only_targets(&name)) {
+                        *statement = None;
+                    }
+                }
+                changed = true;
+            }
+        }
+    }
+    let statements: Vec<Statement> = statements.into_iter().flatten().collect();
+    let rerunnable =
+        matches!(kind, Kind::Postgres) || statements.iter().all(|s| s.column_guard_at.is_none());
+    let sql = statements
+        .iter()
+        .map(|s| format!("{};\n", s.guarded(kind)))
+        .collect();
+    Squashed { sql, rerunnable }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_statements, squash};
+    use crate::datastore::engine::SqlWithArguments;
+    use crate::datastore::query::tests::setup_clear_db;
+    use crate::datastore::{Kind, QueryEngine};
+    use sqlx::any::AnyRow;
+    use sqlx::Row;
+
+    #[test]
+    fn split() {
+        let sql = "-- Backfill; carefully\n\
+                   CREATE TABLE log (msg TEXT);\n\
+                   \n\
+                   INSERT INTO log VALUES ('a;b'), ('it''s');  /* one; */ UPDATE \"odd;name\"\n\
+                   SET msg = 'x\n;y';\n\
+                   -- trailing comment;\n\
+                   DELETE FROM log\n";
+        assert_eq!(
+            split_statements(sql),
+            [
+                (2, "CREATE TABLE log (msg TEXT)".to_owned()),
+                (4, "INSERT INTO log VALUES ('a;b'), ('it''s')".to_owned()),
+                (4, "UPDATE \"odd;name\"\nSET msg = 'x\n;y'".to_owned()),
+                (8, "DELETE FROM log".to_owned()),
+            ]
+        );
+        assert!(split_statements("-- nothing;\n;\n").is_empty());
+    }
+
+    #[tokio::test]
+    async fn squashed_is_equivalent() {
+        let originals = [
+            "CREATE TABLE users (id INTEGER, name TEXT);\n\
+             CREATE INDEX users_name ON users (name);",
+            "CREATE TABLE \"tmp\" (x INTEGER);\n\
+             INSERT INTO tmp VALUES (1);\n\
+             ALTER TABLE users ADD COLUMN email TEXT;\n\
+             CREATE TABLE kept (x INTEGER);\n\
+             CREATE TABLE other (x INTEGER);",
+            "INSERT INTO kept SELECT x FROM tmp;\n\
+             DROP TABLE tmp;\n\
+             DROP TABLE kept;\n\
+             INSERT INTO other VALUES (2);\n\
+             INSERT INTO users (id, name) SELECT x, 'b' FROM other;\n\
+             DROP TABLE other;\n\
+             DROP INDEX users_name;\n\
+             CREATE UNIQUE INDEX users_email ON users (email);\n\
+             INSERT INTO users (id, name) VALUES (1, 'a;b');",
+        ];
+        let squashed = squash(&originals, Kind::Sqlite);
+        assert!(!squashed.rerunnable);
+        assert_eq!(
+            squashed.sql,
+            "CREATE TABLE IF NOT EXISTS users (id INTEGER, name TEXT);\n\
+             ALTER TABLE users ADD COLUMN email TEXT;\n\
+             CREATE TABLE IF NOT EXISTS other (x INTEGER);\n\
+             INSERT INTO other VALUES (2);\n\
+             INSERT INTO users (id, name) SELECT x, 'b' FROM other;\n\
+             DROP TABLE IF EXISTS other;\n\
+             CREATE UNIQUE INDEX IF NOT EXISTS users_email ON users (email);\n\
+             INSERT INTO users (id, name) VALUES (1, 'a;b');\n"
+        );
+        let on_postgres = squash(&originals, Kind::Postgres);
+        assert!(on_postgres.rerunnable);
+        assert!(on_postgres
+            .sql
+            .contains("\nALTER TABLE users ADD COLUMN IF NOT EXISTS email TEXT;\n"));
+
+        /// The objects of the database and the rows of `users`.
+        async fn schema(qe: &QueryEngine) -> (Vec<String>, Vec<String>) {
+            let sql = "SELECT type || ' ' || name || ': ' || sql FROM sqlite_master \
+                       WHERE tbl_name <> '__chiselstrike_migrations' ORDER BY name"
+                .to_owned();
+            let objects = qe.fetch_all(SqlWithArguments { sql, args: vec![] });
+            let sql = "SELECT id || ' ' || name FROM users ORDER BY id".to_owned();
+            let users = qe.fetch_all(SqlWithArguments { sql, args: vec![] });
+            fn strings(rows: Vec<AnyRow>) -> Vec<String> {
+                rows.iter().map(|row| row.get(0)).collect()
+            }
+            (
+                strings(objects.await.unwrap()),
+                strings(users.await.unwrap()),
+            )
+        }
+        let (migrated, _migrated_file) = setup_clear_db(&[]).await;
+        let (fresh, _fresh_file) = setup_clear_db(&[]).await;
+        for qe in [&migrated, &fresh] {
+            super::create_table(qe).await.unwrap();
+        }
+        for (i, sql) in originals.iter().enumerate() {
+            let id = i.to_string();
+            migrated.execute_raw_migration(sql, &id).await.unwrap();
+        }
+        fresh
+            .execute_raw_migration(&squashed.sql, "squashed_0_2")
+            .await
+            .unwrap();
+        let expected = schema(&migrated).await;
+        assert_eq!(expected.1, ["1 a;b", "2 b"]);
+        assert_eq!(schema(&fresh).await, expected);
+    }
+
+    #[tokio::test]
+    async fn squashed_runs_again() {
+        let originals = [
+            "CREATE TABLE users (id INTEGER);",
+            "CREATE INDEX users_id ON users (id);\n\
+             CREATE TABLE tmp (x INTEGER);\n\
+             DROP TABLE tmp;",
+        ];
+        let squashed = squash(&originals, Kind::Sqlite);
+        assert!(squashed.rerunnable);
+
+        // The squashed migration runs where the first migration ran already,
+        // and again where it ran itself.
+        let (qe, _db_file) = setup_clear_db(&[]).await;
+        super::create_table(&qe).await.unwrap();
+        qe.execute_raw_migration(originals[0], "0").await.unwrap();
+        for id in ["squashed", "squashed_again"] {
+            qe.execute_raw_migration(&squashed.sql, id).await.unwrap();
+        }
+
+        let added = squash(
+            &[
+                "ALTER TABLE users ADD email TEXT",
+                "ALTER TABLE users ADD CONSTRAINT users_email UNIQUE (email)",
+            ],
+            Kind::Postgres,
+        );
+        assert_eq!(
+            added.sql,
+            "ALTER TABLE users ADD IF NOT EXISTS email TEXT;\n\
+             ALTER TABLE users ADD CONSTRAINT users_email UNIQUE (email);\n"
+        );
+    }
+}
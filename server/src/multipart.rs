@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Parsing of `multipart/form-data` request bodies.
+
+use crate::api::{Body, RouteFn};
+use crate::blob::BlobStore;
+use crate::types::{ObjectType, Type};
+use crate::JsonObject;
+use anyhow::{Context, Result};
+use deno_core::futures;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::request::Parts;
+use hyper::{Request, Response};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A single part of a multipart body.
+#[derive(Debug)]
+pub(crate) struct MultipartField {
+    pub(crate) name: String,
+    pub(crate) file_name: Option<String>,
+    pub(crate) content_type: Option<String>,
+    pub(crate) data: Bytes,
+}
+
+/// A parsed `multipart/form-data` body, with its fields indexed by name.
+///
+/// A name may only appear once, as parsing fails on duplicated fields rather
+/// than letting the last one win.
+#[derive(Debug, Default)]
+pub(crate) struct MultipartBody {
+    fields: HashMap<String, MultipartField>,
+}
+
+impl MultipartBody {
+    pub(crate) async fn parse(content_type: &str, body: hyper::Body) -> Result<Self> {
+        let boundary = multer::parse_boundary(content_type)?;
+        let stream = futures::stream::unfold(body, |mut body| async {
+            body.data().await.map(|chunk| (chunk, body))
+        });
+        let mut multipart = multer::Multipart::new(stream, boundary);
+
+        let mut fields = HashMap::new();
+        while let Some(field) = multipart.next_field().await? {
+            let name = field
+                .name()
+                .context("multipart field without a name")?
+                .to_owned();
+            let file_name = field.file_name().map(str::to_owned);
+            let content_type = field.content_type().map(|m| m.to_string());
+            anyhow::ensure!(
+                !fields.contains_key(&name),
+                "duplicated multipart field {}",
+                name
+            );
+            let data = field.bytes().await?;
+            fields.insert(
+                name.clone(),
+                MultipartField {
+                    name,
+                    file_name,
+                    content_type,
+                    data,
+                },
+            );
+        }
+        Ok(Self { fields })
+    }
+
+    pub(crate) fn into_fields(self) -> impl Iterator<Item = MultipartField> {
+        self.fields.into_values()
+    }
+}
+
+/// Adapts a handler that takes a parsed multipart body into a [`RouteFn`].
+///
+/// Requests that are not `multipart/form-data` are rejected before reaching `handler`.
+pub(crate) fn multipart_route<F, Fut>(handler: F) -> RouteFn
+where
+    F: Fn(Parts, MultipartBody) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<Body>>> + 'static,
+{
+    let handler = Arc::new(handler);
+    Arc::new(
+        move |req: Request<hyper::Body>| -> LocalBoxFuture<'static, _> {
+            let handler = handler.clone();
+            async move {
+                let (parts, body) = req.into_parts();
+                let content_type = parts
+                    .headers
+                    .get(hyper::header::CONTENT_TYPE)
+                    .context("missing Content-Type")?
+                    .to_str()?
+                    .to_owned();
+                let body = MultipartBody::parse(&content_type, body).await?;
+                handler(parts, body).await
+            }
+            .boxed_local()
+        },
+    )
+}
+
+/// Writes an uploaded file to `store` and sets the `field_name` string or `Blob`
+/// field of `object`, an object of `object_type`, to the URL it is served at.
+pub(crate) async fn store_upload(
+    store: &dyn BlobStore,
+    field: MultipartField,
+    object_type: &Arc<ObjectType>,
+    field_name: &str,
+    object: &mut JsonObject,
+) -> Result<String> {
+    match object_type.get_field(field_name) {
+        Some(f) if matches!(f.type_, Type::String | Type::Blob) => {}
+        Some(_) => anyhow::bail!(
+            "field {} of type {} is not a string or a Blob",
+            field_name,
+            object_type.name()
+        ),
+        None => anyhow::bail!("type {} has no field {}", object_type.name(), field_name),
+    }
+
+    let mut key = format!("{}/{}/{}", object_type.name(), field_name, Uuid::new_v4());
+    let extension = field
+        .file_name
+        .as_deref()
+        .and_then(|f| std::path::Path::new(f).extension())
+        .and_then(|e| e.to_str())
+        .filter(|e| e.chars().all(|c| c.is_ascii_alphanumeric()));
+    if let Some(extension) = extension {
+        key = format!("{}.{}", key, extension);
+    }
+    store.put(&key, field.data).await?;
+    let url = store.url(&key);
+    object.insert(field_name.to_owned(), url.clone().into());
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(names: &[&str]) -> hyper::Body {
+        let mut body = String::new();
+        for name in names {
+            body += &format!(
+                "--X\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\nvalue\r\n",
+                name
+            );
+        }
+        body += "--X--\r\n";
+        body.into()
+    }
+
+    #[tokio::test]
+    async fn duplicated_fields() {
+        let content_type = "multipart/form-data; boundary=X";
+        let parsed = MultipartBody::parse(content_type, body(&["a", "b"]))
+            .await
+            .unwrap();
+        assert_eq!(parsed.into_fields().count(), 2);
+        MultipartBody::parse(content_type, body(&["a", "a"]))
+            .await
+            .unwrap_err();
+    }
+}
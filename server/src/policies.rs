@@ -30,9 +30,9 @@ pub(crate) type LabelPolicies = HashMap<String, Policy>;
 
 #[derive(Clone, Default, Debug)]
 pub(crate) struct FieldPolicies {
-    /// Maps a field name to the transformation we apply to that field's values.
+    /// Maps a field's JSON name to the transformation we apply to that field's values.
     pub(crate) transforms: HashMap<String, fn(Value) -> Value>,
-    /// Names of fields that must equal the currently logged-in user.
+    /// JSON names of fields that must equal the currently logged-in user.
     pub(crate) match_login: HashSet<String>,
     /// ID of the currently logged-in user.
     pub(crate) current_userid: Option<String>,
@@ -108,10 +108,14 @@ impl Policies {
                         if !p.except_uri.is_match(current_path) {
                             match p.kind {
                                 Kind::Transform(f) => {
-                                    field_policies.transforms.insert(fld.name.clone(), f);
+                                    field_policies
+                                        .transforms
+                                        .insert(fld.json_name().to_owned(), f);
                                 }
                                 Kind::MatchLogin => {
-                                    field_policies.match_login.insert(fld.name.clone());
+                                    field_policies
+                                        .match_login
+                                        .insert(fld.json_name().to_owned());
                                 }
                             }
                         }
@@ -34,6 +34,10 @@ impl<T> PrefixMap<T> {
         self.map.iter().map(|(k, v)| (k.as_path(), v))
     }
 
+    pub(crate) fn get_mut(&mut self, k: &Path) -> Option<&mut T> {
+        self.map.get_mut(k)
+    }
+
     pub(crate) fn insert(&mut self, k: PathBuf, v: T) -> Option<T> {
         self.map.insert(k, v)
     }
@@ -0,0 +1,66 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! A CPU profiler of the server, started and stopped through the admin
+//! routes, that reports a flamegraph. Only built with the `profiling`
+//! feature, as sampling the stacks slows the server down.
+
+use crate::api::{json_response, response_template, Body};
+use anyhow::{Context, Result};
+use hyper::{Response, StatusCode};
+use once_cell::sync::Lazy;
+use pprof::{ProfilerGuard, ProfilerGuardBuilder};
+use std::sync::Mutex;
+
+/// Stack samples taken per second.
+const FREQUENCY: i32 = 99;
+
+/// The profile being taken, if any. There is a single one for the process,
+/// as the profiler samples all the threads.
+static PROFILE: Lazy<Mutex<Option<ProfilerGuard<'static>>>> = Lazy::new(Default::default);
+
+fn conflict(message: &str) -> Result<Response<Body>> {
+    json_response(
+        StatusCode::CONFLICT,
+        serde_json::json!({ "error": message }),
+    )
+}
+
+/// Starts sampling the stacks of the server, unless it already is.
+pub(crate) async fn start() -> Result<Response<Body>> {
+    let mut profile = PROFILE.lock().unwrap();
+    if profile.is_some() {
+        return conflict("the profiler is already running");
+    }
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        // Unwinding through these can deadlock.
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .context("could not start the profiler")?;
+    *profile = Some(guard);
+    info!("Started the CPU profiler");
+    json_response(StatusCode::OK, serde_json::json!({ "running": true }))
+}
+
+/// Stops the profiler and responds with the flamegraph of what it sampled,
+/// as an SVG.
+pub(crate) async fn stop() -> Result<Response<Body>> {
+    let guard = match PROFILE.lock().unwrap().take() {
+        Some(guard) => guard,
+        None => return conflict("the profiler is not running"),
+    };
+    let report = guard
+        .report()
+        .build()
+        .context("could not build the profile")?;
+    drop(guard);
+    let mut svg = vec![];
+    report
+        .flamegraph(&mut svg)
+        .context("could not draw the flamegraph")?;
+    info!("Stopped the CPU profiler");
+    Ok(response_template()
+        .status(StatusCode::OK)
+        .header("Content-Type", "image/svg+xml")
+        .body(Body::from_bytes(svg.into()))?)
+}
@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Reads from the database replica of the region a request comes from.
+//!
+//! When chiseld runs with `--region-db eu=postgres://eu-replica/db`, the
+//! queries of a request whose region header says `eu` read from that
+//! replica instead of the primary database, until the request writes. Writes
+//! always go to the primary, and the replica only catches up with them later,
+//! so the reads of a request that wrote go to the primary as well, in the
+//! transaction of the request.
+//!
+//! Reads from a replica are not part of the request transaction, which
+//! breaks its isolation: what a request reads and then writes back may have
+//! changed on the primary in the meantime, or be stale already, and two reads
+//! of the request may see the replica at different points in time.
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+
+/// The headers the region of a request is read from, unless `--region-header` says otherwise.
+pub(crate) const DEFAULT_REGION_HEADERS: &str = "CF-Region,X-Fly-Region";
+
+/// The headers the region of a request is read from, set by [`init`].
+static REGION_HEADERS: OnceCell<Vec<String>> = OnceCell::new();
+
+/// Reads the region of requests from the first of `headers` they have.
+pub(crate) fn init(headers: Vec<String>) {
+    REGION_HEADERS
+        .set(headers)
+        .map_err(|_| ())
+        .expect("REGION_HEADERS already initialized before region::init()");
+}
+
+/// The headers set by [`init`], none if it wasn't called.
+pub(crate) fn headers() -> &'static [String] {
+    REGION_HEADERS.get().map_or(&[], Vec::as_slice)
+}
+
+/// The region a request with `headers` comes from, read from the first of
+/// the headers named `names` that it has.
+pub(crate) fn region_of(names: &[String], headers: &HashMap<String, String>) -> Option<String> {
+    names.iter().find_map(|name| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_ascii_lowercase())
+            .filter(|region| !region.is_empty())
+    })
+}
+
+/// Parses a `--region-db` argument, a region and the URL of its database
+/// replica, as in `eu=postgres://eu-replica/db`.
+pub(crate) fn parse_region_db(arg: &str) -> Result<(String, String)> {
+    let (region, url) = arg
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not REGION=URL", arg))?;
+    anyhow::ensure!(!region.is_empty(), "'{}' has no region", arg);
+    Ok((region.to_ascii_lowercase(), url.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions() {
+        assert_eq!(
+            parse_region_db("EU=postgres://replica/db?a=b").unwrap(),
+            ("eu".to_owned(), "postgres://replica/db?a=b".to_owned())
+        );
+        parse_region_db("postgres://replica/db").unwrap_err();
+        parse_region_db("=postgres://replica/db").unwrap_err();
+
+        let headers = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(region_of(&[], &headers(&[("cf-region", "EU")])), None);
+        let names: Vec<_> = DEFAULT_REGION_HEADERS
+            .split(',')
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            region_of(
+                &names,
+                &headers(&[("x-fly-region", "ams"), ("cf-region", "EU")])
+            ),
+            Some("eu".to_owned())
+        );
+        assert_eq!(
+            region_of(&names, &headers(&[("X-Fly-Region", "ams")])),
+            Some("ams".to_owned())
+        );
+        assert_eq!(region_of(&names, &headers(&[("cf-region", " ")])), None);
+        assert_eq!(region_of(&names, &headers(&[("host", "eu")])), None);
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+use anyhow::Result;
+use hyper::Request;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Path parameters captured while matching a [`RoutePattern`].
+///
+/// Inserted as a request extension before the route is invoked. A trailing
+/// wildcard captures the remainder of the path under the `*` key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct RouteParams(pub(crate) HashMap<String, String>);
+
+/// Gets the value of parameter `name` captured by the route that `req` matched.
+pub(crate) fn route_param(req: &Request<hyper::Body>, name: &str) -> Result<String> {
+    req.extensions()
+        .get::<RouteParams>()
+        .and_then(|p| p.0.get(name))
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Internal error: missing {} in route", name))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard,
+}
+
+/// A route path with `:param` segments and an optional trailing `*` wildcard,
+/// e.g. `/dev/users/:id/posts/:postId` or `/dev/files/*`.
+#[derive(Clone, Debug)]
+pub(crate) struct RoutePattern {
+    path: PathBuf,
+    segments: Vec<Segment>,
+}
+
+/// The segments of `path`, skipping empty ones.
+pub(crate) fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+impl RoutePattern {
+    /// Whether `path` uses the pattern syntax, as opposed to being a plain prefix.
+    pub(crate) fn is_pattern(path: &Path) -> bool {
+        split(&path.to_string_lossy()).any(|s| s == "*" || s.starts_with(':'))
+    }
+
+    pub(crate) fn parse(path: &Path) -> Result<Self> {
+        let path_str = path.to_string_lossy();
+        let mut segments = vec![];
+        for s in split(&path_str) {
+            anyhow::ensure!(
+                segments.last() != Some(&Segment::Wildcard),
+                "wildcard must be the last segment of route {}",
+                path_str
+            );
+            let segment = if s == "*" {
+                Segment::Wildcard
+            } else if let Some(name) = s.strip_prefix(':') {
+                anyhow::ensure!(!name.is_empty(), "unnamed parameter in route {}", path_str);
+                anyhow::ensure!(
+                    !segments.contains(&Segment::Param(name.to_owned())),
+                    "duplicated parameter {} in route {}",
+                    name,
+                    path_str
+                );
+                Segment::Param(name.to_owned())
+            } else {
+                Segment::Literal(s.to_owned())
+            };
+            segments.push(segment);
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            segments,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Matches a request path, returning the captured parameters on success.
+    pub(crate) fn matches(&self, path: &str) -> Option<RouteParams> {
+        let mut params = HashMap::new();
+        let mut parts = split(path);
+        for segment in &self.segments {
+            match segment {
+                Segment::Wildcard => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    params.insert("*".to_owned(), rest.join("/"));
+                }
+                Segment::Literal(lit) => {
+                    if parts.next()? != lit.as_str() {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), parts.next()?.to_owned());
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(RouteParams(params)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RouteParams, RoutePattern};
+    use std::path::Path;
+
+    fn pattern(path: &str) -> RoutePattern {
+        RoutePattern::parse(Path::new(path)).unwrap()
+    }
+
+    fn params(kv: &[(&str, &str)]) -> Option<RouteParams> {
+        Some(RouteParams(
+            kv.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn is_pattern() {
+        assert!(RoutePattern::is_pattern(Path::new("/dev/users/:id")));
+        assert!(RoutePattern::is_pattern(Path::new("/dev/files/*")));
+        assert!(!RoutePattern::is_pattern(Path::new("/dev/users")));
+    }
+
+    #[test]
+    fn params_are_captured() {
+        let p = pattern("/dev/users/:id/posts/:postId");
+        assert_eq!(
+            p.matches("/dev/users/1/posts/2"),
+            params(&[("id", "1"), ("postId", "2")])
+        );
+        assert_eq!(p.matches("/dev/users/1/posts"), None);
+        assert_eq!(p.matches("/dev/users/1/posts/2/3"), None);
+        assert_eq!(p.matches("/dev/people/1/posts/2"), None);
+    }
+
+    #[test]
+    fn wildcard() {
+        let p = pattern("/dev/files/*");
+        assert_eq!(p.matches("/dev/files/a/b/c"), params(&[("*", "a/b/c")]));
+        assert_eq!(p.matches("/dev/files"), params(&[("*", "")]));
+        assert_eq!(p.matches("/dev/other/a"), None);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(RoutePattern::parse(Path::new("/dev/*/a")).is_err());
+        assert!(RoutePattern::parse(Path::new("/dev/:")).is_err());
+        assert!(RoutePattern::parse(Path::new("/dev/:id/:id")).is_err());
+    }
+}
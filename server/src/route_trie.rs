@@ -0,0 +1,292 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+use crate::route_pattern::{split, RouteParams, RoutePattern};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A trie of route paths, one level per path segment, so that finding the
+/// route of a request takes as many steps as the request has segments, no
+/// matter how many routes there are.
+///
+/// Holds both [`RoutePattern`]s and plain routes, which serve every path
+/// under them. A request goes to the pattern it matches, if any, and to its
+/// longest plain prefix otherwise. When several patterns match, literal
+/// segments win over parameters, and parameters over wildcards.
+#[derive(Clone, Debug)]
+pub(crate) struct RouteTrie<T> {
+    root: Node<T>,
+}
+
+#[derive(Clone, Debug)]
+struct Node<T> {
+    literals: HashMap<String, Node<T>>,
+    /// Children for `:param` segments, tried in the order they were added.
+    params: Vec<(String, Node<T>)>,
+    wildcard: Option<Box<Node<T>>>,
+    /// The pattern ending at this node.
+    pattern: Option<(RoutePattern, T)>,
+    /// The plain route of the path of this node.
+    prefix: Option<(PathBuf, T)>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            literals: Default::default(),
+            params: Default::default(),
+            wildcard: None,
+            pattern: None,
+            prefix: None,
+        }
+    }
+}
+
+impl<T> Default for RouteTrie<T> {
+    fn default() -> Self {
+        Self {
+            root: Default::default(),
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn child(&mut self, segment: &str) -> Option<&mut Node<T>> {
+        if segment == "*" {
+            return self.wildcard.as_deref_mut();
+        }
+        if let Some(name) = segment.strip_prefix(':') {
+            return self
+                .params
+                .iter_mut()
+                .find(|(n, _)| n == name)
+                .map(|(_, child)| child);
+        }
+        self.literals.get_mut(segment)
+    }
+
+    fn child_or_insert(&mut self, segment: &str) -> &mut Node<T> {
+        if segment == "*" {
+            return self.wildcard.get_or_insert_with(Default::default);
+        }
+        if let Some(name) = segment.strip_prefix(':') {
+            let i = match self.params.iter().position(|(n, _)| n == name) {
+                Some(i) => i,
+                None => {
+                    self.params.push((name.to_owned(), Default::default()));
+                    self.params.len() - 1
+                }
+            };
+            return &mut self.params[i].1;
+        }
+        self.literals.entry(segment.to_owned()).or_default()
+    }
+
+    fn find_pattern(&self, parts: &[&str]) -> Option<&(RoutePattern, T)> {
+        let (first, rest) = match parts.split_first() {
+            Some(split) => split,
+            // A wildcard matches nothing too.
+            None => {
+                return self
+                    .pattern
+                    .as_ref()
+                    .or_else(|| self.wildcard.as_ref()?.pattern.as_ref())
+            }
+        };
+        let literal = self.literals.get(*first);
+        if let Some(found) = literal.and_then(|child| child.find_pattern(rest)) {
+            return Some(found);
+        }
+        for (_, child) in &self.params {
+            if let Some(found) = child.find_pattern(rest) {
+                return Some(found);
+            }
+        }
+        self.wildcard.as_ref()?.pattern.as_ref()
+    }
+
+    fn collect_paths<'a>(&'a self, paths: &mut Vec<&'a Path>) {
+        if let Some((path, _)) = &self.prefix {
+            paths.push(path);
+        }
+        if let Some((pattern, _)) = &self.pattern {
+            paths.push(pattern.path());
+        }
+        let params = self.params.iter().map(|(_, child)| child);
+        for child in self
+            .literals
+            .values()
+            .chain(params)
+            .chain(self.wildcard.as_deref())
+        {
+            child.collect_paths(paths);
+        }
+    }
+}
+
+impl<T> RouteTrie<T> {
+    /// The value of the route of `path`, added with `default` if there is none yet.
+    ///
+    /// `path` is a [`RoutePattern`] if it uses the pattern syntax, and a
+    /// plain route otherwise.
+    pub(crate) fn get_or_insert_with(
+        &mut self,
+        path: &Path,
+        default: impl FnOnce() -> T,
+    ) -> Result<&mut T> {
+        let pattern = match RoutePattern::is_pattern(path) {
+            true => Some(RoutePattern::parse(path)?),
+            false => None,
+        };
+        let mut node = &mut self.root;
+        for segment in split(&path.to_string_lossy()) {
+            node = node.child_or_insert(segment);
+        }
+        let value = match pattern {
+            Some(pattern) => &mut node.pattern.get_or_insert_with(|| (pattern, default())).1,
+            None => {
+                &mut node
+                    .prefix
+                    .get_or_insert_with(|| (path.to_owned(), default()))
+                    .1
+            }
+        };
+        Ok(value)
+    }
+
+    /// The path and value of the route serving the request `path`, with the
+    /// parameters it captures if it is a pattern.
+    pub(crate) fn find(&self, path: &str) -> Option<(&Path, &T, Option<RouteParams>)> {
+        let parts: Vec<&str> = split(path).collect();
+        let found = self.root.find_pattern(&parts).and_then(|(pattern, value)| {
+            let params = pattern.matches(path)?;
+            Some((pattern.path(), value, Some(params)))
+        });
+        found.or_else(|| {
+            let (route, value) = self.longest_prefix(&parts)?;
+            Some((route.as_path(), value, None))
+        })
+    }
+
+    fn longest_prefix(&self, parts: &[&str]) -> Option<&(PathBuf, T)> {
+        let mut node = &self.root;
+        let mut found = node.prefix.as_ref();
+        for part in parts {
+            node = match node.literals.get(*part) {
+                Some(child) => child,
+                None => break,
+            };
+            found = node.prefix.as_ref().or(found);
+        }
+        found
+    }
+
+    /// Removes the routes whose path starts with `prefix`.
+    pub(crate) fn remove_prefix(&mut self, prefix: &Path) {
+        let mut node = &mut self.root;
+        for segment in split(&prefix.to_string_lossy()) {
+            node = match node.child(segment) {
+                Some(child) => child,
+                None => return,
+            };
+        }
+        *node = Default::default();
+    }
+
+    /// The paths of all the routes, sorted.
+    pub(crate) fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![];
+        self.root.collect_paths(&mut paths);
+        paths.sort();
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RouteTrie;
+    use std::path::Path;
+
+    fn trie(paths: &[&str]) -> RouteTrie<String> {
+        let mut trie = RouteTrie::default();
+        for path in paths {
+            *trie
+                .get_or_insert_with(Path::new(path), Default::default)
+                .unwrap() = path.to_string();
+        }
+        trie
+    }
+
+    /// The route serving `path` and its parameters, in alphabetical order.
+    fn find(trie: &RouteTrie<String>, path: &str) -> Option<(String, Vec<String>)> {
+        let (route_path, route, params) = trie.find(path)?;
+        assert_eq!(route_path, Path::new(route));
+        let mut params: Vec<String> = params
+            .map(|p| {
+                p.0.into_iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect()
+            })
+            .unwrap_or_default();
+        params.sort();
+        Some((route.clone(), params))
+    }
+
+    fn found(route: &str, params: &[&str]) -> Option<(String, Vec<String>)> {
+        let params = params.iter().map(|p| p.to_string()).collect();
+        Some((route.to_string(), params))
+    }
+
+    #[test]
+    fn precedence() {
+        let t = trie(&[
+            "/dev/files/*",
+            "/dev/:type/:id",
+            "/dev/:type/batch",
+            "/dev/books/:isbn",
+            "/dev",
+        ]);
+        assert_eq!(
+            find(&t, "/dev/books/1"),
+            found("/dev/books/:isbn", &["isbn=1"])
+        );
+        assert_eq!(
+            find(&t, "/dev/users/batch"),
+            found("/dev/:type/batch", &["type=users"])
+        );
+        assert_eq!(
+            find(&t, "/dev/users/1"),
+            found("/dev/:type/:id", &["id=1", "type=users"])
+        );
+        assert_eq!(find(&t, "/dev/files/1"), found("/dev/files/*", &["*=1"]));
+        assert_eq!(
+            find(&t, "/dev/files/a/b"),
+            found("/dev/files/*", &["*=a/b"])
+        );
+        assert_eq!(find(&t, "/dev/files"), found("/dev/files/*", &["*="]));
+        assert_eq!(find(&t, "/dev/users/1/2"), found("/dev", &[]));
+        assert_eq!(find(&t, "/prod/users/1"), None);
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let t = trie(&["/a/b/c", "/a/b", "/a/bb/c"]);
+        assert_eq!(find(&t, "/a/b/c/d"), found("/a/b/c", &[]));
+        assert_eq!(find(&t, "/a/b/cc"), found("/a/b", &[]));
+        assert_eq!(find(&t, "/a/b/"), found("/a/b", &[]));
+        assert_eq!(find(&t, "/a/bb"), None);
+        assert_eq!(find(&t, "/a"), None);
+    }
+
+    #[test]
+    fn remove_prefix() {
+        let mut t = trie(&["/dev/a", "/dev/:type", "/dev/a/b", "/prod/a"]);
+        t.remove_prefix(Path::new("/dev/a"));
+        assert_eq!(t.paths(), [Path::new("/dev/:type"), Path::new("/prod/a")]);
+        t.remove_prefix(Path::new("/dev/nope"));
+        t.remove_prefix(Path::new("/dev"));
+        assert_eq!(t.paths(), [Path::new("/prod/a")]);
+        t.get_or_insert_with(Path::new("/dev/*/a"), Default::default)
+            .unwrap_err();
+    }
+}
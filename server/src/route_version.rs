@@ -0,0 +1,212 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Numbered API versions, served under `/v1/`, `/v2/` and so on.
+//!
+//! They are regular ChiselStrike API versions whose names have that form,
+//! so `chisel apply --version v2` registers routes at `/v2/...` without
+//! touching `/v1/...`. A request can also pick the version with an
+//! [`API_VERSION_HEADER`] instead of the path prefix, and the responses of a
+//! deprecated version announce it (RFC 8594).
+
+use crate::datastore::MetaService;
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Request, Uri};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Header naming the version a request is for, as in `API-Version: 2`.
+pub(crate) const API_VERSION_HEADER: &str = "API-Version";
+
+/// Meta key under which the deprecated versions and their sunset dates are kept.
+const DEPRECATIONS_KEY: &str = "deprecated_versions";
+
+/// An API version named `v{n}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct RouteVersion(pub(crate) u32);
+
+impl RouteVersion {
+    /// The version named `name`, if it has the form `v{n}`.
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        let version = Self(name.strip_prefix('v')?.parse().ok()?);
+        // Rejects names like v02 or v+2, which would be served at another path.
+        (version.to_string() == name).then(|| version)
+    }
+
+    /// The version `path` is under, if it is under one.
+    pub(crate) fn of_path(path: &str) -> Option<Self> {
+        path.strip_prefix('/')?
+            .split('/')
+            .next()
+            .and_then(Self::parse)
+    }
+}
+
+impl fmt::Display for RouteVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// A request whose [`API_VERSION_HEADER`] can't be honored.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ApiVersionError {
+    #[error["API-Version must be a version number, got '{0}'"]]
+    Invalid(String),
+    #[error["API-Version {0} doesn't match the version in the path"]]
+    Mismatch(u32),
+}
+
+/// The version `req` is for. A request picking its version with an
+/// [`API_VERSION_HEADER`] is rewritten to be under that version's path.
+/// The internal routes have no versions, so the header is ignored for them.
+pub(crate) fn resolve(req: &mut Request<hyper::Body>) -> Result<Option<RouteVersion>> {
+    let path_version = RouteVersion::of_path(req.uri().path());
+    let version = match req.headers().get(API_VERSION_HEADER) {
+        Some(header) if !req.uri().path().starts_with("/__chiselstrike/") => header
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .map(RouteVersion)
+            .ok_or_else(|| {
+                ApiVersionError::Invalid(String::from_utf8_lossy(header.as_bytes()).into_owned())
+            })?,
+        _ => return Ok(path_version),
+    };
+    match path_version {
+        Some(path_version) if path_version != version => {
+            Err(ApiVersionError::Mismatch(version.0).into())
+        }
+        Some(_) => Ok(Some(version)),
+        None => {
+            let mut parts = req.uri().clone().into_parts();
+            let path_and_query = parts
+                .path_and_query
+                .as_ref()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+            parts.path_and_query = Some(format!("/{}{}", version, path_and_query).parse()?);
+            *req.uri_mut() = Uri::from_parts(parts)?;
+            Ok(Some(version))
+        }
+    }
+}
+
+/// Adds the `Deprecation` and `Sunset` headers of RFC 8594 to a response
+/// of a version going away on `sunset`.
+pub(crate) fn mark_deprecated(headers: &mut HeaderMap, sunset: NaiveDate) {
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    let sunset = sunset.format("%a, %d %b %Y 00:00:00 GMT").to_string();
+    if let Ok(sunset) = HeaderValue::from_str(&sunset) {
+        headers.insert("sunset", sunset);
+    }
+}
+
+/// The version named `version` and the date `sunset`, as `YYYY-MM-DD`,
+/// it goes away on.
+pub(crate) fn parse_deprecation(version: &str, sunset: &str) -> Result<(RouteVersion, NaiveDate)> {
+    let version = RouteVersion::parse(version).with_context(|| {
+        format!(
+            "'{}' is not a numbered version; only versions named v1, v2, ... can be deprecated",
+            version
+        )
+    })?;
+    let sunset = NaiveDate::parse_from_str(sunset, "%Y-%m-%d")
+        .with_context(|| format!("the sunset date must be YYYY-MM-DD, got '{}'", sunset))?;
+    Ok((version, sunset))
+}
+
+/// The deprecated versions, with their sunset dates.
+pub(crate) async fn load_deprecations(
+    meta: &MetaService,
+) -> Result<BTreeMap<RouteVersion, NaiveDate>> {
+    let stored: BTreeMap<u32, String> = match meta.get_meta_value(DEPRECATIONS_KEY).await? {
+        Some(value) => serde_json::from_str(&value)?,
+        None => return Ok(BTreeMap::new()),
+    };
+    stored
+        .into_iter()
+        .map(|(version, sunset)| {
+            Ok((
+                RouteVersion(version),
+                NaiveDate::parse_from_str(&sunset, "%Y-%m-%d")?,
+            ))
+        })
+        .collect()
+}
+
+/// Records that `version` is deprecated until `sunset`, replacing any
+/// earlier sunset date of it.
+pub(crate) async fn save_deprecation(
+    meta: &MetaService,
+    version: RouteVersion,
+    sunset: NaiveDate,
+) -> Result<()> {
+    let mut deprecations = load_deprecations(meta).await?;
+    deprecations.insert(version, sunset);
+    let stored: BTreeMap<u32, String> = deprecations
+        .into_iter()
+        .map(|(version, sunset)| (version.0, sunset.format("%Y-%m-%d").to_string()))
+        .collect();
+    let mut transaction = meta.start_transaction().await?;
+    meta.set_meta_value(
+        &mut transaction,
+        DEPRECATIONS_KEY,
+        &serde_json::to_string(&stored)?,
+    )
+    .await?;
+    MetaService::commit_transaction(transaction).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, version: Option<&str>) -> Request<hyper::Body> {
+        let mut req = Request::builder().uri(path);
+        if let Some(version) = version {
+            req = req.header(API_VERSION_HEADER, version);
+        }
+        req.body(hyper::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(RouteVersion::parse("v2"), Some(RouteVersion(2)));
+        assert_eq!(RouteVersion::parse("v02"), None);
+        assert_eq!(RouteVersion::parse("v+2"), None);
+        assert_eq!(RouteVersion::parse("dev"), None);
+        assert_eq!(RouteVersion::of_path("/v1/users"), Some(RouteVersion(1)));
+        assert_eq!(RouteVersion::of_path("/dev/users"), None);
+    }
+
+    #[test]
+    fn header_picks_the_version() {
+        let mut req = request("/users?id=1", Some("2"));
+        assert_eq!(resolve(&mut req).unwrap(), Some(RouteVersion(2)));
+        assert_eq!(req.uri(), "/v2/users?id=1");
+
+        let mut req = request("/v2/users", Some("2"));
+        assert_eq!(resolve(&mut req).unwrap(), Some(RouteVersion(2)));
+        assert_eq!(req.uri(), "/v2/users");
+
+        let mut req = request("/v1/users", None);
+        assert_eq!(resolve(&mut req).unwrap(), Some(RouteVersion(1)));
+
+        let mut req = request("/__chiselstrike/auth/users", Some("2"));
+        assert_eq!(resolve(&mut req).unwrap(), None);
+        assert_eq!(req.uri(), "/__chiselstrike/auth/users");
+
+        resolve(&mut request("/v1/users", Some("2"))).unwrap_err();
+        resolve(&mut request("/users", Some("two"))).unwrap_err();
+    }
+
+    #[test]
+    fn deprecation_headers() {
+        let mut headers = HeaderMap::new();
+        mark_deprecated(&mut headers, NaiveDate::from_ymd(2023, 6, 30));
+        assert_eq!(headers["Deprecation"], "true");
+        assert_eq!(headers["Sunset"], "Fri, 30 Jun 2023 00:00:00 GMT");
+    }
+}
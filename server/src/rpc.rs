@@ -1,36 +1,54 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::api::{ApiInfo, RequestPath};
+use crate::auth;
 use crate::chisel::{self, AddTypeRequest};
-use crate::datastore::{MetaService, QueryEngine};
+use crate::datastore::query::{QueryOpChain, QueryPlan, RequestContext};
+use crate::datastore::snapshot::{Snapshot, SnapshotManager};
+use crate::datastore::{encryption, MetaService, QueryEngine};
 use crate::deno;
 use crate::deno::endpoint_path_from_source_path;
 use crate::deno::mutate_policies;
 use crate::deno::remove_type_version;
 use crate::deno::set_type_system;
+use crate::migrations;
 use crate::policies::{Policies, VersionPolicy};
 use crate::prefix_map::PrefixMap;
+use crate::route_version;
 use crate::runtime;
+use crate::schema_validation::{self, EndpointSchemas, SchemaValidationMiddleware};
+use crate::secrets::get_secrets;
+use crate::server::endpoint_route;
 use crate::server::CommandTrait;
 use crate::server::CoordinatorChannel;
 use crate::types::AuthOrNot::IsNotAuth;
 use crate::types::{
-    DbIndex, Field, NewField, NewObject, ObjectType, Type, TypeSystem, TypeSystemError,
+    CheckConstraint, DbIndex, Field, IdStrategy, NewField, NewObject, ObjectType, Type, TypeSystem,
+    TypeSystemError, UniqueConstraint, VirtualRelation, WebhookConfig,
 };
+use crate::webhooks::Webhooks;
 use anyhow::{Context, Result};
 use async_lock::Mutex;
 use chisel::chisel_rpc_server::{ChiselRpc, ChiselRpcServer};
 use chisel::{
-    ChiselApplyRequest, ChiselApplyResponse, ChiselDeleteRequest, ChiselDeleteResponse,
-    DescribeRequest, DescribeResponse, IndexCandidate, PopulateRequest, PopulateResponse,
-    RestartRequest, RestartResponse, StatusRequest, StatusResponse,
+    AddUserRequest, AddUserResponse, AdminSecretRequest, AdminSecretResponse, AppliedMigration,
+    ApplyMigrationRequest, ApplyMigrationResponse, ChiselApplyRequest, ChiselApplyResponse,
+    ChiselDeleteRequest, ChiselDeleteResponse, CreateSnapshotRequest, CreateSnapshotResponse,
+    DeleteUserRequest, DeleteUserResponse, DeprecateVersionRequest, DeprecateVersionResponse,
+    DescribeRequest, DescribeResponse, EncryptExistingRequest, EncryptExistingResponse,
+    FakeMigrationRequest, FakeMigrationResponse, IndexCandidate, ListMigrationsRequest,
+    ListMigrationsResponse, ListSnapshotsRequest, ListSnapshotsResponse, ListUsersRequest,
+    ListUsersResponse, PopulateRequest, PopulateResponse, QueryRequest, QueryResponse,
+    RestartRequest, RestartResponse, RestoreSnapshotRequest, RestoreSnapshotResponse,
+    RotateSecretRequest, RotateSecretResponse, SquashMigrationsRequest, SquashMigrationsResponse,
+    StatusRequest, StatusResponse,
 };
 use deno_core::futures;
 use deno_core::url::Url;
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
 use petgraph::graphmap::GraphMap;
 use petgraph::Directed;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -69,6 +87,7 @@ pub(crate) struct GlobalRpcState {
     commands: Vec<CoordinatorChannel>,
     policies: Policies,
     versions: BTreeSet<String>,
+    webhooks: Webhooks,
 }
 
 #[derive(Clone)]
@@ -84,6 +103,7 @@ impl GlobalRpcState {
         init: InitState,
         query_engine: QueryEngine,
         commands: Vec<CoordinatorChannel>,
+        mut webhooks: Webhooks,
     ) -> Result<Self> {
         let InitState {
             sources,
@@ -100,15 +120,18 @@ impl GlobalRpcState {
             versions.insert(rp.api_version().to_owned());
         }
 
+        let query_engine = Arc::new(query_engine);
+        webhooks.update(&query_engine, &type_system);
         Ok(Self {
             id: Uuid::new_v4(),
             type_system,
             meta,
-            query_engine: Arc::new(query_engine),
+            query_engine,
             commands,
             sources,
             policies,
             versions,
+            webhooks,
         })
     }
 
@@ -130,11 +153,38 @@ impl GlobalRpcState {
 /// endpoints. The user-generated data plane endpoints are serviced with REST.
 pub(crate) struct RpcService {
     state: Arc<Mutex<GlobalRpcState>>,
+    snapshots: Arc<SnapshotManager>,
+    /// In production, the calls meant for local development are refused.
+    production: bool,
 }
 
 impl RpcService {
-    pub(crate) fn new(state: Arc<Mutex<GlobalRpcState>>) -> Self {
-        Self { state }
+    pub(crate) fn new(state: Arc<Mutex<GlobalRpcState>>, snapshots: Arc<SnapshotManager>) -> Self {
+        Self {
+            state,
+            snapshots,
+            production: false,
+        }
+    }
+
+    pub(crate) fn with_production(mut self, production: bool) -> Self {
+        self.production = production;
+        self
+    }
+
+    /// Refuses calls that bypass authentication when running in production.
+    fn only_in_development(&self) -> Result<(), Status> {
+        if self.production {
+            return Err(Status::permission_denied(
+                "managing users with `chisel auth` is only possible on servers not started with --production",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn user_state(&self) -> (Arc<QueryEngine>, TypeSystem) {
+        let state = self.state.lock().await;
+        (state.query_engine.clone(), state.type_system.clone())
     }
 
     /// Delete a new version of ChiselStrike
@@ -177,6 +227,7 @@ impl RpcService {
 
         let prefix: PathBuf = format!("/{}/", api_version).into();
         state.sources.remove_prefix(&prefix);
+        schema_validation::save_schemas(&state.meta, &api_version, &EndpointSchemas::new()).await?;
         state.type_system.versions.remove(&api_version);
         state.policies.versions.remove(&api_version);
 
@@ -201,6 +252,103 @@ impl RpcService {
         }))
     }
 
+    /// Makes a numbered version announce its sunset date.
+    async fn deprecate_version_aux(
+        &self,
+        request: Request<DeprecateVersionRequest>,
+    ) -> Result<Response<DeprecateVersionResponse>> {
+        let request = request.into_inner();
+        let (version, sunset) =
+            route_version::parse_deprecation(&request.version, &request.sunset_date)?;
+        let state = self.state.lock().await;
+        route_version::save_deprecation(&state.meta, version, sunset).await?;
+
+        let cmd = send_command!({
+            runtime::get().api.deprecate_version(version.0, sunset);
+            Ok(())
+        });
+        state.send_command(cmd).await?;
+        Ok(Response::new(DeprecateVersionResponse {}))
+    }
+
+    /// Encrypts the values of an encrypted field stored before it was encrypted.
+    async fn encrypt_existing_aux(
+        &self,
+        request: Request<EncryptExistingRequest>,
+    ) -> Result<Response<EncryptExistingResponse>> {
+        let request = request.into_inner();
+        let (qeng, ts) = self.user_state().await;
+        let ty = match ts.lookup_type(&request.type_name, &request.version)? {
+            Type::Object(ty) => ty,
+            ty => anyhow::bail!("{} is not an entity", ty.name()),
+        };
+        let field = ty.lookup_field(&request.field_name)?.with_context(|| {
+            format!("{} has no field {}", request.type_name, request.field_name)
+        })?;
+        let encrypted = qeng.encrypt_existing(&ty, field).await?;
+        Ok(Response::new(EncryptExistingResponse { encrypted }))
+    }
+
+    async fn list_migrations_aux(
+        &self,
+        _request: Request<ListMigrationsRequest>,
+    ) -> Result<Response<ListMigrationsResponse>> {
+        let (qeng, _) = self.user_state().await;
+        let applied = migrations::records(&qeng)
+            .await?
+            .into_iter()
+            .map(|record| AppliedMigration {
+                id: record.id,
+                applied_at: record.applied_at,
+                error: record.error.unwrap_or_default(),
+            })
+            .collect();
+        Ok(Response::new(ListMigrationsResponse { applied }))
+    }
+
+    /// Runs a hand-written migration, see [`QueryEngine::execute_raw_migration`].
+    async fn apply_migration_aux(
+        &self,
+        request: Request<ApplyMigrationRequest>,
+    ) -> Result<Response<ApplyMigrationResponse>> {
+        let request = request.into_inner();
+        let (qeng, _) = self.user_state().await;
+        qeng.execute_raw_migration(&request.sql, &request.id)
+            .await?;
+        info!("Applied migration {}", request.id);
+        Ok(Response::new(ApplyMigrationResponse {}))
+    }
+
+    /// Records a hand-written migration as applied, see [`migrations::fake`].
+    async fn fake_migration_aux(
+        &self,
+        request: Request<FakeMigrationRequest>,
+    ) -> Result<Response<FakeMigrationResponse>> {
+        let request = request.into_inner();
+        let (qeng, _) = self.user_state().await;
+        migrations::fake(&qeng, &request.id).await?;
+        warn!(
+            "Marked migration {} as applied without running it",
+            request.id
+        );
+        Ok(Response::new(FakeMigrationResponse {}))
+    }
+
+    /// The net changes of hand-written migrations, see [`migrations::squash`].
+    async fn squash_migrations_aux(
+        &self,
+        request: Request<SquashMigrationsRequest>,
+    ) -> Result<Response<SquashMigrationsResponse>> {
+        let request = request.into_inner();
+        let sql: Vec<&str> = request.sql.iter().map(String::as_str).collect();
+        let kind = self.state.lock().await.query_engine.kind();
+        let squashed = migrations::squash(&sql, kind);
+        Ok(Response::new(SquashMigrationsResponse {
+            sql: squashed.sql,
+            rerunnable: squashed.rerunnable,
+        }))
+    }
+
     async fn populate_aux(
         &self,
         request: Request<PopulateRequest>,
@@ -223,6 +371,43 @@ impl RpcService {
 
         Ok(Response::new(response))
     }
+
+    /// Runs a query over the data of a version, without any policies, for
+    /// `chisel repl`.
+    async fn query_aux(&self, request: Request<QueryRequest>) -> Result<Response<QueryResponse>> {
+        let request = request.into_inner();
+        let op_chain: QueryOpChain =
+            serde_json::from_str(&request.op_chain).context("invalid query")?;
+
+        let (query_plan, qeng) = {
+            let state = self.state.lock().await;
+            let policies = Policies::default();
+            let context = RequestContext {
+                policies: &policies,
+                ts: &state.type_system,
+                api_version: request.version,
+                user_id: None,
+                tenant_id: None,
+                region: None,
+                path: String::new(),
+                headers: HashMap::new(),
+            };
+            let query_plan = QueryPlan::from_op_chain(&context, op_chain)?;
+            (query_plan, state.query_engine.clone())
+        };
+        let tr = qeng.clone().start_transaction_static().await?;
+        let rows = qeng
+            .query(tr, query_plan)?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Response::new(QueryResponse {
+            rows: serde_json::to_string(&rows)?,
+        }))
+    }
+
     /// Apply a new version of ChiselStrike
     async fn apply_aux(
         &self,
@@ -231,6 +416,7 @@ impl RpcService {
         let apply_request = request.into_inner();
         let api_version = apply_request.version;
         validate_api_version(&api_version)?;
+        let dry_run = apply_request.dry_run;
 
         let api_version_tag = apply_request.version_tag;
         let app_name = apply_request.app_name;
@@ -255,15 +441,30 @@ impl RpcService {
         }
         endpoint_paths.sort_unstable();
 
+        let mut schemas = EndpointSchemas::new();
+        for (path, schema) in apply_request.schemas {
+            let path = match without_extension(&path).strip_prefix("endpoints/") {
+                Some(path) => format!("/{}/{}", api_version, path),
+                None => continue,
+            };
+            let schema: serde_json::Value = serde_json::from_str(&schema)
+                .with_context(|| format!("parsing the JSON Schema of {}", path))?;
+            SchemaValidationMiddleware::new(&schema)
+                .with_context(|| format!("compiling the JSON Schema of {}", path))?;
+            schemas.insert(path, schema);
+        }
+
         // Do this before any permanent changes to any of the databases. Otherwise
         // we end up with bad code commited to the meta database and will fail to load
         // chiseld next time, as it tries to replenish the endpoints
-        let endpoints = sources.clone();
-        let cmd = send_command!({
-            deno::compile_endpoints(endpoints).await?;
-            Ok(())
-        });
-        state.send_command(cmd).await.context("parsing endpoints")?;
+        if !dry_run {
+            let endpoints = sources.clone();
+            let cmd = send_command!({
+                deno::compile_endpoints(endpoints).await?;
+                Ok(())
+            });
+            state.send_command(cmd).await.context("parsing endpoints")?;
+        }
 
         anyhow::ensure!(
             "__chiselstrike" != &api_version,
@@ -272,7 +473,9 @@ impl RpcService {
 
         // so that an empty apply removes the version.
         // We'll add it back as soon as we notice this is not empty
-        state.versions.remove(&api_version);
+        if !dry_run {
+            state.versions.remove(&api_version);
+        }
 
         let mut type_names = BTreeSet::new();
         let mut type_names_user_order = vec![];
@@ -351,26 +554,100 @@ or
                     },
                 };
 
-                fields.push(Field::new(
+                let mut new_field = Field::new(
                     NewField::new(&field.name, field_ty, &api_version)?,
                     field.labels,
                     field.default_value,
                     field.is_optional,
                     field.is_unique,
-                ));
+                )
+                .with_json_name(field.json_name)
+                .with_description(field.description);
+                if field.is_deprecated {
+                    new_field = new_field.deprecated(field.deprecated_message);
+                }
+                if field.is_encrypted {
+                    encryption::ensure_key().with_context(|| {
+                        format!("field {} of {} is @encrypted", field.name, name)
+                    })?;
+                    new_field = new_field.encrypted();
+                }
+                fields.push(new_field);
             }
             let ty_indexes = indexes.get(&name).cloned().unwrap_or_default();
 
-            let ty = Arc::new(ObjectType::new(
-                NewObject::new(&name, &api_version),
-                fields,
-                ty_indexes,
-                IsNotAuth,
-            )?);
+            let ty = Arc::new(
+                ObjectType::new(
+                    NewObject::new(&name, &api_version)
+                        .with_namespace(state.query_engine.namespace())
+                        .with_context(|| format!("invalid namespace for {}", name))?
+                        .with_table_name(type_def.table_name.clone())
+                        .with_context(|| format!("invalid @tableName of {}", name))?,
+                    fields,
+                    ty_indexes,
+                    IsNotAuth,
+                )?
+                .with_description(type_def.description)
+                .with_parent(type_def.parent)
+                .with_multi_tenant(type_def.multi_tenant)?
+                .with_id_strategy(match &type_def.id_strategy {
+                    Some(id_strategy) => id_strategy
+                        .parse()
+                        .with_context(|| format!("invalid @idStrategy of {}", name))?,
+                    None => IdStrategy::default(),
+                })
+                .with_webhook(
+                    type_def
+                        .webhook
+                        .map(|w| WebhookConfig::new(&w.url, &w.secret, &w.events))
+                        .transpose()
+                        .with_context(|| format!("invalid @webhook of {}", name))?,
+                )
+                .with_unique_constraints(
+                    type_def
+                        .unique_constraints
+                        .into_iter()
+                        .map(|c| UniqueConstraint {
+                            fields: c.fields,
+                            name: c.name,
+                        })
+                        .collect(),
+                )?
+                .with_check_constraints(
+                    type_def
+                        .check_constraints
+                        .into_iter()
+                        .map(|c| CheckConstraint {
+                            name: c.name,
+                            expression: c.expression,
+                        })
+                        .collect(),
+                )?
+                .with_virtual_relations(
+                    type_def
+                        .virtual_relations
+                        .into_iter()
+                        .map(|r| VirtualRelation {
+                            name: r.name,
+                            target_type: r.target_type,
+                            subquery: r.subquery,
+                        })
+                        .collect(),
+                )?,
+            );
             new_types.insert(name.to_owned(), ty.clone());
 
             match version_types.lookup_custom_type(&name) {
                 Ok(old_type) => {
+                    if let Some(table_name) = &type_def.table_name {
+                        anyhow::ensure!(
+                            old_type.backing_table() == table_name,
+                            "cannot move the rows of existing type {} from table {} to {}",
+                            name,
+                            old_type.backing_table(),
+                            table_name
+                        );
+                    }
                     let delta = TypeSystem::generate_type_delta(&old_type, ty)?;
                     to_update.push((old_type.clone(), delta));
                 }
@@ -381,6 +658,72 @@ or
             }
         }
 
+        let mut tables: HashSet<&str> = state.type_system.backing_tables().collect();
+        for ty in &to_insert {
+            anyhow::ensure!(
+                tables.insert(ty.backing_table()),
+                "table {} of type {} is taken by another type",
+                ty.backing_table(),
+                ty.name()
+            );
+        }
+
+        for ty in new_types.values() {
+            let mut ancestors = vec![ty.name()];
+            let mut child = ty;
+            while let Some(parent) = child.parent() {
+                anyhow::ensure!(
+                    !ancestors.contains(&parent),
+                    "type '{}' extends itself through '{}'",
+                    ty.name(),
+                    child.name()
+                );
+                let parent_ty = new_types.get(parent).with_context(|| {
+                    format!("type '{}' extends unknown type '{}'", child.name(), parent)
+                })?;
+                child.check_extends(parent_ty)?;
+                ancestors.push(parent);
+                child = parent_ty;
+            }
+            for relation in ty.virtual_relations() {
+                anyhow::ensure!(
+                    new_types.contains_key(&relation.target_type),
+                    "relation '{}' of type '{}' is to unknown type '{}'",
+                    relation.name,
+                    ty.name(),
+                    relation.target_type
+                );
+            }
+        }
+
+        // Worked out even when they aren't returned, so that changes the data
+        // database can't make fail before the metadata is touched.
+        let query_engine = &state.query_engine;
+        let mut statements = vec![];
+        for ty in &to_insert {
+            statements.extend(query_engine.create_table_sql(ty)?);
+        }
+        for ty in &to_remove {
+            statements.extend(query_engine.drop_table_sql(ty)?);
+        }
+        for (old, delta) in &to_update {
+            // The other indexes exist already.
+            statements.extend(query_engine.alter_table_sql(old, delta, &delta.added_indexes)?);
+        }
+        let mut warnings: Vec<String> = new_types
+            .values()
+            .flat_map(|ty| ty.check_constraints())
+            .filter_map(CheckConstraint::dialect_warning)
+            .collect();
+        warnings.sort();
+        if dry_run {
+            return Ok(Response::new(ChiselApplyResponse {
+                statements,
+                warnings,
+                ..Default::default()
+            }));
+        }
+
         let meta = &state.meta;
         let mut transaction = meta.start_transaction().await?;
 
@@ -458,6 +801,12 @@ or
         }
         QueryEngine::commit_transaction(transaction).await?;
 
+        // Now that the tables of the new types exist.
+        let state = &mut *state;
+        state
+            .webhooks
+            .update(&state.query_engine, &state.type_system);
+
         let prefix: PathBuf = format!("/{}/", api_version).into();
         state.sources.remove_prefix(&prefix);
 
@@ -466,6 +815,7 @@ or
         }
 
         state.meta.persist_sources(&state.sources).await?;
+        schema_validation::save_schemas(&state.meta, &api_version, &schemas).await?;
 
         let types_global = state.type_system.clone();
 
@@ -487,13 +837,12 @@ or
                 runtime.api.remove_routes(&prefix);
 
                 for path in &endpoints_for_cmd {
-                    let func = Arc::new({
-                        let path = path.clone();
-                        move |req| deno::run_js(path.clone(), req).boxed_local()
-                    });
-                    runtime.api.add_route(path.into(), func);
+                    runtime.api.add_route(
+                        path.into(),
+                        endpoint_route(path.clone(), schemas.get(path))?,
+                    )?;
                 }
-                runtime.api.update_api_info(&api_version, api_info);
+                runtime.api.update_api_info(&api_version, api_info)?;
             }
             for path in endpoints_for_cmd {
                 deno::activate_endpoint(&path).await?;
@@ -508,6 +857,8 @@ or
             types: type_names_user_order,
             endpoints: endpoint_paths,
             labels,
+            statements: vec![],
+            warnings,
         }))
     }
 
@@ -629,16 +980,67 @@ impl ChiselRpc for RpcService {
                     for field in ty.user_fields() {
                         field_defs.push(chisel::FieldDefinition {
                             name: field.name.to_owned(),
-                            field_type: field.type_.name().to_string(),
+                            field_type: field.type_.name(),
                             labels: field.labels.clone(),
                             default_value: field.user_provided_default().clone(),
                             is_optional: field.is_optional,
                             is_unique: field.is_unique,
+                            json_name: field.json_name.clone(),
+                            description: field.description.clone(),
+                            is_deprecated: field.is_deprecated,
+                            deprecated_message: field.deprecated_message.clone(),
+                            is_encrypted: field.is_encrypted,
                         });
                     }
                     let type_def = chisel::TypeDefinition {
                         name: ty.name().to_string(),
                         field_defs,
+                        description: ty.description().map(str::to_owned),
+                        index_defs: ty
+                            .indexes()
+                            .iter()
+                            .map(|index| chisel::IndexDefinition {
+                                fields: index.fields.clone(),
+                            })
+                            .collect(),
+                        unique_constraints: ty
+                            .unique_constraints()
+                            .iter()
+                            .map(|c| chisel::UniqueConstraintDefinition {
+                                fields: c
+                                    .fields
+                                    .iter()
+                                    .map(|f| ty.get_field(f).map_or(f.as_str(), Field::json_name))
+                                    .map(str::to_owned)
+                                    .collect(),
+                                name: c.name.clone(),
+                            })
+                            .collect(),
+                        check_constraints: ty
+                            .check_constraints()
+                            .iter()
+                            .map(|c| chisel::CheckConstraintDefinition {
+                                name: c.name.clone(),
+                                expression: c.expression.clone(),
+                            })
+                            .collect(),
+                        parent: ty.parent().map(str::to_owned),
+                        multi_tenant: ty.is_multi_tenant(),
+                        id_strategy: ty.id_strategy().to_string(),
+                        webhook: ty.webhook().map(|w| chisel::WebhookDefinition {
+                            url: w.url.clone(),
+                            secret: w.secret.clone(),
+                            events: w.events.iter().map(|e| e.as_str().to_owned()).collect(),
+                        }),
+                        virtual_relations: ty
+                            .virtual_relations()
+                            .iter()
+                            .map(|r| chisel::VirtualRelationDefinition {
+                                name: r.name.clone(),
+                                target_type: r.target_type.clone(),
+                                subquery: r.subquery.clone(),
+                            })
+                            .collect(),
                     };
                     type_defs.push(type_def);
                 }
@@ -688,6 +1090,196 @@ impl ChiselRpc for RpcService {
         let ok = nix::sys::signal::raise(nix::sys::signal::Signal::SIGUSR1).is_ok();
         Ok(Response::new(RestartResponse { server_id, ok }))
     }
+
+    async fn create_snapshot(
+        &self,
+        _request: tonic::Request<CreateSnapshotRequest>,
+    ) -> Result<tonic::Response<CreateSnapshotResponse>, tonic::Status> {
+        let snapshot = self
+            .snapshots
+            .create()
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(Response::new(CreateSnapshotResponse {
+            snapshot: Some(snapshot.into()),
+        }))
+    }
+
+    async fn list_snapshots(
+        &self,
+        _request: tonic::Request<ListSnapshotsRequest>,
+    ) -> Result<tonic::Response<ListSnapshotsResponse>, tonic::Status> {
+        let snapshots = self
+            .snapshots
+            .list()
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(Response::new(ListSnapshotsResponse {
+            snapshots: snapshots.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    /// Schedules the restore and restarts the server, which swaps the database on startup.
+    async fn restore_snapshot(
+        &self,
+        request: tonic::Request<RestoreSnapshotRequest>,
+    ) -> Result<tonic::Response<RestoreSnapshotResponse>, tonic::Status> {
+        let id = request.into_inner().id;
+        let server_id = {
+            let state = self.state.lock().await;
+            state.id.to_string()
+        };
+        self.snapshots
+            .schedule_restore(&id)
+            .await
+            .map_err(|e| Status::invalid_argument(format!("{:?}", e)))?;
+        let ok = nix::sys::signal::raise(nix::sys::signal::Signal::SIGUSR1).is_ok();
+        Ok(Response::new(RestoreSnapshotResponse { server_id, ok }))
+    }
+
+    async fn query(
+        &self,
+        request: tonic::Request<QueryRequest>,
+    ) -> Result<tonic::Response<QueryResponse>, tonic::Status> {
+        self.query_aux(request)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))
+    }
+
+    async fn add_user(
+        &self,
+        request: tonic::Request<AddUserRequest>,
+    ) -> Result<tonic::Response<AddUserResponse>, tonic::Status> {
+        self.only_in_development()?;
+        let (qeng, ts) = self.user_state().await;
+        let user_id = auth::add_user(&qeng, &ts, &request.into_inner().username)
+            .await
+            .map_err(|e| Status::invalid_argument(format!("{:?}", e)))?;
+        Ok(Response::new(AddUserResponse { user_id }))
+    }
+
+    async fn list_users(
+        &self,
+        _request: tonic::Request<ListUsersRequest>,
+    ) -> Result<tonic::Response<ListUsersResponse>, tonic::Status> {
+        self.only_in_development()?;
+        let (qeng, ts) = self.user_state().await;
+        let users = auth::list_users(&qeng, &ts)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))?;
+        Ok(Response::new(ListUsersResponse {
+            users: users
+                .into_iter()
+                .map(|u| chisel::UserDefinition {
+                    id: u.id,
+                    username: u.username,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn delete_user(
+        &self,
+        request: tonic::Request<DeleteUserRequest>,
+    ) -> Result<tonic::Response<DeleteUserResponse>, tonic::Status> {
+        self.only_in_development()?;
+        let (qeng, ts) = self.user_state().await;
+        auth::delete_user(&qeng, &ts, &request.into_inner().username)
+            .await
+            .map_err(|e| Status::invalid_argument(format!("{:?}", e)))?;
+        Ok(Response::new(DeleteUserResponse {}))
+    }
+
+    async fn rotate_secret(
+        &self,
+        request: tonic::Request<RotateSecretRequest>,
+    ) -> Result<tonic::Response<RotateSecretResponse>, tonic::Status> {
+        let file_secrets = get_secrets().await.unwrap_or_default();
+        let file_secret = file_secrets
+            .get(auth::AUTH_SECRET_NAME)
+            .and_then(|s| s.as_str())
+            .map(str::to_owned);
+        let state = self.state.lock().await;
+        let secret =
+            auth::rotate_auth_secret(&state.meta, file_secret, request.into_inner().new_secret)
+                .await
+                .map_err(|e| Status::invalid_argument(format!("{:?}", e)))?;
+        Ok(Response::new(RotateSecretResponse { secret }))
+    }
+
+    /// Hands out the secret of this process, so the CLI can use the admin routes.
+    async fn admin_secret(
+        &self,
+        _request: tonic::Request<AdminSecretRequest>,
+    ) -> Result<tonic::Response<AdminSecretResponse>, tonic::Status> {
+        Ok(Response::new(AdminSecretResponse {
+            secret: auth::PROCESS_SECRET.clone(),
+        }))
+    }
+
+    async fn deprecate_version(
+        &self,
+        request: Request<DeprecateVersionRequest>,
+    ) -> Result<Response<DeprecateVersionResponse>, Status> {
+        self.deprecate_version_aux(request)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))
+    }
+
+    async fn encrypt_existing(
+        &self,
+        request: Request<EncryptExistingRequest>,
+    ) -> Result<Response<EncryptExistingResponse>, Status> {
+        self.encrypt_existing_aux(request)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))
+    }
+
+    async fn list_migrations(
+        &self,
+        request: Request<ListMigrationsRequest>,
+    ) -> Result<Response<ListMigrationsResponse>, Status> {
+        self.list_migrations_aux(request)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))
+    }
+
+    async fn apply_migration(
+        &self,
+        request: Request<ApplyMigrationRequest>,
+    ) -> Result<Response<ApplyMigrationResponse>, Status> {
+        self.apply_migration_aux(request)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))
+    }
+
+    async fn fake_migration(
+        &self,
+        request: Request<FakeMigrationRequest>,
+    ) -> Result<Response<FakeMigrationResponse>, Status> {
+        self.fake_migration_aux(request)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))
+    }
+
+    async fn squash_migrations(
+        &self,
+        request: Request<SquashMigrationsRequest>,
+    ) -> Result<Response<SquashMigrationsResponse>, Status> {
+        self.squash_migrations_aux(request)
+            .await
+            .map_err(|e| Status::internal(format!("{:?}", e)))
+    }
+}
+
+impl From<Snapshot> for chisel::SnapshotDefinition {
+    fn from(s: Snapshot) -> Self {
+        Self {
+            id: s.id,
+            created_at: s.created_at,
+            size: s.size,
+        }
+    }
 }
 
 pub(crate) fn spawn(
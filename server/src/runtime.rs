@@ -1,6 +1,7 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::api::ApiService;
+use crate::context::RequestContext;
 use crate::rcmut::RcMut;
 use derive_new::new;
 use once_cell::sync::OnceCell;
@@ -10,6 +11,8 @@ use std::rc::Rc;
 #[derive(new)]
 pub(crate) struct Runtime {
     pub(crate) api: Rc<ApiService>,
+    /// The context `api` routes requests with, kept to update its type system.
+    pub(crate) context: RequestContext,
 }
 
 thread_local!(static RUNTIME: OnceCell<Rc<RefCell<Runtime>>> = OnceCell::new());
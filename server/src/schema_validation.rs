@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Validation of request bodies against the JSON Schema of their endpoint.
+//!
+//! `chisel apply` sends the schema an endpoint is registered with along
+//! with its code. The schemas are kept in the meta database, by endpoint
+//! path, so that they are back when the endpoints are loaded on startup.
+
+use crate::api::{json_response, Body, Middleware, RouteFn};
+use crate::datastore::MetaService;
+use anyhow::{anyhow, Result};
+use deno_core::futures::future::LocalBoxFuture;
+use deno_core::futures::FutureExt;
+use hyper::{Method, Request, Response, StatusCode};
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Meta key under which the schemas of all endpoints are kept.
+const SCHEMAS_KEY: &str = "endpoint_schemas";
+
+/// JSON Schemas by the path of the endpoint they are for, e.g. `/dev/orders`.
+pub(crate) type EndpointSchemas = HashMap<String, Value>;
+
+pub(crate) async fn load_schemas(meta: &MetaService) -> Result<EndpointSchemas> {
+    match meta.get_meta_value(SCHEMAS_KEY).await? {
+        Some(value) => Ok(serde_json::from_str(&value)?),
+        None => Ok(EndpointSchemas::new()),
+    }
+}
+
+/// Replaces the schemas of the endpoints of `api_version` with `schemas`.
+pub(crate) async fn save_schemas(
+    meta: &MetaService,
+    api_version: &str,
+    schemas: &EndpointSchemas,
+) -> Result<()> {
+    let prefix = format!("/{}/", api_version);
+    let mut all = load_schemas(meta).await?;
+    all.retain(|path, _| !path.starts_with(&prefix));
+    all.extend(schemas.iter().map(|(p, s)| (p.clone(), s.clone())));
+    let mut transaction = meta.start_transaction().await?;
+    meta.set_meta_value(&mut transaction, SCHEMAS_KEY, &serde_json::to_string(&all)?)
+        .await?;
+    MetaService::commit_transaction(transaction).await
+}
+
+/// Answers the `POST` and `PUT` requests whose body doesn't match `schema`
+/// with `422 Unprocessable Entity`, listing every offending value by its
+/// JSON pointer, before the handler runs. `PATCH` bodies only hold the
+/// fields that change, so they aren't held to the schema.
+pub(crate) struct SchemaValidationMiddleware {
+    schema: Arc<JSONSchema>,
+}
+
+impl SchemaValidationMiddleware {
+    pub(crate) fn new(schema: &Value) -> Result<Self> {
+        let schema =
+            JSONSchema::compile(schema).map_err(|e| anyhow!("invalid JSON Schema: {}", e))?;
+        Ok(Self {
+            schema: Arc::new(schema),
+        })
+    }
+
+    /// `route_fn`, behind this middleware.
+    pub(crate) fn wrap(self, route_fn: RouteFn) -> RouteFn {
+        Arc::new(move |req| self.call(req, route_fn.clone()))
+    }
+}
+
+/// What is wrong with `body`, as `{"pointer": ..., "message": ...}` errors.
+fn errors(schema: &JSONSchema, body: &[u8]) -> Vec<Value> {
+    let error = |pointer: String, message: String| serde_json::json!({ "pointer": pointer, "message": message });
+    let instance: Value = match serde_json::from_slice(body) {
+        Ok(instance) => instance,
+        Err(e) => return vec![error("".into(), format!("body is not JSON: {}", e))],
+    };
+    match schema.validate(&instance) {
+        Ok(()) => vec![],
+        Err(errors) => errors
+            .map(|e| error(e.instance_path.to_string(), e.to_string()))
+            .collect(),
+    }
+}
+
+impl Middleware for SchemaValidationMiddleware {
+    fn call(
+        &self,
+        req: Request<hyper::Body>,
+        next: RouteFn,
+    ) -> LocalBoxFuture<'static, Result<Response<Body>>> {
+        if ![Method::POST, Method::PUT].contains(req.method()) {
+            return next(req);
+        }
+        let schema = self.schema.clone();
+        async move {
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            let errors = errors(&schema, &body);
+            if !errors.is_empty() {
+                return json_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    serde_json::json!({ "errors": errors }),
+                );
+            }
+            next(Request::from_parts(parts, body.into())).await
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn rejects_invalid_bodies() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "fields": {
+                    "type": "object",
+                    "properties": { "email": { "type": "string" } },
+                },
+                "age": { "type": "number" },
+            },
+            "required": ["age"],
+        });
+        let route = SchemaValidationMiddleware::new(&schema)
+            .unwrap()
+            .wrap(Arc::new(|_req| {
+                async { Ok(Response::builder().status(201).body(Body::default())?) }.boxed_local()
+            }));
+        let send = |method: Method, body: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri("/dev/people")
+                .body(hyper::Body::from(body.to_owned()))
+                .unwrap();
+            let route = route.clone();
+            async move {
+                let response = route(req).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+            }
+        };
+
+        assert_eq!(send(Method::POST, r#"{"age": 3}"#).await.0, 201);
+        let (status, body) = send(Method::PUT, r#"{"age": "3", "fields": {"email": 1}}"#).await;
+        assert_eq!(status, 422);
+        let mut pointers: Vec<_> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["pointer"].as_str().unwrap().to_owned())
+            .collect();
+        pointers.sort();
+        assert_eq!(pointers, ["/age", "/fields/email"]);
+        assert_eq!(send(Method::POST, "{").await.0, 422);
+        assert_eq!(send(Method::PATCH, r#"{"age": "3"}"#).await.0, 201);
+        SchemaValidationMiddleware::new(&json!({"type": 3})).unwrap_err();
+    }
+}
@@ -1,7 +1,12 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
-use crate::api::ApiService;
-use crate::datastore::{DbConnection, MetaService, QueryEngine};
+use crate::api::{ApiService, RouteFn};
+use crate::auth::add_rotated_auth_secrets;
+use crate::blob::LocalBlobStore;
+use crate::context::RequestContext;
+use crate::datastore::meta::cleaner::Cleaner;
+use crate::datastore::snapshot::SnapshotManager;
+use crate::datastore::{DbConnection, MetaService, PoolConfig, QueryEngine};
 use crate::deno;
 use crate::deno::init_deno;
 use crate::deno::set_meta;
@@ -10,22 +15,32 @@ use crate::deno::set_query_engine;
 use crate::deno::set_type_system;
 use crate::deno::update_secrets;
 use crate::deno::{activate_endpoint, compile_endpoints};
+use crate::idempotency::{self, IdempotencyMiddleware};
+use crate::logs;
+use crate::migrations;
+use crate::region;
 use crate::rpc::InitState;
 use crate::rpc::{GlobalRpcState, RpcService};
 use crate::runtime;
 use crate::runtime::Runtime;
+use crate::schema_validation::{self, EndpointSchemas, SchemaValidationMiddleware};
 use crate::secrets::get_secrets;
+use crate::session_cache;
+use crate::tenancy::{self, MultiTenancyMiddleware};
+use crate::types;
+use crate::webhooks::Webhooks;
 use crate::JsonObject;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_lock::Mutex;
 use deno_core::futures;
 use enclose::enclose;
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
 use futures::StreamExt;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
@@ -60,12 +75,70 @@ pub struct Opt {
     /// size of database connection pool.
     #[structopt(short, long, default_value = "10")]
     nr_connections: usize,
+    /// How long (in milliseconds) a query may run before it is cancelled. Zero lets queries run
+    /// for as long as they take.
+    #[structopt(long, default_value = "0")]
+    query_timeout_ms: u64,
+    /// Migrate the table of each type on its first query, rather than all of them at startup.
+    #[structopt(long)]
+    lazy_migrations: bool,
+    /// Prefix the tables of new types with NAMESPACE_.
+    #[structopt(long)]
+    namespace: Option<String>,
     /// How many executor threads to create
     #[structopt(short, long, default_value = "1")]
     executor_threads: usize,
     /// If on, serve a web UI on an internal route.
     #[structopt(long)]
     webui: bool,
+    /// How long (in milliseconds) the readiness probe waits for the database.
+    #[structopt(long, default_value = "1000")]
+    health_check_timeout: u64,
+    /// How often (in seconds) expired session tokens are purged.
+    #[structopt(long, default_value = "3600")]
+    session_cleanup_interval: u64,
+    /// How long (in seconds) a session is kept after it expires, before being purged.
+    #[structopt(long, default_value = "0")]
+    session_ttl: u64,
+    /// How long (in seconds) the username of a logged-in user is cached.
+    #[structopt(long, default_value = "60")]
+    session_cache_ttl: u64,
+    /// How many usernames of logged-in users are cached at most. Zero disables the cache.
+    #[structopt(long, default_value = "10000")]
+    session_cache_size: usize,
+    /// File where every request served is appended as a line of JSON, for `chisel analyze`.
+    #[structopt(long)]
+    access_log: Option<PathBuf>,
+    /// Directory where uploaded files are stored.
+    #[structopt(long, default_value = ".chiseld-blobs")]
+    blob_dir: PathBuf,
+    /// Serve uploaded files to anyone, rather than only to requests with the admin secret.
+    #[structopt(long)]
+    public_blobs: bool,
+    /// Directory where database snapshots are kept.
+    #[structopt(long, default_value = ".chiseld-snapshots")]
+    snapshot_dir: PathBuf,
+    /// How often (in seconds) a database snapshot is taken. Zero disables periodic snapshots.
+    #[structopt(long, default_value = "0")]
+    snapshot_interval: u64,
+    /// How many snapshots to keep before deleting the oldest ones. Zero keeps all of them.
+    #[structopt(long, default_value = "10")]
+    snapshot_retention: usize,
+    /// Reject writes to deprecated fields with 400 Bad Request, instead of warning about them.
+    #[structopt(long)]
+    strict_mode: bool,
+    /// Run in production, refusing the RPC calls meant for local development, like `chisel auth`.
+    #[structopt(long)]
+    production: bool,
+    /// Serve each tenant at a subdomain of this domain, answering 404 Not Found elsewhere.
+    #[structopt(long)]
+    tenant_domain: Option<String>,
+    /// Database replica a region reads from, as REGION=URL. Repeat for several regions.
+    #[structopt(long, parse(try_from_str = region::parse_region_db))]
+    region_db: Vec<(String, String)>,
+    /// Headers the region of a request is read from, the first one present winning.
+    #[structopt(long, default_value = region::DEFAULT_REGION_HEADERS, use_delimiter = true)]
+    region_header: Vec<String>,
 }
 
 /// Whether an action should be repeated.
@@ -97,6 +170,9 @@ struct SharedState {
     executor_threads: usize,
     db: DbConnection,
     nr_connections: usize,
+    blob_dir: PathBuf,
+    public_blobs: bool,
+    strict_mode: bool,
 }
 
 impl SharedState {
@@ -119,6 +195,7 @@ impl SharedTasks {
 
 pub(crate) async fn add_endpoints(
     sources: HashMap<String, String>,
+    schemas: &EndpointSchemas,
     api_service: &ApiService,
 ) -> Result<()> {
     compile_endpoints(sources.clone()).await?;
@@ -127,23 +204,33 @@ pub(crate) async fn add_endpoints(
         let path = deno::endpoint_path_from_source_path(path);
         activate_endpoint(&path).await?;
 
-        let func = Arc::new({
-            let path = path.to_string();
-            move |req| deno::run_js(path.clone(), req).boxed_local()
-        });
-        api_service.add_route(path.into(), func);
+        let route = endpoint_route(path.to_string(), schemas.get(&path))?;
+        api_service.add_route(path.into(), route)?;
     }
     Ok(())
 }
 
-async fn read_secrets() -> Result<JsonObject> {
+/// The route of the JavaScript endpoint at `path`, behind the middleware
+/// every endpoint goes through and, if it has one, the validation of its
+/// request bodies against `schema`.
+pub(crate) fn endpoint_route(path: String, schema: Option<&Value>) -> Result<RouteFn> {
+    let mut func: RouteFn = Arc::new(move |req| deno::run_js(path.clone(), req).boxed_local());
+    if let Some(schema) = schema {
+        func = SchemaValidationMiddleware::new(schema)?.wrap(func);
+    }
+    Ok(MultiTenancyMiddleware::wrap(IdempotencyMiddleware::wrap(
+        func,
+    )))
+}
+
+async fn read_secrets(meta: &MetaService) -> Result<JsonObject> {
     static LAST_TRY_WAS_FAILURE: Mutex<bool> = Mutex::new(false);
     let secrets = get_secrets().await;
     let mut was_failure = LAST_TRY_WAS_FAILURE.lock().await;
-    match secrets {
+    let mut secrets = match secrets {
         Ok(secrets) => {
             *was_failure = false;
-            Ok(secrets)
+            secrets
         }
         Err(e) => {
             if !*was_failure {
@@ -152,12 +239,16 @@ async fn read_secrets() -> Result<JsonObject> {
             *was_failure = true;
             if e.is::<serde_json::Error>() {
                 // Map broken files to empty secrets.
-                Ok(Default::default())
+                Default::default()
             } else {
-                Err(e)
+                return Err(e);
             }
         }
+    };
+    if let Err(e) = add_rotated_auth_secrets(meta, &mut secrets).await {
+        warn!("Could not read rotated auth secrets: {:?}", e);
     }
+    Ok(secrets)
 }
 
 async fn run(state: SharedState, init: InitState, mut cmd: ExecutorChannel) -> Result<()> {
@@ -166,35 +257,53 @@ async fn run(state: SharedState, init: InitState, mut cmd: ExecutorChannel) -> R
         policies,
         type_system: ts,
     } = init;
-    init_deno(state.inspect_brk).await?;
+    init_deno(state.inspect_brk, &state.api_listen_addr).await?;
+
+    let meta = MetaService::local_connection(&state.db, state.nr_connections).await?;
 
     // Ensure we read the secrets before spawning an ApiService; secrets may dictate API authorization.
-    let secret = match read_secrets().await {
+    let secret = match read_secrets(&meta).await {
         Ok(v) => v,
         Err(_) => Default::default(), // During startup, map io error to empty secrets.
     };
     update_secrets(secret).await;
 
-    let meta = MetaService::local_connection(&state.db, state.nr_connections).await?;
-
     let api_info = meta.load_api_info().await?;
 
-    let mut api_service = ApiService::new(api_info);
-    crate::auth::init(&mut api_service).await?;
-    crate::introspect::init(&api_service);
-
     let query_engine =
         Arc::new(QueryEngine::local_connection(&state.db, state.nr_connections).await?);
     ts.create_builtin_backing_tables(query_engine.as_ref())
         .await?;
+    idempotency::create_table(&query_engine).await?;
+    migrations::create_table(&query_engine).await?;
+    let blob_store = Arc::new(LocalBlobStore::new(state.blob_dir.clone()));
+    let context = RequestContext::new(query_engine.clone(), blob_store, ts.clone())
+        .with_strict_mode(state.strict_mode);
+
+    let mut api_service = ApiService::new(api_info).with_context(context.clone());
+    crate::auth::init(&mut api_service).await?;
+    crate::introspect::init(&api_service)?;
+    crate::blob::init(&api_service, state.public_blobs)?;
+    crate::admin::init(&api_service, &ts)?;
+    crate::sse::init(&api_service)?;
+    crate::webhooks::init(
+        &api_service,
+        MetaService::local_connection(&state.db, 1).await?,
+    )?;
+    crate::entities::init(&api_service)?;
+    crate::transactions::init(&api_service)?;
+    for (version, sunset) in crate::route_version::load_deprecations(&meta).await? {
+        api_service.deprecate_version(version.0, sunset);
+    }
     let api_service = Rc::new(api_service);
+    let schemas = schema_validation::load_schemas(&meta).await?;
     let versions: Vec<&String> = ts.versions.keys().collect();
 
     for v in versions {
-        crate::introspect::add_introspection(&api_service, v);
+        crate::introspect::add_introspection(&api_service, v)?;
     }
 
-    let rt = Runtime::new(api_service.clone());
+    let rt = Runtime::new(api_service.clone(), context);
     runtime::set(rt);
     set_type_system(ts).await;
     set_query_engine(query_engine).await;
@@ -209,7 +318,7 @@ async fn run(state: SharedState, init: InitState, mut cmd: ExecutorChannel) -> R
             (path, v.clone())
         })
         .collect();
-    add_endpoints(hashmap, &api_service).await?;
+    add_endpoints(hashmap, &schemas, &api_service).await?;
 
     let command_task = tokio::task::spawn_local(async move {
         while let Some(item) = cmd.rx.next().await {
@@ -280,7 +389,30 @@ fn find_legacy_sqlite_dbs(opt: &Opt) -> Vec<PathBuf> {
 async fn run_shared_state(
     opt: Opt,
 ) -> Result<(SharedTasks, SharedState, Vec<ExecutorChannel>, InitState)> {
-    let db_conn = DbConnection::connect(&opt.db_uri, opt.nr_connections).await?;
+    if let Some(db_file) = extract(&opt.db_uri) {
+        let restored =
+            SnapshotManager::apply_pending_restore(&opt.snapshot_dir, Path::new(&db_file)).await?;
+        if let Some(id) = restored {
+            info!("Restored database snapshot {}", id);
+        }
+    }
+
+    if let Some(access_log) = &opt.access_log {
+        logs::set_access_log(access_log)?;
+    }
+
+    if let Some(namespace) = &opt.namespace {
+        types::validate_table_name(namespace).context("invalid --namespace")?;
+    }
+    let pool_config = PoolConfig {
+        nr_connections: opt.nr_connections,
+        query_timeout: Duration::from_millis(opt.query_timeout_ms),
+        lazy_migrations: opt.lazy_migrations,
+        namespace: opt.namespace.clone(),
+    };
+    let db_conn = DbConnection::connect_with(&opt.db_uri, &pool_config)
+        .await?
+        .with_regions(&opt.region_db, opt.nr_connections)?;
     let meta = MetaService::local_connection(&db_conn, opt.nr_connections).await?;
 
     let legacy_dbs = find_legacy_sqlite_dbs(&opt);
@@ -307,16 +439,57 @@ async fn run_shared_state(
     let sources = meta.load_sources().await?;
     let policies = meta.load_policies().await?;
     let type_system = meta.load_type_system().await?;
+
+    // With lazy migrations, each type is migrated on its first query instead.
+    if !opt.lazy_migrations {
+        for version_types in type_system.versions.values() {
+            for ty in version_types.custom_types.values() {
+                query_engine.ensure_version_column(ty).await?;
+            }
+        }
+        // Tables created before changes were tracked need their triggers too.
+        let mut transaction = query_engine.start_transaction().await?;
+        for version_types in type_system.versions.values() {
+            for ty in version_types.custom_types.values() {
+                query_engine
+                    .install_change_triggers(&mut transaction, ty)
+                    .await?;
+            }
+        }
+        QueryEngine::commit_transaction(transaction).await?;
+    }
     let init = InitState {
         sources,
         policies,
         type_system,
     };
+    session_cache::init(
+        Duration::from_secs(opt.session_cache_ttl),
+        opt.session_cache_size,
+    );
+    if let Some(domain) = &opt.tenant_domain {
+        tenancy::init(domain.clone());
+    }
+    region::init(opt.region_header.clone());
+    let health_check_engine = query_engine.clone();
+    let cleaner = Cleaner::new(
+        MetaService::local_connection(&db_conn, 1).await?,
+        query_engine.clone(),
+        &init.type_system,
+        Duration::from_secs(opt.session_cleanup_interval),
+        Duration::from_secs(opt.session_ttl),
+    )?;
+    let webhooks = Webhooks::new(MetaService::local_connection(&db_conn, 1).await?);
     let state = Arc::new(Mutex::new(
-        GlobalRpcState::new(meta, init.clone(), query_engine, rpc_commands).await?,
+        GlobalRpcState::new(meta, init.clone(), query_engine, rpc_commands, webhooks).await?,
     ));
 
-    let rpc = RpcService::new(state);
+    let snapshots = Arc::new(SnapshotManager::new(
+        QueryEngine::local_connection(&db_conn, 1).await?,
+        opt.snapshot_dir.clone(),
+        opt.snapshot_retention,
+    ));
+    let rpc = RpcService::new(state, snapshots.clone()).with_production(opt.production);
 
     let (signal_tx, signal_rx) = utils::make_signal_channel();
 
@@ -340,6 +513,7 @@ async fn run_shared_state(
     let secret_commands = commands2.clone();
 
     let secret_shutdown = signal_rx.clone();
+    let secret_meta = MetaService::local_connection(&db_conn, 1).await?;
     // Spawn periodic hot-reload of secrets.  This doesn't load secrets immediately, though.
     let _secret_reader = tokio::task::spawn(async move {
         loop {
@@ -350,7 +524,7 @@ async fn run_shared_state(
                 }
             };
 
-            let secrets = match read_secrets().await {
+            let secrets = match read_secrets(&secret_meta).await {
                 Ok(s) => s,
                 Err(_) => continue, // ignore IO errors
             };
@@ -366,6 +540,13 @@ async fn run_shared_state(
         }
     });
 
+    let _session_cleaner = tokio::task::spawn(cleaner.run(signal_rx.clone()));
+    let _transaction_reaper = tokio::task::spawn(crate::transactions::reap_idle(signal_rx.clone()));
+    if opt.snapshot_interval > 0 {
+        let interval = Duration::from_secs(opt.snapshot_interval);
+        let _snapshotter = tokio::task::spawn(snapshots.run(interval, signal_rx.clone()));
+    }
+
     // rpc server should start listening only when all threads start
     let (readiness_tx, readiness_rx) = async_channel::bounded(opt.executor_threads);
 
@@ -387,6 +568,8 @@ async fn run_shared_state(
         opt.internal_routes_listen_addr,
         opt.webui,
         opt.rpc_listen_addr,
+        health_check_engine,
+        Duration::from_millis(opt.health_check_timeout),
     );
     debug!(
         "Internal HTTP server is ready. URL: {}",
@@ -401,6 +584,9 @@ async fn run_shared_state(
         executor_threads: opt.executor_threads,
         db: db_conn,
         nr_connections: opt.nr_connections,
+        blob_dir: opt.blob_dir,
+        public_blobs: opt.public_blobs,
+        strict_mode: opt.strict_mode,
     };
 
     let tasks = SharedTasks { rpc_task, sig_task };
@@ -0,0 +1,141 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Cache of the usernames of logged-in users.
+//!
+//! Policies match the username of whoever makes a request, but requests
+//! only carry the user's id in the `ChiselUID` header, so the username would
+//! otherwise be read from the database on every request. Requests land on
+//! any executor thread, so the cache is process-wide.
+
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The cache used by the server, if [`init`] was called.
+static SESSION_CACHE: OnceCell<SessionCache> = OnceCell::new();
+
+/// Sets up the process-wide cache, keeping up to `capacity` usernames for
+/// `ttl` each. A capacity of zero disables caching.
+pub(crate) fn init(ttl: Duration, capacity: usize) {
+    SESSION_CACHE
+        .set(SessionCache::new(ttl, capacity))
+        .map_err(|_| ())
+        .expect("SESSION_CACHE already initialized before session_cache::init()");
+}
+
+pub(crate) fn get() -> Option<&'static SessionCache> {
+    SESSION_CACHE.get()
+}
+
+struct Entry {
+    username: String,
+    expires: Instant,
+    last_used: Instant,
+}
+
+/// Usernames by user id, each kept until its TTL runs out. When full, the
+/// least recently used entry makes room for new ones.
+pub(crate) struct SessionCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl SessionCache {
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: Default::default(),
+            ttl,
+            capacity,
+        }
+    }
+
+    pub(crate) fn get(&self, user_id: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let entry = entries.get_mut(user_id)?;
+        if entry.expires <= now {
+            entries.remove(user_id);
+            return None;
+        }
+        entry.last_used = now;
+        Some(entry.username.clone())
+    }
+
+    pub(crate) fn insert(&self, user_id: String, username: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if entries.len() >= self.capacity && !entries.contains_key(&user_id) {
+            entries.retain(|_, e| e.expires > now);
+            if entries.len() >= self.capacity {
+                let lru = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.last_used)
+                    .map(|(id, _)| id.clone());
+                if let Some(lru) = lru {
+                    entries.remove(&lru);
+                }
+            }
+        }
+        entries.insert(
+            user_id,
+            Entry {
+                username,
+                expires: now + self.ttl,
+                last_used: now,
+            },
+        );
+    }
+
+    /// Drops the entries of the users named `username`, whose sessions are
+    /// gone.
+    pub(crate) fn forget(&self, username: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| e.username != username);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let cache = SessionCache::new(Duration::from_millis(50), 10);
+        cache.insert("u1".into(), "alice".into());
+        assert_eq!(cache.get("u1").as_deref(), Some("alice"));
+        assert_eq!(cache.get("u2"), None);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("u1"), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = SessionCache::new(Duration::from_secs(60), 2);
+        cache.insert("u1".into(), "alice".into());
+        cache.insert("u2".into(), "bob".into());
+        cache.get("u1");
+        cache.insert("u3".into(), "carol".into());
+        assert_eq!(cache.get("u1").as_deref(), Some("alice"));
+        assert_eq!(cache.get("u2"), None);
+        assert_eq!(cache.get("u3").as_deref(), Some("carol"));
+    }
+
+    #[test]
+    fn forget() {
+        let cache = SessionCache::new(Duration::from_secs(60), 10);
+        cache.insert("u1".into(), "alice".into());
+        cache.insert("u2".into(), "bob".into());
+        cache.forget("alice");
+        assert_eq!(cache.get("u1"), None);
+        assert_eq!(cache.get("u2").as_deref(), Some("bob"));
+
+        let disabled = SessionCache::new(Duration::from_secs(60), 0);
+        disabled.insert("u1".into(), "alice".into());
+        assert_eq!(disabled.get("u1"), None);
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Server-sent events streaming the changes to a type, for live queries.
+
+use crate::admin::AdminAuth;
+use crate::api::{response_template, version_param, ApiService, Body};
+use crate::context::{query_engine_route, RequestContext};
+use crate::datastore::query::QueryPlan;
+use crate::datastore::watch::{self, ChangeEvent, ChangeStream, Operation, POLL_INTERVAL};
+use crate::datastore::QueryEngine;
+use crate::route_pattern::route_param;
+use crate::types::ObjectType;
+use crate::JsonObject;
+use anyhow::Result;
+use deno_core::futures::{future, stream, StreamExt};
+use hyper::{Method, Request, Response};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+pub(crate) const SSE_PATH: &str = "/__chiselstrike/sse";
+
+/// The row of `ty` whose id is `id`, unless there is none.
+pub(crate) async fn fetch_row(
+    qeng: &Arc<QueryEngine>,
+    ty: &Arc<ObjectType>,
+    id: &str,
+) -> Result<Option<JsonObject>> {
+    let tr = qeng.clone().start_transaction_static().await?;
+    let mut rows = qeng.query(tr, QueryPlan::from_type(ty).filter_by_id(id))?;
+    rows.next().await.transpose()
+}
+
+/// Formats a change as an event named after its operation, whose data is
+/// `{"operation": ..., "data": ...}`. Deleted rows only carry their id.
+async fn to_event(
+    qeng: &Arc<QueryEngine>,
+    ty: &Arc<ObjectType>,
+    change: ChangeEvent,
+) -> Result<Box<[u8]>> {
+    let row = match change.operation {
+        Operation::Delete => None,
+        _ if change.after.is_some() => change.after,
+        _ => fetch_row(qeng, ty, &change.id).await?,
+    };
+    let data = match row {
+        Some(row) => serde_json::Value::Object(row),
+        // Also the case of rows deleted right after the change.
+        None => json!({ "id": change.id }),
+    };
+    let payload = json!({ "operation": change.operation, "data": data });
+    let event = format!(
+        "event: {}\ndata: {}\n\n",
+        change.operation.as_str(),
+        payload
+    );
+    Ok(event.into_bytes().into_boxed_slice())
+}
+
+pub(crate) fn receiver_stream(rx: broadcast::Receiver<ChangeEvent>) -> ChangeStream {
+    Box::pin(stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(change) => return Some((Ok(change), rx)),
+                Err(RecvError::Lagged(missed)) => warn!("Change watcher missed {} changes", missed),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }))
+}
+
+/// Streams the changes to the rows of `:type`, or only to row `:id` if `single` is set.
+async fn watch(
+    req: Request<hyper::Body>,
+    qeng: Arc<QueryEngine>,
+    single: bool,
+) -> Result<Response<Body>> {
+    let type_name = route_param(&req, "type")?;
+    let id = match single {
+        true => Some(route_param(&req, "id")?),
+        false => None,
+    };
+    let version = version_param(&req);
+    let context = RequestContext::of(&req)?;
+    let ty = context
+        .type_system()
+        .lookup_object_type(&type_name, &version)?;
+    anyhow::ensure!(!ty.is_auth(), "Cannot watch type {}", type_name);
+
+    // Soft deletes are only updates to the change triggers, so the types
+    // that have them are polled instead.
+    let changes = match watch::is_watchable(&ty) {
+        true => receiver_stream(qeng.clone().watch_table(ty.clone(), POLL_INTERVAL).await?),
+        false => qeng.watch(ty.backing_table()).await?,
+    };
+    let changes = changes.filter(move |change| {
+        let keep = match (&id, change) {
+            (Some(id), Ok(change)) => change.id == *id,
+            _ => true,
+        };
+        future::ready(keep)
+    });
+    let events = changes.then(move |change| {
+        let (qeng, ty) = (qeng.clone(), ty.clone());
+        async move { to_event(&qeng, &ty, change?).await }
+    });
+    Ok(response_template()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(Body::Stream(Box::pin(events)))?)
+}
+
+/// Registers `GET /__chiselstrike/sse/:type`, which streams the changes to a type,
+/// and `GET /__chiselstrike/sse/:type/:id`, which streams those to a single entity.
+///
+/// The events aren't filtered by policies, so the route is guarded like the admin ones.
+pub(crate) fn init(api: &ApiService) -> Result<()> {
+    let sse = api.add_route_group(SSE_PATH, vec![Arc::new(AdminAuth)]);
+    sse.add_route(
+        Method::GET,
+        "/:type",
+        query_engine_route(|req, qeng| watch(req, qeng, false)),
+    )?;
+    sse.add_route(
+        Method::GET,
+        "/:type/:id",
+        query_engine_route(|req, qeng| watch(req, qeng, true)),
+    )
+}
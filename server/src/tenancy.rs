@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Multi-tenancy: one server keeping the data of many tenants apart.
+//!
+//! When chiseld runs with `--tenant-domain myapp.com`, a request to an
+//! endpoint at `acme.myapp.com` is made for the [`TENANT_NAME`] entity whose
+//! subdomain is `acme`. Queries of multi-tenant types then only see the rows
+//! whose `tenant_id` is the id of that tenant, and saving an entity of such a
+//! type sets it.
+
+use crate::api::{ApiService, Body, Middleware, RouteFn};
+use crate::auth::builtin_backing_table;
+use crate::context::RequestContext;
+use crate::datastore::engine::SqlWithArguments;
+use crate::datastore::query::SqlValue;
+use crate::types::{ObjectType, Type};
+use crate::JsonObject;
+use anyhow::Result;
+use deno_core::futures::future::LocalBoxFuture;
+use deno_core::futures::FutureExt;
+use hyper::header::HOST;
+use hyper::{Request, Response};
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use sqlx::Row;
+use std::sync::Arc;
+
+pub(crate) const TENANT_NAME: &str = "Tenant";
+
+/// Field of the multi-tenant types holding the id of the tenant a row belongs to.
+pub(crate) const TENANT_ID_FIELD: &str = "tenant_id";
+
+/// Header through which the TypeScript API tells the native routes the
+/// tenant a request is made for.
+pub(crate) const TENANT_HEADER: &str = "ChiselTenant";
+
+/// The domain whose subdomains the tenants are served at, if [`init`] was called.
+static TENANT_DOMAIN: OnceCell<String> = OnceCell::new();
+
+/// Makes the endpoints only answer requests made for a tenant, at a
+/// subdomain of `domain`.
+pub(crate) fn init(domain: String) {
+    TENANT_DOMAIN
+        .set(domain.to_ascii_lowercase())
+        .map_err(|_| ())
+        .expect("TENANT_DOMAIN already initialized before tenancy::init()");
+}
+
+/// Whether the server serves tenants, which [`init`] makes it do.
+pub(crate) fn is_enabled() -> bool {
+    TENANT_DOMAIN.get().is_some()
+}
+
+/// The tenant a request is made for, added to its extensions by
+/// [`MultiTenancyMiddleware`].
+#[derive(Clone, Debug)]
+pub(crate) struct TenantId(pub(crate) String);
+
+/// The subdomain of `domain` named by `host`, which may carry a port.
+/// Hosts that are `domain` itself or further down than one subdomain name none.
+fn subdomain(host: &str, domain: &str) -> Option<String> {
+    let host = host.split(':').next()?.to_ascii_lowercase();
+    let subdomain = host.strip_suffix(domain)?.strip_suffix('.')?;
+    (!subdomain.is_empty() && !subdomain.contains('.')).then(|| subdomain.to_owned())
+}
+
+/// The id of the tenant at `subdomain`, if there is one.
+async fn tenant_id(context: &RequestContext, subdomain: &str) -> Result<Option<String>> {
+    let table = builtin_backing_table(&context.type_system(), TENANT_NAME)?;
+    let rows = context
+        .query_engine
+        .fetch_all(SqlWithArguments {
+            sql: format!("SELECT id FROM \"{}\" WHERE subdomain = $1", table),
+            args: vec![SqlValue::String(subdomain.to_owned())],
+        })
+        .await?;
+    Ok(rows.first().map(|row| row.get("id")))
+}
+
+/// Answers `404 Not Found` to the requests whose `Host` isn't the subdomain
+/// of a tenant, so that no data is served without knowing whose it is, and
+/// adds the [`TenantId`] to the others. Lets everything through unless
+/// [`init`] was called.
+pub(crate) struct MultiTenancyMiddleware;
+
+impl MultiTenancyMiddleware {
+    /// `route_fn`, behind this middleware.
+    pub(crate) fn wrap(route_fn: RouteFn) -> RouteFn {
+        Arc::new(move |req| MultiTenancyMiddleware.call(req, route_fn.clone()))
+    }
+}
+
+impl Middleware for MultiTenancyMiddleware {
+    fn call(
+        &self,
+        mut req: Request<hyper::Body>,
+        next: RouteFn,
+    ) -> LocalBoxFuture<'static, Result<Response<Body>>> {
+        let domain = match TENANT_DOMAIN.get() {
+            // The auth endpoints are shared by all tenants.
+            Some(domain) if !req.uri().path().starts_with("/__chiselstrike/") => domain,
+            _ => return next(req),
+        };
+        async move {
+            let host = req
+                .headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| req.uri().host());
+            let subdomain = match host.and_then(|host| subdomain(host, domain)) {
+                Some(subdomain) => subdomain,
+                None => return ApiService::not_found(),
+            };
+            let context = RequestContext::of(&req)?;
+            match tenant_id(&context, &subdomain).await? {
+                Some(id) => {
+                    req.extensions_mut().insert(TenantId(id));
+                    next(req).await
+                }
+                None => ApiService::not_found(),
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Makes `value`, an entity of `ty` about to be saved, and the entities
+/// nested in it belong to the tenant `tenant_id`, where their types are
+/// multi-tenant.
+pub(crate) fn set_tenant(ty: &ObjectType, value: &mut JsonObject, tenant_id: &str) {
+    if ty.is_multi_tenant() {
+        if let Ok(field) = ty.tenant_id_field() {
+            value.insert(field.json_name().to_owned(), tenant_id.into());
+        }
+    }
+    for field in ty.user_fields() {
+        if let Type::Object(nested_ty) = &field.type_ {
+            if let Some(Value::Object(nested)) = value.get_mut(field.json_name()) {
+                set_tenant(nested_ty, nested, tenant_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::{make_field, VERSION};
+    use crate::types::{AuthOrNot, NewObject};
+    use serde_json::json;
+
+    #[test]
+    fn subdomains() {
+        let domain = "myapp.com";
+        assert_eq!(subdomain("acme.myapp.com", domain).as_deref(), Some("acme"));
+        assert_eq!(
+            subdomain("ACME.MyApp.com:8080", domain).as_deref(),
+            Some("acme")
+        );
+        assert_eq!(subdomain("myapp.com", domain), None);
+        assert_eq!(subdomain("a.b.myapp.com", domain), None);
+        assert_eq!(subdomain("acmemyapp.com", domain), None);
+        assert_eq!(subdomain("acme.other.com", domain), None);
+    }
+
+    #[test]
+    fn sets_nested_tenants() {
+        let object = |name: &str, fields| {
+            ObjectType::new(
+                NewObject::new(name, VERSION),
+                fields,
+                vec![],
+                AuthOrNot::IsNotAuth,
+            )
+            .unwrap()
+        };
+        let owner = Arc::new(object("Owner", vec![make_field("name", Type::String)]));
+        let project = object(
+            "Project",
+            vec![
+                make_field("tenant_id", Type::String),
+                make_field("owner", Type::Object(owner)),
+            ],
+        )
+        .with_multi_tenant(true)
+        .unwrap();
+        let task = object(
+            "Task",
+            vec![
+                make_field("tenant_id", Type::String),
+                make_field("project", Type::Object(Arc::new(project))),
+            ],
+        )
+        .with_multi_tenant(true)
+        .unwrap();
+
+        let mut value = json!({"project": {"owner": {"name": "ann"}}});
+        set_tenant(&task, value.as_object_mut().unwrap(), "t1");
+        assert_eq!(
+            value,
+            json!({
+                "tenant_id": "t1",
+                "project": {"tenant_id": "t1", "owner": {"name": "ann"}},
+            })
+        );
+    }
+}
@@ -0,0 +1,275 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Transactions spanning several requests, for `ChiselEntity.transaction`.
+//!
+//! `POST /__chiselstrike/transactions/begin` opens a transaction and returns
+//! its id. Requests to the entity routes carrying that id in the
+//! [`TRANSACTION_HEADER`] then run inside it, until it is ended by `commit`
+//! or `rollback`. A request that fails after writing some of its entities
+//! marks the transaction failed, see [`Writes`], and committing a failed
+//! transaction rolls it back. Requests may land on any executor thread, so the open
+//! transactions are kept in a process-wide map, which [`reap_idle`] keeps
+//! clear of abandoned ones.
+
+use crate::admin::AdminAuth;
+use crate::api::{json_response, response_template, ApiService, Body};
+use crate::context::query_engine_route;
+use crate::datastore::engine::TransactionStatic;
+use crate::datastore::QueryEngine;
+use anyhow::{Context, Result};
+use deno_core::futures;
+use futures::FutureExt;
+use hyper::{Method, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde_json::json;
+use sqlx::any::Any;
+use sqlx::Transaction;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+pub(crate) const TRANSACTIONS_PATH: &str = "/__chiselstrike/transactions";
+
+/// Header holding the id of the transaction a request belongs to.
+pub(crate) const TRANSACTION_HEADER: &str = "ChiselTransaction";
+
+/// Transactions left unused for longer than this are rolled back.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the open transactions are checked for idle ones.
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
+struct OpenTransaction {
+    transaction: TransactionStatic,
+    last_used: Instant,
+    /// Whether a request that wrote in it failed.
+    failed: bool,
+}
+
+/// Why a transaction can't be used or ended.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum TransactionError {
+    #[error["no open transaction {0}"]]
+    Unknown(String),
+    #[error["transaction {0} is still in use"]]
+    InUse(String),
+}
+
+#[derive(Default)]
+pub(crate) struct TransactionManager {
+    open: Mutex<HashMap<String, OpenTransaction>>,
+}
+
+pub(crate) static TRANSACTIONS: Lazy<TransactionManager> = Lazy::new(Default::default);
+
+fn transaction_id(req: &Request<hyper::Body>) -> Result<Option<&str>> {
+    match req.headers().get(TRANSACTION_HEADER) {
+        Some(id) => Ok(Some(id.to_str()?)),
+        None => Ok(None),
+    }
+}
+
+impl TransactionManager {
+    async fn begin(&self, qeng: Arc<QueryEngine>) -> Result<String> {
+        let transaction = qeng.start_transaction_static().await?;
+        let id = Uuid::new_v4().to_string();
+        self.open.lock().unwrap().insert(
+            id.clone(),
+            OpenTransaction {
+                transaction,
+                last_used: Instant::now(),
+                failed: false,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Rolls back the transactions left unused for longer than [`IDLE_TIMEOUT`].
+    fn reap_idle(&self) {
+        // Dropping a transaction rolls it back.
+        self.open
+            .lock()
+            .unwrap()
+            .retain(|_, t| t.last_used.elapsed() < IDLE_TIMEOUT);
+    }
+
+    fn get(&self, id: &str) -> Result<TransactionStatic> {
+        let mut open = self.open.lock().unwrap();
+        let open = open
+            .get_mut(id)
+            .ok_or_else(|| TransactionError::Unknown(id.to_owned()))?;
+        open.last_used = Instant::now();
+        Ok(open.transaction.clone())
+    }
+
+    fn fail(&self, id: &str) {
+        if let Some(open) = self.open.lock().unwrap().get_mut(id) {
+            open.failed = true;
+        }
+    }
+
+    /// Removes the transaction `req` belongs to from the open ones, and tells
+    /// whether it failed. A transaction that requests are still using is left
+    /// open, failing with [`TransactionError::InUse`].
+    fn take(&self, req: &Request<hyper::Body>) -> Result<(Transaction<'static, Any>, bool)> {
+        let id = transaction_id(req)?
+            .with_context(|| format!("missing {} header", TRANSACTION_HEADER))?;
+        let mut open = self.open.lock().unwrap();
+        let taken = open
+            .remove(id)
+            .ok_or_else(|| TransactionError::Unknown(id.to_owned()))?;
+        match Arc::try_unwrap(taken.transaction) {
+            Ok(transaction) => Ok((transaction.into_inner(), taken.failed)),
+            Err(transaction) => {
+                open.insert(
+                    id.to_owned(),
+                    OpenTransaction {
+                        transaction,
+                        ..taken
+                    },
+                );
+                Err(TransactionError::InUse(id.to_owned()).into())
+            }
+        }
+    }
+}
+
+/// The transaction a request writes its entities in: the one it names in the
+/// [`TRANSACTION_HEADER`], or else one of its own.
+///
+/// The writes are only kept if the request reaches [`Writes::finish`]. If it
+/// returns before that, on an error or with a failure status, its own
+/// transaction is dropped and so rolled back, and the named one is marked
+/// failed. That way a request never leaves part of its writes behind.
+pub(crate) struct Writes {
+    /// Only taken by [`Writes::finish`].
+    transaction: Option<TransactionStatic>,
+    /// The id of the named transaction, until the writes are finished.
+    named: Option<String>,
+}
+
+impl Writes {
+    pub(crate) async fn begin(req: &Request<hyper::Body>, qeng: &Arc<QueryEngine>) -> Result<Self> {
+        let named = transaction_id(req)?.map(str::to_owned);
+        let transaction = match &named {
+            Some(id) => TRANSACTIONS.get(id)?,
+            None => qeng.clone().start_transaction_static().await?,
+        };
+        Ok(Self {
+            transaction: Some(transaction),
+            named,
+        })
+    }
+
+    pub(crate) fn transaction(&self) -> &TransactionStatic {
+        self.transaction.as_ref().unwrap()
+    }
+
+    /// Keeps the writes: commits them, unless they are part of a named
+    /// transaction, which commits them along with the rest.
+    pub(crate) async fn finish(mut self) -> Result<()> {
+        let transaction = self.transaction.take().unwrap();
+        if self.named.take().is_none() {
+            QueryEngine::commit_transaction_static(transaction).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writes {
+    fn drop(&mut self) {
+        if let Some(id) = &self.named {
+            TRANSACTIONS.fail(id);
+        }
+    }
+}
+
+/// Rolls back idle transactions every [`REAP_INTERVAL`] until `shutdown` fires.
+pub(crate) async fn reap_idle(shutdown: async_channel::Receiver<()>) {
+    loop {
+        futures::select! {
+            _ = sleep(REAP_INTERVAL).fuse() => {},
+            _ = shutdown.recv().fuse() => {
+                break;
+            }
+        };
+        TRANSACTIONS.reap_idle();
+    }
+}
+
+async fn begin(qeng: Arc<QueryEngine>) -> Result<Response<Body>> {
+    let id = TRANSACTIONS.begin(qeng).await?;
+    json_response(StatusCode::OK, json!({ "id": id }))
+}
+
+/// Commits the transaction, or responds with `409 Conflict` after rolling it
+/// back if it failed.
+async fn commit(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let (transaction, failed) = TRANSACTIONS.take(&req)?;
+    if failed {
+        transaction.rollback().await?;
+        return Ok(response_template()
+            .status(StatusCode::CONFLICT)
+            .body("the transaction was rolled back, as one of its writes failed".into())?);
+    }
+    QueryEngine::commit_transaction(transaction).await?;
+    json_response(StatusCode::OK, json!({}))
+}
+
+async fn rollback(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let (transaction, _) = TRANSACTIONS.take(&req)?;
+    transaction.rollback().await?;
+    json_response(StatusCode::OK, json!({}))
+}
+
+/// Registers the `begin`, `commit` and `rollback` routes under `/__chiselstrike/transactions`.
+pub(crate) fn init(api: &ApiService) -> Result<()> {
+    let transactions = api.add_route_group(TRANSACTIONS_PATH, vec![Arc::new(AdminAuth)]);
+    transactions.add_route(
+        Method::POST,
+        "/begin",
+        query_engine_route(|_req, qeng| begin(qeng)),
+    )?;
+    transactions.add_route(
+        Method::POST,
+        "/commit",
+        Arc::new(|req| commit(req).boxed_local()),
+    )?;
+    transactions.add_route(
+        Method::POST,
+        "/rollback",
+        Arc::new(|req| rollback(req).boxed_local()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::setup_clear_db;
+
+    #[tokio::test]
+    async fn take_leaves_transactions_in_use_open() {
+        let (qe, _db_file) = setup_clear_db(&[]).await;
+        let transactions = TransactionManager::default();
+        let id = transactions.begin(Arc::new(qe)).await.unwrap();
+        let req = || {
+            Request::builder()
+                .header(TRANSACTION_HEADER, &id)
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        let take_error = |req: Request<hyper::Body>| match transactions.take(&req) {
+            Ok(_) => panic!("took transaction {}", id),
+            Err(e) => e.downcast::<TransactionError>().unwrap(),
+        };
+
+        let in_use = transactions.get(&id).unwrap();
+        assert!(matches!(take_error(req()), TransactionError::InUse(_)));
+        drop(in_use);
+        let (_, failed) = transactions.take(&req()).unwrap();
+        assert!(!failed);
+        assert!(matches!(take_error(req()), TransactionError::Unknown(_)));
+    }
+}
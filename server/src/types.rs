@@ -3,11 +3,13 @@
 use crate::auth::{AUTH_ACCOUNT_NAME, AUTH_SESSION_NAME, AUTH_TOKEN_NAME, AUTH_USER_NAME};
 use crate::datastore::query::{truncate_identifier, QueryPlan};
 use crate::datastore::QueryEngine;
-use crate::types::AuthOrNot::IsAuth;
+use crate::tenancy::{TENANT_ID_FIELD, TENANT_NAME};
+use crate::types::AuthOrNot::{IsAuth, IsNotAuth};
 use anyhow::Context;
 use deno_core::futures;
 use derive_new::new;
 use futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use uuid::Uuid;
@@ -73,6 +75,11 @@ fn string_field(name: &str) -> Field {
         is_optional: false,
         api_version: "__chiselstrike".into(),
         is_unique: false,
+        json_name: None,
+        description: None,
+        is_deprecated: false,
+        deprecated_message: None,
+        is_encrypted: false,
     }
 }
 
@@ -93,6 +100,11 @@ fn optional_number_field(name: &str) -> Field {
         is_optional: true,
         api_version: "__chiselstrike".into(),
         is_unique: false,
+        json_name: None,
+        description: None,
+        is_deprecated: false,
+        deprecated_message: None,
+        is_encrypted: false,
     }
 }
 
@@ -105,6 +117,15 @@ impl Default for TypeSystem {
         ts.builtin_types.insert("string".into(), Type::String);
         ts.builtin_types.insert("number".into(), Type::Float);
         ts.builtin_types.insert("boolean".into(), Type::Boolean);
+        ts.builtin_types.insert("Blob".into(), Type::Blob);
+        for elem in [Type::String, Type::Float, Type::Boolean] {
+            let array = Type::Array(Box::new(elem.clone()));
+            ts.builtin_types.insert(array.name().into(), array);
+            let map = Type::Map(Some(Box::new(elem)));
+            ts.builtin_types.insert(map.name().into(), map);
+        }
+        let map = Type::Map(None);
+        ts.builtin_types.insert(map.name().into(), map);
         ts.add_builtin_object_type(
             AUTH_USER_NAME,
             vec![
@@ -156,6 +177,12 @@ impl Default for TypeSystem {
             "auth_account",
             IsAuth,
         );
+        ts.add_builtin_object_type(
+            TENANT_NAME,
+            vec![string_field("subdomain")],
+            "tenant",
+            IsNotAuth,
+        );
 
         ts
     }
@@ -173,6 +200,12 @@ impl TypeSystem {
             }
         }
         QueryEngine::commit_transaction(transaction).await?;
+        // The tables may predate the version column.
+        for ty in self.builtin_types.values() {
+            if let Type::Object(ty) = ty {
+                query_engine.ensure_version_column(ty).await?;
+            }
+        }
         Ok(())
     }
 
@@ -194,6 +227,57 @@ impl TypeSystem {
             .ok_or_else(|| TypeSystemError::NoSuchVersion(api_version.to_owned()))
     }
 
+    /// Deserializes `json` into a `T` holding types, looking up the object types
+    /// they refer to in this type system.
+    #[cfg(test)]
+    pub(crate) fn from_json<T: serde::de::DeserializeOwned>(
+        &self,
+        json: &str,
+    ) -> anyhow::Result<T> {
+        object_type_ref::LOOKUP.with(|ts| ts.replace(Some(self.clone())));
+        let value = serde_json::from_str(json);
+        object_type_ref::LOOKUP.with(|ts| ts.replace(None));
+        Ok(value?)
+    }
+
+    /// A copy of this type system whose types keep their rows in tables of
+    /// their own, named after the original ones with `<namespace>_` in front,
+    /// as are their indexes. Tests sharing a database can each work on a fork
+    /// of the same types without seeing the rows of the others. The built-in
+    /// auth types are shared with the original, as their tables are with
+    /// `--namespace`.
+    #[cfg(test)]
+    pub(crate) fn fork(&self, namespace: &str) -> anyhow::Result<TypeSystem> {
+        validate_table_name(namespace).context("invalid namespace")?;
+        let mut forked = self.clone();
+        forked.versions.clear();
+        for (version, types) in &self.versions {
+            // The types are forked after the ones they refer to, which are
+            // looked up in the fork.
+            let mut pending: Vec<_> = types.custom_types.values().collect();
+            while !pending.is_empty() {
+                let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|ty| {
+                    ty.user_fields().all(|field| match &field.type_ {
+                        Type::Object(nested) => forked
+                            .lookup_object_type(nested.name(), &nested.api_version)
+                            .is_ok(),
+                        _ => true,
+                    })
+                });
+                anyhow::ensure!(
+                    !ready.is_empty(),
+                    "types of version {} refer to each other in a cycle",
+                    version
+                );
+                for ty in ready {
+                    forked.add_type(Arc::new(ty.forked(namespace, &forked)?))?;
+                }
+                pending = rest;
+            }
+        }
+        Ok(forked)
+    }
+
     /// Adds a custom type to the type system.
     ///
     /// # Arguments
@@ -253,6 +337,16 @@ impl TypeSystem {
                         ));
                     }
 
+                    if old.is_encrypted && !field.is_encrypted {
+                        return Err(TypeSystemError::UnsafeReplacement(
+                            new_type.name.clone(),
+                            format!(
+                                "removing encryption from field {}, whose values are stored encrypted. Incompatible change",
+                                field.name,
+                            ),
+                        ));
+                    }
+
                     if field.is_unique && !old.is_unique {
                         // FIXME: it should be possible to do it by issuing a select count() and
                         // then a select count distinct and comparing both results. But to do this
@@ -271,12 +365,22 @@ impl TypeSystem {
                         || field.type_ != old.type_
                         || field.is_optional != old.is_optional
                         || field.is_unique != old.is_unique
+                        || field.json_name != old.json_name
+                        || field.description != old.description
+                        || field.is_deprecated != old.is_deprecated
+                        || field.deprecated_message != old.deprecated_message
+                        || field.is_encrypted != old.is_encrypted
                     {
                         Some(FieldAttrDelta {
                             type_: field.type_.clone(),
                             default: field.default.clone(),
                             is_optional: field.is_optional,
                             is_unique: field.is_unique,
+                            json_name: field.json_name.clone(),
+                            description: field.description.clone(),
+                            is_deprecated: field.is_deprecated,
+                            deprecated_message: field.deprecated_message.clone(),
+                            is_encrypted: field.is_encrypted,
                         })
                     } else {
                         None
@@ -324,6 +428,35 @@ impl TypeSystem {
             updated_fields,
             added_indexes: Self::find_added_indexes(old_type, &new_type),
             removed_indexes: Self::find_removed_indexes(old_type, &new_type),
+            added_unique_constraints: Self::constraint_diff(
+                new_type.unique_constraints(),
+                old_type.unique_constraints(),
+            ),
+            removed_unique_constraints: Self::constraint_diff(
+                old_type.unique_constraints(),
+                new_type.unique_constraints(),
+            ),
+            added_check_constraints: Self::constraint_diff(
+                new_type.check_constraints(),
+                old_type.check_constraints(),
+            ),
+            removed_check_constraints: Self::constraint_diff(
+                old_type.check_constraints(),
+                new_type.check_constraints(),
+            ),
+            added_virtual_relations: Self::constraint_diff(
+                new_type.virtual_relations(),
+                old_type.virtual_relations(),
+            ),
+            removed_virtual_relations: Self::constraint_diff(
+                old_type.virtual_relations(),
+                new_type.virtual_relations(),
+            ),
+            description: new_type.description.clone(),
+            parent: new_type.parent.clone(),
+            multi_tenant: new_type.multi_tenant,
+            id_strategy: new_type.id_strategy,
+            webhook: new_type.webhook.clone(),
         })
     }
 
@@ -344,6 +477,12 @@ impl TypeSystem {
             .collect()
     }
 
+    /// The constraints in `lhs` that aren't in `rhs`. A constraint that
+    /// changed is in neither, so it's removed and added again.
+    fn constraint_diff<C: Clone + PartialEq>(lhs: &[C], rhs: &[C]) -> Vec<C> {
+        lhs.iter().filter(|c| !rhs.contains(c)).cloned().collect()
+    }
+
     /// Looks up a custom type with name `type_name` across API versions
     ///
     /// # Arguments
@@ -363,11 +502,23 @@ impl TypeSystem {
         version.lookup_custom_type(type_name)
     }
 
+    /// The backing tables of the built-in types and of the custom types of
+    /// all versions.
+    pub(crate) fn backing_tables(&self) -> impl Iterator<Item = &str> {
+        let builtin = self.builtin_types.values().filter_map(|ty| match ty {
+            Type::Object(ty) => Some(ty),
+            _ => None,
+        });
+        let custom = self.versions.values().flat_map(|v| v.custom_types.values());
+        builtin.chain(custom).map(|ty| ty.backing_table())
+    }
+
     /// Looks up a builtin type with name `type_name`.
     pub(crate) fn lookup_builtin_type(&self, type_name: &str) -> Result<Type, TypeSystemError> {
         self.builtin_types
             .get(type_name)
             .cloned()
+            .or_else(|| Type::enum_from_name(type_name))
             .ok_or_else(|| TypeSystemError::NotABuiltinType(type_name.to_string()))
     }
 
@@ -484,23 +635,117 @@ impl TypeSystem {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(crate) enum Type {
     String,
     Float,
     Boolean,
     Id,
-    Object(Arc<ObjectType>),
+    /// Binary data kept in a [`crate::blob::BlobStore`]; the database only holds its key.
+    Blob,
+    /// An ordered list of scalars, kept in the database as a JSON array.
+    Array(Box<Type>),
+    /// Scalars by string keys, kept in the database as a JSON object. Values
+    /// of any JSON type are allowed if the value type is None.
+    Map(Option<Box<Type>>),
+    /// A string that can only be one of the given values, as the TypeScript
+    /// union `"pending" | "active" | "closed"`.
+    Enum(Vec<String>),
+    /// Serialized as a reference to the type, see [`object_type_ref`].
+    Object(#[serde(with = "object_type_ref")] Arc<ObjectType>),
+}
+
+/// (De)serializes the object type of a [`Type::Object`] as its name and
+/// version, so that entities referring to each other don't get inlined.
+/// The type is looked up again on deserialization, which is thus only
+/// possible through [`TypeSystem::from_json`].
+mod object_type_ref {
+    use super::{ObjectType, TypeSystem};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_derive::{Deserialize, Serialize};
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    thread_local! {
+        /// Type system the object types are looked up in while deserializing.
+        pub(super) static LOOKUP: RefCell<Option<TypeSystem>> = RefCell::new(None);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ObjectTypeRef {
+        name: String,
+        api_version: String,
+    }
+
+    pub(super) fn serialize<S: Serializer>(ty: &Arc<ObjectType>, s: S) -> Result<S::Ok, S::Error> {
+        ObjectTypeRef {
+            name: ty.name.clone(),
+            api_version: ty.api_version.clone(),
+        }
+        .serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Arc<ObjectType>, D::Error> {
+        let r = ObjectTypeRef::deserialize(d)?;
+        LOOKUP.with(|ts| match &*ts.borrow() {
+            Some(ts) => ts
+                .lookup_object_type(&r.name, &r.api_version)
+                .map_err(serde::de::Error::custom),
+            None => Err(serde::de::Error::custom(format!(
+                "cannot resolve type {} outside of TypeSystem::from_json",
+                r.name
+            ))),
+        })
+    }
 }
 
 impl Type {
-    pub(crate) fn name(&self) -> &str {
-        match self {
+    pub(crate) fn name(&self) -> String {
+        let name = match self {
             Type::Float => "number",
             Type::Id => "string",
             Type::String => "string",
             Type::Boolean => "boolean",
+            Type::Blob => "Blob",
+            Type::Array(elem) => match **elem {
+                Type::Float => "number[]",
+                Type::Boolean => "boolean[]",
+                _ => "string[]",
+            },
+            Type::Map(value) => match value.as_deref() {
+                None => "Record<string, any>",
+                Some(Type::Float) => "Record<string, number>",
+                Some(Type::Boolean) => "Record<string, boolean>",
+                Some(_) => "Record<string, string>",
+            },
+            Type::Enum(variants) => {
+                return variants
+                    .iter()
+                    .map(|v| format!("\"{}\"", v))
+                    .collect::<Vec<_>>()
+                    .join(" | ")
+            }
             Type::Object(ty) => &ty.name,
+        };
+        name.to_owned()
+    }
+
+    /// The [`Type::Enum`] named `name`, if it is one.
+    fn enum_from_name(name: &str) -> Option<Type> {
+        let mut variants = vec![];
+        let mut rest = name.trim();
+        loop {
+            let (variant, after) = rest.strip_prefix('"')?.split_once('"')?;
+            variants.push(variant.to_owned());
+            rest = after.trim_start();
+            if rest.is_empty() {
+                return Some(Type::Enum(variants));
+            }
+            rest = rest.strip_prefix('|')?.trim_start();
         }
     }
 }
@@ -626,6 +871,65 @@ impl<'a> NewObject<'a> {
             backing_table,
         }
     }
+
+    /// Puts the generated name of the backing table in `namespace`, if given,
+    /// see [`TypeSystem::fork`]. A name given with [`Self::with_table_name`]
+    /// afterwards is kept as it is.
+    pub(crate) fn with_namespace(self, namespace: Option<&str>) -> anyhow::Result<Self> {
+        match namespace {
+            Some(namespace) => Ok(Self {
+                backing_table: namespaced(namespace, &self.backing_table)?,
+                ..self
+            }),
+            None => Ok(self),
+        }
+    }
+
+    /// Makes the type keep its rows in `table_name`, if given, rather than in
+    /// a table named after the type.
+    pub(crate) fn with_table_name(self, table_name: Option<String>) -> anyhow::Result<Self> {
+        match table_name {
+            Some(backing_table) => {
+                validate_table_name(&backing_table)?;
+                Ok(Self {
+                    backing_table,
+                    ..self
+                })
+            }
+            None => Ok(self),
+        }
+    }
+}
+
+/// Checks that `name` can be given as the backing table of a type: an SQL
+/// identifier that needs no quoting, and that PostgreSQL doesn't truncate.
+pub(crate) fn validate_table_name(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "table name '{}' is not a valid SQL identifier: it must be made of letters, digits \
+         and underscores, and not start with a digit",
+        name
+    );
+    anyhow::ensure!(
+        truncate_identifier(name) == name,
+        "table name '{}' is longer than 63 bytes",
+        name
+    );
+    anyhow::ensure!(
+        !name.starts_with("__chiselstrike"),
+        "table name '{}' is reserved for ChiselStrike",
+        name
+    );
+    Ok(())
+}
+
+/// The name of `table` in `namespace`, see [`TypeSystem::fork`]. Fails if
+/// the prefix makes it too long.
+fn namespaced(namespace: &str, table: &str) -> anyhow::Result<String> {
+    let namespaced = format!("{}_{}", namespace, table);
+    validate_table_name(&namespaced)?;
+    Ok(namespaced)
 }
 
 impl<'a> ObjectDescriptor for NewObject<'a> {
@@ -647,13 +951,18 @@ impl<'a> ObjectDescriptor for NewObject<'a> {
 }
 
 /// Whether a type is used in authentication.
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum AuthOrNot {
     IsAuth,
     IsNotAuth,
 }
 
-#[derive(Debug)]
+/// Name of the column holding the version of a row. Starts at zero and
+/// goes up by one on every update.
+pub(crate) const VERSION_FIELD_NAME: &str = "__version";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct ObjectType {
     /// id of this object in the meta-database. Will be None for objects that are not persisted yet
     pub(crate) meta_id: Option<i32>,
@@ -663,11 +972,39 @@ pub(crate) struct ObjectType {
     fields: Vec<Field>,
     /// Indexes that are to be created in the database to accelerate queries.
     indexes: Vec<DbIndex>,
+    /// Sets of fields whose values no two rows may share.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    unique_constraints: Vec<UniqueConstraint>,
+    /// SQL conditions every row has to meet.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    check_constraints: Vec<CheckConstraint>,
+    /// Relationships computed by a query rather than stored in a column.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    virtual_relations: Vec<VirtualRelation>,
     /// user-visible ID of this object.
     chisel_id: Field,
+    /// Hidden counter of the writes to each row, used to detect conflicting updates.
+    chisel_version: Field,
     /// Name of the backing table for this type.
     backing_table: String,
     is_auth: AuthOrNot,
+    /// Documentation of this type, from the JSDoc comment of its class.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Name of the type this one extends. Its backing table holds the fields
+    /// of the parent too, see [`ObjectType::check_extends`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    /// Whether each row belongs to a tenant, whose id its `tenant_id` field
+    /// holds. See [`crate::tenancy`].
+    #[serde(default)]
+    multi_tenant: bool,
+    /// How the ids of new rows are generated.
+    #[serde(default)]
+    id_strategy: IdStrategy,
+    /// Where the changes to the rows are POSTed, see [`crate::webhooks`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webhook: Option<WebhookConfig>,
 
     pub(crate) api_version: String,
 }
@@ -689,6 +1026,30 @@ impl ObjectType {
                 api_version,
                 field.api_version
             );
+            anyhow::ensure!(
+                fields
+                    .iter()
+                    .filter(|f| f.json_name() == field.json_name())
+                    .count()
+                    == 1,
+                "more than one field of type '{}' goes by '{}' in JSON",
+                desc.name(),
+                field.json_name()
+            );
+        }
+        for field in fields.iter().filter(|f| f.is_encrypted) {
+            anyhow::ensure!(
+                matches!(field.type_, Type::String),
+                "field '{}' of type '{}' is encrypted, but only string fields can be",
+                field.name,
+                desc.name()
+            );
+            anyhow::ensure!(
+                !field.is_unique,
+                "field '{}' of type '{}' can't be both encrypted and unique",
+                field.name,
+                desc.name()
+            );
         }
         for index in &indexes {
             for field_name in &index.fields {
@@ -710,6 +1071,27 @@ impl ObjectType {
             is_optional: false,
             api_version: "__chiselstrike".into(),
             is_unique: true,
+            json_name: None,
+            description: None,
+            is_deprecated: false,
+            deprecated_message: None,
+            is_encrypted: false,
+        };
+        let chisel_version = Field {
+            id: None,
+            name: VERSION_FIELD_NAME.to_string(),
+            type_: Type::Float,
+            labels: Vec::default(),
+            default: None,
+            effective_default: None,
+            is_optional: false,
+            api_version: "__chiselstrike".into(),
+            is_unique: false,
+            json_name: None,
+            description: None,
+            is_deprecated: false,
+            deprecated_message: None,
+            is_encrypted: false,
         };
         Ok(Self {
             meta_id: desc.id(),
@@ -718,8 +1100,17 @@ impl ObjectType {
             backing_table,
             fields,
             indexes,
+            unique_constraints: vec![],
+            check_constraints: vec![],
+            virtual_relations: vec![],
             chisel_id,
+            chisel_version,
             is_auth,
+            description: None,
+            parent: None,
+            multi_tenant: false,
+            id_strategy: IdStrategy::default(),
+            webhook: None,
         })
     }
 
@@ -731,22 +1122,268 @@ impl ObjectType {
         std::iter::once(&self.chisel_id).chain(self.fields.iter())
     }
 
-    pub(crate) fn has_field(&self, field_name: &str) -> bool {
-        self.all_fields().any(|f| f.name == field_name)
-    }
-
     pub(crate) fn get_field(&self, field_name: &str) -> Option<&Field> {
         self.all_fields().find(|f| f.name == field_name)
     }
 
+    /// The field whose key in JSON is `json_name`, see [`Field::json_name`].
+    pub(crate) fn get_field_by_json_name(&self, json_name: &str) -> Option<&Field> {
+        self.all_fields().find(|f| f.json_name() == json_name)
+    }
+
+    /// The field that `name` refers to, by either its column or its JSON
+    /// name. It's an error for `name` to be the column name of one field
+    /// and the JSON name of another.
+    pub(crate) fn lookup_field(&self, name: &str) -> anyhow::Result<Option<&Field>> {
+        match (self.get_field(name), self.get_field_by_json_name(name)) {
+            (Some(by_column), Some(by_json)) if by_column != by_json => anyhow::bail!(
+                "field name '{}' of {} is ambiguous: it is the JSON name of '{}'",
+                name,
+                self.name,
+                by_json.name
+            ),
+            (by_column, by_json) => Ok(by_column.or(by_json)),
+        }
+    }
+
     pub(crate) fn backing_table(&self) -> &str {
         &self.backing_table
     }
 
+    /// A copy of this type keeping its rows in `<namespace>_<table>`, and
+    /// referring to the types of `ts` rather than to its own.
+    #[cfg(test)]
+    fn forked(&self, namespace: &str, ts: &TypeSystem) -> anyhow::Result<Self> {
+        let mut forked: Self = ts.from_json(&serde_json::to_string(self)?)?;
+        forked.backing_table = namespaced(namespace, &self.backing_table)?;
+        for index in &mut forked.indexes {
+            index.backing_table = index
+                .backing_table
+                .as_deref()
+                .map(|table| namespaced(namespace, table))
+                .transpose()?;
+        }
+        Ok(forked)
+    }
+
+    /// The version column, which isn't part of [`Self::all_fields`].
+    pub(crate) fn version_field(&self) -> &Field {
+        &self.chisel_version
+    }
+
     pub(crate) fn name(&self) -> &str {
         &self.name
     }
 
+    pub(crate) fn with_description(self, description: Option<String>) -> Self {
+        Self {
+            description,
+            ..self
+        }
+    }
+
+    pub(crate) fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub(crate) fn with_parent(self, parent: Option<String>) -> Self {
+        Self { parent, ..self }
+    }
+
+    pub(crate) fn parent(&self) -> Option<&str> {
+        self.parent.as_deref()
+    }
+
+    /// Makes the rows of this type belong to tenants, which needs a string
+    /// field named `tenant_id` to hold the tenant.
+    pub(crate) fn with_multi_tenant(self, multi_tenant: bool) -> anyhow::Result<Self> {
+        if multi_tenant {
+            self.tenant_id_field()?;
+        }
+        Ok(Self {
+            multi_tenant,
+            ..self
+        })
+    }
+
+    pub(crate) fn is_multi_tenant(&self) -> bool {
+        self.multi_tenant
+    }
+
+    pub(crate) fn with_id_strategy(self, id_strategy: IdStrategy) -> Self {
+        Self {
+            id_strategy,
+            ..self
+        }
+    }
+
+    pub(crate) fn id_strategy(&self) -> IdStrategy {
+        self.id_strategy
+    }
+
+    pub(crate) fn with_webhook(self, webhook: Option<WebhookConfig>) -> Self {
+        Self { webhook, ..self }
+    }
+
+    pub(crate) fn webhook(&self) -> Option<&WebhookConfig> {
+        self.webhook.as_ref()
+    }
+
+    /// The field holding the tenant of a row of a multi-tenant type.
+    pub(crate) fn tenant_id_field(&self) -> anyhow::Result<&Field> {
+        match self.get_field_by_json_name(TENANT_ID_FIELD) {
+            Some(field) if field.type_ == Type::String && !field.is_optional => Ok(field),
+            _ => anyhow::bail!(
+                "multi-tenant type '{}' needs a field '{}' of type string",
+                self.name,
+                TENANT_ID_FIELD
+            ),
+        }
+    }
+
+    /// Checks that this type can extend `parent`, which it does by having
+    /// every field of it, with the same type, in its own backing table.
+    pub(crate) fn check_extends(&self, parent: &ObjectType) -> anyhow::Result<()> {
+        for parent_field in parent.user_fields() {
+            let field = self.get_field(&parent_field.name).with_context(|| {
+                format!(
+                    "type '{}' extends '{}' but lacks its field '{}'",
+                    self.name, parent.name, parent_field.name
+                )
+            })?;
+            anyhow::ensure!(
+                field.type_ == parent_field.type_,
+                "field '{}' of type '{}' is a {} but in its parent '{}' it is a {}",
+                field.name,
+                self.name,
+                field.type_.name(),
+                parent.name,
+                parent_field.type_.name()
+            );
+        }
+        Ok(())
+    }
+
+    /// Sets the unique constraints of this type. Their fields may be given by
+    /// either their column or their JSON names, and end up as column names.
+    pub(crate) fn with_unique_constraints(
+        self,
+        unique_constraints: Vec<UniqueConstraint>,
+    ) -> anyhow::Result<Self> {
+        let mut resolved = vec![];
+        for constraint in unique_constraints {
+            anyhow::ensure!(
+                !constraint.fields.is_empty(),
+                "unique constraint on type '{}' has no fields",
+                self.name
+            );
+            let mut fields = vec![];
+            for name in &constraint.fields {
+                let field = self.lookup_field(name)?.with_context(|| {
+                    format!(
+                        "unique constraint over field '{}' which is not present on type '{}'",
+                        name, self.name
+                    )
+                })?;
+                anyhow::ensure!(
+                    !field.is_encrypted,
+                    "unique constraint over field '{}' of type '{}', which is encrypted",
+                    name,
+                    self.name
+                );
+                fields.push(field.name.clone());
+            }
+            resolved.push(UniqueConstraint {
+                fields,
+                name: constraint.name,
+            });
+        }
+        Ok(Self {
+            unique_constraints: resolved,
+            ..self
+        })
+    }
+
+    pub(crate) fn unique_constraints(&self) -> &[UniqueConstraint] {
+        &self.unique_constraints
+    }
+
+    /// Sets the check constraints of this type, whose names must be unique.
+    pub(crate) fn with_check_constraints(
+        self,
+        check_constraints: Vec<CheckConstraint>,
+    ) -> anyhow::Result<Self> {
+        let mut names = std::collections::HashSet::new();
+        for constraint in &check_constraints {
+            anyhow::ensure!(
+                !constraint.name.is_empty() && !constraint.name.contains('"'),
+                "check constraint on type '{}' needs a name without double quotes",
+                self.name
+            );
+            anyhow::ensure!(
+                names.insert(&constraint.name),
+                "check constraint '{}' defined twice on type '{}'",
+                constraint.name,
+                self.name
+            );
+            anyhow::ensure!(
+                !constraint.expression.trim().is_empty(),
+                "check constraint '{}' on type '{}' has no expression",
+                constraint.name,
+                self.name
+            );
+        }
+        Ok(Self {
+            check_constraints,
+            ..self
+        })
+    }
+
+    pub(crate) fn check_constraints(&self) -> &[CheckConstraint] {
+        &self.check_constraints
+    }
+
+    /// Sets the virtual relations of this type, whose names may be used by
+    /// neither another relation nor a field.
+    pub(crate) fn with_virtual_relations(
+        self,
+        virtual_relations: Vec<VirtualRelation>,
+    ) -> anyhow::Result<Self> {
+        let mut names = std::collections::HashSet::new();
+        for relation in &virtual_relations {
+            anyhow::ensure!(
+                names.insert(&relation.name),
+                "relation '{}' defined twice on type '{}'",
+                relation.name,
+                self.name
+            );
+            anyhow::ensure!(
+                self.lookup_field(&relation.name)?.is_none(),
+                "relation '{}' has the name of a field of type '{}'",
+                relation.name,
+                self.name
+            );
+            anyhow::ensure!(
+                !relation.subquery.trim().is_empty(),
+                "relation '{}' on type '{}' has no subquery",
+                relation.name,
+                self.name
+            );
+        }
+        Ok(Self {
+            virtual_relations,
+            ..self
+        })
+    }
+
+    pub(crate) fn virtual_relations(&self) -> &[VirtualRelation] {
+        &self.virtual_relations
+    }
+
+    pub(crate) fn virtual_relation(&self, name: &str) -> Option<&VirtualRelation> {
+        self.virtual_relations.iter().find(|r| r.name == name)
+    }
+
     pub(crate) fn persisted_name(&self) -> String {
         format!("{}.{}", self.api_version, self.name)
     }
@@ -775,7 +1412,147 @@ impl PartialEq for ObjectType {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// How the ids of the new rows of a type are generated. Whatever the
+/// strategy, a row can be saved with an id of any of these forms, so that
+/// the rows saved before the strategy changed stay valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum IdStrategy {
+    /// Random UUIDs, as `4b7a51c4-2e47-4d0e-8e0f-9bd3f6f1a1d0`.
+    RandomUuid,
+    /// ULIDs, which sort by the time they were generated at.
+    Ulid,
+    /// 64-bit numbers made of a timestamp, `machine_id` and a sequence
+    /// number, so that servers with different machine ids never collide.
+    Snowflake { machine_id: u16 },
+    /// 1, 2, 3, and so on.
+    AutoIncrement,
+}
+
+impl Default for IdStrategy {
+    fn default() -> Self {
+        IdStrategy::RandomUuid
+    }
+}
+
+impl std::str::FromStr for IdStrategy {
+    type Err = anyhow::Error;
+
+    /// Parses the strategy as `@idStrategy` gives it: `uuid`, `ulid`,
+    /// `snowflake:<machine id>` or `autoincrement`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.split_once(':') {
+            None if s == "uuid" => IdStrategy::RandomUuid,
+            None if s == "ulid" => IdStrategy::Ulid,
+            None if s == "autoincrement" => IdStrategy::AutoIncrement,
+            Some(("snowflake", machine_id)) => {
+                let machine_id = machine_id
+                    .parse()
+                    .ok()
+                    .filter(|id| *id < 1 << SNOWFLAKE_MACHINE_ID_BITS)
+                    .with_context(|| {
+                        format!(
+                            "the machine id of snowflake ids goes from 0 to {}, got {}",
+                            (1 << SNOWFLAKE_MACHINE_ID_BITS) - 1,
+                            machine_id
+                        )
+                    })?;
+                IdStrategy::Snowflake { machine_id }
+            }
+            _ => anyhow::bail!(
+                "unknown id strategy '{}', expected uuid, ulid, snowflake or autoincrement",
+                s
+            ),
+        })
+    }
+}
+
+impl std::fmt::Display for IdStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdStrategy::RandomUuid => write!(f, "uuid"),
+            IdStrategy::Ulid => write!(f, "ulid"),
+            IdStrategy::Snowflake { machine_id } => write!(f, "snowflake:{}", machine_id),
+            IdStrategy::AutoIncrement => write!(f, "autoincrement"),
+        }
+    }
+}
+
+/// A change to a row that a webhook can be notified of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DataEvent {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl DataEvent {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DataEvent::Insert => "insert",
+            DataEvent::Update => "update",
+            DataEvent::Delete => "delete",
+        }
+    }
+}
+
+impl std::str::FromStr for DataEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "insert" => Ok(DataEvent::Insert),
+            "update" => Ok(DataEvent::Update),
+            "delete" => Ok(DataEvent::Delete),
+            _ => anyhow::bail!(
+                "unknown webhook event '{}', expected insert, update or delete",
+                s
+            ),
+        }
+    }
+}
+
+/// Where the changes to the rows of a type are POSTed, as `@webhook` gives it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct WebhookConfig {
+    /// The http or https URL the changes are POSTed to.
+    pub(crate) url: String,
+    /// Name of the secret holding the key the deliveries are signed with,
+    /// rather than the key itself, so that it isn't kept in the models.
+    pub(crate) secret: String,
+    /// The changes that are delivered.
+    pub(crate) events: Vec<DataEvent>,
+}
+
+impl WebhookConfig {
+    pub(crate) fn new(url: &str, secret: &str, events: &[String]) -> anyhow::Result<Self> {
+        let parsed = deno_core::url::Url::parse(url)
+            .with_context(|| format!("the webhook URL '{}' is not valid", url))?;
+        anyhow::ensure!(
+            matches!(parsed.scheme(), "http" | "https"),
+            "the webhook URL '{}' is not http or https",
+            url
+        );
+        anyhow::ensure!(!secret.is_empty(), "the webhook of {} has no secret", url);
+        anyhow::ensure!(!events.is_empty(), "the webhook of {} has no events", url);
+        let events = events
+            .iter()
+            .map(|e| e.parse())
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            url: url.to_owned(),
+            secret: secret.to_owned(),
+            events,
+        })
+    }
+}
+
+/// Number of bits of a snowflake id holding the machine id.
+pub(crate) const SNOWFLAKE_MACHINE_ID_BITS: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct DbIndex {
     /// Id of this index in the meta database. Before it's creation, it will be None.
     pub(crate) meta_id: Option<i32>,
@@ -811,6 +1588,89 @@ impl DbIndex {
             truncate_identifier(&name).to_owned()
         })
     }
+
+    /// What [`Self::name`] shows for an index of `backing_table` that has no
+    /// id yet, with `new` in place of the id.
+    pub(crate) fn planned_name(&self, backing_table: &str) -> String {
+        let name = format!("index_new_{backing_table}__{}", self.fields.join("_"));
+        truncate_identifier(&name).to_owned()
+    }
+}
+
+/// Fields whose values no two rows may share all at once, as the username
+/// and the provider of an account. Enforced by a unique index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UniqueConstraint {
+    /// Column names of the fields.
+    pub(crate) fields: Vec<String>,
+    /// The name given to the constraint in the model, if any.
+    pub(crate) name: Option<String>,
+}
+
+impl UniqueConstraint {
+    /// How errors refer to this constraint.
+    pub(crate) fn display_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.fields.join(", "))
+    }
+
+    /// Name of the index enforcing this constraint on `backing_table`.
+    pub(crate) fn index_name(&self, backing_table: &str) -> String {
+        let suffix = self.name.clone().unwrap_or_else(|| self.fields.join("_"));
+        truncate_identifier(&format!("unique_{backing_table}__{suffix}")).to_owned()
+    }
+}
+
+/// A condition in SQL that every row has to meet, as `price > 0`. It is
+/// passed to the database as is, so it's written for one in particular.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CheckConstraint {
+    pub(crate) name: String,
+    pub(crate) expression: String,
+}
+
+/// Pieces of SQL that only one of the supported databases understands.
+const DIALECT_SPECIFIC_SQL: &[(&str, &str)] = &[
+    ("::", "PostgreSQL"),
+    ("~", "PostgreSQL"),
+    ("ilike", "PostgreSQL"),
+    ("similar to", "PostgreSQL"),
+    ("jsonb", "PostgreSQL"),
+    ("regexp_", "PostgreSQL"),
+    ("glob", "SQLite"),
+    ("regexp", "SQLite"),
+    ("json_extract", "SQLite"),
+    ("typeof(", "SQLite"),
+    ("instr(", "SQLite"),
+];
+
+impl CheckConstraint {
+    /// A warning that the expression won't work on every database, if it
+    /// holds SQL that only one of them knows of.
+    pub(crate) fn dialect_warning(&self) -> Option<String> {
+        let expression = self.expression.to_lowercase();
+        let (sql, dialect) = DIALECT_SPECIFIC_SQL
+            .iter()
+            .find(|(sql, _)| expression.contains(sql))?;
+        Some(format!(
+            "check constraint '{}' uses `{}`, which only {} understands",
+            self.name, sql, dialect
+        ))
+    }
+}
+
+/// A relationship to the entities of `target_type` picked by `subquery`,
+/// which is worked out when the relation is asked for instead of being
+/// stored. `subquery` is a condition in SQL on the columns of the target,
+/// possibly followed by `ORDER BY` and `LIMIT`, in which `$id` stands for
+/// the id of the entity the relation is of, as `post = $id`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VirtualRelation {
+    pub(crate) name: String,
+    pub(crate) target_type: String,
+    pub(crate) subquery: String,
 }
 
 #[derive(Debug)]
@@ -933,10 +1793,12 @@ impl<'a> FieldDescriptor for NewField<'a> {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct Field {
     pub(crate) id: Option<i32>,
     pub(crate) name: String,
+    #[serde(rename = "type")]
     pub(crate) type_: Type,
     pub(crate) labels: Vec<String>,
     pub(crate) is_optional: bool,
@@ -952,6 +1814,21 @@ pub(crate) struct Field {
     default: Option<String>,
     effective_default: Option<String>,
     api_version: String,
+    /// The key of this field in JSON, if it's not the same as the column name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) json_name: Option<String>,
+    /// Documentation of the field, from the JSDoc comment of its property.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    /// Whether callers should stop using this field, say because it was replaced.
+    #[serde(default)]
+    pub(crate) is_deprecated: bool,
+    /// What to use instead, if the field is deprecated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) deprecated_message: Option<String>,
+    /// Whether the values of the field are stored encrypted, see [`crate::datastore::encryption`].
+    #[serde(default)]
+    pub(crate) is_encrypted: bool,
 }
 
 impl Field {
@@ -981,9 +1858,60 @@ impl Field {
             effective_default,
             is_optional,
             is_unique,
+            json_name: None,
+            description: None,
+            is_deprecated: false,
+            deprecated_message: None,
+            is_encrypted: false,
+        }
+    }
+
+    pub(crate) fn with_description(self, description: Option<String>) -> Self {
+        Self {
+            description,
+            ..self
+        }
+    }
+
+    /// Marks the field as deprecated, with `message` telling what to use instead.
+    pub(crate) fn deprecated(self, message: Option<String>) -> Self {
+        Self {
+            is_deprecated: true,
+            deprecated_message: message,
+            ..self
         }
     }
 
+    /// Stores the values of the field encrypted.
+    pub(crate) fn encrypted(self) -> Self {
+        Self {
+            is_encrypted: true,
+            ..self
+        }
+    }
+
+    /// What callers using this field are warned with, if it's deprecated.
+    pub(crate) fn deprecation_warning(&self) -> Option<String> {
+        if !self.is_deprecated {
+            return None;
+        }
+        Some(match &self.deprecated_message {
+            Some(message) => format!("Field '{}' is deprecated: {}", self.json_name(), message),
+            None => format!("Field '{}' is deprecated.", self.json_name()),
+        })
+    }
+
+    /// Makes the field go by `json_name` in JSON, rather than by its column name.
+    pub(crate) fn with_json_name(self, json_name: Option<String>) -> Self {
+        Self { json_name, ..self }
+    }
+
+    /// The key of this field in the JSON objects that entities are read
+    /// from and written as.
+    pub(crate) fn json_name(&self) -> &str {
+        self.json_name.as_deref().unwrap_or(&self.name)
+    }
+
     pub(crate) fn user_provided_default(&self) -> &Option<String> {
         &self.default
     }
@@ -1009,26 +1937,356 @@ impl Field {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct FieldAttrDelta {
+    #[serde(rename = "type")]
     pub(crate) type_: Type,
     pub(crate) default: Option<String>,
     pub(crate) is_optional: bool,
     pub(crate) is_unique: bool,
+    pub(crate) json_name: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) is_deprecated: bool,
+    pub(crate) deprecated_message: Option<String>,
+    pub(crate) is_encrypted: bool,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct FieldDelta {
     pub(crate) id: i32,
     pub(crate) attrs: Option<FieldAttrDelta>,
     pub(crate) labels: Option<Vec<String>>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct ObjectDelta {
     pub(crate) added_fields: Vec<Field>,
     pub(crate) removed_fields: Vec<Field>,
     pub(crate) updated_fields: Vec<FieldDelta>,
     pub(crate) added_indexes: Vec<DbIndex>,
     pub(crate) removed_indexes: Vec<DbIndex>,
+    pub(crate) added_unique_constraints: Vec<UniqueConstraint>,
+    pub(crate) removed_unique_constraints: Vec<UniqueConstraint>,
+    pub(crate) added_check_constraints: Vec<CheckConstraint>,
+    pub(crate) removed_check_constraints: Vec<CheckConstraint>,
+    pub(crate) added_virtual_relations: Vec<VirtualRelation>,
+    pub(crate) removed_virtual_relations: Vec<VirtualRelation>,
+    /// Description of the new version of the type.
+    pub(crate) description: Option<String>,
+    /// Parent of the new version of the type.
+    pub(crate) parent: Option<String>,
+    /// Whether the new version of the type is multi-tenant.
+    pub(crate) multi_tenant: bool,
+    /// How the new version of the type generates ids.
+    #[serde(default)]
+    pub(crate) id_strategy: IdStrategy,
+    /// Webhook of the new version of the type.
+    #[serde(default)]
+    pub(crate) webhook: Option<WebhookConfig>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::{make_field, make_object, make_type_system, VERSION};
+
+    #[test]
+    fn object_type_json_roundtrip() {
+        let person = make_object("Person", vec![make_field("name", Type::String)]);
+        let company = make_object(
+            "Company",
+            vec![
+                make_field("name", Type::String),
+                make_field("ceo", Type::Object(person.clone())),
+            ],
+        );
+        let ts = make_type_system(&[person.clone(), company.clone()]);
+
+        let json = serde_json::to_string(&*company).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value["fields"][1]["type"],
+            serde_json::json!({"object": {"name": "Person", "apiVersion": person.api_version}})
+        );
+
+        let roundtripped: ObjectType = ts.from_json(&json).unwrap();
+        assert_eq!(serde_json::to_string(&roundtripped).unwrap(), json);
+        match &roundtripped.get_field("ceo").unwrap().type_ {
+            Type::Object(ceo) => assert!(Arc::ptr_eq(ceo, &person)),
+            ty => panic!("unexpected type of ceo: {:?}", ty),
+        }
+
+        assert!(serde_json::from_str::<ObjectType>(&json).is_err());
+    }
+
+    #[test]
+    fn fork() {
+        let person = make_object("Person", vec![make_field("name", Type::String)]);
+        let company = Arc::new(
+            ObjectType::new(
+                NewObject::new("Company", VERSION)
+                    .with_namespace(Some("app"))
+                    .unwrap(),
+                vec![
+                    make_field("name", Type::String),
+                    make_field("ceo", Type::Object(person.clone())),
+                ],
+                vec![DbIndex::new(1, "app_companies".into(), vec!["name".into()])],
+                IsNotAuth,
+            )
+            .unwrap(),
+        );
+        assert!(company.backing_table().starts_with("app_ty_Company_"));
+        let ts = make_type_system(&[company.clone(), person.clone()]);
+
+        let forked = ts.fork("t1").unwrap();
+        let lookup = |ts: &TypeSystem, name: &str| ts.lookup_object_type(name, VERSION).unwrap();
+        let forked_person = lookup(&forked, "Person");
+        let forked_company = lookup(&forked, "Company");
+        assert_eq!(
+            forked_person.backing_table(),
+            format!("t1_{}", person.backing_table())
+        );
+        assert_eq!(
+            forked_company.backing_table(),
+            format!("t1_{}", company.backing_table())
+        );
+        match &forked_company.get_field("ceo").unwrap().type_ {
+            Type::Object(ceo) => assert!(Arc::ptr_eq(ceo, &forked_person)),
+            ty => panic!("unexpected type of ceo: {:?}", ty),
+        }
+        assert_eq!(
+            forked_company.indexes()[0].name().unwrap(),
+            "index_1_t1_app_companies__name"
+        );
+        assert!(Arc::ptr_eq(
+            &lookup(&forked, AUTH_USER_NAME),
+            &lookup(&ts, AUTH_USER_NAME)
+        ));
+        assert!(Arc::ptr_eq(&lookup(&ts, "Person"), &person));
+
+        ts.fork("1st").unwrap_err();
+        ts.fork(&"n".repeat(60)).unwrap_err();
+    }
+
+    #[test]
+    fn table_names() {
+        for name in ["legacy_users", "_old", "Orders2022"] {
+            validate_table_name(name).unwrap();
+        }
+        let long = "t".repeat(64);
+        for name in [
+            "",
+            "2022_orders",
+            "user-accounts",
+            "users; --",
+            "\"quoted\"",
+            long.as_str(),
+        ] {
+            validate_table_name(name).unwrap_err();
+        }
+        validate_table_name("__chiselstrike_meta").unwrap_err();
+
+        let desc = NewObject::new("User", VERSION)
+            .with_table_name(Some("legacy_users".into()))
+            .unwrap();
+        let ty = ObjectType::new(desc, vec![], vec![], AuthOrNot::IsNotAuth).unwrap();
+        assert_eq!(ty.backing_table(), "legacy_users");
+        let ts = make_type_system(&[Arc::new(ty)]);
+        assert!(ts.backing_tables().any(|t| t == "legacy_users"));
+        assert!(ts.backing_tables().any(|t| t == "auth_user"));
+        NewObject::new("User", VERSION)
+            .with_table_name(Some("legacy users".into()))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn builtin_types() {
+        let ts = TypeSystem::default();
+        for name in [
+            AUTH_USER_NAME,
+            AUTH_SESSION_NAME,
+            AUTH_TOKEN_NAME,
+            AUTH_ACCOUNT_NAME,
+        ] {
+            match ts.lookup_builtin_type(name) {
+                Ok(Type::Object(ty)) => {
+                    assert_eq!(ty.name(), name);
+                    assert!(ty.is_auth());
+                }
+                other => panic!("unexpected lookup of {}: {:?}", name, other),
+            }
+            assert!(matches!(ts.lookup_type(name, "dev"), Ok(Type::Object(_))));
+        }
+        for (name, ty) in [
+            ("string", Type::String),
+            ("number", Type::Float),
+            ("boolean", Type::Boolean),
+            ("Blob", Type::Blob),
+            ("string[]", Type::Array(Box::new(Type::String))),
+            ("number[]", Type::Array(Box::new(Type::Float))),
+            ("boolean[]", Type::Array(Box::new(Type::Boolean))),
+            (
+                "Record<string, number>",
+                Type::Map(Some(Box::new(Type::Float))),
+            ),
+            ("Record<string, any>", Type::Map(None)),
+            (
+                "\"pending\" | \"active\"",
+                Type::Enum(vec!["pending".into(), "active".into()]),
+            ),
+        ] {
+            assert_eq!(ts.lookup_builtin_type(name).unwrap(), ty);
+            assert_eq!(ty.name(), name);
+        }
+        for name in ["\"pending\" |", "\"pending\" | active", "\"pending"] {
+            assert!(ts.lookup_builtin_type(name).is_err());
+        }
+    }
+
+    #[test]
+    fn unique_constraint_delta() {
+        let account = |constraints| {
+            let fields = vec![
+                make_field("provider", Type::String),
+                make_field("username", Type::String),
+            ];
+            let ty = ObjectType::new(
+                NewObject::new("Account", VERSION),
+                fields,
+                vec![],
+                AuthOrNot::IsNotAuth,
+            )
+            .unwrap();
+            Arc::new(ty.with_unique_constraints(constraints).unwrap())
+        };
+        let identity = UniqueConstraint {
+            fields: vec!["provider".into(), "username".into()],
+            name: Some("identity".into()),
+        };
+        let username = UniqueConstraint {
+            fields: vec!["username".into()],
+            name: None,
+        };
+        let old = account(vec![identity.clone()]);
+        let new = account(vec![username.clone()]);
+
+        let delta = TypeSystem::generate_type_delta(&old, new.clone()).unwrap();
+        assert_eq!(delta.added_unique_constraints, vec![username]);
+        assert_eq!(delta.removed_unique_constraints, vec![identity]);
+        let delta = TypeSystem::generate_type_delta(&new, new.clone()).unwrap();
+        assert!(delta.added_unique_constraints.is_empty());
+        assert!(delta.removed_unique_constraints.is_empty());
+
+        let missing = UniqueConstraint {
+            fields: vec!["email".into()],
+            name: None,
+        };
+        let ty = ObjectType::new(
+            NewObject::new("Account", VERSION),
+            vec![make_field("username", Type::String)],
+            vec![],
+            AuthOrNot::IsNotAuth,
+        )
+        .unwrap();
+        assert!(ty.with_unique_constraints(vec![missing]).is_err());
+    }
+
+    #[test]
+    fn check_constraint_dialects() {
+        let check = |expression: &str| CheckConstraint {
+            name: "valid".into(),
+            expression: expression.into(),
+        };
+        assert_eq!(
+            check("price > 0 AND length(name) < 10").dialect_warning(),
+            None
+        );
+        assert_eq!(
+            check("name ~ '^[a-z]+$'").dialect_warning().unwrap(),
+            "check constraint 'valid' uses `~`, which only PostgreSQL understands"
+        );
+        assert_eq!(
+            check("name GLOB '[a-z]*'").dialect_warning().unwrap(),
+            "check constraint 'valid' uses `glob`, which only SQLite understands"
+        );
+
+        let ty = ObjectType::new(
+            NewObject::new("Product", VERSION),
+            vec![make_field("price", Type::Float)],
+            vec![],
+            AuthOrNot::IsNotAuth,
+        )
+        .unwrap();
+        assert!(ty
+            .with_check_constraints(vec![check("price > 0"), check("price < 10")])
+            .is_err());
+    }
+
+    #[test]
+    fn check_extends() {
+        let parent = make_object("Stamped", vec![make_field("created", Type::Float)]);
+        let child = make_object(
+            "Post",
+            vec![
+                make_field("created", Type::Float),
+                make_field("title", Type::String),
+            ],
+        );
+        child.check_extends(&parent).unwrap();
+        assert!(parent.check_extends(&child).is_err());
+
+        let retyped = make_object("Post", vec![make_field("created", Type::String)]);
+        let err = retyped.check_extends(&parent).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "field 'created' of type 'Post' is a string but in its parent 'Stamped' it is a number"
+        );
+    }
+
+    #[test]
+    fn multi_tenant() {
+        let project = |fields| {
+            ObjectType::new(
+                NewObject::new("Project", VERSION),
+                fields,
+                vec![],
+                AuthOrNot::IsNotAuth,
+            )
+            .unwrap()
+        };
+        let ty = project(vec![
+            make_field("name", Type::String),
+            make_field("tenant_id", Type::String),
+        ])
+        .with_multi_tenant(true)
+        .unwrap();
+        assert!(ty.is_multi_tenant());
+
+        let err = project(vec![make_field("tenant_id", Type::Float)])
+            .with_multi_tenant(true)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "multi-tenant type 'Project' needs a field 'tenant_id' of type string"
+        );
+        assert!(!project(vec![])
+            .with_multi_tenant(false)
+            .unwrap()
+            .is_multi_tenant());
+    }
+
+    #[test]
+    fn missing_builtin_type() {
+        let ts = TypeSystem::default();
+        let err = ts.lookup_builtin_type("Person").unwrap_err();
+        assert!(matches!(&err, TypeSystemError::NotABuiltinType(name) if name == "Person"));
+        assert_eq!(
+            err.to_string(),
+            "builtin type expected, got `Person` instead"
+        );
+    }
 }
@@ -0,0 +1,427 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! POSTs the changes to the rows of the types with a `@webhook` to its URL.
+//!
+//! A background task watches the changes to each such type, as
+//! [`crate::sse`] does, and delivers those of the events the webhook asks for
+//! as `{"event": ..., "type": ..., "id": ..., "data": ..., "timestamp": ...}`,
+//! where `data` is the row after the change, or `null` once it is deleted.
+//! Like GitHub's, deliveries are signed: their `X-ChiselStrike-Signature-256`
+//! header is `sha256=` followed by the hex HMAC-SHA256 of the body, keyed
+//! with the value of the secret the webhook names. An attempt fails if the
+//! webhook doesn't respond with a 2xx status within 5 seconds, and is then
+//! retried up to 3 times, 1, 2 and 4 seconds apart. Every attempt is recorded
+//! in the `webhook_deliveries` table of the meta database, which keeps the
+//! latest 1000 of each type.
+//!
+//! The watchers start over on every update of the type system, as `chisel
+//! apply` makes, and the changes made while they do are not delivered.
+//!
+//! `POST /__chiselstrike/webhooks/test` sends a `ping` to the webhook of a
+//! type the same way, without retries, and `GET
+//! /__chiselstrike/webhooks/:type/deliveries` lists the latest attempts.
+
+use crate::admin::AdminAuth;
+use crate::api::{json_response, version_param, ApiService, Body};
+use crate::context::RequestContext;
+use crate::datastore::watch::{self, Operation, POLL_INTERVAL};
+use crate::datastore::{MetaService, QueryEngine};
+use crate::route_pattern::route_param;
+use crate::secrets::get_secrets;
+use crate::sse::{fetch_row, receiver_stream};
+use crate::types::{DataEvent, ObjectType, TypeSystem, WebhookConfig};
+use crate::JsonObject;
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use deno_core::futures::{FutureExt, StreamExt};
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Client, Method, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+pub(crate) const WEBHOOKS_PATH: &str = "/__chiselstrike/webhooks";
+
+/// How many of the latest deliveries `GET /:type/deliveries` lists.
+const LISTED_DELIVERIES: usize = 50;
+
+/// How many of the latest deliveries to the webhook of a type are kept.
+const KEPT_DELIVERIES: usize = 1000;
+
+/// Header of the deliveries holding their signature.
+pub(crate) const SIGNATURE_HEADER: &str = "X-ChiselStrike-Signature-256";
+
+/// Header of the deliveries saying which event they are about.
+pub(crate) const EVENT_HEADER: &str = "X-ChiselStrike-Event";
+
+/// How long the webhook has to respond to a delivery.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times a failed delivery is retried.
+const MAX_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry. Each retry waits twice as long
+/// as the previous one.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// An attempt at delivering a change to a webhook.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Delivery {
+    pub(crate) type_name: String,
+    pub(crate) event: String,
+    pub(crate) url: String,
+    /// 0 for the first attempt, 1 for the first retry, and so on.
+    pub(crate) attempt: u32,
+    /// The status the webhook responded with, unless it didn't.
+    pub(crate) status: Option<u16>,
+    pub(crate) latency_ms: u64,
+    /// Why the attempt failed, unless it succeeded.
+    pub(crate) error: Option<String>,
+    /// When the attempt was made, in RFC 3339.
+    pub(crate) delivered_at: String,
+}
+
+impl From<Operation> for DataEvent {
+    fn from(operation: Operation) -> Self {
+        match operation {
+            Operation::Create => DataEvent::Insert,
+            Operation::Update => DataEvent::Update,
+            Operation::Delete => DataEvent::Delete,
+        }
+    }
+}
+
+/// The value of [`SIGNATURE_HEADER`] for `body`, keyed with `secret`.
+pub(crate) fn signature(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+/// How long to wait before retry number `retry`, counting from 1.
+fn backoff(retry: u32) -> Duration {
+    FIRST_BACKOFF * 2u32.pow(retry - 1)
+}
+
+/// The body of the delivery of `event` to the row `id` of `type_name`.
+pub(crate) fn payload(event: &str, type_name: &str, id: &str, data: Option<JsonObject>) -> Vec<u8> {
+    json!({
+        "event": event,
+        "type": type_name,
+        "id": id,
+        "data": data,
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// The body of the `ping` event [`test_webhook`] sends to the webhook of `type_name`.
+fn ping_payload(type_name: &str) -> Vec<u8> {
+    json!({
+        "event": "ping",
+        "type": type_name,
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// The client making all the deliveries, which share its pool of connections.
+static CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> = Lazy::new(|| {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(https)
+});
+
+async fn post(config: &WebhookConfig, event: &str, body: &[u8]) -> Result<StatusCode> {
+    let secrets = get_secrets().await?;
+    let secret = secrets
+        .get(&config.secret)
+        .and_then(|secret| secret.as_str())
+        .with_context(|| format!("the secret {} of the webhook is not set", config.secret))?;
+    let request = Request::post(&config.url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, event)
+        .header(SIGNATURE_HEADER, signature(secret.as_bytes(), body))
+        .body(hyper::Body::from(body.to_vec()))?;
+    let response = timeout(TIMEOUT, CLIENT.request(request))
+        .await
+        .with_context(|| format!("no response within {} seconds", TIMEOUT.as_secs()))??;
+    Ok(response.status())
+}
+
+/// Makes attempt number `attempt` at delivering `body` to the webhook of
+/// `type_name`, and records it.
+async fn send(
+    meta: &MetaService,
+    type_name: &str,
+    config: &WebhookConfig,
+    event: &str,
+    body: &[u8],
+    attempt: u32,
+) -> Delivery {
+    let delivered_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let start = Instant::now();
+    let result = post(config, event, body).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let (status, error) = match result {
+        Ok(status) if status.is_success() => (Some(status.as_u16()), None),
+        Ok(status) => (
+            Some(status.as_u16()),
+            Some(format!("the webhook responded with {}", status)),
+        ),
+        Err(e) => (None, Some(format!("{:#}", e))),
+    };
+    let delivery = Delivery {
+        type_name: type_name.to_owned(),
+        event: event.to_owned(),
+        url: config.url.clone(),
+        attempt,
+        status,
+        latency_ms,
+        error,
+        delivered_at,
+    };
+    if let Err(e) = meta
+        .insert_webhook_delivery(&delivery, KEPT_DELIVERIES)
+        .await
+    {
+        warn!(
+            "Could not record a delivery to the webhook of {}: {:?}",
+            type_name, e
+        );
+    }
+    delivery
+}
+
+/// Delivers `body`, retrying with exponential back-off while it fails.
+async fn deliver(
+    meta: Arc<MetaService>,
+    type_name: String,
+    config: WebhookConfig,
+    event: DataEvent,
+    body: Vec<u8>,
+) {
+    for attempt in 0..=MAX_RETRIES {
+        if attempt > 0 {
+            sleep(backoff(attempt)).await;
+        }
+        let delivery = send(&meta, &type_name, &config, event.as_str(), &body, attempt).await;
+        match delivery.error {
+            None => return,
+            Some(error) if attempt == MAX_RETRIES => warn!(
+                "Gave up delivering {} to the webhook of {}: {}",
+                event.as_str(),
+                type_name,
+                error
+            ),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Delivers the changes to `ty` that its webhook asks for, as they happen.
+async fn deliver_changes(
+    meta: Arc<MetaService>,
+    qeng: Arc<QueryEngine>,
+    ty: Arc<ObjectType>,
+    config: WebhookConfig,
+) -> Result<()> {
+    // Soft deletes are only updates to the change triggers, see crate::sse.
+    let mut changes = match watch::is_watchable(&ty) {
+        true => receiver_stream(qeng.clone().watch_table(ty.clone(), POLL_INTERVAL).await?),
+        false => qeng.watch(ty.backing_table()).await?,
+    };
+    while let Some(change) = changes.next().await {
+        let change = match change {
+            Ok(change) => change,
+            Err(e) => {
+                warn!("Could not watch the changes to {}: {:?}", ty.name(), e);
+                continue;
+            }
+        };
+        let event = DataEvent::from(change.operation);
+        if !config.events.contains(&event) {
+            continue;
+        }
+        let data = match event {
+            DataEvent::Delete => None,
+            _ if change.after.is_some() => change.after,
+            _ => fetch_row(&qeng, &ty, &change.id).await.unwrap_or_else(|e| {
+                warn!("Could not read {} {}: {:?}", ty.name(), change.id, e);
+                None
+            }),
+        };
+        let body = payload(event.as_str(), ty.name(), &change.id, data);
+        // Retries of one delivery don't hold back the others.
+        tokio::task::spawn(deliver(
+            meta.clone(),
+            ty.name().to_owned(),
+            config.clone(),
+            event,
+            body,
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct TestBody {
+    #[serde(rename = "type")]
+    type_name: String,
+}
+
+/// Sends a `ping` to the webhook of the `type` of the body, and responds with
+/// the status the webhook responded with, how long it took and why the
+/// delivery failed, if it did.
+async fn test_webhook(req: Request<hyper::Body>, meta: Arc<MetaService>) -> Result<Response<Body>> {
+    let version = version_param(&req);
+    let type_system = RequestContext::of(&req)?.type_system().clone();
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let body: TestBody = serde_json::from_slice(&body).context("invalid webhook test")?;
+    let ty = type_system.lookup_object_type(&body.type_name, &version)?;
+    let config = ty
+        .webhook()
+        .with_context(|| format!("type {} has no webhook", ty.name()))?;
+    let delivery = send(
+        &meta,
+        ty.name(),
+        config,
+        "ping",
+        &ping_payload(ty.name()),
+        0,
+    )
+    .await;
+    json_response(
+        StatusCode::OK,
+        json!({
+            "status": delivery.status,
+            "latency_ms": delivery.latency_ms,
+            "error": delivery.error,
+        }),
+    )
+}
+
+/// Responds with the latest attempts at delivering to the webhook of `:type`.
+async fn list_deliveries(
+    req: Request<hyper::Body>,
+    meta: Arc<MetaService>,
+) -> Result<Response<Body>> {
+    let type_name = route_param(&req, "type")?;
+    let deliveries = meta
+        .webhook_deliveries(&type_name, LISTED_DELIVERIES)
+        .await?;
+    json_response(StatusCode::OK, json!({ "deliveries": deliveries }))
+}
+
+/// Registers `POST /__chiselstrike/webhooks/test` and `GET
+/// /__chiselstrike/webhooks/:type/deliveries`, guarded like the admin routes.
+pub(crate) fn init(api: &ApiService, meta: MetaService) -> Result<()> {
+    let meta = Arc::new(meta);
+    let webhooks = api
+        .add_route_group(WEBHOOKS_PATH, vec![Arc::new(AdminAuth)])
+        .json_body();
+    webhooks.add_route(Method::POST, "/test", {
+        let meta = meta.clone();
+        Arc::new(move |req| test_webhook(req, meta.clone()).boxed_local())
+    })?;
+    webhooks.add_route(
+        Method::GET,
+        "/:type/deliveries",
+        Arc::new(move |req| list_deliveries(req, meta.clone()).boxed_local()),
+    )
+}
+
+/// The tasks delivering the changes to the types with a webhook.
+pub(crate) struct Webhooks {
+    meta: Arc<MetaService>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Webhooks {
+    pub(crate) fn new(meta: MetaService) -> Self {
+        Self {
+            meta: Arc::new(meta),
+            tasks: vec![],
+        }
+    }
+
+    /// Delivers the changes to the types of `type_system` from now on,
+    /// instead of those to the types it was last updated with. The watchers
+    /// of the types are started over, and the changes made until they are
+    /// watching again are not delivered, as they don't resume from where the
+    /// previous ones stopped.
+    pub(crate) fn update(&mut self, qeng: &Arc<QueryEngine>, type_system: &TypeSystem) {
+        self.stop();
+        let types = type_system
+            .versions
+            .values()
+            .flat_map(|version| version.custom_types.values());
+        for ty in types {
+            let config = match ty.webhook() {
+                Some(config) => config.clone(),
+                None => continue,
+            };
+            let (meta, qeng, ty) = (self.meta.clone(), qeng.clone(), ty.clone());
+            self.tasks.push(tokio::task::spawn(async move {
+                let name = ty.name().to_owned();
+                if let Err(e) = deliver_changes(meta, qeng, ty, config).await {
+                    warn!("Stopped delivering the changes to {}: {:?}", name, e);
+                }
+            }));
+        }
+    }
+
+    fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Webhooks {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        // The example of GitHub's documentation on validating webhook deliveries.
+        assert_eq!(
+            signature(b"It's a Secret to Everybody", b"Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+        assert_ne!(
+            signature(b"another secret", b"Hello, World!"),
+            signature(b"It's a Secret to Everybody", b"Hello, World!")
+        );
+    }
+
+    #[test]
+    fn backoffs() {
+        let backoffs: Vec<_> = (1..=MAX_RETRIES).map(backoff).collect();
+        assert_eq!(backoffs, [1, 2, 4].map(Duration::from_secs).to_vec());
+    }
+}